@@ -0,0 +1,4 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/starkroot.proto").expect("Failed to compile protobuf definitions");
+}
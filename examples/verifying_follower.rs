@@ -0,0 +1,271 @@
+//! A follower node verifying the state roots published by a sequencer, and serving storage proofs
+//! against them.
+//!
+//! Each block goes through the whole pipeline:
+//!
+//! 1. the state update is fetched from a [Feed],
+//! 2. it is turned into a commitment state diff,
+//! 3. the diff is committed to the follower's tries,
+//! 4. the resulting state root is checked against the root published with the update,
+//! 5. a storage proof is generated and verified against the published root, as a light client would.
+//!
+//! The tries are stored in RocksDB with the `rocksdb` feature, in memory otherwise. The feed is a
+//! simulated sequencer so that the example runs offline, a real node fetches the state updates
+//! from the feeder gateway or a full node's RPC instead. The sequencer computes the published
+//! roots with pathfinder's Merkle tree rather than with this crate, so that the follower checks its
+//! roots against an independent implementation.
+//!
+//! Run with `cargo run --example verifying_follower --features rocksdb`.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use bitvec::prelude::{BitSlice, BitVec, Msb0};
+use bitvec::view::BitView;
+use pathfinder_common::hash::PedersenHash;
+use pathfinder_crypto::hash::pedersen_hash;
+use pathfinder_storage::StoredNode;
+use starknet_api::core::{ContractAddress, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_core::types::{
+    ContractStorageDiffItem, DeployedContractItem, NonceUpdate, StateDiff, StateUpdate, StorageEntry,
+};
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+use starkroot::mpts::deoxys::backend::{Backend, MemoryBackend};
+use starkroot::mpts::deoxys::config::ChainConfig;
+use starkroot::mpts::deoxys::engine::CommitmentEngine;
+use starkroot::mpts::deoxys::lib::build_commitment_state_diff;
+use starkroot::mpts::pathfinder::storage::Storage;
+use starkroot::mpts::pathfinder::tree::MerkleTree;
+
+/// Number of contracts the simulated sequencer writes to.
+const CONTRACTS: u64 = 4;
+
+/// A source of state updates.
+trait Feed {
+    /// Returns the state update of `block_number`, `None` if it was not produced yet.
+    fn fetch(&mut self, block_number: u64) -> Option<StateUpdate>;
+}
+
+/// Storage of the sequencer's pathfinder trees, which are rebuilt from the whole state every block
+/// and so never read a committed node.
+struct NoStorage;
+
+impl Storage for NoStorage {
+    fn get(&self, _: u64) -> anyhow::Result<Option<StoredNode>> {
+        Ok(None)
+    }
+
+    fn hash(&self, _: u64) -> anyhow::Result<Option<pathfinder_crypto::Felt>> {
+        Ok(None)
+    }
+
+    fn leaf(&self, _: &BitSlice<u8, Msb0>) -> anyhow::Result<Option<pathfinder_crypto::Felt>> {
+        Ok(None)
+    }
+}
+
+/// State of a contract of the simulated sequencer.
+#[derive(Default)]
+struct ContractState {
+    class_hash: FieldElement,
+    nonce: FieldElement,
+    storage: BTreeMap<FieldElement, FieldElement>,
+}
+
+/// Simulated sequencer, publishing state updates along with the state roots they lead to.
+#[derive(Default)]
+struct Sequencer {
+    contracts: BTreeMap<FieldElement, ContractState>,
+    /// The next block to produce.
+    next: u64,
+}
+
+fn pathfinder_felt(felt: FieldElement) -> pathfinder_crypto::Felt {
+    pathfinder_crypto::Felt::from_be_bytes(felt.to_bytes_be()).expect("Field elements are felts")
+}
+
+/// The 251 bits path of a key in a Starknet trie.
+fn path(key: FieldElement) -> BitVec<u8, Msb0> {
+    key.to_bytes_be().view_bits::<Msb0>()[5..].to_bitvec()
+}
+
+impl Sequencer {
+    fn apply(&mut self, state_diff: &StateDiff) {
+        for deployed in state_diff.deployed_contracts.iter() {
+            self.contracts.entry(deployed.address).or_default().class_hash = deployed.class_hash;
+        }
+        for diff in state_diff.storage_diffs.iter() {
+            let storage = &mut self.contracts.entry(diff.address).or_default().storage;
+            storage.extend(diff.storage_entries.iter().map(|entry| (entry.key, entry.value)));
+        }
+        for update in state_diff.nonces.iter() {
+            self.contracts.entry(update.contract_address).or_default().nonce = update.nonce;
+        }
+    }
+
+    /// The state root of the sequencer's state, computed with pathfinder's Merkle tree.
+    fn state_root(&self) -> anyhow::Result<FieldElement> {
+        let mut contracts_trie = MerkleTree::<PedersenHash, 251>::empty();
+        for (address, contract) in self.contracts.iter() {
+            let mut storage_trie = MerkleTree::<PedersenHash, 251>::empty();
+            for (key, value) in contract.storage.iter() {
+                storage_trie.set(&NoStorage, path(*key), pathfinder_felt(*value))?;
+            }
+            let storage_root = storage_trie.commit(&NoStorage)?.root_commitment;
+
+            // h(h(h(class_hash, storage_root), nonce), 0)
+            let state_hash = pedersen_hash(pathfinder_felt(contract.class_hash), storage_root);
+            let state_hash = pedersen_hash(state_hash, pathfinder_felt(contract.nonce));
+            let state_hash = pedersen_hash(state_hash, pathfinder_crypto::Felt::ZERO);
+            contracts_trie.set(&NoStorage, path(*address), state_hash)?;
+        }
+
+        // No class is declared: the classes trie is empty and the state root is the contracts trie root
+        let root = contracts_trie.commit(&NoStorage)?.root_commitment;
+        Ok(FieldElement::from_bytes_be(&root.to_be_bytes()).expect("Felts are field elements"))
+    }
+
+    fn state_diff(block_number: u64) -> StateDiff {
+        let contracts = (1..=CONTRACTS).map(FieldElement::from);
+        let deployed_contracts = match block_number {
+            0 => contracts
+                .clone()
+                .map(|address| DeployedContractItem { address, class_hash: FieldElement::from(0x10_u64) })
+                .collect(),
+            _ => vec![],
+        };
+        let storage_diffs = contracts
+            .clone()
+            .map(|address| ContractStorageDiffItem {
+                address,
+                storage_entries: vec![StorageEntry {
+                    key: FieldElement::from(block_number),
+                    value: FieldElement::from(block_number + 1) * address,
+                }],
+            })
+            .collect();
+        let nonces = contracts
+            .map(|contract_address| NonceUpdate { contract_address, nonce: FieldElement::from(block_number + 1) })
+            .collect();
+
+        StateDiff {
+            storage_diffs,
+            deprecated_declared_classes: vec![],
+            declared_classes: vec![],
+            deployed_contracts,
+            replaced_classes: vec![],
+            nonces,
+        }
+    }
+}
+
+impl Feed for Sequencer {
+    fn fetch(&mut self, block_number: u64) -> Option<StateUpdate> {
+        if block_number != self.next {
+            return None;
+        }
+        let mut state_update = StateUpdate {
+            block_hash: FieldElement::ZERO,
+            new_root: FieldElement::ZERO,
+            old_root: FieldElement::ZERO,
+            state_diff: Self::state_diff(block_number),
+        };
+
+        self.apply(&state_update.state_diff);
+        state_update.new_root = self.state_root().ok()?;
+        self.next += 1;
+        Some(state_update)
+    }
+}
+
+fn backend() -> Result<Backend, Box<dyn Error>> {
+    #[cfg(feature = "rocksdb")]
+    {
+        use starkroot::mpts::deoxys::backend::RocksDbBackend;
+
+        let path = std::env::temp_dir().join(format!("verifying_follower-{}", std::process::id()));
+        Ok(Arc::new(RocksDbBackend::open(path, 16)?))
+    }
+    #[cfg(not(feature = "rocksdb"))]
+    Ok(Arc::new(MemoryBackend::new()))
+}
+
+fn felt(felt: FieldElement) -> Felt {
+    Felt::from_bytes_be(&felt.to_bytes_be())
+}
+
+/// Follows `blocks` blocks from `feed`.
+///
+/// # Returns
+///
+/// The verified state root of the last block.
+fn follow(feed: &mut impl Feed, blocks: u64, config: &ChainConfig) -> Result<Felt, Box<dyn Error>> {
+    let mut engine = CommitmentEngine::new(backend()?)?;
+    let mut state_root = Felt::ZERO;
+
+    for block_number in 0..blocks {
+        let state_update = feed.fetch(block_number).ok_or_else(|| format!("block {block_number} is not available"))?;
+        let csd = build_commitment_state_diff(&state_update)?;
+
+        state_root = engine.update_state_root(csd, block_number, config)?.into();
+        if state_root != felt(state_update.new_root) {
+            return Err(format!(
+                "block {block_number}: computed state root {state_root:#x}, published {:#x}",
+                state_update.new_root
+            )
+            .into());
+        }
+
+        // Serve the slot written by the block, and prove it to the published root
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let keys = [StorageKey(PatriciaKey(StarkFelt::from(block_number)))];
+        let proof = engine.storage_proof(&contract_address, &keys, config)?;
+        let values = proof.verify(felt(state_update.new_root), &keys, config)?;
+        println!("block {block_number}: state root {state_root:#x}, proven slot value {:#x}", values[0]);
+    }
+
+    Ok(state_root)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = ChainConfig::default();
+    let state_root = follow(&mut Sequencer::default(), 16, &config)?;
+    println!("followed 16 blocks, state root {state_root:#x}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_follows_sequencer() {
+        let config = ChainConfig::default();
+        let mut sequencer = Sequencer::default();
+        let state_root = follow(&mut sequencer, 8, &config).unwrap();
+        assert_eq!(state_root, felt(sequencer.state_root().unwrap()));
+    }
+
+    #[test]
+    fn test_detects_forged_root() {
+        struct Forger(Sequencer);
+
+        impl Feed for Forger {
+            fn fetch(&mut self, block_number: u64) -> Option<StateUpdate> {
+                let mut state_update = self.0.fetch(block_number)?;
+                if block_number == 3 {
+                    state_update.new_root = state_update.new_root + FieldElement::ONE;
+                }
+                Some(state_update)
+            }
+        }
+
+        let config = ChainConfig::default();
+        let mut forger = Forger(Sequencer::default());
+        assert!(follow(&mut forger, 8, &config).is_err());
+    }
+}
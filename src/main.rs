@@ -1,5 +1,3 @@
-mod mpts;
-
 fn main() {
     println!("Hello, world!");
 }
@@ -0,0 +1,196 @@
+//! Best-effort decoding of storage slots with the ABI of the contract's class, for debugging.
+//!
+//! ABIs describe the entry points of a class, not its storage. Storage variables which are not
+//! mappings usually have a getter of the same name though (ie: `name`, `total_supply`), whose
+//! output type tells how to decode the variable. Slots of other variables, and of mappings, are
+//! left undecoded.
+
+use std::io;
+
+use serde_json::Value;
+use starknet_api::core::ContractAddress;
+use starknet_api::state::StorageKey;
+use starknet_core::utils::starknet_keccak;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+
+use super::cairo_serde::{decode, CairoType, CairoValue};
+use super::class_store::ClassStore;
+use super::engine::CommitmentEngine;
+use super::error::TrieError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeStorageError {
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid ABI: {0}")]
+    Abi(#[from] serde_json::Error),
+}
+
+/// A function of the ABI without inputs, which may read a storage variable of the same name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Getter {
+    name: String,
+    output: Option<CairoType>,
+}
+
+/// The getters of a contract's ABI, Cairo 0 or Cairo 1.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContractAbi {
+    getters: Vec<Getter>,
+}
+
+/// A storage slot, with the variable it belongs to if the ABI told it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedSlot {
+    pub variable: Option<String>,
+    /// Offset of the slot from the variable's address, ie: 1 for the high limb of a `u256`.
+    pub offset: u64,
+    /// The value decoded with the getter's output type, a raw felt if it is unknown.
+    pub value: CairoValue,
+    /// The value as a Cairo short string, if it is printable ASCII.
+    pub short_string: Option<String>,
+}
+
+/// The Cairo type of an ABI type name, for the types fitting in a storage slot or two.
+fn cairo_type(name: &str) -> Option<CairoType> {
+    let ty = match name {
+        "felt" | "core::felt252" => CairoType::Felt,
+        "core::bool" => CairoType::Bool,
+        "Uint256" | "core::integer::u256" => CairoType::U256,
+        "core::starknet::contract_address::ContractAddress" => CairoType::ContractAddress,
+        name => CairoType::UInt(name.strip_prefix("core::integer::u")?.parse().ok()?),
+    };
+    Some(ty)
+}
+
+fn getter(function: &Value) -> Option<Getter> {
+    if !function["inputs"].as_array()?.is_empty() {
+        return None;
+    }
+    let output = match function["outputs"].as_array()?.as_slice() {
+        [output] => output["type"].as_str().and_then(cairo_type),
+        _ => None,
+    };
+    Some(Getter { name: function["name"].as_str()?.to_string(), output })
+}
+
+fn short_string(value: Felt) -> Option<String> {
+    let bytes = value.to_bytes_be();
+    let string = &bytes[bytes.iter().position(|byte| *byte != 0)?..];
+    string.iter().all(|byte| (0x20..0x7f).contains(byte)).then(|| String::from_utf8_lossy(string).into_owned())
+}
+
+impl ContractAbi {
+    /// Parses a JSON ABI, as found in the class definitions served by the gateway.
+    pub fn from_json(abi: &[u8]) -> Result<Self, serde_json::Error> {
+        let entries = serde_json::from_slice::<Vec<Value>>(abi)?;
+        // Cairo 1 ABIs nest the functions of the contract's interfaces
+        let functions = entries.iter().flat_map(|entry| match entry["type"].as_str() {
+            Some("function") => vec![entry],
+            Some("interface") => entry["items"].as_array().map(|items| items.iter().collect()).unwrap_or_default(),
+            _ => vec![],
+        });
+        Ok(Self { getters: functions.filter_map(getter).collect() })
+    }
+
+    /// Decodes a storage slot, see [DecodedSlot].
+    pub fn decode(&self, key: &StorageKey, value: Felt) -> DecodedSlot {
+        let key = FieldElement::from_bytes_be(&key.0.key().0).unwrap();
+        let variable = self.getters.iter().find_map(|getter| {
+            let name = getter.name.strip_prefix("get_").unwrap_or(&getter.name);
+            let size = getter.output.as_ref().and_then(CairoType::size).unwrap_or(1) as u64;
+            (0..size)
+                .find(|offset| starknet_keccak(name.as_bytes()) + FieldElement::from(*offset) == key)
+                .map(|offset| (name, offset, getter.output.as_ref()))
+        });
+
+        let short_string = short_string(value);
+        let Some((name, offset, output)) = variable else {
+            return DecodedSlot { variable: None, offset: 0, value: CairoValue::Felt(value), short_string };
+        };
+        let decoded = match output {
+            // Each slot holds one limb
+            Some(CairoType::U256) => decode(&CairoType::UInt(128), &[value]).ok(),
+            Some(ty) => decode(ty, &[value]).ok(),
+            None => None,
+        };
+        DecodedSlot {
+            variable: Some(name.to_string()),
+            offset,
+            value: decoded.unwrap_or(CairoValue::Felt(value)),
+            short_string,
+        }
+    }
+}
+
+/// Decodes a storage slot of a contract with the ABI of its current class, if it was stored.
+///
+/// # Arguments
+///
+/// * `engine`           - The tries, for the class of the contract.
+/// * `store`            - The class store holding the ABIs.
+/// * `contract_address` - The contract the slot belongs to.
+/// * `key`              - The storage key of the slot.
+/// * `value`            - The value of the slot.
+pub fn decode_storage(
+    engine: &CommitmentEngine,
+    store: &ClassStore,
+    contract_address: &ContractAddress,
+    key: &StorageKey,
+    value: Felt,
+) -> Result<DecodedSlot, DecodeStorageError> {
+    let abi = match store.abi(&engine.class_hash(contract_address)?)? {
+        Some(abi) => ContractAbi::from_json(&abi)?,
+        None => ContractAbi::default(),
+    };
+    Ok(abi.decode(key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use mp_convert::field_element::FromFieldElement;
+
+    use super::*;
+
+    const ABI: &str = r#"[
+        { "type": "impl", "name": "TokenImpl", "interface_name": "IToken" },
+        {
+            "type": "interface",
+            "name": "IToken",
+            "items": [
+                { "type": "function", "name": "name", "inputs": [], "outputs": [{ "type": "core::felt252" }] },
+                { "type": "function", "name": "get_total_supply", "inputs": [], "outputs": [{ "type": "core::integer::u256" }] },
+                {
+                    "type": "function",
+                    "name": "balance_of",
+                    "inputs": [{ "name": "account", "type": "core::starknet::contract_address::ContractAddress" }],
+                    "outputs": [{ "type": "core::integer::u256" }]
+                }
+            ]
+        }
+    ]"#;
+
+    #[test]
+    fn test_decode_slot() {
+        let abi = ContractAbi::from_json(ABI.as_bytes()).unwrap();
+        let key = |name: &str, offset: u64| {
+            StorageKey::from_field_element(&(starknet_keccak(name.as_bytes()) + FieldElement::from(offset)))
+        };
+
+        let name = abi.decode(&key("name", 0), Felt::from_bytes_be_slice(b"TKN"));
+        assert_eq!(name.variable.as_deref(), Some("name"));
+        assert_eq!(name.short_string.as_deref(), Some("TKN"));
+
+        let high = abi.decode(&key("total_supply", 1), Felt::from(7_u64));
+        assert_eq!((high.variable.as_deref(), high.offset), (Some("total_supply"), 1));
+        assert_eq!(high.value, CairoValue::UInt(7));
+
+        // Mappings are not decoded
+        let balance = abi.decode(&key("balance_of", 0), Felt::ONE);
+        assert_eq!(balance.variable, None);
+        assert_eq!(balance.value, CairoValue::Felt(Felt::ONE));
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeSet, HashMap};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use indexmap::IndexMap;
+use mp_convert::field_element::FromFieldElement;
+use starknet_api::core::{ContractAddress, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+
+/// Address of the system contract holding the alias mapping used by stateful compression
+/// (Starknet 0.13.3+).
+pub const ALIAS_CONTRACT_ADDRESS: u64 = 0x2;
+/// Storage key of the alias contract holding the next available alias.
+pub const ALIAS_COUNTER_STORAGE_KEY: u64 = 0;
+/// First alias ever allocated.
+pub const INITIAL_AVAILABLE_ALIAS: u64 = 128;
+/// Values below this bound are never aliased, they are published as is.
+pub const MIN_VALUE_FOR_ALIAS_ALLOC: u64 = 128;
+/// Storage keys of contracts up to this address are never aliased.
+pub const MAX_NON_COMPRESSED_CONTRACT_ADDRESS: u64 = 15;
+
+fn alias_contract_address() -> ContractAddress {
+    ContractAddress(PatriciaKey(StarkFelt::from(ALIAS_CONTRACT_ADDRESS)))
+}
+
+fn storage_key(felt: StarkFelt) -> StorageKey {
+    StorageKey(PatriciaKey(felt))
+}
+
+/// In-memory view of the alias contract's storage.
+///
+/// Stateful compression replaces contract addresses and storage keys by short aliases in the data
+/// published to L1. The mapping itself lives in the storage of [ALIAS_CONTRACT_ADDRESS], so it is
+/// committed to like any other contract storage: this keeps it in sync with the committed diffs so
+/// that compressed diffs can be decoded, and so that aliases can be allocated for locally executed
+/// blocks.
+#[derive(Debug, Clone, Default)]
+pub struct AliasMapping {
+    aliases: HashMap<StarkFelt, StarkFelt>,
+    values: HashMap<StarkFelt, StarkFelt>,
+    next_alias: Option<StarkFelt>,
+}
+
+impl AliasMapping {
+    /// Applies the alias contract writes of a committed diff to the mapping.
+    pub fn apply(&mut self, csd: &CommitmentStateDiff) {
+        let Some(updates) = csd.storage_updates.get(&alias_contract_address()) else {
+            return;
+        };
+        for (key, alias) in updates {
+            let key = *key.0.key();
+            if key == StarkFelt::from(ALIAS_COUNTER_STORAGE_KEY) {
+                self.next_alias = Some(*alias);
+            } else {
+                self.aliases.insert(key, *alias);
+                self.values.insert(*alias, key);
+            }
+        }
+    }
+
+    /// Returns the alias of a contract address or storage key, if one was allocated.
+    pub fn alias_of(&self, value: &StarkFelt) -> Option<StarkFelt> {
+        self.aliases.get(value).copied()
+    }
+
+    /// Decodes a value read from a compressed state diff.
+    ///
+    /// Values below [MIN_VALUE_FOR_ALIAS_ALLOC] are never aliased and are returned as is.
+    pub fn decompress(&self, value: &StarkFelt) -> Option<StarkFelt> {
+        if *value < StarkFelt::from(MIN_VALUE_FOR_ALIAS_ALLOC) {
+            return Some(*value);
+        }
+        self.values.get(value).copied()
+    }
+
+    /// Injects the alias contract writes induced by a locally executed diff.
+    ///
+    /// This follows the sequencer's allocation rules: every modified contract address and every
+    /// storage key of a contract above [MAX_NON_COMPRESSED_CONTRACT_ADDRESS] which is not aliased yet
+    /// is given the next available alias, in ascending order, and the counter is updated. Diffs from
+    /// the feeder gateway already contain these writes and must not go through this.
+    pub fn allocate(&mut self, csd: &mut CommitmentStateDiff) {
+        let min_value = StarkFelt::from(MIN_VALUE_FOR_ALIAS_ALLOC);
+        let max_non_compressed = StarkFelt::from(MAX_NON_COMPRESSED_CONTRACT_ADDRESS);
+
+        let addresses = csd
+            .storage_updates
+            .keys()
+            .chain(csd.address_to_nonce.keys())
+            .chain(csd.address_to_class_hash.keys())
+            .map(|contract_address| *contract_address.0.key());
+        let keys = csd
+            .storage_updates
+            .iter()
+            .filter(|(contract_address, _)| *contract_address.0.key() > max_non_compressed)
+            .flat_map(|(_, updates)| updates.keys().map(|key| *key.0.key()));
+
+        let to_allocate: BTreeSet<StarkFelt> = addresses
+            .chain(keys)
+            .filter(|value| *value >= min_value && !self.aliases.contains_key(value))
+            .collect();
+        if to_allocate.is_empty() {
+            return;
+        }
+
+        let mut next_alias = self.next_alias.unwrap_or(StarkFelt::from(INITIAL_AVAILABLE_ALIAS));
+        let mut writes = IndexMap::new();
+        for value in to_allocate {
+            writes.insert(storage_key(value), next_alias);
+            let next = FieldElement::from_bytes_be(&next_alias.0).unwrap() + FieldElement::ONE;
+            next_alias = StarkFelt::from_field_element(next);
+        }
+        writes.insert(storage_key(StarkFelt::from(ALIAS_COUNTER_STORAGE_KEY)), next_alias);
+
+        let alias_updates = csd.storage_updates.entry(alias_contract_address()).or_default();
+        alias_updates.extend(writes);
+
+        self.apply(csd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_csd() -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_allocate_aliases() {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x1000_u64)));
+        let mut csd = empty_csd();
+        let updates = [5_u64, 200].map(|key| (storage_key(StarkFelt::from(key)), StarkFelt::ONE));
+        csd.storage_updates.insert(contract_address, updates.into_iter().collect());
+
+        let mut mapping = AliasMapping::default();
+        mapping.allocate(&mut csd);
+
+        // aliases are allocated in ascending order, values below 128 are never aliased
+        assert_eq!(mapping.alias_of(&StarkFelt::from(200_u64)), Some(StarkFelt::from(128_u64)));
+        assert_eq!(mapping.alias_of(&StarkFelt::from(0x1000_u64)), Some(StarkFelt::from(129_u64)));
+        assert_eq!(mapping.alias_of(&StarkFelt::from(5_u64)), None);
+        assert_eq!(mapping.decompress(&StarkFelt::from(129_u64)), Some(StarkFelt::from(0x1000_u64)));
+        assert_eq!(mapping.decompress(&StarkFelt::from(5_u64)), Some(StarkFelt::from(5_u64)));
+
+        let alias_updates = &csd.storage_updates[&alias_contract_address()];
+        let counter = alias_updates[&storage_key(StarkFelt::from(ALIAS_COUNTER_STORAGE_KEY))];
+        assert_eq!(counter, StarkFelt::from(130_u64));
+
+        // already aliased values are not allocated again
+        let mut csd_next = empty_csd();
+        csd_next.address_to_nonce.insert(contract_address, Default::default());
+        mapping.allocate(&mut csd_next);
+        assert!(csd_next.storage_updates.is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+//! `async` variants of the blocking entry points, for nodes built on an async runtime.
+//!
+//! Commits and proofs are CPU bound and run on rayon, calling them from an async task stalls the
+//! executor thread for the whole commit. The variants below offload the call to a dedicated thread
+//! which runs it on the thread pool of the tries (see [runtime](super::runtime)), and return a
+//! future which resolves once it is done. The futures do not depend on the executor: they are
+//! polled the same way from tokio or any other runtime.
+//!
+//! Calls are not cancelled by dropping their future, a commit always runs to completion. Reads take
+//! their [tries](StateTries) by value, a clone of the caller's handle. Writes take the
+//! [WriteHandle] of the tries, which is given back along with their result.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+use starknet_api::core::ContractAddress;
+use starknet_api::state::StorageKey;
+#[cfg(feature = "pedersen")]
+use starknet_api::transaction::{Event, Transaction};
+use tokio::sync::oneshot;
+
+use super::config::ChainConfig;
+use super::engine::StateTries;
+use super::error::CommitError;
+#[cfg(feature = "pedersen")]
+use super::error::CommitmentError;
+use super::handles::WriteHandle;
+#[cfg(feature = "pedersen")]
+use super::lib::try_calculate_tx_and_event_commitments;
+use super::reorg::{RevertError, RevertReport};
+use super::runtime::spawn;
+use super::storage_proof::{get_storage_proof, StorageProof, StorageProofError};
+
+/// The result of an [offloaded](offload) call.
+///
+/// A panic of the call is resumed when the future is polled.
+#[derive(Debug)]
+pub struct Offloaded<R> {
+    receiver: oneshot::Receiver<std::thread::Result<R>>,
+}
+
+impl<R> Future for Offloaded<R> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(Ok(result))) => Poll::Ready(result),
+            Poll::Ready(Ok(Err(payload))) => panic::resume_unwind(payload),
+            Poll::Ready(Err(_)) => unreachable!("offloaded calls always send their result"),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Runs `f` on the compute pool, from a dedicated thread, see [spawn].
+pub fn offload<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> Offloaded<R> {
+    let (sender, receiver) = oneshot::channel();
+    spawn(move || {
+        // The caller may have dropped the future, the result is then discarded
+        let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(f)));
+    });
+    Offloaded { receiver }
+}
+
+/// See [WriteHandle::commit].
+pub fn try_update_state_root_async(
+    mut writer: WriteHandle,
+    csd: CommitmentStateDiff,
+    block_number: u64,
+    config: Arc<ChainConfig>,
+) -> Offloaded<(WriteHandle, Result<Felt252Wrapper, CommitError>)> {
+    offload(move || {
+        let result = writer.commit(csd, block_number, &config);
+        (writer, result)
+    })
+}
+
+/// See [WriteHandle::revert_to].
+pub fn revert_to_async(
+    mut writer: WriteHandle,
+    block_number: u64,
+) -> Offloaded<(WriteHandle, Result<RevertReport, RevertError>)> {
+    offload(move || {
+        let result = writer.revert_to(block_number);
+        (writer, result)
+    })
+}
+
+/// See [get_storage_proof].
+pub fn get_storage_proof_async(
+    tries: StateTries,
+    contract_address: ContractAddress,
+    keys: Vec<StorageKey>,
+    block_number: u64,
+    config: Arc<ChainConfig>,
+) -> Offloaded<Result<StorageProof, StorageProofError>> {
+    offload(move || get_storage_proof(&tries, &contract_address, &keys, block_number, &config))
+}
+
+/// See [try_calculate_tx_and_event_commitments].
+#[cfg(feature = "pedersen")]
+pub fn try_calculate_tx_and_event_commitments_async(
+    transactions: Vec<Transaction>,
+    events: Vec<Event>,
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+) -> Offloaded<Result<(Felt252Wrapper, Felt252Wrapper), CommitmentError>> {
+    offload(move || try_calculate_tx_and_event_commitments(&transactions, &events, chain_id, block_number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_commits() {
+        use std::sync::Arc;
+
+        use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+        use starknet_api::hash::StarkFelt;
+        use starknet_api::state::StorageKey;
+
+        use rayon::ThreadPoolBuilder;
+
+        use crate::mpts::deoxys::backend::MemoryBackend;
+        use crate::mpts::deoxys::runtime::Execution;
+
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let execution = Execution { pool: Some(pool), ..Default::default() };
+        let writer = || {
+            let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+            tries.configure(execution.clone());
+            WriteHandle::acquire(tries).unwrap()
+        };
+
+        let csd = |block_number: u64| {
+            let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+            let key = StorageKey(PatriciaKey(StarkFelt::from(block_number)));
+            CommitmentStateDiff {
+                address_to_class_hash: [(contract_address, ClassHash(StarkFelt::from(0x12_u64)))].into_iter().collect(),
+                address_to_nonce: Default::default(),
+                storage_updates: [(contract_address, [(key, StarkFelt::ONE)].into_iter().collect())]
+                    .into_iter()
+                    .collect(),
+                class_hash_to_compiled_class_hash: Default::default(),
+            }
+        };
+        // The commits of two chains share the only pool thread, neither of them holds it while
+        // waiting for the other
+        let chain_config = Arc::new(ChainConfig::default());
+        let first = try_update_state_root_async(writer(), csd(1), 1, Arc::clone(&chain_config));
+        let second = try_update_state_root_async(writer(), csd(2), 2, chain_config);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let _ = sender.send(runtime.block_on(async { (first.await, second.await) }));
+        });
+        let (first, second) =
+            receiver.recv_timeout(std::time::Duration::from_secs(60)).expect("Concurrent commits deadlocked");
+        let ((first_writer, first), (_, second)) = (first, second);
+        assert_ne!(first.unwrap(), second.unwrap());
+        // The handle is given back, pinned reads see the block it committed
+        assert_eq!(first_writer.reader().unwrap().block_number(), 1);
+    }
+
+    #[test]
+    fn test_offload() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        assert_eq!(runtime.block_on(offload(|| 1 + 1)), 2);
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| runtime.block_on(offload(|| panic!("commit failed")))));
+        assert!(panicked.is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use mc_db::storage_handler::{self, DeoxysStorageError};
+
+use super::engine::StateTries;
+use super::error::{CommitError, ErrorContext, ResultExt, TrieError};
+use super::historical::state_root_at;
+use super::quarantine::quarantine;
+use super::squash::empty_storage_tracker;
+
+/// The tries which are committed as part of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Trie {
+    ContractStorage,
+    Contracts,
+    Classes,
+}
+
+impl Trie {
+    pub const ALL: [Trie; 3] = [Trie::ContractStorage, Trie::Contracts, Trie::Classes];
+
+    fn revert_to(&self, block_number: u64) -> Result<(), DeoxysStorageError> {
+        match self {
+            Trie::ContractStorage => storage_handler::contract_storage_trie_mut().revert_to(block_number),
+            Trie::Contracts => storage_handler::contract_trie_mut().revert_to(block_number),
+            Trie::Classes => storage_handler::class_trie_mut().revert_to(block_number),
+        }
+    }
+}
+
+/// Reverts every trie to the state right after `block_number`, dropping their uncommitted changes.
+///
+/// The tries are reverted one after the other: if one of them fails to, the tries are left at
+/// different blocks and must be restored from a snapshot.
+pub(crate) fn revert_tries(tries: &StateTries, block_number: u64) -> Result<(), TrieError> {
+    if let Some(mut engine) = tries.engine() {
+        return engine.revert_to(block_number);
+    }
+    for trie in Trie::ALL {
+        trie.revert_to(block_number).context(|| ErrorContext::block(block_number).trie(trie))?;
+    }
+    Ok(())
+}
+
+/// Returns the latest block committed to the tries, if `block_number` was committed and its
+/// version of the tries was not pruned.
+///
+/// The tries are read rather than the [root registry](super::roots::root_registry), which does not
+/// survive restarts: the blocks after `block_number` are walked until one was not committed.
+pub(crate) fn latest_block_since(tries: &StateTries, block_number: u64) -> Result<Option<u64>, TrieError> {
+    if let Some(engine) = tries.engine() {
+        return Ok(engine.latest().filter(|latest| (engine.horizon()..=*latest).contains(&block_number)));
+    }
+    if state_root_at(block_number)?.is_none() {
+        return Ok(None);
+    }
+    let mut latest = block_number;
+    while state_root_at(latest + 1)?.is_some() {
+        latest += 1;
+    }
+    Ok(Some(latest))
+}
+
+/// Rolls every trie back to the state right after `block_number - 1`, along with what the block
+/// recorded while its tries were updated: the contracts it [quarantined](super::quarantine) and
+/// the contracts whose storage it emptied.
+///
+/// When the tries are those of an [engine](super::engine::CommitmentEngine), the tries and the
+/// metadata of a block are written by a single backend commit: the staged writes are dropped and
+/// the backend is left untouched. The node's database offers no such batch: the contract storage
+/// tries, the contracts trie and the classes trie are each committed by their own handler, so a
+/// failure in one of the sub-commits of a block can leave the others committed. Rolling all of them
+/// back (including those which did not commit, to drop their uncommitted changes) restores a state
+/// where the block was never applied, so that it can be retried.
+///
+/// The root registry and the indexes fed from the committed blocks are only written once a block
+/// is fully committed, a failed block never reaches them.
+///
+/// # Arguments
+///
+/// * `tries`        - The tries the block was committed to.
+/// * `block_number` - The block whose commit failed.
+/// * `error`        - The error which made the commit fail.
+///
+/// # Returns
+///
+/// `error`, or [CommitError::Rollback] if the tries could not be rolled back.
+pub(crate) fn rollback_block(tries: &StateTries, block_number: u64, error: TrieError) -> CommitError {
+    quarantine(tries).rollback(block_number);
+    // Contracts whose storage the block filled again are picked up on their next storage update
+    empty_storage_tracker(tries).empty_since.retain(|_, since| *since < block_number);
+
+    // Tries committed to a backend are only durable once the whole block is, it is enough to drop
+    // what was staged. The backend is left as it was if the tries cannot be reopened over it.
+    if let Some(mut engine) = tries.engine() {
+        return match engine.discard() {
+            Ok(()) => CommitError::Trie(error),
+            Err(_) => CommitError::Rollback { block_number, cause: error, rollback: None },
+        };
+    }
+
+    // There is no committed state to go back to before genesis
+    let Some(previous_block) = block_number.checked_sub(1) else {
+        return CommitError::Rollback { block_number, cause: error, rollback: None };
+    };
+
+    match revert_tries(tries, previous_block) {
+        Ok(()) => CommitError::Trie(error),
+        Err(rollback) => CommitError::Rollback { block_number, cause: error, rollback: Some(rollback) },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use bonsai_trie::databases::HashMapDb;
+    use bonsai_trie::id::{BasicId, BasicIdBuilder};
+    use bonsai_trie::{BonsaiStorage, BonsaiStorageConfig};
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+    use starknet_types_core::felt::Felt;
+    use starknet_types_core::hash::Pedersen;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::{BackendError, MemoryBackend};
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::contracts::contract_trie_root;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+    use crate::mpts::deoxys::proof::felt_to_path;
+    use crate::mpts::deoxys::roots::root_registry;
+
+    type Storage = BonsaiStorage<BasicId, HashMapDb<BasicId>, Pedersen>;
+
+    fn storage() -> Storage {
+        BonsaiStorage::new(HashMapDb::<BasicId>::default(), BonsaiStorageConfig::default()).unwrap()
+    }
+
+    fn insert(storage: &mut Storage, key: u64, value: u64) {
+        storage.insert(b"trie", &felt_to_path(&Felt::from(key)), &Felt::from(value)).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_after_partial_commit() {
+        let mut id_builder = BasicIdBuilder::new();
+        let mut storage_trie = storage();
+
+        let block_0 = id_builder.new_id();
+        insert(&mut storage_trie, 1, 10);
+        storage_trie.commit(block_0).unwrap();
+        let root = storage_trie.root_hash(b"trie").unwrap();
+
+        // block 1 fails after the storage trie was committed, but before the other tries were
+        let block_1 = id_builder.new_id();
+        insert(&mut storage_trie, 2, 20);
+        storage_trie.commit(block_1).unwrap();
+        assert_ne!(storage_trie.root_hash(b"trie").unwrap(), root);
+
+        storage_trie.revert_to(block_0, block_1).unwrap();
+        assert_eq!(storage_trie.root_hash(b"trie").unwrap(), root);
+    }
+
+    #[test]
+    fn test_rollback_block() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        let address = |value: u64| ContractAddress(PatriciaKey(StarkFelt::from(value)));
+        let csd = |value: u64| CommitmentStateDiff {
+            address_to_class_hash: [(address(1), ClassHash(StarkFelt::ONE))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(
+                address(1),
+                [(StorageKey(PatriciaKey(StarkFelt::ONE)), StarkFelt::from(value))].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        let state_root = try_update_state_root(&tries, csd(10), 1, &config).unwrap();
+        quarantine(&tries).add(address(2), 1, "corrupted storage trie");
+
+        // Block 2 fails once its contracts were updated and the contracts it touched recorded
+        contract_trie_root(&tries, &csd(20), 2, &config).unwrap();
+        quarantine(&tries).add(address(3), 2, "corrupted storage trie");
+        quarantine(&tries).skip(&address(2), 2, &csd(20));
+        empty_storage_tracker(&tries).record(address(4), true, 2);
+        let error = rollback_block(&tries, 2, BackendError::Io("disk full".to_string()).into());
+        assert!(matches!(error, CommitError::Trie(TrieError::Backend(BackendError::Io(_)))));
+
+        {
+            let engine = tries.engine().unwrap();
+            assert_eq!(engine.latest(), Some(1));
+            assert_eq!(engine.state_root(&config).unwrap(), state_root);
+        }
+        {
+            let quarantine = quarantine(&tries);
+            assert!(!quarantine.contains(&address(3)));
+            assert!(quarantine.get(&address(2)).unwrap().skipped.is_empty());
+        }
+        assert_eq!(empty_storage_tracker(&tries).empty_since(&address(4)), None);
+
+        // The block is then committed from scratch
+        quarantine(&tries).release(&address(2));
+        assert_ne!(try_update_state_root(&tries, csd(20), 2, &config).unwrap(), state_root);
+    }
+}
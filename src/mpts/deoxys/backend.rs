@@ -0,0 +1,941 @@
+//! Storage backends the state tries can be committed to.
+//!
+//! The [tries of the node's database](super::engine::StateTries::node_db) are stored by the node
+//! itself. A [StarkrootBackend] is a plain versioned key-value store which an
+//! [engine](super::engine::CommitmentEngine) builds the tries on top of instead, so that tests and
+//! other nodes can embed the commitment logic without any global state:
+//!
+//! * [MemoryBackend] keeps everything in memory, for tests and stateless tooling.
+//! * [OverlayBackend] reads through to another backend and keeps its own writes in memory, so that
+//!   blocks can be committed speculatively on top of a database which is never written to.
+//! * `RocksDbBackend` (with the `rocksdb` feature) persists to a RocksDB database.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+use bonsai_trie::id::{BasicId, Id};
+use bonsai_trie::{BonsaiDatabase, BonsaiPersistentDatabase, DBError, DatabaseKey};
+
+use super::atomic::Trie;
+use super::checksum::{seal, unseal, CorruptionError};
+use super::compression::{Compression, TrieCompression};
+use super::stats::TrieWrites;
+
+/// A column of a backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    /// The nodes of a trie, along with the metadata the trie keeps about them.
+    Trie(Trie),
+    /// The class hash of each contract, as committed to by its leaf.
+    ClassHashes,
+    /// The nonce of each contract, as committed to by its leaf.
+    Nonces,
+    /// What the [engine](super::engine::CommitmentEngine) knows about the tries, ie: the latest
+    /// block committed.
+    Metadata,
+}
+
+impl Column {
+    pub const ALL: [Column; 6] = [
+        Column::Trie(Trie::ContractStorage),
+        Column::Trie(Trie::Contracts),
+        Column::Trie(Trie::Classes),
+        Column::ClassHashes,
+        Column::Nonces,
+        Column::Metadata,
+    ];
+
+    /// Name of the column, ie: of the RocksDB column family holding it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Trie(Trie::ContractStorage) => "contract_storage_trie",
+            Column::Trie(Trie::Contracts) => "contracts_trie",
+            Column::Trie(Trie::Classes) => "classes_trie",
+            Column::ClassHashes => "contract_class_hashes",
+            Column::Nonces => "contract_nonces",
+            Column::Metadata => "engine_metadata",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BackendError {
+    #[error("backend I/O failed: {0}")]
+    Io(String),
+    #[error("backend is read-only")]
+    ReadOnly,
+    #[error("block {0} was not committed to the backend, or its snapshot was pruned")]
+    NoSnapshot(u64),
+    #[error("trie failed: {0}")]
+    Trie(String),
+    #[error(transparent)]
+    Corrupted(#[from] CorruptionError),
+}
+
+impl DBError for BackendError {}
+
+/// A versioned key-value store the state tries are stored in.
+///
+/// Writes are staged until [commit](StarkrootBackend::commit), which makes them durable as the
+/// state of a block. Staged writes are visible to the reads of the same backend, and are kept staged
+/// if the commit fails so that it can be retried or the writes [discarded](StarkrootBackend::discard).
+pub trait StarkrootBackend: Send + Sync {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError>;
+
+    /// Stages a write, `None` deletes the key.
+    fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError>;
+
+    /// Returns the entries whose key starts with `prefix`, in ascending key order.
+    fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError>;
+
+    /// Returns the entries whose key is in `start..end`, in ascending key order. Only the entries in
+    /// the range are read, however many keys the column holds.
+    fn scan_range(&self, column: Column, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError>;
+
+    /// Makes the staged writes durable as the state right after `block_number`.
+    ///
+    /// Fails only if the writes were not made durable, as callers roll the block back and retry it.
+    fn commit(&self, block_number: u64) -> Result<(), BackendError>;
+
+    /// Drops the staged writes, going back to the state of the last commit.
+    fn discard(&self);
+
+    /// Returns a read-only view of the state right after `block_number`.
+    fn snapshot(&self, block_number: u64) -> Result<Arc<dyn StarkrootBackend>, BackendError>;
+
+    /// Rewrites the committed entries whose key starts with `prefix` so that they are laid out
+    /// contiguously, reclaiming the space of the entries deleted since. This is a hint: backends
+    /// without an on-disk layout do nothing.
+    fn compact(&self, _column: Column, _prefix: &[u8]) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Forgets the snapshots of the blocks before `block_number`. Backends without snapshots do
+    /// nothing.
+    fn prune_snapshots_before(&self, _block_number: u64) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+/// Handle over a backend, shared by the tries stored in it.
+pub type Backend = Arc<dyn StarkrootBackend>;
+
+/// Writes staged in memory, `None` for deletions.
+#[derive(Debug, Clone, Default)]
+pub struct WriteSet {
+    columns: HashMap<Column, BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl WriteSet {
+    fn get(&self, column: Column, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.columns.get(&column).and_then(|entries| entries.get(key)).cloned()
+    }
+
+    fn put(&mut self, column: Column, key: &[u8], value: Option<&[u8]>) {
+        self.columns.entry(column).or_default().insert(key.to_vec(), value.map(<[u8]>::to_vec));
+    }
+
+    /// Applies the staged writes of `column` with a key in `range` over `entries`.
+    fn overlay(&self, column: Column, range: KeyRange, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let Some(staged) = self.columns.get(&column) else {
+            return entries;
+        };
+        let mut entries: BTreeMap<_, _> = entries.into_iter().collect();
+        for (key, value) in staged.range(range) {
+            match value {
+                Some(value) => entries.insert(key.clone(), value.clone()),
+                None => entries.remove(key),
+            };
+        }
+        entries.into_iter().collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.columns.values().all(BTreeMap::is_empty)
+    }
+}
+
+type Entries = HashMap<Column, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// The first key past every key starting with `prefix`, `None` if there is none.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&u8::MAX) {
+        end.pop();
+    }
+    let last = end.last_mut()?;
+    *last += 1;
+    Some(end)
+}
+
+/// The keys starting with `prefix`.
+fn prefix_range(prefix: &[u8]) -> KeyRange {
+    (Bound::Included(prefix.to_vec()), prefix_end(prefix).map_or(Bound::Unbounded, Bound::Excluded))
+}
+
+/// The keys in `start..end`, empty if `end` is before `start`.
+fn key_range(start: &[u8], end: &[u8]) -> KeyRange {
+    (Bound::Included(start.to_vec()), Bound::Excluded(end.max(start).to_vec()))
+}
+
+fn scan(entries: &Entries, column: Column, range: KeyRange) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let Some(entries) = entries.get(&column) else {
+        return Vec::new();
+    };
+    entries.range(range).map(|(key, value)| (key.clone(), value.clone())).collect()
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    committed: Entries,
+    staged: WriteSet,
+    /// The committed state after each block, shared with the snapshots handed out.
+    snapshots: BTreeMap<u64, Arc<Entries>>,
+}
+
+/// Backend holding everything in memory, nothing is persisted.
+///
+/// Each commit keeps a full copy of the state for [snapshots](StarkrootBackend::snapshot), so this
+/// is meant for tests and short-lived tooling rather than for following a chain.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StarkrootBackend for MemoryBackend {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let state = self.state.lock().expect("Poisoned lock on memory backend");
+        if let Some(value) = state.staged.get(column, key) {
+            return Ok(value);
+        }
+        Ok(state.committed.get(&column).and_then(|entries| entries.get(key)).cloned())
+    }
+
+    fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError> {
+        self.state.lock().expect("Poisoned lock on memory backend").staged.put(column, key, value);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        let state = self.state.lock().expect("Poisoned lock on memory backend");
+        Ok(state.staged.overlay(column, prefix_range(prefix), scan(&state.committed, column, prefix_range(prefix))))
+    }
+
+    fn scan_range(&self, column: Column, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        let state = self.state.lock().expect("Poisoned lock on memory backend");
+        let entries = scan(&state.committed, column, key_range(start, end));
+        Ok(state.staged.overlay(column, key_range(start, end), entries))
+    }
+
+    fn commit(&self, block_number: u64) -> Result<(), BackendError> {
+        let mut state = self.state.lock().expect("Poisoned lock on memory backend");
+        let staged = std::mem::take(&mut state.staged);
+        for (column, writes) in staged.columns {
+            let entries = state.committed.entry(column).or_default();
+            for (key, value) in writes {
+                match value {
+                    Some(value) => entries.insert(key, value),
+                    None => entries.remove(&key),
+                };
+            }
+        }
+        let snapshot = Arc::new(state.committed.clone());
+        state.snapshots.insert(block_number, snapshot);
+        Ok(())
+    }
+
+    fn discard(&self) {
+        self.state.lock().expect("Poisoned lock on memory backend").staged = WriteSet::default();
+    }
+
+    fn snapshot(&self, block_number: u64) -> Result<Arc<dyn StarkrootBackend>, BackendError> {
+        let state = self.state.lock().expect("Poisoned lock on memory backend");
+        let entries = state.snapshots.get(&block_number).ok_or(BackendError::NoSnapshot(block_number))?;
+        Ok(Arc::new(SnapshotBackend { entries: Arc::clone(entries) }))
+    }
+
+    fn prune_snapshots_before(&self, block_number: u64) -> Result<(), BackendError> {
+        let mut state = self.state.lock().expect("Poisoned lock on memory backend");
+        state.snapshots = state.snapshots.split_off(&block_number);
+        Ok(())
+    }
+}
+
+/// Read-only state of a [MemoryBackend] after a block.
+#[derive(Debug)]
+struct SnapshotBackend {
+    entries: Arc<Entries>,
+}
+
+impl StarkrootBackend for SnapshotBackend {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        Ok(self.entries.get(&column).and_then(|entries| entries.get(key)).cloned())
+    }
+
+    fn put(&self, _column: Column, _key: &[u8], _value: Option<&[u8]>) -> Result<(), BackendError> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        Ok(scan(&self.entries, column, prefix_range(prefix)))
+    }
+
+    fn scan_range(&self, column: Column, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        Ok(scan(&self.entries, column, key_range(start, end)))
+    }
+
+    fn commit(&self, _block_number: u64) -> Result<(), BackendError> {
+        Err(BackendError::ReadOnly)
+    }
+
+    fn discard(&self) {}
+
+    fn snapshot(&self, block_number: u64) -> Result<Arc<dyn StarkrootBackend>, BackendError> {
+        Err(BackendError::NoSnapshot(block_number))
+    }
+}
+
+/// Backend reading through to a base backend which it never writes to.
+///
+/// Writes, committed or not, only live in the overlay: this commits blocks speculatively on top of a
+/// database, ie: to compute the state root a block would produce without applying it. Snapshots are
+/// the base's, the blocks committed to the overlay have none.
+pub struct OverlayBackend {
+    base: Backend,
+    writes: Mutex<WriteSet>,
+}
+
+impl OverlayBackend {
+    pub fn new(base: Backend) -> Self {
+        Self { base, writes: Mutex::new(WriteSet::default()) }
+    }
+
+    /// Whether anything was written to the overlay.
+    pub fn is_dirty(&self) -> bool {
+        !self.writes.lock().expect("Poisoned lock on overlay backend").is_empty()
+    }
+}
+
+impl fmt::Debug for OverlayBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OverlayBackend").field("writes", &self.writes).finish_non_exhaustive()
+    }
+}
+
+impl StarkrootBackend for OverlayBackend {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        if let Some(value) = self.writes.lock().expect("Poisoned lock on overlay backend").get(column, key) {
+            return Ok(value);
+        }
+        self.base.get(column, key)
+    }
+
+    fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError> {
+        self.writes.lock().expect("Poisoned lock on overlay backend").put(column, key, value);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        let entries = self.base.scan_prefix(column, prefix)?;
+        let writes = self.writes.lock().expect("Poisoned lock on overlay backend");
+        Ok(writes.overlay(column, prefix_range(prefix), entries))
+    }
+
+    fn scan_range(&self, column: Column, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        let entries = self.base.scan_range(column, start, end)?;
+        let writes = self.writes.lock().expect("Poisoned lock on overlay backend");
+        Ok(writes.overlay(column, key_range(start, end), entries))
+    }
+
+    fn commit(&self, _block_number: u64) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Forgets every write, going back to the state of the base.
+    fn discard(&self) {
+        *self.writes.lock().expect("Poisoned lock on overlay backend") = WriteSet::default();
+    }
+
+    fn snapshot(&self, block_number: u64) -> Result<Arc<dyn StarkrootBackend>, BackendError> {
+        self.base.snapshot(block_number)
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+pub use self::rocks::RocksDbBackend;
+
+#[cfg(feature = "rocksdb")]
+mod rocks {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use rocksdb::checkpoint::Checkpoint;
+    use rocksdb::{ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch, DB};
+
+    use super::{key_range, prefix_end, prefix_range, BackendError, Column, StarkrootBackend, WriteSet};
+
+    fn io(e: impl ToString) -> BackendError {
+        BackendError::Io(e.to_string())
+    }
+
+    /// Backend persisting to a RocksDB database, with one column family per [Column].
+    ///
+    /// Staged writes are applied in a single write batch on commit, which also checkpoints the
+    /// database under `snapshots/` for [snapshots](StarkrootBackend::snapshot). Only the
+    /// `keep_snapshots` latest checkpoints are kept, checkpoints hard-link the database files so
+    /// they are cheap as long as they are not kept for long.
+    ///
+    /// The block is durable once its batch is written: a checkpoint failing afterwards does not fail
+    /// the commit, the block is left without a snapshot and the failure is reported by
+    /// [checkpoint_failure](RocksDbBackend::checkpoint_failure).
+    pub struct RocksDbBackend {
+        db: DB,
+        path: PathBuf,
+        keep_snapshots: usize,
+        staged: Mutex<WriteSet>,
+        read_only: bool,
+        checkpoint_failure: Mutex<Option<(u64, BackendError)>>,
+    }
+
+    impl RocksDbBackend {
+        /// Opens (or creates) the database at `path`.
+        pub fn open(path: impl AsRef<Path>, keep_snapshots: usize) -> Result<Self, BackendError> {
+            let path = path.as_ref().to_path_buf();
+            let mut options = Options::default();
+            options.create_if_missing(true);
+            options.create_missing_column_families(true);
+            let columns = Column::ALL.map(|column| ColumnFamilyDescriptor::new(column.name(), Options::default()));
+            let db = DB::open_cf_descriptors(&options, &path, columns).map_err(io)?;
+            Ok(Self {
+                db,
+                path,
+                keep_snapshots,
+                staged: Mutex::default(),
+                read_only: false,
+                checkpoint_failure: Mutex::default(),
+            })
+        }
+
+        fn open_read_only(path: &Path) -> Result<Self, BackendError> {
+            let names = Column::ALL.map(|column| column.name());
+            let db = DB::open_cf_for_read_only(&Options::default(), path, names, false).map_err(io)?;
+            Ok(Self {
+                db,
+                path: path.to_path_buf(),
+                keep_snapshots: 0,
+                staged: Mutex::default(),
+                read_only: true,
+                checkpoint_failure: Mutex::default(),
+            })
+        }
+
+        fn snapshot_path(&self, block_number: u64) -> PathBuf {
+            self.path.join("snapshots").join(block_number.to_string())
+        }
+
+        /// Returns the last block whose checkpoint failed once it was committed, and why. The block
+        /// has no [snapshot](StarkrootBackend::snapshot), the following ones are checkpointed again.
+        pub fn checkpoint_failure(&self) -> Option<(u64, BackendError)> {
+            self.checkpoint_failure.lock().expect("Poisoned lock on rocksdb backend").clone()
+        }
+
+        /// Checkpoints the database as the state right after `block_number`.
+        ///
+        /// The checkpoint is created aside and moved in place once complete, so that a failure
+        /// leaves the block without a snapshot rather than with a partial one.
+        fn checkpoint(&self, block_number: u64) -> Result<(), BackendError> {
+            let path = self.snapshot_path(block_number);
+            let partial = self.path.join("snapshots").join(format!("{block_number}.partial"));
+            // A block committed again replaces its checkpoint
+            for stale in [&path, &partial] {
+                if stale.exists() {
+                    fs::remove_dir_all(stale).map_err(io)?;
+                }
+            }
+            fs::create_dir_all(self.path.join("snapshots")).map_err(io)?;
+            Checkpoint::new(&self.db).map_err(io)?.create_checkpoint(&partial).map_err(io)?;
+            fs::rename(&partial, &path).map_err(io)?;
+
+            let blocks = self.snapshot_blocks()?;
+            for block_number in &blocks[..blocks.len().saturating_sub(self.keep_snapshots)] {
+                fs::remove_dir_all(self.snapshot_path(*block_number)).map_err(io)?;
+            }
+            Ok(())
+        }
+
+        /// The blocks which have a checkpoint, in ascending order.
+        fn snapshot_blocks(&self) -> Result<Vec<u64>, BackendError> {
+            let path = self.path.join("snapshots");
+            if !path.exists() {
+                return Ok(Vec::new());
+            }
+            let mut blocks = fs::read_dir(path)
+                .map_err(io)?
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+                .collect::<Vec<_>>();
+            blocks.sort_unstable();
+            Ok(blocks)
+        }
+    }
+
+    impl StarkrootBackend for RocksDbBackend {
+        fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            if let Some(value) = self.staged.lock().expect("Poisoned lock on rocksdb backend").get(column, key) {
+                return Ok(value);
+            }
+            let cf = self.db.cf_handle(column.name()).expect("Column families are created on open");
+            self.db.get_cf(cf, key).map_err(io)
+        }
+
+        fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError> {
+            if self.read_only {
+                return Err(BackendError::ReadOnly);
+            }
+            self.staged.lock().expect("Poisoned lock on rocksdb backend").put(column, key, value);
+            Ok(())
+        }
+
+        fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            let cf = self.db.cf_handle(column.name()).expect("Column families are created on open");
+            let mut entries = Vec::new();
+            for entry in self.db.iterator_cf(cf, IteratorMode::From(prefix, Direction::Forward)) {
+                let (key, value) = entry.map_err(io)?;
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                entries.push((key.into_vec(), value.into_vec()));
+            }
+            let staged = self.staged.lock().expect("Poisoned lock on rocksdb backend");
+            Ok(staged.overlay(column, prefix_range(prefix), entries))
+        }
+
+        fn scan_range(
+            &self,
+            column: Column,
+            start: &[u8],
+            end: &[u8],
+        ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            let cf = self.db.cf_handle(column.name()).expect("Column families are created on open");
+            let mut entries = Vec::new();
+            for entry in self.db.iterator_cf(cf, IteratorMode::From(start, Direction::Forward)) {
+                let (key, value) = entry.map_err(io)?;
+                if &*key >= end {
+                    break;
+                }
+                entries.push((key.into_vec(), value.into_vec()));
+            }
+            let staged = self.staged.lock().expect("Poisoned lock on rocksdb backend");
+            Ok(staged.overlay(column, key_range(start, end), entries))
+        }
+
+        fn commit(&self, block_number: u64) -> Result<(), BackendError> {
+            if self.read_only {
+                return Err(BackendError::ReadOnly);
+            }
+            // The writes stay staged until the batch is written, a failed commit can be retried
+            let mut staged = self.staged.lock().expect("Poisoned lock on rocksdb backend");
+            let mut batch = WriteBatch::default();
+            for (column, writes) in &staged.columns {
+                let cf = self.db.cf_handle(column.name()).expect("Column families are created on open");
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => batch.put_cf(cf, key, value),
+                        None => batch.delete_cf(cf, key),
+                    }
+                }
+            }
+            self.db.write(batch).map_err(io)?;
+            *staged = WriteSet::default();
+            drop(staged);
+            // The block is durable from here on: failing the commit would have it rolled back and
+            // retried, so a failed checkpoint only costs the snapshot of the block
+            if self.keep_snapshots > 0 {
+                if let Err(e) = self.checkpoint(block_number) {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(block_number, error = %e, "Failed to checkpoint the committed block");
+                    *self.checkpoint_failure.lock().expect("Poisoned lock on rocksdb backend") =
+                        Some((block_number, e));
+                }
+            }
+            Ok(())
+        }
+
+        fn discard(&self) {
+            *self.staged.lock().expect("Poisoned lock on rocksdb backend") = WriteSet::default();
+        }
+
+        fn snapshot(&self, block_number: u64) -> Result<Arc<dyn StarkrootBackend>, BackendError> {
+            let path = self.snapshot_path(block_number);
+            if !path.exists() {
+                return Err(BackendError::NoSnapshot(block_number));
+            }
+            Ok(Arc::new(Self::open_read_only(&path)?))
+        }
+
+        fn compact(&self, column: Column, prefix: &[u8]) -> Result<(), BackendError> {
+            let cf = self.db.cf_handle(column.name()).expect("Column families are created on open");
+            self.db.compact_range_cf(cf, Some(prefix), prefix_end(prefix).as_deref());
+            Ok(())
+        }
+
+        fn prune_snapshots_before(&self, block_number: u64) -> Result<(), BackendError> {
+            for pruned in self.snapshot_blocks()?.into_iter().take_while(|pruned| *pruned < block_number) {
+                fs::remove_dir_all(self.snapshot_path(pruned)).map_err(io)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Adapter storing a bonsai trie in a column of a [StarkrootBackend].
+///
+/// The tries of a block share the same backend: committing a trie only stages its writes, the
+/// [engine](super::engine::CommitmentEngine) [commits](StarkrootBackend::commit) the backend once
+/// every trie of the block is committed, so that the block is durable as a whole or not at all.
+pub struct BonsaiBackend {
+    backend: Backend,
+    trie: Trie,
+    column: Column,
+    compression: TrieCompression,
+    checksums: bool,
+    writes: Option<Arc<Mutex<TrieWrites>>>,
+}
+
+impl BonsaiBackend {
+    pub fn new(backend: Backend, trie: Trie) -> Self {
+        let compression = TrieCompression::default();
+        Self { backend, trie, column: Column::Trie(trie), compression, checksums: false, writes: None }
+    }
+
+    /// Compresses the payloads written to the trie. Payloads are readable whatever compression they
+    /// were written with, so it can be changed over existing data.
+    pub fn with_compression(self, compression: TrieCompression) -> Self {
+        Self { compression, ..self }
+    }
+
+    /// [Seals](seal) the payloads written to the trie with a checksum, which is validated when they
+    /// are read. Unlike compression, this cannot be changed over existing data.
+    pub fn with_checksums(self, checksums: bool) -> Self {
+        Self { checksums, ..self }
+    }
+
+    /// Counts the leaves and nodes bonsai writes to the trie into `writes`, which tries can share.
+    pub(crate) fn with_write_counts(self, writes: Arc<Mutex<TrieWrites>>) -> Self {
+        Self { writes: Some(writes), ..self }
+    }
+
+    /// Counts a write of bonsai, given the value it replaces.
+    fn count(&self, key: &DatabaseKey, previous: Option<&[u8]>, next: Option<&[u8]>) {
+        let Some(writes) = self.writes.as_ref() else {
+            return;
+        };
+        let mut writes = writes.lock().expect("Poisoned lock on trie writes");
+        match key {
+            DatabaseKey::Trie(_) => writes.new_nodes += u64::from(previous.is_none() && next.is_some()),
+            DatabaseKey::Flat(key) => writes.leaf(self.trie, key, previous, next),
+            DatabaseKey::TrieLog(_) => {}
+        }
+    }
+
+    fn encode(&self, value: &[u8]) -> Result<Vec<u8>, BackendError> {
+        let encoded = self.compression.encode(self.trie, value).map_err(|e| BackendError::Io(e.to_string()))?;
+        Ok(if self.checksums { seal(&encoded) } else { encoded })
+    }
+
+    fn decode(&self, key: &[u8], stored: Vec<u8>) -> Result<Vec<u8>, BackendError> {
+        let location = || format!("key 0x{}", key.iter().map(|byte| format!("{byte:02x}")).collect::<String>());
+        let encoded = if self.checksums { unseal(self.trie, location, &stored)? } else { &stored };
+        Compression::decode(encoded).map_err(|e| BackendError::Io(e.to_string()))
+    }
+
+    /// Bonsai keys are namespaced by their kind within the trie's column.
+    pub(crate) fn key(key: &DatabaseKey) -> Vec<u8> {
+        let (kind, key) = match key {
+            DatabaseKey::Trie(key) => (0, key),
+            DatabaseKey::Flat(key) => (1, key),
+            DatabaseKey::TrieLog(key) => (2, key),
+        };
+        let mut namespaced = Vec::with_capacity(key.len() + 1);
+        namespaced.push(kind);
+        namespaced.extend_from_slice(key);
+        namespaced
+    }
+}
+
+impl fmt::Debug for BonsaiBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BonsaiBackend").field("column", &self.column).finish_non_exhaustive()
+    }
+}
+
+impl BonsaiDatabase for BonsaiBackend {
+    type Batch = WriteSet;
+    type DatabaseError = BackendError;
+
+    fn create_batch(&self) -> Self::Batch {
+        WriteSet::default()
+    }
+
+    fn get(&self, key: &DatabaseKey) -> Result<Option<Vec<u8>>, Self::DatabaseError> {
+        let key = Self::key(key);
+        self.backend.get(self.column, &key)?.map(|value| self.decode(&key, value)).transpose()
+    }
+
+    fn get_by_prefix(&self, prefix: &DatabaseKey) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::DatabaseError> {
+        let entries = self.backend.scan_prefix(self.column, &Self::key(prefix))?;
+        // The namespace is not part of the keys bonsai knows about
+        entries.into_iter().map(|(key, value)| Ok((key[1..].to_vec(), self.decode(&key, value)?))).collect()
+    }
+
+    fn insert(
+        &mut self,
+        key: &DatabaseKey,
+        value: &[u8],
+        batch: Option<&mut Self::Batch>,
+    ) -> Result<Option<Vec<u8>>, Self::DatabaseError> {
+        let namespaced = Self::key(key);
+        let previous =
+            self.backend.get(self.column, &namespaced)?.map(|stored| self.decode(&namespaced, stored)).transpose()?;
+        self.count(key, previous.as_deref(), Some(value));
+        let value = self.encode(value)?;
+        match batch {
+            Some(batch) => batch.put(self.column, &namespaced, Some(&value)),
+            None => self.backend.put(self.column, &namespaced, Some(&value))?,
+        }
+        Ok(previous)
+    }
+
+    fn remove(
+        &mut self,
+        key: &DatabaseKey,
+        batch: Option<&mut Self::Batch>,
+    ) -> Result<Option<Vec<u8>>, Self::DatabaseError> {
+        let namespaced = Self::key(key);
+        let previous =
+            self.backend.get(self.column, &namespaced)?.map(|stored| self.decode(&namespaced, stored)).transpose()?;
+        self.count(key, previous.as_deref(), None);
+        match batch {
+            Some(batch) => batch.put(self.column, &namespaced, None),
+            None => self.backend.put(self.column, &namespaced, None)?,
+        }
+        Ok(previous)
+    }
+
+    fn contains(&self, key: &DatabaseKey) -> Result<bool, Self::DatabaseError> {
+        self.backend.get(self.column, &Self::key(key)).map(|value| value.is_some())
+    }
+
+    fn remove_by_prefix(&mut self, prefix: &DatabaseKey) -> Result<(), Self::DatabaseError> {
+        for (key, _) in self.backend.scan_prefix(self.column, &Self::key(prefix))? {
+            self.backend.put(self.column, &key, None)?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: Self::Batch) -> Result<(), Self::DatabaseError> {
+        for (column, writes) in batch.columns {
+            for (key, value) in writes {
+                self.backend.put(column, &key, value.as_deref())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tries are committed with the block number as their id.
+fn block_number(id: BasicId) -> u64 {
+    u64::from_be_bytes(id.to_bytes()[..].try_into().expect("Basic ids are u64"))
+}
+
+impl BonsaiPersistentDatabase<BasicId> for BonsaiBackend {
+    type Transaction = BonsaiBackend;
+    type DatabaseError = BackendError;
+
+    fn snapshot(&mut self, _id: BasicId) {
+        // The backend is committed once per block by the engine, which surfaces its errors
+    }
+
+    fn transaction(&self, id: BasicId) -> Option<Self::Transaction> {
+        let backend = self.backend.snapshot(block_number(id)).ok()?;
+        // Snapshots are read-only, their writes are not counted
+        Some(BonsaiBackend { backend: Arc::new(OverlayBackend::new(backend)), writes: None, ..*self })
+    }
+
+    fn merge(&mut self, _transaction: Self::Transaction) -> Result<(), Self::DatabaseError> {
+        Err(BackendError::ReadOnly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLUMN: Column = Column::Trie(Trie::Contracts);
+
+    #[test]
+    fn test_memory_backend() {
+        let backend = MemoryBackend::new();
+        backend.put(COLUMN, b"a1", Some(b"1")).unwrap();
+        backend.put(COLUMN, b"a2", Some(b"2")).unwrap();
+        backend.put(COLUMN, b"b1", Some(b"3")).unwrap();
+        assert_eq!(backend.get(COLUMN, b"a1").unwrap(), Some(b"1".to_vec()));
+        backend.commit(1).unwrap();
+
+        backend.put(COLUMN, b"a1", None).unwrap();
+        assert_eq!(backend.scan_prefix(COLUMN, b"a").unwrap(), vec![(b"a2".to_vec(), b"2".to_vec())]);
+        backend.put(COLUMN, b"a3", Some(b"4")).unwrap();
+        assert_eq!(
+            backend.scan_range(COLUMN, b"a2", b"b1").unwrap(),
+            vec![(b"a2".to_vec(), b"2".to_vec()), (b"a3".to_vec(), b"4".to_vec())]
+        );
+        assert_eq!(backend.scan_range(COLUMN, b"b1", b"a1").unwrap(), vec![]);
+        backend.commit(2).unwrap();
+
+        let snapshot = backend.snapshot(1).unwrap();
+        assert_eq!(snapshot.get(COLUMN, b"a1").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(snapshot.scan_range(COLUMN, b"a1", b"a2").unwrap(), vec![(b"a1".to_vec(), b"1".to_vec())]);
+        assert_eq!(snapshot.put(COLUMN, b"a1", None), Err(BackendError::ReadOnly));
+        assert_eq!(backend.get(COLUMN, b"a1").unwrap(), None);
+
+        backend.prune_snapshots_before(2).unwrap();
+        assert!(matches!(backend.snapshot(1), Err(BackendError::NoSnapshot(1))));
+    }
+
+    #[test]
+    fn test_overlay_backend() {
+        let base = Arc::new(MemoryBackend::new());
+        base.put(COLUMN, b"a1", Some(b"1")).unwrap();
+        base.commit(1).unwrap();
+
+        let overlay = OverlayBackend::new(base.clone());
+        overlay.put(COLUMN, b"a1", None).unwrap();
+        overlay.put(COLUMN, b"a2", Some(b"2")).unwrap();
+        overlay.commit(2).unwrap();
+
+        assert_eq!(overlay.scan_prefix(COLUMN, b"a").unwrap(), vec![(b"a2".to_vec(), b"2".to_vec())]);
+        assert_eq!(base.get(COLUMN, b"a1").unwrap(), Some(b"1".to_vec()));
+        assert!(overlay.is_dirty());
+
+        overlay.discard();
+        assert_eq!(overlay.get(COLUMN, b"a1").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_compressed_trie() {
+        use bitvec::prelude::{BitVec, Msb0};
+        use bonsai_trie::{BonsaiStorage, BonsaiStorageConfig};
+        use starknet_types_core::felt::Felt;
+        use starknet_types_core::hash::Pedersen;
+
+        let compression = TrieCompression { contracts: Compression::Zstd(3), ..Default::default() };
+        let root = |backend: &Backend, compression| {
+            let bonsai = BonsaiBackend::new(Arc::clone(backend), Trie::Contracts).with_compression(compression);
+            let mut trie = BonsaiStorage::<BasicId, _, Pedersen>::new(bonsai, BonsaiStorageConfig::default()).unwrap();
+            for key in 0x11_u64..0x13 {
+                let path = BitVec::<u8, Msb0>::from_slice(&Felt::from(key).to_bytes_be())[5..].to_owned();
+                trie.insert(b"trie", &path, &Felt::from(key)).unwrap();
+            }
+            trie.commit(BasicId::new(1)).unwrap();
+            backend.commit(1).unwrap();
+            trie.root_hash(b"trie").unwrap()
+        };
+
+        let (plain, compressed): (Backend, Backend) = (Arc::new(MemoryBackend::new()), Arc::new(MemoryBackend::new()));
+        assert_eq!(root(&plain, TrieCompression::default()), root(&compressed, compression));
+        let nodes = BonsaiBackend::key(&DatabaseKey::Trie(&[]));
+        let stored = compressed.scan_prefix(COLUMN, &nodes).unwrap();
+        assert!(!stored.is_empty() && stored.iter().all(|(_, value)| value.starts_with(b"SRZ")));
+
+        // Payloads are readable whatever compression they were written with
+        let reopened = BonsaiBackend::new(compressed, Trie::Contracts);
+        let (key, value) = plain.scan_prefix(COLUMN, &nodes).unwrap().remove(0);
+        assert_eq!(reopened.get(&DatabaseKey::Trie(&key[1..])).unwrap(), Some(value));
+    }
+
+    #[test]
+    fn test_checksummed_trie() {
+        let backend: Backend = Arc::new(MemoryBackend::new());
+        let mut bonsai = BonsaiBackend::new(Arc::clone(&backend), Trie::Contracts).with_checksums(true);
+        bonsai.insert(&DatabaseKey::Trie(b"node"), b"payload", None).unwrap();
+        assert_eq!(bonsai.get(&DatabaseKey::Trie(b"node")).unwrap(), Some(b"payload".to_vec()));
+
+        // A bit flipped on disk
+        let key = BonsaiBackend::key(&DatabaseKey::Trie(b"node"));
+        let mut stored = backend.get(COLUMN, &key).unwrap().unwrap();
+        stored[0] ^= 1;
+        backend.put(COLUMN, &key, Some(&stored)).unwrap();
+        assert!(matches!(
+            bonsai.get(&DatabaseKey::Trie(b"node")),
+            Err(BackendError::Corrupted(CorruptionError::ChecksumMismatch { .. }))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn test_rocksdb_backend() {
+        let path = std::env::temp_dir().join(format!("starkroot-rocksdb-{}", std::process::id()));
+        {
+            let backend = RocksDbBackend::open(&path, 2).unwrap();
+            backend.put(COLUMN, b"a1", Some(b"1")).unwrap();
+            backend.put(COLUMN, b"a2", Some(b"2")).unwrap();
+            assert_eq!(backend.get(COLUMN, b"a1").unwrap(), Some(b"1".to_vec()));
+            backend.commit(1).unwrap();
+
+            backend.put(COLUMN, b"a1", None).unwrap();
+            assert_eq!(backend.scan_prefix(COLUMN, b"a").unwrap(), vec![(b"a2".to_vec(), b"2".to_vec())]);
+            assert_eq!(backend.scan_range(COLUMN, b"a", b"a2").unwrap(), vec![]);
+            assert_eq!(backend.scan_range(COLUMN, b"a2", b"b").unwrap(), vec![(b"a2".to_vec(), b"2".to_vec())]);
+            backend.commit(2).unwrap();
+
+            backend.put(COLUMN, b"a3", Some(b"3")).unwrap();
+            backend.discard();
+            assert_eq!(backend.get(COLUMN, b"a3").unwrap(), None);
+
+            let snapshot = backend.snapshot(1).unwrap();
+            assert_eq!(snapshot.get(COLUMN, b"a1").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(snapshot.put(COLUMN, b"a1", None), Err(BackendError::ReadOnly));
+
+            // Only the latest `keep_snapshots` checkpoints are kept
+            backend.commit(3).unwrap();
+            assert!(matches!(backend.snapshot(1), Err(BackendError::NoSnapshot(1))));
+        }
+
+        // Committed writes are persisted
+        let backend = RocksDbBackend::open(&path, 2).unwrap();
+        assert_eq!(backend.scan_prefix(COLUMN, b"a").unwrap(), vec![(b"a2".to_vec(), b"2".to_vec())]);
+        assert_eq!(backend.checkpoint_failure(), None);
+        drop(backend);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn test_rocksdb_checkpoint_failure() {
+        let path = std::env::temp_dir().join(format!("starkroot-rocksdb-checkpoint-{}", std::process::id()));
+        let backend = RocksDbBackend::open(&path, 2).unwrap();
+        // The checkpoints cannot be created where a file is in the way
+        std::fs::write(path.join("snapshots"), b"").unwrap();
+
+        // The block is committed all the same, without a snapshot
+        backend.put(COLUMN, b"a1", Some(b"1")).unwrap();
+        backend.commit(1).unwrap();
+        assert!(matches!(backend.checkpoint_failure(), Some((1, BackendError::Io(_)))));
+        assert!(matches!(backend.snapshot(1), Err(BackendError::NoSnapshot(1))));
+        backend.discard();
+        assert_eq!(backend.get(COLUMN, b"a1").unwrap(), Some(b"1".to_vec()));
+
+        drop(backend);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Backfilling the history of tries bootstrapped from a snapshot.
+//!
+//! Tries [imported](super::trie_snapshot::import_snapshot) at block N cannot be reverted before N:
+//! the snapshot holds no trie log. A [Backfill] commits the blocks up to N to a lane of its own, an
+//! engine over a separate backend, while the node keeps committing the tip. Once the lane reached N
+//! with the snapshot's state root, [stitching](Backfill::stitch) it copies its trie logs to the
+//! node's backend and hands its snapshots over, so that the node's tries can be reverted to any of
+//! the backfilled blocks.
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use bonsai_trie::DatabaseKey;
+use mp_felt::Felt252Wrapper;
+
+use super::atomic::Trie;
+use super::backend::{BackendError, BonsaiBackend, Column};
+use super::config::ChainConfig;
+use super::engine::{trie_log_block, CommitmentEngine};
+use super::error::TrieError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackfillError {
+    #[error("expected block {expected}, received block {received}")]
+    OutOfOrder { expected: u64, received: u64 },
+    #[error("backfill reached block {reached:?}, it must reach block {anchor} to be stitched")]
+    Incomplete { reached: Option<u64>, anchor: u64 },
+    #[error("tries have versions from block {horizon}, the backfill was anchored at block {anchor}")]
+    NotAnchored { horizon: u64, anchor: u64 },
+    #[error("backfilled tries of block {anchor} have state root {computed:#x}, expected {expected:#x}")]
+    RootMismatch { anchor: u64, expected: Felt252Wrapper, computed: Felt252Wrapper },
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+/// What [stitching](Backfill::stitch) a backfill did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StitchReport {
+    /// The first block the tries can now be reverted to.
+    pub horizon: u64,
+    /// Number of trie log entries copied, across the three tries.
+    pub trie_log_entries: usize,
+}
+
+/// Historical blocks committed alongside the tip, see the [module](self) documentation.
+#[derive(Debug)]
+pub struct Backfill {
+    lane: CommitmentEngine,
+    anchor: u64,
+    anchor_root: Felt252Wrapper,
+}
+
+impl Backfill {
+    /// Starts backfilling the blocks up to `anchor` in `lane`.
+    ///
+    /// # Arguments
+    ///
+    /// * `lane`        - The tries the blocks are committed to, empty to backfill from genesis or
+    ///   opened at an older snapshot.
+    /// * `anchor`      - The block the node's tries were bootstrapped from.
+    /// * `anchor_root` - The state root of `anchor`, ie: the one the snapshot was checked against.
+    pub fn new(lane: CommitmentEngine, anchor: u64, anchor_root: Felt252Wrapper) -> Self {
+        Self { lane, anchor, anchor_root }
+    }
+
+    /// The next block to backfill.
+    pub fn next_block(&self) -> u64 {
+        self.lane.latest().map_or(0, |latest| latest + 1)
+    }
+
+    /// Whether every block up to the anchor was backfilled.
+    pub fn is_complete(&self) -> bool {
+        self.lane.latest() == Some(self.anchor)
+    }
+
+    /// Commits the next block to backfill, see [CommitmentEngine::update_state_root].
+    pub fn commit(
+        &mut self,
+        csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, BackfillError> {
+        let expected = self.next_block();
+        if block_number != expected || block_number > self.anchor {
+            return Err(BackfillError::OutOfOrder { expected, received: block_number });
+        }
+        Ok(self.lane.update_state_root(csd, block_number, config)?)
+    }
+
+    /// Extends the versions of `tries` back to the first backfilled block.
+    ///
+    /// `tries` must not have been pruned past the anchor, blocks may have been committed on top of it
+    /// though. The lane's backend is kept by `tries` from then on, for the snapshots of the
+    /// backfilled blocks.
+    pub fn stitch(self, tries: &mut CommitmentEngine, config: &ChainConfig) -> Result<StitchReport, BackfillError> {
+        if !self.is_complete() {
+            return Err(BackfillError::Incomplete { reached: self.lane.latest(), anchor: self.anchor });
+        }
+        let Some(latest) = tries.latest().filter(|_| tries.horizon() == self.anchor) else {
+            return Err(BackfillError::NotAnchored { horizon: tries.horizon(), anchor: self.anchor });
+        };
+        let computed = self.lane.state_root(config)?;
+        if computed != self.anchor_root {
+            return Err(BackfillError::RootMismatch { anchor: self.anchor, expected: self.anchor_root, computed });
+        }
+
+        // The trie logs of the tries up to the anchor, if any, are replaced by the lane's
+        let trie_logs = BonsaiBackend::key(&DatabaseKey::TrieLog(&[]));
+        let backfilled = |key: &[u8]| trie_log_block(key).is_some_and(|block_number| block_number <= self.anchor);
+        let mut trie_log_entries = 0;
+        for trie in Trie::ALL {
+            let column = Column::Trie(trie);
+            for (key, _) in tries.backend().scan_prefix(column, &trie_logs)? {
+                if backfilled(&key) {
+                    tries.backend().put(column, &key, None)?;
+                }
+            }
+            for (key, value) in self.lane.backend().scan_prefix(column, &trie_logs)? {
+                if backfilled(&key) {
+                    tries.backend().put(column, &key, Some(&value))?;
+                    trie_log_entries += 1;
+                }
+            }
+        }
+        tries.backend().commit(latest)?;
+
+        let horizon = self.lane.horizon();
+        tries.prepend_history(self.lane.backend().clone(), horizon);
+        Ok(StitchReport { horizon, trie_log_entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, Nonce, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::trie_snapshot::{export_snapshot, import_snapshot};
+
+    fn csd(block_number: u64) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(block_number % 2 + 0x11)));
+        let key = StorageKey(PatriciaKey(StarkFelt::from(block_number)));
+        CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::THREE))].into_iter().collect(),
+            address_to_nonce: [(contract_address, Nonce(StarkFelt::from(block_number)))].into_iter().collect(),
+            storage_updates: [(contract_address, [(key, StarkFelt::from(block_number + 1))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_backfill_stitch() {
+        let config = ChainConfig::default();
+        let mut chain = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let roots = (0..6)
+            .map(|block_number| chain.update_state_root(csd(block_number), block_number, &config).unwrap())
+            .collect::<Vec<_>>();
+
+        // The node starts from a snapshot of block 3 and follows the tip
+        let mut bytes = Vec::new();
+        export_snapshot(&chain, 3, &config, &mut bytes).unwrap();
+        let mut tries = import_snapshot(Arc::new(MemoryBackend::new()), &mut bytes.as_slice(), &config).unwrap();
+        tries.update_state_root(csd(4), 4, &config).unwrap();
+
+        let mut backfill = Backfill::new(CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap(), 3, roots[3]);
+        assert!(matches!(
+            backfill.commit(csd(1), 1, &config),
+            Err(BackfillError::OutOfOrder { expected: 0, received: 1 })
+        ));
+        for block_number in 0..3 {
+            assert_eq!(
+                backfill.commit(csd(block_number), block_number, &config).unwrap(),
+                roots[block_number as usize]
+            );
+        }
+        assert!(!backfill.is_complete());
+        backfill.commit(csd(3), 3, &config).unwrap();
+        assert!(backfill.is_complete());
+        // The tip keeps advancing while the history is backfilled
+        tries.update_state_root(csd(5), 5, &config).unwrap();
+
+        let report = backfill.stitch(&mut tries, &config).unwrap();
+        assert_eq!(report.horizon, 0);
+        assert!(report.trie_log_entries > 0);
+        tries.revert_to(1).unwrap();
+        assert_eq!(tries.state_root(&config).unwrap(), roots[1]);
+        assert_eq!(tries.update_state_root(csd(2), 2, &config).unwrap(), roots[2]);
+    }
+}
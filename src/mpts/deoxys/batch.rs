@@ -0,0 +1,127 @@
+use blockifier::state::cached_state::CommitmentStateDiff;
+use indexmap::IndexMap;
+use mp_felt::Felt252Wrapper;
+use starknet_core::types::StateUpdate;
+
+use super::config::ChainConfig;
+use super::error::{CommitError, DiffError};
+use super::handles::WriteHandle;
+use super::lib::build_commitment_state_diff;
+
+/// Merges the state diffs of consecutive blocks into the diff of the whole range.
+///
+/// Class hashes, nonces and storage values of later blocks override earlier ones, declared classes
+/// accumulate.
+///
+/// # Arguments
+///
+/// * `csds` - The state diffs, in block order.
+///
+/// # Returns
+///
+/// The state diff taking the state before the first block to the state after the last one.
+pub fn merge_commitment_state_diffs(csds: impl IntoIterator<Item = CommitmentStateDiff>) -> CommitmentStateDiff {
+    let mut merged = CommitmentStateDiff {
+        address_to_class_hash: IndexMap::new(),
+        address_to_nonce: IndexMap::new(),
+        storage_updates: IndexMap::new(),
+        class_hash_to_compiled_class_hash: IndexMap::new(),
+    };
+
+    for csd in csds {
+        merged.address_to_class_hash.extend(csd.address_to_class_hash);
+        merged.address_to_nonce.extend(csd.address_to_nonce);
+        merged.class_hash_to_compiled_class_hash.extend(csd.class_hash_to_compiled_class_hash);
+        for (contract_address, updates) in csd.storage_updates {
+            merged.storage_updates.entry(contract_address).or_default().extend(updates);
+        }
+    }
+
+    merged
+}
+
+/// Aggregates the changes of consecutive state updates into a single state diff.
+///
+/// See [build_commitment_state_diff] and [merge_commitment_state_diffs].
+///
+/// * `state_updates`: The state updates of consecutive blocks, in block order
+pub fn build_commitment_state_diff_batch(state_updates: &[StateUpdate]) -> Result<CommitmentStateDiff, DiffError> {
+    let csds = state_updates.iter().map(build_commitment_state_diff).collect::<Result<Vec<_>, _>>()?;
+    Ok(merge_commitment_state_diffs(csds))
+}
+
+/// Update the state commitment hash value with the state diffs of consecutive blocks at once.
+///
+/// The diffs are merged and the tries are committed once, at `last_block`, which is much faster than
+/// committing each block when backfilling history. The intermediate blocks are not committed on
+/// their own: their state roots are not known and the tries cannot be reverted to them.
+///
+/// # Arguments
+///
+/// * `writer` - The write handle of the state tries to update.
+/// * `csds` - The state diffs of the blocks `first_block..=last_block`, in block order.
+/// * `first_block` - The first block of the batch.
+/// * `last_block` - The last block of the batch.
+/// * `config` - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The state root after `last_block` as a `Felt252Wrapper`.
+pub fn update_state_root_batch(
+    writer: &mut WriteHandle,
+    csds: Vec<CommitmentStateDiff>,
+    first_block: u64,
+    last_block: u64,
+    config: &ChainConfig,
+) -> Result<Felt252Wrapper, CommitError> {
+    let blocks = last_block.checked_sub(first_block).map(|blocks| blocks + 1);
+    if blocks != Some(csds.len() as u64) {
+        return Err(CommitError::BatchRange { first_block, last_block, diffs: csds.len() });
+    }
+
+    writer.commit(merge_commitment_state_diffs(csds), last_block, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use starknet_api::core::{ClassHash, ContractAddress, Nonce, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::engine::StateTries;
+
+    fn csd(value: u64, key: u64) -> CommitmentStateDiff {
+        let address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::from(key)));
+        CommitmentStateDiff {
+            address_to_class_hash: [(address, ClassHash(StarkFelt::from(value)))].into_iter().collect(),
+            address_to_nonce: [(address, Nonce(StarkFelt::from(value)))].into_iter().collect(),
+            storage_updates: [(address, [(key, StarkFelt::from(value))].into_iter().collect())].into_iter().collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let merged = merge_commitment_state_diffs([csd(1, 1), csd(2, 2), csd(3, 1)]);
+        let address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+
+        assert_eq!(merged.address_to_class_hash[&address], ClassHash(StarkFelt::from(3_u64)));
+        assert_eq!(merged.address_to_nonce[&address], Nonce(StarkFelt::from(3_u64)));
+        let storage = &merged.storage_updates[&address];
+        assert_eq!(storage.len(), 2);
+        assert_eq!(storage[&StorageKey(PatriciaKey(StarkFelt::ONE))], StarkFelt::from(3_u64));
+        assert_eq!(storage[&StorageKey(PatriciaKey(StarkFelt::TWO))], StarkFelt::from(2_u64));
+    }
+
+    #[test]
+    fn test_batch_range() {
+        let mut writer = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+        let result = update_state_root_batch(&mut writer, vec![csd(1, 1)], 3, 4, &ChainConfig::default());
+        assert!(matches!(result, Err(CommitError::BatchRange { first_block: 3, last_block: 4, diffs: 1 })));
+    }
+}
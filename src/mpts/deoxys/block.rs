@@ -0,0 +1,403 @@
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+use starknet_api::transaction::{Event, Transaction};
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Pedersen, Poseidon, StarkHash};
+
+use super::config::{protocol_version, ChainConfig, CommitmentScheme};
+use super::consts::ProtocolConstants;
+use super::error::{CommitError, CommitmentError};
+use super::handles::WriteHandle;
+use super::lib::try_calculate_tx_and_event_commitments_with_scheme;
+use super::receipts::{try_memory_receipt_commitment, TransactionReceipt};
+use super::state_diff::calculate_state_diff_commitment_with_constants;
+
+/// Every commitment of a block.
+///
+/// Commitments which do not exist for the block's protocol version are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCommitments {
+    pub tx: Felt252Wrapper,
+    pub event: Felt252Wrapper,
+    /// Receipt commitment, since Starknet 0.13.2.
+    pub receipt: Option<Felt252Wrapper>,
+    /// State diff commitment, since Starknet 0.13.2.
+    pub state_diff: Option<Felt252Wrapper>,
+    pub state_root: Felt252Wrapper,
+    /// `None` in the commitments passed to [calculate_block_hash], which computes it.
+    pub block_hash: Option<Felt252Wrapper>,
+}
+
+/// The contents of a block committed to by [commit_block].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockBody<'a> {
+    pub transactions: &'a [Transaction],
+    pub events: &'a [Event],
+    /// The hash of the transaction which emitted each event, committed to since v0.13.2.
+    pub event_transaction_hashes: &'a [Felt],
+    /// Committed to since v0.13.2.
+    pub receipts: &'a [TransactionReceipt],
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommitBlockError {
+    #[error(transparent)]
+    Commitment(#[from] CommitmentError),
+    #[error(transparent)]
+    Commit(#[from] CommitError),
+    #[error(transparent)]
+    BlockHash(#[from] BlockHashError),
+}
+
+/// Computes all the commitments of a block, including its hash, and updates the state tries.
+///
+/// The transaction and event commitments follow the [scheme](CommitmentScheme::for_protocol_version)
+/// of `protocol_version`, the receipt and state diff commitments are only computed since v0.13.2.
+/// The state diff commitment does not cover Cairo 0 declarations, see
+/// [calculate_state_diff_commitment_with_deprecated](super::state_diff::calculate_state_diff_commitment_with_deprecated).
+///
+/// # Arguments
+///
+/// * `writer` - The write handle of the state tries to update
+/// * `body` - The transactions, events and receipts of the block
+/// * `csd` - The commitment state diff of the block
+/// * `chain_id` - The current chain id
+/// * `header` - The header of the block, carrying its number
+/// * `protocol_version` - The Starknet protocol version of the block
+/// * `config` - Chain-specific commitment rules
+///
+/// # Returns
+///
+/// The commitments of the block. The state tries are only updated once the transaction, event and
+/// receipt commitments were computed.
+pub fn commit_block(
+    writer: &mut WriteHandle,
+    body: &BlockBody,
+    csd: CommitmentStateDiff,
+    chain_id: Felt252Wrapper,
+    header: &BlockHeader,
+    protocol_version: &str,
+    config: &ChainConfig,
+) -> Result<BlockCommitments, CommitBlockError> {
+    let block_number = header.block_number;
+    let scheme = CommitmentScheme::for_protocol_version(protocol_version);
+    let since_v0_13_2 = BlockHashFormula::for_protocol_version(protocol_version) >= BlockHashFormula::V0;
+
+    let (commitments, receipt) = writer.tries().execution().install(|| {
+        rayon::join(
+            || {
+                try_calculate_tx_and_event_commitments_with_scheme(
+                    body.transactions,
+                    body.events,
+                    body.event_transaction_hashes,
+                    chain_id,
+                    block_number,
+                    scheme,
+                )
+            },
+            || since_v0_13_2.then(|| try_memory_receipt_commitment(body.receipts)).transpose(),
+        )
+    });
+    let ((tx, event), receipt) = (commitments?, receipt?);
+    let state_diff =
+        since_v0_13_2.then(|| calculate_state_diff_commitment_with_constants(&csd, &[], &config.constants));
+    let state_root = writer.commit(csd, block_number, config)?;
+
+    let mut commitments = BlockCommitments { tx, event, receipt, state_diff, state_root, block_hash: None };
+    let formula = BlockHashFormula::for_protocol_version(protocol_version);
+    commitments.block_hash =
+        Some(calculate_block_hash_with_formula(header, &commitments, protocol_version, formula, &config.constants)?);
+    Ok(commitments)
+}
+
+/// How the block hash is computed, which changed with the Starknet protocol versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BlockHashFormula {
+    /// Before v0.7.0: Pedersen hash of the header, with the chain id and without the sequencer
+    /// address, the timestamp nor the events.
+    Legacy,
+    /// From v0.7.0 to v0.13.1: Pedersen hash of the header.
+    Pedersen,
+    /// v0.13.2 and v0.13.3: Poseidon hash of the header prefixed with `STARKNET_BLOCK_HASH0`,
+    /// committing to the receipts, the state diff and the L1 gas prices.
+    V0,
+    /// Since v0.13.4: [V0](BlockHashFormula::V0) prefixed with `STARKNET_BLOCK_HASH1`, committing to
+    /// the L2 gas prices as well.
+    V1,
+}
+
+impl BlockHashFormula {
+    /// The formula of the blocks of a Starknet protocol version, ie: `"0.13.2"`.
+    ///
+    /// Block headers carry a version since v0.9.1: an empty version is treated as a version between
+    /// v0.7.0 and v0.9.0, the blocks before v0.7.0 must use [BlockHashFormula::Legacy] explicitly.
+    /// Other unparsable versions are treated as the latest version.
+    pub fn for_protocol_version(version: &str) -> Self {
+        if version.is_empty() {
+            return BlockHashFormula::Pedersen;
+        }
+        match protocol_version(version) {
+            Some(version) if version < (0, 7, 0) => BlockHashFormula::Legacy,
+            Some(version) if version < (0, 13, 2) => BlockHashFormula::Pedersen,
+            Some(version) if version < (0, 13, 4) => BlockHashFormula::V0,
+            _ => BlockHashFormula::V1,
+        }
+    }
+}
+
+/// The data availability mode of a block, committed to by the block hash since v0.13.2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum L1DataAvailabilityMode {
+    #[default]
+    Calldata,
+    Blob,
+}
+
+/// The gas prices of a block, committed to by the block hash since v0.13.2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GasPrices {
+    pub l1_gas_price_wei: u128,
+    pub l1_gas_price_fri: u128,
+    pub l1_data_gas_price_wei: u128,
+    pub l1_data_gas_price_fri: u128,
+    /// L2 gas prices, since v0.13.4.
+    pub l2_gas_price_wei: u128,
+    pub l2_gas_price_fri: u128,
+}
+
+/// The fields of a block header the block hash commits to, besides the [commitments](BlockCommitments).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub block_number: u64,
+    pub parent_block_hash: Felt,
+    pub sequencer_address: Felt,
+    pub timestamp: u64,
+    pub transaction_count: u64,
+    pub event_count: u64,
+    /// Number of entries of the state diff, since v0.13.2.
+    pub state_diff_length: u64,
+    pub l1_da_mode: L1DataAvailabilityMode,
+    pub gas_prices: GasPrices,
+    /// Only committed to before v0.7.0.
+    pub chain_id: Felt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BlockHashError {
+    #[error("the {0} commitment is required by the block hash since Starknet v0.13.2")]
+    MissingCommitment(&'static str),
+}
+
+fn felt(felt: &FieldElement) -> Felt {
+    Felt::from_bytes_be(&felt.to_bytes_be())
+}
+
+/// Packs the counts of the block and its data availability mode into a felt: 64 bits each for the
+/// transaction, event and state diff counts, then a bit set for blob data availability.
+fn concat_counts(header: &BlockHeader) -> Felt {
+    let mut bytes = [0u8; 32];
+    bytes[0..8].copy_from_slice(&header.transaction_count.to_be_bytes());
+    bytes[8..16].copy_from_slice(&header.event_count.to_be_bytes());
+    bytes[16..24].copy_from_slice(&header.state_diff_length.to_be_bytes());
+    if header.l1_da_mode == L1DataAvailabilityMode::Blob {
+        bytes[24] = 0x80;
+    }
+    Felt::from_bytes_be(&bytes)
+}
+
+/// The gas prices of the block, hashed since v0.13.4.
+fn gas_prices_hash(gas_prices: &GasPrices, constants: &ProtocolConstants) -> Felt {
+    Poseidon::hash_array(&[
+        felt(&constants.gas_prices_prefix),
+        Felt::from(gas_prices.l1_gas_price_wei),
+        Felt::from(gas_prices.l1_gas_price_fri),
+        Felt::from(gas_prices.l1_data_gas_price_wei),
+        Felt::from(gas_prices.l1_data_gas_price_fri),
+        Felt::from(gas_prices.l2_gas_price_wei),
+        Felt::from(gas_prices.l2_gas_price_fri),
+    ])
+}
+
+/// Computes the hash of a block, see [BlockHashFormula].
+///
+/// # Arguments
+///
+/// * `header` - The header fields of the block
+/// * `commitments` - The commitments of the block, its [block hash](BlockCommitments::block_hash) is
+///   ignored
+/// * `protocol_version` - The Starknet protocol version of the block, see
+///   [BlockHashFormula::for_protocol_version]
+///
+/// # Returns
+///
+/// The block hash, or an error if the protocol version commits to a commitment which is missing.
+pub fn calculate_block_hash(
+    header: &BlockHeader,
+    commitments: &BlockCommitments,
+    protocol_version: &str,
+) -> Result<Felt252Wrapper, BlockHashError> {
+    let formula = BlockHashFormula::for_protocol_version(protocol_version);
+    calculate_block_hash_with_formula(header, commitments, protocol_version, formula, &ProtocolConstants::default())
+}
+
+/// [calculate_block_hash] with an explicit formula and domain separators, ie: for the blocks before
+/// v0.7.0 or for chains overriding the [protocol constants](ProtocolConstants).
+pub fn calculate_block_hash_with_formula(
+    header: &BlockHeader,
+    commitments: &BlockCommitments,
+    protocol_version: &str,
+    formula: BlockHashFormula,
+    constants: &ProtocolConstants,
+) -> Result<Felt252Wrapper, BlockHashError> {
+    let (state_root, tx, event) =
+        (Felt::from(commitments.state_root), Felt::from(commitments.tx), Felt::from(commitments.event));
+    let hash = match formula {
+        BlockHashFormula::Legacy => Pedersen::hash_array(&[
+            Felt::from(header.block_number),
+            state_root,
+            Felt::ZERO,
+            Felt::ZERO,
+            Felt::from(header.transaction_count),
+            tx,
+            Felt::ZERO,
+            Felt::ZERO,
+            Felt::ZERO,
+            Felt::ZERO,
+            header.chain_id,
+            header.parent_block_hash,
+        ]),
+        BlockHashFormula::Pedersen => Pedersen::hash_array(&[
+            Felt::from(header.block_number),
+            state_root,
+            header.sequencer_address,
+            Felt::from(header.timestamp),
+            Felt::from(header.transaction_count),
+            tx,
+            Felt::from(header.event_count),
+            event,
+            Felt::ZERO,
+            Felt::ZERO,
+            header.parent_block_hash,
+        ]),
+        BlockHashFormula::V0 | BlockHashFormula::V1 => {
+            let receipt = commitments.receipt.ok_or(BlockHashError::MissingCommitment("receipt"))?;
+            let state_diff = commitments.state_diff.ok_or(BlockHashError::MissingCommitment("state diff"))?;
+            let prefix = if formula == BlockHashFormula::V0 {
+                constants.block_hash_v0_prefix
+            } else {
+                constants.block_hash_v1_prefix
+            };
+            let mut elements = vec![
+                felt(&prefix),
+                Felt::from(header.block_number),
+                state_root,
+                header.sequencer_address,
+                Felt::from(header.timestamp),
+                concat_counts(header),
+                Felt::from(state_diff),
+                tx,
+                event,
+                Felt::from(receipt),
+            ];
+            let gas_prices = &header.gas_prices;
+            if formula == BlockHashFormula::V0 {
+                elements.extend([
+                    Felt::from(gas_prices.l1_gas_price_wei),
+                    Felt::from(gas_prices.l1_gas_price_fri),
+                    Felt::from(gas_prices.l1_data_gas_price_wei),
+                    Felt::from(gas_prices.l1_data_gas_price_fri),
+                ]);
+            } else {
+                elements.push(gas_prices_hash(gas_prices, constants));
+            }
+            elements.extend([
+                Felt::from_bytes_be_slice(protocol_version.as_bytes()),
+                Felt::ZERO,
+                header.parent_block_hash,
+            ]);
+            Poseidon::hash_array(&elements)
+        }
+    };
+    Ok(Felt252Wrapper::from(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::engine::StateTries;
+    use crate::mpts::deoxys::state_diff::calculate_state_diff_commitment;
+
+    fn deploy(value: u64) -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: [(ContractAddress(PatriciaKey(StarkFelt::from(value))), ClassHash(StarkFelt::ONE))]
+                .into_iter()
+                .collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_commit_block() {
+        let mut writer = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+
+        let config = ChainConfig::default();
+        let chain_id = Felt252Wrapper::from(Felt::from(0x534e_u64));
+        let header = BlockHeader { block_number: 1, ..Default::default() };
+        let commitments =
+            commit_block(&mut writer, &BlockBody::default(), deploy(1), chain_id, &header, "0.13.1", &config).unwrap();
+        assert_eq!((commitments.receipt, commitments.state_diff), (None, None));
+        assert_eq!(commitments.block_hash, Some(calculate_block_hash(&header, &commitments, "0.13.1").unwrap()));
+
+        // The receipt and state diff of the block are committed to since v0.13.2
+        let header = BlockHeader { block_number: 2, ..header };
+        let commitments =
+            commit_block(&mut writer, &BlockBody::default(), deploy(2), chain_id, &header, "0.13.2", &config).unwrap();
+        assert_eq!(commitments.receipt, Some(Felt252Wrapper::ZERO));
+        assert_eq!(commitments.state_diff, Some(calculate_state_diff_commitment(&deploy(2))));
+        assert_eq!(commitments.block_hash, Some(calculate_block_hash(&header, &commitments, "0.13.2").unwrap()));
+    }
+
+    #[test]
+    fn test_block_hash_formulas() {
+        assert_eq!(BlockHashFormula::for_protocol_version("0.6.2"), BlockHashFormula::Legacy);
+        assert_eq!(BlockHashFormula::for_protocol_version(""), BlockHashFormula::Pedersen);
+        assert_eq!(BlockHashFormula::for_protocol_version("0.13.1.1"), BlockHashFormula::Pedersen);
+        assert_eq!(BlockHashFormula::for_protocol_version("0.13.3"), BlockHashFormula::V0);
+        assert_eq!(BlockHashFormula::for_protocol_version("0.13.4"), BlockHashFormula::V1);
+
+        let header = BlockHeader {
+            transaction_count: 4,
+            event_count: 3,
+            state_diff_length: 2,
+            l1_da_mode: L1DataAvailabilityMode::Blob,
+            ..Default::default()
+        };
+        assert_eq!(
+            concat_counts(&header),
+            Felt::from_hex("0x0000000000000004000000000000000300000000000000028000000000000000").unwrap()
+        );
+
+        let commitments = BlockCommitments {
+            tx: Felt252Wrapper::ONE,
+            event: Felt252Wrapper::TWO,
+            receipt: None,
+            state_diff: None,
+            state_root: Felt252Wrapper::from(Felt::THREE),
+            block_hash: None,
+        };
+        assert_eq!(
+            calculate_block_hash(&header, &commitments, "0.13.2"),
+            Err(BlockHashError::MissingCommitment("receipt"))
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use blockifier::execution::contract_class::ContractClass;
+use blockifier::state::errors::StateError;
+use blockifier::state::state_api::{StateReader, StateResult};
+use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+
+use super::state_reader::CommittedStateReader;
+
+/// Blockifier [StateReader] over a [CommittedStateReader].
+///
+/// This lets sequencers execute transactions against the exact state which produced the last
+/// committed root, instead of maintaining a second state database.
+///
+/// Contract classes are not part of the committed state, they have to be registered with
+/// [BlockifierStateAdapter::with_class] before executing transactions which use them.
+pub struct BlockifierStateAdapter<R> {
+    reader: R,
+    classes: HashMap<ClassHash, (ContractClass, CompiledClassHash)>,
+}
+
+impl<R: CommittedStateReader> BlockifierStateAdapter<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, classes: HashMap::new() }
+    }
+
+    /// Registers the compiled class for `class_hash`.
+    pub fn with_class(
+        mut self,
+        class_hash: ClassHash,
+        contract_class: ContractClass,
+        compiled_class_hash: CompiledClassHash,
+    ) -> Self {
+        self.classes.insert(class_hash, (contract_class, compiled_class_hash));
+        self
+    }
+
+    /// The underlying committed state reader.
+    pub fn reader(&self) -> &R {
+        &self.reader
+    }
+}
+
+fn read_error(error: impl Display) -> StateError {
+    StateError::StateReadError(error.to_string())
+}
+
+impl<R> StateReader for BlockifierStateAdapter<R>
+where
+    R: CommittedStateReader,
+    R::Error: Display,
+{
+    fn get_storage_at(&self, contract_address: ContractAddress, key: StorageKey) -> StateResult<StarkFelt> {
+        self.reader.get_storage(&contract_address, &key).map_err(read_error)
+    }
+
+    fn get_nonce_at(&self, contract_address: ContractAddress) -> StateResult<Nonce> {
+        self.reader.get_nonce(&contract_address).map_err(read_error)
+    }
+
+    fn get_class_hash_at(&self, contract_address: ContractAddress) -> StateResult<ClassHash> {
+        self.reader.get_class_hash(&contract_address).map_err(read_error)
+    }
+
+    fn get_compiled_contract_class(&self, class_hash: ClassHash) -> StateResult<ContractClass> {
+        self.classes
+            .get(&class_hash)
+            .map(|(contract_class, _)| contract_class.clone())
+            .ok_or(StateError::UndeclaredClassHash(class_hash))
+    }
+
+    fn get_compiled_class_hash(&self, class_hash: ClassHash) -> StateResult<CompiledClassHash> {
+        self.classes
+            .get(&class_hash)
+            .map(|(_, compiled_class_hash)| *compiled_class_hash)
+            .ok_or(StateError::UndeclaredClassHash(class_hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::execution::contract_class::ContractClassV0;
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use indexmap::IndexMap;
+    use starknet_api::core::PatriciaKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::engine::CommitmentEngine;
+    use crate::mpts::deoxys::state_reader::TrieStateReader;
+
+    fn address(n: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn key(n: u64) -> StorageKey {
+        StorageKey(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    /// A reader whose database is unavailable.
+    struct Unavailable;
+
+    impl CommittedStateReader for Unavailable {
+        type Error = &'static str;
+
+        fn block_number(&self) -> u64 {
+            0
+        }
+
+        fn get_storage(&self, _: &ContractAddress, _: &StorageKey) -> Result<StarkFelt, Self::Error> {
+            Err("unavailable")
+        }
+
+        fn get_nonce(&self, _: &ContractAddress) -> Result<Nonce, Self::Error> {
+            Err("unavailable")
+        }
+
+        fn get_class_hash(&self, _: &ContractAddress) -> Result<ClassHash, Self::Error> {
+            Err("unavailable")
+        }
+    }
+
+    #[test]
+    fn test_committed_state() {
+        let config = ChainConfig::default();
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: [(address(1), ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: [(address(1), Nonce(StarkFelt::THREE))].into_iter().collect(),
+            storage_updates: [(address(1), [(key(1), StarkFelt::from(10_u64))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        engine.update_state_root(csd, 0, &config).unwrap();
+
+        let state = BlockifierStateAdapter::new(TrieStateReader::at(&engine, 0).unwrap().unwrap());
+        assert_eq!(state.reader().block_number(), 0);
+        assert_eq!(state.get_storage_at(address(1), key(1)).unwrap(), StarkFelt::from(10_u64));
+        assert_eq!(state.get_storage_at(address(1), key(2)).unwrap(), StarkFelt::ZERO);
+        assert_eq!(state.get_nonce_at(address(1)).unwrap(), Nonce(StarkFelt::THREE));
+        assert_eq!(state.get_class_hash_at(address(1)).unwrap(), ClassHash(StarkFelt::TWO));
+        assert_eq!(state.get_class_hash_at(address(2)).unwrap(), ClassHash::default());
+    }
+
+    #[test]
+    fn test_classes_and_read_errors() {
+        let class_hash = ClassHash(StarkFelt::TWO);
+        let contract_class = ContractClass::V0(ContractClassV0::default());
+        let state = BlockifierStateAdapter::new(Unavailable).with_class(
+            class_hash,
+            contract_class.clone(),
+            CompiledClassHash(StarkFelt::THREE),
+        );
+
+        assert_eq!(state.get_compiled_contract_class(class_hash).unwrap(), contract_class);
+        assert_eq!(state.get_compiled_class_hash(class_hash).unwrap(), CompiledClassHash(StarkFelt::THREE));
+        let undeclared = ClassHash(StarkFelt::ONE);
+        assert!(matches!(
+            state.get_compiled_contract_class(undeclared),
+            Err(StateError::UndeclaredClassHash(class_hash)) if class_hash == undeclared
+        ));
+        assert!(matches!(
+            state.get_compiled_class_hash(undeclared),
+            Err(StateError::UndeclaredClassHash(class_hash)) if class_hash == undeclared
+        ));
+
+        // Read errors of the committed state are reported as such
+        assert!(matches!(
+            state.get_storage_at(address(1), key(1)),
+            Err(StateError::StateReadError(error)) if error == "unavailable"
+        ));
+        assert!(matches!(state.get_nonce_at(address(1)), Err(StateError::StateReadError(_))));
+        assert!(matches!(state.get_class_hash_at(address(1)), Err(StateError::StateReadError(_))));
+    }
+}
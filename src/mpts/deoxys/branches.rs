@@ -0,0 +1,307 @@
+//! Short-lived branches of the state tries, ie: the candidate tips of a chain during a reorg.
+//!
+//! Each branch forks the canonical tries at a committed ancestor block. Forking is cheap: the
+//! branch reads through to the canonical backend's [snapshot](StarkrootBackend::snapshot) of the
+//! ancestor and only keeps its own writes, in an [overlay](OverlayBackend). Once the fork choice is
+//! made, the winning branch is promoted onto the canonical tries and the others are discarded.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+
+use super::backend::{OverlayBackend, StarkrootBackend};
+use super::config::ChainConfig;
+use super::engine::CommitmentEngine;
+use super::error::TrieError;
+use super::lib::clone_commitment_state_diff;
+
+/// Identifier of a branch within a [BranchSet].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BranchId(u64);
+
+impl fmt::Display for BranchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BranchError {
+    #[error("unknown branch {0}, it was promoted or discarded")]
+    UnknownBranch(BranchId),
+    #[error("block {block_number} was not committed to the canonical tries, latest is {latest:?}")]
+    NotCommitted { block_number: u64, latest: Option<u64> },
+    #[error("block {block_number} does not follow the tip {tip} of branch {branch}")]
+    OutOfOrder { branch: BranchId, block_number: u64, tip: u64 },
+    #[error("branch {branch} led to state root {expected:#x}, promoting it led to {computed:#x}")]
+    PromotionMismatch { branch: BranchId, expected: Felt252Wrapper, computed: Felt252Wrapper },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+struct Branch {
+    /// The canonical block the branch forked at.
+    ancestor: u64,
+    engine: CommitmentEngine,
+    /// The blocks committed to the branch, replayed onto the canonical tries on promotion.
+    blocks: Vec<(u64, CommitmentStateDiff)>,
+    state_root: Option<Felt252Wrapper>,
+}
+
+impl Branch {
+    fn tip(&self) -> u64 {
+        self.blocks.last().map(|(block_number, _)| *block_number).unwrap_or(self.ancestor)
+    }
+}
+
+/// What [promoting](BranchSet::promote) a branch changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Promotion {
+    /// The canonical state root after the promotion.
+    pub state_root: Felt252Wrapper,
+    /// The canonical blocks which were reverted to make way for the branch.
+    pub reverted: u64,
+    /// The branches which forked at one of the reverted blocks, and were discarded with them.
+    pub discarded: Vec<BranchId>,
+}
+
+/// The canonical state tries and the branches forked from them.
+///
+/// Blocks are committed to the canonical tries with [canonical_mut](BranchSet::canonical_mut), or
+/// to a branch with [commit](BranchSet::commit). A branch never affects the canonical tries nor the
+/// other branches until it is [promoted](BranchSet::promote).
+pub struct BranchSet {
+    canonical: CommitmentEngine,
+    branches: BTreeMap<BranchId, Branch>,
+    next_id: u64,
+}
+
+impl fmt::Debug for BranchSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BranchSet")
+            .field("canonical", &self.canonical)
+            .field("branches", &self.branches.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl BranchSet {
+    pub fn new(canonical: CommitmentEngine) -> Self {
+        Self { canonical, branches: BTreeMap::new(), next_id: 0 }
+    }
+
+    pub fn canonical(&self) -> &CommitmentEngine {
+        &self.canonical
+    }
+
+    pub fn canonical_mut(&mut self) -> &mut CommitmentEngine {
+        &mut self.canonical
+    }
+
+    /// The branches, in creation order.
+    pub fn branches(&self) -> impl Iterator<Item = BranchId> + '_ {
+        self.branches.keys().copied()
+    }
+
+    /// Forks a new branch from the canonical state right after `ancestor`.
+    ///
+    /// `ancestor` must have been committed to the canonical tries and still have its snapshot in
+    /// the canonical backend.
+    pub fn fork(&mut self, ancestor: u64) -> Result<BranchId, BranchError> {
+        let latest = self.canonical.latest();
+        if latest.map_or(true, |latest| ancestor > latest) {
+            return Err(BranchError::NotCommitted { block_number: ancestor, latest });
+        }
+        let snapshot = self.canonical.backend().snapshot(ancestor).map_err(TrieError::from)?;
+        let engine = self.canonical.reopen(Arc::new(OverlayBackend::new(snapshot)))?;
+
+        let id = BranchId(self.next_id);
+        self.next_id += 1;
+        self.branches.insert(id, Branch { ancestor, engine, blocks: Vec::new(), state_root: None });
+        Ok(id)
+    }
+
+    /// The canonical block `branch` forked at.
+    pub fn ancestor(&self, branch: BranchId) -> Result<u64, BranchError> {
+        Ok(self.branch(branch)?.ancestor)
+    }
+
+    /// The latest block of `branch`, its ancestor if no block was committed to it yet.
+    pub fn tip(&self, branch: BranchId) -> Result<u64, BranchError> {
+        Ok(self.branch(branch)?.tip())
+    }
+
+    /// The tries of `branch`, ie: to read its state or generate proofs against its tip.
+    pub fn engine(&self, branch: BranchId) -> Result<&CommitmentEngine, BranchError> {
+        Ok(&self.branch(branch)?.engine)
+    }
+
+    /// Commits the next block of `branch`, which must come after its tip.
+    ///
+    /// # Returns
+    ///
+    /// The state root of the branch.
+    pub fn commit(
+        &mut self,
+        branch: BranchId,
+        csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, BranchError> {
+        let entry = self.branches.get_mut(&branch).ok_or(BranchError::UnknownBranch(branch))?;
+        let tip = entry.tip();
+        if block_number <= tip {
+            return Err(BranchError::OutOfOrder { branch, block_number, tip });
+        }
+
+        let state_root = entry.engine.update_state_root(clone_commitment_state_diff(&csd), block_number, config)?;
+        entry.blocks.push((block_number, csd));
+        entry.state_root = Some(state_root);
+        Ok(state_root)
+    }
+
+    /// Forgets `branch` and its blocks.
+    pub fn discard(&mut self, branch: BranchId) -> Result<(), BranchError> {
+        self.branches.remove(&branch).map(|_| ()).ok_or(BranchError::UnknownBranch(branch))
+    }
+
+    /// Makes `branch` canonical: the canonical tries are reverted to its ancestor, and its blocks
+    /// are committed to them.
+    ///
+    /// The other branches forked at or before the ancestor are kept, those forked after it are
+    /// discarded since the blocks they build on are no longer canonical. If the promotion fails,
+    /// the canonical tries are restored to their tip and the branch is kept.
+    pub fn promote(&mut self, branch: BranchId, config: &ChainConfig) -> Result<Promotion, BranchError> {
+        let ancestor = self.branch(branch)?.ancestor;
+        let latest = self.canonical.latest();
+        let Some((latest, reverted)) =
+            latest.and_then(|latest| latest.checked_sub(ancestor).map(|reverted| (latest, reverted)))
+        else {
+            // The canonical tries were reverted below the ancestor since the fork
+            return Err(BranchError::NotCommitted { block_number: ancestor, latest });
+        };
+        let tip = self.canonical.backend().snapshot(latest).map_err(TrieError::from)?;
+
+        let entry = self.branches.remove(&branch).ok_or(BranchError::UnknownBranch(branch))?;
+        let promoted = (|| -> Result<Felt252Wrapper, BranchError> {
+            if reverted > 0 {
+                self.canonical.revert_to(ancestor)?;
+            }
+            let mut computed = self.canonical.state_root(config)?;
+            for (block_number, csd) in entry.blocks.iter() {
+                computed = self.canonical.update_state_root(clone_commitment_state_diff(csd), *block_number, config)?;
+            }
+            match entry.state_root {
+                Some(expected) if expected != computed => {
+                    Err(BranchError::PromotionMismatch { branch, expected, computed })
+                }
+                _ => Ok(computed),
+            }
+        })();
+        let state_root = match promoted {
+            Ok(state_root) => state_root,
+            Err(e) => {
+                self.branches.insert(branch, entry);
+                self.canonical.restore(&tip, latest)?;
+                return Err(e);
+            }
+        };
+
+        let discarded = self
+            .branches
+            .iter()
+            .filter(|(_, branch)| branch.ancestor > ancestor)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in discarded.iter() {
+            self.branches.remove(id);
+        }
+        Ok(Promotion { state_root, reverted, discarded })
+    }
+
+    fn branch(&self, branch: BranchId) -> Result<&Branch, BranchError> {
+        self.branches.get(&branch).ok_or(BranchError::UnknownBranch(branch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+
+    fn csd(key: u64, value: u64) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::from(key)));
+        CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::THREE))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(contract_address, [(key, StarkFelt::from(value))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_branches() {
+        let config = ChainConfig::default();
+        let mut branches = BranchSet::new(CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap());
+        for block_number in 0..3 {
+            branches.canonical_mut().update_state_root(csd(block_number, 1), block_number, &config).unwrap();
+        }
+        let canonical_root = branches.canonical().state_root(&config).unwrap();
+        assert!(matches!(branches.fork(3), Err(BranchError::NotCommitted { block_number: 3, .. })));
+
+        // Two candidate tips from block 1, and one on top of the current tip
+        let (a, b, c) = (branches.fork(1).unwrap(), branches.fork(1).unwrap(), branches.fork(2).unwrap());
+        branches.commit(a, csd(2, 2), 2, &config).unwrap();
+        let root_a = branches.commit(a, csd(3, 2), 3, &config).unwrap();
+        let root_b = branches.commit(b, csd(2, 3), 2, &config).unwrap();
+        assert_ne!(root_a, root_b);
+        assert!(matches!(branches.commit(b, csd(2, 4), 2, &config), Err(BranchError::OutOfOrder { tip: 2, .. })));
+        assert_eq!(branches.canonical().state_root(&config).unwrap(), canonical_root);
+        assert_eq!(branches.tip(a).unwrap(), 3);
+
+        let promotion = branches.promote(a, &config).unwrap();
+        assert_eq!(promotion, Promotion { state_root: root_a, reverted: 1, discarded: vec![c] });
+        assert_eq!(branches.canonical().latest(), Some(3));
+        assert_eq!(branches.branches().collect::<Vec<_>>(), vec![b]);
+
+        branches.discard(b).unwrap();
+        assert!(matches!(branches.discard(b), Err(BranchError::UnknownBranch(_))));
+    }
+
+    #[test]
+    fn test_failed_promotion() {
+        let config = ChainConfig::default();
+        let mut branches = BranchSet::new(CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap());
+        for block_number in 0..3 {
+            branches.canonical_mut().update_state_root(csd(block_number, 1), block_number, &config).unwrap();
+        }
+        let canonical_root = branches.canonical().state_root(&config).unwrap();
+        let a = branches.fork(1).unwrap();
+        branches.commit(a, csd(2, 2), 2, &config).unwrap();
+
+        // A branch whose root the canonical tries cannot reproduce
+        branches.branches.get_mut(&a).unwrap().state_root = Some(Felt252Wrapper::ONE);
+        assert!(matches!(branches.promote(a, &config), Err(BranchError::PromotionMismatch { .. })));
+        assert_eq!(branches.canonical().latest(), Some(2));
+        assert_eq!(branches.canonical().state_root(&config).unwrap(), canonical_root);
+        assert_eq!(branches.tip(a).unwrap(), 2);
+        branches.canonical_mut().update_state_root(csd(3, 1), 3, &config).unwrap();
+
+        // A branch forked above the canonical tip, once reverted
+        let b = branches.fork(3).unwrap();
+        branches.canonical_mut().revert_to(2).unwrap();
+        assert!(matches!(branches.promote(b, &config), Err(BranchError::NotCommitted { block_number: 3, .. })));
+        assert_eq!(branches.tip(b).unwrap(), 3);
+    }
+}
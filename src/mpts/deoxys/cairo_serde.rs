@@ -0,0 +1,246 @@
+//! Decoding of felts into Cairo values, following the `Serde` layout of the Cairo core library.
+//!
+//! Storage lays out fixed-size values the same way: the members of a struct, or the limbs of a
+//! `u256`, sit at consecutive addresses from the variable's (see
+//! [storage_var_keys](super::storage_keys::storage_var_keys)). [CairoType::size] tells how many
+//! slots to read, and the [verified values](super::verified_read::VerifiedValue) of those slots
+//! decode to a typed value.
+
+use starknet_types_core::felt::Felt;
+
+use super::verified_read::VerifiedValue;
+
+/// Number of bytes in a full word of a `ByteArray`.
+const BYTES_PER_WORD: usize = 31;
+
+/// Schema of a Cairo value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CairoType {
+    Felt,
+    Bool,
+    /// An unsigned integer of the given number of bits, at most 128.
+    UInt(u32),
+    U256,
+    ContractAddress,
+    ByteArray,
+    /// An `Array<T>`, serialized as its length followed by its elements.
+    Array(Box<CairoType>),
+    /// The members of a struct, in declaration order.
+    Struct(Vec<(String, CairoType)>),
+}
+
+/// A decoded Cairo value.
+///
+/// `ByteArray`s decode to their raw bytes, which Cairo does not require to be UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CairoValue {
+    Felt(Felt),
+    Bool(bool),
+    UInt(u128),
+    U256 { low: u128, high: u128 },
+    ContractAddress(Felt),
+    ByteArray(Vec<u8>),
+    Array(Vec<CairoValue>),
+    Struct(Vec<(String, CairoValue)>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CairoSerdeError {
+    #[error("expected more felts to decode a {0}")]
+    Truncated(&'static str),
+    #[error("{0} felts left after decoding the value")]
+    TrailingFelts(usize),
+    #[error("{value:#x} is out of range for a {ty}")]
+    OutOfRange { ty: &'static str, value: Felt },
+    #[error("unsigned integers have at most 128 bits, got {0}")]
+    UnsupportedWidth(u32),
+}
+
+impl CairoType {
+    /// Number of felts the type spans, `None` for dynamically sized types.
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            CairoType::Felt | CairoType::Bool | CairoType::UInt(_) | CairoType::ContractAddress => Some(1),
+            CairoType::U256 => Some(2),
+            CairoType::ByteArray | CairoType::Array(_) => None,
+            CairoType::Struct(members) => members.iter().map(|(_, ty)| ty.size()).sum(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            CairoType::Felt => "felt252",
+            CairoType::Bool => "bool",
+            CairoType::UInt(_) => "unsigned integer",
+            CairoType::U256 => "u256",
+            CairoType::ContractAddress => "ContractAddress",
+            CairoType::ByteArray => "ByteArray",
+            CairoType::Array(_) => "Array",
+            CairoType::Struct(_) => "struct",
+        }
+    }
+}
+
+impl CairoValue {
+    /// The value as a string, if it is a UTF-8 `ByteArray`.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            CairoValue::ByteArray(bytes) => std::str::from_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+}
+
+fn next(felts: &mut &[Felt], ty: &'static str) -> Result<Felt, CairoSerdeError> {
+    let (first, rest) = felts.split_first().ok_or(CairoSerdeError::Truncated(ty))?;
+    *felts = rest;
+    Ok(*first)
+}
+
+/// Converts a felt to an integer of at most `bits` bits.
+fn uint(value: Felt, bits: u32, ty: &'static str) -> Result<u128, CairoSerdeError> {
+    let bytes = value.to_bytes_be();
+    let int = u128::from_be_bytes(bytes[16..].try_into().expect("16 bytes"));
+    if bytes[..16].iter().any(|byte| *byte != 0) || (bits < 128 && int >> bits != 0) {
+        return Err(CairoSerdeError::OutOfRange { ty, value });
+    }
+    Ok(int)
+}
+
+/// Converts a felt to a length, bounded by the felts left so that malformed lengths fail early.
+fn len(value: Felt, felts: &[Felt], ty: &'static str) -> Result<usize, CairoSerdeError> {
+    let len = uint(value, 64, ty)?;
+    if len > felts.len() as u128 {
+        return Err(CairoSerdeError::Truncated(ty));
+    }
+    Ok(len as usize)
+}
+
+/// The `len` last bytes of a felt, which must hold no more.
+fn word_bytes(value: Felt, len: usize) -> Result<Vec<u8>, CairoSerdeError> {
+    let bytes = value.to_bytes_be();
+    if bytes[..32 - len].iter().any(|byte| *byte != 0) {
+        return Err(CairoSerdeError::OutOfRange { ty: "ByteArray word", value });
+    }
+    Ok(bytes[32 - len..].to_vec())
+}
+
+/// Decodes a value from the front of `felts`, advancing it past the felts of the value.
+pub fn decode_prefix(ty: &CairoType, felts: &mut &[Felt]) -> Result<CairoValue, CairoSerdeError> {
+    let name = ty.name();
+    let value = match ty {
+        CairoType::Felt => CairoValue::Felt(next(felts, name)?),
+        CairoType::Bool => CairoValue::Bool(uint(next(felts, name)?, 1, name)? == 1),
+        CairoType::UInt(bits) if *bits > 128 => return Err(CairoSerdeError::UnsupportedWidth(*bits)),
+        CairoType::UInt(bits) => CairoValue::UInt(uint(next(felts, name)?, *bits, name)?),
+        CairoType::U256 => {
+            let low = uint(next(felts, name)?, 128, name)?;
+            let high = uint(next(felts, name)?, 128, name)?;
+            CairoValue::U256 { low, high }
+        }
+        CairoType::ContractAddress => CairoValue::ContractAddress(next(felts, name)?),
+        CairoType::ByteArray => {
+            let words = len(next(felts, name)?, felts, name)?;
+            let mut bytes = Vec::with_capacity(words * BYTES_PER_WORD);
+            for _ in 0..words {
+                bytes.extend(word_bytes(next(felts, name)?, BYTES_PER_WORD)?);
+            }
+            let pending_word = next(felts, name)?;
+            let pending_len = uint(next(felts, name)?, 8, name)? as usize;
+            if pending_len >= BYTES_PER_WORD {
+                return Err(CairoSerdeError::OutOfRange {
+                    ty: "ByteArray pending word length",
+                    value: Felt::from(pending_len as u64),
+                });
+            }
+            bytes.extend(word_bytes(pending_word, pending_len)?);
+            CairoValue::ByteArray(bytes)
+        }
+        CairoType::Array(element) => {
+            let elements = len(next(felts, name)?, felts, name)?;
+            CairoValue::Array((0..elements).map(|_| decode_prefix(element, felts)).collect::<Result<_, _>>()?)
+        }
+        CairoType::Struct(members) => CairoValue::Struct(
+            members
+                .iter()
+                .map(|(member, ty)| Ok((member.clone(), decode_prefix(ty, felts)?)))
+                .collect::<Result<_, _>>()?,
+        ),
+    };
+    Ok(value)
+}
+
+/// Decodes a value spanning exactly `felts`.
+pub fn decode(ty: &CairoType, mut felts: &[Felt]) -> Result<CairoValue, CairoSerdeError> {
+    let value = decode_prefix(ty, &mut felts)?;
+    if !felts.is_empty() {
+        return Err(CairoSerdeError::TrailingFelts(felts.len()));
+    }
+    Ok(value)
+}
+
+/// Decodes a value from the verified values of its consecutive storage slots, in order.
+pub fn decode_verified(ty: &CairoType, values: &[VerifiedValue]) -> Result<CairoValue, CairoSerdeError> {
+    decode(ty, &values.iter().map(|value| value.value).collect::<Vec<_>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(bytes: &[u8]) -> Felt {
+        let mut word = [0u8; 32];
+        word[32 - bytes.len()..].copy_from_slice(bytes);
+        Felt::from_bytes_be(&word)
+    }
+
+    #[test]
+    fn test_decode_byte_array() {
+        let string = "a string spanning more than one word";
+        let (full, pending) = string.as_bytes().split_at(BYTES_PER_WORD);
+        let felts = [Felt::ONE, word(full), word(pending), Felt::from(pending.len() as u64)];
+        let value = decode(&CairoType::ByteArray, &felts).unwrap();
+        assert_eq!(value.as_str(), Some(string));
+
+        let empty = decode(&CairoType::ByteArray, &[Felt::ZERO, Felt::ZERO, Felt::ZERO]).unwrap();
+        assert_eq!(empty, CairoValue::ByteArray(vec![]));
+        // The pending word holds more bytes than its length
+        assert!(matches!(
+            decode(&CairoType::ByteArray, &[Felt::ZERO, word(b"ab"), Felt::ONE]),
+            Err(CairoSerdeError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_struct() {
+        let position = CairoType::Struct(vec![
+            ("owner".to_string(), CairoType::ContractAddress),
+            ("amount".to_string(), CairoType::U256),
+            ("active".to_string(), CairoType::Bool),
+            ("ids".to_string(), CairoType::Array(Box::new(CairoType::UInt(32)))),
+        ]);
+        assert_eq!(position.size(), None);
+        let felts = [0xa_u64, 5, 1, 1, 2, 7, 8].map(Felt::from);
+        assert_eq!(
+            decode(&position, &felts).unwrap(),
+            CairoValue::Struct(vec![
+                ("owner".to_string(), CairoValue::ContractAddress(Felt::from(0xa_u64))),
+                ("amount".to_string(), CairoValue::U256 { low: 5, high: 1 }),
+                ("active".to_string(), CairoValue::Bool(true)),
+                ("ids".to_string(), CairoValue::Array(vec![CairoValue::UInt(7), CairoValue::UInt(8)])),
+            ])
+        );
+
+        assert_eq!(decode(&CairoType::U256, &[Felt::ONE]), Err(CairoSerdeError::Truncated("u256")));
+        assert_eq!(decode(&CairoType::Felt, &[Felt::ONE, Felt::ONE]), Err(CairoSerdeError::TrailingFelts(1)));
+        assert_eq!(
+            decode(&CairoType::UInt(8), &[Felt::from(256_u64)]),
+            Err(CairoSerdeError::OutOfRange { ty: "unsigned integer", value: Felt::from(256_u64) })
+        );
+        // An array claiming more elements than there are felts
+        assert_eq!(
+            decode(&CairoType::Array(Box::new(CairoType::Felt)), &[Felt::from(u64::MAX)]),
+            Err(CairoSerdeError::Truncated("Array"))
+        );
+    }
+}
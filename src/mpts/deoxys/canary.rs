@@ -0,0 +1,313 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::MutexGuard;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mc_db::storage_handler::{self, StorageView};
+use starknet_api::core::ContractAddress;
+use starknet_api::state::StorageKey;
+use starknet_types_core::felt::Felt;
+
+use super::atomic::Trie;
+use super::config::{ChainConfig, NodeHash, StorageWrite};
+use super::contracts::contract_leaf_hash;
+use super::engine::{CommitmentEngine, StateTries};
+use super::error::TrieError;
+use super::proof::{felt_to_path, from_bonsai, ProofError, ProofNode};
+
+/// Number of canary failures kept in memory.
+const FAILURE_LOG_CAPACITY: usize = 1024;
+
+/// A sampled key whose proof did not verify against the freshly committed root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanaryFailure {
+    pub block_number: u64,
+    pub trie: Trie,
+    pub contract_address: ContractAddress,
+    /// The storage key, for [Trie::ContractStorage] failures.
+    pub key: Option<StorageKey>,
+    pub error: ProofError,
+}
+
+/// Canary verifications performed since the process started, along with the latest failures.
+#[derive(Debug, Default)]
+pub struct CanaryLog {
+    pub verified: u64,
+    pub failed: u64,
+    /// Verifications which could not run because the tries could not be read.
+    pub errors: u64,
+    /// The block number and error of the latest verification which could not run.
+    pub latest_error: Option<(u64, String)>,
+    failures: VecDeque<CanaryFailure>,
+}
+
+impl CanaryLog {
+    fn record(&mut self, failure: Option<CanaryFailure>) {
+        let Some(failure) = failure else {
+            self.verified += 1;
+            return;
+        };
+        self.failed += 1;
+        if self.failures.len() == FAILURE_LOG_CAPACITY {
+            self.failures.pop_front();
+        }
+        self.failures.push_back(failure);
+    }
+
+    pub(crate) fn record_error(&mut self, block_number: u64, error: &TrieError) {
+        self.errors += 1;
+        self.latest_error = Some((block_number, error.to_string()));
+    }
+
+    /// The latest failures, oldest first.
+    pub fn failures(&self) -> impl Iterator<Item = &CanaryFailure> {
+        self.failures.iter()
+    }
+}
+
+/// Canary verification log of the commits to `tries`.
+pub fn canary_log(tries: &StateTries) -> MutexGuard<'_, CanaryLog> {
+    tries.state().canary_log.lock().expect("Poisoned lock on canary log")
+}
+
+/// Picks up to `count` elements of `items` at random, or pseudo-randomly with a fixed seed if
+/// `deterministic`.
+fn sample<T>(mut items: Vec<T>, count: usize, deterministic: bool) -> Vec<T> {
+    let random_state = if deterministic { None } else { Some(RandomState::new()) };
+    let count = count.min(items.len());
+    // partial Fisher-Yates shuffle
+    for i in 0..count {
+        let mut hasher: Box<dyn Hasher> = match &random_state {
+            Some(random_state) => Box::new(random_state.build_hasher()),
+            None => Box::new(DefaultHasher::new()),
+        };
+        hasher.write_usize(i);
+        let j = i + (hasher.finish() as usize) % (items.len() - i);
+        items.swap(i, j);
+    }
+    items.truncate(count);
+    items
+}
+
+/// The tries the canary reads back: those of the node's database, or those of an
+/// [engine](super::engine::CommitmentEngine).
+trait CanaryTries {
+    fn storage_root(&self, contract_address: &ContractAddress) -> Result<Felt, TrieError>;
+
+    fn storage_proof(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<Vec<ProofNode>, TrieError>;
+
+    /// The root of the contracts trie and the proof of the leaf of a contract.
+    fn contract_proof(&self, contract_address: &ContractAddress) -> Result<(Felt, Vec<ProofNode>), TrieError>;
+
+    fn class_hash_and_nonce(&self, contract_address: &ContractAddress) -> Result<(Felt, Felt), TrieError>;
+}
+
+/// The tries of the node's database, where the class hashes and nonces of a block are stored along
+/// with its state diff.
+struct NodeDbTries<'a> {
+    csd: &'a CommitmentStateDiff,
+}
+
+impl CanaryTries for NodeDbTries<'_> {
+    fn storage_root(&self, contract_address: &ContractAddress) -> Result<Felt, TrieError> {
+        Ok(storage_handler::contract_storage_trie().root(contract_address)?)
+    }
+
+    fn storage_proof(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<Vec<ProofNode>, TrieError> {
+        let proof = storage_handler::contract_storage_trie().get_proof(contract_address, key)?;
+        Ok(proof.into_iter().map(from_bonsai).collect())
+    }
+
+    fn contract_proof(&self, contract_address: &ContractAddress) -> Result<(Felt, Vec<ProofNode>), TrieError> {
+        let handler_contract = storage_handler::contract_trie();
+        let proof = handler_contract.get_proof(contract_address)?;
+        Ok((handler_contract.root()?, proof.into_iter().map(from_bonsai).collect()))
+    }
+
+    fn class_hash_and_nonce(&self, contract_address: &ContractAddress) -> Result<(Felt, Felt), TrieError> {
+        let class_hash = match self.csd.address_to_class_hash.get(contract_address) {
+            Some(class_hash) => *class_hash,
+            None => storage_handler::contract_class_hash().get(contract_address)?.unwrap_or_default(),
+        };
+        let nonce = match self.csd.address_to_nonce.get(contract_address) {
+            Some(nonce) => *nonce,
+            None => storage_handler::contract_nonces().get(contract_address)?.unwrap_or_default(),
+        };
+        Ok((Felt::from_bytes_be(&class_hash.0.0), Felt::from_bytes_be(&nonce.0.0)))
+    }
+}
+
+impl CanaryTries for CommitmentEngine {
+    fn storage_root(&self, contract_address: &ContractAddress) -> Result<Felt, TrieError> {
+        CommitmentEngine::storage_root(self, contract_address)
+    }
+
+    fn storage_proof(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<Vec<ProofNode>, TrieError> {
+        self.storage_slot_proof(contract_address, key)
+    }
+
+    fn contract_proof(&self, contract_address: &ContractAddress) -> Result<(Felt, Vec<ProofNode>), TrieError> {
+        self.contract_leaf_proof(contract_address)
+    }
+
+    fn class_hash_and_nonce(&self, contract_address: &ContractAddress) -> Result<(Felt, Felt), TrieError> {
+        let (class_hash, nonce) = (self.class_hash(contract_address)?, self.nonce(contract_address)?);
+        Ok((Felt::from_bytes_be(&class_hash.0.0), Felt::from_bytes_be(&nonce.0.0)))
+    }
+}
+
+/// Verifies the proofs of a random sample of the storage slots written by a block against the
+/// freshly committed roots, along with the proofs of their contract leaves.
+///
+/// The storage values are checked against those of the state diff, and the contract leaves against
+/// the leaves recomputed from the class hash, nonce and storage root of the contracts, so that a
+/// trie bug shows up within one block of being introduced. Failures are recorded in the
+/// [canary log](canary_log) and do not fail the commit.
+///
+/// # Arguments
+///
+/// * `tries`        - The tries the block was committed to.
+/// * `csd`          - Commitment state diff of the block which was just committed.
+/// * `block_number` - The block number.
+/// * `config`       - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The number of sampled keys whose proof failed to verify.
+pub fn verify_sample(
+    tries: &StateTries,
+    csd: &CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<usize, TrieError> {
+    let writes = csd
+        .storage_updates
+        .iter()
+        .flat_map(|(contract_address, updates)| {
+            updates.iter().filter_map(move |(key, value)| {
+                let expected = match config.zero_writes.write(*value)? {
+                    StorageWrite::Set(value) => Some(Felt::from_bytes_be(&value.0)),
+                    StorageWrite::Delete => None,
+                };
+                Some((*contract_address, *key, expected))
+            })
+        })
+        .collect::<Vec<_>>();
+    let writes = sample(writes, config.canary_sample, tries.execution().deterministic);
+
+    match tries.engine() {
+        Some(engine) => verify_writes(&*engine, writes, block_number, config),
+        None => verify_writes(&NodeDbTries { csd }, writes, block_number, config),
+    }
+}
+
+fn verify_writes(
+    tries: &impl CanaryTries,
+    writes: Vec<(ContractAddress, StorageKey, Option<Felt>)>,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<usize, TrieError> {
+    let mut failures = 0;
+    for (contract_address, key, expected) in writes {
+        let storage_root = tries.storage_root(&contract_address)?;
+        let proof = tries.storage_proof(&contract_address, &key)?;
+        let storage_failure =
+            check(config.hashers.storage_node, storage_root, key.0.key().0, &proof, expected).err().map(|error| {
+                CanaryFailure { block_number, trie: Trie::ContractStorage, contract_address, key: Some(key), error }
+            });
+
+        let (class_hash, nonce) = tries.class_hash_and_nonce(&contract_address)?;
+        let leaf = contract_leaf_hash(class_hash, nonce, storage_root, config);
+        let (contract_trie_root, proof) = tries.contract_proof(&contract_address)?;
+        let contract_failure =
+            check(config.hashers.contracts_node, contract_trie_root, contract_address.0.key().0, &proof, Some(leaf))
+                .err()
+                .map(|error| CanaryFailure { block_number, trie: Trie::Contracts, contract_address, key: None, error });
+
+        let mut log = canary_log(tries);
+        for failure in [storage_failure, contract_failure] {
+            failures += failure.is_some() as usize;
+            log.record(failure);
+        }
+    }
+
+    Ok(failures)
+}
+
+fn check(
+    hash: NodeHash,
+    root: Felt,
+    key: [u8; 32],
+    proof: &[ProofNode],
+    expected: Option<Felt>,
+) -> Result<(), ProofError> {
+    let proven = hash.verify_proof(root, &felt_to_path(&Felt::from_bytes_be(&key)), proof)?;
+    if proven != expected {
+        return Err(ProofError::ValueMismatch { expected, proven });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use starknet_api::core::{Nonce, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::{Column, MemoryBackend};
+    use crate::mpts::deoxys::lib::{clone_commitment_state_diff, try_update_state_root};
+
+    #[test]
+    fn test_sample() {
+        let items = (0..100).collect::<Vec<_>>();
+
+        let sampled = sample(items.clone(), 10, false);
+        assert_eq!(sampled.len(), 10);
+        assert_eq!(sampled.iter().collect::<HashSet<_>>().len(), 10);
+        assert!(sampled.iter().all(|item| items.contains(item)));
+        assert_eq!(sample(items.clone(), 10, true), sample(items.clone(), 10, true));
+
+        assert_eq!(sample(items, 1000, false).len(), 100);
+    }
+
+    #[test]
+    fn test_verify_sample() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: [(contract_address, Nonce(StarkFelt::from(3_u64)))].into_iter().collect(),
+            storage_updates: [(
+                contract_address,
+                (1..=4_u64).map(|key| (StorageKey(PatriciaKey(StarkFelt::from(key))), StarkFelt::from(key))).collect(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let config = ChainConfig { canary_sample: 2, ..Default::default() };
+        try_update_state_root(&tries, clone_commitment_state_diff(&csd), 1, &config).unwrap();
+        let verified = canary_log(&tries).verified;
+        assert_eq!(verify_sample(&tries, &csd, 1, &config).unwrap(), 0);
+        // two storage slots and their contract leaves
+        assert_eq!(canary_log(&tries).verified, verified + 4);
+
+        // The contract leaf is recomputed from the nonce, not read back from the contracts trie
+        tries
+            .engine()
+            .unwrap()
+            .backend()
+            .put(Column::Nonces, &contract_address.0.key().0, Some(&StarkFelt::from(4_u64).0))
+            .unwrap();
+        assert_eq!(verify_sample(&tries, &csd, 1, &config).unwrap(), 2);
+        let failure = canary_log(&tries).failures().last().cloned().unwrap();
+        assert_eq!(failure.block_number, 1);
+        assert_eq!(failure.trie, Trie::Contracts);
+        assert_eq!(failure.contract_address, contract_address);
+    }
+}
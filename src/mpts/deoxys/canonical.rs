@@ -0,0 +1,125 @@
+use blockifier::state::cached_state::CommitmentStateDiff;
+
+/// Canonical ordering of a [CommitmentStateDiff].
+///
+/// [CommitmentStateDiff] is backed by `IndexMap`s, which iterate in insertion order. This order
+/// depends on how the diff was produced (gateway json, blockifier execution, ...) and must never
+/// leak into anything we hash. Canonical order is ascending contract address, then ascending storage
+/// key / class hash.
+pub trait Canonicalize {
+    /// Sorts every map of the diff in canonical order.
+    fn canonicalize(&mut self);
+
+    /// Returns true if every map of the diff is already in canonical order.
+    fn is_canonical(&self) -> bool;
+}
+
+impl Canonicalize for CommitmentStateDiff {
+    fn canonicalize(&mut self) {
+        self.address_to_class_hash.sort_keys();
+        self.address_to_nonce.sort_keys();
+        self.class_hash_to_compiled_class_hash.sort_keys();
+        self.storage_updates.sort_keys();
+        for updates in self.storage_updates.values_mut() {
+            updates.sort_keys();
+        }
+    }
+
+    fn is_canonical(&self) -> bool {
+        fn is_sorted<'a, K: Ord + 'a>(mut keys: impl Iterator<Item = &'a K>) -> bool {
+            let Some(mut previous) = keys.next() else {
+                return true;
+            };
+            for key in keys {
+                if key < previous {
+                    return false;
+                }
+                previous = key;
+            }
+            true
+        }
+
+        is_sorted(self.address_to_class_hash.keys())
+            && is_sorted(self.address_to_nonce.keys())
+            && is_sorted(self.class_hash_to_compiled_class_hash.keys())
+            && is_sorted(self.storage_updates.keys())
+            && self.storage_updates.values().all(|updates| is_sorted(updates.keys()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+    use mp_felt::Felt252Wrapper;
+    use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::engine::StateTries;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+
+    fn address(n: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn storage_key(n: u64) -> StorageKey {
+        StorageKey(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn csd(contracts: &[u64]) -> CommitmentStateDiff {
+        let mut csd = CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        for &n in contracts {
+            csd.address_to_nonce.insert(address(n), Nonce(StarkFelt::from(n)));
+            let updates = contracts.iter().map(|&k| (storage_key(k), StarkFelt::from(k + n))).collect();
+            csd.storage_updates.insert(address(n), updates);
+        }
+        csd
+    }
+
+    /// State root of the diff committed as the first block of an empty state.
+    fn state_root(csd: CommitmentStateDiff) -> Felt252Wrapper {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+        try_update_state_root(&tries, csd, 1, &ChainConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let mut forward = csd(&[1, 2, 3]);
+        let mut backward = csd(&[3, 2, 1]);
+        assert!(forward.is_canonical());
+        assert!(!backward.is_canonical());
+
+        forward.canonicalize();
+        backward.canonicalize();
+        assert!(backward.is_canonical());
+        assert!(forward.storage_updates.iter().eq(backward.storage_updates.iter()));
+        assert!(forward.address_to_nonce.iter().eq(backward.address_to_nonce.iter()));
+    }
+
+    #[test]
+    fn test_root_is_order_independent() {
+        let diff = |contracts: &[u64]| {
+            let mut csd = csd(contracts);
+            for &n in contracts {
+                let class_hash = ClassHash(StarkFelt::from(0x100 + n));
+                csd.address_to_class_hash.insert(address(n), class_hash);
+                csd.class_hash_to_compiled_class_hash.insert(class_hash, CompiledClassHash(StarkFelt::from(0x200 + n)));
+            }
+            csd
+        };
+        let forward = state_root(diff(&[1, 7, 3, 1024]));
+        let backward = state_root(diff(&[1024, 3, 7, 1]));
+        assert_eq!(forward, backward);
+        assert_ne!(forward, state_root(diff(&[1, 7, 3])));
+    }
+}
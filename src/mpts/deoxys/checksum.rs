@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use super::atomic::Trie;
+
+/// Length of the checksum appended to sealed payloads.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// A payload whose checksum does not match its content, ie: it was corrupted on disk.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CorruptionError {
+    #[error("{location}: checksum {computed:#010x} does not match stored checksum {stored:#010x}")]
+    ChecksumMismatch { location: String, stored: u32, computed: u32 },
+    #[error("{location}: payload of {len} bytes is too short to hold a checksum")]
+    Truncated { location: String, len: usize },
+}
+
+/// Appends a CRC32 checksum to a node payload before it is written.
+pub fn seal(payload: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(payload.len() + CHECKSUM_LEN);
+    sealed.extend(payload);
+    sealed.extend(crc32fast::hash(payload).to_be_bytes());
+    sealed
+}
+
+/// Validates the checksum of a sealed node payload read from `trie`, and strips it.
+///
+/// # Arguments
+///
+/// * `trie`     - The trie the node was read from, for metrics and errors.
+/// * `location` - Where the node was read from (ie: its key), for errors.
+/// * `sealed`   - The payload, as returned by [seal].
+///
+/// # Returns
+///
+/// The payload without its checksum.
+pub fn unseal<'a>(
+    trie: Trie,
+    location: impl FnOnce() -> String,
+    sealed: &'a [u8],
+) -> Result<&'a [u8], CorruptionError> {
+    let mut metrics = checksum_metrics();
+    let Some(split) = sealed.len().checked_sub(CHECKSUM_LEN) else {
+        metrics.record(trie, false);
+        let location = format!("{trie:?} trie {}", location());
+        return Err(CorruptionError::Truncated { location, len: sealed.len() });
+    };
+    let (payload, checksum) = sealed.split_at(split);
+
+    let stored = u32::from_be_bytes(checksum.try_into().unwrap());
+    let computed = crc32fast::hash(payload);
+    metrics.record(trie, stored == computed);
+    if stored != computed {
+        return Err(CorruptionError::ChecksumMismatch {
+            location: format!("{trie:?} trie {}", location()),
+            stored,
+            computed,
+        });
+    }
+
+    Ok(payload)
+}
+
+/// Number of node reads of a trie whose checksum was validated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChecksumCounters {
+    pub verified: u64,
+    pub corrupted: u64,
+}
+
+/// Checksum validation metrics, per trie.
+#[derive(Debug, Default)]
+pub struct ChecksumMetrics {
+    tries: HashMap<Trie, ChecksumCounters>,
+}
+
+impl ChecksumMetrics {
+    fn record(&mut self, trie: Trie, valid: bool) {
+        let counters = self.tries.entry(trie).or_default();
+        if valid {
+            counters.verified += 1;
+        } else {
+            counters.corrupted += 1;
+        }
+    }
+
+    pub fn get(&self, trie: Trie) -> ChecksumCounters {
+        self.tries.get(&trie).copied().unwrap_or_default()
+    }
+}
+
+static CHECKSUM_METRICS: OnceLock<Mutex<ChecksumMetrics>> = OnceLock::new();
+
+/// Returns the process-wide [ChecksumMetrics].
+pub fn checksum_metrics() -> MutexGuard<'static, ChecksumMetrics> {
+    CHECKSUM_METRICS.get_or_init(Default::default).lock().expect("Poisoned lock on checksum metrics")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_corruption() {
+        let sealed = seal(b"node");
+        assert_eq!(unseal(Trie::Contracts, || "0x1".to_string(), &sealed), Ok(&b"node"[..]));
+
+        let mut corrupted = sealed.clone();
+        corrupted[0] ^= 1;
+        assert!(matches!(
+            unseal(Trie::Contracts, || "0x1".to_string(), &corrupted),
+            Err(CorruptionError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            unseal(Trie::Contracts, || "0x1".to_string(), &sealed[..2]),
+            Err(CorruptionError::Truncated { len: 2, .. })
+        ));
+    }
+}
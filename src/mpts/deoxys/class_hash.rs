@@ -0,0 +1,407 @@
+//! Hashing of the class definitions, to check the class hashes declared in the state diffs.
+//!
+//! The class hash of a Sierra class commits to its entry points, its ABI and its Sierra program,
+//! the compiled class hash to the CASM the Sierra was compiled to. State diffs only carry the
+//! hashes: [verify_class_hashes] recomputes them from the definitions fetched from the gateway.
+//!
+//! Cairo 0 classes are hashed with [compute_legacy_class_hash]. Their class hash commits to the
+//! keccak of the program's and the ABI's JSON, as serialized by the Python gateway, which is
+//! reproduced here from the JSON of the class as declared.
+
+#[cfg(feature = "pedersen")]
+use std::collections::HashMap;
+use std::io;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+#[cfg(feature = "class-verification")]
+use cairo_lang_starknet_classes::casm_contract_class::CasmContractClass;
+use mp_convert::field_element::FromFieldElement;
+#[cfg(feature = "pedersen")]
+use serde_json::Value;
+use starknet_api::core::{ClassHash, CompiledClassHash};
+#[cfg(feature = "pedersen")]
+use starknet_api::deprecated_contract_class::{EntryPoint, EntryPointType};
+use starknet_core::types::{FlattenedSierraClass, SierraEntryPoint};
+use starknet_core::utils::starknet_keccak;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+#[cfg(feature = "pedersen")]
+use starknet_types_core::hash::Pedersen;
+use starknet_types_core::hash::{Poseidon, StarkHash};
+
+use super::class_store::ClassStore;
+use super::consts::ProtocolConstants;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClassHashError {
+    #[error("failed to read class {0:?} from the class store: {1}")]
+    Store(ClassHash, io::Error),
+    #[error("failed to deserialize Sierra class {0:?}: {1}")]
+    Deserialize(ClassHash, serde_json::Error),
+}
+
+/// A declared class whose definition does not hash to the hashes of the state diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClassHashMismatch {
+    ClassHash { declared: ClassHash, computed: ClassHash },
+    CompiledClassHash { class_hash: ClassHash, declared: CompiledClassHash, computed: CompiledClassHash },
+}
+
+fn felt(felt: &FieldElement) -> Felt {
+    Felt::from_bytes_be(&felt.to_bytes_be())
+}
+
+fn entry_points_hash(entry_points: &[SierraEntryPoint]) -> Felt {
+    let elements = entry_points
+        .iter()
+        .flat_map(|entry_point| [felt(&entry_point.selector), Felt::from(entry_point.function_idx)])
+        .collect::<Vec<_>>();
+    Poseidon::hash_array(&elements)
+}
+
+/// Computes the class hash of a Sierra class, as served by the gateway and the RPC.
+///
+/// The ABI is hashed as the string it was declared with, so the class must not have been
+/// re-serialized.
+pub fn compute_sierra_class_hash(class: &FlattenedSierraClass) -> ClassHash {
+    compute_sierra_class_hash_with_constants(class, &ProtocolConstants::default())
+}
+
+/// [compute_sierra_class_hash] with the class version of the chain's [protocol constants](ProtocolConstants).
+pub fn compute_sierra_class_hash_with_constants(
+    class: &FlattenedSierraClass,
+    constants: &ProtocolConstants,
+) -> ClassHash {
+    let sierra_program = class.sierra_program.iter().map(felt).collect::<Vec<_>>();
+    let hash = Poseidon::hash_array(&[
+        felt(&constants.contract_class_version),
+        entry_points_hash(&class.entry_points_by_type.external),
+        entry_points_hash(&class.entry_points_by_type.l1_handler),
+        entry_points_hash(&class.entry_points_by_type.constructor),
+        felt(&starknet_keccak(class.abi.as_bytes())),
+        Poseidon::hash_array(&sierra_program),
+    ]);
+    ClassHash::from_field_element(FieldElement::from_bytes_be(&hash.to_bytes_be()).unwrap())
+}
+
+/// Computes the compiled class hash of a CASM class.
+#[cfg(feature = "class-verification")]
+pub fn compute_compiled_class_hash(class: &CasmContractClass) -> CompiledClassHash {
+    let hash = class.compiled_class_hash().to_biguint().to_bytes_be();
+    CompiledClassHash::from_field_element(FieldElement::from_byte_slice_be(&hash).unwrap())
+}
+
+/// Version of the Cairo 0 class hash.
+#[cfg(feature = "pedersen")]
+const LEGACY_API_VERSION: Felt = Felt::ZERO;
+
+/// Writes `value` as Python's `json.dumps(value, sort_keys=True)` does, which is what the Cairo 0
+/// hinted class hash is computed over.
+#[cfg(feature = "pedersen")]
+fn write_python_json(value: &Value, out: &mut String) {
+    match value {
+        Value::Array(values) => {
+            out.push('[');
+            for (index, value) in values.iter().enumerate() {
+                if index > 0 {
+                    out.push_str(", ");
+                }
+                write_python_json(value, out);
+            }
+            out.push(']');
+        }
+        Value::Object(object) => {
+            let mut entries = object.iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            out.push('{');
+            for (index, (key, value)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    out.push_str(", ");
+                }
+                write_python_string(key, out);
+                out.push_str(": ");
+                write_python_json(value, out);
+            }
+            out.push('}');
+        }
+        Value::String(string) => write_python_string(string, out),
+        value => out.push_str(&value.to_string()),
+    }
+}
+
+/// Writes a JSON string escaping non-ASCII characters, as Python's `ensure_ascii` does.
+#[cfg(feature = "pedersen")]
+fn write_python_string(string: &str, out: &mut String) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if c.is_ascii() && !c.is_ascii_control() => out.push(c),
+            c => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("\\u{unit:04x}"));
+                }
+            }
+        }
+    }
+    out.push('"');
+}
+
+/// Rewrites the named tuples of a Cairo type as `(a : felt)`, the format of the compilers before
+/// 0.10.0.
+#[cfg(feature = "pedersen")]
+fn legacy_cairo_type(cairo_type: &str) -> String {
+    let mut legacy = String::with_capacity(cairo_type.len());
+    let mut chars = cairo_type.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&' ') && legacy.ends_with(|c: char| c.is_alphanumeric() || c == '_') {
+            legacy.push(' ');
+        }
+        legacy.push(c);
+    }
+    legacy
+}
+
+#[cfg(feature = "pedersen")]
+fn use_legacy_cairo_types(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(string) if key == "cairo_type" || key == "value" => {
+                        *string = legacy_cairo_type(string);
+                    }
+                    value => use_legacy_cairo_types(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(use_legacy_cairo_types),
+        _ => {}
+    }
+}
+
+/// The keccak of the program without its debug info and of the ABI, which commits to the hints.
+#[cfg(feature = "pedersen")]
+fn hinted_class_hash(abi: &Value, program: &Value) -> Felt {
+    let mut program = program.clone();
+    if let Value::Object(object) = &mut program {
+        object.insert("debug_info".to_string(), Value::Null);
+        if matches!(object.get("compiler_version"), None | Some(Value::Null)) {
+            object.remove("compiler_version");
+            for field in ["identifiers", "reference_manager"] {
+                object.get_mut(field).into_iter().for_each(use_legacy_cairo_types);
+            }
+        }
+        match object.get_mut("attributes") {
+            Some(Value::Array(attributes)) if attributes.is_empty() => {
+                object.remove("attributes");
+            }
+            Some(Value::Array(attributes)) => {
+                for attribute in attributes.iter_mut().filter_map(Value::as_object_mut) {
+                    if attribute.get("accessible_scopes").and_then(Value::as_array).is_some_and(Vec::is_empty) {
+                        attribute.remove("accessible_scopes");
+                    }
+                    if attribute.get("flow_tracking_data").is_some_and(Value::is_null) {
+                        attribute.remove("flow_tracking_data");
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut json = String::new();
+    write_python_json(&serde_json::json!({ "abi": abi, "program": program }), &mut json);
+    felt(&starknet_keccak(json.as_bytes()))
+}
+
+/// Computes the class hash of a Cairo 0 class.
+///
+/// The class hash commits to the entry points, the builtins, the bytecode and, through the hinted
+/// class hash, to the hints and the ABI.
+///
+/// `class` is the JSON of the class as declared, ie: as served by the feeder gateway's
+/// `get_class_by_hash`, with its program uncompressed. The hinted class hash is computed over the
+/// ABI and the program as they were declared: deserializing them into typed classes first would
+/// drop or add the optional fields they do not model, and change the hash.
+#[cfg(feature = "pedersen")]
+pub fn compute_legacy_class_hash(class: &Value) -> Result<ClassHash, serde_json::Error> {
+    let entry_points_by_type =
+        serde_json::from_value::<HashMap<EntryPointType, Vec<EntryPoint>>>(class["entry_points_by_type"].clone())?;
+    let entry_points_hash = |entry_point_type| {
+        let entry_points = entry_points_by_type.get(&entry_point_type).map(Vec::as_slice).unwrap_or_default();
+        let elements = entry_points
+            .iter()
+            .flat_map(|entry_point| {
+                [Felt::from_bytes_be(&entry_point.selector.0.0), Felt::from(entry_point.offset.0 as u64)]
+            })
+            .collect::<Vec<_>>();
+        Pedersen::hash_array(&elements)
+    };
+    let program = &class["program"];
+    let builtins = serde_json::from_value::<Vec<String>>(program["builtins"].clone())?
+        .iter()
+        .map(|builtin| Felt::from_bytes_be_slice(builtin.as_bytes()))
+        .collect::<Vec<_>>();
+    let data =
+        serde_json::from_value::<Vec<FieldElement>>(program["data"].clone())?.iter().map(felt).collect::<Vec<_>>();
+
+    let hash = Pedersen::hash_array(&[
+        LEGACY_API_VERSION,
+        entry_points_hash(EntryPointType::External),
+        entry_points_hash(EntryPointType::L1Handler),
+        entry_points_hash(EntryPointType::Constructor),
+        Pedersen::hash_array(&builtins),
+        hinted_class_hash(&class["abi"], program),
+        Pedersen::hash_array(&data),
+    ]);
+    Ok(ClassHash::from_field_element(FieldElement::from_bytes_be(&hash.to_bytes_be()).unwrap()))
+}
+
+/// Checks the hashes of the classes declared by a state diff against their definitions in the class
+/// store.
+///
+/// Classes without a stored definition are skipped, as are the compiled class hashes of classes
+/// stored without their CASM. CASM is only hashed with the `class-verification` feature.
+///
+/// # Returns
+///
+/// Every mismatch found.
+pub fn verify_class_hashes(
+    csd: &CommitmentStateDiff,
+    store: &ClassStore,
+) -> Result<Vec<ClassHashMismatch>, ClassHashError> {
+    let mut mismatches = Vec::new();
+
+    for (class_hash, _compiled_class_hash) in csd.class_hash_to_compiled_class_hash.iter() {
+        let definition = store.get(class_hash).map_err(|e| ClassHashError::Store(*class_hash, e))?;
+        let Some(definition) = definition else {
+            continue;
+        };
+
+        let sierra = serde_json::from_slice::<FlattenedSierraClass>(&definition.sierra)
+            .map_err(|e| ClassHashError::Deserialize(*class_hash, e))?;
+        let computed = compute_sierra_class_hash(&sierra);
+        if computed != *class_hash {
+            mismatches.push(ClassHashMismatch::ClassHash { declared: *class_hash, computed });
+        }
+
+        #[cfg(feature = "class-verification")]
+        if let Some(casm) = definition.casm {
+            let casm = serde_json::from_slice::<CasmContractClass>(&casm)
+                .map_err(|e| ClassHashError::Deserialize(*class_hash, e))?;
+            let computed = compute_compiled_class_hash(&casm);
+            if computed != *_compiled_class_hash {
+                mismatches.push(ClassHashMismatch::CompiledClassHash {
+                    class_hash: *class_hash,
+                    declared: *_compiled_class_hash,
+                    computed,
+                });
+            }
+        }
+    }
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use starknet_api::hash::StarkFelt;
+    use starknet_core::types::EntryPointsByType;
+
+    use super::*;
+    use crate::mpts::deoxys::class_store::ClassDefinition;
+
+    fn class(abi: &str) -> FlattenedSierraClass {
+        FlattenedSierraClass {
+            sierra_program: vec![FieldElement::ONE, FieldElement::TWO, FieldElement::THREE],
+            contract_class_version: "0.1.0".to_string(),
+            entry_points_by_type: EntryPointsByType {
+                constructor: vec![],
+                external: vec![SierraEntryPoint { selector: starknet_keccak(b"transfer"), function_idx: 0 }],
+                l1_handler: vec![],
+            },
+            abi: abi.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_verify_class_hashes() {
+        let root = std::env::temp_dir().join(format!("starkroot-class-hash-{}", std::process::id()));
+        let store = ClassStore::open(&root).unwrap();
+
+        let declared = compute_sierra_class_hash(&class("[]"));
+        assert_ne!(compute_sierra_class_hash(&class("[ ]")), declared);
+        let forged = ClassHash(StarkFelt::from(0x42_u64));
+        for class_hash in [declared, forged] {
+            let definition = ClassDefinition { sierra: serde_json::to_vec(&class("[]")).unwrap(), casm: None };
+            store.insert(&class_hash, &definition).unwrap();
+        }
+
+        let unknown = ClassHash(StarkFelt::from(0x43_u64));
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: [declared, forged, unknown]
+                .into_iter()
+                .map(|class_hash| (class_hash, CompiledClassHash(StarkFelt::ONE)))
+                .collect(),
+        };
+        let mismatches = verify_class_hashes(&csd, &store).unwrap();
+        assert_eq!(mismatches, vec![ClassHashMismatch::ClassHash { declared: forged, computed: declared }]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "pedersen")]
+    #[test]
+    fn test_legacy_class_hash() {
+        let mut json = String::new();
+        write_python_json(&serde_json::json!({ "b": [1, "caf\u{e9}\n"], "a": null }), &mut json);
+        assert_eq!(json, r#"{"a": null, "b": [1, "caf\u00e9\n"]}"#);
+        assert_eq!(legacy_cairo_type("(a: felt, b: felt*)"), "(a : felt, b : felt*)");
+
+        let class = |debug_info: Value, hints: Value| {
+            serde_json::json!({
+                "abi": [{ "inputs": [{ "name": "amount", "type": "felt" }], "name": "mint", "type": "function" }],
+                "entry_points_by_type": {
+                    "CONSTRUCTOR": [],
+                    "EXTERNAL": [{ "offset": "0x0", "selector": format!("{:#x}", starknet_keccak(b"mint")) }],
+                    "L1_HANDLER": [],
+                },
+                "program": {
+                    "attributes": [],
+                    "builtins": ["pedersen", "range_check"],
+                    "compiler_version": "0.10.3",
+                    "data": ["0x40780017fff7fff", "0x1", "0x208b7fff7fff7ffe"],
+                    "debug_info": debug_info,
+                    "hints": hints,
+                    "identifiers": {},
+                    "main_scope": "__main__",
+                    "prime": "0x800000000000011000000000000000000000000000000000000000000000001",
+                    "reference_manager": { "references": [] },
+                },
+            })
+        };
+        let hash = compute_legacy_class_hash(&class(Value::Null, serde_json::json!({}))).unwrap();
+        let debug_info = serde_json::json!({ "file_contents": {}, "instruction_locations": {} });
+        assert_eq!(compute_legacy_class_hash(&class(debug_info, serde_json::json!({}))).unwrap(), hash);
+        let hints = serde_json::json!({ "0": [{ "code": "memory[ap] = 1" }] });
+        assert_ne!(compute_legacy_class_hash(&class(Value::Null, hints)).unwrap(), hash);
+
+        // The ABI is hashed as declared: a function declared without its outputs is not the same
+        // class as one declared with empty outputs
+        let mut with_outputs = class(Value::Null, serde_json::json!({}));
+        with_outputs["abi"][0]["outputs"] = serde_json::json!([]);
+        assert_ne!(compute_legacy_class_hash(&with_outputs).unwrap(), hash);
+    }
+}
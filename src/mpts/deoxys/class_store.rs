@@ -0,0 +1,186 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use mp_convert::field_element::FromFieldElement;
+use starknet_api::core::ClassHash;
+use starknet_core::utils::starknet_keccak;
+use starknet_ff::FieldElement;
+
+use super::compression::Compression;
+
+/// The full definition of a declared class, as serialized by the gateway.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClassDefinition {
+    /// The Sierra (or legacy Cairo 0) class.
+    pub sierra: Vec<u8>,
+    /// The CASM compiled from the Sierra class, if known.
+    pub casm: Option<Vec<u8>>,
+}
+
+/// Optional on-disk store of class definitions, kept alongside the class trie.
+///
+/// Definitions are stored in a content-addressed blob directory and indexed by class hash, so that
+/// identical Sierra or CASM payloads are only stored once:
+///
+/// ```text
+/// <root>/blobs/<keccak of payload>
+/// <root>/classes/<class hash>         (sierra blob id, casm blob id)
+/// <root>/abis/<class hash>            (abi blob id)
+/// ```
+///
+/// ABIs are optional and stored apart from the definitions, they are only used to decode storage
+/// for debugging, see [abi](super::abi).
+#[derive(Debug, Clone)]
+pub struct ClassStore {
+    root: PathBuf,
+    compression: Compression,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+impl ClassStore {
+    /// Opens the store at `root`, creating it if needed.
+    pub fn open(root: impl AsRef<Path>) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join("blobs"))?;
+        fs::create_dir_all(root.join("classes"))?;
+        fs::create_dir_all(root.join("abis"))?;
+        Ok(Self { root, compression: Compression::None })
+    }
+
+    /// Compresses the blobs written from now on. Existing blobs stay readable whatever compression
+    /// they were written with.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    fn class_path(&self, class_hash: &ClassHash) -> PathBuf {
+        self.root.join("classes").join(hex(&class_hash.0.0))
+    }
+
+    fn abi_path(&self, class_hash: &ClassHash) -> PathBuf {
+        self.root.join("abis").join(hex(&class_hash.0.0))
+    }
+
+    fn blob_path(&self, id: &str) -> PathBuf {
+        self.root.join("blobs").join(id)
+    }
+
+    /// Writes a payload to the blob directory unless it is already present, returning its id.
+    fn put_blob(&self, payload: &[u8]) -> io::Result<String> {
+        let id = hex(&starknet_keccak(payload).to_bytes_be());
+        let path = self.blob_path(&id);
+        if !path.exists() {
+            // write then rename so a crash never leaves a truncated blob behind
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, self.compression.encode(payload)?)?;
+            fs::rename(tmp, path)?;
+        }
+        Ok(id)
+    }
+
+    fn get_blob(&self, id: &str) -> io::Result<Vec<u8>> {
+        Compression::decode(&fs::read(self.blob_path(id))?)
+    }
+
+    /// Stores the definition of `class_hash`, replacing any previous definition.
+    pub fn insert(&self, class_hash: &ClassHash, definition: &ClassDefinition) -> io::Result<()> {
+        let sierra = self.put_blob(&definition.sierra)?;
+        let casm = definition.casm.as_deref().map(|casm| self.put_blob(casm)).transpose()?.unwrap_or_default();
+
+        let path = self.class_path(class_hash);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, format!("{sierra}\n{casm}"))?;
+        fs::rename(tmp, path)
+    }
+
+    /// Returns the definition of `class_hash`, if it was stored.
+    pub fn get(&self, class_hash: &ClassHash) -> io::Result<Option<ClassDefinition>> {
+        let index = match fs::read_to_string(self.class_path(class_hash)) {
+            Ok(index) => index,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (sierra, casm) = index
+            .split_once('\n')
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Malformed class index entry"))?;
+
+        let sierra = self.get_blob(sierra)?;
+        let casm = if casm.is_empty() { None } else { Some(self.get_blob(casm)?) };
+
+        Ok(Some(ClassDefinition { sierra, casm }))
+    }
+
+    /// Stores the JSON ABI of `class_hash`, replacing any previous ABI.
+    pub fn insert_abi(&self, class_hash: &ClassHash, abi: &[u8]) -> io::Result<()> {
+        let id = self.put_blob(abi)?;
+        let path = self.abi_path(class_hash);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, id)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Returns the JSON ABI of `class_hash`, if it was stored.
+    pub fn abi(&self, class_hash: &ClassHash) -> io::Result<Option<Vec<u8>>> {
+        match fs::read_to_string(self.abi_path(class_hash)) {
+            Ok(id) => self.get_blob(&id).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns true if a definition is stored for `class_hash`.
+    pub fn contains(&self, class_hash: &ClassHash) -> bool {
+        self.class_path(class_hash).exists()
+    }
+
+    /// Returns the hashes of all the stored classes.
+    pub fn class_hashes(&self) -> io::Result<Vec<ClassHash>> {
+        let mut class_hashes = Vec::new();
+        for entry in fs::read_dir(self.root.join("classes"))? {
+            let name = entry?.file_name();
+            // skips leftover temporary files
+            let Some(class_hash) = name.to_str().and_then(|name| FieldElement::from_hex_be(name).ok()) else {
+                continue;
+            };
+            class_hashes.push(ClassHash::from_field_element(class_hash));
+        }
+        Ok(class_hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+
+    #[test]
+    fn test_class_store_dedup() {
+        let root = std::env::temp_dir().join(format!("starkroot-class-store-{}", std::process::id()));
+        let store = ClassStore::open(&root).unwrap().with_compression(Compression::Lz4);
+
+        let definition = ClassDefinition { sierra: b"sierra".to_vec(), casm: Some(b"casm".to_vec()) };
+        let class_a = ClassHash(StarkFelt::from(1_u64));
+        let class_b = ClassHash(StarkFelt::from(2_u64));
+        store.insert(&class_a, &definition).unwrap();
+        store.insert(&class_b, &definition).unwrap();
+
+        assert_eq!(store.get(&class_a).unwrap(), Some(definition.clone()));
+        assert_eq!(store.get(&class_b).unwrap(), Some(definition));
+        assert_eq!(store.get(&ClassHash(StarkFelt::from(3_u64))).unwrap(), None);
+        assert_eq!(store.class_hashes().unwrap().len(), 2);
+        // both classes share the same two blobs
+        assert_eq!(fs::read_dir(root.join("blobs")).unwrap().count(), 2);
+
+        store.insert_abi(&class_a, b"[]").unwrap();
+        assert_eq!(store.abi(&class_a).unwrap(), Some(b"[]".to_vec()));
+        assert_eq!(store.abi(&class_b).unwrap(), None);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use cairo_lang_starknet_classes::casm_contract_class::CasmContractClass;
+use cairo_lang_starknet_classes::contract_class::ContractClass as SierraClass;
+use starknet_api::core::{ClassHash, CompiledClassHash};
+use starknet_api::hash::StarkFelt;
+use starknet_types_core::felt::Felt;
+
+use super::class_hash::compute_compiled_class_hash;
+use super::class_store::{ClassDefinition, ClassStore};
+use super::config::ChainConfig;
+use super::error::CommitError;
+use super::report::VerificationReport;
+use super::runtime::class_store;
+
+/// How inconsistent declares are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerificationMode {
+    /// Mismatches are reported, the declares are still accepted.
+    #[default]
+    Lenient,
+    /// The first mismatch rejects the state diff.
+    Strict,
+}
+
+/// A declared class whose `compiled_class_hash` does not match the CASM compiled from its Sierra.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassMismatch {
+    pub class_hash: ClassHash,
+    pub declared: CompiledClassHash,
+    pub computed: CompiledClassHash,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClassVerificationError {
+    #[error("failed to read class {0:?} from the class store: {1}")]
+    Store(ClassHash, std::io::Error),
+    #[error("failed to deserialize Sierra class {0:?}: {1}")]
+    Deserialize(ClassHash, serde_json::Error),
+    #[error("failed to compile Sierra class {0:?}: {1}")]
+    Compilation(ClassHash, String),
+    #[error(
+        "declared compiled class hash {:?} of class {:?} does not match computed {:?}",
+        .0.declared,
+        .0.class_hash,
+        .0.computed
+    )]
+    Mismatch(ClassMismatch),
+}
+
+/// Compiles a Sierra class to CASM with the bundled compiler and returns its compiled class hash.
+pub fn compiled_class_hash(
+    class_hash: ClassHash,
+    sierra: &[u8],
+) -> Result<CompiledClassHash, ClassVerificationError> {
+    let sierra: SierraClass =
+        serde_json::from_slice(sierra).map_err(|e| ClassVerificationError::Deserialize(class_hash, e))?;
+    let casm = CasmContractClass::from_contract_class(sierra, false, usize::MAX)
+        .map_err(|e| ClassVerificationError::Compilation(class_hash, e.to_string()))?;
+    Ok(compute_compiled_class_hash(&casm))
+}
+
+/// Verifies the compiled class hash of every declared class of a state diff whose definition is in
+/// the class store.
+///
+/// Classes without a stored definition are skipped.
+///
+/// # Arguments
+///
+/// * `csd`   - Commitment state diff for the current block.
+/// * `store` - The class definition store.
+/// * `mode`  - Whether mismatches are rejected or only reported.
+///
+/// # Returns
+///
+/// Every mismatch found (always empty in [VerificationMode::Strict]).
+pub fn verify_declared_classes(
+    csd: &CommitmentStateDiff,
+    store: &ClassStore,
+    mode: VerificationMode,
+) -> Result<Vec<ClassMismatch>, ClassVerificationError> {
+    verify_declared_classes_with(csd, store, mode, compiled_class_hash)
+}
+
+/// [verify_declared_classes] with the compiler computing the compiled class hash of a class from its
+/// Sierra.
+fn verify_declared_classes_with(
+    csd: &CommitmentStateDiff,
+    store: &ClassStore,
+    mode: VerificationMode,
+    compiled_class_hash: impl Fn(ClassHash, &[u8]) -> Result<CompiledClassHash, ClassVerificationError>,
+) -> Result<Vec<ClassMismatch>, ClassVerificationError> {
+    let mut mismatches = Vec::new();
+
+    for (class_hash, declared) in csd.class_hash_to_compiled_class_hash.iter() {
+        let definition = store.get(class_hash).map_err(|e| ClassVerificationError::Store(*class_hash, e))?;
+        let Some(definition) = definition else {
+            continue;
+        };
+
+        let computed = compiled_class_hash(*class_hash, &definition.sierra)?;
+        if computed != *declared {
+            let mismatch = ClassMismatch { class_hash: *class_hash, declared: *declared, computed };
+            match mode {
+                VerificationMode::Strict => return Err(ClassVerificationError::Mismatch(mismatch)),
+                VerificationMode::Lenient => mismatches.push(mismatch),
+            }
+        }
+    }
+
+    Ok(mismatches)
+}
+
+/// Verifies the declared classes of a block against the [class store](class_store) before it is
+/// committed, as configured by [ChainConfig::class_verification].
+///
+/// In [VerificationMode::Strict] the block is rejected on the first inconsistent declare, or when a
+/// class cannot be checked at all. In [VerificationMode::Lenient] the mismatches are only reported.
+pub(crate) fn verify_ingested_classes(
+    csd: &CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<(), CommitError> {
+    let Some(mode) = config.class_verification else {
+        return Ok(());
+    };
+    if csd.class_hash_to_compiled_class_hash.is_empty() {
+        return Ok(());
+    }
+    let Some(store) = class_store() else {
+        return Ok(());
+    };
+
+    match verify_declared_classes(csd, &store, mode) {
+        Ok(_mismatches) => {
+            #[cfg(feature = "tracing")]
+            if !_mismatches.is_empty() {
+                let report = mismatches_report(block_number, &_mismatches);
+                tracing::warn!(block_number, report = %report.to_json(), "Block declares inconsistent classes");
+            }
+            Ok(())
+        }
+        Err(error) if mode == VerificationMode::Strict => Err(CommitError::ClassVerification { block_number, error }),
+        Err(_error) => {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(block_number, error = %_error, "Failed to verify the declared classes");
+            Ok(())
+        }
+    }
+}
+
+/// Reports the mismatches found by [verify_declared_classes] in the machine-readable
+/// [VerificationReport] format, with one failed `compiled_class_hash` check per mismatch.
+pub fn mismatches_report(block_number: u64, mismatches: &[ClassMismatch]) -> VerificationReport {
+    let mut report = VerificationReport::new("declared_classes").with_context("block_number", block_number);
+    let felt = |felt: &StarkFelt| Felt::from_bytes_be(&felt.0);
+    for ClassMismatch { class_hash, declared, computed } in mismatches {
+        let field = format!("compiled_class_hash[{:#x}]", felt(&class_hash.0));
+        report.check(field, Some(felt(&declared.0)), Some(felt(&computed.0)));
+    }
+    report
+}
+
+/// Result of a [recompilation audit](spawn_recompilation_audit).
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    /// Number of classes which were recompiled.
+    pub checked: usize,
+    /// Classes without a known compiled class hash, or without CASM (legacy classes).
+    pub skipped: Vec<ClassHash>,
+    /// Classes whose recompiled hash differs from the one they were declared with.
+    pub mismatches: Vec<ClassMismatch>,
+    /// Classes which could not be read or recompiled.
+    pub failures: Vec<(ClassHash, String)>,
+    /// Whether the audit was cancelled before walking every class.
+    pub cancelled: bool,
+}
+
+/// Handle over a running [recompilation audit](spawn_recompilation_audit).
+#[derive(Debug)]
+pub struct AuditHandle {
+    progress: Arc<AtomicUsize>,
+    cancel: Arc<AtomicBool>,
+    handle: JoinHandle<Result<AuditReport, std::io::Error>>,
+}
+
+impl AuditHandle {
+    /// Number of classes processed so far.
+    pub fn progress(&self) -> usize {
+        self.progress.load(Ordering::Relaxed)
+    }
+
+    /// Requests the audit to stop after the class being processed.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Waits for the audit to complete and returns its report.
+    pub fn join(self) -> Result<AuditReport, std::io::Error> {
+        self.handle.join().expect("Recompilation audit panicked")
+    }
+}
+
+/// Spawns a background job which recompiles every stored Sierra class with the bundled compiler and
+/// compares the result with the compiled class hash it was declared with.
+///
+/// This is meant to be run when the network migrates to a new Sierra -> CASM compiler version, to
+/// find out which classes would compile differently.
+///
+/// # Arguments
+///
+/// * `store`    - The class definition store.
+/// * `declared` - Returns the compiled class hash a class was declared with, if known, ie:
+///   [stored_compiled_class_hash].
+pub fn spawn_recompilation_audit<F>(store: ClassStore, declared: F) -> AuditHandle
+where
+    F: Fn(&ClassHash, &ClassDefinition) -> Option<CompiledClassHash> + Send + 'static,
+{
+    spawn_recompilation_audit_with(store, declared, compiled_class_hash)
+}
+
+/// The compiled class hash of the CASM a class was stored with, as compiled when the class was
+/// declared. `None` if the class was stored without its CASM or if the CASM cannot be read.
+pub fn stored_compiled_class_hash(_class_hash: &ClassHash, definition: &ClassDefinition) -> Option<CompiledClassHash> {
+    let casm = serde_json::from_slice::<CasmContractClass>(definition.casm.as_deref()?).ok()?;
+    Some(compute_compiled_class_hash(&casm))
+}
+
+/// [spawn_recompilation_audit] with the compiler computing the compiled class hash of a class from
+/// its Sierra.
+fn spawn_recompilation_audit_with<F, C>(store: ClassStore, declared: F, compiled_class_hash: C) -> AuditHandle
+where
+    F: Fn(&ClassHash, &ClassDefinition) -> Option<CompiledClassHash> + Send + 'static,
+    C: Fn(ClassHash, &[u8]) -> Result<CompiledClassHash, ClassVerificationError> + Send + 'static,
+{
+    let progress = Arc::new(AtomicUsize::new(0));
+    let cancel = Arc::new(AtomicBool::new(false));
+
+    let handle = {
+        let progress = Arc::clone(&progress);
+        let cancel = Arc::clone(&cancel);
+        thread::spawn(move || {
+            let mut report = AuditReport::default();
+
+            for class_hash in store.class_hashes()? {
+                if cancel.load(Ordering::Relaxed) {
+                    report.cancelled = true;
+                    break;
+                }
+                progress.fetch_add(1, Ordering::Relaxed);
+
+                let definition = match store.get(&class_hash) {
+                    Ok(Some(definition)) if definition.casm.is_some() => definition,
+                    Ok(_) => {
+                        report.skipped.push(class_hash);
+                        continue;
+                    }
+                    Err(e) => {
+                        report.failures.push((class_hash, e.to_string()));
+                        continue;
+                    }
+                };
+                let Some(declared) = declared(&class_hash, &definition) else {
+                    report.skipped.push(class_hash);
+                    continue;
+                };
+
+                match compiled_class_hash(class_hash, &definition.sierra) {
+                    Ok(computed) => {
+                        report.checked += 1;
+                        if computed != declared {
+                            report.mismatches.push(ClassMismatch { class_hash, declared, computed });
+                        }
+                    }
+                    Err(e) => report.failures.push((class_hash, e.to_string())),
+                }
+            }
+
+            Ok(report)
+        })
+    };
+
+    AuditHandle { progress, cancel, handle }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::engine::StateTries;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+    use crate::mpts::deoxys::roots::root_registry;
+    use crate::mpts::deoxys::runtime::{exclusive, init, RestoreRuntime};
+    use crate::mpts::deoxys::settings::CommitmentConfig;
+
+    fn declare(classes: &[(ClassHash, CompiledClassHash)]) -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: classes.iter().copied().collect(),
+        }
+    }
+
+    fn store(name: &str) -> (std::path::PathBuf, ClassStore) {
+        let root = std::env::temp_dir().join(format!("starkroot-{name}-{}", std::process::id()));
+        let store = ClassStore::open(&root).unwrap();
+        (root, store)
+    }
+
+    #[test]
+    fn test_verify_declared_classes() {
+        let (root, store) = store("class-verification");
+        let (consistent, inconsistent, unknown) =
+            (ClassHash(StarkFelt::ONE), ClassHash(StarkFelt::TWO), ClassHash(StarkFelt::THREE));
+        for class_hash in [consistent, inconsistent] {
+            store.insert(&class_hash, &ClassDefinition { sierra: b"{}".to_vec(), casm: None }).unwrap();
+        }
+        let compiled = CompiledClassHash(StarkFelt::from(0x42_u64));
+        let declared = CompiledClassHash(StarkFelt::from(0x43_u64));
+        let csd = declare(&[(consistent, compiled), (inconsistent, declared), (unknown, declared)]);
+        let compile = |_: ClassHash, _: &[u8]| Ok(compiled);
+
+        let mismatch = ClassMismatch { class_hash: inconsistent, declared, computed: compiled };
+        let mismatches = verify_declared_classes_with(&csd, &store, VerificationMode::Lenient, compile).unwrap();
+        assert_eq!(mismatches, vec![mismatch.clone()]);
+        assert_eq!(mismatches_report(1, &mismatches).checks.len(), 1);
+        match verify_declared_classes_with(&csd, &store, VerificationMode::Strict, compile) {
+            Err(ClassVerificationError::Mismatch(rejected)) => assert_eq!(rejected, mismatch),
+            result => panic!("inconsistent declare was not rejected: {result:?}"),
+        }
+
+        // Sierra which cannot be deserialized is never compiled
+        assert!(matches!(
+            verify_declared_classes(&csd, &store, VerificationMode::Lenient),
+            Err(ClassVerificationError::Deserialize(class_hash, _)) if class_hash == consistent
+        ));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_recompilation_audit() {
+        let (root, store) = store("class-audit");
+        let class = |class_hash: u64, sierra: &[u8], casm: bool| {
+            let definition = ClassDefinition { sierra: sierra.to_vec(), casm: casm.then(|| b"{}".to_vec()) };
+            store.insert(&ClassHash(StarkFelt::from(class_hash)), &definition).unwrap();
+            ClassHash(StarkFelt::from(class_hash))
+        };
+        let _consistent = class(1, b"compiled", true);
+        let recompiled = class(2, b"recompiled", true);
+        let without_casm = class(3, b"compiled", false);
+        let undeclared = class(4, b"compiled", true);
+        let uncompilable = class(5, b"uncompilable", true);
+
+        let (compiled, recompiled_hash) = (CompiledClassHash(StarkFelt::ONE), CompiledClassHash(StarkFelt::TWO));
+        let audit = spawn_recompilation_audit_with(
+            store,
+            move |class_hash, _| (*class_hash != undeclared).then_some(compiled),
+            move |class_hash, sierra| match sierra {
+                b"compiled" => Ok(compiled),
+                b"recompiled" => Ok(recompiled_hash),
+                _ => Err(ClassVerificationError::Compilation(class_hash, "unsupported".to_string())),
+            },
+        );
+        let progress = audit.progress.clone();
+        let mut report = audit.join().unwrap();
+        // Classes are walked in the order of the store's directory listing
+        report.skipped.sort();
+
+        assert_eq!(progress.load(Ordering::Relaxed), 5);
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report.mismatches,
+            vec![ClassMismatch { class_hash: recompiled, declared: compiled, computed: recompiled_hash }]
+        );
+        assert_eq!(report.skipped, vec![without_casm, undeclared]);
+        assert_eq!(report.failures.iter().map(|(class_hash, _)| *class_hash).collect::<Vec<_>>(), vec![uncompilable]);
+        assert!(!report.cancelled);
+
+        // A CASM which cannot be read tells nothing about the compiled class hash the class was declared with
+        let definition = ClassDefinition { sierra: Vec::new(), casm: Some(b"{}".to_vec()) };
+        assert_eq!(stored_compiled_class_hash(&recompiled, &definition), None);
+        assert_eq!(stored_compiled_class_hash(&recompiled, &ClassDefinition::default()), None);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_ingested_classes() {
+        let _exclusive = exclusive();
+        let _restore = RestoreRuntime::new();
+        let (root, store) = store("class-ingestion");
+        let mut config = CommitmentConfig::default();
+        config.storage.class_store = Some(root.clone());
+        init(config).unwrap();
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let class_hash = ClassHash(StarkFelt::ONE);
+        store.insert(&class_hash, &ClassDefinition { sierra: b"{}".to_vec(), casm: None }).unwrap();
+        let csd = || declare(&[(class_hash, CompiledClassHash(StarkFelt::ONE))]);
+
+        // The class cannot be checked: strict mode rejects the block before touching the tries
+        let strict = ChainConfig { class_verification: Some(VerificationMode::Strict), ..Default::default() };
+        let rejected = try_update_state_root(&tries, csd(), 1, &strict);
+        assert!(matches!(
+            rejected,
+            Err(CommitError::ClassVerification { block_number: 1, error: ClassVerificationError::Deserialize(..) })
+        ));
+        assert!(root_registry(&tries).get(1).is_none());
+
+        let lenient = ChainConfig { class_verification: Some(VerificationMode::Lenient), ..Default::default() };
+        try_update_state_root(&tries, csd(), 1, &lenient).unwrap();
+        assert!(root_registry(&tries).get(1).is_some());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,28 +1,56 @@
 use blockifier::state::cached_state::CommitmentStateDiff;
-use mc_db::storage_handler::{self, DeoxysStorageError};
+use mc_db::storage_handler;
 use mp_felt::Felt252Wrapper;
-use mp_hashers::poseidon::PoseidonHasher;
-use mp_hashers::HasherT;
 use rayon::prelude::*;
+use starknet_api::core::ClassHash;
 use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
 
-// "CONTRACT_CLASS_LEAF_V0"
-const CONTRACT_CLASS_HASH_VERSION: FieldElement =
-    FieldElement::from_mont([9331882290187415277, 12057587991035439952, 18444375821049509847, 115292049744600508]);
+use super::atomic::Trie;
+use super::config::ChainConfig;
+use super::engine::StateTries;
+use super::error::{ErrorContext, ResultExt, TrieError};
+use super::proof::{felt_to_path, from_bonsai, ProofError, ProofNode};
+use super::recording::Interaction;
+use super::report::VerificationReport;
+use super::storage_proof::{state_commitment, StorageProofError};
 
 /// Calculates the class trie root
 ///
+/// The keys of `csd` must have been [validated](super::conversions::validate_trie_keys) beforehand.
+///
 /// # Arguments
 ///
+/// * `tries`        - The tries to update.
 /// * `csd`          - Commitment state diff for the current block.
-/// * `bonsai_class` - Bonsai db used to store class hashes.
 /// * `block_number` - The current block number.
+/// * `config`       - Chain-specific commitment rules.
 ///
 /// # Returns
 ///
 /// The class root.
-pub fn class_trie_root(csd: &CommitmentStateDiff, block_number: u64) -> Result<Felt252Wrapper, DeoxysStorageError> {
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(block_number = block_number, classes = csd.class_hash_to_compiled_class_hash.len())
+    )
+)]
+pub(crate) fn class_trie_root(
+    tries: &StateTries,
+    csd: &CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<Felt252Wrapper, TrieError> {
+    if let Some(mut engine) = tries.engine() {
+        return engine.update_classes(csd, block_number, config);
+    }
+    if !config.hashers.node_db_compatible() {
+        return Err(TrieError::NodeHash);
+    }
+
     let mut handler_class = storage_handler::class_trie_mut();
+    let capture = &tries.state().capture;
 
     let updates = csd
         .class_hash_to_compiled_class_hash
@@ -31,28 +59,202 @@ pub fn class_trie_root(csd: &CommitmentStateDiff, block_number: u64) -> Result<F
         .map(|(class_hash, compiled_class_hash)| {
             let compiled_class_hash = FieldElement::from_bytes_be(&compiled_class_hash.0.0).unwrap();
 
-            let hash = PoseidonHasher::hash_elements(CONTRACT_CLASS_HASH_VERSION, compiled_class_hash);
+            let leaf_hash = class_commitment_leaf_hash(compiled_class_hash, config);
+            capture.record(block_number, || Interaction::ClassLeaf {
+                class_hash: Felt::from_bytes_be(&class_hash.0.0),
+                leaf_hash: Felt::from_bytes_be(&leaf_hash.to_bytes_be()),
+            });
 
-            (class_hash, hash)
+            (class_hash, leaf_hash)
         })
         .collect::<Vec<_>>();
 
-    handler_class.init()?;
-    handler_class.update(updates)?;
-    handler_class.commit(block_number)?;
+    let context = || ErrorContext::block(block_number).trie(Trie::Classes);
+    handler_class.init().context(context)?;
+    handler_class.update(updates).context(context)?;
+    handler_class.commit(block_number).context(context)?;
 
-    Ok(handler_class.root()?.into())
+    let root = handler_class.root().context(context)?;
+    capture.record(block_number, || Interaction::TrieRoot { trie: Trie::Classes, root });
+    Ok(root.into())
+}
+
+/// Computes the leaf value of a class in the class trie.
+///
+/// This is _not_ the compiled class hash itself, but `h(class_leaf_version, compiled_class_hash)`
+/// with the [class leaf hash](super::config::TrieHashers::class_leaf) and
+/// [version](super::consts::ProtocolConstants::class_leaf_version) of `config`, ie:
+/// `Poseidon("CONTRACT_CLASS_LEAF_V0", compiled_class_hash)` on Starknet.
+pub fn class_commitment_leaf_hash(compiled_class_hash: FieldElement, config: &ChainConfig) -> FieldElement {
+    config.hashers.class_leaf.hash_elements(config.constants.class_leaf_version, compiled_class_hash)
+}
+
+/// Proof that a class is declared on Starknet, for L1 or cross-chain verifiers.
+///
+/// It bundles the class trie proof of the class' leaf with the roots needed to link the class trie
+/// to the state root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassDeclarationProof {
+    pub block_number: u64,
+    pub class_hash: Felt,
+    /// The class trie leaf, see [class_commitment_leaf_hash].
+    pub leaf_hash: Felt,
+    /// Class trie proof of `class_hash`, root first.
+    pub class_proof: Vec<ProofNode>,
+    pub classes_trie_root: Felt,
+    pub contracts_trie_root: Felt,
+    pub state_root: Felt,
+}
+
+impl ClassDeclarationProof {
+    /// Verifies the proof, with the Starknet commitment rules.
+    pub fn verify(&self) -> Result<(), ProofError> {
+        self.verify_with_config(&ChainConfig::default())
+    }
+
+    /// Verifies that the class trie proof proves `leaf_hash` under `classes_trie_root`, and that the
+    /// roots hash to `state_root`, with chain-specific commitment rules.
+    pub fn verify_with_config(&self, config: &ChainConfig) -> Result<(), ProofError> {
+        let key = felt_to_path(&self.class_hash);
+        let proven = config.hashers.classes_node.verify_proof(self.classes_trie_root, &key, &self.class_proof)?;
+        if proven != Some(self.leaf_hash) {
+            return Err(ProofError::ValueMismatch { expected: Some(self.leaf_hash), proven });
+        }
+
+        let state_root = state_commitment(self.contracts_trie_root, self.classes_trie_root, config);
+        if state_root != self.state_root {
+            return Err(ProofError::StateRootMismatch { expected: self.state_root, computed: state_root });
+        }
+
+        Ok(())
+    }
+
+    /// Verifies the proof, see [ClassDeclarationProof::verify], and reports the result in the
+    /// machine-readable [VerificationReport] format.
+    pub fn report(&self) -> VerificationReport {
+        let mut report = VerificationReport::new("class_declaration_proof")
+            .with_context("block_number", self.block_number)
+            .with_context("class_hash", format!("{:#x}", self.class_hash));
+
+        match self.verify() {
+            Ok(()) => {
+                report.check("value", Some(self.leaf_hash), Some(self.leaf_hash));
+                report.check("state_root", Some(self.state_root), Some(self.state_root));
+            }
+            Err(e) => report.proof_error(&e),
+        }
+
+        report
+    }
+
+    /// Checks that the proven leaf commits to `compiled_class_hash`, with the [class leaf
+    /// hash](super::config::TrieHashers::class_leaf) of `config`.
+    pub fn commits_to(&self, compiled_class_hash: FieldElement, config: &ChainConfig) -> bool {
+        let leaf_hash = class_commitment_leaf_hash(compiled_class_hash, config);
+        Felt::from(Felt252Wrapper::from(leaf_hash)) == self.leaf_hash
+    }
+}
+
+/// Generates a [ClassDeclarationProof] for `class_hash` from the committed tries.
+///
+/// The proof is read from the trie versions of `block_number`, which committed blocks never modify.
+/// The tries of an [engine](super::engine::CommitmentEngine) generate it from their snapshot of the
+/// block.
+///
+/// # Arguments
+///
+/// * `tries`        - The committed tries.
+/// * `class_hash`   - The hash of the declared class.
+/// * `block_number` - The block the proof is generated at.
+/// * `config`       - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The proof, or `None` if the class was not declared at `block_number`.
+pub fn class_declaration_proof(
+    tries: &StateTries,
+    class_hash: &ClassHash,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<Option<ClassDeclarationProof>, StorageProofError> {
+    // The engine is only locked while the snapshot is opened
+    let view = match tries.engine() {
+        Some(engine) => Some(engine.view_at(block_number)?.ok_or(StorageProofError::NotCommitted { block_number })?),
+        None => None,
+    };
+    if let Some(view) = view {
+        return Ok(view.class_declaration_proof(class_hash, config)?);
+    }
+    if !config.hashers.node_db_compatible() {
+        return Err(TrieError::NodeHash.into());
+    }
+
+    let not_committed = || StorageProofError::NotCommitted { block_number };
+    let handler_class = storage_handler::class_trie();
+    let classes_trie_root = handler_class.root_at(block_number)?.ok_or_else(not_committed)?;
+    let contracts_trie_root = storage_handler::contract_trie().root_at(block_number)?.ok_or_else(not_committed)?;
+    let Some(leaf_hash) = handler_class.get_at(class_hash, block_number)? else {
+        return Ok(None);
+    };
+    let class_proof = handler_class.get_proof_at(class_hash, block_number)?.into_iter().map(from_bonsai).collect();
+    let state_root = state_commitment(contracts_trie_root, classes_trie_root, config);
+
+    Ok(Some(ClassDeclarationProof {
+        block_number,
+        class_hash: Felt::from_bytes_be(&class_hash.0.0),
+        leaf_hash,
+        class_proof,
+        classes_trie_root,
+        contracts_trie_root,
+        state_root,
+    }))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+    use starknet_api::core::CompiledClassHash;
+    use starknet_api::hash::StarkFelt;
+
     use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+
+    fn declare(class_hash: ClassHash, compiled_class_hash: u64) -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: [(class_hash, CompiledClassHash(StarkFelt::from(compiled_class_hash)))]
+                .into_iter()
+                .collect(),
+        }
+    }
 
     #[test]
-    fn test_contract_class_hash_version() {
-        assert_eq!(
-            CONTRACT_CLASS_HASH_VERSION,
-            FieldElement::from_byte_slice_be("CONTRACT_CLASS_LEAF_V0".as_bytes()).unwrap(),
-        );
+    fn test_class_declaration_proof_at() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        let (first, second) = (ClassHash(StarkFelt::ONE), ClassHash(StarkFelt::TWO));
+        let first_root = try_update_state_root(&tries, declare(first, 0x10), 1, &config).unwrap();
+        let second_root = try_update_state_root(&tries, declare(second, 0x20), 2, &config).unwrap();
+
+        // The proofs of block 1 are read from its version of the tries, which moved on to block 2
+        let proof = class_declaration_proof(&tries, &first, 1, &config).unwrap().unwrap();
+        proof.verify().unwrap();
+        assert_eq!((proof.block_number, proof.state_root), (1, Felt::from(first_root)));
+        assert!(proof.commits_to(FieldElement::from(0x10_u64), &config));
+        assert_eq!(class_declaration_proof(&tries, &second, 1, &config).unwrap(), None);
+
+        let proof = class_declaration_proof(&tries, &second, 2, &config).unwrap().unwrap();
+        proof.verify().unwrap();
+        assert_eq!((proof.block_number, proof.state_root), (2, Felt::from(second_root)));
+        assert!(matches!(
+            class_declaration_proof(&tries, &first, 3, &config),
+            Err(StorageProofError::NotCommitted { block_number: 3 })
+        ));
     }
 }
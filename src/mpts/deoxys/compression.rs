@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use super::atomic::Trie;
+
+/// Prefix of compressed payloads. Payloads without it are stored uncompressed, which is what lets
+/// a backend enable compression without rewriting existing data.
+const MAGIC: &[u8; 3] = b"SRZ";
+
+const TAG_LZ4: u8 = 1;
+const TAG_ZSTD: u8 = 2;
+
+/// Compression applied to payloads written to the backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    /// Zstandard at the given level (1 to 22).
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Encodes a payload for storage.
+    pub fn encode(self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let (tag, compressed) = match self {
+            // an uncompressed payload which happens to start with the magic must still be tagged
+            Compression::None if !payload.starts_with(MAGIC) => return Ok(payload.to_vec()),
+            Compression::None => (0, payload.to_vec()),
+            Compression::Lz4 => (TAG_LZ4, lz4_flex::compress_prepend_size(payload)),
+            Compression::Zstd(level) => (TAG_ZSTD, zstd::bulk::compress(payload, level)?),
+        };
+
+        let mut encoded = Vec::with_capacity(MAGIC.len() + 1 + compressed.len());
+        encoded.extend(MAGIC);
+        encoded.push(tag);
+        encoded.extend(compressed);
+        Ok(encoded)
+    }
+
+    /// Decodes a stored payload, whatever compression it was written with.
+    pub fn decode(stored: &[u8]) -> io::Result<Vec<u8>> {
+        let Some(tagged) = stored.strip_prefix(MAGIC) else {
+            return Ok(stored.to_vec());
+        };
+        let invalid = |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        match tagged.split_first() {
+            Some((0, payload)) => Ok(payload.to_vec()),
+            Some((&TAG_LZ4, payload)) => lz4_flex::decompress_size_prepended(payload).map_err(|e| invalid(&e)),
+            Some((&TAG_ZSTD, payload)) => zstd::stream::decode_all(payload),
+            _ => Err(invalid(&"unknown compression tag")),
+        }
+    }
+
+    /// Re-encodes a stored payload with this compression, to migrate existing data.
+    pub fn migrate(self, stored: &[u8]) -> io::Result<Vec<u8>> {
+        self.encode(&Self::decode(stored)?)
+    }
+}
+
+/// Per-trie compression settings of the node payloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrieCompression {
+    pub contract_storage: Compression,
+    pub contracts: Compression,
+    pub classes: Compression,
+}
+
+impl TrieCompression {
+    pub fn get(&self, trie: Trie) -> Compression {
+        match trie {
+            Trie::ContractStorage => self.contract_storage,
+            Trie::Contracts => self.contracts,
+            Trie::Classes => self.classes,
+        }
+    }
+
+    /// Encodes a node payload of `trie`, accounting for it in [trie_stats].
+    pub fn encode(&self, trie: Trie, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let encoded = self.get(trie).encode(payload)?;
+        compression_stats().record(trie, payload.len(), encoded.len());
+        Ok(encoded)
+    }
+}
+
+/// Bytes written to a trie before and after compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub raw_bytes: u64,
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Ratio of raw to stored bytes, `1.0` when nothing was written.
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+#[derive(Debug, Default)]
+struct CompressionRegistry {
+    tries: HashMap<Trie, CompressionStats>,
+}
+
+impl CompressionRegistry {
+    fn record(&mut self, trie: Trie, raw: usize, stored: usize) {
+        let stats = self.tries.entry(trie).or_default();
+        stats.raw_bytes += raw as u64;
+        stats.stored_bytes += stored as u64;
+    }
+}
+
+static COMPRESSION_STATS: OnceLock<Mutex<CompressionRegistry>> = OnceLock::new();
+
+fn compression_stats() -> MutexGuard<'static, CompressionRegistry> {
+    COMPRESSION_STATS.get_or_init(Default::default).lock().expect("Poisoned lock on compression stats")
+}
+
+/// Storage statistics of the tries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrieStats {
+    pub contract_storage: CompressionStats,
+    pub contracts: CompressionStats,
+    pub classes: CompressionStats,
+}
+
+/// Returns the storage statistics of the node payloads encoded through [TrieCompression] since the
+/// process started.
+pub fn trie_stats() -> TrieStats {
+    let registry = compression_stats();
+    let get = |trie| registry.tries.get(&trie).copied().unwrap_or_default();
+    TrieStats {
+        contract_storage: get(Trie::ContractStorage),
+        contracts: get(Trie::Contracts),
+        classes: get(Trie::Classes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_migration() {
+        let payload = vec![7u8; 1024];
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd(3)] {
+            let encoded = compression.encode(&payload).unwrap();
+            assert_eq!(Compression::decode(&encoded).unwrap(), payload);
+        }
+        assert!(Compression::Lz4.encode(&payload).unwrap().len() < payload.len());
+
+        // legacy uncompressed payloads are still readable, and can be migrated
+        let migrated = Compression::Zstd(3).migrate(&payload).unwrap();
+        assert_eq!(Compression::decode(&migrated).unwrap(), payload);
+
+        // uncompressed payloads colliding with the magic are tagged
+        let colliding = b"SRZ\x01".to_vec();
+        assert_eq!(Compression::decode(&Compression::None.encode(&colliding).unwrap()).unwrap(), colliding);
+    }
+}
@@ -0,0 +1,348 @@
+use std::time::Duration;
+
+use bitvec::prelude::{BitSlice, Msb0};
+use mp_felt::Felt252Wrapper;
+#[cfg(feature = "pedersen")]
+use mp_hashers::pedersen::PedersenHasher;
+use mp_hashers::poseidon::PoseidonHasher;
+use mp_hashers::HasherT;
+use starknet_api::hash::StarkFelt;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Pedersen, Poseidon};
+
+#[cfg(feature = "class-verification")]
+use super::class_verification::VerificationMode;
+use super::compression::TrieCompression;
+use super::consts::ProtocolConstants;
+use super::lib::calculate_state_root_with_prefix;
+use super::proof::{verify_proof, ProofError, ProofNode};
+use super::quarantine::FailureMode;
+use super::retry::RetryPolicy;
+use super::system_contracts::SystemContracts;
+
+/// Chain-specific rules applied when computing commitments.
+///
+/// The [Default] configuration matches Starknet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainConfig {
+    /// How storage writes of value zero are applied to the contract storage tries.
+    pub zero_writes: ZeroWriteSemantics,
+    /// Hash functions used for the commitments computed on top of the tries.
+    pub hashers: TrieHashers,
+    /// Domain separators hashed into the commitments.
+    pub constants: ProtocolConstants,
+    /// Contracts living at reserved addresses, with their own leaf conventions.
+    pub system_contracts: SystemContracts,
+    /// Whether committed storage writes are indexed by slot in the
+    /// [storage history](super::history::storage_history). This is off by default as the index grows
+    /// with every write. The index only covers the blocks committed since it was enabled.
+    pub index_storage_writes: bool,
+    /// Whether the blocks at which each contract changed are indexed in the
+    /// [contract activity index](super::history::contract_activity). Off by default. The index only
+    /// covers the blocks committed since it was enabled.
+    pub index_contract_activity: bool,
+    /// Compression of the node payloads of each trie, applied to the
+    /// [backends](super::backend::BonsaiBackend) of the [engines](super::engine::CommitmentEngine).
+    /// The global tries are stored by the node's database, which compresses them itself.
+    pub compression: TrieCompression,
+    /// Whether node payloads are [sealed](super::checksum::seal) with a checksum validated on read,
+    /// by the backends of the engines as for [compression](ChainConfig::compression).
+    pub node_checksums: bool,
+    /// How failures to update a single contract are handled.
+    pub failure_mode: FailureMode,
+    /// How transient backend errors are retried when reading and committing the tries.
+    pub retry: RetryPolicy,
+    /// Number of written storage slots whose proofs are [verified](super::canary::verify_sample)
+    /// against the new roots after each commit. `0` disables canary verification.
+    pub canary_sample: usize,
+    /// Maximum expected duration of a block commit, checked by the [SLA watchdog](super::watchdog).
+    pub commit_sla: Option<Duration>,
+    /// How the trie roots are combined into the state root.
+    pub state_commitment: StateCommitment,
+    /// Whether the compiled class hashes of the declared classes stored in the
+    /// [class store](super::runtime::class_store) are checked before the block is committed, see
+    /// [verify_declared_classes](super::class_verification::verify_declared_classes). Off by default.
+    #[cfg(feature = "class-verification")]
+    pub class_verification: Option<VerificationMode>,
+}
+
+impl ChainConfig {
+    /// The Starknet rules of the blocks of a protocol version, ie: `"0.10.3"`.
+    ///
+    /// Syncing from genesis means switching rules along the way: the configuration of each block is
+    /// that of the protocol version in its header.
+    pub fn for_protocol_version(version: &str) -> Self {
+        Self { state_commitment: StateCommitment::for_protocol_version(version), ..Default::default() }
+    }
+
+    /// Combines the contracts and classes trie roots into the state root.
+    pub fn state_root(&self, contracts_trie_root: Felt252Wrapper, classes_trie_root: Felt252Wrapper) -> Felt252Wrapper {
+        match (self.state_commitment, self.hashers.state_root) {
+            (StateCommitment::Legacy, _) => contracts_trie_root,
+            #[cfg(feature = "pedersen")]
+            (StateCommitment::V0, HashFunction::Pedersen) => calculate_state_root_with_prefix::<PedersenHasher>(
+                self.constants.state_prefix,
+                contracts_trie_root,
+                classes_trie_root,
+            ),
+            (StateCommitment::V0, HashFunction::Poseidon) => calculate_state_root_with_prefix::<PoseidonHasher>(
+                self.constants.state_prefix,
+                contracts_trie_root,
+                classes_trie_root,
+            ),
+        }
+    }
+}
+
+/// Parses a Starknet protocol version, ie: `"0.13.1.1"`, into its major, minor and patch numbers.
+pub(crate) fn protocol_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version.split('.').map(|part| part.parse::<u64>());
+    let (major, minor, patch) = (parts.next()?.ok()?, parts.next()?.ok()?, parts.next().unwrap_or(Ok(0)).ok()?);
+    Some((major, minor, patch))
+}
+
+/// How the state root is derived from the trie roots, which changed with Starknet v0.11.0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StateCommitment {
+    /// Before v0.11.0 there was no classes trie: the state root is the contracts trie root, a
+    /// Pedersen trie.
+    Legacy,
+    /// Since v0.11.0: `h("STARKNET_STATE_V0", contracts_trie_root, classes_trie_root)` with the
+    /// [state root hash](TrieHashers::state_root), or the contracts trie root as long as the classes
+    /// trie is empty.
+    #[default]
+    V0,
+}
+
+impl StateCommitment {
+    /// The state commitment of the blocks of a Starknet protocol version, ie: `"0.10.3"`.
+    ///
+    /// Unparsable versions are treated as the latest version.
+    pub fn for_protocol_version(version: &str) -> Self {
+        match protocol_version(version) {
+            Some(version) if version < (0, 11, 0) => StateCommitment::Legacy,
+            _ => StateCommitment::V0,
+        }
+    }
+}
+
+/// A hash function available to commitments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashFunction {
+    #[cfg(feature = "pedersen")]
+    Pedersen,
+    Poseidon,
+}
+
+impl HashFunction {
+    /// Hashes two field elements.
+    pub fn hash_elements(self, a: FieldElement, b: FieldElement) -> FieldElement {
+        match self {
+            #[cfg(feature = "pedersen")]
+            HashFunction::Pedersen => PedersenHasher::hash_elements(a, b),
+            HashFunction::Poseidon => PoseidonHasher::hash_elements(a, b),
+        }
+    }
+
+    /// Hashes an array of field elements.
+    pub fn compute_hash_on_elements(self, elements: &[FieldElement]) -> FieldElement {
+        match self {
+            #[cfg(feature = "pedersen")]
+            HashFunction::Pedersen => PedersenHasher::compute_hash_on_elements(elements),
+            HashFunction::Poseidon => PoseidonHasher::compute_hash_on_elements(elements),
+        }
+    }
+}
+
+/// How the transaction and event commitments of a block are computed, which changed with Starknet
+/// v0.13.2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitmentScheme {
+    /// Before v0.13.2: Pedersen tries whose leaves are `h(tx_hash, h(signature))` and
+    /// `h(from_address, h(keys), h(data))`, with Pedersen.
+    #[cfg(feature = "pedersen")]
+    Pedersen,
+    /// Since v0.13.2: Poseidon tries whose leaves are `h(tx_hash, signature...)` and
+    /// `h(from_address, tx_hash, keys_len, keys..., data_len, data...)`, with Poseidon.
+    Poseidon,
+}
+
+impl CommitmentScheme {
+    /// The scheme of the blocks of a Starknet protocol version, ie: `"0.13.1.1"`.
+    ///
+    /// Block headers carry a version since v0.9.1: an empty version is treated as a version before
+    /// v0.9.1. Other unparsable versions are treated as the latest version.
+    pub fn for_protocol_version(version: &str) -> Self {
+        #[cfg(feature = "pedersen")]
+        if version.is_empty() {
+            return CommitmentScheme::Pedersen;
+        }
+        match protocol_version(version) {
+            #[cfg(feature = "pedersen")]
+            Some(version) if version < (0, 13, 2) => CommitmentScheme::Pedersen,
+            _ => CommitmentScheme::Poseidon,
+        }
+    }
+}
+
+/// The hash of the nodes of a trie.
+///
+/// Unlike [HashFunction], Pedersen is available whatever the `pedersen` feature: the node's database
+/// hashes the nodes of the contract storage and contracts tries with Pedersen in any case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeHash {
+    Pedersen,
+    Poseidon,
+}
+
+impl NodeHash {
+    /// Verifies a proof of `key` against `root`, with the nodes hashed with this hash.
+    ///
+    /// # Returns
+    ///
+    /// The proven leaf, or `None` for a proof of non-membership.
+    pub fn verify_proof(
+        self,
+        root: Felt,
+        key: &BitSlice<u8, Msb0>,
+        proof: &[ProofNode],
+    ) -> Result<Option<Felt>, ProofError> {
+        match self {
+            NodeHash::Pedersen => verify_proof::<Pedersen>(root, key, proof),
+            NodeHash::Poseidon => verify_proof::<Poseidon>(root, key, proof),
+        }
+    }
+}
+
+/// Per-commitment hash function selection.
+///
+/// The [Default] matches Starknet. The node's database hashes the nodes of its tries as Starknet
+/// does: other node hashes are only supported with an
+/// [engine](super::engine::CommitmentEngine), see [TrieHashers::node_db_compatible].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrieHashers {
+    /// Hash of the contracts trie leaves, `h(h(h(class_hash, storage_root), nonce), 0)`.
+    pub contract_leaf: HashFunction,
+    /// Hash of the classes trie leaves, `h("CONTRACT_CLASS_LEAF_V0", compiled_class_hash)`.
+    pub class_leaf: HashFunction,
+    /// Hash combining the contracts and classes trie roots into the state root.
+    pub state_root: HashFunction,
+    /// Hash of the nodes of the contract storage tries.
+    pub storage_node: NodeHash,
+    /// Hash of the nodes of the contracts trie.
+    pub contracts_node: NodeHash,
+    /// Hash of the nodes of the classes trie.
+    pub classes_node: NodeHash,
+}
+
+/// Without the `pedersen` feature, the default is [TrieHashers::poseidon] but for the node hashes,
+/// which remain Starknet's.
+impl Default for TrieHashers {
+    #[cfg(feature = "pedersen")]
+    fn default() -> Self {
+        Self {
+            contract_leaf: HashFunction::Pedersen,
+            class_leaf: HashFunction::Poseidon,
+            state_root: HashFunction::Poseidon,
+            storage_node: NodeHash::Pedersen,
+            contracts_node: NodeHash::Pedersen,
+            classes_node: NodeHash::Poseidon,
+        }
+    }
+
+    #[cfg(not(feature = "pedersen"))]
+    fn default() -> Self {
+        let (storage_node, contracts_node, classes_node) = Self::NODE_DB;
+        Self { storage_node, contracts_node, classes_node, ..Self::poseidon() }
+    }
+}
+
+impl TrieHashers {
+    /// The node hashes of the contract storage, contracts and classes tries of the node's database.
+    const NODE_DB: (NodeHash, NodeHash, NodeHash) = (NodeHash::Pedersen, NodeHash::Pedersen, NodeHash::Poseidon);
+
+    /// Poseidon everywhere, for experimental chains which do not use Pedersen at all.
+    pub fn poseidon() -> Self {
+        Self {
+            contract_leaf: HashFunction::Poseidon,
+            class_leaf: HashFunction::Poseidon,
+            state_root: HashFunction::Poseidon,
+            storage_node: NodeHash::Poseidon,
+            contracts_node: NodeHash::Poseidon,
+            classes_node: NodeHash::Poseidon,
+        }
+    }
+
+    /// Whether the tries are hashed as in the node's database, which is required to commit without
+    /// an [engine](super::engine::CommitmentEngine).
+    pub fn node_db_compatible(&self) -> bool {
+        (self.storage_node, self.contracts_node, self.classes_node) == Self::NODE_DB
+    }
+}
+
+/// How a storage write of value zero is applied to a contract storage trie.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ZeroWriteSemantics {
+    /// Writing zero removes the leaf from the trie, as on Starknet. Storing an explicit zero leaf
+    /// instead would make the root drift from the sequencer's.
+    #[default]
+    Delete,
+    /// Writing zero leaves the trie untouched. This is only meant for non-standard chains which never
+    /// clear storage slots: the zero writes to slots holding a value are counted in
+    /// [CommitStats::ignored_zero_writes](super::stats::CommitStats::ignored_zero_writes), and
+    /// reported when tracing is enabled, as the state root diverges from Starknet's from then on.
+    Ignore,
+}
+
+/// A write to a contract storage trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageWrite {
+    /// Sets the leaf to a non-zero value.
+    Set(StarkFelt),
+    /// Removes the leaf from the trie.
+    Delete,
+}
+
+impl ZeroWriteSemantics {
+    /// Maps a storage diff value to the write it induces on the trie, if any.
+    pub fn write(self, value: StarkFelt) -> Option<StorageWrite> {
+        if value != StarkFelt::ZERO {
+            return Some(StorageWrite::Set(value));
+        }
+        match self {
+            ZeroWriteSemantics::Delete => Some(StorageWrite::Delete),
+            ZeroWriteSemantics::Ignore => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "pedersen")]
+    fn test_commitment_scheme() {
+        assert_eq!(CommitmentScheme::for_protocol_version(""), CommitmentScheme::Pedersen);
+        assert_eq!(CommitmentScheme::for_protocol_version("0.9.1"), CommitmentScheme::Pedersen);
+        assert_eq!(CommitmentScheme::for_protocol_version("0.13.1.1"), CommitmentScheme::Pedersen);
+        assert_eq!(CommitmentScheme::for_protocol_version("0.13.2"), CommitmentScheme::Poseidon);
+        assert_eq!(CommitmentScheme::for_protocol_version("unknown"), CommitmentScheme::Poseidon);
+    }
+
+    #[test]
+    fn test_state_prefix_override() {
+        let (contracts, classes) = (Felt252Wrapper::from(1u64), Felt252Wrapper::from(2u64));
+        let config = ChainConfig::default();
+        let root = config.state_root(contracts, classes);
+
+        let constants = ProtocolConstants { state_prefix: FieldElement::from(7u64), ..Default::default() };
+        let overridden = ChainConfig { constants, ..Default::default() };
+        assert_ne!(overridden.state_root(contracts, classes), root);
+        assert_eq!(
+            ChainConfig { constants: ProtocolConstants::default(), ..overridden }.state_root(contracts, classes),
+            root
+        );
+    }
+}
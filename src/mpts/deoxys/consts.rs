@@ -0,0 +1,104 @@
+//! Protocol constants used when hashing commitments.
+//!
+//! Every domain separator and prefix hashed by this crate is defined here, so that chains diverging
+//! from Starknet have a single place to audit them. Chains override them through
+//! [ChainConfig::constants](super::config::ChainConfig::constants), see [ProtocolConstants].
+//!
+//! Transaction hashes are computed by `mp_transactions` with its own prefixes, and Starknet hashes
+//! the transaction and event leaves of the block commitments without any: neither is defined here.
+
+use starknet_ff::FieldElement;
+
+/// Prefix of the state root, hashed together with the contracts and classes trie roots.
+pub const STARKNET_STATE_PREFIX: &[u8] = b"STARKNET_STATE_V0";
+
+/// Prefix of the classes trie leaves, hashed together with the compiled class hash.
+pub const CONTRACT_CLASS_LEAF_PREFIX: &[u8] = b"CONTRACT_CLASS_LEAF_V0";
+
+/// [CONTRACT_CLASS_LEAF_PREFIX] as a field element.
+pub const CONTRACT_CLASS_LEAF_VERSION: FieldElement =
+    FieldElement::from_mont([9331882290187415277, 12057587991035439952, 18444375821049509847, 115292049744600508]);
+
+/// Prefix of the state diff commitment, hashed before the state diff.
+pub const STARKNET_STATE_DIFF_PREFIX: &[u8] = b"STARKNET_STATE_DIFF0";
+
+/// Prefix of the block hash of Starknet v0.13.2 and v0.13.3.
+pub const STARKNET_BLOCK_HASH0: &[u8] = b"STARKNET_BLOCK_HASH0";
+
+/// Prefix of the block hash since Starknet v0.13.4.
+pub const STARKNET_BLOCK_HASH1: &[u8] = b"STARKNET_BLOCK_HASH1";
+
+/// Prefix of the gas prices hashed into the block hash since Starknet v0.13.4.
+pub const STARKNET_GAS_PRICES0: &[u8] = b"STARKNET_GAS_PRICES0";
+
+/// Version prefix of the Sierra class hash, hashed before the entry points of the class.
+pub const CONTRACT_CLASS_VERSION: &[u8] = b"CONTRACT_CLASS_V0.1.0";
+
+/// Version of the contracts trie leaves, hashed last into `h(h(h(class_hash, storage_root), nonce), 0)`.
+pub const CONTRACT_STATE_HASH_VERSION: FieldElement = FieldElement::ZERO;
+
+/// Bound of the storage addresses of Cairo contracts, `2**251 - 256`. Addresses derived from
+/// storage variable names are reduced modulo this bound.
+pub const ADDR_BOUND: FieldElement =
+    FieldElement::from_mont([18446743986131443745, 160989183, 18446744073709255680, 576459263475590224]);
+
+/// First mainnet block whose transaction commitment includes the signature of declare and
+/// deploy account transactions.
+pub const SIGNATURE_IN_COMMITMENT_BLOCK: u64 = 61394;
+
+/// The domain separators hashed into the commitments of a chain.
+///
+/// The [Default] holds the constants above, as on Starknet. Hashing code reads them from the
+/// [ChainConfig](super::config::ChainConfig) it is given, the functions taking none hash with the
+/// defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolConstants {
+    /// See [STARKNET_STATE_PREFIX].
+    pub state_prefix: FieldElement,
+    /// See [CONTRACT_CLASS_LEAF_VERSION].
+    pub class_leaf_version: FieldElement,
+    /// See [CONTRACT_STATE_HASH_VERSION].
+    pub contract_state_hash_version: FieldElement,
+    /// See [STARKNET_STATE_DIFF_PREFIX].
+    pub state_diff_prefix: FieldElement,
+    /// See [STARKNET_BLOCK_HASH0].
+    pub block_hash_v0_prefix: FieldElement,
+    /// See [STARKNET_BLOCK_HASH1].
+    pub block_hash_v1_prefix: FieldElement,
+    /// See [STARKNET_GAS_PRICES0].
+    pub gas_prices_prefix: FieldElement,
+    /// See [CONTRACT_CLASS_VERSION].
+    pub contract_class_version: FieldElement,
+}
+
+impl Default for ProtocolConstants {
+    fn default() -> Self {
+        let felt = |prefix: &[u8]| FieldElement::from_byte_slice_be(prefix).unwrap();
+        Self {
+            state_prefix: felt(STARKNET_STATE_PREFIX),
+            class_leaf_version: CONTRACT_CLASS_LEAF_VERSION,
+            contract_state_hash_version: CONTRACT_STATE_HASH_VERSION,
+            state_diff_prefix: felt(STARKNET_STATE_DIFF_PREFIX),
+            block_hash_v0_prefix: felt(STARKNET_BLOCK_HASH0),
+            block_hash_v1_prefix: felt(STARKNET_BLOCK_HASH1),
+            gas_prices_prefix: felt(STARKNET_GAS_PRICES0),
+            contract_class_version: felt(CONTRACT_CLASS_VERSION),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contract_class_leaf_version() {
+        assert_eq!(CONTRACT_CLASS_LEAF_VERSION, FieldElement::from_byte_slice_be(CONTRACT_CLASS_LEAF_PREFIX).unwrap());
+    }
+
+    #[test]
+    fn test_addr_bound() {
+        let bound = "0x7ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff00";
+        assert_eq!(ADDR_BOUND, FieldElement::from_hex_be(bound).unwrap());
+    }
+}
@@ -0,0 +1,300 @@
+use serde_json::{json, Value};
+use starknet_api::core::{ContractAddress, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+
+use super::config::ChainConfig;
+use super::contracts::contract_leaf_hash;
+use super::engine::StateTries;
+use super::proof::{felt_to_path, ProofError, ProofNode};
+use super::proof_format::{proof_from_json, ProofFormatError, ProofNodeJson};
+use super::storage_proof::{get_storage_proof, state_commitment, StorageProof, StorageProofError};
+
+/// A storage slot along with its proof in the contract storage trie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageSlotProof {
+    pub key: StorageKey,
+    /// Zero if the slot is absent from the trie.
+    pub value: Felt,
+    /// Proof of `key` in the contract storage trie, root first.
+    pub proof: Vec<ProofNode>,
+}
+
+/// Proof of a contract and of some of its storage slots, in the spirit of Ethereum's EIP-1186
+/// `eth_getProof`.
+///
+/// A contract which is not deployed has a zero class hash, nonce and storage root: its proof then
+/// proves its absence from the contracts trie, and its slots are all zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractProof {
+    pub block_number: u64,
+    pub address: ContractAddress,
+    pub class_hash: Felt,
+    pub nonce: Felt,
+    pub storage_root: Felt,
+    /// Proof of the contract leaf in the contracts trie, root first.
+    pub contract_proof: Vec<ProofNode>,
+    pub storage_proofs: Vec<StorageSlotProof>,
+    /// Roots of the contracts and classes tries, which hash to the state root.
+    pub contracts_trie_root: Felt,
+    pub class_commitment: Felt,
+}
+
+impl ContractProof {
+    fn deployed(&self) -> bool {
+        self.class_hash != Felt::ZERO || self.nonce != Felt::ZERO || self.storage_root != Felt::ZERO
+    }
+
+    /// Verifies the proof against a state root, with the Starknet commitment rules.
+    pub fn verify(&self, state_root: Felt) -> Result<(), ProofError> {
+        self.verify_with_config(state_root, &ChainConfig::default())
+    }
+
+    /// Verifies the proof against a state root, with chain-specific commitment rules.
+    ///
+    /// The roots must hash to `state_root`, the contract proof must prove the contract leaf (or its
+    /// absence) and every storage proof must prove its value.
+    pub fn verify_with_config(&self, state_root: Felt, config: &ChainConfig) -> Result<(), ProofError> {
+        let computed = state_commitment(self.contracts_trie_root, self.class_commitment, config);
+        if computed != state_root {
+            return Err(ProofError::StateRootMismatch { expected: state_root, computed });
+        }
+
+        let key = felt_to_path(&Felt::from_bytes_be(&self.address.0.key().0));
+        let proven =
+            config.hashers.contracts_node.verify_proof(self.contracts_trie_root, &key, &self.contract_proof)?;
+        let expected =
+            self.deployed().then(|| contract_leaf_hash(self.class_hash, self.nonce, self.storage_root, config));
+        if proven != expected {
+            return Err(ProofError::ValueMismatch { expected, proven });
+        }
+
+        for slot in &self.storage_proofs {
+            let key = felt_to_path(&Felt::from_bytes_be(&slot.key.0.key().0));
+            let proven =
+                config.hashers.storage_node.verify_proof(self.storage_root, &key, &slot.proof)?.unwrap_or(Felt::ZERO);
+            if proven != slot.value {
+                return Err(ProofError::ValueMismatch { expected: Some(slot.value), proven: Some(proven) });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bundles a [StorageProof] with the keys it was generated for.
+    ///
+    /// The slot values are read from the storage proofs, which must thus be valid under the node hash
+    /// of `config`.
+    pub fn from_storage_proof(
+        proof: StorageProof,
+        keys: &[StorageKey],
+        config: &ChainConfig,
+    ) -> Result<Self, ProofError> {
+        let (class_hash, nonce, storage_root, storage_proofs) = match proof.contract_data {
+            Some(data) => {
+                if data.storage_proofs.len() != keys.len() {
+                    return Err(ProofError::ProofCount { expected: keys.len(), actual: data.storage_proofs.len() });
+                }
+                let storage_proofs = keys
+                    .iter()
+                    .zip(data.storage_proofs)
+                    .map(|(key, proof)| {
+                        let path = felt_to_path(&Felt::from_bytes_be(&key.0.key().0));
+                        let value =
+                            config.hashers.storage_node.verify_proof(data.root, &path, &proof)?.unwrap_or(Felt::ZERO);
+                        Ok(StorageSlotProof { key: *key, value, proof })
+                    })
+                    .collect::<Result<_, ProofError>>()?;
+                (data.class_hash, data.nonce, data.root, storage_proofs)
+            }
+            None => {
+                let storage_proofs =
+                    keys.iter().map(|key| StorageSlotProof { key: *key, value: Felt::ZERO, proof: vec![] }).collect();
+                (Felt::ZERO, Felt::ZERO, Felt::ZERO, storage_proofs)
+            }
+        };
+
+        Ok(Self {
+            block_number: proof.block_number,
+            address: proof.contract_address,
+            class_hash,
+            nonce,
+            storage_root,
+            contract_proof: proof.contract_proof,
+            storage_proofs,
+            contracts_trie_root: proof.contracts_trie_root,
+            class_commitment: proof.class_commitment,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let felt = |felt: &Felt| json!(format!("{felt:#x}"));
+        let proof = |proof: &[ProofNode]| proof.iter().map(ProofNode::to_json).collect::<Vec<_>>();
+        let storage_proofs = self.storage_proofs.iter().map(|slot| {
+            json!({
+                "key": felt(&Felt::from_bytes_be(&slot.key.0.key().0)),
+                "value": felt(&slot.value),
+                "proof": proof(&slot.proof),
+            })
+        });
+
+        json!({
+            "block_number": self.block_number,
+            "address": felt(&Felt::from_bytes_be(&self.address.0.key().0)),
+            "class_hash": felt(&self.class_hash),
+            "nonce": felt(&self.nonce),
+            "storage_root": felt(&self.storage_root),
+            "contract_proof": proof(&self.contract_proof),
+            "storage_proofs": storage_proofs.collect::<Vec<_>>(),
+            "contracts_trie_root": felt(&self.contracts_trie_root),
+            "class_commitment": felt(&self.class_commitment),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, ProofFormatError> {
+        let felt = |value: &Value, field: &'static str| {
+            let felt = value.as_str().and_then(|value| FieldElement::from_hex_be(value).ok());
+            felt.map(|felt| Felt::from_bytes_be(&felt.to_bytes_be())).ok_or(ProofFormatError::Invalid(field))
+        };
+        let patricia_key = |felt: Felt| PatriciaKey(StarkFelt(felt.to_bytes_be()));
+
+        let storage_proofs = value["storage_proofs"]
+            .as_array()
+            .ok_or(ProofFormatError::Invalid("storage_proofs"))?
+            .iter()
+            .map(|slot| {
+                Ok(StorageSlotProof {
+                    key: StorageKey(patricia_key(felt(&slot["key"], "key")?)),
+                    value: felt(&slot["value"], "value")?,
+                    proof: proof_from_json(&slot["proof"])?,
+                })
+            })
+            .collect::<Result<_, ProofFormatError>>()?;
+
+        Ok(Self {
+            block_number: value["block_number"].as_u64().ok_or(ProofFormatError::Invalid("block_number"))?,
+            address: ContractAddress(patricia_key(felt(&value["address"], "address")?)),
+            class_hash: felt(&value["class_hash"], "class_hash")?,
+            nonce: felt(&value["nonce"], "nonce")?,
+            storage_root: felt(&value["storage_root"], "storage_root")?,
+            contract_proof: proof_from_json(&value["contract_proof"])?,
+            storage_proofs,
+            contracts_trie_root: felt(&value["contracts_trie_root"], "contracts_trie_root")?,
+            class_commitment: felt(&value["class_commitment"], "class_commitment")?,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ContractProofError {
+    #[error(transparent)]
+    StorageProof(#[from] StorageProofError),
+    #[error("generated proof does not verify: {0}")]
+    Proof(#[from] ProofError),
+}
+
+/// Generates the proof of a contract and of some of its storage slots at the latest committed block.
+///
+/// See [get_storage_proof].
+pub fn get_contract_proof(
+    tries: &StateTries,
+    contract_address: &ContractAddress,
+    keys: &[StorageKey],
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<ContractProof, ContractProofError> {
+    let proof = get_storage_proof(tries, contract_address, keys, block_number, config)?;
+    Ok(ContractProof::from_storage_proof(proof, keys, config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::{ClassHash, Nonce};
+
+    use super::*;
+    use crate::mpts::deoxys::engine::{CommitmentEngine, StateSeed};
+
+    #[test]
+    fn test_deployed_contract() {
+        let config = ChainConfig::default();
+        let address = |value: u64| ContractAddress(PatriciaKey(StarkFelt::from(value)));
+        let key = |value: u64| StorageKey(PatriciaKey(StarkFelt::from(value)));
+        let seed = StateSeed {
+            storage: vec![
+                (address(0x10), key(1), StarkFelt::from(7_u64)),
+                (address(0x10), key(2), StarkFelt::from(8_u64)),
+                (address(0x20), key(1), StarkFelt::from(9_u64)),
+            ],
+            class_hashes: vec![(address(0x10), ClassHash(StarkFelt::ONE)), (address(0x20), ClassHash(StarkFelt::TWO))],
+            nonces: vec![(address(0x10), Nonce(StarkFelt::THREE))],
+            compiled_class_hashes: vec![],
+        };
+        let (engine, state_root) = CommitmentEngine::in_memory(seed, 1, &config).unwrap();
+        let state_root = Felt::from(state_root);
+
+        let keys = [key(1), key(2), key(3)];
+        let storage_proof = engine.storage_proof(&address(0x10), &keys, &config).unwrap();
+        let proof = ContractProof::from_storage_proof(storage_proof, &keys, &config).unwrap();
+        let values: Vec<_> = proof.storage_proofs.iter().map(|slot| slot.value).collect();
+        assert_eq!(values, [Felt::from(7_u64), Felt::from(8_u64), Felt::ZERO]);
+        assert_eq!((proof.class_hash, proof.nonce), (Felt::ONE, Felt::THREE));
+        assert_eq!(proof.verify(state_root), Ok(()));
+        assert_eq!(ContractProof::from_json(&proof.to_json()), Ok(proof.clone()));
+        assert!(matches!(proof.verify(Felt::ONE), Err(ProofError::StateRootMismatch { .. })));
+
+        // The absence of a contract is proven against the same non-empty trie
+        let storage_proof = engine.storage_proof(&address(0x30), &keys, &config).unwrap();
+        let absent = ContractProof::from_storage_proof(storage_proof, &keys, &config).unwrap();
+        assert!(!absent.contract_proof.is_empty());
+        assert_eq!(absent.verify(state_root), Ok(()));
+
+        let mut tampered = proof.clone();
+        tampered.nonce = Felt::ONE;
+        assert!(matches!(tampered.verify(state_root), Err(ProofError::ValueMismatch { .. })));
+
+        let mut tampered = proof.clone();
+        tampered.storage_proofs[1].value = Felt::from(9_u64);
+        assert!(matches!(tampered.verify(state_root), Err(ProofError::ValueMismatch { .. })));
+
+        // A slot cannot be claimed absent while it is in the trie
+        let mut tampered = proof.clone();
+        tampered.storage_proofs[0].value = Felt::ZERO;
+        assert!(matches!(tampered.verify(state_root), Err(ProofError::ValueMismatch { .. })));
+
+        let mut tampered = proof.clone();
+        match &mut tampered.contract_proof[0] {
+            ProofNode::Binary { left, .. } => *left = *left + Felt::ONE,
+            ProofNode::Edge { child, .. } => *child = *child + Felt::ONE,
+        }
+        assert!(tampered.verify(state_root).is_err());
+
+        // The proof of a slot does not prove another slot
+        let mut tampered = proof;
+        tampered.storage_proofs[0].proof = tampered.storage_proofs[1].proof.clone();
+        assert!(tampered.verify(state_root).is_err());
+    }
+
+    #[test]
+    fn test_undeployed_contract() {
+        let key = StorageKey(PatriciaKey(StarkFelt::ONE));
+        let mut proof = ContractProof {
+            block_number: 3,
+            address: ContractAddress(PatriciaKey(StarkFelt::TWO)),
+            class_hash: Felt::ZERO,
+            nonce: Felt::ZERO,
+            storage_root: Felt::ZERO,
+            contract_proof: vec![],
+            storage_proofs: vec![StorageSlotProof { key, value: Felt::ZERO, proof: vec![] }],
+            contracts_trie_root: Felt::ZERO,
+            class_commitment: Felt::ZERO,
+        };
+
+        assert_eq!(proof.verify(Felt::ZERO), Ok(()));
+        assert_eq!(ContractProof::from_json(&proof.to_json()), Ok(proof.clone()));
+
+        proof.storage_proofs[0].value = Felt::ONE;
+        assert!(matches!(proof.verify(Felt::ZERO), Err(ProofError::ValueMismatch { .. })));
+    }
+}
@@ -3,121 +3,321 @@ use std::collections::HashSet;
 use blockifier::state::cached_state::CommitmentStateDiff;
 use mc_db::storage_handler::{self, DeoxysStorageError, StorageView};
 use mp_felt::Felt252Wrapper;
-use mp_hashers::pedersen::PedersenHasher;
-use mp_hashers::HasherT;
 use rayon::prelude::*;
 use starknet_api::core::ContractAddress;
+use starknet_api::hash::StarkFelt;
 use starknet_ff::FieldElement;
 use starknet_types_core::felt::Felt;
 
+use super::atomic::Trie;
+use super::config::{ChainConfig, HashFunction, StorageWrite};
+use super::engine::StateTries;
+use super::error::{ErrorContext, ResultExt, TrieError};
+use super::quarantine::{quarantine, FailureMode, Quarantine};
+use super::recording::{Capture, Interaction};
+use super::squash::empty_storage_tracker;
+
 /// Calculates the contract trie root
 ///
+/// The keys of `csd` must have been [validated](super::conversions::validate_trie_keys) beforehand.
+///
 /// # Arguments
 ///
+/// * `tries`           - The tries to update.
 /// * `csd`             - Commitment state diff for the current block.
 /// * `block_number`    - The current block number.
+/// * `config`          - Chain-specific commitment rules.
 ///
 /// # Returns
 ///
 /// The contract root.
-pub fn contract_trie_root(csd: &CommitmentStateDiff, block_number: u64) -> Result<Felt252Wrapper, DeoxysStorageError> {
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            block_number = block_number,
+            contracts = csd.storage_updates.len(),
+            deployed = csd.address_to_class_hash.len(),
+        )
+    )
+)]
+pub(crate) fn contract_trie_root(
+    tries: &StateTries,
+    csd: &CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<Felt252Wrapper, TrieError> {
+    if let Some(mut engine) = tries.engine() {
+        let mut quarantine = quarantine(tries);
+        let root = engine.update_contracts(csd, block_number, config, Some(&mut quarantine))?;
+        let mut empty_storage = empty_storage_tracker(tries);
+        for contract_address in csd.storage_updates.keys().filter(|address| !quarantine.contains(address)) {
+            let storage_root = engine.storage_root(contract_address)?;
+            empty_storage.record(*contract_address, storage_root == Felt::ZERO, block_number);
+        }
+        return Ok(root);
+    }
+    if !config.hashers.node_db_compatible() {
+        return Err(TrieError::NodeHash);
+    }
+
     // NOTE: handlers implicitely acquire a lock on their respective tries
     // for the duration of their livetimes
     let mut handler_contract = storage_handler::contract_trie_mut();
     let mut handler_storage_trie = storage_handler::contract_storage_trie_mut();
+    let capture = &tries.state().capture;
+
+    let mut quarantine = quarantine(tries);
+    let on_failure = |quarantine: &mut Quarantine, contract_address: &ContractAddress, trie: Trie, e: TrieError| {
+        quarantine_or_fail(quarantine, config.failure_mode, contract_address, block_number, trie, e)
+    };
 
-    // First we insert the contract storage changes
-    for (contract_address, updates) in csd.storage_updates.iter() {
-        handler_storage_trie.init(contract_address)?;
+    // First we insert the contract storage changes. The inserts of a contract which fails halfway
+    // through cannot be undone on their own, so they are dropped with every other uncommitted insert
+    // and the pass starts over without the quarantined contract.
+    loop {
+        let mut partial = false;
+        for (contract_address, updates) in csd.storage_updates.iter() {
+            if quarantine.contains(contract_address) {
+                continue;
+            }
 
-        for (key, value) in updates {
-            handler_storage_trie.insert(*contract_address, *key, *value)?;
+            let result = (|| {
+                handler_storage_trie
+                    .init(contract_address)
+                    .context(|| ErrorContext::default().trie(Trie::ContractStorage))?;
+
+                for (key, value) in updates {
+                    let value = match config.zero_writes.write(*value) {
+                        Some(StorageWrite::Set(value)) => value,
+                        // Bonsai removes a leaf from the trie when it is set to zero
+                        Some(StorageWrite::Delete) => StarkFelt::ZERO,
+                        None => continue,
+                    };
+                    handler_storage_trie
+                        .insert(*contract_address, *key, value)
+                        .context(|| ErrorContext::default().trie(Trie::ContractStorage).key(*key))?;
+                    capture.record(block_number, || Interaction::StorageWrite {
+                        contract_address: address_felt(contract_address),
+                        key: Felt::from_bytes_be(&key.0.key().0),
+                        value: Felt::from_bytes_be(&value.0),
+                    });
+                }
+                Ok(())
+            })();
+            if let Err(e) = result {
+                // Before genesis there is no committed state to drop the inserts of the block to
+                let failure_mode = if block_number == 0 { FailureMode::Strict } else { config.failure_mode };
+                quarantine_or_fail(
+                    &mut quarantine,
+                    failure_mode,
+                    contract_address,
+                    block_number,
+                    Trie::ContractStorage,
+                    e,
+                )?;
+                partial = true;
+            }
+        }
+        if !partial {
+            break;
         }
+        handler_storage_trie
+            .revert_to(block_number - 1)
+            .context(|| ErrorContext::block(block_number).trie(Trie::ContractStorage))?;
     }
 
-    // Then we commit them
-    handler_storage_trie.commit(block_number)?;
+    // Then we commit them, bonsai only hashes the tries on commit so the inserts above are cheap
+    handler_storage_trie
+        .commit(block_number)
+        .context(|| ErrorContext::block(block_number).trie(Trie::ContractStorage))?;
 
     // We need to initialize the contract trie for each contract that has a class_hash or nonce update
     // to retrieve the corresponding storage root
     for contract_address in csd.address_to_class_hash.keys().chain(csd.address_to_nonce.keys()) {
-        if !csd.storage_updates.contains_key(contract_address) {
+        if !csd.storage_updates.contains_key(contract_address) && !quarantine.contains(contract_address) {
             // Initialize the storage trie if this contract address does not have storage updates
-            handler_storage_trie.init(contract_address)?;
+            if let Err(e) = handler_storage_trie.init(contract_address) {
+                on_failure(&mut quarantine, contract_address, Trie::ContractStorage, e.into())?;
+            }
         }
     }
 
     // We need to calculate the contract_state_leaf_hash for each contract
-    // that not appear in the storage_updates but has a class_hash or nonce update
-    let all_contract_address: HashSet<ContractAddress> = csd
+    // that not appear in the storage_updates but has a class_hash or nonce update.
+    // Quarantined contracts keep their stale leaf.
+    let mut seen = HashSet::new();
+    let contract_addresses: Vec<&ContractAddress> = csd
         .storage_updates
         .keys()
         .chain(csd.address_to_class_hash.keys())
         .chain(csd.address_to_nonce.keys())
-        .cloned()
+        .filter(|contract_address| !quarantine.contains(contract_address) && seen.insert(*contract_address))
         .collect();
 
-    // Then we compute the leaf hashes retrieving the corresponding storage root
-    let updates = all_contract_address
-        .iter()
-        .par_bridge()
+    // Then we compute the storage root and leaf hash of each contract on the thread pool the commit
+    // runs on, each contract storage trie being independent from the others. Blocks touching hundreds
+    // of contracts spend most of their time here.
+    let handler_storage_trie = &handler_storage_trie;
+    let leaves = contract_addresses
+        .par_iter()
         .map(|contract_address| {
-            let storage_root = handler_storage_trie.root(contract_address)?;
-            let leaf_hash = contract_state_leaf_hash(csd, contract_address, storage_root)?;
-
-            Ok::<(&ContractAddress, Felt), DeoxysStorageError>((contract_address, leaf_hash))
+            let leaf = handler_storage_trie.root(contract_address).map_err(|e| (Trie::ContractStorage, e)).and_then(
+                |storage_root| {
+                    contract_state_leaf_hash(capture, csd, block_number, contract_address, storage_root, config)
+                        .map(|leaf_hash| (storage_root, leaf_hash))
+                        .map_err(|e| (Trie::Contracts, e))
+                },
+            );
+            (*contract_address, leaf)
         })
-        .collect::<Result<Vec<_>, _>>()?;
+        .collect::<Vec<_>>();
+
+    // Leaf hashes are applied in diff order, so that commits are recorded deterministically
+    let mut updates = Vec::with_capacity(leaves.len());
+    {
+        let mut empty_storage = empty_storage_tracker(tries);
+        for (contract_address, leaf) in leaves {
+            let (storage_root, leaf_hash) = match leaf {
+                Ok(leaf) => leaf,
+                Err((trie, e)) => {
+                    on_failure(&mut quarantine, contract_address, trie, e.into())?;
+                    continue;
+                }
+            };
+            // Contracts whose storage was entirely zeroed out have collapsed to the empty root
+            if csd.storage_updates.contains_key(contract_address) {
+                empty_storage.record(*contract_address, storage_root == Felt::ZERO, block_number);
+            }
+            let contract_address_felt = address_felt(contract_address);
+            capture.record(block_number, || Interaction::StorageRoot {
+                contract_address: contract_address_felt,
+                root: storage_root,
+            });
+            capture.record(block_number, || Interaction::ContractLeaf {
+                contract_address: contract_address_felt,
+                leaf_hash,
+            });
+            updates.push((contract_address, leaf_hash));
+        }
+    }
+    // The skipped updates are kept to be replayed once the contracts are repaired
+    for contract_address in
+        csd.storage_updates.keys().chain(csd.address_to_class_hash.keys()).chain(csd.address_to_nonce.keys())
+    {
+        quarantine.skip(contract_address, block_number, csd);
+    }
+    drop(quarantine);
 
     // then we compute the contract root by applying the changes so far
-    handler_contract.update(updates)?;
-    handler_contract.commit(block_number)?;
+    let context = || ErrorContext::block(block_number).trie(Trie::Contracts);
+    handler_contract.update(updates).context(context)?;
+    handler_contract.commit(block_number).context(context)?;
+
+    let root = handler_contract.root().context(context)?;
+    capture.record(block_number, || Interaction::TrieRoot { trie: Trie::Contracts, root });
+    Ok(root.into())
+}
 
-    Ok(handler_contract.root()?.into())
+/// Quarantines a contract which failed to update in [FailureMode::Quarantine], fails otherwise.
+pub(crate) fn quarantine_or_fail(
+    quarantine: &mut Quarantine,
+    failure_mode: FailureMode,
+    contract_address: &ContractAddress,
+    block_number: u64,
+    trie: Trie,
+    e: TrieError,
+) -> Result<(), TrieError> {
+    let e = e.with_context(ErrorContext::block(block_number).trie(trie).contract(*contract_address));
+    match failure_mode {
+        FailureMode::Strict => Err(e),
+        FailureMode::Quarantine => {
+            quarantine.add(*contract_address, block_number, &e);
+            Ok(())
+        }
+    }
 }
 
 /// Computes the contract state leaf hash
 ///
 /// # Arguments
 ///
+/// * `capture`          - What the commit is captured by.
 /// * `csd`             - Commitment state diff for the current block.
+/// * `block_number`     - The current block number.
 /// * `contract_address` - The contract address.
 /// * `storage_root`     - The storage root of the contract.
+/// * `config`           - Chain-specific commitment rules.
 ///
 /// # Returns
 ///
 /// The contract state leaf hash.
 fn contract_state_leaf_hash(
+    capture: &Capture,
     csd: &CommitmentStateDiff,
+    block_number: u64,
     contract_address: &ContractAddress,
     storage_root: Felt,
+    config: &ChainConfig,
 ) -> Result<Felt, DeoxysStorageError> {
-    let (class_hash, nonce) = class_hash_and_nonce(csd, contract_address)?;
+    let (class_hash, nonce) = class_hash_and_nonce(capture, csd, block_number, contract_address)?;
+
+    Ok(contract_leaf_hash(class_hash, nonce, storage_root, config))
+}
+
+/// Computes the contracts trie leaf of a contract, `h(h(h(class_hash, storage_root), nonce), 0)`,
+/// with the [leaf hash](super::config::TrieHashers::contract_leaf) and
+/// [version](super::consts::ProtocolConstants::contract_state_hash_version) of `config`.
+pub(crate) fn contract_leaf_hash(class_hash: Felt, nonce: Felt, storage_root: Felt, config: &ChainConfig) -> Felt {
+    let version = Felt::from_bytes_be(&config.constants.contract_state_hash_version.to_bytes_be());
+    compute_contract_state_hash(class_hash, storage_root, nonce, version, config.hashers.contract_leaf)
+}
 
-    let storage_root = FieldElement::from_bytes_be(&storage_root.to_bytes_be()).unwrap();
+/// Computes the contract state hash, `h(h(h(class_hash, storage_root), nonce), contract_state_version)`,
+/// which is the leaf of the contract in the contracts trie.
+///
+/// Verifiers can check a contract leaf returned by a storage proof against its class hash, nonce
+/// and storage root without rebuilding the trie. `hash` is the chain's
+/// [contract_leaf](super::config::TrieHashers::contract_leaf) hash function, and
+/// `contract_state_version` is zero on every Starknet version so far.
+pub fn compute_contract_state_hash(
+    class_hash: Felt,
+    storage_root: Felt,
+    nonce: Felt,
+    contract_state_version: Felt,
+    hash: HashFunction,
+) -> Felt {
+    let [class_hash, storage_root, nonce, contract_state_version] =
+        [class_hash, storage_root, nonce, contract_state_version]
+            .map(|felt| FieldElement::from_bytes_be(&felt.to_bytes_be()).unwrap());
 
     // computes the contract state leaf hash
-    let contract_state_hash = PedersenHasher::hash_elements(class_hash, storage_root);
-    let contract_state_hash = PedersenHasher::hash_elements(contract_state_hash, nonce);
-    let contract_state_hash = PedersenHasher::hash_elements(contract_state_hash, FieldElement::ZERO);
+    let contract_state_hash = hash.hash_elements(class_hash, storage_root);
+    let contract_state_hash = hash.hash_elements(contract_state_hash, nonce);
+    let contract_state_hash = hash.hash_elements(contract_state_hash, contract_state_version);
 
-    Ok(Felt::from_bytes_be(&contract_state_hash.to_bytes_be()))
+    Felt::from_bytes_be(&contract_state_hash.to_bytes_be())
 }
 
 /// Retrieves the class hash and nonce of a contract address
 ///
 /// # Arguments
 ///
+/// * `capture`          - What the commit is captured by.
 /// * `csd`             - Commitment state diff for the current block.
+/// * `block_number`     - The current block number.
 /// * `contract_address` - The contract address.
 ///
 /// # Returns
 ///
 /// The class hash and nonce of the contract address.
 fn class_hash_and_nonce(
+    capture: &Capture,
     csd: &CommitmentStateDiff,
+    block_number: u64,
     contract_address: &ContractAddress,
-) -> Result<(FieldElement, FieldElement), DeoxysStorageError> {
+) -> Result<(Felt, Felt), DeoxysStorageError> {
     let class_hash = match csd.address_to_class_hash.get(contract_address) {
         Some(class_hash) => *class_hash,
         None => storage_handler::contract_class_hash().get(contract_address)?.unwrap_or_default(),
@@ -126,5 +326,84 @@ fn class_hash_and_nonce(
         Some(nonce) => *nonce,
         None => storage_handler::contract_nonces().get(contract_address)?.unwrap_or_default(),
     };
-    Ok((FieldElement::from_bytes_be(&class_hash.0.0).unwrap(), FieldElement::from_bytes_be(&nonce.0.0).unwrap()))
+    let (class_hash, nonce) = (Felt::from_bytes_be(&class_hash.0.0), Felt::from_bytes_be(&nonce.0.0));
+
+    if !csd.address_to_class_hash.contains_key(contract_address) || !csd.address_to_nonce.contains_key(contract_address)
+    {
+        capture.record(block_number, || Interaction::ContractRead {
+            contract_address: address_felt(contract_address),
+            class_hash,
+            nonce,
+        });
+    }
+    Ok((class_hash, nonce))
+}
+
+fn address_felt(contract_address: &ContractAddress) -> Felt {
+    Felt::from_bytes_be(&contract_address.0.key().0)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitvec::vec::BitVec;
+    use bonsai_trie::databases::HashMapDb;
+    use bonsai_trie::id::{BasicId, BasicIdBuilder};
+    use bonsai_trie::{BonsaiStorage, BonsaiStorageConfig};
+    use starknet_types_core::hash::Pedersen;
+
+    use super::*;
+    use crate::mpts::deoxys::config::{TrieHashers, ZeroWriteSemantics};
+
+    const IDENTIFIER: &[u8] = b"storage";
+
+    fn insert(storage: &mut BonsaiStorage<BasicId, HashMapDb<BasicId>, Pedersen>, key: u64, value: u64) {
+        let key = BitVec::from_vec(key.to_be_bytes().to_vec());
+        storage.insert(IDENTIFIER, key.as_bitslice(), &Felt::from(value)).unwrap();
+    }
+
+    #[test]
+    fn test_compute_contract_state_hash() {
+        let (class_hash, storage_root, nonce) = (Felt::from(0x10_u64), Felt::from(0x20_u64), Felt::ONE);
+        let hash = HashFunction::Poseidon;
+
+        let expected = [class_hash, storage_root, nonce, Felt::ZERO]
+            .map(|felt| FieldElement::from_bytes_be(&felt.to_bytes_be()).unwrap())
+            .into_iter()
+            .reduce(|state, felt| hash.hash_elements(state, felt))
+            .unwrap();
+        let contract_state_hash = compute_contract_state_hash(class_hash, storage_root, nonce, Felt::ZERO, hash);
+        assert_eq!(contract_state_hash, Felt::from_bytes_be(&expected.to_bytes_be()));
+        let config =
+            ChainConfig { hashers: TrieHashers { contract_leaf: hash, ..Default::default() }, ..Default::default() };
+        assert_eq!(contract_state_hash, contract_leaf_hash(class_hash, nonce, storage_root, &config));
+        assert_ne!(contract_state_hash, compute_contract_state_hash(class_hash, storage_root, nonce, Felt::ONE, hash));
+    }
+
+    #[test]
+    fn test_zero_write_semantics() {
+        let value = StarkFelt::from(42_u64);
+
+        assert_eq!(ZeroWriteSemantics::Delete.write(value), Some(StorageWrite::Set(value)));
+        assert_eq!(ZeroWriteSemantics::Delete.write(StarkFelt::ZERO), Some(StorageWrite::Delete));
+        assert_eq!(ZeroWriteSemantics::Ignore.write(value), Some(StorageWrite::Set(value)));
+        assert_eq!(ZeroWriteSemantics::Ignore.write(StarkFelt::ZERO), None);
+    }
+
+    #[test]
+    fn test_zero_write_removes_leaf() {
+        let mut storage = BonsaiStorage::new(HashMapDb::<BasicId>::default(), BonsaiStorageConfig::default()).unwrap();
+        let mut id_builder = BasicIdBuilder::new();
+
+        insert(&mut storage, 1, 10);
+        storage.commit(id_builder.new_id()).unwrap();
+        let root_before = storage.root_hash(IDENTIFIER).unwrap();
+
+        // a slot is written then zeroed out in a later block
+        insert(&mut storage, 2, 20);
+        storage.commit(id_builder.new_id()).unwrap();
+        insert(&mut storage, 2, 0);
+        storage.commit(id_builder.new_id()).unwrap();
+
+        assert_eq!(storage.root_hash(IDENTIFIER).unwrap(), root_before);
+    }
 }
@@ -0,0 +1,255 @@
+use std::fmt;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_convert::field_element::FromFieldElement;
+use starknet_api::core::{ClassHash, ContractAddress};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_core::types::{
+    ContractStorageDiffItem, DeclaredClassItem, DeployedContractItem, NonceUpdate, ReplacedClassItem, StateDiff,
+    StorageEntry,
+};
+use starknet_ff::FieldElement;
+
+use super::config::ChainConfig;
+use super::error::DiffError;
+
+/// Errors raised when a felt does not fit in the key space of the state tries.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ConversionError {
+    #[error("contract address {0:#x} is out of range (must be < 2**251)")]
+    ContractAddressOutOfRange(FieldElement),
+    #[error("storage key {0:#x} is out of range (must be < 2**251)")]
+    StorageKeyOutOfRange(FieldElement),
+    #[error("class hash {0:#x} is out of range (must be < 2**251)")]
+    ClassHashOutOfRange(FieldElement),
+}
+
+/// Checks whether a big-endian felt fits in the 251 bits addressable by the state tries.
+///
+/// This is the case iff the 5 most significant bits of the 256 bits representation are unset.
+fn fits_in_251_bits(bytes: &[u8]) -> bool {
+    bytes[0] & 0b1111_1000 == 0
+}
+
+/// Checks whether a felt can be used as a key in one of the state tries (`felt < 2**251`).
+pub fn is_valid_trie_key(felt: &FieldElement) -> bool {
+    fits_in_251_bits(&felt.to_bytes_be())
+}
+
+/// Converts a felt into a [ContractAddress], rejecting values `>= 2**251`.
+pub fn try_contract_address(felt: &FieldElement) -> Result<ContractAddress, ConversionError> {
+    if !is_valid_trie_key(felt) {
+        return Err(ConversionError::ContractAddressOutOfRange(*felt));
+    }
+    Ok(ContractAddress::from_field_element(felt))
+}
+
+/// Converts a felt into a [StorageKey], rejecting values `>= 2**251`.
+pub fn try_storage_key(felt: &FieldElement) -> Result<StorageKey, ConversionError> {
+    if !is_valid_trie_key(felt) {
+        return Err(ConversionError::StorageKeyOutOfRange(*felt));
+    }
+    Ok(StorageKey::from_field_element(felt))
+}
+
+/// Converts a felt into a [ClassHash], rejecting values `>= 2**251`.
+pub fn try_class_hash(felt: &FieldElement) -> Result<ClassHash, ConversionError> {
+    if !is_valid_trie_key(felt) {
+        return Err(ConversionError::ClassHashOutOfRange(*felt));
+    }
+    Ok(ClassHash::from_field_element(felt))
+}
+
+/// Locates an entry of a [StateDiff], by its position in the lists of the sequencer's response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffEntry {
+    DeployedContract(usize),
+    ReplacedClass(usize),
+    DeclaredClass(usize),
+    Nonce(usize),
+    StorageDiff(usize),
+    StorageEntry { diff: usize, entry: usize },
+}
+
+impl fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffEntry::DeployedContract(index) => write!(f, "deployed_contracts[{index}]"),
+            DiffEntry::ReplacedClass(index) => write!(f, "replaced_classes[{index}]"),
+            DiffEntry::DeclaredClass(index) => write!(f, "declared_classes[{index}]"),
+            DiffEntry::Nonce(index) => write!(f, "nonces[{index}]"),
+            DiffEntry::StorageDiff(index) => write!(f, "storage_diffs[{index}]"),
+            DiffEntry::StorageEntry { diff, entry } => write!(f, "storage_diffs[{diff}].storage_entries[{entry}]"),
+        }
+    }
+}
+
+/// Checks the class hash assigned to a contract: in range, and non-zero unless the contract is one
+/// of the chain's system contracts.
+fn check_assigned_class_hash(
+    entry: DiffEntry,
+    contract_address: &FieldElement,
+    class_hash: &FieldElement,
+    config: &ChainConfig,
+) -> Result<(), DiffError> {
+    let address = try_contract_address(contract_address).map_err(|reason| DiffError::InvalidEntry { entry, reason })?;
+    try_class_hash(class_hash).map_err(|reason| DiffError::InvalidEntry { entry, reason })?;
+    if *class_hash == FieldElement::ZERO && config.system_contracts.get(&address).is_none() {
+        return Err(DiffError::ZeroClassHash { entry, contract_address: *contract_address });
+    }
+    Ok(())
+}
+
+/// Validates every entry of a [StateDiff] before it is turned into a [CommitmentStateDiff].
+///
+/// On top of the range checks of the conversions above, which the builders of [lib](super::lib)
+/// also perform, this rejects user contracts deployed or upgraded to the zero class hash. Errors
+/// point at the offending entry of the sequencer's response.
+///
+/// # Arguments
+///
+/// * `state_diff` - The state diff of a state update fetched from the sequencer.
+/// * `config` - Chain-specific commitment rules, for the system contracts.
+pub fn validate_state_diff(state_diff: &StateDiff, config: &ChainConfig) -> Result<(), DiffError> {
+    for (index, DeployedContractItem { address, class_hash }) in state_diff.deployed_contracts.iter().enumerate() {
+        check_assigned_class_hash(DiffEntry::DeployedContract(index), address, class_hash, config)?;
+    }
+
+    for (index, ReplacedClassItem { contract_address, class_hash }) in state_diff.replaced_classes.iter().enumerate() {
+        check_assigned_class_hash(DiffEntry::ReplacedClass(index), contract_address, class_hash, config)?;
+    }
+
+    for (index, DeclaredClassItem { class_hash, .. }) in state_diff.declared_classes.iter().enumerate() {
+        let entry = DiffEntry::DeclaredClass(index);
+        try_class_hash(class_hash).map_err(|reason| DiffError::InvalidEntry { entry, reason })?;
+    }
+
+    for (index, NonceUpdate { contract_address, .. }) in state_diff.nonces.iter().enumerate() {
+        let entry = DiffEntry::Nonce(index);
+        try_contract_address(contract_address).map_err(|reason| DiffError::InvalidEntry { entry, reason })?;
+    }
+
+    for (diff, ContractStorageDiffItem { address, storage_entries }) in state_diff.storage_diffs.iter().enumerate() {
+        let entry = DiffEntry::StorageDiff(diff);
+        try_contract_address(address).map_err(|reason| DiffError::InvalidEntry { entry, reason })?;
+        for (index, StorageEntry { key, .. }) in storage_entries.iter().enumerate() {
+            let entry = DiffEntry::StorageEntry { diff, entry: index };
+            try_storage_key(key).map_err(|reason| DiffError::InvalidEntry { entry, reason })?;
+        }
+    }
+
+    Ok(())
+}
+
+fn stark_felt_to_field_element(felt: &StarkFelt) -> FieldElement {
+    FieldElement::from_bytes_be(&felt.0).unwrap()
+}
+
+/// Validates that every key of a [CommitmentStateDiff] fits in the key space of the tries.
+///
+/// [CommitmentStateDiff]s can be built outside of this crate (ie: from blockifier's execution
+/// output) without going through the checked conversions above, so this is called before any key
+/// enters a trie.
+///
+/// # Arguments
+///
+/// * `csd` - Commitment state diff for the current block.
+pub fn validate_trie_keys(csd: &CommitmentStateDiff) -> Result<(), ConversionError> {
+    let contract_addresses = csd
+        .address_to_class_hash
+        .keys()
+        .chain(csd.address_to_nonce.keys())
+        .chain(csd.storage_updates.keys());
+
+    for contract_address in contract_addresses {
+        let felt = contract_address.0.key();
+        if !fits_in_251_bits(&felt.0) {
+            return Err(ConversionError::ContractAddressOutOfRange(stark_felt_to_field_element(felt)));
+        }
+    }
+
+    for key in csd.storage_updates.values().flat_map(|updates| updates.keys()) {
+        let felt = key.0.key();
+        if !fits_in_251_bits(&felt.0) {
+            return Err(ConversionError::StorageKeyOutOfRange(stark_felt_to_field_element(felt)));
+        }
+    }
+
+    for class_hash in csd.class_hash_to_compiled_class_hash.keys() {
+        if !fits_in_251_bits(&class_hash.0.0) {
+            return Err(ConversionError::ClassHashOutOfRange(stark_felt_to_field_element(&class_hash.0)));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trie_key_bounds() {
+        let max =
+            FieldElement::from_hex_be("0x7ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").unwrap();
+        let bound =
+            FieldElement::from_hex_be("0x800000000000000000000000000000000000000000000000000000000000000").unwrap();
+
+        assert!(is_valid_trie_key(&FieldElement::ZERO));
+        assert!(is_valid_trie_key(&max));
+        assert!(!is_valid_trie_key(&bound));
+        assert!(!is_valid_trie_key(&FieldElement::MAX));
+    }
+
+    #[test]
+    fn test_out_of_range_conversions() {
+        assert_eq!(
+            try_contract_address(&FieldElement::MAX),
+            Err(ConversionError::ContractAddressOutOfRange(FieldElement::MAX))
+        );
+        assert_eq!(try_storage_key(&FieldElement::MAX), Err(ConversionError::StorageKeyOutOfRange(FieldElement::MAX)));
+        assert_eq!(try_class_hash(&FieldElement::MAX), Err(ConversionError::ClassHashOutOfRange(FieldElement::MAX)));
+        assert!(try_contract_address(&FieldElement::ONE).is_ok());
+    }
+
+    #[test]
+    fn test_validate_state_diff() {
+        let config = ChainConfig::default();
+        let felt = FieldElement::from;
+        let mut state_diff = StateDiff {
+            storage_diffs: vec![ContractStorageDiffItem {
+                address: felt(0x10_u64),
+                storage_entries: vec![
+                    StorageEntry { key: felt(1_u64), value: felt(1_u64) },
+                    StorageEntry { key: FieldElement::MAX, value: felt(1_u64) },
+                ],
+            }],
+            deprecated_declared_classes: vec![],
+            declared_classes: vec![],
+            // System contracts have no class hash
+            deployed_contracts: vec![DeployedContractItem { address: felt(0x1_u64), class_hash: FieldElement::ZERO }],
+            replaced_classes: vec![],
+            nonces: vec![],
+        };
+
+        let error = validate_state_diff(&state_diff, &config).unwrap_err();
+        assert!(matches!(
+            error,
+            DiffError::InvalidEntry {
+                entry: DiffEntry::StorageEntry { diff: 0, entry: 1 },
+                reason: ConversionError::StorageKeyOutOfRange(_)
+            }
+        ));
+        assert!(error.to_string().starts_with("invalid storage_diffs[0].storage_entries[1]: storage key"));
+
+        state_diff.storage_diffs[0].storage_entries.pop();
+        assert!(validate_state_diff(&state_diff, &config).is_ok());
+        let upgrade = ReplacedClassItem { contract_address: felt(0x10_u64), class_hash: FieldElement::ZERO };
+        state_diff.replaced_classes.push(upgrade);
+        assert!(matches!(
+            validate_state_diff(&state_diff, &config),
+            Err(DiffError::ZeroClassHash { entry: DiffEntry::ReplacedClass(0), .. })
+        ));
+    }
+}
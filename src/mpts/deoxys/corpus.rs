@@ -0,0 +1,74 @@
+//! Regression corpus of minimized [recorded bundles](super::recording), run with
+//! `cargo test --features corpus`.
+//!
+//! Each file of the `corpus` directory holds a case:
+//!
+//! ```json
+//! {
+//!     "description": "what went wrong, and the fix",
+//!     "chain": { "delete_zero_writes": false },
+//!     "bundle": { "version": 1, "block_number": 0, "diff": {}, "interactions": [], "outcome": {} }
+//! }
+//! ```
+//!
+//! `chain` holds the [ChainSettings] the block is replayed with, omitted fields keep their Starknet
+//! default. The bundle is minimized to the part of the diff and interactions which exhibit the bug,
+//! with the values fixed to what the commit must produce: replaying it must not diverge, and the
+//! recorded trie roots must hash to the recorded state root.
+//!
+//! Cases are recorded from the failing block with [record_block](super::recording::record_block)
+//! and written with [Bundle::write], then minimized by hand. Cases which were written by hand
+//! instead are marked `"source": "handwritten"`, and are to be replaced by recorded blocks.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use super::config::ChainConfig;
+use super::recording::{replay, Bundle};
+use super::settings::{ChainSettings, CommitmentConfig};
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus")
+}
+
+struct Case {
+    description: String,
+    config: ChainConfig,
+    bundle: Bundle,
+}
+
+fn load(path: &Path) -> Case {
+    let content = fs::read(path).unwrap_or_else(|e| panic!("failed to read {path:?}: {e}"));
+    let case: Value = serde_json::from_slice(&content).unwrap_or_else(|e| panic!("invalid case {path:?}: {e}"));
+
+    let chain: ChainSettings = serde_json::from_value(case["chain"].clone())
+        .unwrap_or_else(|e| panic!("invalid chain settings in {path:?}: {e}"));
+    let config = CommitmentConfig { chain, ..Default::default() }.chain_config();
+    let bundle = Bundle::from_json(&case["bundle"]).unwrap_or_else(|e| panic!("invalid bundle in {path:?}: {e}"));
+    let description = case["description"].as_str().unwrap_or_default().to_string();
+
+    Case { description, config, bundle }
+}
+
+#[test]
+fn test_corpus() {
+    let mut paths = fs::read_dir(corpus_dir())
+        .expect("Missing corpus directory")
+        .map(|entry| entry.expect("Failed to list corpus").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "corpus is empty");
+
+    for path in paths {
+        let Case { description, config, bundle } = load(&path);
+        let report = replay(&bundle, &config);
+
+        assert!(report.passed(), "{path:?} ({description}) diverged: {:#?}", report.divergences);
+        if let (Some(state_root), Ok(recorded)) = (report.state_root, &bundle.outcome) {
+            assert_eq!(state_root, *recorded, "{path:?} ({description}) state root mismatch");
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use starknet_api::core::ContractAddress;
+
+/// Height of the state tries.
+const TRIE_HEIGHT: u64 = 251;
+
+/// Predicted cost of committing a state diff.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitCost {
+    /// Number of leaves written across all tries.
+    pub leaf_writes: u64,
+    /// Upper bound of the number of node hashes computed.
+    pub hashes: u64,
+    /// Upper bound of the number of trie nodes written to the backend.
+    pub node_writes: u64,
+}
+
+impl CommitCost {
+    fn add_trie(&mut self, leaves: u64) {
+        if leaves == 0 {
+            return;
+        }
+        // Every node on the path from a leaf to the root is rehashed and rewritten. Paths share their
+        // top nodes: with n leaves at most ~log2(n) levels are fully shared, which is what is
+        // subtracted here. In a Patricia trie most of these nodes are collapsed into edges, so these
+        // bounds are pessimistic but they only depend on the diff.
+        let shared = u64::from(leaves.ilog2());
+        let path = TRIE_HEIGHT - shared;
+        let nodes = leaves * path + (1 << shared) - 1;
+
+        self.leaf_writes += leaves;
+        self.hashes += nodes;
+        self.node_writes += nodes;
+    }
+}
+
+/// Estimates the cost of committing a state diff, before applying it.
+///
+/// The estimate is deterministic and only depends on the shape of the diff, so that block builders
+/// can bound commitment latency when choosing how many transactions to include. It accounts for
+/// the storage tries, the contracts trie (including the 3 hashes of each contract leaf) and the
+/// classes trie (1 hash per class leaf).
+///
+/// # Arguments
+///
+/// * `csd` - Commitment state diff for the current block.
+///
+/// # Returns
+///
+/// The predicted cost of the commit.
+pub fn estimate_commit_cost(csd: &CommitmentStateDiff) -> CommitCost {
+    let mut cost = CommitCost::default();
+
+    for updates in csd.storage_updates.values() {
+        cost.add_trie(updates.len() as u64);
+    }
+
+    let contracts: HashSet<&ContractAddress> = csd
+        .storage_updates
+        .keys()
+        .chain(csd.address_to_class_hash.keys())
+        .chain(csd.address_to_nonce.keys())
+        .collect();
+    let contracts = contracts.len() as u64;
+    cost.add_trie(contracts);
+    cost.hashes += 3 * contracts;
+
+    let classes = csd.class_hash_to_compiled_class_hash.len() as u64;
+    cost.add_trie(classes);
+    cost.hashes += classes;
+
+    // the state root
+    if classes > 0 || contracts > 0 {
+        cost.hashes += 1;
+    }
+
+    cost
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+
+    fn csd(slots: u64) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(1_u64)));
+        let updates = (0..slots).map(|key| (StorageKey(PatriciaKey(StarkFelt::from(key))), StarkFelt::ONE)).collect();
+        CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::ONE))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(contract_address, updates)].into_iter().collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_estimate_commit_cost() {
+        let empty = CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        assert_eq!(estimate_commit_cost(&empty), CommitCost::default());
+
+        let one = estimate_commit_cost(&csd(1));
+        // one storage leaf and one contract leaf, 251 nodes each, plus leaf and state root hashes
+        assert_eq!(one, CommitCost { leaf_writes: 2, hashes: 2 * 251 + 3 + 1, node_writes: 2 * 251 });
+
+        // costs grow with the diff, but less than linearly since paths are shared
+        let many = estimate_commit_cost(&csd(64));
+        assert!(many.node_writes > one.node_writes);
+        assert!(many.node_writes < 64 * one.node_writes);
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::hash::Hash;
+
+use indexmap::map::Entry;
+use indexmap::IndexMap;
+use starknet_api::core::{ClassHash, ContractAddress};
+use starknet_api::state::StorageKey;
+
+/// How to handle an entry which appears more than once in a single state update.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Reject the state update.
+    Error,
+    /// Keep the first value seen for the entry.
+    FirstWins,
+    /// Keep the last value seen for the entry. This matches how the sequencer applies writes.
+    #[default]
+    LastWins,
+}
+
+/// An entry which appeared more than once in a single state update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DuplicateEntry {
+    DeployedContract(ContractAddress),
+    ReplacedClass(ContractAddress),
+    DeclaredClass(ClassHash),
+    Nonce(ContractAddress),
+    StorageKey { contract_address: ContractAddress, key: StorageKey },
+}
+
+impl fmt::Display for DuplicateEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicateEntry::DeployedContract(address) => write!(f, "deployed contract {:?}", address),
+            DuplicateEntry::ReplacedClass(address) => write!(f, "replaced class for {:?}", address),
+            DuplicateEntry::DeclaredClass(class_hash) => write!(f, "declared class {:?}", class_hash),
+            DuplicateEntry::Nonce(address) => write!(f, "nonce update for {:?}", address),
+            DuplicateEntry::StorageKey { contract_address, key } => {
+                write!(f, "storage key {:?} of contract {:?}", key, contract_address)
+            }
+        }
+    }
+}
+
+impl DuplicatePolicy {
+    /// Inserts `value` at `key` according to the policy.
+    ///
+    /// If `key` is already present, the duplicate is pushed to `report` (or returned as an error
+    /// with [DuplicatePolicy::Error]).
+    ///
+    /// # Arguments
+    ///
+    /// * `map`    - The map to insert into.
+    /// * `key`    - The key of the entry.
+    /// * `value`  - The value of the entry.
+    /// * `entry`  - Describes the entry, only called if it is a duplicate.
+    /// * `report` - Duplicates found so far.
+    pub fn insert<K: Hash + Eq, V>(
+        self,
+        map: &mut IndexMap<K, V>,
+        key: K,
+        value: V,
+        entry: impl FnOnce() -> DuplicateEntry,
+        report: &mut Vec<DuplicateEntry>,
+    ) -> Result<(), DuplicateEntry> {
+        match map.entry(key) {
+            Entry::Vacant(vacant) => {
+                vacant.insert(value);
+            }
+            Entry::Occupied(mut occupied) => {
+                let entry = entry();
+                match self {
+                    DuplicatePolicy::Error => return Err(entry),
+                    DuplicatePolicy::FirstWins => {}
+                    DuplicatePolicy::LastWins => {
+                        occupied.insert(value);
+                    }
+                }
+                report.push(entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+
+    fn insert_twice(policy: DuplicatePolicy) -> (Result<(), DuplicateEntry>, IndexMap<u8, u8>, Vec<DuplicateEntry>) {
+        let entry = || DuplicateEntry::Nonce(ContractAddress(PatriciaKey(StarkFelt::ONE)));
+        let mut map = IndexMap::new();
+        let mut report = Vec::new();
+
+        policy.insert(&mut map, 0, 1, entry, &mut report).unwrap();
+        let result = policy.insert(&mut map, 0, 2, entry, &mut report);
+
+        (result, map, report)
+    }
+
+    #[test]
+    fn test_duplicate_policies() {
+        let (result, map, report) = insert_twice(DuplicatePolicy::LastWins);
+        assert!(result.is_ok());
+        assert_eq!(map[&0], 2);
+        assert_eq!(report.len(), 1);
+
+        let (result, map, report) = insert_twice(DuplicatePolicy::FirstWins);
+        assert!(result.is_ok());
+        assert_eq!(map[&0], 1);
+        assert_eq!(report.len(), 1);
+
+        let (result, map, _) = insert_twice(DuplicatePolicy::Error);
+        assert!(result.is_err());
+        assert_eq!(map[&0], 1);
+    }
+}
@@ -0,0 +1,1719 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock};
+
+use bitvec::prelude::{BitSlice, BitVec, Msb0};
+use blockifier::state::cached_state::CommitmentStateDiff;
+use bonsai_trie::id::BasicId;
+use bonsai_trie::{BonsaiStorage, BonsaiStorageConfig, DatabaseKey};
+use indexmap::IndexMap;
+use mp_felt::Felt252Wrapper;
+use rayon::prelude::*;
+use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Pedersen, Poseidon};
+
+use super::atomic::Trie;
+use super::backend::{Backend, BackendError, BonsaiBackend, Column, MemoryBackend, OverlayBackend};
+use super::canary::CanaryLog;
+use super::canonical::Canonicalize;
+use super::classes::{class_commitment_leaf_hash, ClassDeclarationProof};
+use super::compression::TrieCompression;
+use super::config::{ChainConfig, NodeHash, StorageWrite, TrieHashers};
+use super::contracts::{contract_leaf_hash, quarantine_or_fail};
+use super::conversions::validate_trie_keys;
+use super::error::{ErrorContext, ResultExt, TrieError};
+use super::history::{ContractActivity, StorageHistory};
+use super::mutation_log::Mutation;
+use super::proof::{felt_to_path, path_to_felt, ProofNode};
+use super::pruning::prune_trie_logs;
+use super::quarantine::{FailureMode, Quarantine};
+use super::recording::{Capture, Interaction, RecordingBackend};
+use super::roots::{FencingToken, RootRegistry};
+use super::runtime::Execution;
+use super::settings::RetentionSettings;
+use super::shadow::ShadowBackend;
+use super::squash::{empty_storage_tracker, EmptyStorageTracker};
+use super::stats::{ContractSizes, TrieWrites};
+use super::storage_proof::{proof, state_commitment, ContractData, StorageProof};
+use super::watchdog::SlaWatchdog;
+
+/// Identifier of the contracts trie and of the classes trie within their bonsai storage.
+const IDENTIFIER: &[u8] = b"trie";
+
+/// Keys of the [metadata](Column::Metadata) of the engine, stored as big-endian `u64`s.
+const LATEST: &[u8] = b"latest";
+const HORIZON: &[u8] = b"horizon";
+const FENCING_TOKEN: &[u8] = b"fencing_token";
+/// Prefix of the records of the blocks committed by the [StateTries] commit path, keyed by
+/// big-endian block number and holding the diff hash followed by the state root of the block.
+const BLOCK_PREFIX: &[u8] = b"block/";
+
+fn block_key(block_number: u64) -> Vec<u8> {
+    [BLOCK_PREFIX, &block_number.to_be_bytes()].concat()
+}
+
+fn felt(felt: &StarkFelt) -> Felt {
+    Felt::from_bytes_be(&felt.0)
+}
+
+fn backend_error(e: impl fmt::Debug) -> TrieError {
+    BackendError::Trie(format!("{e:?}")).into()
+}
+
+/// The block of a trie log entry, given its key in a trie column: trie logs are keyed by the id of
+/// the commit which wrote them, past the namespace.
+pub(crate) fn trie_log_block(key: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(key.get(1..9)?.try_into().expect("8 bytes")))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CompactionError {
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+    #[error("rebuilt storage trie of contract {contract_address:?} has root {computed:#x}, expected {expected:#x}")]
+    RootMismatch { contract_address: ContractAddress, expected: Felt, computed: Felt },
+}
+
+/// Outcome of a [contract compaction](CommitmentEngine::compact_contract).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub storage_root: Felt,
+    pub leaves: usize,
+    /// Number of entries stored for the contract's storage trie before the compaction, stale nodes
+    /// included.
+    pub entries_before: usize,
+    pub entries_after: usize,
+}
+
+/// What [pruning](CommitmentEngine::prune_before) the versions of the tries removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VersionPruneReport {
+    /// The first block the tries can still be reverted to.
+    pub horizon: u64,
+    /// Number of trie log entries deleted, across the three tries.
+    pub trie_log_entries: usize,
+}
+
+/// The state an [in-memory engine](CommitmentEngine::in_memory) starts from.
+#[derive(Debug, Clone, Default)]
+pub struct StateSeed {
+    pub storage: Vec<(ContractAddress, StorageKey, StarkFelt)>,
+    pub class_hashes: Vec<(ContractAddress, ClassHash)>,
+    pub nonces: Vec<(ContractAddress, Nonce)>,
+    pub compiled_class_hashes: Vec<(ClassHash, CompiledClassHash)>,
+}
+
+impl From<StateSeed> for CommitmentStateDiff {
+    fn from(seed: StateSeed) -> Self {
+        let mut storage_updates: IndexMap<ContractAddress, IndexMap<StorageKey, StarkFelt>> = IndexMap::new();
+        for (contract_address, key, value) in seed.storage {
+            storage_updates.entry(contract_address).or_default().insert(key, value);
+        }
+        CommitmentStateDiff {
+            address_to_class_hash: seed.class_hashes.into_iter().collect(),
+            address_to_nonce: seed.nonces.into_iter().collect(),
+            storage_updates,
+            class_hash_to_compiled_class_hash: seed.compiled_class_hashes.into_iter().collect(),
+        }
+    }
+}
+
+/// The state tries of a chain, stored in a [backend](super::backend) of the caller's choosing
+/// instead of the node's database.
+///
+/// This commits the same state roots as [WriteHandle::commit](super::handles::WriteHandle::commit)
+/// without any global state: each engine owns its tries and the [ChainConfig] it was opened with,
+/// so that several of them can live in the same process (ie: in tests, or when embedding the
+/// commitment logic in another node). The indexes and quarantine of [StateTries] only follow the
+/// commits made through their API.
+pub struct CommitmentEngine {
+    backend: Backend,
+    /// `backend` as the tries read and write it, recording their node accesses while a block is
+    /// [recorded](super::recording::record_block).
+    nodes: Arc<RecordingBackend>,
+    /// Leaves and nodes written by the tries, see [written_stats](super::stats::written_stats).
+    writes: Arc<Mutex<TrieWrites>>,
+    /// The hashers the tries were opened with, of which only the node hashes are used.
+    hashers: TrieHashers,
+    /// How the node payloads are stored.
+    format: NodeFormat,
+    contract_storage: NodeTrie,
+    contracts: NodeTrie,
+    classes: NodeTrie,
+    latest: Option<u64>,
+    /// Number of blocks the versions of the tries are kept for, see [CommitmentEngine::set_retention].
+    retention: Option<u64>,
+    /// The first block the tries can be reverted to, the versions before it were pruned.
+    horizon: u64,
+    /// Backend holding the snapshots of the blocks before the first one committed to `backend`, see
+    /// [Backfill](super::backfill::Backfill).
+    archive: Option<Backend>,
+    /// The most recent [fencing token](FencingToken) of the [StateTries] commits, persisted along
+    /// with the next block.
+    fencing_token: Option<FencingToken>,
+    /// What the commits are captured by, shared with the [StateTries] of the engine.
+    capture: Arc<Capture>,
+}
+
+impl fmt::Debug for CommitmentEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitmentEngine").field("latest", &self.latest).finish_non_exhaustive()
+    }
+}
+
+/// A trie of the engine, whose nodes are hashed with the [node hash](NodeHash) configured for it.
+enum NodeTrie {
+    Pedersen(BonsaiStorage<BasicId, BonsaiBackend, Pedersen>),
+    Poseidon(BonsaiStorage<BasicId, BonsaiBackend, Poseidon>),
+}
+
+impl NodeTrie {
+    fn new(backend: BonsaiBackend, hash: NodeHash) -> Result<Self, TrieError> {
+        let config = BonsaiStorageConfig::default();
+        Ok(match hash {
+            NodeHash::Pedersen => NodeTrie::Pedersen(BonsaiStorage::new(backend, config).map_err(backend_error)?),
+            NodeHash::Poseidon => NodeTrie::Poseidon(BonsaiStorage::new(backend, config).map_err(backend_error)?),
+        })
+    }
+
+    fn insert(&mut self, identifier: &[u8], key: &BitSlice<u8, Msb0>, value: &Felt) -> Result<(), TrieError> {
+        match self {
+            NodeTrie::Pedersen(storage) => storage.insert(identifier, key, value),
+            NodeTrie::Poseidon(storage) => storage.insert(identifier, key, value),
+        }
+        .map_err(backend_error)
+    }
+
+    fn get(&self, identifier: &[u8], key: &BitSlice<u8, Msb0>) -> Result<Option<Felt>, TrieError> {
+        match self {
+            NodeTrie::Pedersen(storage) => storage.get(identifier, key),
+            NodeTrie::Poseidon(storage) => storage.get(identifier, key),
+        }
+        .map_err(backend_error)
+    }
+
+    fn get_keys(&self, identifier: &[u8]) -> Result<Vec<Vec<u8>>, TrieError> {
+        match self {
+            NodeTrie::Pedersen(storage) => storage.get_keys(identifier),
+            NodeTrie::Poseidon(storage) => storage.get_keys(identifier),
+        }
+        .map_err(backend_error)
+    }
+
+    fn get_proof(&self, identifier: &[u8], key: &BitSlice<u8, Msb0>) -> Result<Vec<bonsai_trie::ProofNode>, TrieError> {
+        match self {
+            NodeTrie::Pedersen(storage) => storage.get_proof(identifier, key),
+            NodeTrie::Poseidon(storage) => storage.get_proof(identifier, key),
+        }
+        .map_err(backend_error)
+    }
+
+    fn commit(&mut self, id: BasicId) -> Result<(), TrieError> {
+        match self {
+            NodeTrie::Pedersen(storage) => storage.commit(id),
+            NodeTrie::Poseidon(storage) => storage.commit(id),
+        }
+        .map_err(backend_error)
+    }
+
+    fn root_hash(&self, identifier: &[u8]) -> Result<Felt, TrieError> {
+        match self {
+            NodeTrie::Pedersen(storage) => storage.root_hash(identifier),
+            NodeTrie::Poseidon(storage) => storage.root_hash(identifier),
+        }
+        .map_err(backend_error)
+    }
+
+    fn revert_to(&mut self, target: BasicId, current: BasicId) -> Result<(), TrieError> {
+        match self {
+            NodeTrie::Pedersen(storage) => storage.revert_to(target, current),
+            NodeTrie::Poseidon(storage) => storage.revert_to(target, current),
+        }
+        .map_err(backend_error)
+    }
+}
+
+type Tries = (NodeTrie, NodeTrie, NodeTrie);
+
+/// How the node payloads of the tries of an engine are stored, as set by the [ChainConfig] it was
+/// opened with.
+#[derive(Debug, Clone, Copy)]
+struct NodeFormat {
+    compression: TrieCompression,
+    checksums: bool,
+}
+
+impl From<&ChainConfig> for NodeFormat {
+    fn from(config: &ChainConfig) -> Self {
+        Self { compression: config.compression, checksums: config.node_checksums }
+    }
+}
+
+/// The column of `trie` in `backend`, compressed and [checksummed](ChainConfig::node_checksums) as
+/// set by `format`.
+fn trie_backend(backend: &Backend, trie: Trie, format: NodeFormat) -> BonsaiBackend {
+    BonsaiBackend::new(Arc::clone(backend), trie).with_compression(format.compression).with_checksums(format.checksums)
+}
+
+fn open_tries(
+    backend: &Backend,
+    hashers: TrieHashers,
+    format: NodeFormat,
+    writes: &Arc<Mutex<TrieWrites>>,
+) -> Result<Tries, TrieError> {
+    let trie_backend = |trie| trie_backend(backend, trie, format).with_write_counts(Arc::clone(writes));
+    Ok((
+        NodeTrie::new(trie_backend(Trie::ContractStorage), hashers.storage_node)?,
+        NodeTrie::new(trie_backend(Trie::Contracts), hashers.contracts_node)?,
+        NodeTrie::new(trie_backend(Trie::Classes), hashers.classes_node)?,
+    ))
+}
+
+fn metadata(backend: &Backend, key: &[u8]) -> Result<Option<u64>, TrieError> {
+    let Some(value) = backend.get(Column::Metadata, key)? else {
+        return Ok(None);
+    };
+    let value = value.try_into().map_err(|_| BackendError::Io(format!("corrupted engine metadata {key:?}")))?;
+    Ok(Some(u64::from_be_bytes(value)))
+}
+
+impl CommitmentEngine {
+    /// Opens the tries stored in `backend`, along with the latest block committed to it.
+    ///
+    /// The trie nodes are stored as on Starknet, see [CommitmentEngine::with_config].
+    pub fn new(backend: Backend) -> Result<Self, TrieError> {
+        Self::with_config(backend, &ChainConfig::default())
+    }
+
+    /// Opens the tries stored in `backend`, their nodes hashed with the node hashes of `hashers`.
+    ///
+    /// See [CommitmentEngine::with_config].
+    pub fn with_hashers(backend: Backend, hashers: TrieHashers) -> Result<Self, TrieError> {
+        Self::with_config(backend, &ChainConfig { hashers, ..Default::default() })
+    }
+
+    /// Opens the tries stored in `backend`, their nodes hashed with the node hashes of `config`,
+    /// [compressed](ChainConfig::compression) and [checksummed](ChainConfig::node_checksums) as it
+    /// sets.
+    ///
+    /// These describe the tries already stored in `backend`: they are only ever read from `config`
+    /// on open, the [ChainConfig] of the updates does not change them.
+    pub fn with_config(backend: Backend, config: &ChainConfig) -> Result<Self, TrieError> {
+        Self::open(backend, config.hashers, NodeFormat::from(config))
+    }
+
+    /// Opens the tries stored in `backend`, their nodes hashed and stored as those of this engine,
+    /// ie: over one of its snapshots.
+    pub(crate) fn reopen(&self, backend: Backend) -> Result<Self, TrieError> {
+        Self::open(backend, self.hashers, self.format)
+    }
+
+    fn open(backend: Backend, hashers: TrieHashers, format: NodeFormat) -> Result<Self, TrieError> {
+        let capture = Arc::<Capture>::default();
+        let nodes = Arc::new(RecordingBackend::new(Arc::clone(&backend), Arc::clone(&capture)));
+        let writes = Arc::default();
+        let (contract_storage, contracts, classes) =
+            open_tries(&(Arc::clone(&nodes) as Backend), hashers, format, &writes)?;
+        Ok(Self {
+            nodes,
+            writes,
+            hashers,
+            format,
+            contract_storage,
+            contracts,
+            classes,
+            latest: metadata(&backend, LATEST)?,
+            horizon: metadata(&backend, HORIZON)?.unwrap_or_default(),
+            backend,
+            retention: None,
+            archive: None,
+            fencing_token: metadata(&backend, FENCING_TOKEN)?.map(FencingToken),
+            capture,
+        })
+    }
+
+    /// Opens the tries stored in `backend`, whose latest committed block is `block_number`, ie: when
+    /// the backend was written to by something else than an engine.
+    ///
+    /// The tries cannot be [reverted](CommitmentEngine::revert_to) before `block_number`: the
+    /// engine does not know which of the earlier versions the backend still holds.
+    ///
+    /// The writes staged to the backend are committed as the state of `block_number`.
+    pub fn open_at(backend: Backend, block_number: u64) -> Result<Self, TrieError> {
+        let mut engine = Self::new(backend)?;
+        engine.horizon = block_number;
+        engine.commit_backend(block_number)?;
+        Ok(engine)
+    }
+
+    /// Creates transient tries holding `seed` as the state right after `block_number`, which are
+    /// never persisted.
+    ///
+    /// Only the touched part of the state needs to be seeded, ie: to re-execute a block statelessly
+    /// or to unit-test the commitment logic without a database. The state roots are those of the
+    /// seeded state, not of the chain's.
+    ///
+    /// # Returns
+    ///
+    /// The engine and the state root of the seed.
+    pub fn in_memory(
+        seed: StateSeed,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<(Self, Felt252Wrapper), TrieError> {
+        let mut engine = Self::with_config(Arc::new(MemoryBackend::new()), config)?;
+        let state_root = engine.update_state_root(seed.into(), block_number, config)?;
+        Ok((engine, state_root))
+    }
+
+    /// The hashers the tries were opened with, see [CommitmentEngine::with_hashers].
+    pub fn hashers(&self) -> TrieHashers {
+        self.hashers
+    }
+
+    /// The backend the tries are stored in.
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    fn tries_backend(&self) -> Backend {
+        Arc::clone(&self.nodes) as Backend
+    }
+
+    fn storage_trie_backend(&self) -> BonsaiBackend {
+        trie_backend(&self.backend, Trie::ContractStorage, self.format).with_write_counts(Arc::clone(&self.writes))
+    }
+
+    /// Returns the leaves and nodes written by the tries since they were last taken, and resets them.
+    pub(crate) fn take_writes(&self) -> TrieWrites {
+        std::mem::take(&mut *self.writes.lock().expect("Poisoned lock on trie writes"))
+    }
+
+    /// The latest block committed by this engine.
+    pub fn latest(&self) -> Option<u64> {
+        self.latest
+    }
+
+    /// The first block the tries can be [reverted](CommitmentEngine::revert_to) to.
+    pub fn horizon(&self) -> u64 {
+        self.horizon
+    }
+
+    /// The most recent fencing token persisted or staged, see
+    /// [commit_fenced](super::handles::WriteHandle::commit_fenced).
+    pub fn fencing_token(&self) -> Option<FencingToken> {
+        self.fencing_token
+    }
+
+    /// Stages `token` as the most recent fencing token, persisted along with the next block.
+    pub(crate) fn set_fencing_token(&mut self, token: FencingToken) {
+        self.fencing_token = Some(token);
+    }
+
+    /// Stages the record of a block committed by the [StateTries] commit path, persisted along with
+    /// it so that [committed_block](Self::committed_block) outlives the
+    /// [root registry](super::roots::RootRegistry).
+    pub(crate) fn stage_committed_block(
+        &self,
+        block_number: u64,
+        diff_hash: Felt252Wrapper,
+        state_root: Felt252Wrapper,
+    ) -> Result<(), TrieError> {
+        let record = [diff_hash.0.to_bytes_be(), state_root.0.to_bytes_be()].concat();
+        Ok(self.backend.put(Column::Metadata, &block_key(block_number), Some(&record))?)
+    }
+
+    /// Returns the diff hash and the state root of a block committed by the [StateTries] commit
+    /// path, if it was not pruned.
+    pub fn committed_block(&self, block_number: u64) -> Result<Option<(Felt252Wrapper, Felt252Wrapper)>, TrieError> {
+        let Some(record) = self.backend.get(Column::Metadata, &block_key(block_number))? else {
+            return Ok(None);
+        };
+        let corrupted = || BackendError::Io(format!("corrupted record of block {block_number}"));
+        let felt = |bytes: &[u8]| {
+            let bytes: [u8; 32] = bytes.try_into().map_err(|_| corrupted())?;
+            FieldElement::from_bytes_be(&bytes).map(Felt252Wrapper::from).map_err(|_| corrupted())
+        };
+        if record.len() != 64 {
+            return Err(corrupted().into());
+        }
+        Ok(Some((felt(&record[..32])?, felt(&record[32..])?)))
+    }
+
+    /// Extends the versions of the tries back to `horizon`, once the trie logs of the earlier blocks
+    /// were copied to the backend. `archive` holds the snapshots of those blocks.
+    pub(crate) fn prepend_history(&mut self, archive: Backend, horizon: u64) {
+        self.archive = Some(archive);
+        self.horizon = horizon;
+    }
+
+    /// Keeps the versions of the tries for the latest `retention` blocks only, pruning the older
+    /// ones as blocks are committed. `None`, the default, keeps every version.
+    ///
+    /// Bonsai keeps a log of the changes of each commit so that the tries can be reverted, which
+    /// grows with every block on long-running nodes.
+    pub fn set_retention(&mut self, retention: Option<u64>) {
+        self.retention = retention.map(|retention| retention.max(1));
+    }
+
+    /// Deletes the versions of the tries older than `block_number`, which can no longer be
+    /// [reverted](CommitmentEngine::revert_to) to. The latest state is always kept.
+    pub fn prune_before(&mut self, block_number: u64) -> Result<VersionPruneReport, TrieError> {
+        let Some(latest) = self.latest else {
+            return Ok(VersionPruneReport { horizon: self.horizon, trie_log_entries: 0 });
+        };
+        let report = self.stage_prune(block_number.min(latest))?;
+        self.commit_backend(latest)?;
+        Ok(report)
+    }
+
+    /// Commits the backend as the state right after `block_number`, along with the metadata of
+    /// the engine.
+    ///
+    /// If the backend fails to commit, the staged writes are dropped and the tries reopened over
+    /// the last committed state: the block must be committed again from scratch.
+    fn commit_backend(&mut self, block_number: u64) -> Result<(), TrieError> {
+        let staged = (|| {
+            self.backend.put(Column::Metadata, LATEST, Some(&block_number.to_be_bytes()))?;
+            self.backend.put(Column::Metadata, HORIZON, Some(&self.horizon.to_be_bytes()))?;
+            if let Some(FencingToken(token)) = self.fencing_token {
+                self.backend.put(Column::Metadata, FENCING_TOKEN, Some(&token.to_be_bytes()))?;
+            }
+            self.backend.commit(block_number)
+        })();
+        if let Err(e) = staged {
+            self.discard()?;
+            return Err(e.into());
+        }
+        self.latest = Some(block_number);
+        // The writes of a block are only counted until it is durable, see [written_stats](super::stats::written_stats)
+        self.take_writes();
+        Ok(())
+    }
+
+    /// Drops the writes staged since the last backend commit, ie: of a block whose commit failed.
+    pub(crate) fn discard(&mut self) -> Result<(), TrieError> {
+        self.nodes.finish();
+        self.backend.discard();
+        self.take_writes();
+        // Bonsai caches the nodes it wrote, the tries are reopened over the committed ones
+        (self.contract_storage, self.contracts, self.classes) =
+            open_tries(&self.tries_backend(), self.hashers, self.format, &self.writes)?;
+        self.horizon = metadata(&self.backend, HORIZON)?.unwrap_or_default();
+        self.fencing_token = metadata(&self.backend, FENCING_TOKEN)?.map(FencingToken);
+        Ok(())
+    }
+
+    /// Stages the deletion of the trie logs of the blocks before `block_number`, which the next
+    /// backend commit applies.
+    fn stage_prune(&mut self, block_number: u64) -> Result<VersionPruneReport, TrieError> {
+        if block_number <= self.horizon {
+            return Ok(VersionPruneReport { horizon: self.horizon, trie_log_entries: 0 });
+        }
+        // Trie logs are keyed by the big-endian id of their commit, the logs of the blocks pruned are
+        // those between the previous horizon and the new one
+        let start = BonsaiBackend::key(&DatabaseKey::TrieLog(&self.horizon.to_be_bytes()));
+        let end = BonsaiBackend::key(&DatabaseKey::TrieLog(&block_number.to_be_bytes()));
+        let mut trie_log_entries = 0;
+        for trie in Trie::ALL {
+            let column = Column::Trie(trie);
+            for (key, _) in self.backend.scan_range(column, &start, &end)? {
+                self.backend.put(column, &key, None)?;
+                trie_log_entries += 1;
+            }
+        }
+        for (key, _) in self.backend.scan_range(Column::Metadata, &block_key(self.horizon), &block_key(block_number))? {
+            self.backend.put(Column::Metadata, &key, None)?;
+        }
+        self.backend.prune_snapshots_before(block_number)?;
+        if let Some(archive) = &self.archive {
+            archive.prune_snapshots_before(block_number)?;
+        }
+        self.horizon = block_number;
+        Ok(VersionPruneReport { horizon: block_number, trie_log_entries })
+    }
+
+    /// Applies a block's state diff to the tries and commits them.
+    ///
+    /// See [WriteHandle::commit](super::handles::WriteHandle::commit). Blocks are not deduplicated:
+    /// the caller must revert the engine before committing a block again.
+    ///
+    /// # Arguments
+    ///
+    /// * `csd`          - The commitment state diff inducing unprocessed state changes.
+    /// * `block_number` - The current block number.
+    /// * `config`       - Chain-specific commitment rules.
+    ///
+    /// # Returns
+    ///
+    /// The updated state root.
+    pub fn update_state_root(
+        &mut self,
+        mut csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, TrieError> {
+        csd.canonicalize();
+        validate_trie_keys(&csd)?;
+        let staged = self
+            .update_contracts(&csd, block_number, config, None)
+            .and_then(|_| self.update_classes(&csd, block_number, config));
+        if let Err(e) = staged {
+            self.capture.mutation_log.end_block(block_number, false);
+            self.discard()?;
+            return Err(e);
+        }
+        let committed = self.commit_block(block_number);
+        self.capture.mutation_log.end_block(block_number, committed.is_ok());
+        committed?;
+        self.state_root(config)
+    }
+
+    /// Applies a block's storage, class hash and nonce updates to the contract storage tries and the
+    /// contracts trie, which are committed but not durable until [commit_block](Self::commit_block).
+    ///
+    /// Contracts which fail to update are added to `quarantine` in
+    /// [FailureMode::Quarantine](super::quarantine::FailureMode::Quarantine), their updates being
+    /// skipped and their leaf left stale. Without a quarantine any failure fails the block.
+    ///
+    /// # Returns
+    ///
+    /// The root of the contracts trie.
+    pub(crate) fn update_contracts(
+        &mut self,
+        csd: &CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+        quarantine: Option<&mut Quarantine>,
+    ) -> Result<Felt252Wrapper, TrieError> {
+        let id = BasicId::new(block_number);
+        self.nodes.start(block_number);
+
+        // Before the first block there is no committed state to drop the inserts of the block to
+        let failure_mode = match quarantine {
+            Some(_) if self.latest.is_some() => config.failure_mode,
+            _ => FailureMode::Strict,
+        };
+        let mut unused = Quarantine::default();
+        let quarantine = quarantine.unwrap_or(&mut unused);
+
+        // Storage tries first, the contract leaves hash their roots. The inserts of a contract which
+        // fails halfway through cannot be undone on their own, so the storage tries are reopened over
+        // their committed nodes and the pass starts over without the quarantined contract.
+        let context = || ErrorContext::block(block_number).trie(Trie::ContractStorage);
+        loop {
+            let mut partial = false;
+            for (contract_address, updates) in csd.storage_updates.iter() {
+                if quarantine.contains(contract_address) {
+                    continue;
+                }
+                if let Err(e) = self.insert_storage(contract_address, updates, block_number, config) {
+                    let trie = Trie::ContractStorage;
+                    quarantine_or_fail(quarantine, failure_mode, contract_address, block_number, trie, e)?;
+                    partial = true;
+                }
+            }
+            if !partial {
+                break;
+            }
+            self.contract_storage = NodeTrie::new(self.storage_trie_backend(), self.hashers.storage_node)?;
+        }
+        self.contract_storage.commit(id).context(context)?;
+
+        let context = || ErrorContext::block(block_number).trie(Trie::Contracts);
+        for (contract_address, class_hash) in csd.address_to_class_hash.iter() {
+            if !quarantine.contains(contract_address) {
+                self.backend
+                    .put(Column::ClassHashes, &contract_address.0.key().0, Some(&class_hash.0.0))
+                    .context(context)?;
+            }
+        }
+        for (contract_address, nonce) in csd.address_to_nonce.iter() {
+            if !quarantine.contains(contract_address) {
+                self.backend.put(Column::Nonces, &contract_address.0.key().0, Some(&nonce.0.0)).context(context)?;
+            }
+        }
+        // Quarantined contracts keep their stale leaf
+        let contract_addresses: Vec<&ContractAddress> = csd
+            .storage_updates
+            .keys()
+            .chain(csd.address_to_class_hash.keys())
+            .chain(csd.address_to_nonce.keys())
+            .filter(|contract_address| !quarantine.contains(contract_address))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        // The storage root and leaf hash of each contract are computed in parallel on the thread pool
+        // the commit runs on, each storage trie being independent from the others, then the leaves
+        // are inserted in address order
+        let engine = &*self;
+        let leaves = contract_addresses
+            .par_iter()
+            .map(|&contract_address| {
+                let leaf = (|| -> Result<_, (Trie, TrieError)> {
+                    let storage_root = engine.storage_root(contract_address).map_err(|e| (Trie::ContractStorage, e))?;
+                    let class_hash = engine.class_hash(contract_address).map_err(|e| (Trie::Contracts, e))?;
+                    let nonce = engine.nonce(contract_address).map_err(|e| (Trie::Contracts, e))?;
+                    let leaf_hash = contract_leaf_hash(felt(&class_hash.0), felt(&nonce.0), storage_root, config);
+                    Ok((storage_root, leaf_hash))
+                })();
+                (contract_address, leaf)
+            })
+            .collect::<Vec<_>>();
+        for (contract_address, leaf) in leaves {
+            let (storage_root, leaf_hash) = match leaf {
+                Ok(leaf) => leaf,
+                Err((trie, e)) => {
+                    quarantine_or_fail(quarantine, failure_mode, contract_address, block_number, trie, e)?;
+                    continue;
+                }
+            };
+            self.contracts
+                .insert(IDENTIFIER, &felt_to_path(&felt(contract_address.0.key())), &leaf_hash)
+                .context(|| context().contract(*contract_address))?;
+            let contract_address = felt(contract_address.0.key());
+            self.capture.record(block_number, || Interaction::StorageRoot { contract_address, root: storage_root });
+            self.capture.record(block_number, || Interaction::ContractLeaf { contract_address, leaf_hash });
+        }
+        // The skipped updates are kept to be replayed once the contracts are repaired
+        for contract_address in
+            csd.storage_updates.keys().chain(csd.address_to_class_hash.keys()).chain(csd.address_to_nonce.keys())
+        {
+            quarantine.skip(contract_address, block_number, csd);
+        }
+        self.contracts.commit(id).context(context)?;
+        let root = self.contracts.root_hash(IDENTIFIER).context(context)?;
+        self.capture.record(block_number, || Interaction::TrieRoot { trie: Trie::Contracts, root });
+        Ok(root.into())
+    }
+
+    /// Inserts the storage updates of a contract into its storage trie.
+    fn insert_storage(
+        &mut self,
+        contract_address: &ContractAddress,
+        updates: &IndexMap<StorageKey, StarkFelt>,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<(), TrieError> {
+        let identifier = contract_address.0.key().0;
+        for (key, value) in updates {
+            let value = match config.zero_writes.write(*value) {
+                Some(StorageWrite::Set(value)) => felt(&value),
+                Some(StorageWrite::Delete) => Felt::ZERO,
+                None => continue,
+            };
+            self.contract_storage
+                .insert(&identifier, &felt_to_path(&felt(key.0.key())), &value)
+                .context(|| ErrorContext::default().key(*key))?;
+            self.capture.record(block_number, || Interaction::StorageWrite {
+                contract_address: felt(contract_address.0.key()),
+                key: felt(key.0.key()),
+                value,
+            });
+        }
+        Ok(())
+    }
+
+    /// Applies a block's declared classes to the classes trie, which is committed but not durable
+    /// until [commit_block](Self::commit_block).
+    ///
+    /// # Returns
+    ///
+    /// The root of the classes trie.
+    pub(crate) fn update_classes(
+        &mut self,
+        csd: &CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, TrieError> {
+        let context = || ErrorContext::block(block_number).trie(Trie::Classes);
+        for (class_hash, compiled_class_hash) in csd.class_hash_to_compiled_class_hash.iter() {
+            let compiled_class_hash = FieldElement::from_bytes_be(&compiled_class_hash.0.0).unwrap();
+            let leaf_hash = Felt::from_bytes_be(&class_commitment_leaf_hash(compiled_class_hash, config).to_bytes_be());
+            self.classes
+                .insert(IDENTIFIER, &felt_to_path(&felt(&class_hash.0)), &leaf_hash)
+                .context(|| context().class_hash(*class_hash))?;
+            self.capture.record(block_number, || Interaction::ClassLeaf { class_hash: felt(&class_hash.0), leaf_hash });
+        }
+        self.classes.commit(BasicId::new(block_number)).context(context)?;
+        let root = self.classes.root_hash(IDENTIFIER).context(context)?;
+        self.capture.record(block_number, || Interaction::TrieRoot { trie: Trie::Classes, root });
+        Ok(root.into())
+    }
+
+    /// Makes the tries committed for `block_number` durable, in a single commit of the backend.
+    ///
+    /// If the backend fails to commit, the block is dropped from the tries and must be applied
+    /// again.
+    pub(crate) fn commit_block(&mut self, block_number: u64) -> Result<(), TrieError> {
+        // The deletions are applied along with the block
+        let horizon = self.horizon;
+        if let Some(retention) = self.retention {
+            if let Err(e) = self.stage_prune((block_number + 1).saturating_sub(retention)) {
+                self.horizon = horizon;
+                self.discard()?;
+                return Err(e);
+            }
+        }
+        let committed = self.commit_backend(block_number).context(|| ErrorContext::block(block_number));
+        self.nodes.finish();
+        committed
+    }
+
+    /// Inserts leaves as they are in the tries and commits them as `block_number`, see
+    /// [mutation_log::replay](super::mutation_log::replay).
+    ///
+    /// The class hashes and nonces of the contracts are not updated, the leaves of the contracts
+    /// trie already commit to them.
+    pub(crate) fn commit_leaves(&mut self, mutations: &[Mutation], block_number: u64) -> Result<(), TrieError> {
+        if let Err(e) = self.stage_leaves(mutations, block_number) {
+            self.discard()?;
+            return Err(e);
+        }
+        self.commit_block(block_number)
+    }
+
+    fn stage_leaves(&mut self, mutations: &[Mutation], block_number: u64) -> Result<(), TrieError> {
+        let id = BasicId::new(block_number);
+        for mutation in mutations {
+            let path = felt_to_path(&mutation.key);
+            match mutation.trie {
+                Trie::ContractStorage => {
+                    self.contract_storage.insert(&mutation.contract_address.to_bytes_be(), &path, &mutation.value)
+                }
+                Trie::Contracts => self.contracts.insert(IDENTIFIER, &path, &mutation.value),
+                Trie::Classes => self.classes.insert(IDENTIFIER, &path, &mutation.value),
+            }
+            .context(|| ErrorContext::block(block_number).trie(mutation.trie))?;
+        }
+        for trie in Trie::ALL {
+            match trie {
+                Trie::ContractStorage => self.contract_storage.commit(id),
+                Trie::Contracts => self.contracts.commit(id),
+                Trie::Classes => self.classes.commit(id),
+            }
+            .context(|| ErrorContext::block(block_number).trie(trie))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the current state root.
+    pub fn state_root(&self, config: &ChainConfig) -> Result<Felt252Wrapper, TrieError> {
+        let contracts_trie_root = self.contracts.root_hash(IDENTIFIER)?.into();
+        let classes_trie_root = self.classes.root_hash(IDENTIFIER)?.into();
+        Ok(config.state_root(contracts_trie_root, classes_trie_root))
+    }
+
+    /// Returns the current root of a contract's storage trie.
+    pub fn storage_root(&self, contract_address: &ContractAddress) -> Result<Felt, TrieError> {
+        self.contract_storage.root_hash(&contract_address.0.key().0)
+    }
+
+    /// Returns the current value of a contract's storage slot, zero if it was never written.
+    pub fn storage_value(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<StarkFelt, TrieError> {
+        let value = self.contract_storage.get(&contract_address.0.key().0, &felt_to_path(&felt(key.0.key())))?;
+        Ok(value.map(|value| StarkFelt(value.to_bytes_be())).unwrap_or_default())
+    }
+
+    /// Returns the current leaf of a contract in the contracts trie, `None` if it has none.
+    pub fn contract_leaf(&self, contract_address: &ContractAddress) -> Result<Option<Felt>, TrieError> {
+        self.contracts.get(IDENTIFIER, &felt_to_path(&felt(contract_address.0.key())))
+    }
+
+    /// Returns the current leaf of a class in the classes trie, `None` if it is not declared.
+    pub fn class_leaf(&self, class_hash: &ClassHash) -> Result<Option<Felt>, TrieError> {
+        self.classes.get(IDENTIFIER, &felt_to_path(&felt(&class_hash.0)))
+    }
+
+    /// Returns the number of leaves of the storage trie of each contract of the contracts trie, ie: of
+    /// its non-zero storage slots.
+    pub fn storage_leaves(&self) -> Result<Vec<(ContractAddress, u64)>, TrieError> {
+        self.contract_addresses()?
+            .into_iter()
+            .map(|contract_address| {
+                let leaves = self.contract_storage.get_keys(&contract_address.0.key().0)?;
+                Ok((contract_address, leaves.len() as u64))
+            })
+            .collect()
+    }
+
+    /// Returns the address of every leaf of the contracts trie, in ascending order.
+    pub fn contract_addresses(&self) -> Result<Vec<ContractAddress>, TrieError> {
+        let keys = self.contracts.get_keys(IDENTIFIER)?;
+        let mut contract_addresses: Vec<_> = keys
+            .into_iter()
+            .map(|key| {
+                let mut path = BitVec::<u8, Msb0>::from_vec(key);
+                path.truncate(251);
+                ContractAddress(PatriciaKey(StarkFelt(path_to_felt(&path).to_bytes_be())))
+            })
+            .collect();
+        contract_addresses.sort();
+        Ok(contract_addresses)
+    }
+
+    /// Returns the current class hash of a contract, zero if it is not deployed.
+    pub fn class_hash(&self, contract_address: &ContractAddress) -> Result<ClassHash, TrieError> {
+        let class_hash = self.backend.get(Column::ClassHashes, &contract_address.0.key().0)?;
+        Ok(ClassHash(class_hash.map(stark_felt).unwrap_or_default()))
+    }
+
+    /// Returns the current nonce of a contract.
+    pub fn nonce(&self, contract_address: &ContractAddress) -> Result<Nonce, TrieError> {
+        let nonce = self.backend.get(Column::Nonces, &contract_address.0.key().0)?;
+        Ok(Nonce(nonce.map(stark_felt).unwrap_or_default()))
+    }
+
+    /// Returns the proof of a storage slot in the storage trie of a contract, root first.
+    pub(crate) fn storage_slot_proof(
+        &self,
+        contract_address: &ContractAddress,
+        key: &StorageKey,
+    ) -> Result<Vec<ProofNode>, TrieError> {
+        let path = felt_to_path(&felt(key.0.key()));
+        Ok(proof(self.contract_storage.get_proof(&contract_address.0.key().0, &path)?))
+    }
+
+    /// Returns the root of the contracts trie along with the proof of a contract leaf, root first.
+    pub(crate) fn contract_leaf_proof(
+        &self,
+        contract_address: &ContractAddress,
+    ) -> Result<(Felt, Vec<ProofNode>), TrieError> {
+        let root = self.contracts.root_hash(IDENTIFIER)?;
+        let path = felt_to_path(&felt(contract_address.0.key()));
+        Ok((root, proof(self.contracts.get_proof(IDENTIFIER, &path)?)))
+    }
+
+    /// Generates the Merkle proofs of storage slots of a contract at the latest committed block.
+    ///
+    /// See [get_storage_proof](super::storage_proof::get_storage_proof).
+    pub fn storage_proof(
+        &self,
+        contract_address: &ContractAddress,
+        keys: &[StorageKey],
+        config: &ChainConfig,
+    ) -> Result<StorageProof, TrieError> {
+        let contracts_trie_root = self.contracts.root_hash(IDENTIFIER)?;
+        let class_commitment = self.classes.root_hash(IDENTIFIER)?;
+        let contract_proof = self.contracts.get_proof(IDENTIFIER, &felt_to_path(&felt(contract_address.0.key())))?;
+
+        let identifier = contract_address.0.key().0;
+        let contract_data = match self.backend.get(Column::ClassHashes, &identifier)? {
+            Some(class_hash) => {
+                let storage_proofs = keys
+                    .iter()
+                    .map(|key| {
+                        let path = felt_to_path(&felt(key.0.key()));
+                        Ok(proof(self.contract_storage.get_proof(&identifier, &path)?))
+                    })
+                    .collect::<Result<_, TrieError>>()?;
+                Some(ContractData {
+                    class_hash: felt(&stark_felt(class_hash)),
+                    nonce: felt(&self.nonce(contract_address)?.0),
+                    root: self.storage_root(contract_address)?,
+                    contract_state_hash_version: Felt::from(Felt252Wrapper::from(
+                        config.constants.contract_state_hash_version,
+                    )),
+                    storage_proofs,
+                })
+            }
+            None => None,
+        };
+
+        Ok(StorageProof {
+            block_number: self.latest.unwrap_or_default(),
+            contract_address: *contract_address,
+            state_commitment: state_commitment(contracts_trie_root, class_commitment, config),
+            class_commitment,
+            contracts_trie_root,
+            contract_proof: proof(contract_proof),
+            contract_data,
+        })
+    }
+
+    /// Generates the proof that a class is declared at the latest committed block.
+    ///
+    /// See [class_declaration_proof](super::classes::class_declaration_proof).
+    pub fn class_declaration_proof(
+        &self,
+        class_hash: &ClassHash,
+        config: &ChainConfig,
+    ) -> Result<Option<ClassDeclarationProof>, TrieError> {
+        let path = felt_to_path(&felt(&class_hash.0));
+        let Some(leaf_hash) = self.classes.get(IDENTIFIER, &path)? else {
+            return Ok(None);
+        };
+        let classes_trie_root = self.classes.root_hash(IDENTIFIER)?;
+        let contracts_trie_root = self.contracts.root_hash(IDENTIFIER)?;
+
+        Ok(Some(ClassDeclarationProof {
+            block_number: self.latest.unwrap_or_default(),
+            class_hash: felt(&class_hash.0),
+            leaf_hash,
+            class_proof: proof(self.classes.get_proof(IDENTIFIER, &path)?),
+            classes_trie_root,
+            contracts_trie_root,
+            state_root: state_commitment(contracts_trie_root, classes_trie_root, config),
+        }))
+    }
+
+    /// Reverts the tries to the state right after `block_number`, which must have been committed
+    /// by the backend and still have its snapshot, and must not have been
+    /// [pruned](CommitmentEngine::prune_before).
+    pub fn revert_to(&mut self, block_number: u64) -> Result<(), TrieError> {
+        let Some(latest) = self.latest.filter(|_| block_number >= self.horizon) else {
+            return Err(BackendError::NoSnapshot(block_number).into());
+        };
+        let (target, current) = (BasicId::new(block_number), BasicId::new(latest));
+        self.contract_storage.revert_to(target, current)?;
+        self.contracts.revert_to(target, current)?;
+        self.classes.revert_to(target, current)?;
+
+        // Class hashes and nonces are not versioned by bonsai, they are restored from the snapshot
+        let snapshot = self.snapshot(block_number)?;
+        for column in [Column::ClassHashes, Column::Nonces] {
+            for (key, _) in self.backend.scan_prefix(column, &[])? {
+                self.backend.put(column, &key, snapshot.get(column, &key)?.as_deref())?;
+            }
+        }
+        self.commit_backend(block_number)
+    }
+
+    /// The snapshot of the backend right after `block_number`, from the archive for the blocks
+    /// before the first one committed to the backend.
+    fn snapshot(&self, block_number: u64) -> Result<Backend, TrieError> {
+        match (&self.archive, self.backend.snapshot(block_number)) {
+            (Some(archive), Err(BackendError::NoSnapshot(_))) => Ok(archive.snapshot(block_number)?),
+            (_, snapshot) => Ok(snapshot?),
+        }
+    }
+
+    /// Opens read-only tries over the state right after `block_number`, ie: to generate proofs at
+    /// a past block without reverting the tries.
+    ///
+    /// The view is independent of the engine, which can keep committing blocks while it is read.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `block_number` was not committed, or its version was pruned.
+    pub fn view_at(&self, block_number: u64) -> Result<Option<CommitmentEngine>, TrieError> {
+        if self.latest.map_or(true, |latest| block_number > latest) || block_number < self.horizon {
+            return Ok(None);
+        }
+        match self.snapshot(block_number) {
+            Ok(snapshot) => Ok(Some(self.reopen(Arc::new(OverlayBackend::new(snapshot)))?)),
+            Err(TrieError::Backend(BackendError::NoSnapshot(_))) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Restores the state committed right after `block_number` from its `snapshot`, taken before
+    /// the tries were [reverted](CommitmentEngine::revert_to) or further blocks were committed.
+    pub(crate) fn restore(&mut self, snapshot: &Backend, block_number: u64) -> Result<(), TrieError> {
+        self.discard()?;
+        for column in Column::ALL {
+            for (key, _) in self.backend.scan_prefix(column, &[])? {
+                self.backend.put(column, &key, None)?;
+            }
+            for (key, value) in snapshot.scan_prefix(column, &[])? {
+                self.backend.put(column, &key, Some(&value))?;
+            }
+        }
+        self.horizon = metadata(snapshot, HORIZON)?.unwrap_or_default();
+        self.commit_backend(block_number)?;
+        (self.contract_storage, self.contracts, self.classes) =
+            open_tries(&self.tries_backend(), self.hashers, self.format, &self.writes)?;
+        Ok(())
+    }
+
+    /// Rewrites the storage trie of a contract from its leaves, without changing its root.
+    ///
+    /// Contracts written to in an append-heavy fashion (order books, event-log-like storage) leave
+    /// the nodes of their storage trie scattered over the backend, along with nodes which are no
+    /// longer referenced. The trie is rebuilt in a scratch backend first and only swapped in once it
+    /// was checked to have the same root, then the backend is asked to
+    /// [compact](super::backend::StarkrootBackend::compact) the contract's key range.
+    ///
+    /// The trie logs of the blocks before `block_number` describe the previous layout of the nodes,
+    /// they are deleted along with the rewrite: the tries can no longer be
+    /// [reverted](CommitmentEngine::revert_to) before `block_number`.
+    ///
+    /// This is an offline operation: no block must be committed while it runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `contract_address` - The contract whose storage trie is compacted.
+    /// * `block_number`     - The latest committed block, the compacted trie is committed as its state.
+    pub fn compact_contract(
+        &mut self,
+        contract_address: &ContractAddress,
+        block_number: u64,
+    ) -> Result<CompactionReport, CompactionError> {
+        let identifier = contract_address.0.key().0;
+        let context = || ErrorContext::block(block_number).trie(Trie::ContractStorage).contract(*contract_address);
+        let storage_root = self.storage_root(contract_address).context(context)?;
+        let id = BasicId::new(block_number);
+
+        let scratch: Backend = Arc::new(MemoryBackend::new());
+        let storage_node = self.hashers.storage_node;
+        let mut rebuilt = NodeTrie::new(trie_backend(&scratch, Trie::ContractStorage, self.format), storage_node)?;
+        let keys = self.contract_storage.get_keys(&identifier).context(context)?;
+        let leaves = keys.len();
+        for key in keys {
+            let mut path = BitVec::<u8, Msb0>::from_vec(key);
+            path.truncate(251);
+            let Some(value) = self.contract_storage.get(&identifier, &path).context(context)? else {
+                continue;
+            };
+            rebuilt.insert(&identifier, &path, &value).context(context)?;
+        }
+        rebuilt.commit(id).context(context)?;
+        let computed = rebuilt.root_hash(&identifier).context(context)?;
+        if computed != storage_root {
+            return Err(CompactionError::RootMismatch {
+                contract_address: *contract_address,
+                expected: storage_root,
+                computed,
+            });
+        }
+
+        // The nodes and leaves of a storage trie are keyed by its identifier, the rewrite is staged
+        // and only committed once the trie reopened over it has the same root
+        let column = Column::Trie(Trie::ContractStorage);
+        let prefixes =
+            [DatabaseKey::Trie(&identifier), DatabaseKey::Flat(&identifier)].map(|key| BonsaiBackend::key(&key));
+        let mut entries_before = 0;
+        let mut entries_after = 0;
+        let staged = (|| -> Result<Felt, TrieError> {
+            for prefix in prefixes.iter() {
+                let stale = self.backend.scan_prefix(column, prefix)?;
+                entries_before += stale.len();
+                for (key, _) in stale {
+                    self.backend.put(column, &key, None)?;
+                }
+                let compacted = scratch.scan_prefix(column, prefix)?;
+                entries_after += compacted.len();
+                for (key, value) in compacted {
+                    self.backend.put(column, &key, Some(&value))?;
+                }
+            }
+            self.stage_prune(block_number)?;
+
+            // Bonsai caches the nodes it read, the trie is reopened over the rewritten ones
+            self.contract_storage = NodeTrie::new(self.storage_trie_backend(), storage_node)?;
+            self.storage_root(contract_address)
+        })();
+        let computed = match staged {
+            Ok(computed) => computed,
+            Err(e) => {
+                self.discard()?;
+                return Err(e.with_context(context()).into());
+            }
+        };
+        if computed != storage_root {
+            self.discard()?;
+            return Err(CompactionError::RootMismatch {
+                contract_address: *contract_address,
+                expected: storage_root,
+                computed,
+            });
+        }
+        self.commit_backend(block_number).context(context)?;
+        for prefix in prefixes.iter() {
+            self.backend.compact(column, prefix).context(context)?;
+        }
+
+        Ok(CompactionReport { storage_root, leaves, entries_before, entries_after })
+    }
+
+    /// Deletes the entries left over by the storage tries of contracts whose storage was entirely
+    /// zeroed out, then asks the backend to [compact](super::backend::StarkrootBackend::compact) their
+    /// key ranges.
+    ///
+    /// The contracts must have been empty since before the [horizon](Self::horizon): no version of
+    /// the tries which can still be reverted to references the nodes of their storage tries. Contracts
+    /// whose storage trie is not empty are skipped.
+    ///
+    /// # Returns
+    ///
+    /// The number of entries deleted.
+    pub fn reclaim_empty_storage(&mut self, contract_addresses: &[ContractAddress]) -> Result<usize, TrieError> {
+        let Some(latest) = self.latest else {
+            return Ok(0);
+        };
+        let column = Column::Trie(Trie::ContractStorage);
+        let mut prefixes = Vec::new();
+        let staged = (|| -> Result<usize, TrieError> {
+            let mut entries = 0;
+            for contract_address in contract_addresses {
+                let context = || ErrorContext::block(latest).trie(Trie::ContractStorage).contract(*contract_address);
+                if self.storage_root(contract_address).context(context)? != Felt::ZERO {
+                    continue;
+                }
+                let identifier = contract_address.0.key().0;
+                for key in [DatabaseKey::Trie(&identifier), DatabaseKey::Flat(&identifier)] {
+                    let prefix = BonsaiBackend::key(&key);
+                    for (key, _) in self.backend.scan_prefix(column, &prefix)? {
+                        self.backend.put(column, &key, None)?;
+                        entries += 1;
+                    }
+                    prefixes.push(prefix);
+                }
+            }
+            // Bonsai caches the nodes it read, the trie is reopened without the deleted ones
+            self.contract_storage = NodeTrie::new(self.storage_trie_backend(), self.hashers.storage_node)?;
+            Ok(entries)
+        })();
+        let entries = match staged {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.discard()?;
+                return Err(e);
+            }
+        };
+        if prefixes.is_empty() {
+            return Ok(0);
+        }
+        self.commit_backend(latest)?;
+        for prefix in prefixes.iter() {
+            self.backend.compact(column, prefix)?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Reclaims the storage tries of the contracts which were emptied before the horizon of `engine`,
+/// see [CommitmentEngine::reclaim_empty_storage]. They are no longer tracked by `tries` once
+/// reclaimed.
+pub(crate) fn reclaim_empty_storage(tries: &StateTries, engine: &mut CommitmentEngine) -> Result<usize, TrieError> {
+    let mut empty_storage = empty_storage_tracker(tries);
+    let contract_addresses = empty_storage.reclaimable(engine.horizon(), 0).copied().collect::<Vec<_>>();
+    if contract_addresses.is_empty() {
+        return Ok(0);
+    }
+    let entries = engine.reclaim_empty_storage(&contract_addresses)?;
+    for contract_address in contract_addresses.iter() {
+        empty_storage.forget(contract_address);
+    }
+    Ok(entries)
+}
+
+fn stark_felt(bytes: Vec<u8>) -> StarkFelt {
+    StarkFelt(bytes.try_into().expect("Felts are stored as 32 bytes"))
+}
+
+/// Engines can shadow the [StateTries], ie: to migrate a node to another storage engine.
+impl ShadowBackend for CommitmentEngine {
+    type Error = TrieError;
+
+    fn commit(
+        &mut self,
+        csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, Self::Error> {
+        self.update_state_root(csd, block_number, config)
+    }
+
+    fn rollback(&mut self, block_number: u64) -> Result<(), Self::Error> {
+        if self.latest != Some(block_number) {
+            return self.discard();
+        }
+        let previous_block = block_number.checked_sub(1).ok_or(BackendError::NoSnapshot(block_number))?;
+        self.revert_to(previous_block)
+    }
+}
+
+/// What the commits to a set of [StateTries] build up in memory, which the tries of another chain
+/// do not share.
+#[derive(Default)]
+pub(crate) struct TriesState {
+    pub(crate) registry: Mutex<RootRegistry>,
+    pub(crate) storage_history: Mutex<StorageHistory>,
+    pub(crate) contract_activity: Mutex<ContractActivity>,
+    pub(crate) empty_storage: Mutex<EmptyStorageTracker>,
+    pub(crate) contract_sizes: Mutex<ContractSizes>,
+    pub(crate) quarantine: Mutex<Quarantine>,
+    pub(crate) canary_log: Mutex<CanaryLog>,
+    pub(crate) sla_watchdog: Mutex<SlaWatchdog>,
+    /// Whether the commits are [frozen](super::standby::freeze).
+    pub(crate) frozen: AtomicBool,
+    /// Whether the [WriteHandle](super::handles::WriteHandle) of the tries is held.
+    pub(crate) writer: AtomicBool,
+    pub(crate) capture: Arc<Capture>,
+    pub(crate) execution: RwLock<Execution>,
+}
+
+static NODE_DB_STATE: OnceLock<Arc<TriesState>> = OnceLock::new();
+
+/// Handle on the tries the commit path ([WriteHandle](super::handles::WriteHandle)) updates and the
+/// read paths (proofs, statistics, iteration) read: the tries of the node's database, or those of an
+/// [engine](CommitmentEngine) over a backend of the caller's choosing.
+///
+/// Clones share the same tries, the engine being locked by each call for its duration. The
+/// [root registry](super::roots::root_registry), the indexes, the quarantine, the canary log and the
+/// SLA watchdog of the commits go along with the tries, so that several chains can be committed in
+/// the same process, ie: by the tenants of a [Scheduler](super::scheduler::Scheduler). Every
+/// [node_db](StateTries::node_db) handle shares those of the node's database.
+#[derive(Clone)]
+pub struct StateTries {
+    engine: Option<Arc<Mutex<CommitmentEngine>>>,
+    state: Arc<TriesState>,
+}
+
+impl StateTries {
+    /// The tries of the node's database.
+    pub fn node_db() -> Self {
+        Self { engine: None, state: Arc::clone(NODE_DB_STATE.get_or_init(Default::default)) }
+    }
+
+    /// The tries of a new engine over `backend`, resuming from the blocks it holds.
+    ///
+    /// The trie nodes are stored as on Starknet, see [StateTries::with_config].
+    pub fn open(backend: Backend) -> Result<Self, TrieError> {
+        CommitmentEngine::new(backend).map(Self::from)
+    }
+
+    /// The tries of a new engine over `backend`, whose nodes are hashed and stored as `config` sets,
+    /// see [CommitmentEngine::with_config].
+    pub fn with_config(backend: Backend, config: &ChainConfig) -> Result<Self, TrieError> {
+        CommitmentEngine::with_config(backend, config).map(Self::from)
+    }
+
+    /// Whether the tries are those of the node's database.
+    pub fn is_node_db(&self) -> bool {
+        self.engine.is_none()
+    }
+
+    /// Locks the engine the tries are committed to, `None` for the node's database.
+    pub(crate) fn engine(&self) -> Option<MutexGuard<'_, CommitmentEngine>> {
+        self.engine.as_ref().map(|engine| engine.lock().expect("Poisoned lock on state engine"))
+    }
+
+    pub(crate) fn state(&self) -> &TriesState {
+        &self.state
+    }
+
+    /// Updates the tries on the thread pool of `execution` and keeps their versions for its
+    /// retention.
+    pub(crate) fn configure(&self, execution: Execution) {
+        // The engine prunes the versions which fell out of the retention window as it commits, as
+        // the node's database does, unless they are pruned on finality
+        if let Some(mut engine) = self.engine() {
+            let retention = execution.retention;
+            engine.set_retention(retention.blocks.filter(|_| retention.finality_margin.is_none()));
+        }
+        *self.state.execution.write().expect("Poisoned lock on tries execution") = execution;
+    }
+
+    /// How the commits to the tries run, see [StateTries::configure].
+    pub(crate) fn execution(&self) -> Execution {
+        self.state.execution.read().expect("Poisoned lock on tries execution").clone()
+    }
+}
+
+impl Default for StateTries {
+    fn default() -> Self {
+        Self::node_db()
+    }
+}
+
+impl From<CommitmentEngine> for StateTries {
+    fn from(engine: CommitmentEngine) -> Self {
+        let state = TriesState { capture: Arc::clone(&engine.capture), ..Default::default() };
+        Self { engine: Some(Arc::new(Mutex::new(engine))), state: Arc::new(state) }
+    }
+}
+
+impl fmt::Debug for StateTries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.is_node_db() { "StateTries(node_db)" } else { "StateTries(engine)" })
+    }
+}
+
+/// Makes the tries committed for `block_number` durable, if they are those of an
+/// [engine](CommitmentEngine), pruning the versions which fell out of its
+/// [retention](CommitmentEngine::set_retention).
+///
+/// Otherwise, when `retention.blocks` is set without a `retention.finality_margin` in the
+/// configuration of the tries, the trie logs of the node's database which fell out of the retention
+/// window are deleted. With a finality margin they are pruned once blocks are accepted on L1
+/// instead, see [notify_finality](super::roots::notify_finality).
+pub(crate) fn commit_state_backend(tries: &StateTries, block_number: u64) -> Result<(), TrieError> {
+    if let Some(mut engine) = tries.engine() {
+        engine.commit_block(block_number)?;
+        // The storage tries emptied before the blocks which fell out of the retention window are no
+        // longer referenced. The block is committed already: they are reclaimed on the next one if
+        // this fails.
+        if let Err(_e) = reclaim_empty_storage(tries, &mut engine) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(block_number, error = %_e, "Failed to reclaim the emptied storage tries");
+        }
+        return Ok(());
+    }
+    match tries.execution().retention {
+        RetentionSettings { blocks: Some(retention), finality_margin: None } => {
+            prune_trie_logs((block_number + 1).saturating_sub(retention))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpts::deoxys::backend::StarkrootBackend;
+    use crate::mpts::deoxys::config::StateCommitment;
+    use crate::mpts::deoxys::lib::{clone_commitment_state_diff, try_update_state_root};
+    use crate::mpts::deoxys::proof::ProofError;
+    use crate::mpts::deoxys::quarantine::quarantine;
+    use crate::mpts::deoxys::roots::root_registry;
+
+    fn csd(value: u64) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::TWO));
+        CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::THREE))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(contract_address, [(key, StarkFelt::from(value))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_engines_are_independent() {
+        let config = ChainConfig::default();
+        let base: Backend = Arc::new(MemoryBackend::new());
+        let mut engine = CommitmentEngine::new(Arc::clone(&base)).unwrap();
+        let root_1 = engine.update_state_root(csd(10), 1, &config).unwrap();
+
+        // A speculative commit on top of the same backend does not affect it
+        let mut speculative = CommitmentEngine::new(Arc::new(OverlayBackend::new(Arc::clone(&base)))).unwrap();
+        let speculative_root = speculative.update_state_root(csd(20), 2, &config).unwrap();
+        assert_ne!(speculative_root, root_1);
+        assert_eq!(CommitmentEngine::new(base).unwrap().state_root(&config).unwrap(), root_1);
+
+        assert_eq!(engine.update_state_root(csd(20), 2, &config).unwrap(), speculative_root);
+        engine.revert_to(1).unwrap();
+        assert_eq!(engine.state_root(&config).unwrap(), root_1);
+    }
+
+    /// Backend whose commits fail while `fail` is set.
+    #[derive(Default)]
+    struct FlakyBackend {
+        inner: MemoryBackend,
+        fail: std::sync::atomic::AtomicBool,
+    }
+
+    impl StarkrootBackend for FlakyBackend {
+        fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            self.inner.get(column, key)
+        }
+
+        fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError> {
+            self.inner.put(column, key, value)
+        }
+
+        fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            self.inner.scan_prefix(column, prefix)
+        }
+
+        fn scan_range(
+            &self,
+            column: Column,
+            start: &[u8],
+            end: &[u8],
+        ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            self.inner.scan_range(column, start, end)
+        }
+
+        fn commit(&self, block_number: u64) -> Result<(), BackendError> {
+            if self.fail.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(BackendError::Io("disk full".to_string()));
+            }
+            self.inner.commit(block_number)
+        }
+
+        fn discard(&self) {
+            self.inner.discard()
+        }
+
+        fn snapshot(&self, block_number: u64) -> Result<Backend, BackendError> {
+            self.inner.snapshot(block_number)
+        }
+    }
+
+    #[test]
+    fn test_commit_failure() {
+        let config = ChainConfig::default();
+        let backend = Arc::new(FlakyBackend::default());
+        let mut engine = CommitmentEngine::new(backend.clone()).unwrap();
+        let root_1 = engine.update_state_root(csd(10), 1, &config).unwrap();
+
+        backend.fail.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(engine.update_state_root(csd(20), 2, &config).is_err());
+        assert_eq!(engine.latest(), Some(1));
+        assert_eq!(engine.state_root(&config).unwrap(), root_1);
+
+        // The block is applied from scratch once the backend recovers
+        backend.fail.store(false, std::sync::atomic::Ordering::SeqCst);
+        let root_2 = engine.update_state_root(csd(20), 2, &config).unwrap();
+        assert_ne!(root_2, root_1);
+
+        // The latest block is persisted along with the tries
+        let reopened = CommitmentEngine::new(backend).unwrap();
+        assert_eq!(reopened.latest(), Some(2));
+        assert_eq!(reopened.state_root(&config).unwrap(), root_2);
+    }
+
+    /// Backend whose nodes and leaves keyed by a contract cannot be read, as if they were corrupted.
+    struct CorruptedBackend {
+        inner: MemoryBackend,
+        contract_address: [u8; 32],
+    }
+
+    impl CorruptedBackend {
+        fn new(contract_address: ContractAddress) -> Self {
+            Self { inner: MemoryBackend::new(), contract_address: contract_address.0.key().0 }
+        }
+    }
+
+    impl StarkrootBackend for CorruptedBackend {
+        fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            if key.windows(32).any(|window| window == self.contract_address) {
+                return Err(BackendError::Io("corrupted node".to_string()));
+            }
+            self.inner.get(column, key)
+        }
+
+        fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError> {
+            self.inner.put(column, key, value)
+        }
+
+        fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            self.inner.scan_prefix(column, prefix)
+        }
+
+        fn scan_range(
+            &self,
+            column: Column,
+            start: &[u8],
+            end: &[u8],
+        ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            self.inner.scan_range(column, start, end)
+        }
+
+        fn commit(&self, block_number: u64) -> Result<(), BackendError> {
+            self.inner.commit(block_number)
+        }
+
+        fn discard(&self) {
+            self.inner.discard()
+        }
+
+        fn snapshot(&self, block_number: u64) -> Result<Backend, BackendError> {
+            self.inner.snapshot(block_number)
+        }
+    }
+
+    #[test]
+    fn test_quarantine_on_engine() {
+        let (healthy, corrupted) =
+            (ContractAddress(PatriciaKey(StarkFelt::ONE)), ContractAddress(PatriciaKey(StarkFelt::from(0xbad_u64))));
+        let mut diff = csd(20);
+        diff.storage_updates.insert(corrupted, [(StorageKey(PatriciaKey(StarkFelt::TWO)), StarkFelt::ONE)].into());
+        diff.address_to_nonce.insert(corrupted, Nonce(StarkFelt::ONE));
+
+        // The contract which fails to update fails the block in strict mode
+        let strict = StateTries::open(Arc::new(CorruptedBackend::new(corrupted))).unwrap();
+        try_update_state_root(&strict, csd(10), 1, &ChainConfig::default()).unwrap();
+        assert!(try_update_state_root(&strict, clone_commitment_state_diff(&diff), 2, &ChainConfig::default()).is_err());
+        assert!(!quarantine(&strict).contains(&corrupted));
+
+        // Otherwise it is skipped, and the others are committed under a dirty root
+        let config = ChainConfig { failure_mode: FailureMode::Quarantine, ..Default::default() };
+        let tries = StateTries::open(Arc::new(CorruptedBackend::new(corrupted))).unwrap();
+        try_update_state_root(&tries, csd(10), 1, &config).unwrap();
+        let state_root = try_update_state_root(&tries, clone_commitment_state_diff(&diff), 2, &config).unwrap();
+        let skipped = quarantine(&tries).get(&corrupted).cloned().unwrap();
+        assert_eq!(skipped.since, 2);
+        assert_eq!(skipped.skipped[&2].nonce, Some(Nonce(StarkFelt::ONE)));
+        assert!(root_registry(&tries).get(2).unwrap().dirty);
+        assert!(quarantine(&tries).get(&healthy).is_none());
+
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        engine.update_state_root(csd(10), 1, &config).unwrap();
+        assert_eq!(engine.update_state_root(csd(20), 2, &config).unwrap(), state_root);
+    }
+
+    #[test]
+    fn test_legacy_state_commitment() {
+        let (legacy, v0) = (ChainConfig::for_protocol_version("0.10.3"), ChainConfig::for_protocol_version("0.11.0"));
+        assert_eq!(legacy.state_commitment, StateCommitment::Legacy);
+        assert_eq!(v0, ChainConfig::default());
+
+        // Both agree as long as the classes trie is empty, which it always is before v0.11.0
+        let mut legacy_engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let mut v0_engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        assert_eq!(
+            legacy_engine.update_state_root(csd(10), 0, &legacy).unwrap(),
+            v0_engine.update_state_root(csd(10), 0, &v0).unwrap()
+        );
+
+        let mut declare = csd(20);
+        declare.class_hash_to_compiled_class_hash =
+            [(ClassHash(StarkFelt::THREE), CompiledClassHash(StarkFelt::ONE))].into_iter().collect();
+        assert_ne!(
+            legacy_engine.update_state_root(clone_commitment_state_diff(&declare), 1, &legacy).unwrap(),
+            v0_engine.update_state_root(declare, 1, &v0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_prune_versions() {
+        let config = ChainConfig::default();
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let mut roots = Vec::new();
+        for block_number in 0..8 {
+            roots.push(engine.update_state_root(csd(block_number), block_number, &config).unwrap());
+        }
+
+        let report = engine.prune_before(4).unwrap();
+        assert_eq!(report.horizon, 4);
+        assert!(report.trie_log_entries > 0);
+        assert_eq!(engine.prune_before(2).unwrap().trie_log_entries, 0);
+        assert!(engine.revert_to(3).is_err());
+        assert_eq!(engine.state_root(&config).unwrap(), roots[7]);
+
+        // The retained blocks can still be reverted to
+        engine.set_retention(Some(2));
+        engine.update_state_root(csd(8), 8, &config).unwrap();
+        assert!(engine.revert_to(6).is_err());
+        engine.revert_to(7).unwrap();
+        assert_eq!(engine.state_root(&config).unwrap(), roots[7]);
+    }
+
+    #[test]
+    fn test_compact_contract() {
+        let config = ChainConfig::default();
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        for block_number in 0..8 {
+            engine.update_state_root(csd(block_number), block_number, &config).unwrap();
+        }
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let state_root = engine.state_root(&config).unwrap();
+
+        let report = engine.compact_contract(&contract_address, 7).unwrap();
+        assert_eq!(report.leaves, 1);
+        assert_eq!(report.storage_root, engine.storage_root(&contract_address).unwrap());
+        assert_eq!(engine.state_root(&config).unwrap(), state_root);
+        assert_eq!(
+            engine.storage_value(&contract_address, &StorageKey(PatriciaKey(StarkFelt::TWO))).unwrap(),
+            StarkFelt::from(7_u64)
+        );
+
+        // The trie logs of the previous layout are gone
+        assert_eq!(engine.horizon(), 7);
+        assert!(engine.revert_to(6).is_err());
+        engine.update_state_root(csd(8), 8, &config).unwrap();
+        engine.revert_to(7).unwrap();
+        assert_eq!(engine.state_root(&config).unwrap(), state_root);
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let config = ChainConfig::default();
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let seed = StateSeed {
+            storage: vec![(contract_address, StorageKey(PatriciaKey(StarkFelt::TWO)), StarkFelt::from(10_u64))],
+            class_hashes: vec![(contract_address, ClassHash(StarkFelt::THREE))],
+            ..Default::default()
+        };
+        let (mut engine, seed_root) = CommitmentEngine::in_memory(seed, 1, &config).unwrap();
+
+        // Seeding is the same as committing the seed as a state diff
+        let mut persistent = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        assert_eq!(persistent.update_state_root(csd(10), 1, &config).unwrap(), seed_root);
+        assert_eq!(
+            engine.update_state_root(csd(20), 2, &config).unwrap(),
+            persistent.update_state_root(csd(20), 2, &config).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_storage_proof() {
+        let config = ChainConfig::default();
+        let (mut engine, _) = CommitmentEngine::in_memory(StateSeed::default(), 0, &config).unwrap();
+        let state_root = engine.update_state_root(csd(10), 1, &config).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let keys = [StorageKey(PatriciaKey(StarkFelt::TWO)), StorageKey(PatriciaKey(StarkFelt::THREE))];
+        let proof = engine.storage_proof(&contract_address, &keys, &config).unwrap();
+        assert_eq!(proof.state_commitment, Felt::from(state_root));
+        assert_eq!(proof.verify(state_root.into(), &keys, &config).unwrap(), vec![Felt::from(10_u64), Felt::ZERO]);
+        assert!(matches!(
+            proof.verify(Felt::ONE, &keys, &config),
+            Err(ProofError::StateRootMismatch { expected, .. }) if expected == Felt::ONE
+        ));
+    }
+
+    #[test]
+    fn test_storage_proof_at_past_block() {
+        let config = ChainConfig::default();
+        let (mut engine, _) = CommitmentEngine::in_memory(StateSeed::default(), 0, &config).unwrap();
+        let past_root = engine.update_state_root(csd(10), 1, &config).unwrap();
+        engine.update_state_root(csd(20), 2, &config).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let keys = [StorageKey(PatriciaKey(StarkFelt::TWO))];
+        let view = engine.view_at(1).unwrap().unwrap();
+        let proof = view.storage_proof(&contract_address, &keys, &config).unwrap();
+        assert_eq!(proof.block_number, 1);
+        assert_eq!(proof.verify(past_root.into(), &keys, &config).unwrap(), vec![Felt::from(10_u64)]);
+
+        // The view does not hold the engine back
+        engine.update_state_root(csd(30), 3, &config).unwrap();
+        assert_eq!(view.storage_proof(&contract_address, &keys, &config).unwrap(), proof);
+        assert!(engine.view_at(4).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_node_hashes() {
+        let starknet = ChainConfig::default();
+        let hashers = TrieHashers {
+            storage_node: NodeHash::Poseidon,
+            contracts_node: NodeHash::Poseidon,
+            classes_node: NodeHash::Pedersen,
+            ..starknet.hashers
+        };
+        let config = ChainConfig { hashers, ..Default::default() };
+        assert!(!config.hashers.node_db_compatible());
+
+        let (mut engine, _) = CommitmentEngine::in_memory(StateSeed::default(), 0, &config).unwrap();
+        assert_eq!(engine.hashers(), hashers);
+        let state_root = engine.update_state_root(csd(10), 1, &config).unwrap();
+        let (mut reference, _) = CommitmentEngine::in_memory(StateSeed::default(), 0, &starknet).unwrap();
+        assert_ne!(reference.update_state_root(csd(10), 1, &starknet).unwrap(), state_root);
+
+        // The proofs only verify with the node hashes the tries were hashed with
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let keys = [StorageKey(PatriciaKey(StarkFelt::TWO))];
+        let proof = engine.storage_proof(&contract_address, &keys, &config).unwrap();
+        assert_eq!(proof.verify(state_root.into(), &keys, &config).unwrap(), vec![Felt::from(10_u64)]);
+        assert!(proof.verify(state_root.into(), &keys, &starknet).is_err());
+
+        // Views and reverts keep hashing the nodes as the engine does
+        let root_2 = engine.update_state_root(csd(20), 2, &config).unwrap();
+        assert_eq!(engine.view_at(1).unwrap().unwrap().state_root(&config).unwrap(), state_root);
+        engine.revert_to(1).unwrap();
+        assert_eq!(engine.update_state_root(csd(20), 2, &config).unwrap(), root_2);
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+
+use mc_db::storage_handler::DeoxysStorageError;
+use mp_felt::Felt252Wrapper;
+use starknet_api::core::{ClassHash, ContractAddress};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+
+use super::atomic::Trie;
+use super::backend::BackendError;
+#[cfg(feature = "class-verification")]
+use super::class_verification::ClassVerificationError;
+use super::conversions::{ConversionError, DiffEntry};
+use super::duplicates::DuplicateEntry;
+use super::roots::FencingToken;
+
+/// Errors that can occur while updating the state tries.
+#[derive(Debug, thiserror::Error)]
+pub enum TrieError {
+    #[error(transparent)]
+    Conversion(#[from] ConversionError),
+    #[error(transparent)]
+    Storage(#[from] DeoxysStorageError),
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error("the tries of the node's database cannot be hashed with the configured node hashes")]
+    NodeHash,
+    #[error("{source} ({context})")]
+    WithContext { context: ErrorContext, source: Box<TrieError> },
+}
+
+impl TrieError {
+    /// Returns the provenance of the error, if known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            TrieError::WithContext { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Attaches `context` to the error, merging it with the context already attached if any.
+    pub fn with_context(self, context: ErrorContext) -> Self {
+        match self {
+            TrieError::WithContext { context: mut inner, source } => {
+                inner.merge(context);
+                TrieError::WithContext { context: inner, source }
+            }
+            e => TrieError::WithContext { context, source: Box::new(e) },
+        }
+    }
+}
+
+/// Provenance of an error: where in the commit it occurred.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub block_number: Option<u64>,
+    pub trie: Option<Trie>,
+    pub contract_address: Option<ContractAddress>,
+    pub key: Option<StorageKey>,
+    pub class_hash: Option<ClassHash>,
+}
+
+impl ErrorContext {
+    pub fn block(block_number: u64) -> Self {
+        Self { block_number: Some(block_number), ..Default::default() }
+    }
+
+    pub fn trie(mut self, trie: Trie) -> Self {
+        self.trie = Some(trie);
+        self
+    }
+
+    pub fn contract(mut self, contract_address: ContractAddress) -> Self {
+        self.contract_address = Some(contract_address);
+        self
+    }
+
+    pub fn key(mut self, key: StorageKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn class_hash(mut self, class_hash: ClassHash) -> Self {
+        self.class_hash = Some(class_hash);
+        self
+    }
+
+    /// Fills the fields which are unknown in `self` from `outer`.
+    fn merge(&mut self, outer: ErrorContext) {
+        self.block_number = self.block_number.or(outer.block_number);
+        self.trie = self.trie.or(outer.trie);
+        self.contract_address = self.contract_address.or(outer.contract_address);
+        self.key = self.key.or(outer.key);
+        self.class_hash = self.class_hash.or(outer.class_hash);
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let felt = |felt: &StarkFelt| Felt::from_bytes_be(&felt.0);
+        let mut fields = Vec::new();
+        if let Some(block_number) = self.block_number {
+            fields.push(format!("block {block_number}"));
+        }
+        if let Some(trie) = self.trie {
+            fields.push(format!("{trie:?} trie"));
+        }
+        if let Some(contract_address) = self.contract_address {
+            fields.push(format!("contract {:#x}", felt(contract_address.0.key())));
+        }
+        if let Some(key) = self.key {
+            fields.push(format!("key {:#x}", felt(key.0.key())));
+        }
+        if let Some(class_hash) = self.class_hash {
+            fields.push(format!("class {:#x}", felt(&class_hash.0)));
+        }
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+/// Attaches an [ErrorContext] to the error of a result.
+///
+/// Contexts attached at different levels are merged into a single one, the innermost (most
+/// specific) fields taking precedence.
+pub trait ResultExt<T> {
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T, TrieError>;
+}
+
+impl<T, E: Into<TrieError>> ResultExt<T> for Result<T, E> {
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T, TrieError> {
+        self.map_err(|e| e.into().with_context(context()))
+    }
+}
+
+/// Errors that can occur while building a commitment state diff from a state update.
+#[derive(Debug, thiserror::Error)]
+pub enum DiffError {
+    #[error(transparent)]
+    Conversion(#[from] ConversionError),
+    #[error("duplicate {0} in state update")]
+    Duplicate(DuplicateEntry),
+    #[error("invalid {entry}: {reason}")]
+    InvalidEntry { entry: DiffEntry, reason: ConversionError },
+    #[error("{entry} assigns the zero class hash to contract {contract_address:#x}, which is not a system contract")]
+    ZeroClassHash { entry: DiffEntry, contract_address: FieldElement },
+}
+
+impl From<DuplicateEntry> for DiffError {
+    fn from(entry: DuplicateEntry) -> Self {
+        DiffError::Duplicate(entry)
+    }
+}
+
+/// Errors that can occur while committing a block.
+#[derive(Debug, thiserror::Error)]
+pub enum CommitError {
+    #[error(
+        "block {block_number} was already committed with a different state diff (stored {stored:?}, received \
+         {received:?})"
+    )]
+    Conflict { block_number: u64, stored: Felt252Wrapper, received: Felt252Wrapper },
+    #[error("commit fenced off: token {token:?} is older than current leader token {current:?}")]
+    Fenced { token: FencingToken, current: FencingToken },
+    #[error("commit fenced off: it carries no token while the current leader token is {current:?}")]
+    Unfenced { current: FencingToken },
+    #[error(
+        "block {block_number} failed to commit ({cause}) and the tries could not be rolled back, they must be \
+         restored from a snapshot"
+    )]
+    Rollback { block_number: u64, cause: TrieError, rollback: Option<TrieError> },
+    #[error("block {block_number} was not committed: commits are frozen for a failover")]
+    Frozen { block_number: u64 },
+    #[error("batch of blocks {first_block} to {last_block} holds {diffs} state diffs")]
+    BatchRange { first_block: u64, last_block: u64, diffs: usize },
+    #[cfg(feature = "class-verification")]
+    #[error("block {block_number} declares an inconsistent class: {error}")]
+    ClassVerification { block_number: u64, error: ClassVerificationError },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+/// Items committed to by the transaction and event commitments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitmentItem {
+    Transaction,
+    Event,
+    Receipt,
+}
+
+impl fmt::Display for CommitmentItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitmentItem::Transaction => write!(f, "transaction"),
+            CommitmentItem::Event => write!(f, "event"),
+            CommitmentItem::Receipt => write!(f, "receipt"),
+        }
+    }
+}
+
+/// Errors that can occur while computing the transaction and event commitments of a block.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CommitmentError {
+    #[error("failed to hash {item} {index}: {reason}")]
+    Hash { item: CommitmentItem, index: usize, reason: String },
+    #[error("{item} {index} holds {value:?}, which is not a valid felt")]
+    Conversion { item: CommitmentItem, index: usize, value: StarkFelt },
+    #[error("{item} commitment trie failed: {reason}")]
+    Backend { item: CommitmentItem, reason: String },
+    #[error("the block header declares {declared} {item}s, {actual} were provided")]
+    CountMismatch { item: CommitmentItem, declared: u64, actual: usize },
+}
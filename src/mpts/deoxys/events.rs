@@ -1,7 +1,3 @@
-use bitvec::vec::BitVec;
-use bonsai_trie::databases::HashMapDb;
-use bonsai_trie::id::{BasicId, BasicIdBuilder};
-use bonsai_trie::{BonsaiStorage, BonsaiStorageConfig};
 use mc_db::storage_handler::bonsai_identifier;
 use mp_felt::Felt252Wrapper;
 use mp_hashers::pedersen::PedersenHasher;
@@ -10,7 +6,11 @@ use rayon::prelude::*;
 use starknet_api::transaction::Event;
 use starknet_ff::FieldElement;
 use starknet_types_core::felt::Felt;
-use starknet_types_core::hash::Pedersen;
+use starknet_types_core::hash::{Pedersen, Poseidon, StarkHash};
+
+use super::config::CommitmentScheme;
+use super::error::{CommitmentError, CommitmentItem};
+use super::transactions::{memory_trie_root, IndexedTrie};
 
 /// Calculate the hash of the event.
 ///
@@ -60,38 +60,197 @@ pub fn calculate_event_hash<H: HasherT>(event: &Event) -> FieldElement {
 ///
 /// The event commitment as `Felt252Wrapper`.
 pub fn memory_event_commitment(events: &[Event]) -> Result<Felt252Wrapper, String> {
-    // TODO @cchudant refacto/optimise this function
+    try_memory_event_commitment(events).map_err(|e| e.to_string())
+}
+
+/// Checks that every value of an event is a valid felt, since hashing it would panic otherwise.
+fn validate_event(index: usize, event: &Event) -> Result<(), CommitmentError> {
+    let values = event.content.keys.iter().map(|key| &key.0).chain(event.content.data.0.iter());
+    for value in std::iter::once(event.from_address.0.key()).chain(values) {
+        if FieldElement::from_bytes_be(&value.0).is_err() {
+            return Err(CommitmentError::Conversion { item: CommitmentItem::Event, index, value: *value });
+        }
+    }
+    Ok(())
+}
+
+/// Calculate the event commitment in memory, see [memory_event_commitment].
+///
+/// # Arguments
+///
+/// * `events` - The events of the block
+///
+/// # Returns
+///
+/// The event commitment as `Felt252Wrapper`, or the first event which could not be committed to.
+pub fn try_memory_event_commitment(events: &[Event]) -> Result<Felt252Wrapper, CommitmentError> {
+    try_memory_event_commitment_with_scheme(events, &[], CommitmentScheme::Pedersen)
+}
+
+/// Calculate the hash of an event, since Starknet v0.13.2.
+///
+/// # Arguments
+///
+/// * `event` - The event we want to calculate the hash of.
+/// * `transaction_hash` - The hash of the transaction which emitted the event.
+///
+/// # Returns
+///
+/// `Poseidon(from_address, transaction_hash, keys_len, keys..., data_len, data...)`.
+pub fn calculate_event_hash_poseidon(event: &Event, transaction_hash: Felt) -> Felt {
+    let keys = &event.content.keys;
+    let data = &event.content.data.0;
+
+    let mut elements = vec![Felt::from_bytes_be(&event.from_address.0.key().0), transaction_hash];
+    elements.push(Felt::from(keys.len() as u64));
+    elements.extend(keys.iter().map(|key| Felt::from_bytes_be(&key.0.0)));
+    elements.push(Felt::from(data.len() as u64));
+    elements.extend(data.iter().map(|value| Felt::from_bytes_be(&value.0)));
+    Poseidon::hash_array(&elements)
+}
+
+/// Calculate the event commitment in memory following the scheme of the block's protocol version,
+/// see [try_memory_event_commitment].
+///
+/// # Arguments
+///
+/// * `events` - The events of the block
+/// * `transaction_hashes` - The hash of the transaction which emitted each event, only used by
+///   [CommitmentScheme::Poseidon]
+/// * `scheme` - The commitment scheme of the block, see [CommitmentScheme::for_protocol_version]
+///
+/// # Returns
+///
+/// The event commitment as `Felt252Wrapper`, or the first event which could not be committed to.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(events = events.len(), scheme = ?scheme))
+)]
+pub fn try_memory_event_commitment_with_scheme(
+    events: &[Event],
+    transaction_hashes: &[Felt],
+    scheme: CommitmentScheme,
+) -> Result<Felt252Wrapper, CommitmentError> {
     if events.is_empty() {
         return Ok(Felt252Wrapper::ZERO);
     }
 
-    let config = BonsaiStorageConfig::default();
-    let bonsai_db = HashMapDb::<BasicId>::default();
-    let mut bonsai_storage =
-        BonsaiStorage::<_, _, Pedersen>::new(bonsai_db, config).expect("Failed to create bonsai storage");
+    // event hashes are computed in parallel
+    let events = events
+        .par_iter()
+        .enumerate()
+        .map(|(index, event)| {
+            validate_event(index, event)?;
+            match scheme {
+                CommitmentScheme::Pedersen => {
+                    Ok(Felt::from(Felt252Wrapper::from(calculate_event_hash::<PedersenHasher>(event))))
+                }
+                CommitmentScheme::Poseidon => {
+                    let transaction_hash = transaction_hashes.get(index).ok_or_else(|| CommitmentError::Hash {
+                        item: CommitmentItem::Event,
+                        index,
+                        reason: "missing the hash of the emitting transaction".to_string(),
+                    })?;
+                    Ok(calculate_event_hash_poseidon(event, *transaction_hash))
+                }
+            }
+        })
+        .collect::<Result<Vec<_>, CommitmentError>>()?;
+
     let identifier = bonsai_identifier::EVENT;
+    match scheme {
+        CommitmentScheme::Pedersen => memory_trie_root::<Pedersen>(identifier, events, CommitmentItem::Event),
+        CommitmentScheme::Poseidon => memory_trie_root::<Poseidon>(identifier, events, CommitmentItem::Event),
+    }
+}
 
-    // event hashes are computed in parallel
-    let events = events.par_iter().map(calculate_event_hash::<PedersenHasher>).collect::<Vec<_>>();
+/// Builds the event commitment of a block from events pushed one at a time, ie: while the
+/// transactions of the block are executed.
+///
+/// Each event is hashed and inserted in the commitment trie as it is pushed, so that events do not
+/// have to be buffered until the end of the block. The commitment is the same as
+/// [try_memory_event_commitment_with_scheme] over the pushed events, in the same order.
+pub struct EventCommitmentBuilder {
+    scheme: CommitmentScheme,
+    trie: IndexedTrie,
+}
 
-    // once event hashes have finished computing, they are inserted into the local Bonsai db
-    for (i, event_hash) in events.into_iter().enumerate() {
-        let key = BitVec::from_vec(i.to_be_bytes().to_vec());
-        let value = Felt::from(Felt252Wrapper::from(event_hash));
-        bonsai_storage.insert(identifier, key.as_bitslice(), &value).expect("Failed to insert into bonsai storage");
+impl EventCommitmentBuilder {
+    pub fn new(scheme: CommitmentScheme) -> Result<Self, CommitmentError> {
+        let trie = IndexedTrie::new(bonsai_identifier::EVENT, CommitmentItem::Event, scheme)?;
+        Ok(Self { scheme, trie })
     }
 
-    // Note that committing changes still has the greatest performance hit
-    // as this is where the root hash is calculated. Due to the Merkle structure
-    // of Bonsai Tries, this results in a trie size that grows very rapidly with
-    // each new insertion. It seems that the only vector of optimization here
-    // would be to optimize the tree traversal and hash computation.
-    let mut id_builder = BasicIdBuilder::new();
-    let id = id_builder.new_id();
+    /// Number of events pushed so far.
+    pub fn len(&self) -> usize {
+        self.trie.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-    // run in a blocking-safe thread to avoid starving the thread pool
-    bonsai_storage.commit(id).expect("Failed to commit to bonsai storage");
-    let root_hash = bonsai_storage.root_hash(identifier).expect("Failed to get root hash");
+    /// Folds the next event of the block into the commitment.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event, in block order.
+    /// * `transaction_hash` - The hash of the transaction which emitted the event, only used by
+    ///   [CommitmentScheme::Poseidon]
+    pub fn push(&mut self, event: &Event, transaction_hash: Felt) -> Result<(), CommitmentError> {
+        validate_event(self.len(), event)?;
+        let event_hash = match self.scheme {
+            CommitmentScheme::Pedersen => {
+                Felt::from(Felt252Wrapper::from(calculate_event_hash::<PedersenHasher>(event)))
+            }
+            CommitmentScheme::Poseidon => calculate_event_hash_poseidon(event, transaction_hash),
+        };
+        self.trie.push(event_hash)
+    }
+
+    /// Computes the event commitment of the events pushed.
+    pub fn finalize(self) -> Result<Felt252Wrapper, CommitmentError> {
+        if self.is_empty() {
+            return Ok(Felt252Wrapper::ZERO);
+        }
+        self.trie.root()
+    }
+}
 
-    Ok(Felt252Wrapper::from(root_hash))
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::{ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::transaction::{EventContent, EventData, EventKey};
+
+    use super::*;
+
+    fn event(n: u64) -> Event {
+        Event {
+            from_address: ContractAddress(PatriciaKey(StarkFelt::from(n))),
+            content: EventContent {
+                keys: vec![EventKey(StarkFelt::from(n + 1))],
+                data: EventData(vec![StarkFelt::from(n + 2), StarkFelt::from(n + 3)]),
+            },
+        }
+    }
+
+    #[test]
+    fn test_event_commitment_builder() {
+        let events = (0..5).map(event).collect::<Vec<_>>();
+        let transaction_hashes = (0..5_u64).map(|n| Felt::from(n / 2)).collect::<Vec<_>>();
+
+        for scheme in [CommitmentScheme::Pedersen, CommitmentScheme::Poseidon] {
+            let mut builder = EventCommitmentBuilder::new(scheme).unwrap();
+            for (event, transaction_hash) in events.iter().zip(&transaction_hashes) {
+                builder.push(event, *transaction_hash).unwrap();
+            }
+            assert_eq!(
+                builder.finalize().unwrap(),
+                try_memory_event_commitment_with_scheme(&events, &transaction_hashes, scheme).unwrap()
+            );
+        }
+        let builder = EventCommitmentBuilder::new(CommitmentScheme::Pedersen).unwrap();
+        assert_eq!(builder.finalize().unwrap(), Felt252Wrapper::ZERO);
+    }
 }
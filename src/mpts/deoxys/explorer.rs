@@ -0,0 +1,161 @@
+use std::io;
+use std::net::ToSocketAddrs;
+use std::thread::{self, JoinHandle};
+
+use mc_db::storage_handler::{self, StorageView};
+use serde_json::{json, Value};
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use super::conversions::{try_contract_address, try_storage_key};
+use super::engine::StateTries;
+use super::proof::{from_bonsai, path_to_felt, ProofNode};
+use super::roots::root_registry;
+use super::runtime::current_config;
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>starkroot explorer</title></head>
+<body style="font-family: monospace">
+<h1>starkroot</h1>
+<h2>Recent blocks</h2>
+<pre id="blocks">loading...</pre>
+<h2>Storage value</h2>
+<form id="storage">
+  contract <input name="contract" size="70" placeholder="0x...">
+  key <input name="key" size="70" placeholder="0x...">
+  <button>fetch</button>
+</form>
+<pre id="value"></pre>
+<script>
+const show = (id) => (r) => r.json().then((json) => {
+  document.getElementById(id).textContent = JSON.stringify(json, null, 2);
+});
+fetch("/api/blocks").then(show("blocks"));
+document.getElementById("storage").onsubmit = (e) => {
+  e.preventDefault();
+  const query = new URLSearchParams(new FormData(e.target));
+  fetch("/api/storage?" + query).then(show("value"));
+};
+</script>
+</body>
+</html>
+"#;
+
+fn proof_json(proof: Vec<bonsai_trie::ProofNode>) -> Vec<Value> {
+    proof
+        .into_iter()
+        .map(|node| match from_bonsai(node) {
+            ProofNode::Binary { left, right } => {
+                json!({ "binary": { "left": format!("{left:#x}"), "right": format!("{right:#x}") } })
+            }
+            ProofNode::Edge { child, path } => json!({
+                "edge": {
+                    "child": format!("{child:#x}"),
+                    "path": format!("{:#x}", path_to_felt(&path)),
+                    "length": path.len(),
+                }
+            }),
+        })
+        .collect()
+}
+
+fn blocks() -> Value {
+    let recent_blocks = current_config().queries.recent_blocks;
+    let tries = StateTries::node_db();
+    let registry = root_registry(&tries);
+    let blocks = registry
+        .recent(recent_blocks)
+        .map(|(block_number, block)| {
+            json!({
+                "block_number": block_number,
+                "state_root": format!("{:#x}", block.state_root.0),
+                "diff_hash": format!("{:#x}", block.diff_hash.0),
+                "finality": format!("{:?}", block.finality),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "latest": registry.latest().map(|(block_number, _)| block_number),
+        "version": registry.version(),
+        "blocks": blocks,
+    })
+}
+
+fn storage(query: &str) -> Result<Value, String> {
+    let param = |name: &str| {
+        let value = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find_map(|(key, value)| (key == name).then_some(value))
+            .ok_or(format!("missing `{name}` parameter"))?;
+        FieldElement::from_hex_be(value).map_err(|e| format!("invalid `{name}` parameter: {e}"))
+    };
+    let contract_address = try_contract_address(&param("contract")?).map_err(|e| e.to_string())?;
+    let key = try_storage_key(&param("key")?).map_err(|e| e.to_string())?;
+
+    let value = storage_handler::contract_storage().get(&(contract_address, key)).map_err(|e| e.to_string())?;
+    let storage_proof = storage_handler::contract_storage_trie()
+        .get_proof(&contract_address, &key)
+        .map_err(|e| e.to_string())?;
+    let contract_proof = storage_handler::contract_trie().get_proof(&contract_address).map_err(|e| e.to_string())?;
+
+    Ok(json!({
+        "value": value.map(|value| format!("{:#x}", Felt::from_bytes_be(&value.0))),
+        "storage_proof": proof_json(storage_proof),
+        "contract_proof": proof_json(contract_proof),
+    }))
+}
+
+fn respond(request: Request) -> io::Result<()> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+
+    let json = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let html = Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap();
+
+    let response = match (request.method(), path) {
+        (Method::Get, "/") => Response::from_string(INDEX).with_header(html),
+        (Method::Get, "/api/blocks") => Response::from_string(blocks().to_string()).with_header(json),
+        (Method::Get, "/api/storage") => match storage(query) {
+            Ok(value) => Response::from_string(value.to_string()).with_header(json),
+            Err(error) => {
+                Response::from_string(json!({ "error": error }).to_string()).with_header(json).with_status_code(400)
+            }
+        },
+        _ => Response::from_string("not found").with_status_code(404),
+    };
+
+    request.respond(response)
+}
+
+/// Serves a lightweight read-only explorer over the commitment data of the node's database.
+///
+/// It lists the latest committed roots and lets operators fetch a storage value along with its
+/// proofs. It is meant for debugging and must not be exposed publicly.
+///
+/// ```text
+/// GET /                                     html page
+/// GET /api/blocks                           recent state roots
+/// GET /api/storage?contract=0x..&key=0x..   storage value, storage and contract proofs
+/// ```
+///
+/// # Arguments
+///
+/// * `addr` - The address to listen on.
+///
+/// # Returns
+///
+/// The handle of the thread serving requests.
+pub fn serve_explorer(addr: impl ToSocketAddrs) -> io::Result<JoinHandle<()>> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+
+    Ok(thread::spawn(move || {
+        for request in server.incoming_requests() {
+            // a client hanging up must not bring the explorer down
+            let _ = respond(request);
+        }
+    }))
+}
@@ -0,0 +1,312 @@
+//! Export of the commitment results of each block to a message bus, for data platforms.
+//!
+//! Each committed block produces its [BlockFacts]: its state root, its state diff commitment, the
+//! leaf counts of the tries and a summary of each contract it changed. The facts are handed to the
+//! [subscribers](subscribe_facts) once the block is committed, and [forward_facts] publishes them
+//! with a [FactPublisher], ie: to Kafka or NATS with the `kafka` and `nats` features. Publishing runs
+//! on the subscriber's thread: a slow or unavailable bus never delays the commits.
+//!
+//! Only the commits made through the [StateTries](super::engine::StateTries) API are exported.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use indexmap::IndexSet;
+use mp_felt::Felt252Wrapper;
+use serde_json::{json, Value};
+use starknet_api::core::{ClassHash, ContractAddress, Nonce};
+use starknet_api::hash::StarkFelt;
+use starknet_types_core::felt::Felt;
+
+use super::state_diff::calculate_state_diff_commitment;
+use super::stats::{CommitStats, LeafCounts};
+
+/// What a block changed of a contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractChange {
+    pub contract_address: ContractAddress,
+    /// Number of storage slots written.
+    pub storage_writes: usize,
+    /// The class the contract was deployed with or upgraded to.
+    pub class_hash: Option<ClassHash>,
+    pub nonce: Option<Nonce>,
+}
+
+/// The commitment results of a committed block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFacts {
+    pub block_number: u64,
+    pub state_root: Felt252Wrapper,
+    /// Commitment of the block's state diff, without the Cairo 0 classes it declared which the
+    /// commitment state diff does not hold.
+    pub state_diff_commitment: Felt252Wrapper,
+    pub stats: CommitStats,
+    /// Number of classes declared.
+    pub declared_classes: usize,
+    /// The contracts changed by the block, in the order of the state diff.
+    pub changed_contracts: Vec<ContractChange>,
+}
+
+fn felt_json(felt: impl Into<Felt>) -> Value {
+    json!(format!("{:#x}", felt.into()))
+}
+
+fn counts_json(counts: &LeafCounts) -> Value {
+    json!({ "new": counts.new, "updated": counts.updated, "deleted": counts.deleted })
+}
+
+impl BlockFacts {
+    /// Gathers the facts of a block from its state diff and the results of its commit.
+    pub fn new(block_number: u64, state_root: Felt252Wrapper, stats: CommitStats, csd: &CommitmentStateDiff) -> Self {
+        let contract_addresses = csd
+            .storage_updates
+            .keys()
+            .chain(csd.address_to_class_hash.keys())
+            .chain(csd.address_to_nonce.keys())
+            .collect::<IndexSet<_>>();
+        let changed_contracts = contract_addresses
+            .into_iter()
+            .map(|contract_address| ContractChange {
+                contract_address: *contract_address,
+                storage_writes: csd.storage_updates.get(contract_address).map_or(0, |updates| updates.len()),
+                class_hash: csd.address_to_class_hash.get(contract_address).copied(),
+                nonce: csd.address_to_nonce.get(contract_address).copied(),
+            })
+            .collect();
+
+        Self {
+            block_number,
+            state_root,
+            state_diff_commitment: calculate_state_diff_commitment(csd),
+            stats,
+            declared_classes: csd.class_hash_to_compiled_class_hash.len(),
+            changed_contracts,
+        }
+    }
+
+    /// Serializes the facts as JSON, felts being `0x`-prefixed hex strings.
+    pub fn to_json(&self) -> Value {
+        let stark_felt = |felt: &StarkFelt| Felt::from_bytes_be(&felt.0);
+        let changed_contracts = self.changed_contracts.iter().map(|change| {
+            json!({
+                "contract_address": felt_json(stark_felt(change.contract_address.0.key())),
+                "storage_writes": change.storage_writes,
+                "class_hash": change.class_hash.map(|class_hash| felt_json(stark_felt(&class_hash.0))),
+                "nonce": change.nonce.map(|nonce| felt_json(stark_felt(&nonce.0))),
+            })
+        });
+        json!({
+            "block_number": self.block_number,
+            "state_root": felt_json(self.state_root),
+            "state_diff_commitment": felt_json(self.state_diff_commitment),
+            "leaves": {
+                "storage": counts_json(&self.stats.storage),
+                "contracts": counts_json(&self.stats.contracts),
+                "classes": counts_json(&self.stats.classes),
+            },
+            "ignored_zero_writes": self.stats.ignored_zero_writes,
+            "new_nodes": self.stats.new_nodes,
+            "declared_classes": self.declared_classes,
+            "changed_contracts": changed_contracts.collect::<Vec<_>>(),
+        })
+    }
+}
+
+static SUBSCRIBERS: OnceLock<Mutex<Vec<Sender<Arc<BlockFacts>>>>> = OnceLock::new();
+
+fn subscribers() -> MutexGuard<'static, Vec<Sender<Arc<BlockFacts>>>> {
+    SUBSCRIBERS.get_or_init(Default::default).lock().expect("Poisoned lock on fact subscribers")
+}
+
+/// Subscribes to the facts of the blocks committed from now on.
+pub fn subscribe_facts() -> Receiver<Arc<BlockFacts>> {
+    let (sender, receiver) = mpsc::channel();
+    subscribers().push(sender);
+    receiver
+}
+
+/// Hands the facts of a committed block to the subscribers.
+///
+/// The facts are only gathered if there is a subscriber, so that commits pay nothing otherwise.
+pub(crate) fn publish_facts(
+    block_number: u64,
+    state_root: Felt252Wrapper,
+    stats: CommitStats,
+    csd: &CommitmentStateDiff,
+) {
+    let mut subscribers = subscribers();
+    if subscribers.is_empty() {
+        return;
+    }
+    let facts = Arc::new(BlockFacts::new(block_number, state_root, stats, csd));
+    // subscribers which hung up are dropped
+    subscribers.retain(|subscriber| subscriber.send(Arc::clone(&facts)).is_ok());
+}
+
+/// A message bus the facts are published to.
+pub trait FactPublisher {
+    type Error: std::error::Error;
+
+    fn publish(&mut self, facts: &BlockFacts) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("failed to publish the facts of block {block_number}: {error}")]
+pub struct PublishError {
+    pub block_number: u64,
+    pub error: String,
+}
+
+/// Publishes the facts received from `facts` until the subscription is closed.
+///
+/// # Returns
+///
+/// The first error of the publisher. The facts it failed to publish are lost, the caller resumes
+/// with a new subscription.
+pub fn forward_facts<P: FactPublisher>(
+    facts: Receiver<Arc<BlockFacts>>,
+    publisher: &mut P,
+) -> Result<(), PublishError> {
+    for facts in facts {
+        publisher
+            .publish(&facts)
+            .map_err(|e| PublishError { block_number: facts.block_number, error: e.to_string() })?;
+    }
+    Ok(())
+}
+
+/// Publishes the facts to a Kafka topic, as JSON keyed by block number.
+#[cfg(feature = "kafka")]
+pub struct KafkaPublisher {
+    producer: rdkafka::producer::BaseProducer,
+    topic: String,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaPublisher {
+    /// Connects to the brokers, a comma-separated list of `host:port`.
+    pub fn new(brokers: &str, topic: impl Into<String>) -> Result<Self, rdkafka::error::KafkaError> {
+        let producer = rdkafka::ClientConfig::new().set("bootstrap.servers", brokers).create()?;
+        Ok(Self { producer, topic: topic.into() })
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl FactPublisher for KafkaPublisher {
+    type Error = rdkafka::error::KafkaError;
+
+    fn publish(&mut self, facts: &BlockFacts) -> Result<(), Self::Error> {
+        use rdkafka::producer::{BaseRecord, Producer};
+
+        let key = facts.block_number.to_string();
+        let payload = facts.to_json().to_string();
+        self.producer.send(BaseRecord::to(&self.topic).key(&key).payload(&payload)).map_err(|(e, _)| e)?;
+        // Facts are published one block at a time, each is delivered before the next
+        self.producer.flush(std::time::Duration::from_secs(10))
+    }
+}
+
+/// Publishes the facts to a NATS subject, as JSON.
+#[cfg(feature = "nats")]
+pub struct NatsPublisher {
+    connection: nats::Connection,
+    subject: String,
+}
+
+#[cfg(feature = "nats")]
+impl NatsPublisher {
+    pub fn new(url: &str, subject: impl Into<String>) -> std::io::Result<Self> {
+        Ok(Self { connection: nats::connect(url)?, subject: subject.into() })
+    }
+}
+
+#[cfg(feature = "nats")]
+impl FactPublisher for NatsPublisher {
+    type Error = std::io::Error;
+
+    fn publish(&mut self, facts: &BlockFacts) -> std::io::Result<()> {
+        self.connection.publish(&self.subject, facts.to_json().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+
+    fn address(n: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    #[derive(Default)]
+    struct Bus {
+        published: Vec<Value>,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("bus is full")]
+    struct Full;
+
+    impl FactPublisher for Bus {
+        type Error = Full;
+
+        fn publish(&mut self, facts: &BlockFacts) -> Result<(), Full> {
+            if self.published.len() == 2 {
+                return Err(Full);
+            }
+            self.published.push(facts.to_json());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_forward_facts() {
+        let key = |n: u64| StorageKey(PatriciaKey(StarkFelt::from(n)));
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: [(address(0x12), ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: [(address(0x11), Nonce(StarkFelt::ONE))].into_iter().collect(),
+            storage_updates: [(
+                address(0x11),
+                [(key(1), StarkFelt::ONE), (key(2), StarkFelt::TWO)].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        let facts = BlockFacts::new(5, Felt252Wrapper::ONE, CommitStats::default(), &csd);
+        assert_eq!(
+            facts.changed_contracts,
+            vec![
+                ContractChange {
+                    contract_address: address(0x11),
+                    storage_writes: 2,
+                    class_hash: None,
+                    nonce: Some(Nonce(StarkFelt::ONE)),
+                },
+                ContractChange {
+                    contract_address: address(0x12),
+                    storage_writes: 0,
+                    class_hash: Some(ClassHash(StarkFelt::TWO)),
+                    nonce: None,
+                },
+            ]
+        );
+        assert_eq!(facts.state_diff_commitment, calculate_state_diff_commitment(&csd));
+
+        let (sender, receiver) = mpsc::channel();
+        for block_number in 5..8 {
+            sender.send(Arc::new(BlockFacts { block_number, ..facts.clone() })).unwrap();
+        }
+        drop(sender);
+        let mut bus = Bus::default();
+        let error = forward_facts(receiver, &mut bus).unwrap_err();
+        assert_eq!(error.block_number, 7);
+        assert_eq!(bus.published.len(), 2);
+        assert_eq!(bus.published[0]["state_root"], "0x1");
+        assert_eq!(bus.published[0]["changed_contracts"][0]["storage_writes"], 2);
+    }
+}
@@ -0,0 +1,285 @@
+//! Following a chain which may reorg, from the parent hashes of its blocks.
+//!
+//! The [ChainFollower] remembers the hash of the recent blocks it committed. A block whose parent
+//! hash does not match the stored tip means the source switched to another branch: the follower
+//! walks back the source's chain until it finds a block hash it stored, the common ancestor, then
+//! reverts its tries to it and commits the blocks of the new branch.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+use starknet_types_core::felt::Felt;
+
+use super::config::ChainConfig;
+use super::engine::CommitmentEngine;
+use super::error::TrieError;
+
+/// A block of the source's chain.
+#[derive(Debug)]
+pub struct SourceBlock {
+    pub block_number: u64,
+    pub block_hash: Felt,
+    pub parent_hash: Felt,
+    pub csd: CommitmentStateDiff,
+}
+
+/// Where the blocks of a new branch are fetched from during a reorg, ie: the feeder gateway.
+pub trait BlockSource {
+    type Error: std::error::Error;
+
+    /// The hash of `block_number` in the source's current chain.
+    fn block_hash(&mut self, block_number: u64) -> Result<Felt, Self::Error>;
+
+    /// The block `block_number` of the source's current chain.
+    fn block(&mut self, block_number: u64) -> Result<SourceBlock, Self::Error>;
+}
+
+/// A reorg handled by the follower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorgEvent {
+    /// The last block both branches share.
+    pub ancestor: u64,
+    /// Number of blocks of the previous branch which were reverted.
+    pub depth: u64,
+    pub previous_tip: u64,
+    pub new_tip: u64,
+}
+
+/// What [following](ChainFollower::follow) a block did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Followed {
+    /// The block extends the tip.
+    Extended { state_root: Felt252Wrapper },
+    /// The block is on another branch, which the follower switched to.
+    Reorged { state_root: Felt252Wrapper, reorg: ReorgEvent },
+    /// The block was already committed.
+    Known,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FollowError {
+    #[error("expected block {expected}, received block {received}")]
+    Gap { expected: u64, received: u64 },
+    #[error("no common ancestor with the new branch of block {block_number} within the last {max_depth} blocks")]
+    NoCommonAncestor { block_number: u64, max_depth: u64 },
+    #[error("block {block_number} of the source does not follow its parent")]
+    BrokenChain { block_number: u64 },
+    #[error("failed to fetch block {block_number} from the source: {error}")]
+    Source { block_number: u64, error: String },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+/// Commits the blocks of a chain to a [CommitmentEngine], following its reorgs.
+pub struct ChainFollower {
+    engine: CommitmentEngine,
+    config: ChainConfig,
+    /// Hashes of the recent committed blocks, at most `max_depth + 1` of them.
+    hashes: BTreeMap<u64, Felt>,
+    max_depth: u64,
+    subscribers: Vec<Sender<ReorgEvent>>,
+}
+
+impl ChainFollower {
+    /// Follows a chain from the state of `engine`, which must not have committed any block yet.
+    ///
+    /// Reorgs deeper than `max_depth` blocks fail: the tries must then be restored from a
+    /// snapshot. The backend of `engine` must keep the snapshots of at least as many blocks.
+    pub fn new(engine: CommitmentEngine, config: ChainConfig, max_depth: u64) -> Self {
+        Self { engine, config, hashes: BTreeMap::new(), max_depth, subscribers: Vec::new() }
+    }
+
+    pub fn engine(&self) -> &CommitmentEngine {
+        &self.engine
+    }
+
+    /// The latest followed block and its hash.
+    pub fn tip(&self) -> Option<(u64, Felt)> {
+        self.hashes.last_key_value().map(|(block_number, block_hash)| (*block_number, *block_hash))
+    }
+
+    /// Subscribes to the reorgs handled from now on.
+    pub fn subscribe(&mut self) -> Receiver<ReorgEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Commits the next block of the source, switching branches if its parent is not the tip.
+    ///
+    /// # Arguments
+    ///
+    /// * `block`  - The block, at most one block after the tip.
+    /// * `source` - Where to fetch the blocks of the new branch from, if `block` is on another one.
+    pub fn follow<S: BlockSource>(&mut self, block: SourceBlock, source: &mut S) -> Result<Followed, FollowError> {
+        let Some((tip, tip_hash)) = self.tip() else {
+            return Ok(Followed::Extended { state_root: self.commit(block)? });
+        };
+        if block.block_number > tip + 1 {
+            return Err(FollowError::Gap { expected: tip + 1, received: block.block_number });
+        }
+        if self.hashes.get(&block.block_number) == Some(&block.block_hash) {
+            return Ok(Followed::Known);
+        }
+        if block.block_number == tip + 1 && block.parent_hash == tip_hash {
+            return Ok(Followed::Extended { state_root: self.commit(block)? });
+        }
+
+        let ancestor = self.common_ancestor(&block, source)?;
+        self.engine.revert_to(ancestor)?;
+        self.hashes.retain(|block_number, _| *block_number <= ancestor);
+        for block_number in ancestor + 1..block.block_number {
+            let block = source.block(block_number).map_err(|e| source_error(block_number, e))?;
+            self.commit_child(block)?;
+        }
+        let new_tip = block.block_number;
+        let state_root = self.commit_child(block)?;
+
+        let reorg = ReorgEvent { ancestor, depth: tip - ancestor, previous_tip: tip, new_tip };
+        // subscribers which hung up are dropped
+        self.subscribers.retain(|subscriber| subscriber.send(reorg).is_ok());
+        Ok(Followed::Reorged { state_root, reorg })
+    }
+
+    /// Walks back the source's chain from the parent of `block` to the latest block whose hash was
+    /// stored.
+    fn common_ancestor<S: BlockSource>(&self, block: &SourceBlock, source: &mut S) -> Result<u64, FollowError> {
+        let no_ancestor =
+            || FollowError::NoCommonAncestor { block_number: block.block_number, max_depth: self.max_depth };
+        let mut block_number = block.block_number.checked_sub(1).ok_or_else(no_ancestor)?;
+        let mut block_hash = block.parent_hash;
+        loop {
+            match self.hashes.get(&block_number) {
+                Some(stored) if *stored == block_hash => return Ok(block_number),
+                Some(_) if block_number > 0 => {}
+                _ => return Err(no_ancestor()),
+            }
+            block_number -= 1;
+            block_hash = source.block_hash(block_number).map_err(|e| source_error(block_number, e))?;
+        }
+    }
+
+    /// Commits a block whose parent must be the tip.
+    fn commit_child(&mut self, block: SourceBlock) -> Result<Felt252Wrapper, FollowError> {
+        match self.tip() {
+            Some((tip, tip_hash)) if tip + 1 == block.block_number && tip_hash == block.parent_hash => {
+                self.commit(block)
+            }
+            _ => Err(FollowError::BrokenChain { block_number: block.block_number }),
+        }
+    }
+
+    fn commit(&mut self, block: SourceBlock) -> Result<Felt252Wrapper, FollowError> {
+        let state_root = self.engine.update_state_root(block.csd, block.block_number, &self.config)?;
+        self.hashes.insert(block.block_number, block.block_hash);
+        if let Some(oldest) = block.block_number.checked_sub(self.max_depth) {
+            self.hashes = self.hashes.split_off(&oldest);
+        }
+        Ok(state_root)
+    }
+}
+
+fn source_error(block_number: u64, e: impl std::error::Error) -> FollowError {
+    FollowError::Source { block_number, error: e.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+
+    /// A chain whose blocks from `fork` on are on branch `branch`.
+    struct Chain {
+        fork: u64,
+        branch: u64,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("unreachable")]
+    struct Unreachable;
+
+    impl Chain {
+        fn hash(&self, block_number: u64) -> Felt {
+            let branch = if block_number >= self.fork { self.branch } else { 0 };
+            Felt::from(block_number * 10 + branch)
+        }
+
+        fn get(&self, block_number: u64) -> SourceBlock {
+            let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+            let key = StorageKey(PatriciaKey(StarkFelt::from(block_number)));
+            let value = StarkFelt::from(self.hash(block_number).to_bytes_be());
+            SourceBlock {
+                block_number,
+                block_hash: self.hash(block_number),
+                parent_hash: block_number.checked_sub(1).map(|parent| self.hash(parent)).unwrap_or(Felt::ZERO),
+                csd: CommitmentStateDiff {
+                    address_to_class_hash: [(contract_address, ClassHash(StarkFelt::TWO))].into_iter().collect(),
+                    address_to_nonce: IndexMap::new(),
+                    storage_updates: [(contract_address, [(key, value)].into_iter().collect())].into_iter().collect(),
+                    class_hash_to_compiled_class_hash: IndexMap::new(),
+                },
+            }
+        }
+    }
+
+    impl BlockSource for Chain {
+        type Error = Unreachable;
+
+        fn block_hash(&mut self, block_number: u64) -> Result<Felt, Unreachable> {
+            Ok(self.hash(block_number))
+        }
+
+        fn block(&mut self, block_number: u64) -> Result<SourceBlock, Unreachable> {
+            Ok(self.get(block_number))
+        }
+    }
+
+    #[test]
+    fn test_follow_reorg() {
+        let config = ChainConfig::default();
+        let engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let mut follower = ChainFollower::new(engine, config.clone(), 4);
+        let reorgs = follower.subscribe();
+
+        let mut chain = Chain { fork: u64::MAX, branch: 0 };
+        for block_number in 0..6 {
+            let block = chain.get(block_number);
+            assert!(matches!(follower.follow(block, &mut chain).unwrap(), Followed::Extended { .. }));
+        }
+        assert!(matches!(follower.follow(chain.get(5), &mut chain).unwrap(), Followed::Known));
+        assert!(matches!(follower.follow(chain.get(8), &mut chain), Err(FollowError::Gap { expected: 6, .. })));
+
+        // The source switches to a branch forked after block 3, and is at block 6 already
+        let mut chain = Chain { fork: 4, branch: 1 };
+        let Followed::Reorged { state_root, reorg } = follower.follow(chain.get(6), &mut chain).unwrap() else {
+            panic!("expected a reorg");
+        };
+        assert_eq!(reorg, ReorgEvent { ancestor: 3, depth: 2, previous_tip: 5, new_tip: 6 });
+        assert_eq!(reorgs.try_recv().unwrap(), reorg);
+        assert_eq!(follower.tip(), Some((6, chain.hash(6))));
+
+        // Same state root as following the new branch from scratch
+        let engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let mut reference = ChainFollower::new(engine, config, 4);
+        for block_number in 0..=6 {
+            reference.follow(chain.get(block_number), &mut chain).unwrap();
+        }
+        assert_eq!(state_root, reference.engine().state_root(&ChainConfig::default()).unwrap());
+
+        // Deeper than the stored hashes
+        let mut chain = Chain { fork: 1, branch: 2 };
+        assert!(matches!(
+            follower.follow(chain.get(7), &mut chain),
+            Err(FollowError::NoCommonAncestor { block_number: 7, max_depth: 4 })
+        ));
+    }
+}
@@ -0,0 +1,296 @@
+//! Simulation of a protocol upgrade over a range of blocks.
+//!
+//! The blocks are replayed twice side by side: once under the current rules, and once under the
+//! candidate rules from the fork height on. Both replays run on their own engine, writing to an
+//! [overlay](OverlayBackend) over the state they start from, which is never touched. Only the
+//! latest version of the tries is kept, so that the memory used grows with the state written by the
+//! replayed blocks rather than with their number.
+
+use std::sync::Arc;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+use starknet_api::transaction::{Event, Transaction};
+use starknet_types_core::felt::Felt;
+
+use super::backend::{Backend, MemoryBackend, OverlayBackend};
+use super::config::{ChainConfig, CommitmentScheme};
+use super::engine::{CommitmentEngine, StateSeed};
+use super::error::{CommitmentError, TrieError};
+use super::events::try_memory_event_commitment_with_scheme;
+use super::lib::clone_commitment_state_diff;
+use super::transactions::try_memory_transaction_commitment_with_scheme;
+
+/// The commitment rules of a protocol version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolRules {
+    pub chain: ChainConfig,
+    pub scheme: CommitmentScheme,
+}
+
+impl ProtocolRules {
+    /// The rules of a Starknet protocol version, ie: `"0.13.2"`, on a chain following `chain`.
+    pub fn for_protocol_version(version: &str, chain: ChainConfig) -> Self {
+        Self { chain, scheme: CommitmentScheme::for_protocol_version(version) }
+    }
+}
+
+/// What a block is replayed from.
+#[derive(Debug)]
+pub struct BlockInputs {
+    pub block_number: u64,
+    pub csd: CommitmentStateDiff,
+    pub transactions: Vec<Transaction>,
+    pub events: Vec<Event>,
+    /// The hash of the transaction which emitted each event, for [CommitmentScheme::Poseidon].
+    pub event_transaction_hashes: Vec<Felt>,
+}
+
+/// A commitment of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Commitment {
+    StateRoot,
+    Transaction,
+    Event,
+}
+
+/// A commitment which differs between the two replays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub block_number: u64,
+    pub commitment: Commitment,
+    pub current: Felt252Wrapper,
+    pub upgraded: Felt252Wrapper,
+}
+
+/// Where the commitments of the replayed blocks diverged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulationReport {
+    /// Number of blocks replayed.
+    pub blocks: u64,
+    pub divergences: Vec<Divergence>,
+}
+
+impl SimulationReport {
+    /// The first block at which `commitment` diverged.
+    pub fn first_divergence(&self, commitment: Commitment) -> Option<&Divergence> {
+        self.divergences.iter().find(|divergence| divergence.commitment == commitment)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SimulationError {
+    #[error("block {block_number} is before the simulation start, blocks must be replayed in ascending order")]
+    OutOfOrder { block_number: u64 },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+    #[error("failed to compute the commitments of block {block_number}: {error}")]
+    Commitment { block_number: u64, error: CommitmentError },
+}
+
+/// Replays blocks under the current rules and under upgraded rules adopted at a fork height.
+#[derive(Debug)]
+pub struct ForkSimulation {
+    current: ProtocolRules,
+    upgraded: ProtocolRules,
+    fork_height: u64,
+    chain_id: Felt252Wrapper,
+    current_engine: CommitmentEngine,
+    upgraded_engine: CommitmentEngine,
+    next_block: u64,
+    report: SimulationReport,
+}
+
+impl ForkSimulation {
+    /// Starts a simulation from the state right after `seed_block`, or from an empty state before
+    /// genesis if there is no seed.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed`        - The state the replays start from, committed under the current rules.
+    /// * `current`     - The rules the chain runs today.
+    /// * `upgraded`    - The rules being assessed.
+    /// * `fork_height` - The first block following `upgraded`.
+    /// * `chain_id`    - The chain id, which the transaction hashes commit to.
+    pub fn new(
+        seed: Option<(u64, StateSeed)>,
+        current: ProtocolRules,
+        upgraded: ProtocolRules,
+        fork_height: u64,
+        chain_id: Felt252Wrapper,
+    ) -> Result<Self, SimulationError> {
+        // The seed is committed once, both replays read through to it
+        let base: Backend = Arc::new(MemoryBackend::new());
+        if let Some((block_number, seed)) = seed {
+            let mut engine = CommitmentEngine::with_config(Arc::clone(&base), &current.chain)?;
+            engine.update_state_root(seed.into(), block_number, &current.chain)?;
+        }
+        Self::over_backend(base, current, upgraded, fork_height, chain_id)
+    }
+
+    /// Starts a simulation from the latest state committed to `base`, ie: the tries of an
+    /// [engine](super::engine::CommitmentEngine), which are only read from.
+    ///
+    /// See [ForkSimulation::new].
+    pub fn over_backend(
+        base: Backend,
+        current: ProtocolRules,
+        upgraded: ProtocolRules,
+        fork_height: u64,
+        chain_id: Felt252Wrapper,
+    ) -> Result<Self, SimulationError> {
+        let engine = || -> Result<CommitmentEngine, TrieError> {
+            // Both replays read the nodes of `base`, which are stored as the current rules set
+            let mut engine =
+                CommitmentEngine::with_config(Arc::new(OverlayBackend::new(Arc::clone(&base))), &current.chain)?;
+            engine.set_retention(Some(1));
+            Ok(engine)
+        };
+        let (current_engine, upgraded_engine) = (engine()?, engine()?);
+        let next_block = current_engine.latest().map_or(0, |latest| latest + 1);
+
+        Ok(Self {
+            current,
+            upgraded,
+            fork_height,
+            chain_id,
+            current_engine,
+            upgraded_engine,
+            next_block,
+            report: SimulationReport::default(),
+        })
+    }
+
+    /// Replays the next block under both rules, recording the commitments which diverged.
+    ///
+    /// Blocks must be replayed in ascending order, gaps are allowed for blocks which do not change
+    /// the state.
+    pub fn replay(&mut self, block: BlockInputs) -> Result<&[Divergence], SimulationError> {
+        let block_number = block.block_number;
+        if block_number < self.next_block {
+            return Err(SimulationError::OutOfOrder { block_number });
+        }
+        let upgraded = if block_number >= self.fork_height { &self.upgraded } else { &self.current };
+
+        let current_commitments = commitments(&mut self.current_engine, &block, &self.current, self.chain_id)?;
+        let upgraded_commitments = commitments(&mut self.upgraded_engine, &block, upgraded, self.chain_id)?;
+
+        let divergences = self.report.divergences.len();
+        for ((commitment, current), (_, upgraded)) in current_commitments.into_iter().zip(upgraded_commitments) {
+            if current != upgraded {
+                self.report.divergences.push(Divergence { block_number, commitment, current, upgraded });
+            }
+        }
+        self.report.blocks += 1;
+        self.next_block = block_number + 1;
+        Ok(&self.report.divergences[divergences..])
+    }
+
+    /// Replays every block of `blocks`, see [ForkSimulation::replay].
+    pub fn replay_all(
+        mut self,
+        blocks: impl IntoIterator<Item = BlockInputs>,
+    ) -> Result<SimulationReport, SimulationError> {
+        for block in blocks {
+            self.replay(block)?;
+        }
+        Ok(self.report)
+    }
+
+    pub fn report(&self) -> &SimulationReport {
+        &self.report
+    }
+}
+
+/// Computes the commitments of a block under `rules`, committing its state diff to `engine`.
+fn commitments(
+    engine: &mut CommitmentEngine,
+    block: &BlockInputs,
+    rules: &ProtocolRules,
+    chain_id: Felt252Wrapper,
+) -> Result<[(Commitment, Felt252Wrapper); 3], SimulationError> {
+    let block_number = block.block_number;
+    let commitment_error = |error| SimulationError::Commitment { block_number, error };
+
+    let state_root = engine.update_state_root(clone_commitment_state_diff(&block.csd), block_number, &rules.chain)?;
+    let transaction =
+        try_memory_transaction_commitment_with_scheme(&block.transactions, chain_id, block_number, rules.scheme)
+            .map_err(commitment_error)?;
+    let event = try_memory_event_commitment_with_scheme(&block.events, &block.event_transaction_hashes, rules.scheme)
+        .map_err(commitment_error)?;
+
+    Ok([(Commitment::StateRoot, state_root), (Commitment::Transaction, transaction), (Commitment::Event, event)])
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::config::TrieHashers;
+
+    fn block(block_number: u64) -> BlockInputs {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::from(block_number)));
+        BlockInputs {
+            block_number,
+            csd: CommitmentStateDiff {
+                address_to_class_hash: [(contract_address, ClassHash(StarkFelt::TWO))].into_iter().collect(),
+                address_to_nonce: IndexMap::new(),
+                storage_updates: [(contract_address, [(key, StarkFelt::THREE)].into_iter().collect())]
+                    .into_iter()
+                    .collect(),
+                class_hash_to_compiled_class_hash: IndexMap::new(),
+            },
+            transactions: vec![],
+            events: vec![],
+            event_transaction_hashes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_fork_simulation() {
+        let current = ProtocolRules::for_protocol_version("0.13.2", ChainConfig::default());
+        let upgraded = ProtocolRules {
+            chain: ChainConfig { hashers: TrieHashers::poseidon(), ..Default::default() },
+            ..current.clone()
+        };
+        let simulation = ForkSimulation::new(None, current, upgraded, 2, Felt252Wrapper::ZERO).unwrap();
+
+        let report = simulation.replay_all((0..4).map(block)).unwrap();
+        assert_eq!(report.blocks, 4);
+        // The state roots diverge from the fork on, the empty commitments never do
+        let diverged = report.divergences.iter().map(|d| (d.block_number, d.commitment)).collect::<Vec<_>>();
+        assert_eq!(diverged, vec![(2, Commitment::StateRoot), (3, Commitment::StateRoot)]);
+        assert_eq!(report.first_divergence(Commitment::Transaction), None);
+    }
+
+    #[test]
+    fn test_fork_simulation_over_backend() {
+        let current = ProtocolRules::for_protocol_version("0.13.2", ChainConfig::default());
+        let upgraded = ProtocolRules {
+            chain: ChainConfig { hashers: TrieHashers::poseidon(), ..Default::default() },
+            ..current.clone()
+        };
+        let base: Backend = Arc::new(MemoryBackend::new());
+        let mut node = CommitmentEngine::new(Arc::clone(&base)).unwrap();
+        let base_root = node.update_state_root(block(0).csd, 0, &current.chain).unwrap();
+
+        let mut simulation =
+            ForkSimulation::over_backend(Arc::clone(&base), current.clone(), upgraded, 2, Felt252Wrapper::ZERO)
+                .unwrap();
+        assert!(matches!(simulation.replay(block(0)), Err(SimulationError::OutOfOrder { block_number: 0 })));
+        for block_number in 1..4 {
+            simulation.replay(block(block_number)).unwrap();
+        }
+        assert_eq!(simulation.report().divergences.iter().map(|d| d.block_number).collect::<Vec<_>>(), vec![2, 3]);
+
+        // The replays did not write to the state they started from
+        let node = CommitmentEngine::new(base).unwrap();
+        assert_eq!(node.latest(), Some(0));
+        assert_eq!(node.state_root(&current.chain).unwrap(), base_root);
+    }
+}
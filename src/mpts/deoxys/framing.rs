@@ -0,0 +1,208 @@
+//! Framing shared by the binary formats of this crate, the [interchange format](super::interchange)
+//! and [trie snapshots](super::trie_snapshot):
+//!
+//! ```text
+//! file   := header chunk* end
+//! header := magic:[u8; 4] version:u16 block_number:u64 state_root:felt
+//! chunk  := kind:u8 count:u32 entry{count} checksum:felt
+//! end    := 0xff
+//!
+//! felt     := 32 bytes big-endian
+//! checksum := starknet_keccak(kind || count || entries)
+//! ```
+//!
+//! Formats only define their magic, version, chunk kinds and entries. Chunks hold at most
+//! [CHUNK_ENTRIES] entries, a corrupted chunk is detected before any of its entries is used.
+
+use std::io::{self, Read, Write};
+
+use mp_felt::Felt252Wrapper;
+use starknet_core::utils::starknet_keccak;
+use starknet_ff::FieldElement;
+
+/// Maximum number of entries in a chunk.
+pub const CHUNK_ENTRIES: usize = 4096;
+
+const END: u8 = 0xff;
+
+/// A binary format built on this framing.
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    pub name: &'static str,
+    pub magic: &'static [u8; 4],
+    pub version: u16,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FramingError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a {0} file")]
+    BadMagic(&'static str),
+    #[error("unsupported {format} format version {version}")]
+    UnsupportedVersion { format: &'static str, version: u16 },
+    #[error("state root in header is not a valid felt")]
+    InvalidStateRoot,
+    #[error("chunk {0} is corrupted (checksum mismatch)")]
+    Checksum(usize),
+    #[error("chunk {0} has more than {CHUNK_ENTRIES} entries")]
+    ChunkTooLarge(usize),
+}
+
+pub(crate) fn write_header<W: Write>(
+    writer: &mut W,
+    format: &Format,
+    block_number: u64,
+    state_root: Felt252Wrapper,
+) -> io::Result<()> {
+    writer.write_all(format.magic)?;
+    writer.write_all(&format.version.to_be_bytes())?;
+    writer.write_all(&block_number.to_be_bytes())?;
+    writer.write_all(&state_root.0.to_bytes_be())
+}
+
+/// Writes a chunk of `count` entries, already encoded in `entries`.
+pub(crate) fn write_chunk<W: Write>(writer: &mut W, kind: u8, count: usize, entries: &[u8]) -> io::Result<()> {
+    debug_assert!(kind != END && count <= CHUNK_ENTRIES);
+    let mut chunk = vec![kind];
+    chunk.extend((count as u32).to_be_bytes());
+    chunk.extend(entries);
+    let checksum = starknet_keccak(&chunk).to_bytes_be();
+
+    writer.write_all(&chunk)?;
+    writer.write_all(&checksum)
+}
+
+pub(crate) fn write_end<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(&[END])
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads a header of `format`, returning its block number and state root.
+pub(crate) fn read_header<R: Read>(reader: &mut R, format: &Format) -> Result<(u64, Felt252Wrapper), FramingError> {
+    if &read_array::<_, 4>(reader)? != format.magic {
+        return Err(FramingError::BadMagic(format.name));
+    }
+    let version = u16::from_be_bytes(read_array(reader)?);
+    if version != format.version {
+        return Err(FramingError::UnsupportedVersion { format: format.name, version });
+    }
+    let block_number = u64::from_be_bytes(read_array(reader)?);
+    let state_root = FieldElement::from_bytes_be(&read_array(reader)?).map_err(|_| FramingError::InvalidStateRoot)?;
+    Ok((block_number, state_root.into()))
+}
+
+/// Reads the chunks following a header, one at a time: [next_chunk](ChunkReader::next_chunk) starts a chunk,
+/// whose entries are then [read](ChunkReader::read) and [verified](ChunkReader::verify).
+pub(crate) struct ChunkReader<'a, R> {
+    reader: &'a mut R,
+    index: usize,
+    chunk: Vec<u8>,
+}
+
+impl<'a, R: Read> ChunkReader<'a, R> {
+    pub(crate) fn new(reader: &'a mut R) -> Self {
+        Self { reader, index: 0, chunk: Vec::new() }
+    }
+
+    /// Starts the next chunk.
+    ///
+    /// # Returns
+    ///
+    /// The kind of the chunk and its number of entries, `None` at the end of the file.
+    pub(crate) fn next_chunk(&mut self) -> Result<Option<(u8, usize)>, FramingError> {
+        let [kind] = read_array(self.reader)?;
+        if kind == END {
+            return Ok(None);
+        }
+        let count = read_array(self.reader)?;
+        let entries = u32::from_be_bytes(count) as usize;
+        if entries > CHUNK_ENTRIES {
+            return Err(FramingError::ChunkTooLarge(self.index));
+        }
+        self.chunk.clear();
+        self.chunk.push(kind);
+        self.chunk.extend(count);
+        Ok(Some((kind, entries)))
+    }
+
+    /// Reads the next `len` bytes of the entries of the current chunk, which must not be used before
+    /// the chunk is verified.
+    pub(crate) fn read(&mut self, len: usize) -> Result<&[u8], FramingError> {
+        let start = self.chunk.len();
+        self.chunk.resize(start + len, 0);
+        self.reader.read_exact(&mut self.chunk[start..])?;
+        Ok(&self.chunk[start..])
+    }
+
+    /// Checks the checksum of the current chunk, once all of its entries were read.
+    ///
+    /// # Returns
+    ///
+    /// The entries of the chunk.
+    pub(crate) fn verify(&mut self) -> Result<&[u8], FramingError> {
+        if starknet_keccak(&self.chunk).to_bytes_be() != read_array::<_, 32>(self.reader)? {
+            return Err(FramingError::Checksum(self.index));
+        }
+        self.index += 1;
+        Ok(&self.chunk[5..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: Format = Format { name: "test", magic: b"TEST", version: 1 };
+
+    #[test]
+    fn test_round_trip() {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &FORMAT, 42, Felt252Wrapper::TWO).unwrap();
+        write_chunk(&mut bytes, 0x01, 2, &[1, 2, 3]).unwrap();
+        write_chunk(&mut bytes, 0x02, 0, &[]).unwrap();
+        write_end(&mut bytes).unwrap();
+
+        let mut reader = bytes.as_slice();
+        assert_eq!(read_header(&mut reader, &FORMAT).unwrap(), (42, Felt252Wrapper::TWO));
+        let mut chunks = ChunkReader::new(&mut reader);
+        assert_eq!(chunks.next_chunk().unwrap(), Some((0x01, 2)));
+        assert_eq!(chunks.read(1).unwrap(), [1]);
+        assert_eq!(chunks.read(2).unwrap(), [2, 3]);
+        assert_eq!(chunks.verify().unwrap(), [1, 2, 3]);
+        assert_eq!(chunks.next_chunk().unwrap(), Some((0x02, 0)));
+        assert_eq!(chunks.verify().unwrap(), []);
+        assert_eq!(chunks.next_chunk().unwrap(), None);
+
+        let other = Format { magic: b"TSET", ..FORMAT };
+        assert!(matches!(read_header(&mut bytes.as_slice(), &other), Err(FramingError::BadMagic("test"))));
+        let newer = Format { version: 2, ..FORMAT };
+        assert!(matches!(
+            read_header(&mut bytes.as_slice(), &newer),
+            Err(FramingError::UnsupportedVersion { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_corrupted_chunk() {
+        let mut bytes = Vec::new();
+        write_chunk(&mut bytes, 0x01, 1, &[1, 2, 3]).unwrap();
+        write_chunk(&mut bytes, 0x01, 1, &[4, 5, 6]).unwrap();
+        let second = bytes.len() / 2;
+        bytes[second + 5] ^= 1;
+
+        let mut reader = bytes.as_slice();
+        let mut chunks = ChunkReader::new(&mut reader);
+        chunks.next_chunk().unwrap();
+        chunks.read(3).unwrap();
+        chunks.verify().unwrap();
+        chunks.next_chunk().unwrap();
+        chunks.read(3).unwrap();
+        assert!(matches!(chunks.verify(), Err(FramingError::Checksum(1))));
+    }
+}
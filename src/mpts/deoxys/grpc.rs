@@ -0,0 +1,319 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use starknet_core::types::{
+    ContractStorageDiffItem, DeclaredClassItem, DeployedContractItem, NonceUpdate, ReplacedClassItem, StateDiff,
+    StateUpdate, StorageEntry,
+};
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use self::proto::commitment_engine_server::{CommitmentEngine, CommitmentEngineServer};
+use self::proto::{
+    proof_node, ApplyBlockRequest, BlockRoot, ContractData, GetProofRequest, GetProofResponse, GetRootRequest,
+    SubscribeRootsRequest,
+};
+use super::config::ChainConfig;
+use super::conversions::{try_contract_address, try_storage_key};
+use super::engine::StateTries;
+use super::error::{CommitError, TrieError};
+use super::handles::WriteHandle;
+use super::lib::build_commitment_state_diff;
+use super::proof::{path_to_felt, ProofNode};
+use super::roots::root_registry;
+use super::storage_proof::{get_storage_proof, StorageProofError};
+
+/// Types and service traits generated from `proto/starkroot.proto`.
+pub mod proto {
+    tonic::include_proto!("starkroot.v1");
+}
+
+/// Number of roots buffered for slow subscribers before they start missing some.
+const ROOTS_CHANNEL_CAPACITY: usize = 1024;
+
+/// gRPC sidecar exposing the commitment engine to non-Rust node components.
+///
+/// See `proto/starkroot.proto` for the service definition. The blocks are committed through the
+/// write handle the service is given, the tries being read meanwhile.
+pub struct CommitmentService {
+    writer: Arc<Mutex<WriteHandle>>,
+    tries: StateTries,
+    config: ChainConfig,
+    roots: broadcast::Sender<BlockRoot>,
+}
+
+impl CommitmentService {
+    pub fn new(writer: WriteHandle, config: ChainConfig) -> Self {
+        let (roots, _) = broadcast::channel(ROOTS_CHANNEL_CAPACITY);
+        let tries = writer.tries().clone();
+        Self { writer: Arc::new(Mutex::new(writer)), tries, config, roots }
+    }
+
+    /// Wraps the service in a tonic server, ready to be added to a `tonic::transport::Server`.
+    pub fn into_server(self) -> CommitmentEngineServer<Self> {
+        CommitmentEngineServer::new(self)
+    }
+}
+
+fn felt(bytes: &[u8]) -> Result<FieldElement, Status> {
+    let invalid = || Status::invalid_argument("felts must be 32 bytes big-endian, below the field modulus");
+    let bytes = bytes.try_into().map_err(|_| invalid())?;
+    FieldElement::from_bytes_be(bytes).map_err(|_| invalid())
+}
+
+fn felt_bytes(felt: impl Into<FieldElement>) -> Vec<u8> {
+    felt.into().to_bytes_be().to_vec()
+}
+
+/// Runs `f` on the blocking thread pool: the tries and the root registry are behind std locks,
+/// which are held for the whole commit of a block.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, Status> + Send + 'static) -> Result<T, Status> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| Status::internal(e.to_string()))?
+}
+
+fn state_update(state_diff: proto::StateDiff) -> Result<StateUpdate, Status> {
+    let storage_diffs = state_diff
+        .storage_diffs
+        .into_iter()
+        .map(|diff| {
+            let storage_entries = diff
+                .entries
+                .into_iter()
+                .map(|entry| Ok(StorageEntry { key: felt(&entry.key)?, value: felt(&entry.value)? }))
+                .collect::<Result<_, Status>>()?;
+            Ok(ContractStorageDiffItem { address: felt(&diff.address)?, storage_entries })
+        })
+        .collect::<Result<_, Status>>()?;
+    let deployed_contracts = state_diff
+        .deployed_contracts
+        .into_iter()
+        .map(|item| Ok(DeployedContractItem { address: felt(&item.address)?, class_hash: felt(&item.class_hash)? }))
+        .collect::<Result<_, Status>>()?;
+    let replaced_classes = state_diff
+        .replaced_classes
+        .into_iter()
+        .map(|item| {
+            Ok(ReplacedClassItem { contract_address: felt(&item.address)?, class_hash: felt(&item.class_hash)? })
+        })
+        .collect::<Result<_, Status>>()?;
+    let declared_classes = state_diff
+        .declared_classes
+        .into_iter()
+        .map(|item| {
+            Ok(DeclaredClassItem {
+                class_hash: felt(&item.class_hash)?,
+                compiled_class_hash: felt(&item.compiled_class_hash)?,
+            })
+        })
+        .collect::<Result<_, Status>>()?;
+    let nonces = state_diff
+        .nonces
+        .into_iter()
+        .map(|item| Ok(NonceUpdate { contract_address: felt(&item.address)?, nonce: felt(&item.nonce)? }))
+        .collect::<Result<_, Status>>()?;
+
+    Ok(StateUpdate {
+        block_hash: FieldElement::ZERO,
+        new_root: FieldElement::ZERO,
+        old_root: FieldElement::ZERO,
+        state_diff: StateDiff {
+            storage_diffs,
+            deprecated_declared_classes: vec![],
+            declared_classes,
+            deployed_contracts,
+            replaced_classes,
+            nonces,
+        },
+    })
+}
+
+fn commit_status(error: CommitError) -> Status {
+    match error {
+        CommitError::Conflict { .. } => Status::already_exists(error.to_string()),
+        CommitError::Fenced { .. } | CommitError::Unfenced { .. } => Status::failed_precondition(error.to_string()),
+        CommitError::Trie(TrieError::Conversion(_)) => Status::invalid_argument(error.to_string()),
+        error => Status::internal(error.to_string()),
+    }
+}
+
+fn proof_status(error: StorageProofError) -> Status {
+    match error {
+        StorageProofError::NotCommitted { .. } => Status::not_found(error.to_string()),
+        error => Status::internal(error.to_string()),
+    }
+}
+
+fn proof(proof: &[ProofNode]) -> Vec<proto::ProofNode> {
+    proof
+        .iter()
+        .map(|node| {
+            let node = match node {
+                ProofNode::Binary { left, right } => proof_node::Node::Binary(proof_node::Binary {
+                    left: left.to_bytes_be().to_vec(),
+                    right: right.to_bytes_be().to_vec(),
+                }),
+                ProofNode::Edge { child, path } => proof_node::Node::Edge(proof_node::Edge {
+                    child: child.to_bytes_be().to_vec(),
+                    path: path_to_felt(path).to_bytes_be().to_vec(),
+                    length: path.len() as u32,
+                }),
+            };
+            proto::ProofNode { node: Some(node) }
+        })
+        .collect()
+}
+
+#[tonic::async_trait]
+impl CommitmentEngine for CommitmentService {
+    type SubscribeRootsStream = Pin<Box<dyn Stream<Item = Result<BlockRoot, Status>> + Send>>;
+
+    async fn apply_block(&self, request: Request<ApplyBlockRequest>) -> Result<Response<BlockRoot>, Status> {
+        let ApplyBlockRequest { block_number, state_diff } = request.into_inner();
+        let state_update = state_update(state_diff.unwrap_or_default())?;
+        let csd = build_commitment_state_diff(&state_update).map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        // Trie updates are CPU bound and take the trie locks
+        let (writer, config) = (Arc::clone(&self.writer), self.config.clone());
+        let state_root = blocking(move || {
+            let mut writer = writer.lock().expect("Poisoned lock on write handle");
+            writer.commit(csd, block_number, &config).map_err(commit_status)
+        })
+        .await?;
+
+        let root = BlockRoot { block_number, state_root: felt_bytes(state_root) };
+        // no subscribers is not an error
+        let _ = self.roots.send(root.clone());
+
+        Ok(Response::new(root))
+    }
+
+    async fn get_root(&self, request: Request<GetRootRequest>) -> Result<Response<BlockRoot>, Status> {
+        let block_number = request.into_inner().block_number;
+        let tries = self.tries.clone();
+        let root = blocking(move || {
+            let registry = root_registry(&tries);
+            let block = match block_number {
+                Some(block_number) => registry.get(block_number).map(|block| (block_number, block)),
+                None => registry.latest(),
+            };
+            let (block_number, block) = block.ok_or_else(|| Status::not_found("block was not committed"))?;
+            Ok(BlockRoot { block_number, state_root: felt_bytes(block.state_root) })
+        })
+        .await?;
+
+        Ok(Response::new(root))
+    }
+
+    async fn get_proof(&self, request: Request<GetProofRequest>) -> Result<Response<GetProofResponse>, Status> {
+        let GetProofRequest { contract_address, key, block_number } = request.into_inner();
+        let contract_address =
+            try_contract_address(&felt(&contract_address)?).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let key = try_storage_key(&felt(&key)?).map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let (tries, config) = (self.tries.clone(), self.config.clone());
+        let response = blocking(move || {
+            let block_number = match block_number {
+                Some(block_number) => block_number,
+                None => root_registry(&tries)
+                    .latest()
+                    .map(|(block_number, _)| block_number)
+                    .ok_or_else(|| Status::not_found("no block was committed"))?,
+            };
+            let storage_proof =
+                get_storage_proof(&tries, &contract_address, &[key], block_number, &config).map_err(proof_status)?;
+            // The value is the one the proof proves, at the same block
+            let value = storage_proof
+                .verify(storage_proof.state_commitment, &[key], &config)
+                .map_err(|e| Status::internal(e.to_string()))?[0];
+            let contract_data = storage_proof.contract_data.as_ref();
+
+            Ok(GetProofResponse {
+                value: (value != Felt::ZERO).then(|| value.to_bytes_be().to_vec()),
+                storage_proof: contract_data.map(|data| proof(&data.storage_proofs[0])).unwrap_or_default(),
+                contract_proof: proof(&storage_proof.contract_proof),
+                block_number,
+                state_root: storage_proof.state_commitment.to_bytes_be().to_vec(),
+                contracts_trie_root: storage_proof.contracts_trie_root.to_bytes_be().to_vec(),
+                class_commitment: storage_proof.class_commitment.to_bytes_be().to_vec(),
+                contract_data: contract_data.map(|data| ContractData {
+                    class_hash: data.class_hash.to_bytes_be().to_vec(),
+                    nonce: data.nonce.to_bytes_be().to_vec(),
+                    storage_root: data.root.to_bytes_be().to_vec(),
+                    contract_state_hash_version: data.contract_state_hash_version.to_bytes_be().to_vec(),
+                }),
+            })
+        })
+        .await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn subscribe_roots(
+        &self,
+        _request: Request<SubscribeRootsRequest>,
+    ) -> Result<Response<Self::SubscribeRootsStream>, Status> {
+        let roots = BroadcastStream::new(self.roots.subscribe())
+            .map(|root| root.map_err(|e| Status::data_loss(format!("subscriber fell behind: {e}"))));
+
+        Ok(Response::new(Box::pin(roots)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+
+    fn bytes(n: u64) -> Vec<u8> {
+        felt_bytes(FieldElement::from(n))
+    }
+
+    #[test]
+    fn test_felt() {
+        assert_eq!(felt(&bytes(0x2a)).unwrap(), FieldElement::from(0x2a_u64));
+        assert_eq!(felt(&[0x2a]).unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(felt(&[0; 33]).unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(felt(&[0xff; 32]).unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_get_proof() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let service = CommitmentService::new(WriteHandle::acquire(tries).unwrap(), ChainConfig::default());
+        let state_diff = proto::StateDiff {
+            storage_diffs: vec![proto::StorageDiff {
+                address: bytes(0x11),
+                entries: vec![proto::StorageEntry { key: bytes(1), value: bytes(0x2a) }],
+            }],
+            deployed_contracts: vec![proto::ContractClass { address: bytes(0x11), class_hash: bytes(0x10) }],
+            ..Default::default()
+        };
+        let request = ApplyBlockRequest { block_number: 1, state_diff: Some(state_diff) };
+        let root = runtime.block_on(service.apply_block(Request::new(request))).unwrap().into_inner();
+
+        let get_proof = |contract_address: Vec<u8>, key: u64, block_number: Option<u64>| {
+            let request = GetProofRequest { contract_address, key: bytes(key), block_number };
+            runtime.block_on(service.get_proof(Request::new(request))).map(Response::into_inner)
+        };
+        let proof = get_proof(bytes(0x11), 1, None).unwrap();
+        assert_eq!((proof.block_number, &proof.state_root), (1, &root.state_root));
+        assert_eq!(proof.value, Some(bytes(0x2a)));
+        assert!(!proof.storage_proof.is_empty());
+        assert_eq!(proof.contract_data.unwrap().class_hash, bytes(0x10));
+
+        // Empty slots are proven absent against the same root
+        let proof = get_proof(bytes(0x11), 2, Some(1)).unwrap();
+        assert_eq!((proof.value, proof.state_root), (None, root.state_root));
+
+        assert_eq!(get_proof(vec![0x11], 1, None).unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(get_proof(bytes(0x11), 1, Some(2)).unwrap_err().code(), Code::NotFound);
+    }
+}
@@ -0,0 +1,276 @@
+//! Typed access to the committed tries.
+//!
+//! Blocks are committed and reverted through the [WriteHandle] of their tries, and read through
+//! [ReadHandle]s:
+//!
+//! - there is at most one [WriteHandle] per tries at a time, and writing takes it mutably, so that a
+//!   single task commits and reverts blocks,
+//! - [ReadHandle]s are `Send + Sync` and pinned to the state root of a block, reads fail instead
+//!   of silently returning another state if the block was reverted or pruned meanwhile.
+//!
+//! The entry points built on the commit path, ie: [import](super::import::import) or
+//! [verify_state_root](super::verify::verify_state_root), take the write handle as well.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+use starknet_api::core::ContractAddress;
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+
+use super::config::ChainConfig;
+use super::engine::StateTries;
+use super::error::{CommitError, TrieError};
+use super::historical::storage_value_at;
+use super::lib::{try_update_state_root, try_update_state_root_fenced};
+use super::pruning::{prune, PrunePolicy, PruneReport};
+use super::reorg::{revert_to, RevertError, RevertReport};
+use super::roots::{root_registry, FencingToken, RootRegistry};
+
+#[derive(Debug, thiserror::Error)]
+#[error("a write handle already exists, blocks can only be committed from one place")]
+pub struct WriterTaken;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    #[error("block {block_number} was reverted, the state root the read was pinned to is gone")]
+    Stale { block_number: u64 },
+    #[error("block {block_number} was pruned")]
+    Pruned { block_number: u64 },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+/// The right to commit and revert the blocks of a set of [StateTries], of which there is at most one
+/// at a time.
+///
+/// The handle can be moved to the task which follows the chain, but not shared: it is `Send` and
+/// not `Sync`. It is released when dropped.
+#[derive(Debug)]
+pub struct WriteHandle {
+    tries: StateTries,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl WriteHandle {
+    /// Takes the write handle of `tries`, failing if it is held elsewhere.
+    pub fn acquire(tries: StateTries) -> Result<Self, WriterTaken> {
+        let writer = &tries.state().writer;
+        writer.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).map_err(|_| WriterTaken)?;
+        Ok(Self { tries, _not_sync: PhantomData })
+    }
+
+    /// The tries the handle writes to, which can be read meanwhile.
+    pub fn tries(&self) -> &StateTries {
+        &self.tries
+    }
+
+    /// Commits a block and returns the updated state root.
+    ///
+    /// Committing the same state diff again at an already committed height (ie: retrying after a
+    /// timeout) is a no-op which returns the stored state root. Committing a different state diff
+    /// at an already committed height is a [CommitError::Conflict].
+    pub fn commit(
+        &mut self,
+        csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, CommitError> {
+        try_update_state_root(&self.tries, csd, block_number, config)
+    }
+
+    /// Commits a block on behalf of the leader identified by `fencing_token`.
+    ///
+    /// See [commit](Self::commit). Commits carrying a token older than the most recent token seen
+    /// are rejected with [CommitError::Fenced], and once a token was seen, unfenced commits are
+    /// rejected with [CommitError::Unfenced]. The token only becomes the most recent one once the
+    /// block is committed. The token of the tries of an [engine](super::engine::CommitmentEngine)
+    /// is persisted along with the block, that of the node's database must be restored with
+    /// [RootRegistry::fence] on startup.
+    pub fn commit_fenced(
+        &mut self,
+        csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+        fencing_token: FencingToken,
+    ) -> Result<Felt252Wrapper, CommitError> {
+        try_update_state_root_fenced(&self.tries, csd, block_number, config, fencing_token)
+    }
+
+    /// Unwinds the tries to the state right after `block_number`, ie: to handle a chain reorg.
+    ///
+    /// See [RevertError] for the blocks which cannot be reverted to.
+    pub fn revert_to(&mut self, block_number: u64) -> Result<RevertReport, RevertError> {
+        revert_to(&self.tries, block_number)
+    }
+
+    /// Prunes the versions of the tries and the per-block data older than the
+    /// [horizon](PrunePolicy::horizon) of `policy`.
+    ///
+    /// The tries can no longer be reverted to the pruned blocks, nor their state read. The latest
+    /// state is always kept.
+    pub fn prune(&mut self, policy: &PrunePolicy) -> Result<PruneReport, TrieError> {
+        prune(&self.tries, policy)
+    }
+
+    /// A read handle pinned to the latest committed block.
+    pub fn reader(&self) -> Option<ReadHandle> {
+        ReadHandle::latest(&self.tries)
+    }
+}
+
+impl Drop for WriteHandle {
+    fn drop(&mut self) {
+        self.tries.state().writer.store(false, Ordering::Release);
+    }
+}
+
+/// A view of a set of [StateTries] pinned to the state root of a committed block.
+///
+/// Handles are cheap to clone and can be shared between threads. Every read checks that the block
+/// still has the pinned state root, so a read racing with a reorg fails with [ReadError::Stale]
+/// rather than mixing two states.
+#[derive(Debug, Clone)]
+pub struct ReadHandle {
+    tries: StateTries,
+    block_number: u64,
+    state_root: Felt252Wrapper,
+}
+
+impl ReadHandle {
+    /// Pins the latest block committed to `tries`, `None` if no block was committed.
+    pub fn latest(tries: &StateTries) -> Option<Self> {
+        let registry = root_registry(tries);
+        let (block_number, block) = registry.latest()?;
+        Some(Self { tries: tries.clone(), block_number, state_root: block.state_root })
+    }
+
+    /// Pins `block_number` of `tries`, `None` if it was not committed or was pruned.
+    pub fn at(tries: &StateTries, block_number: u64) -> Option<Self> {
+        let block = *root_registry(tries).get(block_number)?;
+        Some(Self { tries: tries.clone(), block_number, state_root: block.state_root })
+    }
+
+    pub fn block_number(&self) -> u64 {
+        self.block_number
+    }
+
+    pub fn state_root(&self) -> Felt252Wrapper {
+        self.state_root
+    }
+
+    /// Returns the value of a contract's storage slot, zero if it was never written.
+    pub fn storage_value(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<StarkFelt, ReadError> {
+        self.read(|| {
+            let Some(engine) = self.tries.engine() else {
+                return storage_value_at(contract_address, key, self.block_number);
+            };
+            if engine.latest() == Some(self.block_number) {
+                return engine.storage_value(contract_address, key).map(Some);
+            }
+            // The view is read without holding the engine, which keeps committing meanwhile
+            let view = engine.view_at(self.block_number)?;
+            drop(engine);
+            view.map(|view| view.storage_value(contract_address, key)).transpose()
+        })
+    }
+
+    /// Runs a read of the tries at the pinned block, checking the pin both before and after so
+    /// that a revert in between is detected.
+    fn read<T>(&self, read: impl FnOnce() -> Result<Option<T>, TrieError>) -> Result<T, ReadError> {
+        self.check(&root_registry(&self.tries))?;
+        let value = read()?.ok_or(ReadError::Pruned { block_number: self.block_number })?;
+        self.check(&root_registry(&self.tries))?;
+        Ok(value)
+    }
+
+    fn check(&self, registry: &RootRegistry) -> Result<(), ReadError> {
+        match registry.get(self.block_number) {
+            Some(block) if block.state_root == self.state_root => Ok(()),
+            Some(_) => Err(ReadError::Stale { block_number: self.block_number }),
+            None if registry.latest().is_some_and(|(latest, _)| latest > self.block_number) => {
+                Err(ReadError::Pruned { block_number: self.block_number })
+            }
+            None => Err(ReadError::Stale { block_number: self.block_number }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use starknet_api::core::PatriciaKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::stats::CommitStats;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_write_handle_is_exclusive() {
+        assert_send_sync::<ReadHandle>();
+        assert_send::<WriteHandle>();
+
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+        let writer = WriteHandle::acquire(tries.clone()).unwrap();
+        assert!(WriteHandle::acquire(tries.clone()).is_err());
+        // The tries of another chain have their own writer
+        assert!(WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).is_ok());
+        drop(writer);
+        assert!(WriteHandle::acquire(tries).is_ok());
+    }
+
+    #[test]
+    fn test_read_handle_is_pinned() {
+        let mut registry = RootRegistry::default();
+        let root = |root: u64| Felt252Wrapper::from(root);
+        registry.record(1, root(10), root(100), CommitStats::default());
+        registry.record(2, root(20), root(200), CommitStats::default());
+        let handle = ReadHandle { tries: StateTries::node_db(), block_number: 2, state_root: root(200) };
+        assert!(handle.check(&registry).is_ok());
+
+        // Block 2 reorged
+        registry.truncate(1);
+        assert!(matches!(handle.check(&registry), Err(ReadError::Stale { block_number: 2 })));
+        registry.record(2, root(21), root(201), CommitStats::default());
+        assert!(matches!(handle.check(&registry), Err(ReadError::Stale { block_number: 2 })));
+
+        // Block 1 pruned
+        let handle = ReadHandle { tries: StateTries::node_db(), block_number: 1, state_root: root(100) };
+        registry.prune_before(2);
+        assert!(matches!(handle.check(&registry), Err(ReadError::Pruned { block_number: 1 })));
+    }
+
+    #[test]
+    fn test_read_handle_reads_its_tries() {
+        let mut writer = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+        let key = StorageKey(PatriciaKey(StarkFelt::ONE));
+        let write = |value: u64| CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: Default::default(),
+            storage_updates: [(contract_address, [(key, StarkFelt::from(value))].into())].into_iter().collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let config = ChainConfig::default();
+        writer.commit(write(10), 1, &config).unwrap();
+        let first = writer.reader().unwrap();
+        writer.commit(write(20), 2, &config).unwrap();
+
+        assert_eq!(first.storage_value(&contract_address, &key).unwrap(), StarkFelt::from(10_u64));
+        assert_eq!(writer.reader().unwrap().storage_value(&contract_address, &key).unwrap(), StarkFelt::from(20_u64));
+        assert_eq!(ReadHandle::at(writer.tries(), 1).unwrap().state_root(), first.state_root());
+
+        // Block 2 reorged
+        let latest = writer.reader().unwrap();
+        writer.revert_to(1).unwrap();
+        assert!(matches!(latest.storage_value(&contract_address, &key), Err(ReadError::Stale { block_number: 2 })));
+    }
+}
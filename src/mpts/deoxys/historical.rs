@@ -0,0 +1,78 @@
+use mc_db::storage_handler;
+use mp_felt::Felt252Wrapper;
+use starknet_api::core::ContractAddress;
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+
+use super::atomic::Trie;
+use super::error::{ErrorContext, ResultExt, TrieError};
+use super::retry::Operation;
+use super::runtime::current_chain_config;
+
+/// Returns the state root right after `block_number`, read from the versioned commits of the
+/// contracts and classes tries instead of the [root registry](super::roots::root_registry), which
+/// does not survive restarts.
+///
+/// # Returns
+///
+/// `None` if `block_number` was not committed, or its trie versions were pruned.
+pub fn state_root_at(block_number: u64) -> Result<Option<Felt252Wrapper>, TrieError> {
+    let config = current_chain_config();
+    let context = |trie| move || ErrorContext::block(block_number).trie(trie);
+
+    let handler_contract = storage_handler::contract_trie();
+    let handler_class = storage_handler::class_trie();
+    let Some(contracts_trie_root) = config
+        .retry
+        .run(Operation::Read, || handler_contract.root_at(block_number))
+        .context(context(Trie::Contracts))?
+    else {
+        return Ok(None);
+    };
+    let Some(classes_trie_root) =
+        config.retry.run(Operation::Read, || handler_class.root_at(block_number)).context(context(Trie::Classes))?
+    else {
+        return Ok(None);
+    };
+
+    let (contracts_trie_root, classes_trie_root) = (contracts_trie_root.into(), classes_trie_root.into());
+    Ok(Some(config.state_root(contracts_trie_root, classes_trie_root)))
+}
+
+/// Returns the value of a contract's storage slot right after `block_number`, read from the
+/// versioned commits of the contract storage tries without replaying the state diffs since.
+///
+/// This is what `starknet_getStorageAt` needs to serve historical block ids. Slots which were never
+/// written are zero.
+///
+/// # Returns
+///
+/// `None` if `block_number` was not committed, or its trie versions were pruned.
+pub fn storage_value_at(
+    contract_address: &ContractAddress,
+    key: &StorageKey,
+    block_number: u64,
+) -> Result<Option<StarkFelt>, TrieError> {
+    let config = current_chain_config();
+    let context = |trie| move || ErrorContext::block(block_number).trie(trie);
+
+    // The contracts trie is committed with every block, it tells whether the block's versions exist
+    let handler_contract = storage_handler::contract_trie();
+    if config
+        .retry
+        .run(Operation::Read, || handler_contract.root_at(block_number))
+        .context(context(Trie::Contracts))?
+        .is_none()
+    {
+        return Ok(None);
+    }
+    drop(handler_contract);
+
+    let handler_storage_trie = storage_handler::contract_storage_trie();
+    let value = config
+        .retry
+        .run(Operation::Read, || handler_storage_trie.get_at(contract_address, key, block_number))
+        .context(|| context(Trie::ContractStorage)().contract(*contract_address).key(*key))?;
+
+    Ok(Some(value.map(|value| StarkFelt(value.to_bytes_be())).unwrap_or_default()))
+}
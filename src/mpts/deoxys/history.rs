@@ -0,0 +1,437 @@
+//! In-memory indexes of the changes made by committed blocks.
+//!
+//! The indexes are caches: they are lost on restart, unless handed over by a [standby](super::standby)
+//! export, and only hold the blocks recorded since, see [Coverage]. They are rebuilt by recording the
+//! stored state diffs of the blocks to cover. Queries outside of their coverage return `None` rather
+//! than a guess, so nothing which must be exact after a restart can rely on them.
+
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::MutexGuard;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use starknet_api::core::ContractAddress;
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+
+use super::engine::StateTries;
+
+/// The blocks whose changes an index holds: every block from the first one recorded, or from the
+/// horizon it was pruned to if later, up to the last one recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// The first block recorded, `None` if none was.
+    pub from: Option<u64>,
+    /// The block the index was pruned to, its changes before it are forgotten.
+    pub horizon: u64,
+    /// The last block recorded, `None` if none was.
+    pub to: Option<u64>,
+}
+
+impl Coverage {
+    /// Returns the first block the index answers for, `None` if it holds none.
+    pub fn first(&self) -> Option<u64> {
+        self.from.map(|from| from.max(self.horizon))
+    }
+
+    /// Whether the index answers for the state right after `block_number`.
+    pub fn contains(&self, block_number: u64) -> bool {
+        self.first().zip(self.to).is_some_and(|(first, to)| (first..=to).contains(&block_number))
+    }
+
+    /// Whether the index holds every change since genesis, ie: what it holds no change of never changed.
+    fn complete(&self) -> bool {
+        self.from == Some(0)
+    }
+
+    /// Extends the coverage to `block_number`.
+    ///
+    /// # Returns
+    ///
+    /// Whether the index can keep its changes. Otherwise blocks were not recorded since the last one,
+    /// ie: while the index was disabled, and it starts over from `block_number`.
+    fn record(&mut self, block_number: u64) -> bool {
+        let contiguous = self.to.map_or(true, |to| block_number <= to + 1);
+        if !contiguous {
+            self.from = Some(block_number);
+        }
+        self.from.get_or_insert(block_number);
+        self.to = Some(self.to.map_or(block_number, |to| to.max(block_number)));
+        contiguous
+    }
+
+    fn prune_before(&mut self, block_number: u64) {
+        self.horizon = self.horizon.max(block_number);
+    }
+
+    fn truncate_after(&mut self, block_number: u64) {
+        if self.from.is_some_and(|from| from > block_number) {
+            self.from = None;
+            self.to = None;
+        }
+        self.to = self.to.map(|to| to.min(block_number));
+    }
+}
+
+/// Per-slot change index of contract storage.
+///
+/// Each storage slot maps to the blocks it was written at along with the value written, in
+/// ascending block order. Historical lookups are a binary search over this index instead of a read
+/// of the trie at every height, which is what analytics queries need. Lookups only answer for the
+/// blocks of its [coverage](StorageHistory::coverage), the trie remains the source of truth.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageHistory {
+    pub(crate) changes: HashMap<(ContractAddress, StorageKey), Vec<(u64, StarkFelt)>>,
+    pub(crate) coverage: Coverage,
+}
+
+impl StorageHistory {
+    /// Records the storage writes of a committed block.
+    ///
+    /// Blocks must be recorded in ascending order, a block recorded again replaces its writes. Every
+    /// block must be recorded, the index starts over after a skipped block.
+    pub fn record(&mut self, block_number: u64, csd: &CommitmentStateDiff) {
+        if !self.coverage.record(block_number) {
+            self.changes.clear();
+        }
+        for (contract_address, updates) in csd.storage_updates.iter() {
+            for (key, value) in updates {
+                let changes = self.changes.entry((*contract_address, *key)).or_default();
+                match changes.last_mut() {
+                    Some((last, last_value)) if *last == block_number => *last_value = *value,
+                    _ => changes.push((block_number, *value)),
+                }
+            }
+        }
+    }
+
+    /// Forgets the writes made before `block_number`, except the last one of each slot which is still
+    /// its value at `block_number`.
+    ///
+    /// # Returns
+    ///
+    /// The number of writes forgotten.
+    pub fn prune_before(&mut self, block_number: u64) -> usize {
+        self.coverage.prune_before(block_number);
+        let mut pruned = 0;
+        for changes in self.changes.values_mut() {
+            let stale = changes.partition_point(|(block, _)| *block < block_number).saturating_sub(1);
+            changes.drain(..stale);
+            pruned += stale;
+        }
+        pruned
+    }
+
+    /// Forgets the writes made after `block_number`, once the tries were reverted to it.
+    pub fn truncate_after(&mut self, block_number: u64) {
+        self.coverage.truncate_after(block_number);
+        self.changes.retain(|_, changes| {
+            changes.truncate(changes.partition_point(|(block, _)| *block <= block_number));
+            !changes.is_empty()
+        });
+    }
+
+    /// Returns the blocks the index holds the writes of.
+    pub fn coverage(&self) -> Coverage {
+        self.coverage
+    }
+
+    /// Returns the blocks of the [coverage](StorageHistory::coverage) at which a storage slot was
+    /// written, in ascending order.
+    pub fn changed_at(&self, contract_address: &ContractAddress, key: &StorageKey) -> Vec<u64> {
+        self.changes
+            .get(&(*contract_address, *key))
+            .map(|changes| changes.iter().map(|(block, _)| *block).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of a storage slot right after `block_number`, zero if it was never written.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the index does not know the value: `block_number` is outside of its coverage, or
+    /// the slot was not written since the start of its coverage, which is not genesis.
+    pub fn value_as_of(
+        &self,
+        contract_address: &ContractAddress,
+        key: &StorageKey,
+        block_number: u64,
+    ) -> Option<StarkFelt> {
+        if !self.coverage.contains(block_number) {
+            return None;
+        }
+        let changes = self.changes.get(&(*contract_address, *key)).map(Vec::as_slice).unwrap_or_default();
+        match changes.partition_point(|(block, _)| *block <= block_number) {
+            0 => self.coverage.complete().then_some(StarkFelt::ZERO),
+            index => Some(changes[index - 1].1),
+        }
+    }
+
+    /// Returns the writes to a storage slot within a range of blocks, in ascending block order, up to
+    /// the last block recorded.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the range starts outside of the coverage of the index.
+    pub fn value_history(
+        &self,
+        contract_address: &ContractAddress,
+        key: &StorageKey,
+        range: impl RangeBounds<u64>,
+    ) -> Option<Vec<(u64, StarkFelt)>> {
+        if !self.coverage.contains(range_start(&range)) {
+            return None;
+        }
+        let Some(changes) = self.changes.get(&(*contract_address, *key)) else {
+            return Some(Vec::new());
+        };
+        let start = changes.partition_point(|(block, _)| *block < range_start(&range));
+        Some(changes[start..].iter().take_while(|(block, _)| range.contains(block)).copied().collect())
+    }
+}
+
+/// Returns the first block of a range.
+fn range_start(range: &impl RangeBounds<u64>) -> u64 {
+    match range.start_bound() {
+        Bound::Included(start) => *start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    }
+}
+
+/// Per-contract activity index: the blocks at which the leaf of each contract changed, ie: when its
+/// storage, nonce or class hash was updated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContractActivity {
+    pub(crate) blocks: HashMap<ContractAddress, Vec<u64>>,
+    pub(crate) coverage: Coverage,
+}
+
+impl ContractActivity {
+    /// Records the contracts touched by a committed block.
+    ///
+    /// Blocks must be recorded in ascending order. Every block must be recorded, the index starts over
+    /// after a skipped block.
+    pub fn record(&mut self, block_number: u64, csd: &CommitmentStateDiff) {
+        if !self.coverage.record(block_number) {
+            self.blocks.clear();
+        }
+        let contract_addresses =
+            csd.storage_updates.keys().chain(csd.address_to_class_hash.keys()).chain(csd.address_to_nonce.keys());
+        for contract_address in contract_addresses {
+            let blocks = self.blocks.entry(*contract_address).or_default();
+            if blocks.last() != Some(&block_number) {
+                blocks.push(block_number);
+            }
+        }
+    }
+
+    /// Forgets the changes made before `block_number`, except the last one of each contract.
+    ///
+    /// # Returns
+    ///
+    /// The number of changes forgotten.
+    pub fn prune_before(&mut self, block_number: u64) -> usize {
+        self.coverage.prune_before(block_number);
+        let mut pruned = 0;
+        for blocks in self.blocks.values_mut() {
+            let stale = blocks.partition_point(|block| *block < block_number).saturating_sub(1);
+            blocks.drain(..stale);
+            pruned += stale;
+        }
+        pruned
+    }
+
+    /// Forgets the changes made after `block_number`, once the tries were reverted to it.
+    pub fn truncate_after(&mut self, block_number: u64) {
+        self.coverage.truncate_after(block_number);
+        self.blocks.retain(|_, blocks| {
+            blocks.truncate(blocks.partition_point(|block| *block <= block_number));
+            !blocks.is_empty()
+        });
+    }
+
+    /// Returns the blocks the index holds the changes of.
+    pub fn coverage(&self) -> Coverage {
+        self.coverage
+    }
+
+    /// Returns the last block at which the contract's leaf changed, among the blocks covered by the
+    /// index: `None` if it did not change since the start of its coverage.
+    pub fn last_changed(&self, contract_address: &ContractAddress) -> Option<u64> {
+        self.blocks.get(contract_address).and_then(|blocks| blocks.last().copied())
+    }
+
+    /// Returns the `count` contracts which changed in the most blocks covered by the index, most
+    /// active first.
+    pub fn most_active(&self, count: usize) -> Vec<ContractAddress> {
+        let mut contracts = self.blocks.iter().map(|(address, blocks)| (blocks.len(), *address)).collect::<Vec<_>>();
+        contracts.sort_unstable_by(|a, b| b.cmp(a));
+        contracts.into_iter().take(count).map(|(_, address)| address).collect()
+    }
+
+    /// Returns the blocks within a range at which the contract's leaf changed, in ascending order, up
+    /// to the last block recorded.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the range starts outside of the coverage of the index.
+    pub fn changed_at(&self, contract_address: &ContractAddress, range: impl RangeBounds<u64>) -> Option<Vec<u64>> {
+        if !self.coverage.contains(range_start(&range)) {
+            return None;
+        }
+        let Some(blocks) = self.blocks.get(contract_address) else {
+            return Some(Vec::new());
+        };
+        let start = blocks.partition_point(|block| *block < range_start(&range));
+        Some(blocks[start..].iter().take_while(|block| range.contains(block)).copied().collect())
+    }
+}
+
+/// Returns the [StorageHistory] of `tries`, maintained on each commit when
+/// [ChainConfig::index_storage_writes](super::config::ChainConfig::index_storage_writes) is set.
+pub fn storage_history(tries: &StateTries) -> MutexGuard<'_, StorageHistory> {
+    tries.state().storage_history.lock().expect("Poisoned lock on storage history")
+}
+
+/// Returns the [ContractActivity] of `tries`, maintained on each commit when
+/// [ChainConfig::index_contract_activity](super::config::ChainConfig::index_contract_activity) is set.
+pub fn contract_activity(tries: &StateTries) -> MutexGuard<'_, ContractActivity> {
+    tries.state().contract_activity.lock().expect("Poisoned lock on contract activity")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::RangeInclusive;
+
+    use indexmap::IndexMap;
+    use starknet_api::core::PatriciaKey;
+
+    use super::*;
+
+    fn csd(writes: &[(u64, u64)]) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let updates = writes
+            .iter()
+            .map(|(key, value)| (StorageKey(PatriciaKey(StarkFelt::from(*key))), StarkFelt::from(*value)))
+            .collect();
+        CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(contract_address, updates)].into_iter().collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    /// Records `blocks`, with the storage writes of `writes` and none otherwise.
+    fn record(history: &mut StorageHistory, blocks: RangeInclusive<u64>, writes: &[(u64, &[(u64, u64)])]) {
+        for block_number in blocks {
+            let block_writes =
+                writes.iter().find(|(block, _)| *block == block_number).map_or(&[][..], |(_, writes)| writes);
+            history.record(block_number, &csd(block_writes));
+        }
+    }
+
+    #[test]
+    fn test_value_as_of() {
+        let mut history = StorageHistory::default();
+        record(&mut history, 2..=9, &[(2, &[(1, 10)]), (5, &[(1, 50), (2, 7)]), (9, &[(1, 0)])]);
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::ONE));
+        let other_key = StorageKey(PatriciaKey(StarkFelt::TWO));
+        let value_as_of = |history: &StorageHistory, key, block| history.value_as_of(&contract_address, key, block);
+
+        // The index only covers the blocks since the first one recorded
+        assert_eq!(value_as_of(&history, &key, 1), None);
+        assert_eq!(value_as_of(&history, &key, 2), Some(StarkFelt::from(10_u64)));
+        assert_eq!(value_as_of(&history, &key, 8), Some(StarkFelt::from(50_u64)));
+        assert_eq!(value_as_of(&history, &key, 9), Some(StarkFelt::ZERO));
+        // A slot not written since may hold any value, unless the index covers every block
+        assert_eq!(value_as_of(&history, &other_key, 3), None);
+        assert_eq!(value_as_of(&history, &other_key, 5), Some(StarkFelt::from(7_u64)));
+
+        assert_eq!(
+            history.value_history(&contract_address, &key, 3..=9),
+            Some(vec![(5, StarkFelt::from(50_u64)), (9, StarkFelt::ZERO)])
+        );
+        assert_eq!(history.value_history(&contract_address, &key, 2..5), Some(vec![(2, StarkFelt::from(10_u64))]));
+        assert_eq!(history.value_history(&contract_address, &key, ..5), None);
+        assert_eq!(history.changed_at(&contract_address, &key), vec![2, 5, 9]);
+
+        history.truncate_after(5);
+        assert_eq!(value_as_of(&history, &key, 5), Some(StarkFelt::from(50_u64)));
+        assert_eq!(value_as_of(&history, &key, 9), None);
+        assert_eq!(history.changed_at(&contract_address, &key), vec![2, 5]);
+        history.truncate_after(1);
+        assert_eq!(history.changed_at(&contract_address, &key), Vec::<u64>::new());
+        assert_eq!(history.coverage(), Coverage::default());
+    }
+
+    #[test]
+    fn test_complete_history() {
+        let mut history = StorageHistory::default();
+        record(&mut history, 0..=6, &[(0, &[(1, 10)]), (3, &[(1, 30)]), (6, &[(1, 60)])]);
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::ONE));
+        let other_key = StorageKey(PatriciaKey(StarkFelt::TWO));
+        assert_eq!(history.value_as_of(&contract_address, &other_key, 4), Some(StarkFelt::ZERO));
+        assert_eq!(history.value_history(&contract_address, &key, ..).unwrap().len(), 3);
+
+        // Pruning keeps the value of each slot at the horizon, but forgets the blocks before it
+        assert_eq!(history.prune_before(4), 1);
+        assert_eq!(history.coverage().first(), Some(4));
+        assert_eq!(history.value_as_of(&contract_address, &key, 2), None);
+        assert_eq!(history.value_as_of(&contract_address, &key, 4), Some(StarkFelt::from(30_u64)));
+        assert_eq!(history.value_as_of(&contract_address, &other_key, 4), Some(StarkFelt::ZERO));
+    }
+
+    #[test]
+    fn test_skipped_blocks() {
+        let mut history = StorageHistory::default();
+        record(&mut history, 0..=3, &[(1, &[(1, 10)])]);
+
+        // The writes of blocks 4 and 5 are unknown, so are the values of every slot from there
+        history.record(6, &csd(&[(2, 20)]));
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::ONE));
+        assert_eq!(history.coverage(), Coverage { from: Some(6), horizon: 0, to: Some(6) });
+        assert_eq!(history.value_as_of(&contract_address, &key, 3), None);
+        assert_eq!(history.value_as_of(&contract_address, &key, 6), None);
+        assert_eq!(history.changed_at(&contract_address, &key), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_contract_activity() {
+        let mut activity = ContractActivity::default();
+        let mut nonce_update = csd(&[]);
+        nonce_update.storage_updates.clear();
+        nonce_update.address_to_nonce.insert(ContractAddress(PatriciaKey(StarkFelt::ONE)), Default::default());
+
+        let mut untouched = csd(&[]);
+        untouched.storage_updates.clear();
+        for block_number in 2..=7 {
+            match block_number {
+                2 => activity.record(2, &csd(&[(1, 10)])),
+                4 => activity.record(4, &nonce_update),
+                7 => activity.record(7, &csd(&[(1, 10), (2, 20)])),
+                _ => activity.record(block_number, &untouched),
+            }
+        }
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        assert_eq!(activity.last_changed(&contract_address), Some(7));
+        assert_eq!(activity.changed_at(&contract_address, 3..), Some(vec![4, 7]));
+        assert_eq!(activity.changed_at(&contract_address, 1..), None);
+        assert_eq!(activity.changed_at(&contract_address, 8..), None);
+        assert_eq!(activity.last_changed(&ContractAddress(PatriciaKey(StarkFelt::TWO))), None);
+        assert_eq!(activity.most_active(5), vec![contract_address]);
+
+        // The index starts over after skipped blocks
+        activity.record(9, &untouched);
+        assert_eq!(activity.coverage(), Coverage { from: Some(9), horizon: 0, to: Some(9) });
+        assert_eq!(activity.last_changed(&contract_address), None);
+        assert_eq!(activity.changed_at(&contract_address, 9..), Some(Vec::new()));
+    }
+}
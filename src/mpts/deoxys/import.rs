@@ -0,0 +1,354 @@
+//! Migration of existing node databases into this crate's backend.
+//!
+//! The state of the node is replayed block by block from a [StateSource], each state root being
+//! checked against the one the node committed to. Pathfinder databases are read directly
+//! ([PathfinderSource]). Juno stores its state in Pebble, which has no Rust reader: juno nodes are
+//! replayed from the state updates served by their JSON-RPC API instead ([StateUpdateDump]).
+
+use std::path::PathBuf;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+#[cfg(feature = "pedersen")]
+use indexmap::IndexMap;
+use mp_felt::Felt252Wrapper;
+#[cfg(feature = "pedersen")]
+use pathfinder_common::{BlockId, BlockNumber};
+#[cfg(feature = "pedersen")]
+use pathfinder_crypto::Felt;
+#[cfg(feature = "pedersen")]
+use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce, PatriciaKey};
+#[cfg(feature = "pedersen")]
+use starknet_api::hash::StarkFelt;
+#[cfg(feature = "pedersen")]
+use starknet_api::state::StorageKey;
+use starknet_core::types::StateUpdate;
+
+use super::atomic::latest_block_since;
+#[cfg(feature = "pedersen")]
+use super::canonical::Canonicalize;
+use super::config::ChainConfig;
+use super::engine::StateTries;
+use super::error::{CommitError, DiffError, TrieError};
+use super::handles::WriteHandle;
+use super::lib::build_commitment_state_diff;
+use super::reorg::RevertError;
+use super::roots::root_registry;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[cfg(feature = "pedersen")]
+    #[error("failed to read the pathfinder database: {0}")]
+    Pathfinder(#[from] anyhow::Error),
+    #[error("failed to read the state update dump {path:?}: {error}")]
+    Dump { path: PathBuf, error: String },
+    #[error("block {0} is missing from the source")]
+    MissingBlock(u64),
+    #[error("invalid state diff of block {block_number}: {error}")]
+    Diff { block_number: u64, error: DiffError },
+    #[error(transparent)]
+    Commit(#[from] CommitError),
+    #[error("imported state root {computed:?} of block {block_number} does not match the source's {expected:?}")]
+    RootMismatch { block_number: u64, expected: Felt252Wrapper, computed: Felt252Wrapper },
+    #[error("failed to read the latest imported block: {0}")]
+    Trie(#[from] TrieError),
+    #[error("failed to revert the mismatching block: {0}")]
+    Revert(#[from] RevertError),
+}
+
+/// A node whose state is replayed by [import].
+pub trait StateSource {
+    /// The latest block of the node, `None` if it has none.
+    fn latest_block(&self) -> Result<Option<u64>, ImportError>;
+
+    /// The state diff of a block, along with the state root the node committed to for it.
+    fn state_update(&self, block_number: u64) -> Result<(CommitmentStateDiff, Felt252Wrapper), ImportError>;
+}
+
+/// The state of a pathfinder database, read through one of its transactions.
+#[cfg(feature = "pedersen")]
+pub struct PathfinderSource<'a, 'tx>(pub &'a pathfinder_storage::Transaction<'tx>);
+
+#[cfg(feature = "pedersen")]
+impl StateSource for PathfinderSource<'_, '_> {
+    fn latest_block(&self) -> Result<Option<u64>, ImportError> {
+        Ok(self.0.block_id(BlockId::Latest)?.map(|(latest, _)| latest.get()))
+    }
+
+    fn state_update(&self, block_number: u64) -> Result<(CommitmentStateDiff, Felt252Wrapper), ImportError> {
+        let block = BlockId::Number(BlockNumber::new_or_panic(block_number));
+        let state_update = self.0.state_update(block)?.ok_or(ImportError::MissingBlock(block_number))?;
+        let header = self.0.block_header(block)?.ok_or(ImportError::MissingBlock(block_number))?;
+        Ok((pathfinder_state_diff(&state_update), Felt252Wrapper::from(stark_felt(&header.state_commitment.0))))
+    }
+}
+
+/// State updates as returned by `starknet_getStateUpdate`, one `<block number>.json` file per block
+/// in a directory, ie: dumped from the JSON-RPC API of a juno node:
+///
+/// ```text
+/// curl -s $JUNO_RPC -H 'Content-Type: application/json' \
+///     -d '{"jsonrpc":"2.0","id":1,"method":"starknet_getStateUpdate","params":[{"block_number":700000}]}' \
+///     | jq .result > dump/700000.json
+/// ```
+#[derive(Debug, Clone)]
+pub struct StateUpdateDump {
+    dir: PathBuf,
+}
+
+impl StateUpdateDump {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn error(&self, path: impl Into<PathBuf>, error: impl ToString) -> ImportError {
+        ImportError::Dump { path: path.into(), error: error.to_string() }
+    }
+}
+
+impl StateSource for StateUpdateDump {
+    fn latest_block(&self) -> Result<Option<u64>, ImportError> {
+        let entries = std::fs::read_dir(&self.dir).map_err(|e| self.error(&self.dir, e))?;
+        let mut latest = None;
+        for entry in entries {
+            let path = entry.map_err(|e| self.error(&self.dir, e))?.path();
+            let block_number = path
+                .extension()
+                .filter(|extension| *extension == "json")
+                .and_then(|_| path.file_stem()?.to_str()?.parse::<u64>().ok());
+            latest = latest.max(block_number);
+        }
+        Ok(latest)
+    }
+
+    fn state_update(&self, block_number: u64) -> Result<(CommitmentStateDiff, Felt252Wrapper), ImportError> {
+        let path = self.dir.join(format!("{block_number}.json"));
+        let content = match std::fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(ImportError::MissingBlock(block_number)),
+            Err(e) => return Err(self.error(path, e)),
+        };
+        let state_update = serde_json::from_slice::<StateUpdate>(&content).map_err(|e| self.error(&path, e))?;
+        let csd =
+            build_commitment_state_diff(&state_update).map_err(|error| ImportError::Diff { block_number, error })?;
+        Ok((csd, Felt252Wrapper::from(state_update.new_root)))
+    }
+}
+
+#[cfg(feature = "pedersen")]
+fn stark_felt(felt: &Felt) -> StarkFelt {
+    StarkFelt(felt.to_be_bytes())
+}
+
+#[cfg(feature = "pedersen")]
+fn contract_address(felt: &Felt) -> ContractAddress {
+    ContractAddress(PatriciaKey(stark_felt(felt)))
+}
+
+/// Converts a pathfinder state update into a [CommitmentStateDiff].
+#[cfg(feature = "pedersen")]
+pub fn pathfinder_state_diff(state_update: &pathfinder_common::StateUpdate) -> CommitmentStateDiff {
+    let mut csd = CommitmentStateDiff {
+        address_to_class_hash: IndexMap::new(),
+        address_to_nonce: IndexMap::new(),
+        storage_updates: IndexMap::new(),
+        class_hash_to_compiled_class_hash: IndexMap::new(),
+    };
+
+    for (address, update) in state_update.contract_updates.iter() {
+        let address = contract_address(&address.0);
+        if let Some(class) = &update.class {
+            csd.address_to_class_hash.insert(address, ClassHash(stark_felt(&class.class_hash().0)));
+        }
+        if let Some(nonce) = &update.nonce {
+            csd.address_to_nonce.insert(address, Nonce(stark_felt(&nonce.0)));
+        }
+        if !update.storage.is_empty() {
+            let storage = update
+                .storage
+                .iter()
+                .map(|(key, value)| (StorageKey(PatriciaKey(stark_felt(&key.0))), stark_felt(&value.0)));
+            csd.storage_updates.insert(address, storage.collect());
+        }
+    }
+
+    for (address, update) in state_update.system_contract_updates.iter() {
+        let storage = update
+            .storage
+            .iter()
+            .map(|(key, value)| (StorageKey(PatriciaKey(stark_felt(&key.0))), stark_felt(&value.0)));
+        csd.storage_updates.entry(contract_address(&address.0)).or_default().extend(storage);
+    }
+
+    // Cairo 0 classes are not part of the classes trie
+    for (sierra_hash, casm_hash) in state_update.declared_sierra_classes.iter() {
+        csd.class_hash_to_compiled_class_hash
+            .insert(ClassHash(stark_felt(&sierra_hash.0)), CompiledClassHash(stark_felt(&casm_hash.0)));
+    }
+
+    // pathfinder stores updates in hash maps
+    csd.canonicalize();
+    csd
+}
+
+/// The block after the latest one committed to the tries, from which an interrupted import resumes.
+fn resume_from(tries: &StateTries) -> Result<u64, ImportError> {
+    if let Some(engine) = tries.engine() {
+        return Ok(engine.latest().map_or(0, |latest| latest + 1));
+    }
+    // The tries of the node's database are walked from the latest block the registry knows about
+    let known = root_registry(tries).latest().map_or(0, |(block_number, _)| block_number);
+    Ok(latest_block_since(tries, known)?.map_or(0, |latest| latest + 1))
+}
+
+/// Imports the state of a node, replaying its blocks one by one.
+///
+/// Each block's state diff is applied to the tries and the resulting state root is checked against
+/// the state root of the source, so the import stops at the first divergence. The diverging block
+/// is reverted (unless it is the genesis block, which has no state to revert to), so that the import
+/// resumes at it once the source is fixed.
+///
+/// # Arguments
+///
+/// * `writer`   - The write handle of the tries to import the state into.
+/// * `source`   - The node to import.
+/// * `from`     - The first block to import, `None` to resume after the latest committed block.
+/// * `config`   - Chain-specific commitment rules.
+/// * `progress` - Called with each imported block number and state root.
+///
+/// # Returns
+///
+/// The number of the last imported block, `None` if there was nothing to import.
+pub fn import(
+    writer: &mut WriteHandle,
+    source: &impl StateSource,
+    from: Option<u64>,
+    config: &ChainConfig,
+    mut progress: impl FnMut(u64, Felt252Wrapper),
+) -> Result<Option<u64>, ImportError> {
+    let Some(latest) = source.latest_block()? else {
+        return Ok(None);
+    };
+    let from = match from {
+        Some(from) => from,
+        None => resume_from(writer.tries())?,
+    };
+    if from > latest {
+        return Ok(None);
+    }
+
+    for block_number in from..=latest {
+        let (csd, expected) = source.state_update(block_number)?;
+        let computed = writer.commit(csd, block_number, config)?;
+        if computed != expected {
+            if block_number > 0 {
+                writer.revert_to(block_number - 1)?;
+            }
+            return Err(ImportError::RootMismatch { block_number, expected, computed });
+        }
+
+        progress(block_number, computed);
+    }
+
+    Ok(Some(latest))
+}
+
+/// Imports the state of a pathfinder database, see [import].
+#[cfg(feature = "pedersen")]
+pub fn import_pathfinder(
+    writer: &mut WriteHandle,
+    transaction: &pathfinder_storage::Transaction<'_>,
+    from: Option<u64>,
+    config: &ChainConfig,
+    progress: impl FnMut(u64, Felt252Wrapper),
+) -> Result<Option<u64>, ImportError> {
+    import(writer, &PathfinderSource(transaction), from, config, progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use starknet_core::types::{ContractStorageDiffItem, DeployedContractItem, StateDiff, StorageEntry};
+    use starknet_ff::FieldElement;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::engine::CommitmentEngine;
+
+    fn felt(n: u64) -> FieldElement {
+        FieldElement::from(n)
+    }
+
+    fn state_update(block_number: u64) -> StateUpdate {
+        StateUpdate {
+            block_hash: felt(block_number),
+            new_root: FieldElement::ZERO,
+            old_root: FieldElement::ZERO,
+            state_diff: StateDiff {
+                storage_diffs: vec![ContractStorageDiffItem {
+                    address: felt(0x11),
+                    storage_entries: vec![StorageEntry { key: felt(block_number + 1), value: felt(0x2a) }],
+                }],
+                deprecated_declared_classes: vec![],
+                declared_classes: vec![],
+                deployed_contracts: if block_number == 0 {
+                    vec![DeployedContractItem { address: felt(0x11), class_hash: felt(0x10) }]
+                } else {
+                    vec![]
+                },
+                replaced_classes: vec![],
+                nonces: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_import_state_update_dump() {
+        let dir = std::env::temp_dir().join(format!("starkroot-import-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // The roots the node committed to, from a separate engine
+        let config = ChainConfig::default();
+        let mut node = CommitmentEngine::with_hashers(Arc::new(MemoryBackend::new()), config.hashers).unwrap();
+        let mut state_updates = (0..2).map(state_update).collect::<Vec<_>>();
+        for (block_number, state_update) in state_updates.iter_mut().enumerate() {
+            let csd = build_commitment_state_diff(state_update).unwrap();
+            state_update.new_root = node.update_state_root(csd, block_number as u64, &config).unwrap().into();
+        }
+        let dump = |block_number: u64, state_update: &StateUpdate| {
+            std::fs::write(dir.join(format!("{block_number}.json")), serde_json::to_vec(state_update).unwrap())
+                .unwrap();
+        };
+
+        let mut writer = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+        let source = StateUpdateDump::new(&dir);
+        let mut imported = Vec::new();
+        dump(0, &state_updates[0]);
+        assert_eq!(
+            import(&mut writer, &source, None, &config, |block_number, _| imported.push(block_number)).unwrap(),
+            Some(0)
+        );
+
+        // A diverging block is reverted, and imported again once the source is fixed
+        let mut diverging = state_updates[1].clone();
+        diverging.new_root = FieldElement::ONE;
+        dump(1, &diverging);
+        assert!(matches!(
+            import(&mut writer, &source, None, &config, |block_number, _| imported.push(block_number)),
+            Err(ImportError::RootMismatch { block_number: 1, .. })
+        ));
+        assert_eq!(writer.tries().engine().unwrap().latest(), Some(0));
+        dump(1, &state_updates[1]);
+        assert_eq!(
+            import(&mut writer, &source, None, &config, |block_number, _| imported.push(block_number)).unwrap(),
+            Some(1)
+        );
+        assert_eq!(imported, vec![0, 1]);
+        assert_eq!(import(&mut writer, &source, None, &config, |_, _| unreachable!()).unwrap(), None);
+
+        std::fs::remove_file(dir.join("0.json")).unwrap();
+        assert!(matches!(import(&mut writer, &source, Some(0), &config, |_, _| {}), Err(ImportError::MissingBlock(0))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// Lanes of the [IngestionQueue], in decreasing priority order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lane {
+    /// Reorg notifications, handled before anything else.
+    Reorg,
+    /// Updates at the tip of the chain.
+    Pending,
+    /// Finalized blocks, ie: historical blocks during sync.
+    Finalized,
+}
+
+impl Lane {
+    const ALL: [Lane; 3] = [Lane::Reorg, Lane::Pending, Lane::Finalized];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Bounded capacity of each lane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneCapacities {
+    pub reorg: usize,
+    pub pending: usize,
+    pub finalized: usize,
+}
+
+impl Default for LaneCapacities {
+    fn default() -> Self {
+        Self { reorg: 16, pending: 64, finalized: 256 }
+    }
+}
+
+impl LaneCapacities {
+    fn get(&self, lane: Lane) -> usize {
+        match lane {
+            Lane::Reorg => self.reorg,
+            Lane::Pending => self.pending,
+            Lane::Finalized => self.finalized,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PushError<T> {
+    #[error("{0:?} lane is full")]
+    Full(Lane, T),
+    #[error("ingestion queue is closed")]
+    Closed(T),
+}
+
+struct Lanes<T> {
+    lanes: [VecDeque<T>; 3],
+    closed: bool,
+}
+
+/// Ingestion queue where items travel on separate bounded lanes.
+///
+/// Items are always popped from the highest priority non-empty lane, so that a burst of historical
+/// blocks can neither starve the tip nor delay reorg handling. Each lane is bounded on its own:
+/// producers of a full lane are blocked (or rejected, see [IngestionQueue::try_push]) without
+/// affecting the other lanes.
+pub struct IngestionQueue<T> {
+    capacities: LaneCapacities,
+    lanes: Mutex<Lanes<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl<T> IngestionQueue<T> {
+    pub fn new(capacities: LaneCapacities) -> Self {
+        Self {
+            capacities,
+            lanes: Mutex::new(Lanes { lanes: Default::default(), closed: false }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lanes<T>> {
+        self.lanes.lock().expect("Poisoned lock on ingestion queue")
+    }
+
+    /// Pushes an item, blocking while its lane is full.
+    pub fn push(&self, lane: Lane, item: T) -> Result<(), PushError<T>> {
+        let capacity = self.capacities.get(lane);
+        let mut lanes = self.lock();
+        while !lanes.closed && lanes.lanes[lane.index()].len() >= capacity {
+            lanes = self.not_full.wait(lanes).expect("Poisoned lock on ingestion queue");
+        }
+        if lanes.closed {
+            return Err(PushError::Closed(item));
+        }
+
+        lanes.lanes[lane.index()].push_back(item);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Pushes an item, failing immediately if its lane is full.
+    pub fn try_push(&self, lane: Lane, item: T) -> Result<(), PushError<T>> {
+        let mut lanes = self.lock();
+        if lanes.closed {
+            return Err(PushError::Closed(item));
+        }
+        if lanes.lanes[lane.index()].len() >= self.capacities.get(lane) {
+            return Err(PushError::Full(lane, item));
+        }
+
+        lanes.lanes[lane.index()].push_back(item);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    fn pop_highest(lanes: &mut Lanes<T>) -> Option<(Lane, T)> {
+        Lane::ALL.into_iter().find_map(|lane| lanes.lanes[lane.index()].pop_front().map(|item| (lane, item)))
+    }
+
+    /// Pops the next item by priority, blocking while the queue is empty.
+    ///
+    /// # Returns
+    ///
+    /// `None` once the queue is closed and drained.
+    pub fn pop(&self) -> Option<(Lane, T)> {
+        let mut lanes = self.lock();
+        loop {
+            if let Some(item) = Self::pop_highest(&mut lanes) {
+                // producers of any lane may be waiting
+                self.not_full.notify_all();
+                return Some(item);
+            }
+            if lanes.closed {
+                return None;
+            }
+            lanes = self.not_empty.wait(lanes).expect("Poisoned lock on ingestion queue");
+        }
+    }
+
+    /// Pops the next item by priority, if any.
+    pub fn try_pop(&self) -> Option<(Lane, T)> {
+        let item = Self::pop_highest(&mut self.lock());
+        if item.is_some() {
+            self.not_full.notify_all();
+        }
+        item
+    }
+
+    /// Number of items waiting in a lane.
+    pub fn len(&self, lane: Lane) -> usize {
+        self.lock().lanes[lane.index()].len()
+    }
+
+    /// Closes the queue: producers are rejected and consumers drain the remaining items.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_lanes() {
+        let queue = IngestionQueue::new(LaneCapacities { reorg: 1, pending: 1, finalized: 2 });
+
+        queue.try_push(Lane::Finalized, 1).unwrap();
+        queue.try_push(Lane::Finalized, 2).unwrap();
+        assert_eq!(queue.try_push(Lane::Finalized, 3), Err(PushError::Full(Lane::Finalized, 3)));
+        // a full finalized lane does not block the tip
+        queue.try_push(Lane::Pending, 10).unwrap();
+        queue.try_push(Lane::Reorg, 100).unwrap();
+
+        assert_eq!(queue.try_pop(), Some((Lane::Reorg, 100)));
+        assert_eq!(queue.try_pop(), Some((Lane::Pending, 10)));
+        assert_eq!(queue.try_pop(), Some((Lane::Finalized, 1)));
+
+        queue.close();
+        assert_eq!(queue.try_push(Lane::Pending, 11), Err(PushError::Closed(11)));
+        assert_eq!(queue.pop(), Some((Lane::Finalized, 2)));
+        assert_eq!(queue.pop(), None);
+    }
+}
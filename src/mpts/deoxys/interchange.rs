@@ -0,0 +1,201 @@
+//! Neutral state interchange format.
+//!
+//! A full state is represented as the [CommitmentStateDiff] which takes an empty state to it, so
+//! that it can be re-applied by any client. The format is versioned, chunked and checksummed, and
+//! only uses fixed-width big-endian fields so that it can be read without this crate. The file is
+//! [framed](super::framing) with the magic `SRIF`, its chunks being:
+//!
+//! ```text
+//! kind 0x01  class hashes       entry := contract_address:felt class_hash:felt
+//! kind 0x02  nonces             entry := contract_address:felt nonce:felt
+//! kind 0x03  storage            entry := contract_address:felt key:felt value:felt
+//! kind 0x04  declared classes   entry := class_hash:felt compiled_class_hash:felt
+//! ```
+
+use std::io::{self, Read, Write};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use indexmap::IndexMap;
+use mp_felt::Felt252Wrapper;
+use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+
+use super::framing::{self, ChunkReader, Format, FramingError, CHUNK_ENTRIES};
+
+pub const MAGIC: &[u8; 4] = b"SRIF";
+pub const VERSION: u16 = 1;
+
+const FORMAT: Format = Format { name: "state interchange", magic: MAGIC, version: VERSION };
+
+const CLASS_HASHES: u8 = 0x01;
+const NONCES: u8 = 0x02;
+const STORAGE: u8 = 0x03;
+const DECLARED_CLASSES: u8 = 0x04;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InterchangeError {
+    #[error(transparent)]
+    Framing(#[from] FramingError),
+    #[error("unknown chunk kind {0:#x}")]
+    UnknownChunk(u8),
+}
+
+impl From<io::Error> for InterchangeError {
+    fn from(error: io::Error) -> Self {
+        Self::Framing(error.into())
+    }
+}
+
+/// A full state, as exchanged between clients.
+#[derive(Debug)]
+pub struct StateSnapshot {
+    pub block_number: u64,
+    /// The state root the snapshot must produce once applied.
+    pub state_root: Felt252Wrapper,
+    pub state: CommitmentStateDiff,
+}
+
+fn write_section<W: Write>(writer: &mut W, kind: u8, entries: impl Iterator<Item = Vec<StarkFelt>>) -> io::Result<()> {
+    let entries = entries.collect::<Vec<_>>();
+    for chunk in entries.chunks(CHUNK_ENTRIES) {
+        let felts = chunk.iter().flatten().flat_map(|felt| felt.0).collect::<Vec<_>>();
+        framing::write_chunk(writer, kind, chunk.len(), &felts)?;
+    }
+    Ok(())
+}
+
+/// Writes a snapshot in the interchange format.
+pub fn export_snapshot<W: Write>(writer: &mut W, snapshot: &StateSnapshot) -> io::Result<()> {
+    export_state(writer, snapshot.block_number, snapshot.state_root, &snapshot.state)
+}
+
+/// [export_snapshot] without taking ownership of the state, which is read back as a [StateSnapshot].
+pub(crate) fn export_state<W: Write>(
+    writer: &mut W,
+    block_number: u64,
+    state_root: Felt252Wrapper,
+    state: &CommitmentStateDiff,
+) -> io::Result<()> {
+    framing::write_header(writer, &FORMAT, block_number, state_root)?;
+
+    write_section(
+        writer,
+        CLASS_HASHES,
+        state.address_to_class_hash.iter().map(|(address, class_hash)| vec![*address.0.key(), class_hash.0]),
+    )?;
+    write_section(
+        writer,
+        NONCES,
+        state.address_to_nonce.iter().map(|(address, nonce)| vec![*address.0.key(), nonce.0]),
+    )?;
+    write_section(
+        writer,
+        STORAGE,
+        state.storage_updates.iter().flat_map(|(address, updates)| {
+            updates.iter().map(|(key, value)| vec![*address.0.key(), *key.0.key(), *value])
+        }),
+    )?;
+    write_section(
+        writer,
+        DECLARED_CLASSES,
+        state.class_hash_to_compiled_class_hash.iter().map(|(class_hash, compiled)| vec![class_hash.0, compiled.0]),
+    )?;
+
+    framing::write_end(writer)
+}
+
+/// Reads a snapshot in the interchange format.
+///
+/// The snapshot is not applied: committing `state` at `block_number` must then produce `state_root`.
+pub fn import_snapshot<R: Read>(reader: &mut R) -> Result<StateSnapshot, InterchangeError> {
+    let (block_number, state_root) = framing::read_header(reader, &FORMAT)?;
+
+    let mut state = CommitmentStateDiff {
+        address_to_class_hash: IndexMap::new(),
+        address_to_nonce: IndexMap::new(),
+        storage_updates: IndexMap::new(),
+        class_hash_to_compiled_class_hash: IndexMap::new(),
+    };
+
+    let mut chunks = ChunkReader::new(reader);
+    while let Some((kind, entries)) = chunks.next_chunk()? {
+        let width = match kind {
+            CLASS_HASHES | NONCES | DECLARED_CLASSES => 2,
+            STORAGE => 3,
+            kind => return Err(InterchangeError::UnknownChunk(kind)),
+        };
+        chunks.read(entries * width * 32)?;
+
+        for entry in chunks.verify()?.chunks(width * 32) {
+            let felt = |i: usize| StarkFelt(entry[i * 32..(i + 1) * 32].try_into().unwrap());
+            let address = || ContractAddress(PatriciaKey(felt(0)));
+            match kind {
+                CLASS_HASHES => {
+                    state.address_to_class_hash.insert(address(), ClassHash(felt(1)));
+                }
+                NONCES => {
+                    state.address_to_nonce.insert(address(), Nonce(felt(1)));
+                }
+                STORAGE => {
+                    let updates = state.storage_updates.entry(address()).or_default();
+                    updates.insert(StorageKey(PatriciaKey(felt(1))), felt(2));
+                }
+                _ => {
+                    state.class_hash_to_compiled_class_hash.insert(ClassHash(felt(0)), CompiledClassHash(felt(1)));
+                }
+            }
+        }
+    }
+
+    Ok(StateSnapshot { block_number, state_root, state })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> StateSnapshot {
+        let address = ContractAddress(PatriciaKey(StarkFelt::from(0x10_u64)));
+        let storage = (0..5000_u64).map(|key| (StorageKey(PatriciaKey(StarkFelt::from(key))), StarkFelt::ONE));
+        StateSnapshot {
+            block_number: 42,
+            state_root: Felt252Wrapper::TWO,
+            state: CommitmentStateDiff {
+                address_to_class_hash: [(address, ClassHash(StarkFelt::ONE))].into_iter().collect(),
+                address_to_nonce: [(address, Nonce(StarkFelt::ONE))].into_iter().collect(),
+                storage_updates: [(address, storage.collect())].into_iter().collect(),
+                class_hash_to_compiled_class_hash: [(ClassHash(StarkFelt::ONE), CompiledClassHash(StarkFelt::TWO))]
+                    .into_iter()
+                    .collect(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = snapshot();
+        let mut bytes = Vec::new();
+        export_snapshot(&mut bytes, &snapshot).unwrap();
+
+        let imported = import_snapshot(&mut bytes.as_slice()).unwrap();
+        assert_eq!(imported.block_number, snapshot.block_number);
+        assert_eq!(imported.state_root, snapshot.state_root);
+        assert_eq!(imported.state, snapshot.state);
+    }
+
+    #[test]
+    fn test_corrupted_chunk() {
+        let mut bytes = Vec::new();
+        export_snapshot(&mut bytes, &snapshot()).unwrap();
+
+        // flips a bit in the first entry of the first chunk
+        let header = 4 + 2 + 8 + 32;
+        bytes[header + 5 + 31] ^= 1;
+
+        assert!(matches!(
+            import_snapshot(&mut bytes.as_slice()),
+            Err(InterchangeError::Framing(FramingError::Checksum(0)))
+        ));
+    }
+}
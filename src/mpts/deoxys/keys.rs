@@ -0,0 +1,233 @@
+//! Loading of the secret keys of the daemon, ie: to sign snapshots or authenticate replicas.
+//!
+//! Keys are never part of the configuration, which only tells where to load them from: a file, an
+//! environment variable or a [KeyProvider] registered by the embedding process, ie: backed by an
+//! HSM or a secret manager. Loaded keys are wiped from memory when dropped.
+//!
+//! ```toml
+//! [keys.snapshot_signing]
+//! source = "file"
+//! path = "/etc/starkroot/snapshot.key"
+//!
+//! [keys.replication]
+//! source = "provider"
+//! provider = "vault"
+//! key = "starkroot/replication"
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use super::runtime::current_config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("failed to read key file {path:?}: {error}")]
+    Io { path: PathBuf, error: std::io::Error },
+    #[error("key file {path:?} is accessible to other users (mode {mode:o}), it must only be readable by its owner")]
+    Permissions { path: PathBuf, mode: u32 },
+    #[error("environment variable {0} is not set")]
+    MissingVar(String),
+    #[error("key from {0} is not hex encoded")]
+    Encoding(String),
+    #[error("no key named {0:?} is configured")]
+    Unconfigured(String),
+    #[error("no key provider named {0:?} is registered")]
+    UnknownProvider(String),
+    #[error("key provider {provider:?} failed to load key {key:?}: {error}")]
+    Provider { provider: String, key: String, error: String },
+}
+
+/// Secret key material, zeroized on drop.
+///
+/// The key is never printed, its [Debug] implementation is redacted.
+#[derive(Clone)]
+pub struct SecretKey(Zeroizing<Vec<u8>>);
+
+impl SecretKey {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+
+    /// Decodes a hex encoded key, with or without its `0x` prefix and surrounding whitespace.
+    fn from_hex(hex: &str, origin: impl fmt::Display) -> Result<Self, KeyError> {
+        let hex = hex.trim();
+        let hex = hex.strip_prefix("0x").unwrap_or(hex).as_bytes();
+        if hex.is_empty() || hex.len() % 2 != 0 {
+            return Err(KeyError::Encoding(origin.to_string()));
+        }
+        let mut bytes = Zeroizing::new(Vec::with_capacity(hex.len() / 2));
+        for pair in hex.chunks(2) {
+            let byte = std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok());
+            bytes.push(byte.ok_or_else(|| KeyError::Encoding(origin.to_string()))?);
+        }
+        Ok(Self(bytes))
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey([REDACTED; {}])", self.0.len())
+    }
+}
+
+/// A store of keys outside of the process, ie: an HSM or a secret manager.
+pub trait KeyProvider: Send + Sync {
+    type Error: std::error::Error;
+
+    fn load(&self, key: &str) -> Result<SecretKey, Self::Error>;
+}
+
+/// Object-safe [KeyProvider], for the registry.
+trait DynKeyProvider: Send + Sync {
+    fn load(&self, key: &str) -> Result<SecretKey, String>;
+}
+
+impl<P: KeyProvider> DynKeyProvider for P {
+    fn load(&self, key: &str) -> Result<SecretKey, String> {
+        KeyProvider::load(self, key).map_err(|e| e.to_string())
+    }
+}
+
+static PROVIDERS: OnceLock<Mutex<HashMap<String, Arc<dyn DynKeyProvider>>>> = OnceLock::new();
+
+fn providers() -> MutexGuard<'static, HashMap<String, Arc<dyn DynKeyProvider>>> {
+    PROVIDERS.get_or_init(Default::default).lock().expect("Poisoned lock on key providers")
+}
+
+/// Registers a key provider, which keys configured with `source = "provider"` are loaded from.
+pub fn register_key_provider(name: impl Into<String>, provider: impl KeyProvider + 'static) {
+    providers().insert(name.into(), Arc::new(provider));
+}
+
+/// Where a key is loaded from, see the [module](self) documentation.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case", deny_unknown_fields)]
+pub enum KeySettings {
+    /// A file holding the hex encoded key, which must only be readable by its owner.
+    File { path: PathBuf },
+    /// An environment variable holding the hex encoded key.
+    Env { var: String },
+    /// A key of a [registered](register_key_provider) provider.
+    Provider { provider: String, key: String },
+}
+
+impl KeySettings {
+    pub fn load(&self) -> Result<SecretKey, KeyError> {
+        match self {
+            KeySettings::File { path } => {
+                let io = |error| KeyError::Io { path: path.clone(), error };
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+
+                    let mode = fs::metadata(path).map_err(io)?.permissions().mode() & 0o777;
+                    if mode & 0o077 != 0 {
+                        return Err(KeyError::Permissions { path: path.clone(), mode });
+                    }
+                }
+                let hex = Zeroizing::new(fs::read_to_string(path).map_err(io)?);
+                SecretKey::from_hex(&hex, path.display())
+            }
+            KeySettings::Env { var } => {
+                let hex = Zeroizing::new(std::env::var(var).map_err(|_| KeyError::MissingVar(var.clone()))?);
+                SecretKey::from_hex(&hex, format!("environment variable {var}"))
+            }
+            KeySettings::Provider { provider, key } => {
+                let registered = providers().get(provider).cloned();
+                let registered = registered.ok_or_else(|| KeyError::UnknownProvider(provider.clone()))?;
+                registered.load(key).map_err(|error| KeyError::Provider {
+                    provider: provider.clone(),
+                    key: key.clone(),
+                    error,
+                })
+            }
+        }
+    }
+}
+
+/// Loads the key configured as `name` in the `keys` section of the
+/// [current configuration](current_config).
+pub fn load_key(name: &str) -> Result<SecretKey, KeyError> {
+    let settings = current_config().keys.remove(name).ok_or_else(|| KeyError::Unconfigured(name.to_string()))?;
+    settings.load()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpts::deoxys::runtime::{exclusive, reconfigure, RestoreRuntime};
+
+    struct Vault;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("sealed")]
+    struct Sealed;
+
+    impl KeyProvider for Vault {
+        type Error = Sealed;
+
+        fn load(&self, key: &str) -> Result<SecretKey, Sealed> {
+            match key {
+                "replication" => Ok(SecretKey::new(vec![7; 32])),
+                _ => Err(Sealed),
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_keys() {
+        let path = std::env::temp_dir().join(format!("starkroot-key-{}", std::process::id()));
+        fs::write(&path, "0xdeadbeef\n").unwrap();
+        let file = KeySettings::File { path: path.clone() };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(matches!(file.load(), Err(KeyError::Permissions { mode: 0o644, .. })));
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let key = file.load().unwrap();
+        assert_eq!(key.expose(), [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(format!("{key:?}"), "SecretKey([REDACTED; 4])");
+        fs::remove_file(path).unwrap();
+
+        let env = KeySettings::Env { var: "STARKROOT_TEST_KEY_UNSET".to_string() };
+        assert!(matches!(env.load(), Err(KeyError::MissingVar(_))));
+
+        let provider = |key: &str| KeySettings::Provider { provider: "vault".to_string(), key: key.to_string() };
+        assert!(matches!(provider("replication").load(), Err(KeyError::UnknownProvider(_))));
+        register_key_provider("vault", Vault);
+        assert_eq!(provider("replication").load().unwrap().expose(), [7; 32]);
+        assert!(matches!(provider("signing").load(), Err(KeyError::Provider { .. })));
+
+        let settings: HashMap<String, KeySettings> =
+            toml::from_str("[signing]\nsource = \"env\"\nvar = \"STARKROOT_SIGNING_KEY\"").unwrap();
+        assert_eq!(settings["signing"], KeySettings::Env { var: "STARKROOT_SIGNING_KEY".to_string() });
+    }
+
+    #[test]
+    fn test_load_configured_key() {
+        let _runtime = exclusive();
+        let _restore = RestoreRuntime::new();
+        register_key_provider("configured", Vault);
+        let mut config = current_config();
+        let settings = KeySettings::Provider { provider: "configured".to_string(), key: "replication".to_string() };
+        config.keys.insert("replication".to_string(), settings);
+        reconfigure(config).unwrap();
+
+        assert_eq!(load_key("replication").unwrap().expose(), [7; 32]);
+        assert!(matches!(load_key("signing"), Err(KeyError::Unconfigured(_))));
+    }
+}
@@ -1,23 +1,54 @@
+use std::time::Instant;
+
 use blockifier::state::cached_state::CommitmentStateDiff;
 use indexmap::IndexMap;
 use mp_convert::field_element::FromFieldElement;
 use mp_felt::Felt252Wrapper;
-use mp_hashers::poseidon::PoseidonHasher;
 use mp_hashers::HasherT;
 use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce};
 use starknet_api::hash::StarkFelt;
-use starknet_api::state::StorageKey;
+#[cfg(feature = "pedersen")]
 use starknet_api::transaction::{Event, Transaction};
 use starknet_core::types::{
-    ContractStorageDiffItem, DeclaredClassItem, DeployedContractItem, NonceUpdate, ReplacedClassItem, StateUpdate,
-    StorageEntry,
+    ContractStorageDiffItem, DeclaredClassItem, DeployedContractItem, NonceUpdate, ReplacedClassItem, StateDiff,
+    StateUpdate, StorageEntry,
 };
 use starknet_ff::FieldElement;
+#[cfg(feature = "pedersen")]
+use starknet_types_core::felt::Felt;
 
+use super::atomic::rollback_block;
+use super::canary::{canary_log, verify_sample};
+use super::canonical::Canonicalize;
+#[cfg(feature = "class-verification")]
+use super::class_verification::verify_ingested_classes;
 use super::classes::class_trie_root;
+use super::config::ChainConfig;
+#[cfg(feature = "pedersen")]
+use super::config::CommitmentScheme;
+use super::consts::STARKNET_STATE_PREFIX;
 use super::contracts::contract_trie_root;
-use super::events::memory_event_commitment;
-use super::transactions::memory_transaction_commitment;
+use super::conversions::{
+    try_class_hash, try_contract_address, try_storage_key, validate_state_diff, validate_trie_keys,
+};
+use super::duplicates::{DuplicateEntry, DuplicatePolicy};
+use super::engine::{commit_state_backend, StateTries};
+use super::error::{CommitError, CommitmentError, CommitmentItem, DiffError, ErrorContext, ResultExt};
+#[cfg(feature = "pedersen")]
+use super::events::try_memory_event_commitment_with_scheme;
+use super::facts::publish_facts;
+use super::history::{contract_activity, storage_history};
+use super::quarantine::quarantine;
+#[cfg(feature = "pedersen")]
+use super::receipts::{try_memory_receipt_commitment, TransactionReceipt};
+use super::retry::{Operation, RetryPolicy};
+use super::roots::{check_persisted, diff_hash, root_registry, stage_committed_block, FencingToken, RootRegistry};
+use super::runtime::{in_current_span, install};
+use super::standby::is_frozen;
+use super::stats::{commit_stats_by_contract, contract_sizes, written_stats, CommitStats};
+#[cfg(feature = "pedersen")]
+use super::transactions::try_memory_transaction_commitment_with_scheme;
+use super::watchdog::{notify_breach, sla_watchdog, CommitPhase, PhaseTimings, SlaBreach};
 
 /// Calculate the transaction and event commitment.
 ///
@@ -31,75 +62,412 @@ use super::transactions::memory_transaction_commitment;
 /// # Returns
 ///
 /// The transaction and the event commitment as `Felt252Wrapper`.
+#[cfg(feature = "pedersen")]
 pub fn calculate_tx_and_event_commitments(
     transactions: &[Transaction],
     events: &[Event],
     chain_id: Felt252Wrapper,
     block_number: u64,
 ) -> (Felt252Wrapper, Felt252Wrapper) {
-    let (commitment_tx, commitment_event) = rayon::join(
-        || memory_transaction_commitment(transactions, chain_id, block_number),
-        || memory_event_commitment(events),
-    );
-    (
-        commitment_tx.expect("Failed to calculate transaction commitment"),
-        commitment_event.expect("Failed to calculate event commitment"),
+    try_calculate_tx_and_event_commitments(transactions, events, chain_id, block_number)
+        .expect("Failed to calculate transaction and event commitments")
+}
+
+/// Calculate the transaction and event commitment, failing instead of panicking on malformed
+/// transactions or events so that callers can retry or skip the block.
+///
+/// See [calculate_tx_and_event_commitments].
+///
+/// # Arguments
+///
+/// * `transactions` - The transactions of the block
+/// * `events` - The events of the block
+/// * `chain_id` - The current chain id
+/// * `block_number` - The current block number
+///
+/// # Returns
+///
+/// The transaction and the event commitment as `Felt252Wrapper`.
+#[cfg(feature = "pedersen")]
+pub fn try_calculate_tx_and_event_commitments(
+    transactions: &[Transaction],
+    events: &[Event],
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+) -> Result<(Felt252Wrapper, Felt252Wrapper), CommitmentError> {
+    try_calculate_tx_and_event_commitments_with_scheme(
+        transactions,
+        events,
+        &[],
+        chain_id,
+        block_number,
+        CommitmentScheme::Pedersen,
+    )
+}
+
+/// Calculate the transaction and event commitment following the scheme of the block's protocol
+/// version, see [try_calculate_tx_and_event_commitments].
+///
+/// # Arguments
+///
+/// * `transactions` - The transactions of the block
+/// * `events` - The events of the block
+/// * `event_transaction_hashes` - The hash of the transaction which emitted each event, only used by
+///   [CommitmentScheme::Poseidon]
+/// * `chain_id` - The current chain id
+/// * `block_number` - The current block number
+/// * `scheme` - The commitment scheme of the block, see [CommitmentScheme::for_protocol_version]
+///
+/// # Returns
+///
+/// The transaction and the event commitment as `Felt252Wrapper`.
+#[cfg(feature = "pedersen")]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(block_number = block_number, transactions = transactions.len(), events = events.len(), scheme = ?scheme)
     )
+)]
+pub fn try_calculate_tx_and_event_commitments_with_scheme(
+    transactions: &[Transaction],
+    events: &[Event],
+    event_transaction_hashes: &[Felt],
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+    scheme: CommitmentScheme,
+) -> Result<(Felt252Wrapper, Felt252Wrapper), CommitmentError> {
+    let (commitment_tx, commitment_event) = install(|| {
+        rayon::join(
+            || try_memory_transaction_commitment_with_scheme(transactions, chain_id, block_number, scheme),
+            in_current_span(|| try_memory_event_commitment_with_scheme(events, event_transaction_hashes, scheme)),
+        )
+    });
+    Ok((commitment_tx?, commitment_event?))
+}
+
+/// Numbers of transactions and events of a block, as declared by its header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderCounts {
+    pub transaction_count: u64,
+    pub event_count: u64,
+}
+
+impl HeaderCounts {
+    /// Checks that the transactions and events of a block match the counts of its header.
+    ///
+    /// This catches gateways dropping or duplicating items before anything is hashed: the
+    /// commitments would otherwise only mismatch, without telling which list is wrong.
+    pub fn check(&self, transactions: usize, events: usize) -> Result<(), CommitmentError> {
+        for (item, declared, actual) in [
+            (CommitmentItem::Transaction, self.transaction_count, transactions),
+            (CommitmentItem::Event, self.event_count, events),
+        ] {
+            if declared != actual as u64 {
+                return Err(CommitmentError::CountMismatch { item, declared, actual });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calculate the transaction and event commitment after checking the transactions and events
+/// against the counts of the block header.
+///
+/// See [try_calculate_tx_and_event_commitments] and [HeaderCounts::check].
+///
+/// # Arguments
+///
+/// * `transactions` - The transactions of the block
+/// * `events` - The events of the block
+/// * `counts` - The transaction and event counts of the block header
+/// * `chain_id` - The current chain id
+/// * `block_number` - The current block number
+///
+/// # Returns
+///
+/// The transaction and the event commitment as `Felt252Wrapper`.
+#[cfg(feature = "pedersen")]
+pub fn try_calculate_tx_and_event_commitments_checked(
+    transactions: &[Transaction],
+    events: &[Event],
+    counts: HeaderCounts,
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+) -> Result<(Felt252Wrapper, Felt252Wrapper), CommitmentError> {
+    counts.check(transactions.len(), events.len())?;
+    try_calculate_tx_and_event_commitments(transactions, events, chain_id, block_number)
+}
+
+/// Calculate the transaction, event and receipt commitments, for blocks from Starknet v0.13.2.
+///
+/// The transaction and event commitments follow [CommitmentScheme::Poseidon], see
+/// [try_memory_transaction_commitment_with_scheme] and [try_memory_event_commitment_with_scheme].
+///
+/// # Arguments
+///
+/// * `transactions` - The transactions of the block
+/// * `events` - The events of the block
+/// * `event_transaction_hashes` - The hash of the transaction which emitted each event
+/// * `receipts` - The receipts of the transactions of the block
+/// * `chain_id` - The current chain id
+/// * `block_number` - The current block number
+///
+/// # Returns
+///
+/// The transaction, event and receipt commitments as `Felt252Wrapper`.
+#[cfg(feature = "pedersen")]
+pub fn try_calculate_tx_event_and_receipt_commitments(
+    transactions: &[Transaction],
+    events: &[Event],
+    event_transaction_hashes: &[Felt],
+    receipts: &[TransactionReceipt],
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+) -> Result<(Felt252Wrapper, Felt252Wrapper, Felt252Wrapper), CommitmentError> {
+    let scheme = CommitmentScheme::Poseidon;
+    let (commitments, commitment_receipt) = install(|| {
+        rayon::join(
+            in_current_span(|| {
+                try_calculate_tx_and_event_commitments_with_scheme(
+                    transactions,
+                    events,
+                    event_transaction_hashes,
+                    chain_id,
+                    block_number,
+                    scheme,
+                )
+            }),
+            in_current_span(|| try_memory_receipt_commitment(receipts)),
+        )
+    });
+    let (commitment_tx, commitment_event) = commitments?;
+    Ok((commitment_tx, commitment_event, commitment_receipt?))
 }
 
 /// Aggregates all the changes from last state update in a way that is easy to access
 /// when computing the state root
 ///
+/// Contract addresses, storage keys and class hashes are range-checked as they are converted, since
+/// they are used as keys in the state tries. Duplicate entries are resolved with
+/// [DuplicatePolicy::LastWins], see [build_commitment_state_diff_with_policy] to configure this.
+///
 /// * `state_update`: The last state update fetched from the sequencer
-pub fn build_commitment_state_diff(state_update: &StateUpdate) -> CommitmentStateDiff {
+pub fn build_commitment_state_diff(state_update: &StateUpdate) -> Result<CommitmentStateDiff, DiffError> {
+    build_commitment_state_diff_with_policy(state_update, DuplicatePolicy::default()).map(|(csd, _)| csd)
+}
+
+/// Aggregates all the changes from last state update after validating every entry, to reject
+/// malformed sequencer responses with an error pointing at the offending entry.
+///
+/// See [validate_state_diff] and [build_commitment_state_diff_with_config].
+///
+/// # Arguments
+///
+/// * `state_update` - The last state update fetched from the sequencer
+/// * `config` - Chain-specific commitment rules, for the system contracts conventions.
+pub fn try_build_commitment_state_diff(
+    state_update: &StateUpdate,
+    config: &ChainConfig,
+) -> Result<CommitmentStateDiff, DiffError> {
+    validate_state_diff(&state_update.state_diff, config)?;
+    build_commitment_state_diff_with_config(state_update, DuplicatePolicy::default(), config).map(|(csd, _)| csd)
+}
+
+/// Aggregates all the changes from last state update, resolving entries which appear more than once
+/// according to `policy`.
+///
+/// # Arguments
+///
+/// * `state_update` - The last state update fetched from the sequencer
+/// * `policy` - How to handle duplicate entries
+///
+/// # Returns
+///
+/// The commitment state diff, along with every duplicate entry which was found.
+pub fn build_commitment_state_diff_with_policy(
+    state_update: &StateUpdate,
+    policy: DuplicatePolicy,
+) -> Result<(CommitmentStateDiff, Vec<DuplicateEntry>), DiffError> {
+    build_commitment_state_diff_with_config(state_update, policy, &ChainConfig::default())
+}
+
+/// Aggregates all the changes from last state update following chain-specific rules.
+///
+/// See [build_commitment_state_diff_with_policy].
+///
+/// # Arguments
+///
+/// * `state_update` - The last state update fetched from the sequencer
+/// * `policy` - How to handle duplicate entries
+/// * `config` - Chain-specific commitment rules, for the system contracts conventions.
+///
+/// # Returns
+///
+/// The commitment state diff, along with every duplicate entry which was found.
+pub fn build_commitment_state_diff_with_config(
+    state_update: &StateUpdate,
+    policy: DuplicatePolicy,
+    config: &ChainConfig,
+) -> Result<(CommitmentStateDiff, Vec<DuplicateEntry>), DiffError> {
     let mut commitment_state_diff = CommitmentStateDiff {
         address_to_class_hash: IndexMap::new(),
         address_to_nonce: IndexMap::new(),
         storage_updates: IndexMap::new(),
         class_hash_to_compiled_class_hash: IndexMap::new(),
     };
+    let mut duplicates = Vec::new();
 
     for DeployedContractItem { address, class_hash } in state_update.state_diff.deployed_contracts.iter() {
-        let address = ContractAddress::from_field_element(address);
-        let class_hash = if address == ContractAddress::from_field_element(FieldElement::ZERO) {
-            // System contracts doesnt have class hashes
-            ClassHash::from_field_element(FieldElement::ZERO)
-        } else {
-            ClassHash::from_field_element(class_hash)
-        };
-        commitment_state_diff.address_to_class_hash.insert(address, class_hash);
+        let address = try_contract_address(address)?;
+        // System contracts may not have class hashes
+        let class_hash = config.system_contracts.class_hash(&address, ClassHash::from_field_element(class_hash));
+        policy.insert(
+            &mut commitment_state_diff.address_to_class_hash,
+            address,
+            class_hash,
+            || DuplicateEntry::DeployedContract(address),
+            &mut duplicates,
+        )?;
     }
 
+    // Class replacements are always applied after deployments, regardless of the order in which the
+    // sequencer serialized them: a contract deployed and then upgraded in the same block ends up with
+    // the replaced class hash as its leaf's class hash.
+    let mut replaced_classes = IndexMap::new();
     for ReplacedClassItem { contract_address, class_hash } in state_update.state_diff.replaced_classes.iter() {
-        let address = ContractAddress::from_field_element(contract_address);
+        let address = try_contract_address(contract_address)?;
         let class_hash = ClassHash::from_field_element(class_hash);
-        commitment_state_diff.address_to_class_hash.insert(address, class_hash);
+        policy.insert(
+            &mut replaced_classes,
+            address,
+            class_hash,
+            || DuplicateEntry::ReplacedClass(address),
+            &mut duplicates,
+        )?;
     }
+    commitment_state_diff.address_to_class_hash.extend(replaced_classes);
 
     for DeclaredClassItem { class_hash, compiled_class_hash } in state_update.state_diff.declared_classes.iter() {
-        let class_hash = ClassHash::from_field_element(class_hash);
+        let class_hash = try_class_hash(class_hash)?;
         let compiled_class_hash = CompiledClassHash::from_field_element(compiled_class_hash);
-        commitment_state_diff.class_hash_to_compiled_class_hash.insert(class_hash, compiled_class_hash);
+        policy.insert(
+            &mut commitment_state_diff.class_hash_to_compiled_class_hash,
+            class_hash,
+            compiled_class_hash,
+            || DuplicateEntry::DeclaredClass(class_hash),
+            &mut duplicates,
+        )?;
     }
 
     for NonceUpdate { contract_address, nonce } in state_update.state_diff.nonces.iter() {
-        let contract_address = ContractAddress::from_field_element(contract_address);
+        let contract_address = try_contract_address(contract_address)?;
         let nonce_value = Nonce::from_field_element(nonce);
-        commitment_state_diff.address_to_nonce.insert(contract_address, nonce_value);
+        policy.insert(
+            &mut commitment_state_diff.address_to_nonce,
+            contract_address,
+            nonce_value,
+            || DuplicateEntry::Nonce(contract_address),
+            &mut duplicates,
+        )?;
     }
 
     for ContractStorageDiffItem { address, storage_entries } in state_update.state_diff.storage_diffs.iter() {
-        let contract_address = ContractAddress::from_field_element(address);
-        let mut storage_map = IndexMap::new();
+        let contract_address = try_contract_address(address)?;
+        // The same contract can appear in several storage diff items, their entries are merged
+        let storage_map = commitment_state_diff.storage_updates.entry(contract_address).or_default();
         for StorageEntry { key, value } in storage_entries.iter() {
-            let key = StorageKey::from_field_element(key);
+            let key = try_storage_key(key)?;
             let value = StarkFelt::from_field_element(value);
-            storage_map.insert(key, value);
+            policy.insert(
+                storage_map,
+                key,
+                value,
+                || DuplicateEntry::StorageKey { contract_address, key },
+                &mut duplicates,
+            )?;
+        }
+    }
+
+    Ok((commitment_state_diff, duplicates))
+}
+
+/// Clones a [CommitmentStateDiff], which does not implement `Clone` itself.
+pub fn clone_commitment_state_diff(csd: &CommitmentStateDiff) -> CommitmentStateDiff {
+    CommitmentStateDiff {
+        address_to_class_hash: csd.address_to_class_hash.clone(),
+        address_to_nonce: csd.address_to_nonce.clone(),
+        storage_updates: csd.storage_updates.clone(),
+        class_hash_to_compiled_class_hash: csd.class_hash_to_compiled_class_hash.clone(),
+    }
+}
+
+/// Converts a [CommitmentStateDiff] back to the [StateDiff] of a state update, ie: to serve a
+/// locally executed block over `starknet_getStateUpdate`.
+///
+/// The commitment state diff does not tell deployments from class replacements, nor does it hold
+/// the Cairo 0 classes declared in the block, so both are taken from the state before the block.
+/// Contracts without storage updates are left out of the storage diffs.
+///
+/// # Arguments
+///
+/// * `csd`                         - The commitment state diff of the block.
+/// * `class_hash_before`           - Looks up the class hash of a contract before the block, zero
+///                                   if it was not deployed. The class hash assignments of deployed
+///                                   contracts are class replacements.
+/// * `deprecated_declared_classes` - The Cairo 0 classes declared in the block.
+pub fn commitment_state_diff_to_state_diff<E>(
+    csd: &CommitmentStateDiff,
+    mut class_hash_before: impl FnMut(&ContractAddress) -> Result<ClassHash, E>,
+    deprecated_declared_classes: &[ClassHash],
+) -> Result<StateDiff, E> {
+    let felt = |felt: &StarkFelt| FieldElement::from_bytes_be(&felt.0).unwrap();
+
+    let mut deployed_contracts = Vec::new();
+    let mut replaced_classes = Vec::new();
+    for (contract_address, class_hash) in csd.address_to_class_hash.iter() {
+        let deployed = class_hash_before(contract_address)? != ClassHash::default();
+        let (address, class_hash) = (felt(contract_address.0.key()), felt(&class_hash.0));
+        if deployed {
+            replaced_classes.push(ReplacedClassItem { contract_address: address, class_hash });
+        } else {
+            deployed_contracts.push(DeployedContractItem { address, class_hash });
         }
-        commitment_state_diff.storage_updates.insert(contract_address, storage_map);
     }
 
-    commitment_state_diff
+    Ok(StateDiff {
+        storage_diffs: csd
+            .storage_updates
+            .iter()
+            .filter(|(_, updates)| !updates.is_empty())
+            .map(|(contract_address, updates)| ContractStorageDiffItem {
+                address: felt(contract_address.0.key()),
+                storage_entries: updates
+                    .iter()
+                    .map(|(key, value)| StorageEntry { key: felt(key.0.key()), value: felt(value) })
+                    .collect(),
+            })
+            .collect(),
+        deprecated_declared_classes: deprecated_declared_classes.iter().map(|class_hash| felt(&class_hash.0)).collect(),
+        declared_classes: csd
+            .class_hash_to_compiled_class_hash
+            .iter()
+            .map(|(class_hash, compiled_class_hash)| DeclaredClassItem {
+                class_hash: felt(&class_hash.0),
+                compiled_class_hash: felt(&compiled_class_hash.0),
+            })
+            .collect(),
+        deployed_contracts,
+        replaced_classes,
+        nonces: csd
+            .address_to_nonce
+            .iter()
+            .map(|(contract_address, nonce)| NonceUpdate {
+                contract_address: felt(contract_address.0.key()),
+                nonce: felt(&nonce.0),
+            })
+            .collect(),
+    })
 }
 
 /// Calculate state commitment hash value.
@@ -123,13 +491,22 @@ pub fn calculate_state_root<H: HasherT>(
 where
     H: HasherT,
 {
-    let starknet_state_prefix = Felt252Wrapper::try_from("STARKNET_STATE_V0".as_bytes()).unwrap();
+    let starknet_state_prefix = Felt252Wrapper::try_from(STARKNET_STATE_PREFIX).unwrap();
+    calculate_state_root_with_prefix::<H>(starknet_state_prefix.0, contracts_trie_root, classes_trie_root)
+}
 
+/// [calculate_state_root] with the [state prefix](super::consts::ProtocolConstants::state_prefix) of
+/// a chain.
+pub(crate) fn calculate_state_root_with_prefix<H: HasherT>(
+    state_prefix: FieldElement,
+    contracts_trie_root: Felt252Wrapper,
+    classes_trie_root: Felt252Wrapper,
+) -> Felt252Wrapper {
     if classes_trie_root == Felt252Wrapper::ZERO {
         contracts_trie_root
     } else {
         let state_commitment_hash =
-            H::compute_hash_on_elements(&[starknet_state_prefix.0, contracts_trie_root.0, classes_trie_root.0]);
+            H::compute_hash_on_elements(&[state_prefix, contracts_trie_root.0, classes_trie_root.0]);
 
         state_commitment_hash.into()
     }
@@ -143,16 +520,548 @@ where
 ///
 /// # Arguments
 ///
+/// * `tries` - The state tries to update.
 /// * `CommitmentStateDiff` - The commitment state diff inducing unprocessed state changes.
-/// * `BonsaiDb` - The database responsible for storing computing the state tries.
 ///
 ///
 /// The updated state root as a `Felt252Wrapper`.
-pub fn update_state_root(csd: CommitmentStateDiff, block_number: u64) -> Felt252Wrapper {
+#[deprecated(note = "commit through the `WriteHandle` of the tries")]
+pub fn update_state_root(tries: &StateTries, csd: CommitmentStateDiff, block_number: u64) -> Felt252Wrapper {
+    try_update_state_root(tries, csd, block_number, &ChainConfig::default()).expect("Failed to update state root")
+}
+
+/// Update the state commitment hash value following chain-specific rules.
+///
+/// See [update_state_root].
+///
+/// # Arguments
+///
+/// * `tries` - The state tries to update.
+/// * `csd` - The commitment state diff inducing unprocessed state changes.
+/// * `block_number` - The current block number.
+/// * `config` - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The updated state root as a `Felt252Wrapper`.
+#[deprecated(note = "commit through the `WriteHandle` of the tries")]
+pub fn update_state_root_with_config(
+    tries: &StateTries,
+    csd: CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Felt252Wrapper {
+    try_update_state_root(tries, csd, block_number, config).expect("Failed to update state root")
+}
+
+/// Update the state commitment hash value, detecting blocks which are committed twice.
+///
+/// Committing the same state diff again at an already committed height (ie: retrying after a
+/// timeout) is a no-op which returns the stored state root. Committing a different state diff at an
+/// already committed height is a [CommitError::Conflict].
+///
+/// # Arguments
+///
+/// * `tries` - The state tries to update.
+/// * `csd` - The commitment state diff inducing unprocessed state changes.
+/// * `block_number` - The current block number.
+/// * `config` - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The updated state root as a `Felt252Wrapper`.
+pub(crate) fn try_update_state_root(
+    tries: &StateTries,
+    csd: CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<Felt252Wrapper, CommitError> {
+    fenced_update_state_root(tries, csd, block_number, config, None)
+}
+
+/// Update the state commitment hash value on behalf of the leader identified by `fencing_token`.
+///
+/// See [try_update_state_root]. Commits carrying a token older than the most recent token seen are
+/// rejected with [CommitError::Fenced], which prevents split-brain corruption when a standby takes
+/// over while the former leader is still running. Once a token was seen, unfenced commits are
+/// rejected as well with [CommitError::Unfenced].
+///
+/// The token becomes the current one once the block is committed: a commit which fails or is a
+/// no-op leaves it as it was. When the tries are those of an
+/// [engine](super::engine::CommitmentEngine), the token is persisted by the same backend commit as
+/// the block so that fencing survives restarts. The node's database does not store it: the token
+/// must then be restored with [RootRegistry::fence] on startup.
+///
+/// # Arguments
+///
+/// * `tries` - The state tries to update.
+/// * `csd` - The commitment state diff inducing unprocessed state changes.
+/// * `block_number` - The current block number.
+/// * `config` - Chain-specific commitment rules.
+/// * `fencing_token` - Token of the leader issuing the commit.
+///
+/// # Returns
+///
+/// The updated state root as a `Felt252Wrapper`.
+pub(crate) fn try_update_state_root_fenced(
+    tries: &StateTries,
+    csd: CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+    fencing_token: FencingToken,
+) -> Result<Felt252Wrapper, CommitError> {
+    fenced_update_state_root(tries, csd, block_number, config, Some(fencing_token))
+}
+
+fn fenced_update_state_root(
+    tries: &StateTries,
+    csd: CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+    fencing_token: Option<FencingToken>,
+) -> Result<Felt252Wrapper, CommitError> {
+    // Classes are compiled once per block, before the registry is locked
+    #[cfg(feature = "class-verification")]
+    verify_ingested_classes(&csd, block_number, config)?;
+
+    // A failed attempt is rolled back before the block is retried as a whole. The reads of an
+    // attempt are not retried on their own, so that no retry waits while the registry is locked.
+    let attempt_config = ChainConfig { retry: RetryPolicy::none(), ..config.clone() };
+    let attempts = config.retry.max_attempts;
+    let mut csd = Some(csd);
+    let mut attempt = 0;
+    let (state_root, committed) = config.retry.run(Operation::Commit, || {
+        attempt += 1;
+        let csd = if attempt < attempts {
+            clone_commitment_state_diff(csd.as_ref().expect("The diff is kept until the last attempt"))
+        } else {
+            csd.take().expect("The diff is kept until the last attempt")
+        };
+
+        // The registry stays locked for the whole attempt so that concurrent retries of the same
+        // block cannot both apply it. It is locked before entering the thread pool: a pool thread
+        // waiting for the lock could otherwise be the one the holder of the lock waits for.
+        let mut registry = root_registry(tries);
+        let registry = &mut *registry;
+        // The tries are updated on their thread pool, which is single-threaded in deterministic mode
+        tries.execution().install(|| commit(tries, csd, block_number, &attempt_config, fencing_token, registry))
+    })?;
+
+    // The facts are gathered and the hooks called once the registry is unlocked, so that the next
+    // block can be committed and the hooks can call back into the API
+    if let Some(Committed { stats, csd, breach }) = committed {
+        publish_facts(block_number, state_root, stats, &csd);
+        if let Some(breach) = breach {
+            notify_breach(tries, &breach);
+        }
+    }
+    Ok(state_root)
+}
+
+/// A block newly committed by [commit], whose facts are to be published.
+struct Committed {
+    stats: CommitStats,
+    csd: CommitmentStateDiff,
+    /// The SLA breach of the commit, to be passed to the [watchdog](super::watchdog) hooks.
+    breach: Option<SlaBreach>,
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "update_state_root",
+        skip_all,
+        fields(
+            block_number = block_number,
+            contracts = csd.storage_updates.len(),
+            deployed = csd.address_to_class_hash.len(),
+            classes = csd.class_hash_to_compiled_class_hash.len(),
+        )
+    )
+)]
+fn commit(
+    tries: &StateTries,
+    mut csd: CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+    fencing_token: Option<FencingToken>,
+    registry: &mut RootRegistry,
+) -> Result<(Felt252Wrapper, Option<Committed>), CommitError> {
+    let start = Instant::now();
+    let mut timings = PhaseTimings::default();
+
+    // A former leader is fenced off before anything else. The token persisted by the engine outlives
+    // the registry, ie: across restarts.
+    if let Some(persisted) = tries.engine().and_then(|engine| engine.fencing_token()) {
+        registry.fencing_token = registry.fencing_token.max(Some(persisted));
+    }
+    registry.check_fence(fencing_token)?;
+
+    // Diff insertion order must not influence the order in which the tries are updated
+    csd.canonicalize();
+
+    if is_frozen(tries) {
+        return Err(CommitError::Frozen { block_number });
+    }
+    let diff_hash = diff_hash(&csd);
+    if let Some(state_root) = registry.check(block_number, diff_hash)? {
+        return Ok((state_root, None));
+    }
+    if let Some(state_root) = check_persisted(tries, block_number, diff_hash)? {
+        return Ok((state_root, None));
+    }
+
+    // Keys are validated before any of them is inserted so that a bad diff leaves the tries untouched
+    validate_trie_keys(&csd).context(|| ErrorContext::block(block_number))?;
+
+    // The engine counts the leaves and nodes written by its tries, while new and updated leaves of
+    // the node's database are told apart by reading it before the update
+    let phase = Instant::now();
+    let counts_writes = !tries.is_node_db();
+    let read_stats = if counts_writes {
+        None
+    } else {
+        Some(commit_stats_by_contract(tries, &csd, config).context(|| ErrorContext::block(block_number))?)
+    };
+    timings.record(CommitPhase::Stats, phase.elapsed());
+
     // Update contract and its storage tries
-    let (contract_trie_root, class_trie_root) = rayon::join(
-        || contract_trie_root(&csd, block_number).expect("Failed to compute contract root"),
-        || class_trie_root(&csd, block_number).expect("Failed to compute class root"),
+    let ((contract_trie_root, contracts_elapsed), (class_trie_root, classes_elapsed)) = rayon::join(
+        || {
+            let phase = Instant::now();
+            (contract_trie_root(tries, &csd, block_number, config), phase.elapsed())
+        },
+        in_current_span(|| {
+            let phase = Instant::now();
+            (class_trie_root(tries, &csd, block_number, config), phase.elapsed())
+        }),
     );
-    calculate_state_root::<PoseidonHasher>(contract_trie_root, class_trie_root)
+    timings.record(CommitPhase::Contracts, contracts_elapsed);
+    timings.record(CommitPhase::Classes, classes_elapsed);
+    // The tries are committed independently: if any of them failed, the others are rolled back so
+    // that the block is either fully applied or not at all
+    let committed = match (contract_trie_root, class_trie_root) {
+        (Ok(contract_trie_root), Ok(class_trie_root)) => {
+            let state_root = config.state_root(contract_trie_root, class_trie_root);
+            // The writes of the tries are counted until the block is durable
+            let stats = match read_stats {
+                Some(stats) => Ok(stats),
+                None => match tries.engine() {
+                    Some(engine) => written_stats(&engine, &csd, config),
+                    None => Ok(Default::default()),
+                },
+            };
+            // The record of the block is persisted by the same backend commit as its tries
+            stats.and_then(|stats| {
+                stage_committed_block(tries, block_number, diff_hash, state_root, fencing_token)
+                    .and_then(|()| commit_state_backend(tries, block_number))
+                    .map(|()| (state_root, stats))
+            })
+        }
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+    let (state_root, (stats, storage_by_contract)) = match committed {
+        Ok(committed) => committed,
+        Err(e) => {
+            tries.state().capture.mutation_log.end_block(block_number, false);
+            return Err(rollback_block(tries, block_number, e));
+        }
+    };
+    tries.state().capture.mutation_log.end_block(block_number, true);
+    #[cfg(feature = "tracing")]
+    if stats.ignored_zero_writes > 0 {
+        tracing::warn!(
+            block_number,
+            ignored_zero_writes = stats.ignored_zero_writes,
+            "Zero writes to non-empty storage slots were ignored, the state root diverges from a chain clearing them"
+        );
+    }
+
+    registry.record(block_number, diff_hash, state_root, stats);
+    // The token is only current once a block it carries is committed
+    registry.fencing_token = registry.fencing_token.max(fencing_token);
+    if quarantine(tries).is_dirty(block_number) {
+        registry.mark_dirty(block_number);
+    }
+    contract_sizes(tries).record(&storage_by_contract);
+    let phase = Instant::now();
+    if config.index_storage_writes {
+        storage_history(tries).record(block_number, &csd);
+    }
+    if config.index_contract_activity {
+        contract_activity(tries).record(block_number, &csd);
+    }
+    timings.record(CommitPhase::Indexes, phase.elapsed());
+    if config.canary_sample > 0 {
+        let phase = Instant::now();
+        // The block is committed at this point: canary failures and errors reading the tries back are
+        // recorded in the canary log and never fail the commit
+        if let Err(e) = verify_sample(tries, &csd, block_number, config) {
+            canary_log(tries).record_error(block_number, &e);
+        }
+        timings.record(CommitPhase::Canary, phase.elapsed());
+    }
+
+    let breach = config.commit_sla.and_then(|sla| {
+        timings.total = start.elapsed();
+        sla_watchdog(tries).observe(block_number, sla, timings)
+    });
+    Ok((state_root, Some(Committed { stats, csd, breach })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::{Backend, MemoryBackend};
+    use crate::mpts::deoxys::conversions::ConversionError;
+    use crate::mpts::deoxys::error::TrieError;
+    use crate::mpts::deoxys::handles::WriteHandle;
+    use crate::mpts::deoxys::standby::{freeze, thaw};
+
+    fn felt(n: u64) -> FieldElement {
+        FieldElement::from(n)
+    }
+
+    fn state_update(
+        deployed_contracts: Vec<DeployedContractItem>,
+        replaced_classes: Vec<ReplacedClassItem>,
+    ) -> StateUpdate {
+        StateUpdate {
+            block_hash: FieldElement::ZERO,
+            new_root: FieldElement::ZERO,
+            old_root: FieldElement::ZERO,
+            state_diff: StateDiff {
+                storage_diffs: vec![],
+                deprecated_declared_classes: vec![],
+                declared_classes: vec![],
+                deployed_contracts,
+                replaced_classes,
+                nonces: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_replaced_class_applied_after_deploy() {
+        // A contract deployed with class 0x10 and upgraded to class 0x20 in the same block, next to an
+        // existing contract being upgraded
+        let state_update = state_update(
+            vec![DeployedContractItem { address: felt(1), class_hash: felt(0x10) }],
+            vec![
+                ReplacedClassItem { contract_address: felt(1), class_hash: felt(0x20) },
+                ReplacedClassItem { contract_address: felt(2), class_hash: felt(0x30) },
+            ],
+        );
+
+        let (csd, duplicates) = build_commitment_state_diff_with_policy(&state_update, DuplicatePolicy::Error).unwrap();
+
+        assert!(duplicates.is_empty());
+        assert_eq!(csd.address_to_class_hash.len(), 2);
+        assert_eq!(
+            csd.address_to_class_hash[&ContractAddress::from_field_element(felt(1))],
+            ClassHash::from_field_element(felt(0x20))
+        );
+        assert_eq!(
+            csd.address_to_class_hash[&ContractAddress::from_field_element(felt(2))],
+            ClassHash::from_field_element(felt(0x30))
+        );
+    }
+
+    // The addresses and class hashes are made up: a recorded mainnet block deploying and upgrading
+    // a contract in the same block is still to be added to the corpus, checked against the state
+    // root published for it
+    #[test]
+    fn test_replaced_class_state_root() {
+        // State root of the blocks committed on top of an empty state
+        let state_root = |state_updates: &[StateUpdate]| {
+            let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+            let config = ChainConfig::default();
+            state_updates
+                .iter()
+                .zip(1..)
+                .map(|(state_update, block_number)| {
+                    let csd = build_commitment_state_diff(state_update).unwrap();
+                    try_update_state_root(&tries, csd, block_number, &config).unwrap()
+                })
+                .last()
+                .unwrap()
+        };
+        let deploy = |class_hash: u64| DeployedContractItem { address: felt(0x11), class_hash: felt(class_hash) };
+        let replace =
+            |class_hash: u64| ReplacedClassItem { contract_address: felt(0x11), class_hash: felt(class_hash) };
+
+        // A contract deployed then upgraded in the same block commits the same leaf as one deployed with
+        // the replaced class, or upgraded in a later block
+        let upgraded = state_root(&[state_update(vec![deploy(0x20)], vec![])]);
+        assert_ne!(state_root(&[state_update(vec![deploy(0x10)], vec![])]), upgraded);
+        assert_eq!(state_root(&[state_update(vec![deploy(0x10)], vec![replace(0x20)])]), upgraded);
+        assert_eq!(
+            state_root(&[state_update(vec![deploy(0x10)], vec![]), state_update(vec![], vec![replace(0x20)])]),
+            upgraded
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_key() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        // 2^251, the first key outside of the tries
+        let mut out_of_range = [0u8; 32];
+        out_of_range[0] = 0x08;
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let storage = |key: StarkFelt| -> IndexMap<_, _> {
+            [(StorageKey(PatriciaKey(key)), StarkFelt::ONE)].into_iter().collect()
+        };
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [
+                (contract_address, storage(StarkFelt::TWO)),
+                (ContractAddress(PatriciaKey(StarkFelt::TWO)), storage(StarkFelt::new(out_of_range).unwrap())),
+            ]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+
+        // The diff is rejected before any of its keys enters the tries
+        assert!(matches!(
+            try_update_state_root(&tries, csd, 0, &ChainConfig::default()),
+            Err(CommitError::Trie(TrieError::WithContext { source, .. }))
+                if matches!(*source, TrieError::Conversion(ConversionError::StorageKeyOutOfRange(_)))
+        ));
+        let engine = tries.engine().unwrap();
+        assert_eq!(engine.latest(), None);
+        let value = engine.storage_value(&contract_address, &StorageKey(PatriciaKey(StarkFelt::TWO))).unwrap();
+        assert_eq!(value, StarkFelt::ZERO);
+    }
+
+    #[test]
+    fn test_duplicate_replaced_class() {
+        let state_update = state_update(
+            vec![],
+            vec![
+                ReplacedClassItem { contract_address: felt(1), class_hash: felt(0x20) },
+                ReplacedClassItem { contract_address: felt(1), class_hash: felt(0x30) },
+            ],
+        );
+
+        let (csd, duplicates) =
+            build_commitment_state_diff_with_policy(&state_update, DuplicatePolicy::LastWins).unwrap();
+
+        assert_eq!(duplicates, vec![DuplicateEntry::ReplacedClass(ContractAddress::from_field_element(felt(1)))]);
+        assert_eq!(
+            csd.address_to_class_hash[&ContractAddress::from_field_element(felt(1))],
+            ClassHash::from_field_element(felt(0x30))
+        );
+    }
+
+    #[test]
+    fn test_commitment_state_diff_to_state_diff() {
+        let mut state_update = state_update(
+            vec![DeployedContractItem { address: felt(0x11), class_hash: felt(0x10) }],
+            vec![ReplacedClassItem { contract_address: felt(0x12), class_hash: felt(0x30) }],
+        );
+        let state_diff = &mut state_update.state_diff;
+        state_diff.storage_diffs = vec![ContractStorageDiffItem {
+            address: felt(0x11),
+            storage_entries: vec![
+                StorageEntry { key: felt(5), value: felt(6) },
+                StorageEntry { key: felt(3), value: felt(4) },
+            ],
+        }];
+        state_diff.declared_classes =
+            vec![DeclaredClassItem { class_hash: felt(0x40), compiled_class_hash: felt(0x41) }];
+        state_diff.deprecated_declared_classes = vec![felt(0x50)];
+        state_diff.nonces = vec![NonceUpdate { contract_address: felt(0x12), nonce: felt(7) }];
+
+        let csd = build_commitment_state_diff(&state_update).unwrap();
+        // 0x12 was deployed before the block with class 0x20
+        let class_hash_before = |contract_address: &ContractAddress| {
+            Ok::<_, Infallible>(if *contract_address == ContractAddress::from_field_element(felt(0x12)) {
+                ClassHash::from_field_element(felt(0x20))
+            } else {
+                ClassHash::default()
+            })
+        };
+        let deprecated = [ClassHash::from_field_element(felt(0x50))];
+        assert_eq!(
+            commitment_state_diff_to_state_diff(&csd, class_hash_before, &deprecated).unwrap(),
+            state_update.state_diff
+        );
+
+        // Lookup failures are reported
+        let failing = |_: &ContractAddress| Err("unavailable");
+        assert_eq!(commitment_state_diff_to_state_diff(&csd, failing, &deprecated), Err("unavailable"));
+    }
+
+    #[test]
+    fn test_persisted_fencing_token() {
+        let backend: Backend = Arc::new(MemoryBackend::new());
+        let tries = StateTries::open(Arc::clone(&backend)).unwrap();
+
+        let config = ChainConfig::default();
+        let csd = |value: u64| CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: [(ContractAddress::from_field_element(felt(1)), Nonce(StarkFelt::from(value)))]
+                .into_iter()
+                .collect(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        // Commits are unfenced until a leader fences them off
+        try_update_state_root(&tries, csd(1), 1, &config).unwrap();
+        try_update_state_root_fenced(&tries, csd(2), 2, &config, FencingToken(2)).unwrap();
+        assert!(matches!(
+            try_update_state_root(&tries, csd(3), 3, &config),
+            Err(CommitError::Unfenced { current: FencingToken(2) })
+        ));
+
+        // The token survives a restart, which loses the registry
+        let tries = StateTries::open(Arc::clone(&backend)).unwrap();
+        assert!(matches!(
+            try_update_state_root_fenced(&tries, csd(3), 3, &config, FencingToken(1)),
+            Err(CommitError::Fenced { token: FencingToken(1), current: FencingToken(2) })
+        ));
+        // A former leader is fenced off even when the tries reject any commit
+        let mut writer = WriteHandle::acquire(tries.clone()).unwrap();
+        let warm = freeze(&mut writer);
+        assert!(matches!(
+            try_update_state_root_fenced(&tries, csd(3), 3, &config, FencingToken(1)),
+            Err(CommitError::Fenced { .. })
+        ));
+        thaw(&mut writer, warm, 0).unwrap();
+
+        // The token of a commit which fails is neither recorded nor persisted
+        let mut out_of_range = [0u8; 32];
+        out_of_range[0] = 0x08;
+        let mut invalid = csd(3);
+        let storage = [(StorageKey(PatriciaKey(StarkFelt::new(out_of_range).unwrap())), StarkFelt::ONE)];
+        invalid.storage_updates.insert(ContractAddress::from_field_element(felt(1)), storage.into_iter().collect());
+        assert!(try_update_state_root_fenced(&tries, invalid, 3, &config, FencingToken(4)).is_err());
+        assert_eq!(root_registry(&tries).fencing_token(), Some(FencingToken(2)));
+        assert_eq!(StateTries::open(backend).unwrap().engine().unwrap().fencing_token(), Some(FencingToken(2)));
+        try_update_state_root_fenced(&tries, csd(3), 3, &config, FencingToken(3)).unwrap();
+        assert_eq!(root_registry(&tries).fencing_token(), Some(FencingToken(3)));
+    }
+
+    #[test]
+    fn test_header_counts() {
+        let counts = HeaderCounts { transaction_count: 3, event_count: 5 };
+        assert_eq!(counts.check(3, 5), Ok(()));
+        assert_eq!(
+            counts.check(3, 4),
+            Err(CommitmentError::CountMismatch { item: CommitmentItem::Event, declared: 5, actual: 4 })
+        );
+        assert_eq!(
+            counts.check(4, 4),
+            Err(CommitmentError::CountMismatch { item: CommitmentItem::Transaction, declared: 3, actual: 4 })
+        );
+    }
 }
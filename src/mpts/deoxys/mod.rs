@@ -1,5 +1,89 @@
+pub mod abi;
+pub mod alias;
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod atomic;
+pub mod backfill;
+pub mod backend;
+pub mod batch;
+#[cfg(feature = "pedersen")]
+pub mod block;
+pub mod blockifier_reader;
+pub mod branches;
+pub mod cairo_serde;
+pub mod canary;
+pub mod canonical;
+pub mod checksum;
+pub mod class_hash;
+pub mod class_store;
+#[cfg(feature = "class-verification")]
+pub mod class_verification;
 pub mod classes;
+pub mod compression;
+pub mod config;
+pub mod consts;
+pub mod contract_proof;
 pub mod contracts;
+pub mod conversions;
+#[cfg(all(test, feature = "corpus"))]
+mod corpus;
+pub mod cost;
+pub mod duplicates;
+pub mod engine;
+pub mod error;
+#[cfg(feature = "pedersen")]
 pub mod events;
+#[cfg(feature = "explorer")]
+pub mod explorer;
+pub mod facts;
+pub mod follower;
+#[cfg(feature = "pedersen")]
+pub mod fork_simulation;
+pub mod framing;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handles;
+pub mod historical;
+pub mod history;
+pub mod import;
+pub mod ingestion;
+pub mod interchange;
+pub mod keys;
 pub mod lib;
+pub mod mutation_log;
+pub mod proof;
+pub mod proof_format;
+pub mod pruning;
+pub mod quarantine;
+pub mod receipts;
+pub mod recording;
+pub mod reorg;
+pub mod replication;
+pub mod report;
+pub mod retry;
+pub mod roots;
+pub mod runtime;
+pub mod scheduler;
+pub mod settings;
+pub mod shadow;
+pub mod squash;
+pub mod standby;
+pub mod state_diff;
+pub mod state_iter;
+pub mod state_reader;
+pub mod stats;
+#[cfg(feature = "pedersen")]
+pub mod storage_keys;
+pub mod storage_proof;
+pub mod system_contracts;
+#[cfg(feature = "pedersen")]
+pub mod token_proofs;
+#[cfg(feature = "pedersen")]
 pub mod transactions;
+pub mod trie_snapshot;
+pub mod upgrade;
+pub mod verified_read;
+pub mod verify;
+pub mod warmup;
+pub mod watchdog;
+pub mod write_back;
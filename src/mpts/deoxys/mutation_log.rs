@@ -0,0 +1,363 @@
+//! Append-only log of the leaves inserted in the tries, block after block, for debugging state root
+//! divergences between nodes.
+//!
+//! Unlike a [recording](super::recording), which captures a single block along with what it read,
+//! the mutation log runs for as long as the node does. When two nodes disagree on a state root,
+//! [first_divergence] over their logs points at the first leaf they inserted differently, and
+//! [replay] recomputes the state roots from a log alone.
+//!
+//! The leaves of a block are written once the block is committed, sorted by trie and key, so that
+//! the logs of two nodes committing the same blocks are identical regardless of the order the tries
+//! were updated in. Blocks which fail to commit are not logged. Each set of [StateTries] has its own
+//! log, [started](start_mutation_log) on the tries of the node's database or on those of an
+//! [engine](CommitmentEngine): the commits to other tries, ie: those of another chain or of a
+//! [branch](super::branches), are not logged along, even at the same heights.
+//!
+//! ```text
+//! file     := "SRML" version:u16 mutation*
+//! mutation := block_number:u64 trie:u8 contract_address:felt key:felt value:felt
+//! trie     := index of the trie in Trie::ALL
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use mp_felt::Felt252Wrapper;
+use starknet_types_core::felt::Felt;
+
+use super::atomic::Trie;
+use super::backend::MemoryBackend;
+use super::config::ChainConfig;
+use super::engine::{CommitmentEngine, StateTries};
+use super::error::TrieError;
+use super::recording::Interaction;
+
+pub const MAGIC: &[u8; 4] = b"SRML";
+pub const VERSION: u16 = 1;
+
+/// A leaf inserted in one of the tries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mutation {
+    pub block_number: u64,
+    pub trie: Trie,
+    /// The contract whose storage trie the leaf is in, zero for the contracts and classes tries.
+    pub contract_address: Felt,
+    /// The storage key, contract address or class hash of the leaf.
+    pub key: Felt,
+    /// The storage value, zero for deletions, or the leaf hash.
+    pub value: Felt,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MutationLogError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a mutation log")]
+    BadMagic,
+    #[error("unsupported mutation log format version {0}")]
+    UnsupportedVersion(u16),
+    #[error("mutation {0} has an unknown trie")]
+    UnknownTrie(usize),
+    #[error("mutation {0} holds a value which is not a felt")]
+    InvalidFelt(usize),
+    #[error("mutation {0} is truncated")]
+    Truncated(usize),
+}
+
+struct MutationLogger {
+    writer: Box<dyn Write + Send>,
+    /// Leaves inserted by the blocks being committed.
+    pending: BTreeMap<u64, Vec<Mutation>>,
+    /// The write which stopped the log, reported once it is [stopped](stop_mutation_log).
+    error: Option<io::Error>,
+}
+
+/// The mutation log of a set of tries, which only their commits feed.
+#[derive(Default)]
+pub(crate) struct MutationLog {
+    logging: AtomicBool,
+    logger: Mutex<Option<MutationLogger>>,
+}
+
+impl MutationLog {
+    fn logger(&self) -> MutexGuard<'_, Option<MutationLogger>> {
+        self.logger.lock().expect("Poisoned lock on mutation log")
+    }
+
+    /// Whether the commits are being logged.
+    pub(crate) fn is_logging(&self) -> bool {
+        self.logging.load(Ordering::Relaxed)
+    }
+
+    fn start(&self, writer: Box<dyn Write + Send>) {
+        // A previous log stopped by an error has nothing more to report
+        let _ = self.stop();
+        *self.logger() = Some(MutationLogger { writer, pending: BTreeMap::new(), error: None });
+        self.logging.store(true, Ordering::Relaxed);
+    }
+
+    fn stop(&self) -> io::Result<()> {
+        self.logging.store(false, Ordering::Relaxed);
+        let Some(mut logger) = self.logger().take() else {
+            return Ok(());
+        };
+        match logger.error {
+            Some(e) => Err(e),
+            None => logger.writer.flush(),
+        }
+    }
+
+    /// Stages the leaf inserted by an interaction of the commit of `block_number`, if it inserted
+    /// one.
+    pub(crate) fn log_interaction(&self, block_number: u64, interaction: &Interaction) {
+        let (trie, contract_address, key, value) = match *interaction {
+            Interaction::StorageWrite { contract_address, key, value } => {
+                (Trie::ContractStorage, contract_address, key, value)
+            }
+            Interaction::ContractLeaf { contract_address, leaf_hash } => {
+                (Trie::Contracts, Felt::ZERO, contract_address, leaf_hash)
+            }
+            Interaction::ClassLeaf { class_hash, leaf_hash } => (Trie::Classes, Felt::ZERO, class_hash, leaf_hash),
+            _ => return,
+        };
+        if let Some(logger) = self.logger().as_mut() {
+            logger.pending.entry(block_number).or_default().push(Mutation {
+                block_number,
+                trie,
+                contract_address,
+                key,
+                value,
+            });
+        }
+    }
+
+    /// Ends the block being committed: its leaves are written to the log if it was committed, and
+    /// dropped otherwise.
+    pub(crate) fn end_block(&self, block_number: u64, committed: bool) {
+        if !self.is_logging() {
+            return;
+        }
+        let mut logger = self.logger();
+        let Some(logger) = logger.as_mut() else {
+            return;
+        };
+        let mut mutations = logger.pending.remove(&block_number).unwrap_or_default();
+        if !committed {
+            return;
+        }
+        mutations.sort_by_key(sort_key);
+        if let Err(e) = write_mutations(&mut logger.writer, &mutations) {
+            self.logging.store(false, Ordering::Relaxed);
+            logger.error = Some(e);
+        }
+    }
+}
+
+/// Starts logging the leaves inserted by the commits to `tries` to the file at `path`, appending to
+/// it if it already holds a log. A log of the tries already running is stopped first.
+pub fn start_mutation_log(tries: &StateTries, path: impl AsRef<Path>) -> Result<(), MutationLogError> {
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_be_bytes())?;
+    } else {
+        read_header(&mut file)?;
+    }
+    tries.state().capture.mutation_log.start(Box::new(BufWriter::new(file)));
+    Ok(())
+}
+
+/// Stops logging the commits to `tries` and flushes the log.
+///
+/// # Returns
+///
+/// The error which stopped the log early, if writing to it failed. Commits never fail because of
+/// the log.
+pub fn stop_mutation_log(tries: &StateTries) -> io::Result<()> {
+    tries.state().capture.mutation_log.stop()
+}
+
+fn sort_key(mutation: &Mutation) -> (u8, [u8; 32], [u8; 32]) {
+    (trie_index(mutation.trie), mutation.contract_address.to_bytes_be(), mutation.key.to_bytes_be())
+}
+
+fn trie_index(trie: Trie) -> u8 {
+    Trie::ALL.iter().position(|other| *other == trie).expect("Trie::ALL holds every trie") as u8
+}
+
+/// Writes mutations in the format of the log, without its header.
+pub fn write_mutations<W: Write + ?Sized>(writer: &mut W, mutations: &[Mutation]) -> io::Result<()> {
+    for mutation in mutations {
+        writer.write_all(&mutation.block_number.to_be_bytes())?;
+        writer.write_all(&[trie_index(mutation.trie)])?;
+        writer.write_all(&mutation.contract_address.to_bytes_be())?;
+        writer.write_all(&mutation.key.to_bytes_be())?;
+        writer.write_all(&mutation.value.to_bytes_be())?;
+    }
+    Ok(())
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<(), MutationLogError> {
+    let mut header = [0u8; 6];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(MutationLogError::BadMagic);
+    }
+    let version = u16::from_be_bytes([header[4], header[5]]);
+    if version != VERSION {
+        return Err(MutationLogError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+/// Reads a mutation log, header included.
+pub fn read_mutation_log<R: Read>(reader: &mut R) -> Result<Vec<Mutation>, MutationLogError> {
+    read_header(reader)?;
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    const LEN: usize = 8 + 1 + 3 * 32;
+    let mut mutations = Vec::with_capacity(bytes.len() / LEN);
+    for (index, record) in bytes.chunks(LEN).enumerate() {
+        if record.len() != LEN {
+            return Err(MutationLogError::Truncated(index));
+        }
+        let felt = |offset: usize| {
+            let bytes: [u8; 32] = record[offset..offset + 32].try_into().expect("32 bytes");
+            let felt = Felt::from_bytes_be(&bytes);
+            (felt.to_bytes_be() == bytes).then_some(felt).ok_or(MutationLogError::InvalidFelt(index))
+        };
+        mutations.push(Mutation {
+            block_number: u64::from_be_bytes(record[..8].try_into().expect("8 bytes")),
+            trie: *Trie::ALL.get(record[8] as usize).ok_or(MutationLogError::UnknownTrie(index))?,
+            contract_address: felt(9)?,
+            key: felt(41)?,
+            value: felt(73)?,
+        });
+    }
+    Ok(mutations)
+}
+
+/// The first mutation two logs disagree on, `None` on the side of a log which ended first.
+///
+/// # Returns
+///
+/// `None` if the logs are identical.
+pub fn first_divergence(left: &[Mutation], right: &[Mutation]) -> Option<(Option<Mutation>, Option<Mutation>)> {
+    let common = left.iter().zip(right).position(|(left, right)| left != right);
+    let index = common.unwrap_or(left.len().min(right.len()));
+    let divergence = (left.get(index).copied(), right.get(index).copied());
+    (divergence != (None, None)).then_some(divergence)
+}
+
+/// Recomputes the state root after each block of a log, from the logged leaves alone.
+///
+/// A block logged again after later blocks, ie: once the node reverted to before it, is replayed
+/// on top of the state of its parent block.
+///
+/// # Returns
+///
+/// The state root after each logged block, in the order of the log.
+pub fn replay(mutations: &[Mutation], config: &ChainConfig) -> Result<Vec<(u64, Felt252Wrapper)>, TrieError> {
+    let mut engine = CommitmentEngine::with_config(Arc::new(MemoryBackend::new()), config)?;
+    let mut state_roots = Vec::new();
+    // Blocks without leaves are not logged, a block is replayed on top of the latest logged one
+    // before it
+    let mut replayed = Vec::<u64>::new();
+    let mut rest = mutations;
+    while let Some(first) = rest.first() {
+        let block_number = first.block_number;
+        let len = rest.iter().position(|mutation| mutation.block_number != block_number).unwrap_or(rest.len());
+        let (block, next) = rest.split_at(len);
+        rest = next;
+
+        if replayed.last().is_some_and(|latest| *latest >= block_number) {
+            replayed.retain(|replayed| *replayed < block_number);
+            match replayed.last() {
+                Some(parent) => engine.revert_to(*parent)?,
+                None => engine = CommitmentEngine::with_config(Arc::new(MemoryBackend::new()), config)?,
+            }
+        }
+        engine.commit_leaves(block, block_number)?;
+        replayed.push(block_number);
+        state_roots.push((block_number, engine.state_root(config)?));
+    }
+    Ok(state_roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::handles::WriteHandle;
+
+    fn csd(value: u64) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+        let storage = [
+            (StorageKey(PatriciaKey(StarkFelt::TWO)), StarkFelt::from(value)),
+            (StorageKey(PatriciaKey(StarkFelt::THREE)), StarkFelt::from(value * 2)),
+        ];
+        CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::THREE))].into_iter().collect(),
+            address_to_nonce: [(contract_address, Nonce(StarkFelt::from(value)))].into_iter().collect(),
+            storage_updates: [(contract_address, storage.into_iter().collect())].into_iter().collect(),
+            class_hash_to_compiled_class_hash: [(
+                ClassHash(StarkFelt::from(value)),
+                CompiledClassHash(StarkFelt::from(value + 1)),
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn test_replay_log() {
+        let config = ChainConfig::default();
+        let path = std::env::temp_dir().join(format!("starkroot-mutation-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The leaves are captured from the commits themselves, those of other tries at the same
+        // heights are not
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+        start_mutation_log(&tries, &path).unwrap();
+        let mut writer = WriteHandle::acquire(tries.clone()).unwrap();
+        let mut other = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+        let state_roots = (0..3_u64)
+            .map(|block_number| {
+                other.commit(csd(block_number + 20), block_number, &config).unwrap();
+                (block_number, writer.commit(csd(block_number + 7), block_number, &config).unwrap())
+            })
+            .collect::<Vec<_>>();
+        stop_mutation_log(&tries).unwrap();
+
+        let mut log = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mutations = read_mutation_log(&mut log.as_slice()).unwrap();
+        assert_eq!(mutations.len(), 3 * 4);
+        assert_eq!(replay(&mutations, &config).unwrap(), state_roots);
+
+        // A node which wrote another value
+        let mut diverging = mutations.clone();
+        diverging[0].value = Felt::from(8_u64);
+        assert_eq!(first_divergence(&mutations, &diverging), Some((Some(mutations[0]), Some(diverging[0]))));
+        assert_eq!(first_divergence(&mutations, &mutations[..2]), Some((Some(mutations[2]), None)));
+        assert_eq!(first_divergence(&mutations, &mutations), None);
+
+        // Logs round trip through their format
+        let mut written = MAGIC.to_vec();
+        written.extend(VERSION.to_be_bytes());
+        write_mutations(&mut written, &mutations).unwrap();
+        assert_eq!(read_mutation_log(&mut written.as_slice()).unwrap(), mutations);
+
+        log.truncate(log.len() - 1);
+        assert!(matches!(read_mutation_log(&mut log.as_slice()), Err(MutationLogError::Truncated(_))));
+    }
+}
@@ -0,0 +1,55 @@
+use starknet_types_core::hash::Pedersen;
+pub use starkroot_types::proof::{felt_to_path, path_to_felt, verify_proof, ProofError, ProofNode};
+
+/// Hash of the nodes of the contract storage tries and of the contracts trie of the node's database.
+///
+/// The node's database hashes the nodes of these tries with Pedersen whatever the `pedersen` feature,
+/// which only selects the hashes of the leaves and commitments. Engine tries hash their nodes with
+/// the [configured](super::config::TrieHashers::storage_node) node hashes, which default to it.
+pub type StateTrieHash = Pedersen;
+
+/// Converts a node of a proof generated by bonsai-trie.
+pub fn from_bonsai(node: bonsai_trie::ProofNode) -> ProofNode {
+    match node {
+        bonsai_trie::ProofNode::Binary { left, right } => ProofNode::Binary { left, right },
+        bonsai_trie::ProofNode::Edge { child, path } => ProofNode::Edge { child, path: path.0 },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bonsai_trie::databases::HashMapDb;
+    use bonsai_trie::id::{BasicId, BasicIdBuilder};
+    use bonsai_trie::{BonsaiStorage, BonsaiStorageConfig};
+    use starknet_types_core::felt::Felt;
+    use starknet_types_core::hash::Pedersen;
+
+    use super::*;
+
+    #[test]
+    fn test_verify_proof() {
+        let mut storage =
+            BonsaiStorage::<_, _, Pedersen>::new(HashMapDb::<BasicId>::default(), BonsaiStorageConfig::default())
+                .unwrap();
+        let entries = [(1_u64, 10_u64), (2, 20), (0x1000, 30)];
+        for (key, value) in entries {
+            storage.insert(b"test", &felt_to_path(&Felt::from(key)), &Felt::from(value)).unwrap();
+        }
+        storage.commit(BasicIdBuilder::new().new_id()).unwrap();
+        let root = storage.root_hash(b"test").unwrap();
+
+        let prove = |key: u64| {
+            let key = felt_to_path(&Felt::from(key));
+            let proof = storage.get_proof(b"test", &key).unwrap();
+            let proof = proof.into_iter().map(from_bonsai).collect::<Vec<_>>();
+            verify_proof::<Pedersen>(root, &key, &proof)
+        };
+
+        assert_eq!(prove(2), Ok(Some(Felt::from(20_u64))));
+        assert_eq!(prove(3), Ok(None));
+        assert!(matches!(
+            verify_proof::<Pedersen>(root + Felt::ONE, &felt_to_path(&Felt::from(2_u64)), &[]),
+            Err(ProofError::Incomplete { height: 0 })
+        ));
+    }
+}
@@ -0,0 +1,417 @@
+//! Conversions of the trie proofs between the formats of the different node implementations.
+//!
+//! - This crate's [ProofNode] lists, ordered from the root down to the leaf.
+//! - The `pathfinder_getProof` JSON, which holds the same ordered lists, see [StorageProof::to_json].
+//! - The RPC 0.8 `starknet_getStorageProof` JSON, which holds the unordered set of the nodes of all
+//!   the proofs of a trie, each node along with its hash. Proofs are told apart by walking the set
+//!   from the root down the key.
+
+use std::collections::{HashMap, HashSet};
+
+use bitvec::prelude::{BitSlice, BitVec, Msb0};
+use bitvec::view::BitView;
+use serde_json::{json, Value};
+use starknet_api::core::ContractAddress;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Pedersen, Poseidon, StarkHash};
+
+use super::config::{ChainConfig, NodeHash};
+use super::proof::{felt_to_path, path_to_felt, ProofNode, StateTrieHash};
+use super::storage_proof::{state_commitment, ContractData, StorageProof};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ProofFormatError {
+    #[error("invalid proof field `{0}`")]
+    Invalid(&'static str),
+    #[error("proof node {0:#x} is missing")]
+    MissingNode(Felt),
+    #[error("proof node listed as {0:#x} has a different hash")]
+    HashMismatch(Felt),
+    #[error("proof is deeper than the trie height")]
+    TooDeep,
+}
+
+fn parse_felt(value: &Value, field: &'static str) -> Result<Felt, ProofFormatError> {
+    let felt = value.as_str().and_then(|value| FieldElement::from_hex_be(value).ok());
+    felt.map(|felt| Felt::from_bytes_be(&felt.to_bytes_be())).ok_or(ProofFormatError::Invalid(field))
+}
+
+fn felt_json(felt: Felt) -> Value {
+    json!(format!("{felt:#x}"))
+}
+
+/// The `len` lowest bits of `value`, the path of an edge node.
+fn edge_path(value: Felt, len: &Value) -> Result<BitVec<u8, Msb0>, ProofFormatError> {
+    let len = len.as_u64().filter(|len| *len <= 251).ok_or(ProofFormatError::Invalid("len"))? as usize;
+    let bytes = value.to_bytes_be();
+    let bits = bytes.view_bits::<Msb0>();
+    if bits[..256 - len].any() {
+        return Err(ProofFormatError::Invalid("path"));
+    }
+    Ok(bits[256 - len..].to_bitvec())
+}
+
+/// The JSON formats of the proof nodes.
+pub trait ProofNodeJson: Sized {
+    /// Serializes the node as in the `pathfinder_getProof` response.
+    fn to_json(&self) -> Value;
+
+    /// Parses a node serialized as in the `pathfinder_getProof` response, see [ProofNodeJson::to_json].
+    fn from_json(value: &Value) -> Result<Self, ProofFormatError>;
+
+    /// Serializes the node as a `MERKLE_NODE` of the RPC 0.8 specification.
+    fn to_rpc_json(&self) -> Value;
+
+    /// Parses a `MERKLE_NODE` of the RPC 0.8 specification.
+    fn from_rpc_json(value: &Value) -> Result<Self, ProofFormatError>;
+}
+
+impl ProofNodeJson for ProofNode {
+    fn to_json(&self) -> Value {
+        match self {
+            ProofNode::Binary { left, right } => {
+                json!({ "binary": { "left": felt_json(*left), "right": felt_json(*right) } })
+            }
+            ProofNode::Edge { child, path } => json!({
+                "edge": {
+                    "child": felt_json(*child),
+                    "path": { "value": felt_json(path_to_felt(path)), "len": path.len() },
+                }
+            }),
+        }
+    }
+
+    fn from_json(value: &Value) -> Result<Self, ProofFormatError> {
+        if let Some(binary) = value.get("binary") {
+            let left = parse_felt(&binary["left"], "left")?;
+            let right = parse_felt(&binary["right"], "right")?;
+            return Ok(ProofNode::Binary { left, right });
+        }
+        let edge = value.get("edge").ok_or(ProofFormatError::Invalid("node"))?;
+        let child = parse_felt(&edge["child"], "child")?;
+        let path = edge_path(parse_felt(&edge["path"]["value"], "path")?, &edge["path"]["len"])?;
+        Ok(ProofNode::Edge { child, path })
+    }
+
+    fn to_rpc_json(&self) -> Value {
+        match self {
+            ProofNode::Binary { left, right } => json!({ "left": felt_json(*left), "right": felt_json(*right) }),
+            ProofNode::Edge { child, path } => json!({
+                "path": felt_json(path_to_felt(path)),
+                "length": path.len(),
+                "child": felt_json(*child),
+            }),
+        }
+    }
+
+    fn from_rpc_json(value: &Value) -> Result<Self, ProofFormatError> {
+        if value.get("left").is_some() {
+            let left = parse_felt(&value["left"], "left")?;
+            let right = parse_felt(&value["right"], "right")?;
+            return Ok(ProofNode::Binary { left, right });
+        }
+        let child = parse_felt(&value["child"], "child")?;
+        let path = edge_path(parse_felt(&value["path"], "path")?, &value["length"])?;
+        Ok(ProofNode::Edge { child, path })
+    }
+}
+
+/// Parses a proof serialized as in the `pathfinder_getProof` response.
+pub fn proof_from_json(value: &Value) -> Result<Vec<ProofNode>, ProofFormatError> {
+    value.as_array().ok_or(ProofFormatError::Invalid("proof"))?.iter().map(ProofNode::from_json).collect()
+}
+
+/// Serializes proofs of the same trie as a `NODE_HASH_TO_NODE_MAPPING` of the RPC 0.8 specification.
+///
+/// Nodes shared by several proofs are only listed once.
+pub fn proofs_to_rpc_json<'a, H: StarkHash>(proofs: impl IntoIterator<Item = &'a [ProofNode]>) -> Value {
+    let mut nodes = Vec::new();
+    let mut seen = HashSet::new();
+    for node in proofs.into_iter().flatten() {
+        let hash = node.hash::<H>();
+        if seen.insert(hash) {
+            nodes.push(json!({ "node_hash": felt_json(hash), "node": node.to_rpc_json() }));
+        }
+    }
+    Value::Array(nodes)
+}
+
+/// Nodes of a `NODE_HASH_TO_NODE_MAPPING` of the RPC 0.8 specification, by hash.
+pub struct RpcProofNodes(HashMap<Felt, ProofNode>);
+
+impl RpcProofNodes {
+    pub fn from_json(value: &Value) -> Result<Self, ProofFormatError> {
+        let nodes = value.as_array().ok_or(ProofFormatError::Invalid("nodes"))?;
+        let nodes = nodes
+            .iter()
+            .map(|node| Ok((parse_felt(&node["node_hash"], "node_hash")?, ProofNode::from_rpc_json(&node["node"])?)))
+            .collect::<Result<_, ProofFormatError>>()?;
+        Ok(Self(nodes))
+    }
+
+    /// Extracts the proof of `key`, root first, by walking the nodes from `root` down the key.
+    ///
+    /// The nodes come from untrusted responses: each of them is checked to hash to the hash it is
+    /// listed under, so that the extracted proof is a path from `root`, and the walk stops at the
+    /// height of the tries. The leaf must still be verified against the proof.
+    pub fn proof<H: StarkHash>(
+        &self,
+        root: Felt,
+        key: &BitSlice<u8, Msb0>,
+    ) -> Result<Vec<ProofNode>, ProofFormatError> {
+        let mut proof = Vec::new();
+        if root == Felt::ZERO {
+            return Ok(proof);
+        }
+
+        let (mut hash, mut height) = (root, 0);
+        while height < key.len() {
+            if proof.len() >= 251 {
+                return Err(ProofFormatError::TooDeep);
+            }
+            let node = self.0.get(&hash).ok_or(ProofFormatError::MissingNode(hash))?;
+            if node.hash::<H>() != hash {
+                return Err(ProofFormatError::HashMismatch(hash));
+            }
+            proof.push(node.clone());
+            match node {
+                ProofNode::Binary { left, right } => {
+                    hash = if key[height] { *right } else { *left };
+                    height += 1;
+                }
+                ProofNode::Edge { child, path } => {
+                    if key.get(height..height + path.len()) != Some(path.as_bitslice()) {
+                        // The key diverges from the edge, the proof is a non-membership proof
+                        break;
+                    }
+                    hash = *child;
+                    height += path.len();
+                }
+            }
+        }
+        Ok(proof)
+    }
+
+    /// Extracts the proof of `key` from the nodes hashed with `hash`, see [proof](Self::proof).
+    pub fn proof_hashed(
+        &self,
+        hash: NodeHash,
+        root: Felt,
+        key: &BitSlice<u8, Msb0>,
+    ) -> Result<Vec<ProofNode>, ProofFormatError> {
+        match hash {
+            NodeHash::Pedersen => self.proof::<Pedersen>(root, key),
+            NodeHash::Poseidon => self.proof::<Poseidon>(root, key),
+        }
+    }
+}
+
+fn key_path(key: &StorageKey) -> BitVec<u8, Msb0> {
+    felt_to_path(&Felt::from_bytes_be(&key.0.key().0))
+}
+
+impl StorageProof {
+    /// Parses a `pathfinder_getProof` response, see [StorageProof::to_json].
+    ///
+    /// # Arguments
+    ///
+    /// * `value`            - The response.
+    /// * `contract_address` - The contract whose storage is proven, which the response does not hold.
+    /// * `block_number`     - The block the proof was generated at, which the response does not hold.
+    pub fn from_json(
+        value: &Value,
+        contract_address: ContractAddress,
+        block_number: u64,
+    ) -> Result<Self, ProofFormatError> {
+        let state_commitment = parse_felt(&value["state_commitment"], "state_commitment")?;
+        let class_commitment = parse_felt(&value["class_commitment"], "class_commitment")?;
+        let contract_proof = proof_from_json(&value["contract_proof"])?;
+        // The contracts trie root is the hash of the first node of the contract proof
+        let contracts_trie_root = contract_proof.first().map_or(Felt::ZERO, ProofNode::hash::<StateTrieHash>);
+
+        let contract_data = match &value["contract_data"] {
+            Value::Null => None,
+            data => Some(ContractData {
+                class_hash: parse_felt(&data["class_hash"], "class_hash")?,
+                nonce: parse_felt(&data["nonce"], "nonce")?,
+                root: parse_felt(&data["root"], "root")?,
+                contract_state_hash_version: parse_felt(
+                    &data["contract_state_hash_version"],
+                    "contract_state_hash_version",
+                )?,
+                storage_proofs: data["storage_proofs"]
+                    .as_array()
+                    .ok_or(ProofFormatError::Invalid("storage_proofs"))?
+                    .iter()
+                    .map(proof_from_json)
+                    .collect::<Result<_, _>>()?,
+            }),
+        };
+
+        Ok(Self {
+            block_number,
+            contract_address,
+            state_commitment,
+            class_commitment,
+            contracts_trie_root,
+            contract_proof,
+            contract_data,
+        })
+    }
+
+    /// Serializes the proof as a `starknet_getStorageProof` response of the RPC 0.8 specification.
+    ///
+    /// The response has no class proofs, and no block hash as the tries do not know it.
+    pub fn to_rpc_json(&self) -> Value {
+        let storage_proofs = self
+            .contract_data
+            .iter()
+            .map(|data| proofs_to_rpc_json::<StateTrieHash>(data.storage_proofs.iter().map(Vec::as_slice)));
+        let leaves = self.contract_data.iter().map(|data| {
+            json!({
+                "nonce": felt_json(data.nonce),
+                "class_hash": felt_json(data.class_hash),
+                "storage_root": felt_json(data.root),
+            })
+        });
+
+        json!({
+            "classes_proof": [],
+            "contracts_proof": {
+                "nodes": proofs_to_rpc_json::<StateTrieHash>([self.contract_proof.as_slice()]),
+                "contract_leaves_data": leaves.collect::<Vec<_>>(),
+            },
+            "contracts_storage_proofs": storage_proofs.collect::<Vec<_>>(),
+            "global_roots": {
+                "contracts_tree_root": felt_json(self.contracts_trie_root),
+                "classes_tree_root": felt_json(self.class_commitment),
+            },
+        })
+    }
+
+    /// Parses a `starknet_getStorageProof` response of the RPC 0.8 specification, for a single
+    /// contract.
+    ///
+    /// # Arguments
+    ///
+    /// * `value`            - The response.
+    /// * `contract_address` - The contract whose storage is proven.
+    /// * `keys`             - The storage keys which were requested, in the same order.
+    /// * `block_number`     - The block the proof was generated at.
+    /// * `config`           - Chain-specific commitment rules, for the node hashes, the contract
+    ///   leaves and the state commitment.
+    pub fn from_rpc_json(
+        value: &Value,
+        contract_address: ContractAddress,
+        keys: &[StorageKey],
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Self, ProofFormatError> {
+        let roots = &value["global_roots"];
+        let contracts_trie_root = parse_felt(&roots["contracts_tree_root"], "contracts_tree_root")?;
+        let class_commitment = parse_felt(&roots["classes_tree_root"], "classes_tree_root")?;
+
+        let contracts = &value["contracts_proof"];
+        let contract_key = felt_to_path(&Felt::from_bytes_be(&contract_address.0.key().0));
+        let contract_proof = RpcProofNodes::from_json(&contracts["nodes"])?.proof_hashed(
+            config.hashers.contracts_node,
+            contracts_trie_root,
+            &contract_key,
+        )?;
+
+        let contract_data = match contracts["contract_leaves_data"].get(0) {
+            None => None,
+            Some(leaf) => {
+                let root = parse_felt(&leaf["storage_root"], "storage_root")?;
+                let storage_nodes = RpcProofNodes::from_json(&value["contracts_storage_proofs"][0])?;
+                Some(ContractData {
+                    class_hash: parse_felt(&leaf["class_hash"], "class_hash")?,
+                    nonce: parse_felt(&leaf["nonce"], "nonce")?,
+                    root,
+                    contract_state_hash_version: Felt::from_bytes_be(
+                        &config.constants.contract_state_hash_version.to_bytes_be(),
+                    ),
+                    storage_proofs: keys
+                        .iter()
+                        .map(|key| storage_nodes.proof_hashed(config.hashers.storage_node, root, &key_path(key)))
+                        .collect::<Result<_, _>>()?,
+                })
+            }
+        };
+
+        Ok(Self {
+            block_number,
+            contract_address,
+            state_commitment: state_commitment(contracts_trie_root, class_commitment, config),
+            class_commitment,
+            contracts_trie_root,
+            contract_proof,
+            contract_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::hash::StarkFelt;
+    use starknet_types_core::hash::Pedersen;
+
+    use super::*;
+
+    /// An edge from `height` down to the leaf at `key`.
+    fn edge(child: u64, key: Felt, height: usize) -> ProofNode {
+        ProofNode::Edge { child: Felt::from(child), path: felt_to_path(&key)[height..].to_bitvec() }
+    }
+
+    #[test]
+    fn test_node_round_trip() {
+        for node in [ProofNode::Binary { left: Felt::ONE, right: Felt::TWO }, edge(10, Felt::from(0x1234_u64), 3)] {
+            assert_eq!(ProofNode::from_json(&node.to_json()), Ok(node.clone()));
+            assert_eq!(ProofNode::from_rpc_json(&node.to_rpc_json()), Ok(node));
+        }
+    }
+
+    #[test]
+    fn test_rpc_proof() {
+        // A trie with two leaves whose keys only differ by their first bit
+        let key = Felt::from(0x1234_u64);
+        let mut first_bit = [0u8; 32];
+        first_bit[0] = 0x04;
+        let (left, right) = (edge(10, key, 1), edge(20, key + Felt::from_bytes_be(&first_bit), 1));
+        let root_node = ProofNode::Binary { left: left.hash::<Pedersen>(), right: right.hash::<Pedersen>() };
+        let root = root_node.hash::<Pedersen>();
+        let proofs = [vec![root_node.clone(), left.clone()], vec![root_node.clone(), right]];
+
+        let nodes = proofs_to_rpc_json::<Pedersen>(proofs.iter().map(Vec::as_slice));
+        assert_eq!(nodes.as_array().unwrap().len(), 3);
+
+        let nodes = RpcProofNodes::from_json(&nodes).unwrap();
+        let key = felt_to_path(&key);
+        assert_eq!(nodes.proof::<Pedersen>(root, &key), Ok(proofs[0].clone()));
+        // The nodes are checked with the node hash of the trie they are read from
+        assert_eq!(nodes.proof_hashed(NodeHash::Pedersen, root, &key), Ok(proofs[0].clone()));
+        assert_eq!(nodes.proof_hashed(NodeHash::Poseidon, root, &key), Err(ProofFormatError::HashMismatch(root)));
+
+        let storage_key = StorageKey(PatriciaKey(StarkFelt::from(0x1234_u64)));
+        assert_eq!(key_path(&storage_key), key);
+    }
+
+    #[test]
+    fn test_untrusted_rpc_proof() {
+        let key = felt_to_path(&Felt::from(0x1234_u64));
+        let leaf = edge(10, Felt::from(0x1234_u64), 0);
+
+        // A node listed under another hash
+        let nodes = json!([{ "node_hash": "0x1", "node": leaf.to_rpc_json() }]);
+        let nodes = RpcProofNodes::from_json(&nodes).unwrap();
+        assert_eq!(nodes.proof::<Pedersen>(Felt::ONE, &key), Err(ProofFormatError::HashMismatch(Felt::ONE)));
+
+        // A zero-length edge pointing to itself
+        let zero_length = json!({ "path": "0x0", "length": 0, "child": "0x1" });
+        let nodes = RpcProofNodes(HashMap::from([(Felt::ONE, ProofNode::from_rpc_json(&zero_length).unwrap())]));
+        assert!(nodes.proof::<Pedersen>(Felt::ONE, &key).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use mc_db::{Column as DbColumn, DatabaseExt, DeoxysBackend};
+
+use super::atomic::Trie;
+use super::backend::BackendError;
+use super::engine::{reclaim_empty_storage, StateTries};
+use super::error::{ErrorContext, ResultExt, TrieError};
+use super::history::{contract_activity, storage_history};
+use super::roots::{root_registry, Finality, RootRegistry};
+use super::settings::RetentionSettings;
+use super::squash::empty_storage_tracker;
+
+/// Which blocks the versions of the tries and the per-block data kept by this crate (the
+/// [root registry](root_registry) and the [storage](storage_history) and [activity](contract_activity)
+/// indexes) are pruned up to.
+///
+/// Each bound which is set restricts pruning further, nothing is pruned when none is:
+///
+/// * `retention` keeps the latest `retention` blocks.
+/// * `finality_margin` keeps the blocks which are not older than the latest block accepted on L1 by
+///   more than `finality_margin` blocks, so that a reorg of blocks which are not settled on L1 yet
+///   can always be handled. Nothing is pruned until a block is accepted on L1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrunePolicy {
+    pub retention: Option<u64>,
+    pub finality_margin: Option<u64>,
+}
+
+impl From<RetentionSettings> for PrunePolicy {
+    fn from(settings: RetentionSettings) -> Self {
+        Self { retention: settings.blocks, finality_margin: settings.finality_margin }
+    }
+}
+
+impl PrunePolicy {
+    /// Returns the first block to keep, the data of the blocks before it can be pruned.
+    ///
+    /// # Returns
+    ///
+    /// `None` if nothing can be pruned.
+    pub fn horizon(&self, registry: &RootRegistry) -> Option<u64> {
+        if self.retention.is_none() && self.finality_margin.is_none() {
+            return None;
+        }
+        let (latest, _) = registry.latest()?;
+
+        let mut horizon = latest;
+        if let Some(retention) = self.retention {
+            horizon = horizon.min((latest + 1).saturating_sub(retention));
+        }
+        if let Some(margin) = self.finality_margin {
+            let settled = registry.latest_with_finality(Finality::AcceptedOnL1)?;
+            horizon = horizon.min(settled.saturating_sub(margin));
+        }
+        Some(horizon)
+    }
+}
+
+/// What [prune] forgot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// The first block kept, `None` if nothing could be pruned.
+    pub horizon: Option<u64>,
+    /// Number of blocks removed from the root registry.
+    pub blocks: usize,
+    /// Number of entries removed from the storage and activity indexes.
+    pub index_entries: usize,
+    /// Number of trie log entries deleted, if the tries are those of an
+    /// [engine](super::engine::CommitmentEngine). The trie logs of the node's database are deleted
+    /// by range, without counting them.
+    pub trie_log_entries: Option<usize>,
+    /// Number of entries of the storage tries emptied before the horizon which were deleted, if the
+    /// tries are those of an [engine](super::engine::CommitmentEngine). The node's database drops
+    /// the nodes of an emptied trie as it is committed, only its trie logs are left.
+    pub storage_entries: Option<usize>,
+}
+
+/// The column holding the trie logs of a global trie in the node's database.
+fn trie_log_column(trie: Trie) -> DbColumn {
+    match trie {
+        Trie::ContractStorage => DbColumn::BonsaiContractsStorageLog,
+        Trie::Contracts => DbColumn::BonsaiContractsLog,
+        Trie::Classes => DbColumn::BonsaiClassesLog,
+    }
+}
+
+/// Deletes the trie logs of the global tries for the blocks before `block_number`, which can no
+/// longer be [reverted](super::handles::WriteHandle::revert_to) to.
+///
+/// Trie log keys start with the big-endian block number they were committed at, so each trie is
+/// pruned with a single range deletion whatever the number of logs. Bonsai only keeps the snapshots
+/// of the global tries in memory, bounded by the configuration of the node's database.
+pub(crate) fn prune_trie_logs(block_number: u64) -> Result<(), TrieError> {
+    let db = DeoxysBackend::expose_db();
+    for trie in Trie::ALL {
+        let column = db.get_column(trie_log_column(trie));
+        db.delete_range_cf(&column, 0_u64.to_be_bytes(), block_number.to_be_bytes())
+            .map_err(|e| BackendError::Io(e.to_string()))
+            .context(|| ErrorContext::block(block_number).trie(trie))?;
+    }
+    Ok(())
+}
+
+/// Prunes the versions of the tries and the per-block data older than the
+/// [horizon](PrunePolicy::horizon) of `policy`.
+///
+/// The tries can no longer be reverted to the pruned blocks, nor their state read, and neither can
+/// they be looked up in the root registry. The storage index no longer answers for the blocks
+/// before the horizon. The latest state is always kept.
+///
+/// # Arguments
+///
+/// * `tries`  - The tries to prune.
+/// * `policy` - Which blocks to prune.
+pub(crate) fn prune(tries: &StateTries, policy: &PrunePolicy) -> Result<PruneReport, TrieError> {
+    let (horizon, blocks) = {
+        let mut registry = root_registry(tries);
+        match policy.horizon(&registry) {
+            Some(horizon) => (horizon, registry.prune_before(horizon)),
+            None => return Ok(PruneReport::default()),
+        }
+    };
+    let index_entries = storage_history(tries).prune_before(horizon) + contract_activity(tries).prune_before(horizon);
+    let (trie_log_entries, storage_entries) = match tries.engine() {
+        Some(mut engine) => {
+            let trie_log_entries = engine.prune_before(horizon)?.trie_log_entries;
+            (Some(trie_log_entries), Some(reclaim_empty_storage(tries, &mut engine)?))
+        }
+        None => {
+            prune_trie_logs(horizon)?;
+            let mut empty_storage = empty_storage_tracker(tries);
+            let reclaimed = empty_storage.reclaimable(horizon, 0).copied().collect::<Vec<_>>();
+            for contract_address in reclaimed.iter() {
+                empty_storage.forget(contract_address);
+            }
+            (None, None)
+        }
+    };
+
+    Ok(PruneReport { horizon: Some(horizon), blocks, index_entries, trie_log_entries, storage_entries })
+}
+
+/// Prunes according to the `retention` settings of `tries`, once blocks are accepted on L1.
+///
+/// This only runs when `retention.finality_margin` is set: retention alone does not depend on
+/// finality and is applied with [WriteHandle::prune](super::handles::WriteHandle::prune).
+pub(crate) fn auto_prune(tries: &StateTries) -> Result<PruneReport, TrieError> {
+    let policy = PrunePolicy::from(tries.execution().retention);
+    if policy.finality_margin.is_none() {
+        return Ok(PruneReport::default());
+    }
+    prune(tries, &policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use mp_felt::Felt252Wrapper;
+    use starknet_api::core::{ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+    use starknet_types_core::felt::Felt;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+    use crate::mpts::deoxys::stats::CommitStats;
+
+    #[test]
+    fn test_horizon() {
+        let mut registry = RootRegistry::default();
+        for block_number in 0..=100 {
+            registry.record(block_number, Felt252Wrapper::ONE, Felt252Wrapper::TWO, CommitStats::default());
+        }
+        let finality = PrunePolicy { retention: None, finality_margin: Some(10) };
+        let both = PrunePolicy { retention: Some(20), finality_margin: Some(10) };
+
+        assert_eq!(PrunePolicy::default().horizon(&registry), None);
+        assert_eq!(PrunePolicy { retention: Some(20), finality_margin: None }.horizon(&registry), Some(81));
+        assert_eq!(finality.horizon(&registry), None);
+
+        registry.set_finality(60, Finality::AcceptedOnL1);
+        assert_eq!(finality.horizon(&registry), Some(50));
+        assert_eq!(both.horizon(&registry), Some(50));
+
+        registry.set_finality(100, Finality::AcceptedOnL1);
+        assert_eq!(both.horizon(&registry), Some(81));
+
+        assert_eq!(registry.prune_before(81), 81);
+        assert_eq!(registry.get(80), None);
+        assert!(registry.get(81).is_some());
+    }
+
+    #[test]
+    fn test_prune_trie_versions() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+        let config = ChainConfig::default();
+        for block_number in 1..=4_u64 {
+            let csd = CommitmentStateDiff {
+                address_to_class_hash: Default::default(),
+                address_to_nonce: Default::default(),
+                storage_updates: [(
+                    contract_address,
+                    [(StorageKey(PatriciaKey(StarkFelt::from(block_number))), StarkFelt::ONE)].into(),
+                )]
+                .into_iter()
+                .collect(),
+                class_hash_to_compiled_class_hash: Default::default(),
+            };
+            try_update_state_root(&tries, csd, block_number, &config).unwrap();
+        }
+
+        let report = prune(&tries, &PrunePolicy { retention: Some(2), finality_margin: None }).unwrap();
+        assert_eq!((report.horizon, report.blocks), (Some(3), 2));
+        assert!(report.trie_log_entries.is_some_and(|entries| entries > 0));
+        // The tries can no longer be reverted to the pruned blocks
+        {
+            let mut engine = tries.engine().unwrap();
+            assert_eq!(engine.horizon(), 3);
+            assert!(engine.revert_to(2).is_err());
+            engine.revert_to(3).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_prune_reclaims_empty_storage() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let emptied = ContractAddress(PatriciaKey(StarkFelt::from(0x12_u64)));
+        let other = ContractAddress(PatriciaKey(StarkFelt::from(0x13_u64)));
+        let write = |contract_address: ContractAddress, value: StarkFelt| CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: Default::default(),
+            storage_updates: [(contract_address, [(StorageKey(PatriciaKey(StarkFelt::ONE)), value)].into())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let config = ChainConfig::default();
+        try_update_state_root(&tries, write(emptied, StarkFelt::ONE), 1, &config).unwrap();
+        try_update_state_root(&tries, write(emptied, StarkFelt::ZERO), 2, &config).unwrap();
+        try_update_state_root(&tries, write(other, StarkFelt::ONE), 3, &config).unwrap();
+        let state_root = try_update_state_root(&tries, write(other, StarkFelt::TWO), 4, &config).unwrap();
+        assert_eq!(empty_storage_tracker(&tries).empty_since(&emptied), Some(2));
+
+        // The storage was emptied at block 2, which is still within the retention window
+        let report = prune(&tries, &PrunePolicy { retention: Some(3), finality_margin: None }).unwrap();
+        assert_eq!((report.horizon, report.storage_entries), (Some(2), Some(0)));
+        assert_eq!(empty_storage_tracker(&tries).empty_since(&emptied), Some(2));
+
+        let report = prune(&tries, &PrunePolicy { retention: Some(2), finality_margin: None }).unwrap();
+        assert_eq!(report.horizon, Some(3));
+        assert!(report.storage_entries.is_some());
+        assert_eq!(empty_storage_tracker(&tries).empty_since(&emptied), None);
+        {
+            let engine = tries.engine().unwrap();
+            assert_eq!(engine.storage_root(&emptied).unwrap(), Felt::ZERO);
+            assert_eq!(engine.state_root(&config).unwrap(), state_root);
+        }
+
+        // The contract can be written to again once its storage trie was reclaimed
+        try_update_state_root(&tries, write(emptied, StarkFelt::ONE), 5, &config).unwrap();
+        assert_ne!(tries.engine().unwrap().storage_root(&emptied).unwrap(), Felt::ZERO);
+        assert_eq!(empty_storage_tracker(&tries).empty_since(&emptied), None);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::sync::MutexGuard;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use indexmap::IndexMap;
+use starknet_api::core::{ClassHash, ContractAddress, Nonce};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+
+use super::engine::StateTries;
+
+/// How failures to update a single contract are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Any failure fails the whole commit.
+    #[default]
+    Strict,
+    /// A contract whose storage trie cannot be updated (corruption, bad data) is quarantined: its
+    /// updates are skipped from then on and the commit goes on, producing a best-effort root flagged
+    /// as [dirty](super::roots::CommittedBlock::dirty). This keeps indexers alive while operators
+    /// investigate. The skipped updates are kept for [replay](Quarantine::replay_diff).
+    Quarantine,
+}
+
+/// Updates of a quarantined contract in a block, which were not applied to the tries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SkippedUpdates {
+    pub class_hash: Option<ClassHash>,
+    pub nonce: Option<Nonce>,
+    pub storage: IndexMap<StorageKey, StarkFelt>,
+}
+
+/// A contract excluded from commits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedContract {
+    /// The block at which the contract failed to update.
+    pub since: u64,
+    pub reason: String,
+    /// The updates skipped since, by block.
+    pub skipped: BTreeMap<u64, SkippedUpdates>,
+}
+
+/// Registry of the quarantined contracts.
+///
+/// As long as a contract is quarantined its leaf is stale, so every root computed since the first
+/// quarantine is dirty: it does not match the sequencer's.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quarantine {
+    pub(crate) contracts: BTreeMap<ContractAddress, QuarantinedContract>,
+}
+
+impl Quarantine {
+    /// Quarantines a contract, keeping the earliest failure if it already was.
+    pub fn add(&mut self, contract_address: ContractAddress, block_number: u64, reason: impl ToString) {
+        self.contracts.entry(contract_address).or_insert_with(|| QuarantinedContract {
+            since: block_number,
+            reason: reason.to_string(),
+            skipped: BTreeMap::new(),
+        });
+    }
+
+    /// Keeps the updates of `contract_address` in `csd`, which were skipped, if it is quarantined.
+    pub(crate) fn skip(&mut self, contract_address: &ContractAddress, block_number: u64, csd: &CommitmentStateDiff) {
+        let Some(contract) = self.contracts.get_mut(contract_address) else {
+            return;
+        };
+        let updates = SkippedUpdates {
+            class_hash: csd.address_to_class_hash.get(contract_address).copied(),
+            nonce: csd.address_to_nonce.get(contract_address).copied(),
+            storage: csd.storage_updates.get(contract_address).cloned().unwrap_or_default(),
+        };
+        contract.skipped.insert(block_number, updates);
+    }
+
+    /// The updates of a quarantined contract skipped since its quarantine, merged into a single
+    /// state diff.
+    ///
+    /// Once the contract's storage trie was repaired and the contract [released](Quarantine::release),
+    /// committing this diff brings its leaf up to date.
+    pub fn replay_diff(&self, contract_address: &ContractAddress) -> Option<CommitmentStateDiff> {
+        let contract = self.contracts.get(contract_address)?;
+        let mut csd = CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        for updates in contract.skipped.values() {
+            if let Some(class_hash) = updates.class_hash {
+                csd.address_to_class_hash.insert(*contract_address, class_hash);
+            }
+            if let Some(nonce) = updates.nonce {
+                csd.address_to_nonce.insert(*contract_address, nonce);
+            }
+            if !updates.storage.is_empty() {
+                let storage = csd.storage_updates.entry(*contract_address).or_default();
+                storage.extend(updates.storage.iter().map(|(key, value)| (*key, *value)));
+            }
+        }
+        Some(csd)
+    }
+
+    pub fn get(&self, contract_address: &ContractAddress) -> Option<&QuarantinedContract> {
+        self.contracts.get(contract_address)
+    }
+
+    pub fn contains(&self, contract_address: &ContractAddress) -> bool {
+        self.contracts.contains_key(contract_address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ContractAddress, &QuarantinedContract)> {
+        self.contracts.iter()
+    }
+
+    /// Returns the first block whose root is dirty, if any.
+    pub fn dirty_since(&self) -> Option<u64> {
+        self.contracts.values().map(|contract| contract.since).min()
+    }
+
+    /// Whether the root of `block_number` is a best-effort root.
+    pub fn is_dirty(&self, block_number: u64) -> bool {
+        self.dirty_since().is_some_and(|since| block_number >= since)
+    }
+
+    /// Forgets the quarantines and skipped updates of `block_number` and the blocks after it, once
+    /// their commit was [rolled back](super::atomic::rollback_block).
+    pub(crate) fn rollback(&mut self, block_number: u64) {
+        self.contracts.retain(|_, contract| contract.since < block_number);
+        for contract in self.contracts.values_mut() {
+            contract.skipped.retain(|skipped_at, _| *skipped_at < block_number);
+        }
+    }
+
+    /// Releases a contract, once its storage trie has been repaired.
+    pub fn release(&mut self, contract_address: &ContractAddress) -> Option<QuarantinedContract> {
+        self.contracts.remove(contract_address)
+    }
+}
+
+/// Returns the [Quarantine] of the contracts of `tries`.
+pub fn quarantine(tries: &StateTries) -> MutexGuard<'_, Quarantine> {
+    tries.state().quarantine.lock().expect("Poisoned lock on quarantine")
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+
+    #[test]
+    fn test_dirty_blocks() {
+        let contract_a = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let contract_b = ContractAddress(PatriciaKey(StarkFelt::TWO));
+        let mut quarantine = Quarantine::default();
+        assert!(!quarantine.is_dirty(10));
+
+        quarantine.add(contract_a, 10, "corrupted node");
+        quarantine.add(contract_a, 12, "corrupted node");
+        quarantine.add(contract_b, 11, "bad data");
+        assert_eq!(quarantine.get(&contract_a).map(|contract| contract.since), Some(10));
+        assert!(!quarantine.is_dirty(9));
+        assert!(quarantine.is_dirty(11));
+
+        quarantine.release(&contract_a);
+        assert_eq!(quarantine.dirty_since(), Some(11));
+    }
+
+    #[test]
+    fn test_replay_skipped_updates() {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+        let key = |key: u64| StorageKey(PatriciaKey(StarkFelt::from(key)));
+        let csd = |block: u64| CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: [(contract_address, Nonce(StarkFelt::from(block)))].into_iter().collect(),
+            storage_updates: [(
+                contract_address,
+                [(key(block), StarkFelt::ONE), (key(1), StarkFelt::from(block))].into(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        let mut quarantine = Quarantine::default();
+        quarantine.skip(&contract_address, 9, &csd(9));
+        assert_eq!(quarantine.replay_diff(&contract_address), None);
+
+        quarantine.add(contract_address, 10, "corrupted node");
+        quarantine.skip(&contract_address, 10, &csd(10));
+        quarantine.skip(&contract_address, 11, &csd(11));
+        let replay = quarantine.replay_diff(&contract_address).unwrap();
+        assert_eq!(replay.address_to_nonce[&contract_address], Nonce(StarkFelt::from(11_u64)));
+        let storage = &replay.storage_updates[&contract_address];
+        assert_eq!(storage.len(), 3);
+        assert_eq!(storage[&key(1)], StarkFelt::from(11_u64));
+        assert_eq!(storage[&key(10)], StarkFelt::ONE);
+    }
+}
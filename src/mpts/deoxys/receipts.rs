@@ -0,0 +1,148 @@
+use bitvec::vec::BitVec;
+use bonsai_trie::databases::HashMapDb;
+use bonsai_trie::id::{BasicId, BasicIdBuilder};
+use bonsai_trie::{BonsaiStorage, BonsaiStorageConfig};
+use mp_felt::Felt252Wrapper;
+use rayon::prelude::*;
+use starknet_core::utils::starknet_keccak;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Poseidon, StarkHash};
+
+use super::error::{CommitmentError, CommitmentItem};
+
+const IDENTIFIER: &[u8] = b"0xreceipt";
+
+/// A message sent to L1 by a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageToL1 {
+    pub from_address: Felt,
+    pub to_address: Felt,
+    pub payload: Vec<Felt>,
+}
+
+/// The parts of a transaction receipt committed to by the receipt commitment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionReceipt {
+    pub transaction_hash: Felt,
+    pub actual_fee: Felt,
+    pub messages_sent: Vec<MessageToL1>,
+    /// `None` if the transaction succeeded.
+    pub revert_reason: Option<String>,
+    /// L1 gas consumed by the transaction.
+    pub l1_gas: Felt,
+    /// L1 data (blob) gas consumed by the transaction.
+    pub l1_data_gas: Felt,
+}
+
+/// Calculate the hash of a transaction receipt, as of Starknet v0.13.2.
+///
+/// # Arguments
+///
+/// * `receipt` - The receipt we want to calculate the hash of.
+///
+/// # Returns
+///
+/// `Poseidon(transaction_hash, actual_fee, messages_hash, revert_reason_hash, 0, l1_gas, l1_data_gas)`, where
+/// the messages are hashed along with their count and the length of their payload, the revert reason
+/// is hashed with `starknet_keccak` (zero if the transaction succeeded), and the zero stands for the
+/// L2 gas, which is not charged yet.
+pub fn calculate_receipt_hash(receipt: &TransactionReceipt) -> Felt {
+    let mut messages = vec![Felt::from(receipt.messages_sent.len() as u64)];
+    for message in &receipt.messages_sent {
+        messages.extend([message.from_address, message.to_address, Felt::from(message.payload.len() as u64)]);
+        messages.extend(&message.payload);
+    }
+    let revert_reason_hash = match &receipt.revert_reason {
+        Some(reason) => Felt::from_bytes_be(&starknet_keccak(reason.as_bytes()).to_bytes_be()),
+        None => Felt::ZERO,
+    };
+
+    Poseidon::hash_array(&[
+        receipt.transaction_hash,
+        receipt.actual_fee,
+        Poseidon::hash_array(&messages),
+        revert_reason_hash,
+        Felt::ZERO,
+        receipt.l1_gas,
+        receipt.l1_data_gas,
+    ])
+}
+
+/// Calculate the receipt commitment in memory using HashMapDb, as of Starknet v0.13.2.
+///
+/// # Arguments
+///
+/// * `receipts` - The receipts of the block, in transaction order.
+///
+/// # Returns
+///
+/// The receipt commitment as `Felt252Wrapper`.
+pub fn memory_receipt_commitment(receipts: &[TransactionReceipt]) -> Result<Felt252Wrapper, String> {
+    try_memory_receipt_commitment(receipts).map_err(|e| e.to_string())
+}
+
+/// Calculate the receipt commitment in memory, see [memory_receipt_commitment].
+///
+/// The receipt hashes are the leaves of a Poseidon Merkle-Patricia trie keyed by the index of the
+/// transaction in the block.
+///
+/// # Arguments
+///
+/// * `receipts` - The receipts of the block, in transaction order.
+///
+/// # Returns
+///
+/// The receipt commitment as `Felt252Wrapper`.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(receipts = receipts.len())))]
+pub fn try_memory_receipt_commitment(receipts: &[TransactionReceipt]) -> Result<Felt252Wrapper, CommitmentError> {
+    if receipts.is_empty() {
+        return Ok(Felt252Wrapper::ZERO);
+    }
+    let backend = |e| CommitmentError::Backend { item: CommitmentItem::Receipt, reason: format!("{e:?}") };
+
+    let config = BonsaiStorageConfig::default();
+    let bonsai_db = HashMapDb::<BasicId>::default();
+    let mut bonsai_storage = BonsaiStorage::<_, _, Poseidon>::new(bonsai_db, config).map_err(backend)?;
+
+    let receipts = receipts.par_iter().map(calculate_receipt_hash).collect::<Vec<_>>();
+
+    for (i, receipt_hash) in receipts.into_iter().enumerate() {
+        let key = BitVec::from_vec(i.to_be_bytes().to_vec());
+        bonsai_storage.insert(IDENTIFIER, key.as_bitslice(), &receipt_hash).map_err(backend)?;
+    }
+
+    let mut id_builder = BasicIdBuilder::new();
+    bonsai_storage.commit(id_builder.new_id()).map_err(backend)?;
+    let root_hash = bonsai_storage.root_hash(IDENTIFIER).map_err(backend)?;
+
+    Ok(Felt252Wrapper::from(root_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(revert_reason: Option<&str>) -> TransactionReceipt {
+        TransactionReceipt {
+            transaction_hash: Felt::from(0x1234_u64),
+            actual_fee: Felt::from(1000_u64),
+            messages_sent: vec![MessageToL1 {
+                from_address: Felt::from(0x10_u64),
+                to_address: Felt::from(0x20_u64),
+                payload: vec![Felt::ONE, Felt::TWO],
+            }],
+            revert_reason: revert_reason.map(String::from),
+            l1_gas: Felt::from(50_u64),
+            l1_data_gas: Felt::from(20_u64),
+        }
+    }
+
+    #[test]
+    fn test_receipt_hash() {
+        assert_ne!(calculate_receipt_hash(&receipt(None)), calculate_receipt_hash(&receipt(Some("out of gas"))));
+        assert_eq!(memory_receipt_commitment(&[]), Ok(Felt252Wrapper::ZERO));
+
+        let commitment = memory_receipt_commitment(&[receipt(None), receipt(Some("out of gas"))]).unwrap();
+        assert_ne!(commitment, memory_receipt_commitment(&[receipt(Some("out of gas")), receipt(None)]).unwrap());
+    }
+}
@@ -0,0 +1,821 @@
+//! Record/replay of the backend interactions of a block commit, for bug reports.
+//!
+//! A [Bundle] holds the state diff of a block along with every value the commit read from the
+//! backend and every value it wrote to it, down to the trie nodes when the block is committed by an
+//! [engine](super::engine::CommitmentEngine). It is self-contained: [replay] recomputes what the
+//! commit should have written from the diff and the recorded reads alone, without any database, so
+//! that a failing block can be attached to an issue instead of the whole database.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use indexmap::IndexMap;
+use mp_felt::Felt252Wrapper;
+use serde_json::{json, Value};
+use starknet_api::core::{ClassHash, CompiledClassHash, ContractAddress, Nonce, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+
+use super::atomic::Trie;
+use super::backend::{Backend, BackendError, Column, StarkrootBackend};
+use super::config::{ChainConfig, StorageWrite};
+use super::contracts::contract_leaf_hash;
+use super::engine::StateTries;
+use super::error::CommitError;
+use super::handles::WriteHandle;
+use super::lib::clone_commitment_state_diff;
+use super::mutation_log::MutationLog;
+use super::storage_proof::state_commitment;
+
+/// Version of the bundle format. Bundles of version 1 hold no trie node accesses.
+pub const BUNDLE_VERSION: u64 = 2;
+
+/// A value read from or written to the backend while committing a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interaction {
+    /// Class hash and nonce of a contract, read from the backend as the diff does not update both.
+    ContractRead { contract_address: Felt, class_hash: Felt, nonce: Felt },
+    /// Root of a contract storage trie, read once its writes were committed.
+    StorageRoot { contract_address: Felt, root: Felt },
+    /// A leaf inserted in a contract storage trie, zero for deletions.
+    StorageWrite { contract_address: Felt, key: Felt, value: Felt },
+    /// A leaf inserted in the contracts trie.
+    ContractLeaf { contract_address: Felt, leaf_hash: Felt },
+    /// A leaf inserted in the classes trie.
+    ClassLeaf { class_hash: Felt, leaf_hash: Felt },
+    /// Root of the contracts or classes trie, read once the block was committed.
+    TrieRoot { trie: Trie, root: Felt },
+}
+
+/// A read or write of the backend the tries are stored in, ie: of a trie node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeAccess {
+    /// A key read, `None` if it was absent. The entries returned by scans are recorded as reads.
+    Read { column: Column, key: Vec<u8>, value: Option<Vec<u8>> },
+    /// A staged write, `None` for deletions.
+    Write { column: Column, key: Vec<u8>, value: Option<Vec<u8>> },
+}
+
+/// What the commits to a set of [StateTries] are captured by, which the commits to other tries do
+/// not feed. The tries of an [engine](super::engine::CommitmentEngine) share those of the engine.
+#[derive(Default)]
+pub(crate) struct Capture {
+    recording: AtomicBool,
+    /// The block being [recorded](record_block), only meaningful while `recording` is set.
+    recorded_block: AtomicU64,
+    interactions: Mutex<Vec<Interaction>>,
+    nodes: Mutex<Vec<NodeAccess>>,
+    pub(crate) mutation_log: MutationLog,
+}
+
+impl Capture {
+    fn interactions(&self) -> MutexGuard<'_, Vec<Interaction>> {
+        self.interactions.lock().expect("Poisoned lock on recorded interactions")
+    }
+
+    fn nodes(&self) -> MutexGuard<'_, Vec<NodeAccess>> {
+        self.nodes.lock().expect("Poisoned lock on recorded node accesses")
+    }
+
+    /// Whether the commit of `block_number` is being [recorded](record_block).
+    fn is_recording(&self, block_number: u64) -> bool {
+        self.recording.load(Ordering::Relaxed) && self.recorded_block.load(Ordering::Relaxed) == block_number
+    }
+
+    /// Starts recording the commit of `block_number`.
+    fn start_recording(&self, block_number: u64) {
+        self.interactions().clear();
+        self.nodes().clear();
+        self.recorded_block.store(block_number, Ordering::Relaxed);
+        self.recording.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops recording, returning the interactions and node accesses recorded.
+    fn finish_recording(&self) -> (Vec<Interaction>, Vec<NodeAccess>) {
+        self.recording.store(false, Ordering::Relaxed);
+        (std::mem::take(&mut *self.interactions()), std::mem::take(&mut *self.nodes()))
+    }
+
+    /// Records an interaction of the commit of `block_number` if the block is being
+    /// [recorded](record_block), and logs the leaf it inserted if the
+    /// [mutation log](super::mutation_log) of the tries is running.
+    ///
+    /// The interaction is only built while recording or logging, so that commits pay nothing
+    /// otherwise.
+    pub(crate) fn record(&self, block_number: u64, interaction: impl FnOnce() -> Interaction) {
+        let (recording, logging) = (self.is_recording(block_number), self.mutation_log.is_logging());
+        if !recording && !logging {
+            return;
+        }
+        let interaction = interaction();
+        if logging {
+            self.mutation_log.log_interaction(block_number, &interaction);
+        }
+        if recording {
+            self.interactions().push(interaction);
+        }
+    }
+
+    /// Records the node accesses of the commit of `block_number` if the block is being
+    /// [recorded](record_block).
+    fn record_nodes(&self, block_number: u64, accesses: Vec<NodeAccess>) {
+        if self.is_recording(block_number) {
+            self.nodes().extend(accesses);
+        }
+    }
+}
+
+/// Backend the tries of an [engine](super::engine::CommitmentEngine) are opened over, recording
+/// their node accesses while the engine commits a [recorded](record_block) block.
+pub(crate) struct RecordingBackend {
+    inner: Backend,
+    /// What the commits of the engine are captured by.
+    capture: Arc<Capture>,
+    armed: AtomicBool,
+    /// The block whose commit is being recorded, only meaningful while `armed` is set.
+    block_number: AtomicU64,
+    accesses: Mutex<Vec<NodeAccess>>,
+}
+
+impl RecordingBackend {
+    pub(crate) fn new(inner: Backend, capture: Arc<Capture>) -> Self {
+        Self {
+            inner,
+            capture,
+            armed: AtomicBool::new(false),
+            block_number: AtomicU64::new(0),
+            accesses: Mutex::default(),
+        }
+    }
+
+    /// Starts recording the node accesses of the commit of `block_number`, if it is being recorded.
+    pub(crate) fn start(&self, block_number: u64) {
+        // The accesses of a commit which failed without being discarded are dropped
+        self.accesses.lock().expect("Poisoned lock on recording backend").clear();
+        self.block_number.store(block_number, Ordering::Relaxed);
+        self.armed.store(self.capture.is_recording(block_number), Ordering::Relaxed);
+    }
+
+    /// Stops recording once the commit succeeded or failed, adding its node accesses to the
+    /// recording.
+    pub(crate) fn finish(&self) {
+        if self.armed.swap(false, Ordering::Relaxed) {
+            let accesses = std::mem::take(&mut *self.accesses.lock().expect("Poisoned lock on recording backend"));
+            self.capture.record_nodes(self.block_number.load(Ordering::Relaxed), accesses);
+        }
+    }
+
+    fn push(&self, access: impl FnOnce() -> NodeAccess) {
+        if self.armed.load(Ordering::Relaxed) {
+            self.accesses.lock().expect("Poisoned lock on recording backend").push(access());
+        }
+    }
+
+    fn push_scan(&self, column: Column, entries: &[(Vec<u8>, Vec<u8>)]) {
+        if !self.armed.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut accesses = self.accesses.lock().expect("Poisoned lock on recording backend");
+        accesses.extend(entries.iter().map(|(key, value)| NodeAccess::Read {
+            column,
+            key: key.clone(),
+            value: Some(value.clone()),
+        }));
+    }
+}
+
+impl StarkrootBackend for RecordingBackend {
+    fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let value = self.inner.get(column, key)?;
+        self.push(|| NodeAccess::Read { column, key: key.to_vec(), value: value.clone() });
+        Ok(value)
+    }
+
+    fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError> {
+        self.inner.put(column, key, value)?;
+        self.push(|| NodeAccess::Write { column, key: key.to_vec(), value: value.map(<[u8]>::to_vec) });
+        Ok(())
+    }
+
+    fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        let entries = self.inner.scan_prefix(column, prefix)?;
+        self.push_scan(column, &entries);
+        Ok(entries)
+    }
+
+    fn scan_range(&self, column: Column, start: &[u8], end: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+        let entries = self.inner.scan_range(column, start, end)?;
+        self.push_scan(column, &entries);
+        Ok(entries)
+    }
+
+    fn commit(&self, block_number: u64) -> Result<(), BackendError> {
+        self.inner.commit(block_number)
+    }
+
+    fn discard(&self) {
+        self.inner.discard()
+    }
+
+    fn snapshot(&self, block_number: u64) -> Result<Arc<dyn StarkrootBackend>, BackendError> {
+        self.inner.snapshot(block_number)
+    }
+
+    fn compact(&self, column: Column, prefix: &[u8]) -> Result<(), BackendError> {
+        self.inner.compact(column, prefix)
+    }
+
+    fn prune_snapshots_before(&self, block_number: u64) -> Result<(), BackendError> {
+        self.inner.prune_snapshots_before(block_number)
+    }
+}
+
+/// A self-contained reproduction of a block commit.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Bundle {
+    pub block_number: u64,
+    pub csd: CommitmentStateDiff,
+    /// The interactions of the commit with the backend, in the order they happened.
+    pub interactions: Vec<Interaction>,
+    /// The trie node accesses of the commit, in the order they happened. Only the commits to the
+    /// tries of an [engine](super::engine::CommitmentEngine) record them, the node's database is
+    /// not reachable from this crate.
+    pub nodes: Vec<NodeAccess>,
+    /// The state root returned by the commit, or its error.
+    pub outcome: Result<Felt, String>,
+}
+
+/// Commits a block while recording its interactions with the backend.
+///
+/// Only the commit of `block_number` to the tries of `writer` is recorded: the blocks committed
+/// meanwhile to other tries, ie: by other [engines](super::engine::CommitmentEngine), are not, even
+/// at the same height.
+///
+/// # Arguments
+///
+/// * `writer`       - The write handle of the tries to commit the block to.
+/// * `csd`          - The commitment state diff of the block.
+/// * `block_number` - The block number.
+/// * `config`       - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The result of the commit, along with its recording.
+pub fn record_block(
+    writer: &mut WriteHandle,
+    csd: CommitmentStateDiff,
+    block_number: u64,
+    config: &ChainConfig,
+) -> (Result<Felt252Wrapper, CommitError>, Bundle) {
+    let recorded = clone_commitment_state_diff(&csd);
+
+    let capture = Arc::clone(&writer.tries().state().capture);
+    capture.start_recording(block_number);
+    let result = writer.commit(csd, block_number, config);
+    let (interactions, nodes) = capture.finish_recording();
+
+    let bundle = Bundle {
+        block_number,
+        csd: recorded,
+        interactions,
+        nodes,
+        outcome: result.as_ref().map(|state_root| Felt::from(*state_root)).map_err(ToString::to_string),
+    };
+    (result, bundle)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BundleError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("unsupported bundle version {0}")]
+    UnsupportedVersion(u64),
+    #[error("invalid bundle field `{0}`")]
+    Invalid(&'static str),
+}
+
+fn felt_json(felt: Felt) -> Value {
+    json!(format!("{felt:#x}"))
+}
+
+fn bytes_json(bytes: Option<&[u8]>) -> Value {
+    match bytes {
+        Some(bytes) => json!(bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>()),
+        None => Value::Null,
+    }
+}
+
+fn parse_bytes(value: &Value, field: &'static str) -> Result<Vec<u8>, BundleError> {
+    let hex = value.as_str().filter(|hex| hex.len() % 2 == 0).ok_or(BundleError::Invalid(field))?;
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect::<Option<_>>()
+        .ok_or(BundleError::Invalid(field))
+}
+
+fn stark_felt(felt: &StarkFelt) -> Felt {
+    Felt::from_bytes_be(&felt.0)
+}
+
+fn address(contract_address: &ContractAddress) -> Felt {
+    stark_felt(contract_address.0.key())
+}
+
+fn trie_name(trie: Trie) -> &'static str {
+    match trie {
+        Trie::ContractStorage => "contract_storage",
+        Trie::Contracts => "contracts",
+        Trie::Classes => "classes",
+    }
+}
+
+impl Interaction {
+    fn to_json(self) -> Value {
+        match self {
+            Interaction::ContractRead { contract_address, class_hash, nonce } => json!({
+                "kind": "contract_read",
+                "contract_address": felt_json(contract_address),
+                "class_hash": felt_json(class_hash),
+                "nonce": felt_json(nonce),
+            }),
+            Interaction::StorageRoot { contract_address, root } => json!({
+                "kind": "storage_root",
+                "contract_address": felt_json(contract_address),
+                "root": felt_json(root),
+            }),
+            Interaction::StorageWrite { contract_address, key, value } => json!({
+                "kind": "storage_write",
+                "contract_address": felt_json(contract_address),
+                "key": felt_json(key),
+                "value": felt_json(value),
+            }),
+            Interaction::ContractLeaf { contract_address, leaf_hash } => json!({
+                "kind": "contract_leaf",
+                "contract_address": felt_json(contract_address),
+                "leaf_hash": felt_json(leaf_hash),
+            }),
+            Interaction::ClassLeaf { class_hash, leaf_hash } => json!({
+                "kind": "class_leaf",
+                "class_hash": felt_json(class_hash),
+                "leaf_hash": felt_json(leaf_hash),
+            }),
+            Interaction::TrieRoot { trie, root } => json!({
+                "kind": "trie_root",
+                "trie": trie_name(trie),
+                "root": felt_json(root),
+            }),
+        }
+    }
+
+    fn from_json(value: &Value) -> Result<Self, BundleError> {
+        let felt = |field: &'static str| parse_felt(&value[field], field);
+        let interaction = match value["kind"].as_str() {
+            Some("contract_read") => Interaction::ContractRead {
+                contract_address: felt("contract_address")?,
+                class_hash: felt("class_hash")?,
+                nonce: felt("nonce")?,
+            },
+            Some("storage_root") => {
+                Interaction::StorageRoot { contract_address: felt("contract_address")?, root: felt("root")? }
+            }
+            Some("storage_write") => Interaction::StorageWrite {
+                contract_address: felt("contract_address")?,
+                key: felt("key")?,
+                value: felt("value")?,
+            },
+            Some("contract_leaf") => {
+                Interaction::ContractLeaf { contract_address: felt("contract_address")?, leaf_hash: felt("leaf_hash")? }
+            }
+            Some("class_leaf") => {
+                Interaction::ClassLeaf { class_hash: felt("class_hash")?, leaf_hash: felt("leaf_hash")? }
+            }
+            Some("trie_root") => {
+                let trie = Trie::ALL
+                    .into_iter()
+                    .find(|trie| Some(trie_name(*trie)) == value["trie"].as_str())
+                    .ok_or(BundleError::Invalid("trie"))?;
+                Interaction::TrieRoot { trie, root: felt("root")? }
+            }
+            _ => return Err(BundleError::Invalid("kind")),
+        };
+        Ok(interaction)
+    }
+}
+
+impl NodeAccess {
+    fn to_json(&self) -> Value {
+        let (kind, column, key, value) = match self {
+            NodeAccess::Read { column, key, value } => ("read", column, key, value),
+            NodeAccess::Write { column, key, value } => ("write", column, key, value),
+        };
+        json!({
+            "kind": kind,
+            "column": column.name(),
+            "key": bytes_json(Some(key)),
+            "value": bytes_json(value.as_deref()),
+        })
+    }
+
+    fn from_json(value: &Value) -> Result<Self, BundleError> {
+        let column = Column::ALL
+            .into_iter()
+            .find(|column| Some(column.name()) == value["column"].as_str())
+            .ok_or(BundleError::Invalid("column"))?;
+        let key = parse_bytes(&value["key"], "key")?;
+        let node = match &value["value"] {
+            Value::Null => None,
+            node => Some(parse_bytes(node, "value")?),
+        };
+        match value["kind"].as_str() {
+            Some("read") => Ok(NodeAccess::Read { column, key, value: node }),
+            Some("write") => Ok(NodeAccess::Write { column, key, value: node }),
+            _ => Err(BundleError::Invalid("kind")),
+        }
+    }
+}
+
+fn parse_felt(value: &Value, field: &'static str) -> Result<Felt, BundleError> {
+    let felt = value.as_str().and_then(|value| FieldElement::from_hex_be(value).ok());
+    felt.map(|felt| Felt::from_bytes_be(&felt.to_bytes_be())).ok_or(BundleError::Invalid(field))
+}
+
+fn parse_entries<const N: usize>(value: &Value, field: &'static str) -> Result<Vec<[StarkFelt; N]>, BundleError> {
+    let entries = value.as_array().ok_or(BundleError::Invalid(field))?;
+    entries
+        .iter()
+        .map(|entry| {
+            let entry = entry.as_array().filter(|entry| entry.len() == N).ok_or(BundleError::Invalid(field))?;
+            let mut felts = [StarkFelt::ZERO; N];
+            for (felt, value) in felts.iter_mut().zip(entry) {
+                *felt = StarkFelt(parse_felt(value, field)?.to_bytes_be());
+            }
+            Ok(felts)
+        })
+        .collect()
+}
+
+impl Bundle {
+    /// Serializes the bundle as JSON, felts being `0x`-prefixed hex strings.
+    pub fn to_json(&self) -> Value {
+        let csd = &self.csd;
+        let storage = csd.storage_updates.iter().flat_map(|(contract_address, updates)| {
+            updates.iter().map(|(key, value)| {
+                let (key, value) = (stark_felt(key.0.key()), stark_felt(value));
+                json!([felt_json(address(contract_address)), felt_json(key), felt_json(value)])
+            })
+        });
+        let outcome = match &self.outcome {
+            Ok(state_root) => json!({ "state_root": felt_json(*state_root) }),
+            Err(error) => json!({ "error": error }),
+        };
+
+        json!({
+            "version": BUNDLE_VERSION,
+            "block_number": self.block_number,
+            "diff": {
+                "class_hashes": csd.address_to_class_hash.iter().map(|(contract_address, class_hash)| {
+                    json!([felt_json(address(contract_address)), felt_json(stark_felt(&class_hash.0))])
+                }).collect::<Vec<_>>(),
+                "nonces": csd.address_to_nonce.iter().map(|(contract_address, nonce)| {
+                    json!([felt_json(address(contract_address)), felt_json(stark_felt(&nonce.0))])
+                }).collect::<Vec<_>>(),
+                "storage": storage.collect::<Vec<_>>(),
+                "declared_classes": csd.class_hash_to_compiled_class_hash.iter().map(|(class_hash, compiled)| {
+                    json!([felt_json(stark_felt(&class_hash.0)), felt_json(stark_felt(&compiled.0))])
+                }).collect::<Vec<_>>(),
+            },
+            "interactions": self.interactions.iter().map(|interaction| interaction.to_json()).collect::<Vec<_>>(),
+            "nodes": self.nodes.iter().map(NodeAccess::to_json).collect::<Vec<_>>(),
+            "outcome": outcome,
+        })
+    }
+
+    /// Deserializes a bundle serialized with [Bundle::to_json].
+    pub fn from_json(value: &Value) -> Result<Self, BundleError> {
+        let version = value["version"].as_u64().ok_or(BundleError::Invalid("version"))?;
+        if !(1..=BUNDLE_VERSION).contains(&version) {
+            return Err(BundleError::UnsupportedVersion(version));
+        }
+        let block_number = value["block_number"].as_u64().ok_or(BundleError::Invalid("block_number"))?;
+
+        let diff = &value["diff"];
+        let contract_address = |felt: StarkFelt| ContractAddress(PatriciaKey(felt));
+        let mut csd = CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        for [address, class_hash] in parse_entries(&diff["class_hashes"], "class_hashes")? {
+            csd.address_to_class_hash.insert(contract_address(address), ClassHash(class_hash));
+        }
+        for [address, nonce] in parse_entries(&diff["nonces"], "nonces")? {
+            csd.address_to_nonce.insert(contract_address(address), Nonce(nonce));
+        }
+        for [address, key, value] in parse_entries(&diff["storage"], "storage")? {
+            let updates = csd.storage_updates.entry(contract_address(address)).or_default();
+            updates.insert(StorageKey(PatriciaKey(key)), value);
+        }
+        for [class_hash, compiled] in parse_entries(&diff["declared_classes"], "declared_classes")? {
+            csd.class_hash_to_compiled_class_hash.insert(ClassHash(class_hash), CompiledClassHash(compiled));
+        }
+
+        let interactions = value["interactions"]
+            .as_array()
+            .ok_or(BundleError::Invalid("interactions"))?
+            .iter()
+            .map(Interaction::from_json)
+            .collect::<Result<_, _>>()?;
+        let nodes = match &value["nodes"] {
+            Value::Null if version == 1 => Vec::new(),
+            nodes => nodes
+                .as_array()
+                .ok_or(BundleError::Invalid("nodes"))?
+                .iter()
+                .map(NodeAccess::from_json)
+                .collect::<Result<_, _>>()?,
+        };
+
+        let outcome = &value["outcome"];
+        let outcome = match outcome["error"].as_str() {
+            Some(error) => Err(error.to_string()),
+            None => Ok(parse_felt(&outcome["state_root"], "outcome")?),
+        };
+
+        Ok(Self { block_number, csd, interactions, nodes, outcome })
+    }
+
+    /// Writes the bundle to a JSON file.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), BundleError> {
+        Ok(fs::write(path, serde_json::to_vec_pretty(&self.to_json())?)?)
+    }
+
+    /// Reads a bundle from a JSON file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, BundleError> {
+        Self::from_json(&serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+/// A value the replay computed differently from the recorded commit.
+///
+/// `expected` is `None` for values the commit wrote but should not have, `recorded` for values it
+/// should have written but did not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub expected: Option<Interaction>,
+    pub recorded: Option<Interaction>,
+}
+
+/// Outcome of the [replay] of a bundle.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub divergences: Vec<Divergence>,
+    /// The node reads which returned something else than the value the key last held, as written
+    /// or read earlier in the commit: the backend lost or corrupted a node.
+    pub inconsistent_reads: Vec<NodeAccess>,
+    /// The state root recomputed from the recorded trie roots, `None` if they were not recorded
+    /// (ie: the commit failed before).
+    pub state_root: Option<Felt>,
+}
+
+impl ReplayReport {
+    /// Whether the recorded commit matches the replay.
+    pub fn passed(&self) -> bool {
+        self.divergences.is_empty() && self.inconsistent_reads.is_empty()
+    }
+}
+
+fn compare<K: Eq + std::hash::Hash>(
+    divergences: &mut Vec<Divergence>,
+    mut expected: HashMap<K, Interaction>,
+    recorded: HashMap<K, Interaction>,
+) {
+    for (key, recorded) in recorded {
+        match expected.remove(&key) {
+            Some(expected) if expected == recorded => {}
+            expected => divergences.push(Divergence { expected, recorded: Some(recorded) }),
+        }
+    }
+    divergences.extend(expected.into_values().map(|expected| Divergence { expected: Some(expected), recorded: None }));
+}
+
+/// Replays a recorded commit.
+///
+/// Everything this crate computes on top of the backend is recomputed from the state diff and the
+/// recorded reads: the storage writes, the contracts and classes trie leaves and the state root.
+/// The trie roots themselves are computed by the backend, they are taken as recorded. A bundle
+/// which replays without divergences thus points at the backend, whose recorded node accesses are
+/// checked to be consistent with each other.
+///
+/// # Arguments
+///
+/// * `bundle` - The recorded commit.
+/// * `config` - The chain-specific commitment rules the block was committed with.
+///
+/// # Returns
+///
+/// The values which the replay computed differently.
+pub fn replay(bundle: &Bundle, config: &ChainConfig) -> ReplayReport {
+    let csd = &bundle.csd;
+    let mut report = ReplayReport::default();
+
+    let mut reads = HashMap::new();
+    let mut storage_roots = HashMap::new();
+    let mut trie_roots = HashMap::new();
+    let (mut writes, mut contract_leaves, mut class_leaves) = (HashMap::new(), HashMap::new(), HashMap::new());
+    for interaction in &bundle.interactions {
+        match *interaction {
+            Interaction::ContractRead { contract_address, class_hash, nonce } => {
+                reads.insert(contract_address, (class_hash, nonce));
+            }
+            Interaction::StorageRoot { contract_address, root } => {
+                storage_roots.insert(contract_address, root);
+            }
+            Interaction::StorageWrite { contract_address, key, .. } => {
+                writes.insert((contract_address, key), *interaction);
+            }
+            Interaction::ContractLeaf { contract_address, .. } => {
+                contract_leaves.insert(contract_address, *interaction);
+            }
+            Interaction::ClassLeaf { class_hash, .. } => {
+                class_leaves.insert(class_hash, *interaction);
+            }
+            Interaction::TrieRoot { trie, root } => {
+                trie_roots.insert(trie, root);
+            }
+        }
+    }
+
+    let expected_writes = csd
+        .storage_updates
+        .iter()
+        .flat_map(|(contract_address, updates)| {
+            updates.iter().filter_map(move |(key, value)| {
+                let value = match config.zero_writes.write(*value)? {
+                    StorageWrite::Set(value) => stark_felt(&value),
+                    StorageWrite::Delete => Felt::ZERO,
+                };
+                let (contract_address, key) = (address(contract_address), stark_felt(key.0.key()));
+                Some(((contract_address, key), Interaction::StorageWrite { contract_address, key, value }))
+            })
+        })
+        .collect();
+    compare(&mut report.divergences, expected_writes, writes);
+
+    let contracts =
+        csd.storage_updates.keys().chain(csd.address_to_class_hash.keys()).chain(csd.address_to_nonce.keys());
+    let mut expected_leaves = HashMap::new();
+    for contract_address in contracts {
+        let felt = address(contract_address);
+        let (read_class_hash, read_nonce) = reads.get(&felt).copied().unwrap_or_default();
+        let class_hash = csd.address_to_class_hash.get(contract_address).map_or(read_class_hash, |c| stark_felt(&c.0));
+        let nonce = csd.address_to_nonce.get(contract_address).map_or(read_nonce, |nonce| stark_felt(&nonce.0));
+        // Without the storage root, the leaf cannot be recomputed: the commit failed before writing it
+        let Some(storage_root) = storage_roots.get(&felt) else { continue };
+
+        let leaf_hash = contract_leaf_hash(class_hash, nonce, *storage_root, config);
+        expected_leaves.insert(felt, Interaction::ContractLeaf { contract_address: felt, leaf_hash });
+    }
+    compare(&mut report.divergences, expected_leaves, contract_leaves);
+
+    let expected_class_leaves = csd
+        .class_hash_to_compiled_class_hash
+        .iter()
+        .map(|(class_hash, compiled_class_hash)| {
+            let compiled_class_hash = FieldElement::from_bytes_be(&compiled_class_hash.0.0).unwrap();
+            let leaf_hash =
+                config.hashers.class_leaf.hash_elements(config.constants.class_leaf_version, compiled_class_hash);
+            let class_hash = stark_felt(&class_hash.0);
+            let leaf_hash = Felt::from_bytes_be(&leaf_hash.to_bytes_be());
+            (class_hash, Interaction::ClassLeaf { class_hash, leaf_hash })
+        })
+        .collect();
+    compare(&mut report.divergences, expected_class_leaves, class_leaves);
+
+    let mut nodes = HashMap::new();
+    for access in &bundle.nodes {
+        match access {
+            NodeAccess::Write { column, key, value } => {
+                nodes.insert((*column, key), value);
+            }
+            NodeAccess::Read { column, key, value } => match nodes.get(&(*column, key)) {
+                Some(known) if *known != value => report.inconsistent_reads.push(access.clone()),
+                Some(_) => {}
+                None => {
+                    nodes.insert((*column, key), value);
+                }
+            },
+        }
+    }
+
+    if let (Some(contracts_root), Some(classes_root)) =
+        (trie_roots.get(&Trie::Contracts), trie_roots.get(&Trie::Classes))
+    {
+        report.state_root = Some(state_commitment(*contracts_root, *classes_root, config));
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::engine::CommitmentEngine;
+
+    fn bundle() -> Bundle {
+        let address = ContractAddress(PatriciaKey(StarkFelt::from(0x10_u64)));
+        let key = StorageKey(PatriciaKey(StarkFelt::from(1_u64)));
+        let config = ChainConfig::default();
+        let contract_address = Felt::from(0x10_u64);
+        let storage_root = Felt::from(0x1234_u64);
+        let leaf_hash = contract_leaf_hash(Felt::ONE, Felt::ZERO, storage_root, &config);
+
+        Bundle {
+            block_number: 7,
+            csd: CommitmentStateDiff {
+                address_to_class_hash: [(address, ClassHash(StarkFelt::ONE))].into_iter().collect(),
+                address_to_nonce: IndexMap::new(),
+                storage_updates: [(address, [(key, StarkFelt::TWO)].into_iter().collect())].into_iter().collect(),
+                class_hash_to_compiled_class_hash: IndexMap::new(),
+            },
+            interactions: vec![
+                Interaction::StorageWrite { contract_address, key: Felt::ONE, value: Felt::TWO },
+                Interaction::ContractRead { contract_address, class_hash: Felt::ZERO, nonce: Felt::ZERO },
+                Interaction::StorageRoot { contract_address, root: storage_root },
+                Interaction::ContractLeaf { contract_address, leaf_hash },
+                Interaction::TrieRoot { trie: Trie::Contracts, root: Felt::from(3_u64) },
+                Interaction::TrieRoot { trie: Trie::Classes, root: Felt::ZERO },
+            ],
+            nodes: vec![
+                NodeAccess::Read { column: Column::Trie(Trie::Contracts), key: vec![0, 1], value: None },
+                NodeAccess::Write { column: Column::Trie(Trie::Contracts), key: vec![0, 1], value: Some(vec![0xab]) },
+                NodeAccess::Read { column: Column::Trie(Trie::Contracts), key: vec![0, 1], value: Some(vec![0xab]) },
+            ],
+            outcome: Ok(Felt::from(3_u64)),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bundle = bundle();
+        assert_eq!(Bundle::from_json(&bundle.to_json()).unwrap(), bundle);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut bundle = bundle();
+        let report = replay(&bundle, &ChainConfig::default());
+        assert!(report.passed());
+        assert_eq!(report.state_root, Some(Felt::from(3_u64)));
+
+        bundle.interactions[0] =
+            Interaction::StorageWrite { contract_address: Felt::from(0x10_u64), key: Felt::ONE, value: Felt::ONE };
+        let report = replay(&bundle, &ChainConfig::default());
+        assert_eq!(report.divergences.len(), 1);
+
+        // The backend returned a node which was not the one written
+        let mut bundle = self::bundle();
+        bundle.nodes[2] =
+            NodeAccess::Read { column: Column::Trie(Trie::Contracts), key: vec![0, 1], value: Some(vec![0xac]) };
+        let report = replay(&bundle, &ChainConfig::default());
+        assert!(report.divergences.is_empty());
+        assert_eq!(report.inconsistent_reads, vec![bundle.nodes[2].clone()]);
+    }
+
+    #[test]
+    fn test_record_block() {
+        let mut writer = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+
+        let config = ChainConfig::default();
+        let (result, bundle) = record_block(&mut writer, self::bundle().csd, 1, &config);
+        let state_root = Felt::from(result.unwrap());
+        assert_eq!(bundle.outcome, Ok(state_root));
+        assert!(bundle.nodes.iter().any(|access| matches!(
+            access,
+            NodeAccess::Write { column: Column::Trie(Trie::ContractStorage), value: Some(_), .. }
+        )));
+        assert!(bundle.nodes.iter().any(|access| matches!(access, NodeAccess::Read { .. })));
+        let report = replay(&Bundle::from_json(&bundle.to_json()).unwrap(), &config);
+        assert!(report.passed(), "{report:?}");
+        assert_eq!(report.state_root, Some(state_root));
+
+        // Only the commits to the recorded tries are recorded, not those of other tries at the same
+        // height
+        let capture = &writer.tries().state().capture;
+        capture.start_recording(2);
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        engine.update_state_root(self::bundle().csd, 2, &config).unwrap();
+        assert_eq!(capture.finish_recording(), (vec![], vec![]));
+    }
+}
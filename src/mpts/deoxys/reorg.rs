@@ -0,0 +1,121 @@
+use super::atomic::{latest_block_since, revert_tries};
+use super::engine::StateTries;
+use super::error::TrieError;
+use super::history::{contract_activity, storage_history};
+use super::roots::{root_registry, Finality};
+use super::squash::empty_storage_tracker;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RevertError {
+    #[error("block {0} was not committed or was pruned, the tries cannot be reverted to it")]
+    NotCommitted(u64),
+    #[error("block {block_number} is accepted on L1, the blocks up to it cannot be reverted")]
+    Settled { block_number: u64 },
+    #[error("failed to revert the tries to block {block_number} ({error}), they must be restored from a snapshot")]
+    Trie { block_number: u64, error: TrieError },
+    #[error("failed to read the committed blocks: {0}")]
+    Read(#[from] TrieError),
+}
+
+/// What a [revert](super::handles::WriteHandle::revert_to) unwound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RevertReport {
+    /// The latest block before the revert.
+    pub previous_latest: u64,
+    /// Number of blocks reverted.
+    pub blocks: usize,
+}
+
+/// Unwinds the contracts trie, the classes trie and the contract storage tries to the state right
+/// after `block_number`, ie: to handle a chain reorg or to reset after a failed block import.
+///
+/// The blocks committed after `block_number` are forgotten from the [root registry](root_registry),
+/// the [storage](storage_history) and [activity](contract_activity) indexes and the
+/// [empty storage tracker](empty_storage_tracker), so that they can be committed again with a
+/// different state diff.
+///
+/// `block_number` must have been committed to the tries and not
+/// [pruned](super::handles::WriteHandle::prune), and none of the blocks after it can be accepted on
+/// L1. The committed blocks are read from the tries, so this works right after a restart, when the
+/// root registry is empty.
+///
+/// # Arguments
+///
+/// * `tries`        - The tries to revert.
+/// * `block_number` - The block to revert to, which becomes the latest committed block.
+///
+/// # Returns
+///
+/// The reverted blocks, none if `block_number` already is the latest block.
+pub(crate) fn revert_to(tries: &StateTries, block_number: u64) -> Result<RevertReport, RevertError> {
+    // The registry stays locked for the whole revert so that no block is committed meanwhile
+    let mut registry = root_registry(tries);
+    let previous_latest = latest_block_since(tries, block_number)?.ok_or(RevertError::NotCommitted(block_number))?;
+    if previous_latest == block_number {
+        return Ok(RevertReport { previous_latest, blocks: 0 });
+    }
+    if let Some(settled) = registry.latest_with_finality(Finality::AcceptedOnL1) {
+        if settled > block_number {
+            return Err(RevertError::Settled { block_number: settled });
+        }
+    }
+
+    revert_tries(tries, block_number).map_err(|error| RevertError::Trie { block_number, error })?;
+
+    registry.truncate(block_number);
+    let blocks = (previous_latest - block_number) as usize;
+    storage_history(tries).truncate_after(block_number);
+    contract_activity(tries).truncate_after(block_number);
+    empty_storage_tracker(tries).truncate_after(block_number);
+
+    Ok(RevertReport { previous_latest, blocks })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use starknet_api::core::{ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+
+    #[test]
+    fn test_revert_after_restart() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+        let config = ChainConfig::default();
+        let mut roots = Vec::new();
+        for block_number in 1..=4_u64 {
+            let csd = CommitmentStateDiff {
+                address_to_class_hash: Default::default(),
+                address_to_nonce: Default::default(),
+                storage_updates: [(
+                    contract_address,
+                    [(StorageKey(PatriciaKey(StarkFelt::ONE)), StarkFelt::from(block_number))].into(),
+                )]
+                .into_iter()
+                .collect(),
+                class_hash_to_compiled_class_hash: Default::default(),
+            };
+            roots.push(try_update_state_root(&tries, csd, block_number, &config).unwrap());
+        }
+
+        // The registry is empty after a restart, the blocks are read from the tries
+        root_registry(&tries).set_finality(2, Finality::AcceptedOnL1);
+        assert!(matches!(revert_to(&tries, 1), Err(RevertError::Settled { block_number: 2 })));
+        *root_registry(&tries) = Default::default();
+        assert!(matches!(revert_to(&tries, 5), Err(RevertError::NotCommitted(5))));
+
+        assert_eq!(revert_to(&tries, 2).unwrap(), RevertReport { previous_latest: 4, blocks: 2 });
+        assert_eq!(tries.engine().unwrap().state_root(&config).unwrap(), roots[1]);
+        assert_eq!(revert_to(&tries, 2).unwrap(), RevertReport { previous_latest: 2, blocks: 0 });
+        assert!(matches!(revert_to(&tries, 3), Err(RevertError::NotCommitted(3))));
+    }
+}
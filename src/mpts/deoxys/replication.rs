@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+
+use super::backend::Backend;
+use super::config::ChainConfig;
+use super::engine::CommitmentEngine;
+use super::error::TrieError;
+use super::interchange::{export_state, import_snapshot, InterchangeError};
+use super::lib::clone_commitment_state_diff;
+
+/// The write set of a committed block, as streamed from a primary to its replicas.
+///
+/// Replication is logical: the write set is the block's canonical state diff, which replicas apply
+/// to their own tries. The primary's state root is shipped along so that replicas detect any
+/// divergence immediately.
+///
+/// Entries are sent across processes in the [interchange](super::interchange) format, one after
+/// the other, see [ReplicationEntry::write_to] and [Replica::follow_reader].
+#[derive(Debug)]
+pub struct ReplicationEntry {
+    pub block_number: u64,
+    pub csd: CommitmentStateDiff,
+    pub state_root: Felt252Wrapper,
+}
+
+impl ReplicationEntry {
+    /// Writes the entry in the interchange format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        export_state(writer, self.block_number, self.state_root, &self.csd)
+    }
+
+    /// Reads the next entry written by [write_to](ReplicationEntry::write_to).
+    ///
+    /// # Returns
+    ///
+    /// `None` once the stream ends between two entries, an error if it ends within an entry.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>, ReplicationError> {
+        let mut first = [0u8; 1];
+        loop {
+            match reader.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(InterchangeError::from(e).into()),
+            }
+        }
+        let snapshot = import_snapshot(&mut first.as_slice().chain(reader))?;
+        Ok(Some(Self { block_number: snapshot.block_number, csd: snapshot.state, state_root: snapshot.state_root }))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicationError {
+    #[error("replication gap: expected block {expected}, received block {received}")]
+    Gap { expected: u64, received: u64 },
+    #[error("block {requested} is no longer buffered (oldest is {oldest:?}), catch up from a snapshot")]
+    NeedsSnapshot { requested: u64, oldest: Option<u64> },
+    #[error("replica diverged at block {block_number}: primary root {primary:?}, replica root {replica:?}")]
+    RootMismatch { block_number: u64, primary: Felt252Wrapper, replica: Felt252Wrapper },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+    #[error(transparent)]
+    Interchange(#[from] InterchangeError),
+}
+
+#[derive(Default)]
+struct LogState {
+    buffer: VecDeque<Arc<ReplicationEntry>>,
+    subscribers: Vec<Sender<Arc<ReplicationEntry>>>,
+}
+
+/// Primary side of the replication stream.
+///
+/// Keeps the last `capacity` entries in memory so that replicas which fell slightly behind can catch
+/// up without a snapshot.
+pub struct ReplicationLog {
+    capacity: usize,
+    state: Mutex<LogState>,
+}
+
+impl ReplicationLog {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, state: Mutex::new(LogState::default()) }
+    }
+
+    /// Publishes a committed block to every replica.
+    pub fn publish(&self, entry: ReplicationEntry) {
+        let entry = Arc::new(entry);
+        let mut state = self.state.lock().expect("Poisoned lock on replication log");
+
+        if state.buffer.len() == self.capacity {
+            state.buffer.pop_front();
+        }
+        state.buffer.push_back(Arc::clone(&entry));
+        // replicas which hung up are dropped
+        state.subscribers.retain(|subscriber| subscriber.send(Arc::clone(&entry)).is_ok());
+    }
+
+    /// Subscribes a replica whose next block to apply is `from_block`.
+    ///
+    /// Buffered entries from `from_block` onwards are sent first, followed by live entries.
+    pub fn subscribe(&self, from_block: u64) -> Result<Receiver<Arc<ReplicationEntry>>, ReplicationError> {
+        let mut state = self.state.lock().expect("Poisoned lock on replication log");
+
+        let oldest = state.buffer.front().map(|entry| entry.block_number);
+        let newest = state.buffer.back().map(|entry| entry.block_number);
+        let is_buffered = match (oldest, newest) {
+            (Some(oldest), Some(newest)) => from_block >= oldest && from_block <= newest + 1,
+            // nothing was published yet
+            _ => true,
+        };
+        if !is_buffered {
+            return Err(ReplicationError::NeedsSnapshot { requested: from_block, oldest });
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        for entry in state.buffer.iter().filter(|entry| entry.block_number >= from_block) {
+            let _ = sender.send(Arc::clone(entry));
+        }
+        state.subscribers.push(sender);
+
+        Ok(receiver)
+    }
+}
+
+/// Replica side of the replication stream.
+///
+/// The replica commits the entries to its own [engine](CommitmentEngine), which serves reads and
+/// proofs independently of the primary.
+pub struct Replica {
+    engine: CommitmentEngine,
+    next_block: u64,
+    config: ChainConfig,
+}
+
+impl Replica {
+    /// Opens a replica over the tries stored in `backend`, which is empty or was bootstrapped from
+    /// a snapshot. The replica expects the block following the latest committed one.
+    pub fn open(backend: Backend, config: ChainConfig) -> Result<Self, ReplicationError> {
+        let engine = CommitmentEngine::with_config(backend, &config)?;
+        let next_block = engine.latest().map_or(0, |latest| latest + 1);
+        Ok(Self { engine, next_block, config })
+    }
+
+    /// The next block this replica expects.
+    pub fn next_block(&self) -> u64 {
+        self.next_block
+    }
+
+    /// The tries of the replica, at the state right before [next_block](Replica::next_block).
+    pub fn engine(&self) -> &CommitmentEngine {
+        &self.engine
+    }
+
+    /// Applies an entry of the replication stream.
+    ///
+    /// Entries which were already applied are ignored, entries past the next expected block are a
+    /// [ReplicationError::Gap]. An entry whose root does not match the primary's is a
+    /// [ReplicationError::RootMismatch], the replica is then reverted to its previous block if any.
+    pub fn apply(&mut self, entry: &ReplicationEntry) -> Result<(), ReplicationError> {
+        if entry.block_number < self.next_block {
+            return Ok(());
+        }
+        if entry.block_number > self.next_block {
+            return Err(ReplicationError::Gap { expected: self.next_block, received: entry.block_number });
+        }
+
+        let previous = self.engine.latest();
+        let csd = clone_commitment_state_diff(&entry.csd);
+        let state_root = self.engine.update_state_root(csd, entry.block_number, &self.config)?;
+        if state_root != entry.state_root {
+            if let Some(previous) = previous {
+                self.engine.revert_to(previous)?;
+            }
+            return Err(ReplicationError::RootMismatch {
+                block_number: entry.block_number,
+                primary: entry.state_root,
+                replica: state_root,
+            });
+        }
+
+        self.next_block += 1;
+        Ok(())
+    }
+
+    /// Applies entries from the stream until it is closed or an error occurs.
+    pub fn follow(&mut self, stream: &Receiver<Arc<ReplicationEntry>>) -> Result<(), ReplicationError> {
+        for entry in stream.iter() {
+            self.apply(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the entries written to `reader` by [ReplicationEntry::write_to], ie: over a socket,
+    /// until it is closed or an error occurs.
+    pub fn follow_reader<R: Read>(&mut self, reader: &mut R) -> Result<(), ReplicationError> {
+        while let Some(entry) = ReplicationEntry::read_from(reader)? {
+            self.apply(&entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+
+    fn csd(value: u64) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(
+                contract_address,
+                [(StorageKey(PatriciaKey(StarkFelt::from(value))), StarkFelt::from(value))].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    /// Commits blocks `0..count` to a primary engine, returning their entries.
+    fn primary(count: u64) -> (CommitmentEngine, Vec<ReplicationEntry>) {
+        let config = ChainConfig::default();
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let entries = (0..count)
+            .map(|block_number| {
+                let state_root = engine.update_state_root(csd(block_number + 1), block_number, &config).unwrap();
+                ReplicationEntry { block_number, csd: csd(block_number + 1), state_root }
+            })
+            .collect();
+        (engine, entries)
+    }
+
+    #[test]
+    fn test_follow_log() {
+        let config = ChainConfig::default();
+        let (primary, entries) = primary(4);
+        let log = ReplicationLog::new(2);
+        let mut entries = entries.into_iter();
+        log.publish(entries.next().unwrap());
+
+        let mut replica = Replica::open(Arc::new(MemoryBackend::new()), config.clone()).unwrap();
+        let stream = log.subscribe(replica.next_block()).unwrap();
+        entries.for_each(|entry| log.publish(entry));
+        // Block 0 was evicted from the buffer
+        assert!(matches!(log.subscribe(0), Err(ReplicationError::NeedsSnapshot { requested: 0, oldest: Some(2) })));
+
+        drop(log);
+        replica.follow(&stream).unwrap();
+        assert_eq!(replica.next_block(), 4);
+        assert_eq!(replica.engine().state_root(&config).unwrap(), primary.state_root(&config).unwrap());
+        let key = StorageKey(PatriciaKey(StarkFelt::THREE));
+        assert_eq!(
+            replica.engine().storage_value(&ContractAddress(PatriciaKey(StarkFelt::ONE)), &key).unwrap(),
+            StarkFelt::THREE
+        );
+    }
+
+    #[test]
+    fn test_follow_reader() {
+        let config = ChainConfig::default();
+        let (primary, entries) = primary(3);
+        let mut bytes = Vec::new();
+        for entry in &entries {
+            entry.write_to(&mut bytes).unwrap();
+        }
+
+        let backend: Backend = Arc::new(MemoryBackend::new());
+        let mut replica = Replica::open(Arc::clone(&backend), config.clone()).unwrap();
+        replica.follow_reader(&mut bytes.as_slice()).unwrap();
+        assert_eq!(replica.next_block(), 3);
+        assert_eq!(replica.engine().state_root(&config).unwrap(), primary.state_root(&config).unwrap());
+
+        // A reopened replica resumes after its latest block, and skips the entries it applied
+        let mut replica = Replica::open(backend, config).unwrap();
+        assert_eq!(replica.next_block(), 3);
+        replica.follow_reader(&mut bytes.as_slice()).unwrap();
+
+        // A stream cut within an entry is an error
+        let truncated = &bytes[..bytes.len() - 1];
+        let mut reader = truncated;
+        ReplicationEntry::read_from(&mut reader).unwrap().unwrap();
+        ReplicationEntry::read_from(&mut reader).unwrap().unwrap();
+        assert!(matches!(ReplicationEntry::read_from(&mut reader), Err(ReplicationError::Interchange(_))));
+    }
+
+    #[test]
+    fn test_divergence() {
+        let config = ChainConfig::default();
+        let (_, mut entries) = primary(3);
+        let mut replica = Replica::open(Arc::new(MemoryBackend::new()), config.clone()).unwrap();
+        replica.apply(&entries[0]).unwrap();
+        let state_root = replica.engine().state_root(&config).unwrap();
+
+        assert!(matches!(replica.apply(&entries[2]), Err(ReplicationError::Gap { expected: 1, received: 2 })));
+        entries[1].state_root = Felt252Wrapper::ONE;
+        assert!(matches!(replica.apply(&entries[1]), Err(ReplicationError::RootMismatch { block_number: 1, .. })));
+        assert_eq!(replica.next_block(), 1);
+        assert_eq!(replica.engine().state_root(&config).unwrap(), state_root);
+    }
+}
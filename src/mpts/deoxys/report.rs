@@ -0,0 +1,142 @@
+use serde_json::{json, Value};
+use starknet_types_core::felt::Felt;
+
+use super::proof::ProofError;
+
+/// A single field compared during a verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldCheck {
+    pub field: String,
+    /// The value the verified data claims, `None` if it claims the field is absent.
+    pub expected: Option<Felt>,
+    /// The value recomputed by the verifier, `None` if it found the field absent.
+    pub computed: Option<Felt>,
+}
+
+impl FieldCheck {
+    pub fn new(field: impl Into<String>, expected: Option<Felt>, computed: Option<Felt>) -> Self {
+        Self { field: field.into(), expected, computed }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.expected == self.computed
+    }
+}
+
+/// Machine-readable outcome of a verification, for monitoring systems and conformance runs.
+///
+/// The JSON layout is stable:
+///
+/// ```json
+/// {
+///   "subject": "class_declaration_proof",
+///   "passed": false,
+///   "context": { "block_number": "12" },
+///   "checks": [
+///     { "field": "state_root", "passed": false, "expected": "0x1", "computed": "0x2" }
+///   ],
+///   "error": null
+/// }
+/// ```
+///
+/// Felts are hex encoded, absent values are `null`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerificationReport {
+    pub subject: String,
+    pub context: Vec<(String, String)>,
+    pub checks: Vec<FieldCheck>,
+    /// Error which prevented some fields from being checked at all.
+    pub error: Option<String>,
+}
+
+fn felt_json(felt: &Option<Felt>) -> Value {
+    match felt {
+        Some(felt) => Value::String(format!("{felt:#x}")),
+        None => Value::Null,
+    }
+}
+
+impl VerificationReport {
+    pub fn new(subject: impl Into<String>) -> Self {
+        Self { subject: subject.into(), ..Default::default() }
+    }
+
+    pub fn with_context(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.context.push((key.into(), value.to_string()));
+        self
+    }
+
+    pub fn check(&mut self, field: impl Into<String>, expected: Option<Felt>, computed: Option<Felt>) {
+        self.checks.push(FieldCheck::new(field, expected, computed));
+    }
+
+    /// Records a [ProofError], as a failed field check when it carries the mismatching values.
+    pub fn proof_error(&mut self, error: &ProofError) {
+        match error {
+            ProofError::HashMismatch { index, expected, computed } => {
+                self.check(format!("proof[{index}]"), Some(*expected), Some(*computed))
+            }
+            ProofError::StateRootMismatch { expected, computed } => {
+                self.check("state_root", Some(*expected), Some(*computed))
+            }
+            ProofError::ValueMismatch { expected, proven } => self.check("value", *expected, *proven),
+            error => self.error = Some(error.to_string()),
+        }
+    }
+
+    /// Whether every check passed and no error occurred.
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.checks.iter().all(FieldCheck::passed)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let context = self.context.iter().map(|(key, value)| (key.clone(), Value::String(value.clone()))).collect();
+        let checks = self
+            .checks
+            .iter()
+            .map(|check| {
+                json!({
+                    "field": check.field,
+                    "passed": check.passed(),
+                    "expected": felt_json(&check.expected),
+                    "computed": felt_json(&check.computed),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "subject": self.subject,
+            "passed": self.passed(),
+            "context": Value::Object(context),
+            "checks": checks,
+            "error": self.error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_json() {
+        let mut report = VerificationReport::new("state_root").with_context("block_number", 12);
+        report.check("contracts_trie_root", Some(Felt::ONE), Some(Felt::ONE));
+        report.proof_error(&ProofError::StateRootMismatch { expected: Felt::ONE, computed: Felt::TWO });
+
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_json(),
+            json!({
+                "subject": "state_root",
+                "passed": false,
+                "context": { "block_number": "12" },
+                "checks": [
+                    { "field": "contracts_trie_root", "passed": true, "expected": "0x1", "computed": "0x1" },
+                    { "field": "state_root", "passed": false, "expected": "0x1", "computed": "0x2" },
+                ],
+                "error": null,
+            })
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use mc_db::storage_handler::DeoxysStorageError;
+
+use super::backend::BackendError;
+use super::error::{CommitError, TrieError};
+
+/// Errors which may be worth retrying.
+pub trait Transient {
+    /// Whether the error is transient (I/O, lock contention), ie: the same operation may succeed
+    /// if retried. Permanent errors (decoding, invalid input) always fail again.
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for DeoxysStorageError {
+    fn is_transient(&self) -> bool {
+        // Insertions, trie merges and trie ids fail on the data itself, not on the database
+        matches!(
+            self,
+            DeoxysStorageError::StorageViewError { .. }
+                | DeoxysStorageError::StorageRetrievalError { .. }
+                | DeoxysStorageError::StorageCommitError { .. }
+        )
+    }
+}
+
+impl Transient for BackendError {
+    fn is_transient(&self) -> bool {
+        matches!(self, BackendError::Io(_))
+    }
+}
+
+impl Transient for TrieError {
+    fn is_transient(&self) -> bool {
+        match self {
+            TrieError::Storage(e) => e.is_transient(),
+            TrieError::Backend(e) => e.is_transient(),
+            TrieError::WithContext { source, .. } => source.is_transient(),
+            TrieError::Conversion(_) | TrieError::NodeHash => false,
+        }
+    }
+}
+
+impl Transient for CommitError {
+    fn is_transient(&self) -> bool {
+        // A block whose tries could not be rolled back must not be applied again over them
+        match self {
+            CommitError::Trie(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+/// Backend operations retried on transient errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Read,
+    /// The commit of a whole block, retried once the failed attempt was rolled back.
+    Commit,
+}
+
+/// How transient backend errors are retried.
+///
+/// Delays grow exponentially from `base_delay` up to `max_delay`. With `jitter`, each delay is
+/// drawn uniformly between zero and its exponential value, so that concurrent retries do not
+/// hit the backend in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of attempts, including the first one. `1` disables retries.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 3, base_delay: Duration::from_millis(10), max_delay: Duration::from_secs(1), jitter: true }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn none() -> Self {
+        Self { max_attempts: 1, ..Default::default() }
+    }
+
+    /// Delay to wait before the retry following the `attempt`-th failed attempt (starting at 1).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self.base_delay.saturating_mul(1 << attempt.saturating_sub(1).min(31)).min(self.max_delay);
+        if !self.jitter {
+            return exponential;
+        }
+        let random = RandomState::new().build_hasher().finish();
+        exponential.mul_f64((random as f64) / (u64::MAX as f64))
+    }
+
+    /// Runs `f`, retrying it while it fails with a transient error.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The kind of operation, for metrics.
+    /// * `f`         - The operation. It is run as a whole on every attempt.
+    ///
+    /// # Returns
+    ///
+    /// The result of the first successful attempt, or the error of the last one.
+    pub fn run<T, E: Transient>(&self, operation: Operation, mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Ok(value) => {
+                    if attempt > 1 {
+                        retry_metrics().record(operation, RetryOutcome::Recovered);
+                    }
+                    return Ok(value);
+                }
+                Err(e) if e.is_transient() && attempt < self.max_attempts => {
+                    retry_metrics().record(operation, RetryOutcome::Retried);
+                    thread::sleep(self.delay(attempt));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    if e.is_transient() && attempt > 1 {
+                        retry_metrics().record(operation, RetryOutcome::Exhausted);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+enum RetryOutcome {
+    Retried,
+    Recovered,
+    Exhausted,
+}
+
+/// Retry counters of an operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryCounters {
+    /// Number of retries performed.
+    pub retries: u64,
+    /// Number of operations which succeeded after at least one retry.
+    pub recovered: u64,
+    /// Number of operations which still failed with a transient error after every attempt.
+    pub exhausted: u64,
+}
+
+/// Retry metrics, per operation.
+#[derive(Debug, Default)]
+pub struct RetryMetrics {
+    operations: HashMap<Operation, RetryCounters>,
+}
+
+impl RetryMetrics {
+    fn record(&mut self, operation: Operation, outcome: RetryOutcome) {
+        let counters = self.operations.entry(operation).or_default();
+        match outcome {
+            RetryOutcome::Retried => counters.retries += 1,
+            RetryOutcome::Recovered => counters.recovered += 1,
+            RetryOutcome::Exhausted => counters.exhausted += 1,
+        }
+    }
+
+    pub fn get(&self, operation: Operation) -> RetryCounters {
+        self.operations.get(&operation).copied().unwrap_or_default()
+    }
+}
+
+static RETRY_METRICS: OnceLock<Mutex<RetryMetrics>> = OnceLock::new();
+
+/// Retry metrics of the process.
+pub fn retry_metrics() -> MutexGuard<'static, RetryMetrics> {
+    RETRY_METRICS.get_or_init(Default::default).lock().expect("Poisoned lock on retry metrics")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::{Backend, Column, MemoryBackend, StarkrootBackend};
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::engine::{CommitmentEngine, StateTries};
+    use crate::mpts::deoxys::lib::try_update_state_root;
+
+    #[derive(Debug)]
+    struct Flaky(bool);
+
+    impl Transient for Flaky {
+        fn is_transient(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_retry() {
+        let policy = RetryPolicy { base_delay: Duration::ZERO, ..Default::default() };
+
+        let mut failures = 2;
+        let result = policy.run(Operation::Read, || {
+            if failures > 0 {
+                failures -= 1;
+                return Err(Flaky(true));
+            }
+            Ok(())
+        });
+        assert!(result.is_ok());
+
+        let mut attempts = 0;
+        let result = policy.run(Operation::Read, || {
+            attempts += 1;
+            Err::<(), _>(Flaky(false))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result = policy.run(Operation::Read, || {
+            attempts += 1;
+            Err::<(), _>(Flaky(true))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(30),
+            jitter: false,
+            ..Default::default()
+        };
+
+        assert_eq!(policy.delay(1), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(20));
+        assert_eq!(policy.delay(3), Duration::from_millis(30));
+
+        let policy = RetryPolicy { jitter: true, ..policy };
+        assert!(policy.delay(3) <= Duration::from_millis(30));
+    }
+
+    /// Backend whose first commits fail.
+    struct FailingCommits {
+        inner: MemoryBackend,
+        failures: AtomicU32,
+    }
+
+    impl StarkrootBackend for FailingCommits {
+        fn get(&self, column: Column, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            self.inner.get(column, key)
+        }
+
+        fn put(&self, column: Column, key: &[u8], value: Option<&[u8]>) -> Result<(), BackendError> {
+            self.inner.put(column, key, value)
+        }
+
+        fn scan_prefix(&self, column: Column, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            self.inner.scan_prefix(column, prefix)
+        }
+
+        fn scan_range(
+            &self,
+            column: Column,
+            start: &[u8],
+            end: &[u8],
+        ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, BackendError> {
+            self.inner.scan_range(column, start, end)
+        }
+
+        fn commit(&self, block_number: u64) -> Result<(), BackendError> {
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(BackendError::Io("disk full".to_string()));
+            }
+            self.inner.commit(block_number)
+        }
+
+        fn discard(&self) {
+            self.inner.discard()
+        }
+
+        fn snapshot(&self, block_number: u64) -> Result<Backend, BackendError> {
+            self.inner.snapshot(block_number)
+        }
+    }
+
+    #[test]
+    fn test_commit_errors() {
+        let io = || TrieError::Backend(BackendError::Io("disk full".to_string()));
+        assert!(CommitError::Trie(io()).is_transient());
+        assert!(!CommitError::Frozen { block_number: 1 }.is_transient());
+        assert!(!CommitError::Rollback { block_number: 1, cause: io(), rollback: None }.is_transient());
+    }
+
+    #[test]
+    fn test_commit_retried_after_rollback() {
+        let backend = Arc::new(FailingCommits { inner: MemoryBackend::new(), failures: AtomicU32::new(1) });
+        let tries = StateTries::open(backend).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64)));
+        let csd = || CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::from(0x12_u64)))].into_iter().collect(),
+            address_to_nonce: Default::default(),
+            storage_updates: [(contract_address, [(StorageKey(PatriciaKey(StarkFelt::ONE)), StarkFelt::TWO)].into())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let retry = RetryPolicy { base_delay: Duration::ZERO, ..Default::default() };
+        let config = ChainConfig { retry, ..Default::default() };
+        let recovered = retry_metrics().get(Operation::Commit).recovered;
+        let state_root = try_update_state_root(&tries, csd(), 1, &config).unwrap();
+        assert_eq!(retry_metrics().get(Operation::Commit).recovered, recovered + 1);
+
+        // The failed attempt left nothing behind
+        let mut expected = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        assert_eq!(expected.update_state_root(csd(), 1, &config).unwrap(), state_root);
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::BTreeMap;
+use std::sync::MutexGuard;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+use mp_hashers::poseidon::PoseidonHasher;
+use mp_hashers::HasherT;
+use starknet_api::hash::StarkFelt;
+use starknet_ff::FieldElement;
+pub use starkroot_types::roots::Finality;
+
+use super::engine::StateTries;
+use super::error::{CommitError, TrieError};
+use super::pruning::auto_prune;
+use super::stats::CommitStats;
+
+/// A block whose state diff was committed to the tries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommittedBlock {
+    /// Fingerprint of the committed state diff, see [diff_hash].
+    pub diff_hash: Felt252Wrapper,
+    /// The state root after the block.
+    pub state_root: Felt252Wrapper,
+    /// Write statistics of the block.
+    pub stats: CommitStats,
+    /// How final the state root is, see [RootRegistry::set_finality].
+    pub finality: Finality,
+    /// Whether the state root is best-effort, some contracts being
+    /// [quarantined](super::quarantine::Quarantine) at the block.
+    pub dirty: bool,
+}
+
+/// Fencing token identifying the leader allowed to commit, in distributed setups.
+///
+/// Tokens are expected to increase with each leader election (ie: an epoch or lease version issued
+/// by the external coordinator). Once a token has been seen, commits carrying an older token are
+/// rejected, so a former leader which is still running cannot corrupt the state after a standby
+/// took over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FencingToken(pub u64);
+
+/// Maximum number of blocks kept by the [RootRegistry], the oldest ones are forgotten first.
+pub const REGISTRY_CAPACITY: usize = 1 << 16;
+
+/// Registry of the state roots of committed blocks.
+///
+/// This is what makes commits idempotent: committing the same diff again at an existing height is
+/// a no-op returning the stored root, while committing a different diff is a conflict.
+///
+/// The registry keeps the latest [REGISTRY_CAPACITY] blocks in memory. When the tries are committed
+/// by an [engine](super::engine::CommitmentEngine), the diff hash and state root of every block are
+/// persisted along with its tries, so that blocks forgotten by the registry (or committed before a
+/// restart) are still deduplicated, until they are [pruned](super::handles::WriteHandle::prune).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RootRegistry {
+    pub(crate) blocks: BTreeMap<u64, CommittedBlock>,
+    pub(crate) fencing_token: Option<FencingToken>,
+    pub(crate) version: u64,
+}
+
+impl RootRegistry {
+    /// Checks that a commit carrying `token` comes from the current leader, and makes `token` the
+    /// current one if it is newer.
+    pub fn fence(&mut self, token: FencingToken) -> Result<(), CommitError> {
+        self.check_fence(Some(token))?;
+        self.fencing_token = Some(token);
+        Ok(())
+    }
+
+    /// Checks that a commit carrying `token`, if any, comes from the current leader without making
+    /// `token` the current one.
+    pub(crate) fn check_fence(&self, token: Option<FencingToken>) -> Result<(), CommitError> {
+        match (token, self.fencing_token) {
+            (Some(token), Some(current)) if token < current => Err(CommitError::Fenced { token, current }),
+            (None, Some(current)) => Err(CommitError::Unfenced { current }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The most recent fencing token seen.
+    pub fn fencing_token(&self) -> Option<FencingToken> {
+        self.fencing_token
+    }
+
+    pub fn get(&self, block_number: u64) -> Option<&CommittedBlock> {
+        self.blocks.get(&block_number)
+    }
+
+    /// Version of the registry, bumped each time a block is recorded, forgotten or changes finality.
+    ///
+    /// Consumers caching roots or proofs can compare versions to know whether they are stale.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the finality status of a committed block.
+    pub fn finality_of(&self, block_number: u64) -> Option<Finality> {
+        self.blocks.get(&block_number).map(|block| block.finality)
+    }
+
+    /// Marks `block_number` and the blocks before it as at least `finality`.
+    ///
+    /// Finality never goes backwards: blocks which are already more final than `finality` are left
+    /// untouched.
+    ///
+    /// # Returns
+    ///
+    /// `false` if `block_number` was not committed.
+    pub fn set_finality(&mut self, block_number: u64, finality: Finality) -> bool {
+        if !self.blocks.contains_key(&block_number) {
+            return false;
+        }
+
+        let mut changed = false;
+        for block in self.blocks.range_mut(..=block_number).rev().map(|(_, block)| block) {
+            if block.finality >= finality {
+                break;
+            }
+            block.finality = finality;
+            changed = true;
+        }
+        if changed {
+            self.version += 1;
+        }
+        true
+    }
+
+    /// Returns the latest block which is at least `finality`.
+    pub fn latest_with_finality(&self, finality: Finality) -> Option<u64> {
+        self.blocks.iter().rev().find(|(_, block)| block.finality >= finality).map(|(block_number, _)| *block_number)
+    }
+
+    /// Returns the latest committed block.
+    pub fn latest(&self) -> Option<(u64, &CommittedBlock)> {
+        self.blocks.last_key_value().map(|(block_number, block)| (*block_number, block))
+    }
+
+    /// Returns up to `count` of the most recently committed blocks, latest first.
+    pub fn recent(&self, count: usize) -> impl Iterator<Item = (u64, &CommittedBlock)> {
+        self.blocks.iter().rev().take(count).map(|(block_number, block)| (*block_number, block))
+    }
+
+    /// Checks whether a diff can be committed at `block_number`.
+    ///
+    /// # Returns
+    ///
+    /// The stored state root if the exact same diff was already committed at this height, `None` if
+    /// nothing was committed at this height yet.
+    pub fn check(
+        &self,
+        block_number: u64,
+        diff_hash: Felt252Wrapper,
+    ) -> Result<Option<Felt252Wrapper>, CommitError> {
+        match self.blocks.get(&block_number) {
+            None => Ok(None),
+            Some(block) if block.diff_hash == diff_hash => Ok(Some(block.state_root)),
+            Some(block) => Err(CommitError::Conflict { block_number, stored: block.diff_hash, received: diff_hash }),
+        }
+    }
+
+    /// Records a committed block.
+    pub fn record(
+        &mut self,
+        block_number: u64,
+        diff_hash: Felt252Wrapper,
+        state_root: Felt252Wrapper,
+        stats: CommitStats,
+    ) {
+        let finality = Finality::default();
+        self.blocks.insert(block_number, CommittedBlock { diff_hash, state_root, stats, finality, dirty: false });
+        while self.blocks.len() > REGISTRY_CAPACITY {
+            self.blocks.pop_first();
+        }
+        self.version += 1;
+    }
+
+    /// Flags the state root of a block as [dirty](CommittedBlock::dirty), returning whether the block
+    /// was committed.
+    pub fn mark_dirty(&mut self, block_number: u64) -> bool {
+        let Some(block) = self.blocks.get_mut(&block_number) else {
+            return false;
+        };
+        if !block.dirty {
+            block.dirty = true;
+            self.version += 1;
+        }
+        true
+    }
+
+    /// Forgets the blocks committed before `block_number`, returning how many were forgotten.
+    pub fn prune_before(&mut self, block_number: u64) -> usize {
+        let kept = self.blocks.split_off(&block_number);
+        let pruned = std::mem::replace(&mut self.blocks, kept).len();
+        if pruned > 0 {
+            self.version += 1;
+        }
+        pruned
+    }
+
+    /// Forgets the blocks committed after `block_number`, returning them.
+    pub fn truncate(&mut self, block_number: u64) -> BTreeMap<u64, CommittedBlock> {
+        let truncated = self.blocks.split_off(&(block_number + 1));
+        if !truncated.is_empty() {
+            self.version += 1;
+        }
+        truncated
+    }
+}
+
+/// Stages the record of a block with the tries of an [engine](super::engine::CommitmentEngine), if
+/// any, see [RootRegistry].
+pub(crate) fn stage_committed_block(
+    tries: &StateTries,
+    block_number: u64,
+    diff_hash: Felt252Wrapper,
+    state_root: Felt252Wrapper,
+    fencing_token: Option<FencingToken>,
+) -> Result<(), TrieError> {
+    let Some(mut engine) = tries.engine() else {
+        return Ok(());
+    };
+    if let Some(fencing_token) = fencing_token {
+        engine.set_fencing_token(fencing_token);
+    }
+    engine.stage_committed_block(block_number, diff_hash, state_root)
+}
+
+/// [RootRegistry::check] against the blocks persisted by the tries of an
+/// [engine](super::engine::CommitmentEngine), if any.
+pub(crate) fn check_persisted(
+    tries: &StateTries,
+    block_number: u64,
+    diff_hash: Felt252Wrapper,
+) -> Result<Option<Felt252Wrapper>, CommitError> {
+    let Some(engine) = tries.engine() else {
+        return Ok(None);
+    };
+    match engine.committed_block(block_number)? {
+        None => Ok(None),
+        Some((stored, state_root)) if stored == diff_hash => Ok(Some(state_root)),
+        Some((stored, _)) => Err(CommitError::Conflict { block_number, stored, received: diff_hash }),
+    }
+}
+
+/// Returns the [RootRegistry] of the blocks committed to `tries`.
+pub fn root_registry(tries: &StateTries) -> MutexGuard<'_, RootRegistry> {
+    tries.state().registry.lock().expect("Poisoned lock on root registry")
+}
+
+/// Returns the finality status of a committed block, `None` if it was not committed.
+///
+/// Applications consuming state roots or proofs can use it to pick their trust level, ie: only
+/// serve proofs of blocks accepted on L1.
+pub fn finality_of(tries: &StateTries, block_number: u64) -> Option<Finality> {
+    root_registry(tries).finality_of(block_number)
+}
+
+/// Notifies that `block_number` reached `finality`, from the L1 sync or any external source.
+///
+/// See [RootRegistry::set_finality].
+/// Blocks accepted on L1 move the [prune horizon](super::pruning::PrunePolicy::horizon) when
+/// `retention.finality_margin` is configured, the data which falls behind it is pruned right away.
+///
+/// # Arguments
+///
+/// * `tries`        - The tries the block was committed to, which are pruned.
+/// * `block_number` - The block whose finality changed.
+/// * `finality`     - The finality it reached.
+///
+/// # Returns
+///
+/// Whether the block was committed, or the error which made pruning fail. The finality is recorded
+/// either way, and pruning is retried with the next block accepted on L1.
+pub fn notify_finality(tries: &StateTries, block_number: u64, finality: Finality) -> Result<bool, TrieError> {
+    if !root_registry(tries).set_finality(block_number, finality) {
+        return Ok(false);
+    }
+    if finality == Finality::AcceptedOnL1 {
+        auto_prune(tries)?;
+    }
+    Ok(true)
+}
+
+fn felt(felt: &StarkFelt) -> FieldElement {
+    FieldElement::from_bytes_be(&felt.0).unwrap()
+}
+
+/// Computes a fingerprint of a canonicalized [CommitmentStateDiff], used to detect blocks being
+/// committed twice.
+///
+/// This is an internal identifier and not a Starknet commitment.
+pub fn diff_hash(csd: &CommitmentStateDiff) -> Felt252Wrapper {
+    let mut elements = Vec::new();
+
+    elements.push(FieldElement::from(csd.address_to_class_hash.len()));
+    for (contract_address, class_hash) in csd.address_to_class_hash.iter() {
+        elements.extend([felt(contract_address.0.key()), felt(&class_hash.0)]);
+    }
+
+    elements.push(FieldElement::from(csd.address_to_nonce.len()));
+    for (contract_address, nonce) in csd.address_to_nonce.iter() {
+        elements.extend([felt(contract_address.0.key()), felt(&nonce.0)]);
+    }
+
+    elements.push(FieldElement::from(csd.class_hash_to_compiled_class_hash.len()));
+    for (class_hash, compiled_class_hash) in csd.class_hash_to_compiled_class_hash.iter() {
+        elements.extend([felt(&class_hash.0), felt(&compiled_class_hash.0)]);
+    }
+
+    elements.push(FieldElement::from(csd.storage_updates.len()));
+    for (contract_address, updates) in csd.storage_updates.iter() {
+        elements.extend([felt(contract_address.0.key()), FieldElement::from(updates.len())]);
+        for (key, value) in updates {
+            elements.extend([felt(key.0.key()), felt(value)]);
+        }
+    }
+
+    PoseidonHasher::compute_hash_on_elements(&elements).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+    use starknet_api::core::{ContractAddress, Nonce, PatriciaKey};
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+
+    #[test]
+    fn test_duplicate_block_detection() {
+        let mut registry = RootRegistry::default();
+        let diff_hash = Felt252Wrapper::ONE;
+        let state_root = Felt252Wrapper::TWO;
+
+        assert_eq!(registry.check(1, diff_hash).unwrap(), None);
+        registry.record(1, diff_hash, state_root, CommitStats::default());
+
+        assert_eq!(registry.check(1, diff_hash).unwrap(), Some(state_root));
+        assert!(matches!(
+            registry.check(1, Felt252Wrapper::THREE),
+            Err(CommitError::Conflict { block_number: 1, .. })
+        ));
+        let mut block = CommittedBlock {
+            diff_hash,
+            state_root,
+            stats: CommitStats::default(),
+            finality: Finality::Pending,
+            dirty: false,
+        };
+        assert_eq!(registry.latest(), Some((1, &block)));
+        assert!(registry.mark_dirty(1));
+        assert!(!registry.mark_dirty(2));
+        block.dirty = true;
+        assert_eq!(registry.latest(), Some((1, &block)));
+    }
+
+    #[test]
+    fn test_registry_capacity() {
+        let mut registry = RootRegistry::default();
+        for block_number in 0..=REGISTRY_CAPACITY as u64 {
+            registry.record(block_number, Felt252Wrapper::ONE, Felt252Wrapper::TWO, CommitStats::default());
+        }
+        assert_eq!(registry.blocks.len(), REGISTRY_CAPACITY);
+        assert_eq!(registry.get(0), None);
+        assert!(registry.get(1).is_some());
+    }
+
+    #[test]
+    fn test_persisted_blocks() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        let csd = |value: u64| CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: [(ContractAddress(PatriciaKey(StarkFelt::ONE)), Nonce(StarkFelt::from(value)))]
+                .into_iter()
+                .collect(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        let state_root = try_update_state_root(&tries, csd(1), 1, &config).unwrap();
+        try_update_state_root(&tries, csd(2), 2, &config).unwrap();
+
+        // The blocks are still deduplicated once the registry forgot them
+        *root_registry(&tries) = RootRegistry::default();
+        assert_eq!(try_update_state_root(&tries, csd(1), 1, &config).unwrap(), state_root);
+        assert!(matches!(
+            try_update_state_root(&tries, csd(3), 1, &config),
+            Err(CommitError::Conflict { block_number: 1, .. })
+        ));
+        assert!(root_registry(&tries).get(1).is_none());
+    }
+
+    #[test]
+    fn test_fencing() {
+        let mut registry = RootRegistry::default();
+
+        registry.fence(FencingToken(1)).unwrap();
+        registry.fence(FencingToken(2)).unwrap();
+        registry.fence(FencingToken(2)).unwrap();
+        assert!(matches!(
+            registry.fence(FencingToken(1)),
+            Err(CommitError::Fenced { token: FencingToken(1), current: FencingToken(2) })
+        ));
+        assert_eq!(registry.fencing_token(), Some(FencingToken(2)));
+    }
+
+    #[test]
+    fn test_finality() {
+        let mut registry = RootRegistry::default();
+        for block_number in 1..=4 {
+            registry.record(block_number, Felt252Wrapper::ONE, Felt252Wrapper::TWO, CommitStats::default());
+        }
+        let version = registry.version();
+
+        assert!(registry.set_finality(3, Finality::AcceptedOnL2));
+        assert!(registry.set_finality(2, Finality::AcceptedOnL1));
+        assert!(!registry.set_finality(5, Finality::Proven));
+        assert_eq!(registry.version(), version + 2);
+
+        assert_eq!(registry.finality_of(1), Some(Finality::AcceptedOnL1));
+        assert_eq!(registry.finality_of(2), Some(Finality::AcceptedOnL1));
+        assert_eq!(registry.finality_of(3), Some(Finality::AcceptedOnL2));
+        assert_eq!(registry.finality_of(4), Some(Finality::Pending));
+        assert_eq!(registry.finality_of(5), None);
+
+        assert!(registry.set_finality(1, Finality::AcceptedOnL2));
+        assert_eq!(registry.finality_of(1), Some(Finality::AcceptedOnL1));
+        assert_eq!(registry.version(), version + 2);
+    }
+}
@@ -0,0 +1,334 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+#[cfg(feature = "rocksdb")]
+use super::backend::RocksDbBackend;
+use super::class_store::ClassStore;
+use super::config::ChainConfig;
+use super::engine::StateTries;
+use super::error::TrieError;
+use super::settings::{CommitmentConfig, ConfigError, ParallelismSettings, RetentionSettings};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReconfigureError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("settings {0:?} cannot change while running, a restart is required")]
+    Structural(Vec<&'static str>),
+    #[error("failed to resize the thread pool: {0}")]
+    ThreadPool(#[from] ThreadPoolBuildError),
+    #[error("failed to open the storage: {0}")]
+    Storage(#[from] TrieError),
+    #[error("failed to open the class store {path:?}: {error}")]
+    ClassStore { path: PathBuf, error: std::io::Error },
+}
+
+/// How the commits to a set of [StateTries] run, from the `parallelism` and `retention` settings
+/// they were [configured](StateTries::configure) with.
+#[derive(Clone, Default)]
+pub(crate) struct Execution {
+    /// Thread pool the tries are updated on, `None` for the global rayon pool.
+    pub(crate) pool: Option<Arc<ThreadPool>>,
+    /// See [ParallelismSettings::deterministic].
+    pub(crate) deterministic: bool,
+    pub(crate) retention: RetentionSettings,
+}
+
+impl Execution {
+    pub(crate) fn new(config: &CommitmentConfig) -> Result<Self, ThreadPoolBuildError> {
+        Ok(Self {
+            pool: thread_pool(&config.parallelism)?,
+            deterministic: config.parallelism.deterministic,
+            retention: config.retention,
+        })
+    }
+
+    /// Runs `f` on the thread pool, so that the parallel trie updates it performs honour
+    /// `parallelism.threads`.
+    pub(crate) fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        let f = in_current_span(f);
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+}
+
+/// The thread pool of `parallelism`, `None` for the global rayon pool.
+fn thread_pool(parallelism: &ParallelismSettings) -> Result<Option<Arc<ThreadPool>>, ThreadPoolBuildError> {
+    let threads = if parallelism.deterministic { Some(1) } else { parallelism.threads };
+    threads.map(|threads| ThreadPoolBuilder::new().num_threads(threads).build().map(Arc::new)).transpose()
+}
+
+/// The live configuration of the process.
+struct Runtime {
+    config: CommitmentConfig,
+    chain_config: Arc<ChainConfig>,
+    /// How the work of the process which is not tied to a set of tries runs, ie: the block
+    /// commitments.
+    execution: Execution,
+    /// The tries opened by [init], which follow the configuration.
+    tries: Option<StateTries>,
+    /// The class store opened by [init], if one is configured.
+    class_store: Option<ClassStore>,
+}
+
+impl Runtime {
+    fn new(config: CommitmentConfig) -> Result<Self, ReconfigureError> {
+        Ok(Self {
+            chain_config: Arc::new(config.chain_config()),
+            execution: Execution::new(&config)?,
+            config,
+            tries: None,
+            class_store: None,
+        })
+    }
+}
+
+static RUNTIME: OnceLock<RwLock<Runtime>> = OnceLock::new();
+
+/// Whether the process runs in deterministic mode, see [ParallelismSettings::deterministic].
+///
+/// In deterministic mode the commits run on a single thread, so that the tries are updated in the
+/// same order on every run, retries are not jittered and canary sampling uses a fixed seed.
+pub fn is_deterministic() -> bool {
+    runtime().read().expect("Poisoned lock on runtime").config.parallelism.deterministic
+}
+
+/// Switches deterministic mode on or off.
+pub fn set_deterministic(deterministic: bool) -> Result<(), ReconfigureError> {
+    let mut config = current_config();
+    config.parallelism.deterministic = deterministic;
+    reconfigure(config).map(|_| ())
+}
+
+fn runtime() -> &'static RwLock<Runtime> {
+    RUNTIME.get_or_init(|| RwLock::new(Runtime::new(CommitmentConfig::default()).expect("Default runtime is valid")))
+}
+
+/// The current configuration.
+pub fn current_config() -> CommitmentConfig {
+    runtime().read().expect("Poisoned lock on runtime").config.clone()
+}
+
+/// The current commitment rules, to be passed to each commit.
+///
+/// This is a snapshot: a block committed while the configuration changes keeps the rules it started
+/// with.
+pub fn current_chain_config() -> Arc<ChainConfig> {
+    Arc::clone(&runtime().read().expect("Poisoned lock on runtime").chain_config)
+}
+
+/// The [class store](ClassStore) at `storage.class_store`, opened by [init].
+pub fn class_store() -> Option<ClassStore> {
+    runtime().read().expect("Poisoned lock on runtime").class_store.clone()
+}
+
+/// Wraps `f` to run in the caller's tracing span, whichever thread of the pool it ends up on.
+#[cfg(feature = "tracing")]
+pub(crate) fn in_current_span<R>(f: impl FnOnce() -> R) -> impl FnOnce() -> R {
+    let span = tracing::Span::current();
+    move || span.in_scope(f)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn in_current_span<R>(f: impl FnOnce() -> R) -> impl FnOnce() -> R {
+    f
+}
+
+/// Runs `f` on the configured thread pool, so that the parallel work it performs honours
+/// `parallelism.threads`.
+///
+/// The commits to a set of tries run on the thread pool the tries were
+/// [configured](StateTries::configure) with instead.
+pub fn install<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+    let execution = runtime().read().expect("Poisoned lock on runtime").execution.clone();
+    execution.install(f)
+}
+
+/// Runs `f` in the background on a dedicated thread, which [installs](install) it on the configured
+/// thread pool.
+///
+/// `f` is not spawned on the pool itself: it would hold a pool thread while waiting for the locks
+/// of the commit, which the holder of the locks may need to make progress.
+pub fn spawn(f: impl FnOnce() + Send + 'static) {
+    let f = in_current_span(f);
+    std::thread::Builder::new()
+        .name("starkroot-offload".to_string())
+        .spawn(move || install(f))
+        .expect("Failed to spawn offload thread");
+}
+
+/// Applies a new configuration without restarting.
+///
+/// Only non-structural settings can change: retention, parallelism, caches, query limits, retries,
+/// canary sampling and the commit SLA. The retention and parallelism apply to the tries opened by
+/// [init], other tries keep those they were [configured](StateTries::configure) with. Settings
+/// which change the stored data or the computed roots (storage paths, hashes, compression,
+/// features...) are rejected as a whole, leaving the current configuration untouched. Commits
+/// already running finish with the configuration they started with.
+///
+/// # Returns
+///
+/// The previous configuration.
+pub fn reconfigure(config: CommitmentConfig) -> Result<CommitmentConfig, ReconfigureError> {
+    config.validate()?;
+
+    let mut runtime = runtime().write().expect("Poisoned lock on runtime");
+    let structural = runtime.config.structural_changes(&config);
+    if !structural.is_empty() {
+        return Err(ReconfigureError::Structural(structural));
+    }
+
+    let pool = if config.parallelism == runtime.config.parallelism {
+        runtime.execution.pool.clone()
+    } else {
+        thread_pool(&config.parallelism)?
+    };
+    let execution = Execution { pool, deterministic: config.parallelism.deterministic, retention: config.retention };
+    if let Some(tries) = &runtime.tries {
+        tries.configure(execution.clone());
+    }
+    let chain_config = Arc::new(config.chain_config());
+    let previous = std::mem::replace(&mut runtime.config, config);
+    runtime.chain_config = chain_config;
+    runtime.execution = execution;
+    Ok(previous)
+}
+
+/// Reloads the configuration file, ie: when the daemon receives `SIGHUP`.
+pub fn reload(path: impl AsRef<Path>) -> Result<CommitmentConfig, ReconfigureError> {
+    reconfigure(CommitmentConfig::from_file(path)?)
+}
+
+/// Sets the configuration the process starts with, structural settings included.
+///
+/// The class store at `storage.class_store` is opened, and the tries of the RocksDB database at
+/// `storage.path` if one is set, which requires the `rocksdb` feature.
+///
+/// This must be called before the first commit.
+///
+/// # Returns
+///
+/// The tries to commit to: those of the database at `storage.path`, or the tries of the node's
+/// database.
+pub fn init(config: CommitmentConfig) -> Result<StateTries, ReconfigureError> {
+    config.validate()?;
+    let class_store = match &config.storage.class_store {
+        Some(path) => {
+            Some(ClassStore::open(path).map_err(|error| ReconfigureError::ClassStore { path: path.clone(), error })?)
+        }
+        None => None,
+    };
+    let tries = if config.storage.path.as_os_str().is_empty() { StateTries::node_db() } else { open_storage(&config)? };
+
+    let mut live = Runtime::new(config)?;
+    tries.configure(live.execution.clone());
+    live.tries = Some(tries.clone());
+    live.class_store = class_store;
+    *runtime().write().expect("Poisoned lock on runtime") = live;
+    Ok(tries)
+}
+
+/// Opens the tries of the database at `storage.path`, which keeps the snapshots of the
+/// `retention.blocks` latest blocks so that they can be reverted to.
+#[cfg(feature = "rocksdb")]
+fn open_storage(config: &CommitmentConfig) -> Result<StateTries, ReconfigureError> {
+    let keep_snapshots = config.retention.blocks.map_or(usize::MAX, |blocks| blocks as usize);
+    let backend = RocksDbBackend::open(&config.storage.path, keep_snapshots).map_err(TrieError::from)?;
+    Ok(StateTries::with_config(Arc::new(backend), &config.chain_config())?)
+}
+
+#[cfg(not(feature = "rocksdb"))]
+fn open_storage(_config: &CommitmentConfig) -> Result<StateTries, ReconfigureError> {
+    Err(ConfigError::Invalid { field: "storage.path", reason: "requires the `rocksdb` feature".to_string() }.into())
+}
+
+/// Serializes the tests which change the configuration of the process.
+#[cfg(test)]
+pub(crate) fn exclusive() -> std::sync::MutexGuard<'static, ()> {
+    static EXCLUSIVE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    EXCLUSIVE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Restores the configuration of the process when dropped, including when a test fails.
+#[cfg(test)]
+pub(crate) struct RestoreRuntime(Option<Runtime>);
+
+#[cfg(test)]
+impl RestoreRuntime {
+    pub(crate) fn new() -> Self {
+        let runtime = runtime().read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let previous = Runtime {
+            config: runtime.config.clone(),
+            chain_config: Arc::clone(&runtime.chain_config),
+            execution: runtime.execution.clone(),
+            tries: runtime.tries.clone(),
+            class_store: runtime.class_store.clone(),
+        };
+        Self(Some(previous))
+    }
+}
+
+#[cfg(test)]
+impl Drop for RestoreRuntime {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            let mut runtime = runtime().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            // The tries opened meanwhile, ie: those of the node's database, go back to the previous
+            // configuration as well
+            if let Some(tries) = &runtime.tries {
+                tries.configure(previous.execution.clone());
+            }
+            *runtime = previous;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconfigure() {
+        let _runtime = exclusive();
+        let _restore = RestoreRuntime::new();
+        let root = std::env::temp_dir().join(format!("starkroot-runtime-{}", std::process::id()));
+        let mut config = CommitmentConfig::default();
+        config.storage.class_store = Some(root.clone());
+        let tries = init(config.clone()).unwrap();
+        assert!(class_store().is_some());
+
+        config.parallelism.threads = Some(2);
+        config.queries.recent_blocks = 8;
+        reconfigure(config.clone()).unwrap();
+        assert_eq!(current_config().queries.recent_blocks, 8);
+        assert_eq!(install(rayon::current_num_threads), 2);
+        // The tries opened by init follow the configuration
+        assert_eq!(tries.execution().install(rayon::current_num_threads), 2);
+
+        let mut structural = config.clone();
+        structural.storage.class_store = None;
+        structural.queries.recent_blocks = 16;
+        assert!(matches!(reconfigure(structural), Err(ReconfigureError::Structural(fields)) if fields == ["storage"]));
+        assert_eq!(current_config(), config);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(not(feature = "rocksdb"))]
+    #[test]
+    fn test_storage_requires_rocksdb() {
+        let _runtime = exclusive();
+        let _restore = RestoreRuntime::new();
+        let mut config = CommitmentConfig::default();
+        config.storage.path = "/var/lib/starkroot".into();
+
+        assert!(matches!(
+            init(config),
+            Err(ReconfigureError::Config(ConfigError::Invalid { field: "storage.path", .. }))
+        ));
+    }
+}
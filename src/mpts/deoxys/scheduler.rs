@@ -0,0 +1,391 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+
+use super::cost::estimate_commit_cost;
+use super::runtime::install;
+
+/// Identifies a chain instance sharing the [Scheduler].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TenantId(pub String);
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SchedulerError {
+    #[error("unknown tenant {0:?}")]
+    UnknownTenant(TenantId),
+    #[error("tenant {0:?} is already registered")]
+    DuplicateTenant(TenantId),
+    #[error("scheduler is shut down")]
+    Closed,
+}
+
+/// Scheduling metrics of a tenant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantMetrics {
+    /// Number of jobs run, including the ones which panicked.
+    pub completed: u64,
+    /// Number of jobs which panicked.
+    pub panicked: u64,
+    /// Number of jobs waiting to run.
+    pub queued: usize,
+    /// Total I/O cost of the jobs run, in [node writes](super::cost::CommitCost::node_writes).
+    pub io_cost: u64,
+    /// Time spent running jobs.
+    pub busy: Duration,
+    /// Time jobs spent waiting in the queue.
+    pub wait: Duration,
+}
+
+struct Job {
+    cost: u64,
+    enqueued: Instant,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+struct Tenant {
+    id: TenantId,
+    queue: VecDeque<Job>,
+    /// Jobs of a tenant run one at a time, so that its blocks are committed in order.
+    running: bool,
+    deficit: u64,
+    metrics: TenantMetrics,
+}
+
+struct State {
+    tenants: Vec<Tenant>,
+    next: usize,
+    quantum: u64,
+    closed: bool,
+}
+
+impl State {
+    fn tenant(&mut self, id: &TenantId) -> Result<&mut Tenant, SchedulerError> {
+        self.tenants
+            .iter_mut()
+            .find(|tenant| tenant.id == *id)
+            .ok_or_else(|| SchedulerError::UnknownTenant(id.clone()))
+    }
+
+    /// Picks the next job to run with deficit round robin, so that every tenant gets the same share
+    /// of the I/O budget whatever the size of its blocks.
+    fn pick(&mut self) -> Option<(usize, Job)> {
+        if !self.tenants.iter().any(|tenant| !tenant.running && !tenant.queue.is_empty()) {
+            return None;
+        }
+        loop {
+            let index = self.next;
+            self.next = (self.next + 1) % self.tenants.len();
+
+            let tenant = &mut self.tenants[index];
+            if tenant.running || tenant.queue.is_empty() {
+                continue;
+            }
+            tenant.deficit += self.quantum;
+            if tenant.queue.front().is_some_and(|job| job.cost <= tenant.deficit) {
+                let job = tenant.queue.pop_front().unwrap();
+                tenant.deficit -= job.cost;
+                if tenant.queue.is_empty() {
+                    // idle tenants do not accumulate credit
+                    tenant.deficit = 0;
+                }
+                tenant.running = true;
+                return Some((index, job));
+            }
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+/// Schedules the commits of several chain instances on the configured thread pool.
+///
+/// This is meant for operators running many appchains in a single process. Each tenant submits its
+/// commits in block order and they run one at a time per tenant, while tenants share the pool
+/// fairly: the I/O budget is split with deficit round robin on the
+/// [estimated cost](super::cost::estimate_commit_cost) of each commit, so that a chain with large
+/// blocks cannot starve the others.
+///
+/// Jobs are [installed](install) on the pool from a few dispatching threads, which only wait for
+/// them. A job which panics is counted in [TenantMetrics::panicked] and the next job of its tenant
+/// runs, unless the process is built with `panic = "abort"`.
+///
+/// The jobs of each tenant commit to the [StateTries](super::engine::StateTries) of their own chain,
+/// which carry its root registry and indexes, so that tenants can commit the same block numbers.
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler running up to `threads` jobs at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `threads` - Number of jobs which can run concurrently across all tenants, on the configured
+    ///   thread pool.
+    /// * `quantum` - I/O budget granted to each tenant on each round, in node writes.
+    pub fn new(threads: usize, quantum: u64) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { tenants: Vec::new(), next: 0, quantum: quantum.max(1), closed: false }),
+            available: Condvar::new(),
+        });
+        let workers = (0..threads)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name("starkroot-scheduler".to_string())
+                    .spawn(move || worker(&shared))
+                    .expect("Failed to spawn scheduler thread")
+            })
+            .collect();
+        Self { shared, workers }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().expect("Poisoned lock on scheduler")
+    }
+
+    pub fn add_tenant(&self, id: TenantId) -> Result<(), SchedulerError> {
+        let mut state = self.state();
+        if state.tenants.iter().any(|tenant| tenant.id == id) {
+            return Err(SchedulerError::DuplicateTenant(id));
+        }
+        state.tenants.push(Tenant {
+            id,
+            queue: VecDeque::new(),
+            running: false,
+            deficit: 0,
+            metrics: TenantMetrics::default(),
+        });
+        Ok(())
+    }
+
+    /// Submits a job of a tenant, which runs after the previously submitted jobs of this tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant` - The tenant the job belongs to.
+    /// * `cost`   - Estimated I/O cost of the job, in node writes.
+    /// * `job`    - The job.
+    pub fn submit(
+        &self,
+        tenant: &TenantId,
+        cost: u64,
+        job: impl FnOnce() + Send + 'static,
+    ) -> Result<(), SchedulerError> {
+        let mut state = self.state();
+        if state.closed {
+            return Err(SchedulerError::Closed);
+        }
+        let tenant = state.tenant(tenant)?;
+        tenant.queue.push_back(Job { cost, enqueued: Instant::now(), run: Box::new(job) });
+        tenant.metrics.queued = tenant.queue.len();
+        drop(state);
+
+        self.shared.available.notify_one();
+        Ok(())
+    }
+
+    /// Submits the commit of a block of a tenant, its cost being estimated from its state diff.
+    pub fn submit_commit(
+        &self,
+        tenant: &TenantId,
+        csd: CommitmentStateDiff,
+        commit: impl FnOnce(CommitmentStateDiff) + Send + 'static,
+    ) -> Result<(), SchedulerError> {
+        let cost = estimate_commit_cost(&csd).node_writes;
+        self.submit(tenant, cost, move || commit(csd))
+    }
+
+    pub fn metrics(&self, tenant: &TenantId) -> Result<TenantMetrics, SchedulerError> {
+        Ok(self.state().tenant(tenant)?.metrics)
+    }
+
+    /// Stops accepting jobs and waits for the queued ones to run.
+    pub fn shutdown(self) {
+        self.state().closed = true;
+        self.shared.available.notify_all();
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker(shared: &Shared) {
+    let mut state = shared.state.lock().expect("Poisoned lock on scheduler");
+    loop {
+        let Some((index, job)) = state.pick() else {
+            let idle = state.tenants.iter().all(|tenant| !tenant.running && tenant.queue.is_empty());
+            if state.closed && idle {
+                return;
+            }
+            state = shared.available.wait(state).expect("Poisoned lock on scheduler");
+            continue;
+        };
+        drop(state);
+
+        let wait = job.enqueued.elapsed();
+        let start = Instant::now();
+        // The tenant must be released even if its job panicked, or none of its jobs would run again
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| install(job.run))).is_err();
+        let busy = start.elapsed();
+
+        state = shared.state.lock().expect("Poisoned lock on scheduler");
+        let tenant = &mut state.tenants[index];
+        tenant.running = false;
+        tenant.metrics.completed += 1;
+        tenant.metrics.panicked += u64::from(panicked);
+        tenant.metrics.queued = tenant.queue.len();
+        tenant.metrics.io_cost += job.cost;
+        tenant.metrics.busy += busy;
+        tenant.metrics.wait += wait;
+        // the next job of this tenant can run now
+        shared.available.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::{ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::engine::StateTries;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+    use crate::mpts::deoxys::roots::root_registry;
+
+    fn state(quantum: u64, costs: &[&[u64]]) -> State {
+        let tenants = costs
+            .iter()
+            .enumerate()
+            .map(|(i, costs)| Tenant {
+                id: TenantId(i.to_string()),
+                queue: costs
+                    .iter()
+                    .map(|cost| Job { cost: *cost, enqueued: Instant::now(), run: Box::new(|| {}) })
+                    .collect(),
+                running: false,
+                deficit: 0,
+                metrics: TenantMetrics::default(),
+            })
+            .collect();
+        State { tenants, next: 0, quantum, closed: false }
+    }
+
+    fn schedule(state: &mut State) -> Vec<usize> {
+        let mut order = Vec::new();
+        while let Some((index, _)) = state.pick() {
+            state.tenants[index].running = false;
+            order.push(index);
+        }
+        order
+    }
+
+    #[test]
+    fn test_fair_share() {
+        // Tenant 0 commits large blocks, tenant 1 small ones: tenant 1 runs 4 blocks for each of tenant 0
+        let mut state = state(10, &[&[40, 40], &[10, 10, 10, 10, 10, 10, 10, 10]]);
+
+        assert_eq!(schedule(&mut state), vec![1, 1, 1, 0, 1, 1, 1, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_one_job_per_tenant() {
+        let mut state = state(10, &[&[1, 1], &[1]]);
+
+        assert_eq!(state.pick().map(|(index, _)| index), Some(0));
+        assert_eq!(state.pick().map(|(index, _)| index), Some(1));
+        // tenant 0 still has a job queued, but its previous job is running
+        assert!(state.pick().is_none());
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = Scheduler::new(2, 10);
+        let tenant = TenantId("appchain".to_string());
+        scheduler.add_tenant(tenant.clone()).unwrap();
+
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        for block_number in 0..5 {
+            let committed = Arc::clone(&committed);
+            scheduler.submit(&tenant, 1, move || committed.lock().unwrap().push(block_number)).unwrap();
+        }
+
+        assert_eq!(scheduler.add_tenant(tenant.clone()), Err(SchedulerError::DuplicateTenant(tenant.clone())));
+        scheduler.shutdown();
+
+        assert_eq!(*committed.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_panicking_job() {
+        let scheduler = Scheduler::new(1, 10);
+        let tenant = TenantId("appchain".to_string());
+        scheduler.add_tenant(tenant.clone()).unwrap();
+
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        scheduler.submit(&tenant, 1, || panic!("corrupted block")).unwrap();
+        let block = Arc::clone(&committed);
+        scheduler.submit(&tenant, 1, move || block.lock().unwrap().push(1)).unwrap();
+        let shared = Arc::clone(&scheduler.shared);
+        scheduler.shutdown();
+
+        // The next job of the tenant still ran
+        assert_eq!(*committed.lock().unwrap(), vec![1]);
+        let state = shared.state.lock().unwrap();
+        assert_eq!((state.tenants[0].metrics.completed, state.tenants[0].metrics.panicked), (2, 1));
+    }
+    #[test]
+    fn test_tenants_commit_the_same_block() {
+        let scheduler = Scheduler::new(2, 10);
+        let tenants = ["appchain-a", "appchain-b"].map(|id| {
+            let tenant = TenantId(id.to_string());
+            scheduler.add_tenant(tenant.clone()).unwrap();
+            (tenant, StateTries::open(Arc::new(MemoryBackend::new())).unwrap())
+        });
+
+        // Each chain commits a different diff as the same block
+        let config = ChainConfig::default();
+        for (value, (tenant, tries)) in (1_u64..).zip(&tenants) {
+            let csd = CommitmentStateDiff {
+                address_to_class_hash: Default::default(),
+                address_to_nonce: Default::default(),
+                storage_updates: [(
+                    ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64))),
+                    [(StorageKey(PatriciaKey(StarkFelt::ONE)), StarkFelt::from(value))].into(),
+                )]
+                .into_iter()
+                .collect(),
+                class_hash_to_compiled_class_hash: Default::default(),
+            };
+            let (tries, config) = (tries.clone(), config.clone());
+            scheduler
+                .submit_commit(tenant, csd, move |csd| {
+                    try_update_state_root(&tries, csd, 1, &config).unwrap();
+                })
+                .unwrap();
+        }
+        scheduler.shutdown();
+
+        // and keeps its own record of it
+        let roots = tenants
+            .iter()
+            .map(|(_, tries)| {
+                let state_root = root_registry(tries).get(1).expect("The block was committed").state_root;
+                assert_eq!(tries.engine().unwrap().state_root(&config).unwrap(), state_root);
+                state_root
+            })
+            .collect::<Vec<_>>();
+        assert_ne!(roots[0], roots[1]);
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+#[cfg(feature = "class-verification")]
+use super::class_verification::VerificationMode;
+use super::compression::{Compression, TrieCompression};
+use super::config::{ChainConfig, HashFunction, NodeHash, TrieHashers, ZeroWriteSemantics};
+use super::keys::KeySettings;
+use super::quarantine::FailureMode;
+use super::retry::RetryPolicy;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {path:?}: {error}")]
+    Io { path: PathBuf, error: std::io::Error },
+    #[error("unsupported configuration file extension {0:?}, expected toml, yaml or yml")]
+    UnsupportedFormat(Option<String>),
+    #[error("invalid TOML configuration: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid YAML configuration: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("invalid configuration: `{field}` {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+/// Where the commitment data is stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    /// Directory of the RocksDB database holding the tries, opened by
+    /// [init](super::runtime::init). Empty keeps them in the node's database.
+    pub path: PathBuf,
+    /// Directory of the [class store](super::class_store::ClassStore), if classes are stored.
+    pub class_store: Option<PathBuf>,
+}
+
+/// How long trie history is kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSettings {
+    /// Number of recent blocks the tries can be reverted to, `None` keeps the whole history.
+    pub blocks: Option<u64>,
+    /// Only prune blocks older than the latest block accepted on L1 by this many blocks, `None`
+    /// prunes regardless of finality. See [PrunePolicy](super::pruning::PrunePolicy).
+    pub finality_margin: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParallelismSettings {
+    /// Number of threads used to update the tries, `None` uses one per core.
+    pub threads: Option<usize>,
+    /// Disables parallelism and randomized behaviors, so that two runs over the same input behave
+    /// identically. This overrides `threads` and is meant for debugging only.
+    pub deterministic: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// Number of most active contracts whose trie paths are
+    /// [warmed up](super::warmup::warmup_configured) on startup.
+    pub warmup_contracts: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HashSetting {
+    #[cfg(feature = "pedersen")]
+    Pedersen,
+    Poseidon,
+}
+
+impl From<HashSetting> for HashFunction {
+    fn from(hash: HashSetting) -> Self {
+        match hash {
+            #[cfg(feature = "pedersen")]
+            HashSetting::Pedersen => HashFunction::Pedersen,
+            HashSetting::Poseidon => HashFunction::Poseidon,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NodeHashSetting {
+    Pedersen,
+    Poseidon,
+}
+
+#[cfg(feature = "class-verification")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClassVerificationSetting {
+    Lenient,
+    Strict,
+}
+
+#[cfg(feature = "class-verification")]
+impl From<ClassVerificationSetting> for VerificationMode {
+    fn from(mode: ClassVerificationSetting) -> Self {
+        match mode {
+            ClassVerificationSetting::Lenient => VerificationMode::Lenient,
+            ClassVerificationSetting::Strict => VerificationMode::Strict,
+        }
+    }
+}
+
+impl From<NodeHashSetting> for NodeHash {
+    fn from(hash: NodeHashSetting) -> Self {
+        match hash {
+            NodeHashSetting::Pedersen => NodeHash::Pedersen,
+            NodeHashSetting::Poseidon => NodeHash::Poseidon,
+        }
+    }
+}
+
+/// The node hashes default to Starknet's, which the node's database requires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HashersSettings {
+    contract_leaf: HashSetting,
+    class_leaf: HashSetting,
+    state_root: HashSetting,
+    #[serde(default)]
+    storage_node: Option<NodeHashSetting>,
+    #[serde(default)]
+    contracts_node: Option<NodeHashSetting>,
+    #[serde(default)]
+    classes_node: Option<NodeHashSetting>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CompressionSetting {
+    None,
+    Lz4,
+    Zstd { level: i32 },
+}
+
+impl From<CompressionSetting> for Compression {
+    fn from(compression: CompressionSetting) -> Self {
+        match compression {
+            CompressionSetting::None => Compression::None,
+            CompressionSetting::Lz4 => Compression::Lz4,
+            CompressionSetting::Zstd { level } => Compression::Zstd(level),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionSettings {
+    contract_storage: CompressionSetting,
+    contracts: CompressionSetting,
+    classes: CompressionSetting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetrySettings {
+    max_attempts: u32,
+    base_delay_ms: u64,
+    max_delay_ms: u64,
+    jitter: bool,
+}
+
+/// Chain-specific commitment rules, see [ChainConfig]. Omitted fields keep their Starknet default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainSettings {
+    /// Whether writing zero to a storage slot deletes it (`true`, as on Starknet) or is ignored.
+    delete_zero_writes: Option<bool>,
+    hashers: Option<HashersSettings>,
+    compression: Option<CompressionSettings>,
+    node_checksums: Option<bool>,
+    /// Whether contracts which fail to update are quarantined instead of failing the commit.
+    quarantine_failures: Option<bool>,
+    retry: Option<RetrySettings>,
+    canary_sample: Option<usize>,
+    commit_sla_ms: Option<u64>,
+    /// How the declared classes are checked against the class store, unchecked if omitted.
+    #[cfg(feature = "class-verification")]
+    class_verification: Option<ClassVerificationSetting>,
+}
+
+/// Limits of the read-only query endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuerySettings {
+    /// Number of blocks listed by the explorer.
+    pub recent_blocks: usize,
+}
+
+impl Default for QuerySettings {
+    fn default() -> Self {
+        Self { recent_blocks: 32 }
+    }
+}
+
+/// Optional features, all off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureSettings {
+    pub index_storage_writes: bool,
+    pub index_contract_activity: bool,
+}
+
+/// Configuration of an embedded commitment engine, as loaded from a configuration file.
+///
+/// Every section and field is optional. A TOML configuration looks like:
+///
+/// ```toml
+/// [storage]
+/// path = "/var/lib/starkroot"
+///
+/// [parallelism]
+/// threads = 8
+///
+/// [chain]
+/// canary_sample = 16
+/// compression.contract_storage = { zstd = { level = 3 } }
+/// compression.contracts = "lz4"
+/// compression.classes = "none"
+///
+/// [features]
+/// index_contract_activity = true
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommitmentConfig {
+    pub storage: StorageSettings,
+    pub retention: RetentionSettings,
+    pub parallelism: ParallelismSettings,
+    pub caches: CacheSettings,
+    pub queries: QuerySettings,
+    pub chain: ChainSettings,
+    pub features: FeatureSettings,
+    /// Where the secret keys are loaded from, by name, see [keys](super::keys).
+    pub keys: BTreeMap<String, KeySettings>,
+}
+
+impl CommitmentConfig {
+    /// Loads and validates a configuration file, whose format is told by its extension.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|error| ConfigError::Io { path: path.to_owned(), error })?;
+
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let config: Self = match extension {
+            Some("toml") => toml::from_str(&content)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)?,
+            _ => return Err(ConfigError::UnsupportedFormat(extension.map(str::to_string))),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the values which parse but make no sense.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| Err(ConfigError::Invalid { field, reason: reason.to_string() });
+
+        if self.parallelism.threads == Some(0) {
+            return invalid("parallelism.threads", "must be at least 1");
+        }
+        if self.retention.blocks == Some(0) {
+            return invalid("retention.blocks", "must be at least 1, the latest state is always kept");
+        }
+        if let Some(retry) = self.chain.retry {
+            if retry.max_attempts == 0 {
+                return invalid("chain.retry.max_attempts", "must be at least 1");
+            }
+            if retry.base_delay_ms > retry.max_delay_ms {
+                return invalid("chain.retry.base_delay_ms", "must not exceed `chain.retry.max_delay_ms`");
+            }
+        }
+        if let Some(compression) = self.chain.compression {
+            for setting in [compression.contract_storage, compression.contracts, compression.classes] {
+                if let CompressionSetting::Zstd { level } = setting {
+                    if !(1..=22).contains(&level) {
+                        return invalid("chain.compression", "zstd level must be between 1 and 22");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the settings which differ between `self` and `other` and cannot change while running,
+    /// because they change the layout of the stored data or the computed roots.
+    pub fn structural_changes(&self, other: &Self) -> Vec<&'static str> {
+        let (chain, other_chain) = (&self.chain, &other.chain);
+        [
+            ("storage", self.storage != other.storage),
+            ("chain.delete_zero_writes", chain.delete_zero_writes != other_chain.delete_zero_writes),
+            ("chain.hashers", chain.hashers != other_chain.hashers),
+            ("chain.compression", chain.compression != other_chain.compression),
+            ("chain.node_checksums", chain.node_checksums != other_chain.node_checksums),
+            ("chain.quarantine_failures", chain.quarantine_failures != other_chain.quarantine_failures),
+            ("features", self.features != other.features),
+        ]
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+    }
+
+    /// The commitment rules described by this configuration.
+    pub fn chain_config(&self) -> ChainConfig {
+        let chain = &self.chain;
+        let default = ChainConfig::default();
+
+        let retry = chain.retry.map_or(default.retry, |retry| RetryPolicy {
+            max_attempts: retry.max_attempts,
+            base_delay: Duration::from_millis(retry.base_delay_ms),
+            max_delay: Duration::from_millis(retry.max_delay_ms),
+            jitter: retry.jitter,
+        });
+
+        ChainConfig {
+            zero_writes: match chain.delete_zero_writes {
+                Some(false) => ZeroWriteSemantics::Ignore,
+                Some(true) => ZeroWriteSemantics::Delete,
+                None => default.zero_writes,
+            },
+            hashers: chain.hashers.map_or(default.hashers, |hashers| TrieHashers {
+                contract_leaf: hashers.contract_leaf.into(),
+                class_leaf: hashers.class_leaf.into(),
+                state_root: hashers.state_root.into(),
+                storage_node: hashers.storage_node.map_or(default.hashers.storage_node, Into::into),
+                contracts_node: hashers.contracts_node.map_or(default.hashers.contracts_node, Into::into),
+                classes_node: hashers.classes_node.map_or(default.hashers.classes_node, Into::into),
+            }),
+            compression: chain.compression.map_or(default.compression, |compression| TrieCompression {
+                contract_storage: compression.contract_storage.into(),
+                contracts: compression.contracts.into(),
+                classes: compression.classes.into(),
+            }),
+            node_checksums: chain.node_checksums.unwrap_or(default.node_checksums),
+            failure_mode: match chain.quarantine_failures {
+                Some(true) => FailureMode::Quarantine,
+                Some(false) => FailureMode::Strict,
+                None => default.failure_mode,
+            },
+            // randomized delays would make two deterministic runs diverge
+            retry: RetryPolicy { jitter: retry.jitter && !self.parallelism.deterministic, ..retry },
+            canary_sample: chain.canary_sample.unwrap_or(default.canary_sample),
+            commit_sla: chain.commit_sla_ms.map(Duration::from_millis).or(default.commit_sla),
+            #[cfg(feature = "class-verification")]
+            class_verification: chain.class_verification.map(Into::into).or(default.class_verification),
+            index_storage_writes: self.features.index_storage_writes,
+            index_contract_activity: self.features.index_contract_activity,
+            ..default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toml() {
+        let config: CommitmentConfig = toml::from_str(
+            r#"
+            [storage]
+            path = "/var/lib/starkroot"
+
+            [chain]
+            canary_sample = 16
+            commit_sla_ms = 500
+            compression.contract_storage = { zstd = { level = 3 } }
+            compression.contracts = "lz4"
+            compression.classes = "none"
+
+            [features]
+            index_contract_activity = true
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let chain_config = config.chain_config();
+        assert_eq!(config.storage.path, PathBuf::from("/var/lib/starkroot"));
+        assert_eq!(chain_config.canary_sample, 16);
+        assert_eq!(chain_config.commit_sla, Some(Duration::from_millis(500)));
+        assert_eq!(chain_config.compression.contract_storage, Compression::Zstd(3));
+        assert_eq!(chain_config.compression.contracts, Compression::Lz4);
+        assert!(chain_config.index_contract_activity);
+        assert_eq!(chain_config.hashers, TrieHashers::default());
+    }
+
+    #[test]
+    fn test_yaml_defaults() {
+        let config: CommitmentConfig = serde_yaml::from_str("parallelism:\n  threads: 4\n").unwrap();
+
+        assert_eq!(config.parallelism.threads, Some(4));
+        assert_eq!(config.chain_config(), ChainConfig::default());
+
+        let config: CommitmentConfig = serde_yaml::from_str("parallelism:\n  deterministic: true\n").unwrap();
+        assert!(!config.chain_config().retry.jitter);
+    }
+
+    #[test]
+    fn test_validation() {
+        let config: CommitmentConfig = toml::from_str("[parallelism]\nthreads = 0").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid { field: "parallelism.threads", .. })));
+
+        let config: CommitmentConfig = toml::from_str(
+            r#"
+            [chain.compression]
+            contract_storage = { zstd = { level = 40 } }
+            contracts = "lz4"
+            classes = "lz4"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid { field: "chain.compression", .. })));
+
+        assert!(toml::from_str::<CommitmentConfig>("[chain]\nunknown = 1").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+
+use super::backend::Backend;
+use super::config::ChainConfig;
+use super::engine::CommitmentEngine;
+use super::error::{CommitError, TrieError};
+use super::handles::WriteHandle;
+use super::lib::clone_commitment_state_diff;
+
+/// A storage engine being migrated to, written to alongside the primary tries.
+///
+/// The shadow backend receives the exact same canonical commits as the primary and returns the state
+/// root it computed, which is compared with the primary's.
+pub trait ShadowBackend {
+    type Error: std::error::Error;
+
+    fn commit(
+        &mut self,
+        csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, Self::Error>;
+
+    /// Drops `block_number`, which was rejected after being committed or failing to commit: the
+    /// shadow goes back to its state right after the previous block.
+    fn rollback(&mut self, block_number: u64) -> Result<(), Self::Error>;
+}
+
+/// How disagreements of the shadow backend are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShadowMode {
+    /// Shadow failures and mismatches are only recorded: the primary is the source of truth and
+    /// commits never fail because of the shadow.
+    #[default]
+    Observe,
+    /// Shadow failures and mismatches fail the commit: the block is rolled back from both backends,
+    /// so that it can be retried.
+    Enforce,
+}
+
+/// Outcome of the shadow write of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShadowOutcome {
+    Match,
+    Mismatch { primary: Felt252Wrapper, shadow: Felt252Wrapper },
+    Failed { error: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShadowError {
+    #[error(transparent)]
+    Primary(#[from] CommitError),
+    #[error("shadow backend diverged at block {block_number}: primary root {primary:?}, shadow root {shadow:?}")]
+    Mismatch { block_number: u64, primary: Felt252Wrapper, shadow: Felt252Wrapper },
+    #[error("shadow backend failed to commit block {block_number}: {error}")]
+    Failed { block_number: u64, error: String },
+    #[error("failed to roll back block {block_number} from both backends ({error}) after: {divergence}")]
+    Rollback { block_number: u64, divergence: Box<ShadowError>, error: String },
+}
+
+/// Tally of the shadow writes since dual-writes started.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShadowReport {
+    pub matched: u64,
+    pub mismatched: u64,
+    pub failed: u64,
+    /// Number of consecutive blocks up to the latest one for which both backends agreed.
+    pub streak: u64,
+    /// The first block the backends disagreed on, and how.
+    pub first_divergence: Option<(u64, ShadowOutcome)>,
+}
+
+impl ShadowReport {
+    fn record(&mut self, block_number: u64, outcome: &ShadowOutcome) {
+        match outcome {
+            ShadowOutcome::Match => {
+                self.matched += 1;
+                self.streak += 1;
+                return;
+            }
+            ShadowOutcome::Mismatch { .. } => self.mismatched += 1,
+            ShadowOutcome::Failed { .. } => self.failed += 1,
+        }
+        self.streak = 0;
+        if self.first_divergence.is_none() {
+            self.first_divergence = Some((block_number, outcome.clone()));
+        }
+    }
+
+    /// Whether the shadow backend agreed with the primary on at least the last `blocks` blocks, ie:
+    /// it can be promoted to primary.
+    pub fn ready_for_cutover(&self, blocks: u64) -> bool {
+        self.streak >= blocks
+    }
+}
+
+/// Writes every commit to the primary tries, through their write handle, and to a shadow backend, and
+/// compares their roots.
+///
+/// This enables zero-downtime migrations to a new storage engine: the shadow is filled and checked
+/// against live traffic until enough blocks agree, then the node is cut over to it.
+pub struct DualWrite<B> {
+    primary: WriteHandle,
+    shadow: B,
+    mode: ShadowMode,
+    report: ShadowReport,
+}
+
+impl<B: ShadowBackend> DualWrite<B> {
+    pub fn new(primary: WriteHandle, shadow: B, mode: ShadowMode) -> Self {
+        Self { primary, shadow, mode, report: ShadowReport::default() }
+    }
+
+    /// Commits a block to both backends.
+    ///
+    /// # Arguments
+    ///
+    /// * `csd`          - The commitment state diff of the block.
+    /// * `block_number` - The block number.
+    /// * `config`       - Chain-specific commitment rules.
+    ///
+    /// # Returns
+    ///
+    /// The state root of the primary.
+    pub fn commit(
+        &mut self,
+        csd: CommitmentStateDiff,
+        block_number: u64,
+        config: &ChainConfig,
+    ) -> Result<Felt252Wrapper, ShadowError> {
+        let shadow_csd = clone_commitment_state_diff(&csd);
+        let primary = self.primary.commit(csd, block_number, config)?;
+
+        let outcome = match self.shadow.commit(shadow_csd, block_number, config) {
+            Ok(shadow) if shadow == primary => ShadowOutcome::Match,
+            Ok(shadow) => ShadowOutcome::Mismatch { primary, shadow },
+            Err(e) => ShadowOutcome::Failed { error: e.to_string() },
+        };
+        self.report.record(block_number, &outcome);
+
+        let divergence = match (self.mode, outcome) {
+            (ShadowMode::Observe, _) | (_, ShadowOutcome::Match) => return Ok(primary),
+            (ShadowMode::Enforce, ShadowOutcome::Mismatch { primary, shadow }) => {
+                ShadowError::Mismatch { block_number, primary, shadow }
+            }
+            (ShadowMode::Enforce, ShadowOutcome::Failed { error }) => ShadowError::Failed { block_number, error },
+        };
+        match self.rollback(block_number) {
+            Ok(()) => Err(divergence),
+            Err(error) => Err(ShadowError::Rollback { block_number, divergence: Box::new(divergence), error }),
+        }
+    }
+
+    /// Rolls `block_number` back from the primary tries and from the shadow backend.
+    fn rollback(&mut self, block_number: u64) -> Result<(), String> {
+        // There is no committed state to go back to before genesis
+        let previous_block = block_number.checked_sub(1).ok_or("there is no block before genesis")?;
+        self.primary.revert_to(previous_block).map_err(|e| e.to_string())?;
+        self.shadow.rollback(block_number).map_err(|e| e.to_string())
+    }
+
+    pub fn report(&self) -> &ShadowReport {
+        &self.report
+    }
+
+    /// Stops dual-writes, returning the shadow backend, ie: after cutover.
+    pub fn into_shadow(self) -> B {
+        self.shadow
+    }
+}
+
+impl DualWrite<CommitmentEngine> {
+    /// Dual-writes to the tries stored in `backend`, ie: a RocksDB database being migrated to, their
+    /// nodes hashed and stored as `config` sets.
+    pub fn over_backend(
+        primary: WriteHandle,
+        backend: Backend,
+        config: &ChainConfig,
+        mode: ShadowMode,
+    ) -> Result<Self, TrieError> {
+        Ok(Self::new(primary, CommitmentEngine::with_config(backend, config)?, mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use starknet_api::core::{ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::engine::StateTries;
+    use crate::mpts::deoxys::roots::root_registry;
+
+    /// An engine whose root is wrong the first time `block_number` is committed.
+    struct Diverging {
+        engine: CommitmentEngine,
+        block_number: u64,
+        diverged: bool,
+    }
+
+    impl ShadowBackend for Diverging {
+        type Error = TrieError;
+
+        fn commit(
+            &mut self,
+            csd: CommitmentStateDiff,
+            block_number: u64,
+            config: &ChainConfig,
+        ) -> Result<Felt252Wrapper, Self::Error> {
+            let state_root = self.engine.update_state_root(csd, block_number, config)?;
+            if block_number == self.block_number && !self.diverged {
+                self.diverged = true;
+                return Ok(Felt252Wrapper::ONE);
+            }
+            Ok(state_root)
+        }
+
+        fn rollback(&mut self, block_number: u64) -> Result<(), Self::Error> {
+            self.engine.rollback(block_number)
+        }
+    }
+
+    fn csd(block_number: u64) -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: Default::default(),
+            storage_updates: [(
+                ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64))),
+                [(StorageKey(PatriciaKey(StarkFelt::from(block_number))), StarkFelt::from(block_number))].into(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_shadow_report() {
+        let mut report = ShadowReport::default();
+
+        report.record(1, &ShadowOutcome::Match);
+        let mismatch = ShadowOutcome::Mismatch { primary: Felt252Wrapper::ONE, shadow: Felt252Wrapper::TWO };
+        report.record(2, &mismatch);
+        report.record(3, &ShadowOutcome::Failed { error: "io".to_string() });
+        report.record(4, &ShadowOutcome::Match);
+        report.record(5, &ShadowOutcome::Match);
+
+        assert_eq!(report.matched, 3);
+        assert_eq!(report.mismatched, 1);
+        assert_eq!(report.failed, 1);
+        assert_eq!(report.first_divergence, Some((2, mismatch)));
+        assert!(report.ready_for_cutover(2));
+        assert!(!report.ready_for_cutover(3));
+    }
+
+    #[test]
+    fn test_dual_write_over_backend() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        let writer = WriteHandle::acquire(tries.clone()).unwrap();
+        let mut dual_write =
+            DualWrite::over_backend(writer, Arc::new(MemoryBackend::new()), &config, ShadowMode::Enforce).unwrap();
+        for block_number in 1..=3 {
+            dual_write.commit(csd(block_number), block_number, &config).unwrap();
+        }
+        assert_eq!(dual_write.report().matched, 3);
+        assert!(dual_write.report().ready_for_cutover(3));
+
+        let shadow = dual_write.into_shadow();
+        assert_eq!(shadow.latest(), Some(3));
+        assert_eq!(shadow.state_root(&config).unwrap(), tries.engine().unwrap().state_root(&config).unwrap());
+    }
+
+    #[test]
+    fn test_enforce_rolls_back_divergence() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        let engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let shadow = Diverging { engine, block_number: 3, diverged: false };
+        let mut dual_write = DualWrite::new(WriteHandle::acquire(tries.clone()).unwrap(), shadow, ShadowMode::Enforce);
+        let mut roots = Vec::new();
+        for block_number in 1..=2 {
+            roots.push(dual_write.commit(csd(block_number), block_number, &config).unwrap());
+        }
+
+        // Block 3 is rolled back from both backends
+        assert!(matches!(
+            dual_write.commit(csd(3), 3, &config),
+            Err(ShadowError::Mismatch { block_number: 3, shadow, .. }) if shadow == Felt252Wrapper::ONE
+        ));
+        assert!(root_registry(&tries).get(3).is_none());
+        assert_eq!(tries.engine().unwrap().state_root(&config).unwrap(), roots[1]);
+        assert_eq!(dual_write.shadow.engine.latest(), Some(2));
+        assert_eq!(dual_write.shadow.engine.state_root(&config).unwrap(), roots[1]);
+
+        // and can be retried
+        let state_root = dual_write.commit(csd(3), 3, &config).unwrap();
+        assert_eq!(dual_write.shadow.engine.state_root(&config).unwrap(), state_root);
+        assert_eq!(dual_write.report().mismatched, 1);
+        assert_eq!(dual_write.report().streak, 1);
+    }
+
+    #[test]
+    fn test_observe_keeps_divergence() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        let engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let writer = WriteHandle::acquire(tries.clone()).unwrap();
+        let mut dual_write =
+            DualWrite::new(writer, Diverging { engine, block_number: 1, diverged: false }, ShadowMode::Observe);
+        let state_root = dual_write.commit(csd(1), 1, &config).unwrap();
+
+        assert_eq!(root_registry(&tries).get(1).map(|block| block.state_root), Some(state_root));
+        assert!(matches!(dual_write.report().first_divergence, Some((1, ShadowOutcome::Mismatch { .. }))));
+    }
+}
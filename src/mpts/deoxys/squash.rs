@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::MutexGuard;
+
+use starknet_api::core::ContractAddress;
+
+use super::engine::StateTries;
+
+/// Tracks contracts whose entire storage has been zeroed out.
+///
+/// Once every slot of a contract is set to zero its storage trie collapses to the empty root and
+/// its nodes are removed from the latest trie. Older trie versions still reference those nodes
+/// until they fall out of the retention window, this is what the "empty since" marker is used for:
+/// once the versions of the tries before it are [pruned](super::handles::WriteHandle::prune), what
+/// is left of the storage trie is
+/// [reclaimed](super::engine::CommitmentEngine::reclaim_empty_storage) and the contract is no
+/// longer tracked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EmptyStorageTracker {
+    pub(crate) empty_since: HashMap<ContractAddress, u64>,
+}
+
+impl EmptyStorageTracker {
+    /// Records the state of a contract's storage trie after `block_number` was committed.
+    ///
+    /// # Arguments
+    ///
+    /// * `contract_address` - The contract whose storage trie was updated.
+    /// * `is_empty`         - Whether the storage trie root is the empty root.
+    /// * `block_number`     - The block which was just committed.
+    pub fn record(&mut self, contract_address: ContractAddress, is_empty: bool, block_number: u64) {
+        if is_empty {
+            self.empty_since.entry(contract_address).or_insert(block_number);
+        } else {
+            self.empty_since.remove(&contract_address);
+        }
+    }
+
+    /// Returns the block at which the contract's storage became empty, if it currently is.
+    pub fn empty_since(&self, contract_address: &ContractAddress) -> Option<u64> {
+        self.empty_since.get(contract_address).copied()
+    }
+
+    /// Returns the contracts whose storage has been empty for more than `retention` blocks as of
+    /// `block_number`. No historical state references the nodes of their storage tries anymore.
+    pub fn reclaimable(&self, block_number: u64, retention: u64) -> impl Iterator<Item = &ContractAddress> {
+        self.empty_since
+            .iter()
+            .filter(move |(_, &since)| block_number.saturating_sub(since) > retention)
+            .map(|(contract_address, _)| contract_address)
+    }
+
+    /// Forgets the contracts whose storage became empty after `block_number`, once the tries were
+    /// reverted to it.
+    ///
+    /// Contracts whose storage was empty at `block_number` but was written to since are not tracked
+    /// again: they are picked up on their next storage update.
+    pub fn truncate_after(&mut self, block_number: u64) {
+        self.empty_since.retain(|_, since| *since <= block_number);
+    }
+
+    /// Stops tracking a contract, once its storage trie history has been reclaimed.
+    pub fn forget(&mut self, contract_address: &ContractAddress) {
+        self.empty_since.remove(contract_address);
+    }
+}
+
+/// Returns the [EmptyStorageTracker] of `tries`, updated on each contract trie commit.
+pub fn empty_storage_tracker(tries: &StateTries) -> MutexGuard<'_, EmptyStorageTracker> {
+    tries.state().empty_storage.lock().expect("Poisoned lock on empty storage tracker")
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+
+    #[test]
+    fn test_empty_since() {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let mut tracker = EmptyStorageTracker::default();
+
+        tracker.record(contract_address, true, 10);
+        tracker.record(contract_address, true, 12);
+        assert_eq!(tracker.empty_since(&contract_address), Some(10));
+        assert_eq!(tracker.reclaimable(15, 5).count(), 0);
+        assert_eq!(tracker.reclaimable(16, 5).collect::<Vec<_>>(), vec![&contract_address]);
+
+        // storage is written to again
+        tracker.record(contract_address, false, 20);
+        assert_eq!(tracker.empty_since(&contract_address), None);
+
+        // storage is emptied again, then the block is reverted
+        tracker.record(contract_address, true, 22);
+        tracker.truncate_after(21);
+        assert_eq!(tracker.empty_since(&contract_address), None);
+    }
+}
@@ -0,0 +1,478 @@
+//! Warm standby support: handing the commitment state over to another process.
+//!
+//! The tries live in the shared database, but the in-memory indexes built while committing (the
+//! [root registry](super::roots::root_registry), the storage and activity indexes, the empty
+//! storage tracker, the contract sizes and the quarantine) would otherwise be lost when the primary
+//! dies, and the standby would start with a cold registry. The primary periodically
+//! [freezes](freeze) to export them, or does so on shutdown, and the standby [thaws](thaw) the
+//! latest export before taking over.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use mp_felt::Felt252Wrapper;
+use serde_json::{json, Value};
+use starknet_api::core::{ClassHash, ContractAddress, Nonce, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+
+use super::engine::StateTries;
+use super::error::TrieError;
+use super::handles::WriteHandle;
+use super::historical::state_root_at;
+use super::history::{contract_activity, storage_history, ContractActivity, Coverage, StorageHistory};
+use super::quarantine::{quarantine, Quarantine, QuarantinedContract, SkippedUpdates};
+use super::roots::{root_registry, CommittedBlock, FencingToken, Finality, RootRegistry};
+use super::squash::{empty_storage_tracker, EmptyStorageTracker};
+use super::stats::{contract_sizes, CommitStats, ContractSizes, LeafCounts};
+use super::warmup::{self, WarmupReport};
+
+/// Version of the warm state format.
+pub const WARM_STATE_VERSION: u64 = 1;
+
+/// Whether the commits to `tries` are [frozen](freeze), in which case they fail with
+/// [CommitError::Frozen](super::error::CommitError::Frozen).
+pub fn is_frozen(tries: &StateTries) -> bool {
+    tries.state().frozen.load(Ordering::Relaxed)
+}
+
+/// The in-memory state of the commitment logic, as exported by [freeze].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WarmState {
+    pub registry: RootRegistry,
+    pub storage_history: StorageHistory,
+    pub contract_activity: ContractActivity,
+    pub empty_storage: EmptyStorageTracker,
+    pub contract_sizes: ContractSizes,
+    pub quarantine: Quarantine,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StandbyError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("unsupported warm state version {0}")]
+    UnsupportedVersion(u64),
+    #[error("invalid warm state field `{0}`")]
+    Invalid(&'static str),
+    #[error("the tries are not at the state exported for block {block_number}, the standby cannot take over from it")]
+    Diverged { block_number: u64 },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+/// Stops committing blocks to the tries of `writer` and exports their in-memory state.
+///
+/// Once this returns, no commit is in flight and every later commit fails until [thaw] is called,
+/// so that the export matches the tries exactly.
+pub fn freeze(writer: &mut WriteHandle) -> WarmState {
+    let tries = writer.tries();
+    // Commits check the flag while holding the registry lock
+    let registry = root_registry(tries);
+    tries.state().frozen.store(true, Ordering::Relaxed);
+
+    WarmState {
+        registry: registry.clone(),
+        storage_history: storage_history(tries).clone(),
+        contract_activity: contract_activity(tries).clone(),
+        empty_storage: empty_storage_tracker(tries).clone(),
+        contract_sizes: contract_sizes(tries).clone(),
+        quarantine: quarantine(tries).clone(),
+    }
+}
+
+/// Restores an exported state into the tries of `writer` and resumes committing blocks, from the
+/// block after the latest block of `state`.
+///
+/// The state is checked against the in-memory state and the tries first: neither may hold a block
+/// after the latest block of the export, and its state root must be the one the tries hold for that
+/// block, ie: the primary did not commit anything after the export. The upper trie nodes of the
+/// `warmup_contracts` most active contracts of the export are then
+/// [preloaded](warmup::warmup_contracts) into the node's database.
+///
+/// The primary can call this with its own export to resume after a freeze.
+pub fn thaw(writer: &mut WriteHandle, state: WarmState, warmup_contracts: usize) -> Result<WarmupReport, StandbyError> {
+    let tries = writer.tries();
+    let mut registry = root_registry(tries);
+    let exported = state.registry.latest();
+    if let Some((live_number, live)) = registry.latest() {
+        let diverged = match exported {
+            Some((block_number, block)) => {
+                live_number > block_number || (live_number == block_number && live.state_root != block.state_root)
+            }
+            None => true,
+        };
+        if diverged {
+            return Err(StandbyError::Diverged { block_number: exported.map_or(0, |(block_number, _)| block_number) });
+        }
+    }
+    if let Some((block_number, block)) = exported {
+        let committed_root = |block_number: u64| match tries.engine() {
+            Some(engine) => engine.committed_block(block_number).map(|block| block.map(|(_, state_root)| state_root)),
+            None => state_root_at(block_number),
+        };
+        if committed_root(block_number)? != Some(block.state_root) || committed_root(block_number + 1)?.is_some() {
+            return Err(StandbyError::Diverged { block_number });
+        }
+    }
+
+    let hot_contracts = state.contract_activity.most_active(warmup_contracts);
+    *registry = state.registry;
+    *storage_history(tries) = state.storage_history;
+    *contract_activity(tries) = state.contract_activity;
+    *empty_storage_tracker(tries) = state.empty_storage;
+    *contract_sizes(tries) = state.contract_sizes;
+    *quarantine(tries) = state.quarantine;
+    tries.state().frozen.store(false, Ordering::Relaxed);
+    drop(registry);
+
+    if !tries.is_node_db() {
+        return Ok(WarmupReport::default());
+    }
+    Ok(warmup::warmup_contracts(&hot_contracts)?)
+}
+
+fn felt_json(felt: &StarkFelt) -> Value {
+    json!(format!("{:#x}", Felt::from_bytes_be(&felt.0)))
+}
+
+fn wrapper_json(felt: Felt252Wrapper) -> Value {
+    json!(format!("{:#x}", Felt::from(felt)))
+}
+
+fn parse_felt(value: &Value, field: &'static str) -> Result<StarkFelt, StandbyError> {
+    let felt = value.as_str().and_then(|value| FieldElement::from_hex_be(value).ok());
+    felt.map(|felt| StarkFelt(felt.to_bytes_be())).ok_or(StandbyError::Invalid(field))
+}
+
+fn parse_wrapper(value: &Value, field: &'static str) -> Result<Felt252Wrapper, StandbyError> {
+    Ok(Felt252Wrapper::from(Felt::from_bytes_be(&parse_felt(value, field)?.0)))
+}
+
+fn parse_u64(value: &Value, field: &'static str) -> Result<u64, StandbyError> {
+    value.as_u64().ok_or(StandbyError::Invalid(field))
+}
+
+fn parse_array<'a>(value: &'a Value, field: &'static str) -> Result<&'a Vec<Value>, StandbyError> {
+    value.as_array().ok_or(StandbyError::Invalid(field))
+}
+
+fn parse_address(value: &Value, field: &'static str) -> Result<ContractAddress, StandbyError> {
+    Ok(ContractAddress(PatriciaKey(parse_felt(value, field)?)))
+}
+
+fn finality_name(finality: Finality) -> &'static str {
+    match finality {
+        Finality::Pending => "pending",
+        Finality::AcceptedOnL2 => "accepted_on_l2",
+        Finality::Proven => "proven",
+        Finality::AcceptedOnL1 => "accepted_on_l1",
+    }
+}
+
+fn parse_finality(value: &Value) -> Result<Finality, StandbyError> {
+    [Finality::Pending, Finality::AcceptedOnL2, Finality::Proven, Finality::AcceptedOnL1]
+        .into_iter()
+        .find(|finality| Some(finality_name(*finality)) == value.as_str())
+        .ok_or(StandbyError::Invalid("finality"))
+}
+
+fn coverage_json(coverage: &Coverage) -> Value {
+    json!([coverage.from, coverage.horizon, coverage.to])
+}
+
+fn parse_coverage(value: &Value, field: &'static str) -> Result<Coverage, StandbyError> {
+    // Older exports do not tell which blocks their indexes cover, which then cover none
+    if value.is_null() {
+        return Ok(Coverage::default());
+    }
+    let block = |value: &Value| match value {
+        Value::Null => Ok(None),
+        block => parse_u64(block, field).map(Some),
+    };
+    Ok(Coverage { from: block(&value[0])?, horizon: parse_u64(&value[1], field)?, to: block(&value[2])? })
+}
+
+fn counts_json(counts: &LeafCounts) -> Value {
+    json!([counts.new, counts.updated, counts.deleted])
+}
+
+fn parse_counts(value: &Value) -> Result<LeafCounts, StandbyError> {
+    let counts = parse_array(value, "stats")?;
+    let count = |index: usize| counts.get(index).and_then(Value::as_u64).ok_or(StandbyError::Invalid("stats"));
+    Ok(LeafCounts { new: count(0)?, updated: count(1)?, deleted: count(2)? })
+}
+
+impl WarmState {
+    /// Serializes the state as JSON, felts being `0x`-prefixed hex strings.
+    pub fn to_json(&self) -> Value {
+        let blocks = self.registry.blocks.iter().map(|(block_number, block)| {
+            json!({
+                "block_number": block_number,
+                "diff_hash": wrapper_json(block.diff_hash),
+                "state_root": wrapper_json(block.state_root),
+                "stats": {
+                    "storage": counts_json(&block.stats.storage),
+                    "contracts": counts_json(&block.stats.contracts),
+                    "classes": counts_json(&block.stats.classes),
+                    "ignored_zero_writes": block.stats.ignored_zero_writes,
+                    "new_nodes": block.stats.new_nodes,
+                },
+                "finality": finality_name(block.finality),
+                "dirty": block.dirty,
+            })
+        });
+        let storage_history = self.storage_history.changes.iter().map(|((contract_address, key), changes)| {
+            let changes = changes.iter().map(|(block, value)| json!([block, felt_json(value)])).collect::<Vec<_>>();
+            json!([felt_json(contract_address.0.key()), felt_json(key.0.key()), changes])
+        });
+        let contract_activity = self
+            .contract_activity
+            .blocks
+            .iter()
+            .map(|(contract_address, blocks)| json!([felt_json(contract_address.0.key()), blocks]));
+        let empty_storage = self
+            .empty_storage
+            .empty_since
+            .iter()
+            .map(|(contract_address, since)| json!([felt_json(contract_address.0.key()), since]));
+        let contract_sizes = self
+            .contract_sizes
+            .leaves
+            .iter()
+            .map(|(contract_address, leaves)| json!([felt_json(contract_address.0.key()), leaves]));
+        let quarantine = self.quarantine.iter().map(|(contract_address, contract)| {
+            let skipped = contract.skipped.iter().map(|(block, updates)| {
+                let storage =
+                    updates.storage.iter().map(|(key, value)| json!([felt_json(key.0.key()), felt_json(value)]));
+                json!([
+                    block,
+                    updates.class_hash.map(|class_hash| felt_json(&class_hash.0)),
+                    updates.nonce.map(|nonce| felt_json(&nonce.0)),
+                    storage.collect::<Vec<_>>(),
+                ])
+            });
+            json!([felt_json(contract_address.0.key()), contract.since, contract.reason, skipped.collect::<Vec<_>>()])
+        });
+
+        json!({
+            "version": WARM_STATE_VERSION,
+            "registry": {
+                "blocks": blocks.collect::<Vec<_>>(),
+                "fencing_token": self.registry.fencing_token.map(|token| token.0),
+                "version": self.registry.version,
+            },
+            "storage_history": storage_history.collect::<Vec<_>>(),
+            "storage_history_coverage": coverage_json(&self.storage_history.coverage),
+            "contract_activity": contract_activity.collect::<Vec<_>>(),
+            "contract_activity_coverage": coverage_json(&self.contract_activity.coverage),
+            "empty_storage": empty_storage.collect::<Vec<_>>(),
+            "contract_sizes": contract_sizes.collect::<Vec<_>>(),
+            "quarantine": quarantine.collect::<Vec<_>>(),
+        })
+    }
+
+    /// Deserializes a state serialized with [WarmState::to_json].
+    pub fn from_json(value: &Value) -> Result<Self, StandbyError> {
+        let version = parse_u64(&value["version"], "version")?;
+        if version != WARM_STATE_VERSION {
+            return Err(StandbyError::UnsupportedVersion(version));
+        }
+
+        let registry = &value["registry"];
+        let mut blocks = BTreeMap::new();
+        for block in parse_array(&registry["blocks"], "blocks")? {
+            let stats = &block["stats"];
+            let committed = CommittedBlock {
+                diff_hash: parse_wrapper(&block["diff_hash"], "diff_hash")?,
+                state_root: parse_wrapper(&block["state_root"], "state_root")?,
+                stats: CommitStats {
+                    storage: parse_counts(&stats["storage"])?,
+                    contracts: parse_counts(&stats["contracts"])?,
+                    classes: parse_counts(&stats["classes"])?,
+                    // Older exports predate these counts
+                    ignored_zero_writes: stats["ignored_zero_writes"].as_u64().unwrap_or_default(),
+                    new_nodes: stats["new_nodes"].as_u64(),
+                },
+                finality: parse_finality(&block["finality"])?,
+                // Older exports have no dirty flag, nor quarantine
+                dirty: block["dirty"].as_bool().unwrap_or(false),
+            };
+            blocks.insert(parse_u64(&block["block_number"], "block_number")?, committed);
+        }
+        let fencing_token = match &registry["fencing_token"] {
+            Value::Null => None,
+            token => Some(FencingToken(parse_u64(token, "fencing_token")?)),
+        };
+        let registry =
+            RootRegistry { blocks, fencing_token, version: parse_u64(&registry["version"], "registry.version")? };
+
+        let mut changes = HashMap::new();
+        for entry in parse_array(&value["storage_history"], "storage_history")? {
+            let contract_address = parse_address(&entry[0], "storage_history")?;
+            let key = StorageKey(PatriciaKey(parse_felt(&entry[1], "storage_history")?));
+            let writes = parse_array(&entry[2], "storage_history")?
+                .iter()
+                .map(|write| Ok((parse_u64(&write[0], "storage_history")?, parse_felt(&write[1], "storage_history")?)))
+                .collect::<Result<_, StandbyError>>()?;
+            changes.insert((contract_address, key), writes);
+        }
+
+        let mut activity = HashMap::new();
+        for entry in parse_array(&value["contract_activity"], "contract_activity")? {
+            let blocks = parse_array(&entry[1], "contract_activity")?
+                .iter()
+                .map(|block| parse_u64(block, "contract_activity"))
+                .collect::<Result<_, _>>()?;
+            activity.insert(parse_address(&entry[0], "contract_activity")?, blocks);
+        }
+
+        let mut empty_since = HashMap::new();
+        for entry in parse_array(&value["empty_storage"], "empty_storage")? {
+            empty_since.insert(parse_address(&entry[0], "empty_storage")?, parse_u64(&entry[1], "empty_storage")?);
+        }
+
+        let mut leaves = HashMap::new();
+        for entry in parse_array(&value["contract_sizes"], "contract_sizes")? {
+            leaves.insert(parse_address(&entry[0], "contract_sizes")?, parse_u64(&entry[1], "contract_sizes")?);
+        }
+
+        let mut quarantine = Quarantine::default();
+        for entry in value["quarantine"].as_array().into_iter().flatten() {
+            let optional_felt = |value: &Value| match value {
+                Value::Null => Ok(None),
+                value => parse_felt(value, "quarantine").map(Some),
+            };
+            let mut skipped = BTreeMap::new();
+            for updates in parse_array(&entry[3], "quarantine")? {
+                let block_number = parse_u64(&updates[0], "quarantine")?;
+                let storage = parse_array(&updates[3], "quarantine")?
+                    .iter()
+                    .map(|write| {
+                        let key = StorageKey(PatriciaKey(parse_felt(&write[0], "quarantine")?));
+                        Ok((key, parse_felt(&write[1], "quarantine")?))
+                    })
+                    .collect::<Result<_, StandbyError>>()?;
+                let updates = SkippedUpdates {
+                    class_hash: optional_felt(&updates[1])?.map(ClassHash),
+                    nonce: optional_felt(&updates[2])?.map(Nonce),
+                    storage,
+                };
+                skipped.insert(block_number, updates);
+            }
+            let contract = QuarantinedContract {
+                since: parse_u64(&entry[1], "quarantine")?,
+                reason: entry[2].as_str().ok_or(StandbyError::Invalid("quarantine"))?.to_string(),
+                skipped,
+            };
+            quarantine.contracts.insert(parse_address(&entry[0], "quarantine")?, contract);
+        }
+
+        Ok(Self {
+            registry,
+            storage_history: StorageHistory {
+                changes,
+                coverage: parse_coverage(&value["storage_history_coverage"], "storage_history_coverage")?,
+            },
+            contract_activity: ContractActivity {
+                blocks: activity,
+                coverage: parse_coverage(&value["contract_activity_coverage"], "contract_activity_coverage")?,
+            },
+            empty_storage: EmptyStorageTracker { empty_since },
+            contract_sizes: ContractSizes { leaves },
+            quarantine,
+        })
+    }
+
+    /// Writes the state to a JSON file.
+    ///
+    /// The file is written next to `path` first then renamed over it, so that a standby never reads a
+    /// partial export.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), StandbyError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.to_json())?)?;
+        Ok(fs::rename(tmp, path)?)
+    }
+
+    /// Reads a state from a JSON file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, StandbyError> {
+        Self::from_json(&serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+
+    #[test]
+    fn test_warm_state_json() {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let key = StorageKey(PatriciaKey(StarkFelt::TWO));
+        let mut state = WarmState::default();
+        let stats = CommitStats { storage: LeafCounts { new: 1, updated: 2, deleted: 3 }, ..Default::default() };
+        let (diff_hash, state_root) = (Felt252Wrapper::from(Felt::THREE), Felt252Wrapper::from(Felt::TWO));
+        state.registry.record(7, diff_hash, state_root, stats);
+        state.registry.set_finality(7, Finality::Proven);
+        state.registry.fence(FencingToken(2)).unwrap();
+        state.storage_history.changes.insert((contract_address, key), vec![(5, StarkFelt::THREE), (7, StarkFelt::ONE)]);
+        state.storage_history.coverage = Coverage { from: Some(5), horizon: 6, to: Some(7) };
+        state.contract_activity.blocks.insert(contract_address, vec![5, 7]);
+        state.contract_activity.coverage = Coverage { from: Some(4), horizon: 0, to: Some(7) };
+        state.empty_storage.empty_since.insert(contract_address, 6);
+        state.contract_sizes.leaves.insert(contract_address, 12);
+        state.registry.mark_dirty(7);
+        state.quarantine.add(contract_address, 6, "corrupted node");
+        let updates = SkippedUpdates {
+            class_hash: None,
+            nonce: Some(Nonce(StarkFelt::TWO)),
+            storage: [(key, StarkFelt::THREE)].into_iter().collect(),
+        };
+        state.quarantine.contracts.get_mut(&contract_address).unwrap().skipped.insert(7, updates);
+
+        assert_eq!(WarmState::from_json(&state.to_json()).unwrap(), state);
+
+        // Exports without dirty roots nor quarantine
+        let mut value = state.to_json();
+        value["registry"]["blocks"][0].as_object_mut().unwrap().remove("dirty");
+        value.as_object_mut().unwrap().remove("quarantine");
+        value.as_object_mut().unwrap().remove("storage_history_coverage");
+        value.as_object_mut().unwrap().remove("contract_activity_coverage");
+        let older = WarmState::from_json(&value).unwrap();
+        assert!(!older.registry.get(7).unwrap().dirty);
+        assert_eq!(older.quarantine, Quarantine::default());
+        assert_eq!(older.storage_history.coverage, Coverage::default());
+        assert_eq!(older.contract_activity.coverage, Coverage::default());
+
+        let mut value = state.to_json();
+        value["version"] = json!(WARM_STATE_VERSION + 1);
+        assert!(matches!(WarmState::from_json(&value), Err(StandbyError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn test_thaw_after_commit() {
+        let mut writer = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+        let (diff_hash, state_root) = (Felt252Wrapper::from(Felt::THREE), Felt252Wrapper::from(Felt::TWO));
+        root_registry(writer.tries()).record(8, diff_hash, state_root, CommitStats::default());
+
+        // The primary committed block 8 after exporting block 7
+        let mut state = WarmState::default();
+        state.registry.record(7, diff_hash, state_root, CommitStats::default());
+        assert!(matches!(thaw(&mut writer, state.clone(), 0), Err(StandbyError::Diverged { block_number: 7 })));
+
+        // Or committed another block 8
+        state.registry.record(8, diff_hash, Felt252Wrapper::ONE, CommitStats::default());
+        assert!(matches!(thaw(&mut writer, state, 0), Err(StandbyError::Diverged { block_number: 8 })));
+        assert!(matches!(thaw(&mut writer, WarmState::default(), 0), Err(StandbyError::Diverged { block_number: 0 })));
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+use starknet_api::core::ClassHash;
+use starknet_api::hash::StarkFelt;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Poseidon, StarkHash};
+
+use super::consts::ProtocolConstants;
+
+/// Calculate the state diff commitment of a block, as of Starknet v0.13.2.
+///
+/// Blocks declaring Cairo 0 classes must use [calculate_state_diff_commitment_with_deprecated], as
+/// the commitment state diff does not hold them.
+///
+/// # Arguments
+///
+/// * `csd` - The commitment state diff of the block.
+///
+/// # Returns
+///
+/// The state diff commitment as `Felt252Wrapper`.
+pub fn calculate_state_diff_commitment(csd: &CommitmentStateDiff) -> Felt252Wrapper {
+    calculate_state_diff_commitment_with_deprecated(csd, &[])
+}
+
+/// Calculate the state diff commitment of a block declaring Cairo 0 classes.
+///
+/// The state diff is hashed with Poseidon as a single array, each section being prefixed with its
+/// length and sorted by key:
+///
+/// ```text
+/// "STARKNET_STATE_DIFF0",
+/// deployed_contracts_and_replaced_classes_len, (address, class_hash)*,
+/// declared_classes_len, (class_hash, compiled_class_hash)*,
+/// deprecated_declared_classes_len, class_hash*,
+/// 1, 0,
+/// storage_diffs_len, (address, updates_len, (key, value)*)*,
+/// nonces_len, (address, nonce)*
+/// ```
+///
+/// Contracts without storage updates are left out of the storage diffs.
+///
+/// # Arguments
+///
+/// * `csd`                         - The commitment state diff of the block.
+/// * `deprecated_declared_classes` - The Cairo 0 classes declared in the block.
+///
+/// # Returns
+///
+/// The state diff commitment as `Felt252Wrapper`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(contracts = csd.storage_updates.len(), deployed = csd.address_to_class_hash.len())
+    )
+)]
+pub fn calculate_state_diff_commitment_with_deprecated(
+    csd: &CommitmentStateDiff,
+    deprecated_declared_classes: &[ClassHash],
+) -> Felt252Wrapper {
+    calculate_state_diff_commitment_with_constants(csd, deprecated_declared_classes, &ProtocolConstants::default())
+}
+
+/// [calculate_state_diff_commitment_with_deprecated] with the
+/// [state diff prefix](ProtocolConstants::state_diff_prefix) of a chain.
+pub fn calculate_state_diff_commitment_with_constants(
+    csd: &CommitmentStateDiff,
+    deprecated_declared_classes: &[ClassHash],
+    constants: &ProtocolConstants,
+) -> Felt252Wrapper {
+    let felt = |value: &StarkFelt| Felt::from_bytes_be(&value.0);
+    let len = |len: usize| Felt::from(len as u64);
+    let mut elements = vec![Felt::from_bytes_be(&constants.state_diff_prefix.to_bytes_be())];
+
+    // Deployed contracts and replaced classes are merged in the commitment state diff, as they are in
+    // the commitment
+    let class_hashes = csd
+        .address_to_class_hash
+        .iter()
+        .map(|(address, class_hash)| (*address.0.key(), class_hash.0))
+        .collect::<BTreeMap<_, _>>();
+    elements.push(len(class_hashes.len()));
+    for (address, class_hash) in class_hashes {
+        elements.extend([felt(&address), felt(&class_hash)]);
+    }
+
+    let declared_classes = csd
+        .class_hash_to_compiled_class_hash
+        .iter()
+        .map(|(class_hash, compiled_class_hash)| (class_hash.0, compiled_class_hash.0))
+        .collect::<BTreeMap<_, _>>();
+    elements.push(len(declared_classes.len()));
+    for (class_hash, compiled_class_hash) in declared_classes {
+        elements.extend([felt(&class_hash), felt(&compiled_class_hash)]);
+    }
+
+    let mut deprecated_declared_classes =
+        deprecated_declared_classes.iter().map(|class_hash| class_hash.0).collect::<Vec<_>>();
+    deprecated_declared_classes.sort();
+    deprecated_declared_classes.dedup();
+    elements.push(len(deprecated_declared_classes.len()));
+    elements.extend(deprecated_declared_classes.iter().map(felt));
+
+    // Data availability modes, only L1 is supported for now
+    elements.extend([Felt::ONE, Felt::ZERO]);
+
+    let storage_diffs = csd
+        .storage_updates
+        .iter()
+        .filter(|(_, updates)| !updates.is_empty())
+        .map(|(address, updates)| {
+            let updates = updates.iter().map(|(key, value)| (*key.0.key(), *value)).collect::<BTreeMap<_, _>>();
+            (*address.0.key(), updates)
+        })
+        .collect::<BTreeMap<_, _>>();
+    elements.push(len(storage_diffs.len()));
+    for (address, updates) in storage_diffs {
+        elements.extend([felt(&address), len(updates.len())]);
+        for (key, value) in updates {
+            elements.extend([felt(&key), felt(&value)]);
+        }
+    }
+
+    let nonces =
+        csd.address_to_nonce.iter().map(|(address, nonce)| (*address.0.key(), nonce.0)).collect::<BTreeMap<_, _>>();
+    elements.push(len(nonces.len()));
+    for (address, nonce) in nonces {
+        elements.extend([felt(&address), felt(&nonce)]);
+    }
+
+    Felt252Wrapper::from(Poseidon::hash_array(&elements))
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use starknet_api::core::{ContractAddress, Nonce, PatriciaKey};
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+
+    fn address(n: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn key(n: u64) -> StorageKey {
+        StorageKey(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    #[test]
+    fn test_state_diff_commitment_is_order_independent() {
+        let csd = |reversed: bool| {
+            let mut storage = vec![(key(1), StarkFelt::ONE), (key(2), StarkFelt::TWO)];
+            let mut nonces = vec![(address(1), Nonce(StarkFelt::ONE)), (address(2), Nonce(StarkFelt::TWO))];
+            if reversed {
+                storage.reverse();
+                nonces.reverse();
+            }
+            CommitmentStateDiff {
+                address_to_class_hash: IndexMap::new(),
+                address_to_nonce: nonces.into_iter().collect(),
+                storage_updates: [(address(1), storage.into_iter().collect()), (address(2), IndexMap::new())]
+                    .into_iter()
+                    .collect(),
+                class_hash_to_compiled_class_hash: IndexMap::new(),
+            }
+        };
+
+        let commitment = calculate_state_diff_commitment(&csd(false));
+        assert_eq!(commitment, calculate_state_diff_commitment(&csd(true)));
+        let deprecated = [ClassHash(StarkFelt::ONE)];
+        assert_ne!(commitment, calculate_state_diff_commitment_with_deprecated(&csd(false), &deprecated));
+    }
+}
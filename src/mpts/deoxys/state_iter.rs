@@ -0,0 +1,316 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use mc_db::storage_handler::{self, DeoxysStorageError, StorageView};
+use rayon::prelude::*;
+use starknet_api::core::{ClassHash, ContractAddress, Nonce};
+use starknet_types_core::felt::Felt;
+
+use super::atomic::Trie;
+use super::backend::BackendError;
+use super::engine::{CommitmentEngine, StateTries};
+use super::error::{ErrorContext, ResultExt, TrieError};
+
+/// Default number of contracts read per chunk by [iter_contracts].
+pub const DEFAULT_CHUNK_SIZE: usize = 1024;
+
+/// A leaf of the contracts trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContractEntry {
+    pub contract_address: ContractAddress,
+    pub class_hash: ClassHash,
+    pub nonce: Nonce,
+    pub storage_root: Felt,
+}
+
+/// Stops a [ContractIter], possibly from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Requests the iteration to stop, the contracts already read are still yielded.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Iterator over the contracts of the state at a block, in ascending address order. See
+/// [iter_contracts].
+#[derive(Debug)]
+pub struct ContractIter {
+    tries: StateTries,
+    block_number: u64,
+    chunk_size: usize,
+    /// The last contract read from the trie, the next chunk starts after it.
+    read_up_to: Option<ContractAddress>,
+    /// The last contract yielded.
+    cursor: Option<ContractAddress>,
+    chunk: VecDeque<Result<ContractEntry, TrieError>>,
+    /// Read-only tries of the engine of `tries` at `block_number`, opened along with the first
+    /// chunk. `None` if the contracts are read from the database.
+    view: Option<CommitmentEngine>,
+    opened: bool,
+    cancel: CancelHandle,
+    done: bool,
+}
+
+impl ContractIter {
+    /// Returns a handle cancelling the iteration.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Returns the last contract yielded, from which an interrupted iteration can be resumed with
+    /// [iter_contracts_from].
+    pub fn cursor(&self) -> Option<ContractAddress> {
+        self.cursor
+    }
+
+    fn next_chunk(&mut self) -> Result<(), TrieError> {
+        let block_number = self.block_number;
+        let context = || ErrorContext::block(block_number).trie(Trie::Contracts);
+        if !self.opened {
+            self.opened = true;
+            // The engine is only locked while the snapshot is opened
+            self.view = match self.tries.engine() {
+                Some(engine) => Some(
+                    engine
+                        .view_at(block_number)
+                        .context(context)?
+                        .ok_or(BackendError::NoSnapshot(block_number))
+                        .context(context)?,
+                ),
+                None => None,
+            };
+        }
+
+        let contract_addresses = match &self.view {
+            Some(view) => view
+                .contract_addresses()
+                .context(context)?
+                .into_iter()
+                .filter(|contract_address| self.read_up_to.map_or(true, |read_up_to| *contract_address > read_up_to))
+                .take(self.chunk_size)
+                .collect(),
+            None => storage_handler::contract_trie()
+                .keys_at(block_number, self.read_up_to.as_ref(), self.chunk_size)
+                .context(context)?,
+        };
+        if contract_addresses.len() < self.chunk_size {
+            self.done = true;
+        }
+        self.read_up_to = contract_addresses.last().copied().or(self.read_up_to);
+
+        if let Some(view) = &self.view {
+            let entries = contract_addresses.iter().map(|contract_address| {
+                view_entry(view, contract_address)
+                    .context(|| ErrorContext::block(block_number).contract(*contract_address))
+            });
+            self.chunk.extend(entries);
+            return Ok(());
+        }
+        let entries = self.tries.execution().install(|| {
+            contract_addresses
+                .par_iter()
+                .map(|contract_address| {
+                    contract_entry(contract_address, block_number)
+                        .context(|| ErrorContext::block(block_number).contract(*contract_address))
+                })
+                .collect::<Vec<_>>()
+        });
+        self.chunk.extend(entries);
+        Ok(())
+    }
+}
+
+impl Iterator for ContractIter {
+    type Item = Result<ContractEntry, TrieError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.chunk.is_empty() && !self.done {
+            if self.cancel.is_cancelled() {
+                self.done = true;
+                return None;
+            }
+            if let Err(e) = self.next_chunk() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        let entry = self.chunk.pop_front()?;
+        if let Ok(entry) = &entry {
+            self.cursor = Some(entry.contract_address);
+        }
+        Some(entry)
+    }
+}
+
+fn view_entry(view: &CommitmentEngine, contract_address: &ContractAddress) -> Result<ContractEntry, TrieError> {
+    Ok(ContractEntry {
+        contract_address: *contract_address,
+        class_hash: view.class_hash(contract_address)?,
+        nonce: view.nonce(contract_address)?,
+        storage_root: view.storage_root(contract_address)?,
+    })
+}
+
+fn contract_entry(contract_address: &ContractAddress, block_number: u64) -> Result<ContractEntry, DeoxysStorageError> {
+    let class_hash = storage_handler::contract_class_hash().get_at(contract_address, block_number)?.unwrap_or_default();
+    let nonce = storage_handler::contract_nonces().get_at(contract_address, block_number)?.unwrap_or_default();
+    let storage_root = storage_handler::contract_storage_trie().root_at(contract_address, block_number)?;
+
+    Ok(ContractEntry { contract_address: *contract_address, class_hash, nonce, storage_root })
+}
+
+/// Iterates over every contract of the state right after `block_number`, yielding its class hash,
+/// nonce and storage root.
+///
+/// Contracts are read from the versioned contracts trie `chunk_size` at a time, each chunk being
+/// read in parallel, so that full-state exports and analytics never hold the whole state in memory.
+/// The trie handlers are only held while a chunk is read, blocks can be committed meanwhile without
+/// affecting the iteration. `block_number` must not be pruned while the iteration runs.
+///
+/// The tries of an [engine](super::engine::CommitmentEngine) are read from its snapshot of
+/// `block_number` instead, which is opened along with the first chunk.
+///
+/// The iteration stops after the first error, or once [cancelled](ContractIter::cancel_handle).
+pub fn iter_contracts(tries: &StateTries, block_number: u64, chunk_size: usize) -> ContractIter {
+    iter_contracts_from(tries, block_number, chunk_size, None)
+}
+
+/// Iterates over the contracts of the state right after `block_number` whose address is greater
+/// than `after`, ie: to resume from the [cursor](ContractIter::cursor) of a cancelled iteration.
+///
+/// See [iter_contracts].
+pub fn iter_contracts_from(
+    tries: &StateTries,
+    block_number: u64,
+    chunk_size: usize,
+    after: Option<ContractAddress>,
+) -> ContractIter {
+    ContractIter {
+        tries: tries.clone(),
+        block_number,
+        chunk_size: chunk_size.max(1),
+        read_up_to: after,
+        cursor: after,
+        chunk: VecDeque::new(),
+        view: None,
+        opened: false,
+        cancel: CancelHandle::default(),
+        done: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use indexmap::IndexMap;
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+
+    fn address(value: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(value)))
+    }
+
+    /// Deploys contracts `0x30`, `0x10` and `0x20`, only the first one having storage.
+    fn deploy() -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: [
+                (address(0x30), ClassHash(StarkFelt::ONE)),
+                (address(0x10), ClassHash(StarkFelt::TWO)),
+            ]
+            .into_iter()
+            .collect(),
+            address_to_nonce: [(address(0x20), Nonce(StarkFelt::THREE))].into_iter().collect(),
+            storage_updates: [(
+                address(0x30),
+                [(StorageKey(PatriciaKey(StarkFelt::ONE)), StarkFelt::TWO)].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    /// Deploys contract `0x40` and bumps the nonce of `0x10`.
+    fn update() -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: [(address(0x40), ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: [(address(0x10), Nonce(StarkFelt::ONE))].into_iter().collect(),
+            storage_updates: IndexMap::new(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_iter_contracts() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        try_update_state_root(&tries, deploy(), 1, &config).unwrap();
+        try_update_state_root(&tries, update(), 2, &config).unwrap();
+
+        // Chunks smaller than the state are chained, in ascending address order
+        let entries = iter_contracts(&tries, 1, 2).collect::<Result<Vec<_>, _>>().unwrap();
+        let summary: Vec<_> =
+            entries.iter().map(|entry| (entry.contract_address, entry.class_hash, entry.nonce)).collect();
+        assert_eq!(
+            summary,
+            [
+                (address(0x10), ClassHash(StarkFelt::TWO), Nonce::default()),
+                (address(0x20), ClassHash::default(), Nonce(StarkFelt::THREE)),
+                (address(0x30), ClassHash(StarkFelt::ONE), Nonce::default()),
+            ]
+        );
+        let storage_roots: Vec<_> = entries.iter().map(|entry| entry.storage_root != Felt::ZERO).collect();
+        assert_eq!(storage_roots, [false, false, true]);
+
+        // The later block is iterated from its own snapshot
+        let entries = iter_contracts(&tries, 2, DEFAULT_CHUNK_SIZE).collect::<Result<Vec<_>, _>>().unwrap();
+        let summary: Vec<_> = entries.iter().map(|entry| (entry.contract_address, entry.nonce)).collect();
+        assert_eq!(
+            summary,
+            [
+                (address(0x10), Nonce(StarkFelt::ONE)),
+                (address(0x20), Nonce(StarkFelt::THREE)),
+                (address(0x30), Nonce::default()),
+                (address(0x40), Nonce::default()),
+            ]
+        );
+
+        let mut uncommitted = iter_contracts(&tries, 3, DEFAULT_CHUNK_SIZE);
+        assert!(matches!(uncommitted.next(), Some(Err(e)) if e.context().and_then(|c| c.block_number) == Some(3)));
+        assert!(uncommitted.next().is_none());
+    }
+
+    #[test]
+    fn test_resume_cancelled_iteration() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        try_update_state_root(&tries, deploy(), 1, &ChainConfig::default()).unwrap();
+
+        // The contracts of the chunk already read are still yielded once cancelled
+        let mut iter = iter_contracts(&tries, 1, 2);
+        assert_eq!(iter.next().unwrap().unwrap().contract_address, address(0x10));
+        iter.cancel_handle().cancel();
+        assert_eq!(iter.next().unwrap().unwrap().contract_address, address(0x20));
+        assert!(iter.next().is_none());
+        assert_eq!(iter.cursor(), Some(address(0x20)));
+
+        let rest = iter_contracts_from(&tries, 1, 2, iter.cursor()).collect::<Result<Vec<_>, _>>().unwrap();
+        let rest: Vec<_> = rest.iter().map(|entry| entry.contract_address).collect();
+        assert_eq!(rest, [address(0x30)]);
+    }
+}
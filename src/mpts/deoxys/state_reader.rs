@@ -0,0 +1,162 @@
+use mc_db::storage_handler::{self, DeoxysStorageError, StorageView};
+use starknet_api::core::{ClassHash, ContractAddress, Nonce};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+
+use super::engine::CommitmentEngine;
+use super::error::TrieError;
+
+/// Read access to the state as of a committed block.
+///
+/// This is the minimal surface executors need to run against the exact state which produced a
+/// committed state root. Values which were never written are returned as their default (zero), as
+/// the sequencer does.
+pub trait CommittedStateReader {
+    type Error;
+
+    /// The block whose post-state is being read.
+    fn block_number(&self) -> u64;
+
+    /// Returns the value of a contract's storage slot.
+    fn get_storage(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<StarkFelt, Self::Error>;
+
+    /// Returns the nonce of a contract.
+    fn get_nonce(&self, contract_address: &ContractAddress) -> Result<Nonce, Self::Error>;
+
+    /// Returns the class hash of a contract, zero if the contract is not deployed.
+    fn get_class_hash(&self, contract_address: &ContractAddress) -> Result<ClassHash, Self::Error>;
+}
+
+/// [CommittedStateReader] over the state committed to the global backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendStateReader {
+    block_number: u64,
+}
+
+impl BackendStateReader {
+    /// Creates a reader pinned to the state right after `block_number` was committed.
+    pub fn at(block_number: u64) -> Self {
+        Self { block_number }
+    }
+}
+
+impl CommittedStateReader for BackendStateReader {
+    type Error = DeoxysStorageError;
+
+    fn block_number(&self) -> u64 {
+        self.block_number
+    }
+
+    fn get_storage(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<StarkFelt, Self::Error> {
+        let value = storage_handler::contract_storage().get_at(&(*contract_address, *key), self.block_number)?;
+        Ok(value.unwrap_or_default())
+    }
+
+    fn get_nonce(&self, contract_address: &ContractAddress) -> Result<Nonce, Self::Error> {
+        Ok(storage_handler::contract_nonces().get_at(contract_address, self.block_number)?.unwrap_or_default())
+    }
+
+    fn get_class_hash(&self, contract_address: &ContractAddress) -> Result<ClassHash, Self::Error> {
+        let class_hash = storage_handler::contract_class_hash().get_at(contract_address, self.block_number)?;
+        Ok(class_hash.unwrap_or_default())
+    }
+}
+
+/// [CommittedStateReader] over the tries of a [CommitmentEngine], for nodes which only keep the tries.
+pub struct TrieStateReader {
+    view: CommitmentEngine,
+    block_number: u64,
+}
+
+impl TrieStateReader {
+    /// Creates a reader pinned to the state right after `block_number` was committed to `engine`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `block_number` was not committed, or its version was pruned.
+    pub fn at(engine: &CommitmentEngine, block_number: u64) -> Result<Option<Self>, TrieError> {
+        Ok(engine.view_at(block_number)?.map(|view| Self { view, block_number }))
+    }
+}
+
+impl CommittedStateReader for TrieStateReader {
+    type Error = TrieError;
+
+    fn block_number(&self) -> u64 {
+        self.block_number
+    }
+
+    fn get_storage(&self, contract_address: &ContractAddress, key: &StorageKey) -> Result<StarkFelt, Self::Error> {
+        self.view.storage_value(contract_address, key)
+    }
+
+    fn get_nonce(&self, contract_address: &ContractAddress) -> Result<Nonce, Self::Error> {
+        self.view.nonce(contract_address)
+    }
+
+    fn get_class_hash(&self, contract_address: &ContractAddress) -> Result<ClassHash, Self::Error> {
+        self.view.class_hash(contract_address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use indexmap::IndexMap;
+    use starknet_api::core::PatriciaKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+
+    fn address(n: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn key(n: u64) -> StorageKey {
+        StorageKey(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    #[test]
+    fn test_trie_state_reader() {
+        let config = ChainConfig::default();
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let deploy = CommitmentStateDiff {
+            address_to_class_hash: [(address(1), ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(address(1), [(key(1), StarkFelt::from(10_u64))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        let update = CommitmentStateDiff {
+            address_to_class_hash: IndexMap::new(),
+            address_to_nonce: [(address(1), Nonce(StarkFelt::ONE))].into_iter().collect(),
+            storage_updates: [(address(1), [(key(1), StarkFelt::from(11_u64))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        engine.update_state_root(deploy, 0, &config).unwrap();
+        engine.update_state_root(update, 1, &config).unwrap();
+
+        // Each reader sees the state right after its block, even once later blocks are committed
+        let before = TrieStateReader::at(&engine, 0).unwrap().unwrap();
+        let after = TrieStateReader::at(&engine, 1).unwrap().unwrap();
+        assert_eq!((before.block_number(), after.block_number()), (0, 1));
+        assert_eq!(before.get_storage(&address(1), &key(1)).unwrap(), StarkFelt::from(10_u64));
+        assert_eq!(after.get_storage(&address(1), &key(1)).unwrap(), StarkFelt::from(11_u64));
+        assert_eq!(before.get_nonce(&address(1)).unwrap(), Nonce::default());
+        assert_eq!(after.get_nonce(&address(1)).unwrap(), Nonce(StarkFelt::ONE));
+        assert_eq!(before.get_class_hash(&address(1)).unwrap(), ClassHash(StarkFelt::TWO));
+
+        // Unwritten values read as zero
+        assert_eq!(after.get_storage(&address(1), &key(2)).unwrap(), StarkFelt::ZERO);
+        assert_eq!(after.get_storage(&address(2), &key(1)).unwrap(), StarkFelt::ZERO);
+        assert_eq!(after.get_class_hash(&address(2)).unwrap(), ClassHash::default());
+
+        assert!(TrieStateReader::at(&engine, 2).unwrap().is_none());
+    }
+}
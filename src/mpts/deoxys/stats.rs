@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::MutexGuard;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mc_db::storage_handler::{self, StorageView};
+use rayon::prelude::*;
+use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+use starknet_api::state::StorageKey;
+use starknet_types_core::felt::Felt;
+
+use super::atomic::Trie;
+use super::config::{ChainConfig, StorageWrite};
+use super::engine::{CommitmentEngine, StateTries};
+use super::error::TrieError;
+use super::retry::Operation;
+
+/// Number of leaves of a trie touched by a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeafCounts {
+    /// Leaves which did not exist before the block.
+    pub new: u64,
+    /// Existing leaves whose value changed.
+    pub updated: u64,
+    /// Existing leaves which were removed, ie: storage slots set to zero.
+    pub deleted: u64,
+}
+
+impl LeafCounts {
+    /// Counts a leaf going from `previous` to `next`, `None` meaning the leaf is absent.
+    pub fn count<T: PartialEq>(&mut self, previous: Option<T>, next: Option<T>) {
+        match (previous, next) {
+            (None, Some(_)) => self.new += 1,
+            (Some(previous), Some(next)) if previous != next => self.updated += 1,
+            (Some(_), None) => self.deleted += 1,
+            _ => {}
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.new + self.updated + self.deleted
+    }
+
+    fn add(&mut self, other: &LeafCounts) {
+        self.new += other.new;
+        self.updated += other.updated;
+        self.deleted += other.deleted;
+    }
+}
+
+/// Per-block write statistics of the state tries, for appchain sequencers to feed into their DA and
+/// storage pricing models.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitStats {
+    pub storage: LeafCounts,
+    pub contracts: LeafCounts,
+    pub classes: LeafCounts,
+    /// Trie nodes created by the block across the tries, leaves excluded. Only known when the tries
+    /// are committed by an [engine](CommitmentEngine): the node's database does not expose the
+    /// nodes it writes.
+    pub new_nodes: Option<u64>,
+    /// Zero writes to slots holding a value which were left out of the storage tries, with
+    /// [ZeroWriteSemantics::Ignore](super::config::ZeroWriteSemantics::Ignore). The state root no
+    /// longer matches the one of a sequencer clearing storage slots, as Starknet's does.
+    pub ignored_zero_writes: u64,
+}
+
+/// Leaves and nodes written to the tries of a [CommitmentEngine] since they were last
+/// [taken](CommitmentEngine::take_writes), as counted by its [BonsaiBackend](super::backend::BonsaiBackend)s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TrieWrites {
+    pub(crate) storage: HashMap<ContractAddress, LeafCounts>,
+    pub(crate) contracts: LeafCounts,
+    pub(crate) classes: LeafCounts,
+    pub(crate) new_nodes: u64,
+}
+
+impl TrieWrites {
+    /// Counts a leaf of `trie` going from `previous` to `next`, given its key in the trie.
+    pub(crate) fn leaf(&mut self, trie: Trie, key: &[u8], previous: Option<&[u8]>, next: Option<&[u8]>) {
+        match trie {
+            Trie::ContractStorage => {
+                // The leaves of the storage tries are keyed by the address of their contract first
+                let Some(identifier) = key.get(..32) else {
+                    return;
+                };
+                let identifier = identifier.try_into().expect("32 bytes");
+                let contract_address = ContractAddress(PatriciaKey(StarkFelt(identifier)));
+                self.storage.entry(contract_address).or_default().count(previous, next)
+            }
+            Trie::Contracts => self.contracts.count(previous, next),
+            Trie::Classes => self.classes.count(previous, next),
+        }
+    }
+}
+
+fn felt(bytes: &[u8; 32]) -> Felt {
+    Felt::from_bytes_be(bytes)
+}
+
+/// Computes the write statistics of a block.
+///
+/// This must be called before the block is applied, since new and updated leaves are told apart by
+/// reading the current tries. The nodes the block creates are only known once it is written, see
+/// [CommitStats::new_nodes].
+///
+/// # Arguments
+///
+/// * `tries`  - The tries the block is to be applied to.
+/// * `csd`    - Commitment state diff for the current block.
+/// * `config` - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The write statistics of the block.
+pub fn commit_stats(
+    tries: &StateTries,
+    csd: &CommitmentStateDiff,
+    config: &ChainConfig,
+) -> Result<CommitStats, TrieError> {
+    commit_stats_by_contract(tries, csd, config).map(|(stats, _)| stats)
+}
+
+/// Computes the write statistics of a block, along with the storage leaf counts of each contract.
+///
+/// See [commit_stats].
+pub fn commit_stats_by_contract(
+    tries: &StateTries,
+    csd: &CommitmentStateDiff,
+    config: &ChainConfig,
+) -> Result<(CommitStats, HashMap<ContractAddress, LeafCounts>), TrieError> {
+    if let Some(engine) = tries.engine() {
+        let engine = &*engine;
+        return count_leaves(
+            csd,
+            config,
+            |contract_address, key| {
+                let value = engine.storage_value(contract_address, key)?;
+                Ok((value != StarkFelt::ZERO).then(|| felt(&value.0)))
+            },
+            |contract_address| Ok(engine.contract_leaf(contract_address)?.is_some()),
+            |class_hash| Ok(engine.class_leaf(class_hash)?.is_some()),
+        );
+    }
+
+    let handler_storage_trie = storage_handler::contract_storage_trie();
+    let handler_contract = storage_handler::contract_trie();
+    let handler_class = storage_handler::class_trie();
+    let slots = csd
+        .storage_updates
+        .iter()
+        .flat_map(|(contract_address, updates)| updates.keys().map(move |key| (*contract_address, *key)))
+        .collect();
+    let (handler_storage_trie, handler_contract, handler_class) =
+        (&handler_storage_trie, &handler_contract, &handler_class);
+    let storage = read_all(tries, slots, |(contract_address, key)| {
+        Ok(config.retry.run(Operation::Read, || handler_storage_trie.get(&contract_address, &key))?)
+    })?;
+    let contracts = read_all(tries, contract_addresses(csd).into_iter().copied().collect(), |contract_address| {
+        Ok(config.retry.run(Operation::Read, || handler_contract.get(&contract_address))?.is_some())
+    })?;
+    let classes = read_all(tries, csd.class_hash_to_compiled_class_hash.keys().copied().collect(), |class_hash| {
+        Ok(config.retry.run(Operation::Read, || handler_class.get(&class_hash))?.is_some())
+    })?;
+    count_leaves(
+        csd,
+        config,
+        |contract_address, key| Ok(storage[&(*contract_address, *key)]),
+        |contract_address| Ok(contracts[contract_address]),
+        |class_hash| Ok(classes[class_hash]),
+    )
+}
+
+/// Reads `keys` in parallel on the thread pool of `tries`: the tries are locked for the whole
+/// commit, which waits for the reads.
+fn read_all<K, V>(
+    tries: &StateTries,
+    keys: Vec<K>,
+    read: impl Fn(K) -> Result<V, TrieError> + Sync,
+) -> Result<HashMap<K, V>, TrieError>
+where
+    K: Copy + Eq + Hash + Send,
+    V: Send,
+{
+    tries.execution().install(|| keys.into_par_iter().map(|key| Ok((key, read(key)?))).collect())
+}
+
+/// Computes the write statistics of a block applied to the tries of `engine`, from the leaves and
+/// nodes its tries wrote since they were last [taken](CommitmentEngine::take_writes).
+///
+/// Only the storage slots left untouched by [ZeroWriteSemantics::Ignore](super::config::ZeroWriteSemantics::Ignore)
+/// are read, to count the ones holding a value.
+pub(crate) fn written_stats(
+    engine: &CommitmentEngine,
+    csd: &CommitmentStateDiff,
+    config: &ChainConfig,
+) -> Result<(CommitStats, HashMap<ContractAddress, LeafCounts>), TrieError> {
+    let writes = engine.take_writes();
+    let mut stats = CommitStats {
+        contracts: writes.contracts,
+        classes: writes.classes,
+        new_nodes: Some(writes.new_nodes),
+        ..Default::default()
+    };
+    for counts in writes.storage.values() {
+        stats.storage.add(counts);
+    }
+    for (contract_address, updates) in csd.storage_updates.iter() {
+        for (key, value) in updates {
+            if config.zero_writes.write(*value).is_none()
+                && engine.storage_value(contract_address, key)? != StarkFelt::ZERO
+            {
+                stats.ignored_zero_writes += 1;
+            }
+        }
+    }
+    Ok((stats, writes.storage))
+}
+
+fn contract_addresses(csd: &CommitmentStateDiff) -> HashSet<&ContractAddress> {
+    csd.storage_updates.keys().chain(csd.address_to_class_hash.keys()).chain(csd.address_to_nonce.keys()).collect()
+}
+
+/// Counts the leaves touched by a block, given how to read the current tries.
+fn count_leaves(
+    csd: &CommitmentStateDiff,
+    config: &ChainConfig,
+    storage: impl Fn(&ContractAddress, &StorageKey) -> Result<Option<Felt>, TrieError>,
+    contract_exists: impl Fn(&ContractAddress) -> Result<bool, TrieError>,
+    class_exists: impl Fn(&ClassHash) -> Result<bool, TrieError>,
+) -> Result<(CommitStats, HashMap<ContractAddress, LeafCounts>), TrieError> {
+    let mut stats = CommitStats::default();
+    let mut by_contract = HashMap::new();
+
+    for (contract_address, updates) in csd.storage_updates.iter() {
+        let counts: &mut LeafCounts = by_contract.entry(*contract_address).or_default();
+        for (key, value) in updates {
+            let previous = storage(contract_address, key)?;
+            let next = match config.zero_writes.write(*value) {
+                Some(StorageWrite::Set(value)) => Some(felt(&value.0)),
+                Some(StorageWrite::Delete) => None,
+                None => {
+                    stats.ignored_zero_writes += u64::from(previous.is_some());
+                    continue;
+                }
+            };
+            stats.storage.count(previous, next);
+            counts.count(previous, next);
+        }
+    }
+
+    // Contract leaves hash their storage root, so every touched contract leaf changes
+    for contract_address in contract_addresses(csd) {
+        if contract_exists(contract_address)? {
+            stats.contracts.updated += 1;
+        } else {
+            stats.contracts.new += 1;
+        }
+    }
+
+    for class_hash in csd.class_hash_to_compiled_class_hash.keys() {
+        if class_exists(class_hash)? {
+            stats.classes.updated += 1;
+        } else {
+            stats.classes.new += 1;
+        }
+    }
+
+    Ok((stats, by_contract))
+}
+
+/// Storage size of a contract, see [ContractSizes].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContractSize {
+    pub contract_address: ContractAddress,
+    /// Number of leaves of the contract's storage trie, ie: of non-zero storage slots.
+    pub leaves: u64,
+}
+
+impl ContractSize {
+    /// Upper bound of the number of nodes of the contract's storage trie, leaves included: a binary
+    /// trie with `n` leaves has `n - 1` binary nodes, and at most one edge above each other node.
+    pub fn max_nodes(&self) -> u64 {
+        (4 * self.leaves).saturating_sub(2)
+    }
+}
+
+/// Storage trie size of each contract, maintained from the [per-contract write
+/// statistics](commit_stats_by_contract) of each commit.
+///
+/// Sizes are accumulated since the index was created: contracts written to before only account for
+/// the slots created since, and [reverted](super::handles::WriteHandle::revert_to) blocks are not
+/// subtracted. [largest_contracts] only falls back to it when the tries are in the node's
+/// database, which does not expose the size of the storage tries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContractSizes {
+    pub(crate) leaves: HashMap<ContractAddress, u64>,
+}
+
+impl ContractSizes {
+    /// Records the storage leaves created and deleted by a committed block.
+    pub fn record(&mut self, counts: &HashMap<ContractAddress, LeafCounts>) {
+        for (contract_address, counts) in counts {
+            let leaves = self.leaves.entry(*contract_address).or_default();
+            *leaves = (*leaves + counts.new).saturating_sub(counts.deleted);
+        }
+    }
+
+    pub fn get(&self, contract_address: &ContractAddress) -> ContractSize {
+        let leaves = self.leaves.get(contract_address).copied().unwrap_or_default();
+        ContractSize { contract_address: *contract_address, leaves }
+    }
+
+    /// Returns the `n` contracts with the largest storage tries, largest first.
+    pub fn largest(&self, n: usize) -> Vec<ContractSize> {
+        let mut sizes = self
+            .leaves
+            .iter()
+            .map(|(contract_address, leaves)| ContractSize { contract_address: *contract_address, leaves: *leaves })
+            .collect::<Vec<_>>();
+        // Ties are broken by address so that the report is stable
+        sizes.sort_unstable_by(|a, b| b.leaves.cmp(&a.leaves).then(a.contract_address.cmp(&b.contract_address)));
+        sizes.truncate(n);
+        sizes
+    }
+}
+
+/// Returns the [ContractSizes] of `tries`, updated on each commit.
+pub fn contract_sizes(tries: &StateTries) -> MutexGuard<'_, ContractSizes> {
+    tries.state().contract_sizes.lock().expect("Poisoned lock on contract sizes")
+}
+
+/// Returns the `n` contracts with the largest storage tries, largest first.
+///
+/// With the tries of an [engine](CommitmentEngine), the sizes are counted from the storage tries
+/// themselves, which walks the whole state. Otherwise they are read from the [ContractSizes] index
+/// maintained by their commits.
+pub fn largest_contracts(tries: &StateTries, n: usize) -> Result<Vec<ContractSize>, TrieError> {
+    if let Some(engine) = tries.engine() {
+        let sizes = ContractSizes { leaves: engine.storage_leaves()?.into_iter().collect() };
+        return Ok(sizes.largest(n));
+    }
+    Ok(contract_sizes(tries).largest(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use indexmap::IndexMap;
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ZeroWriteSemantics;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+    use crate::mpts::deoxys::roots::root_registry;
+
+    #[test]
+    fn test_leaf_counts() {
+        let mut counts = LeafCounts::default();
+
+        counts.count(None, Some(Felt::ONE));
+        counts.count(Some(Felt::ONE), Some(Felt::TWO));
+        counts.count(Some(Felt::ONE), Some(Felt::ONE));
+        counts.count(Some(Felt::ONE), None);
+        counts.count(None, None);
+
+        assert_eq!(counts, LeafCounts { new: 1, updated: 1, deleted: 1 });
+        assert_eq!(counts.total(), 3);
+    }
+
+    #[test]
+    fn test_largest_contracts() {
+        let contract = |n: u64| ContractAddress(PatriciaKey(StarkFelt::from(n)));
+        let mut sizes = ContractSizes::default();
+
+        sizes.record(&HashMap::from([
+            (contract(1), LeafCounts { new: 3, updated: 0, deleted: 0 }),
+            (contract(2), LeafCounts { new: 5, updated: 1, deleted: 0 }),
+            (contract(3), LeafCounts { new: 3, updated: 0, deleted: 0 }),
+        ]));
+        sizes.record(&HashMap::from([(contract(2), LeafCounts { new: 0, updated: 0, deleted: 4 })]));
+
+        let largest = sizes.largest(2);
+        assert_eq!(
+            largest.iter().map(|size| (size.contract_address, size.leaves)).collect::<Vec<_>>(),
+            vec![(contract(1), 3), (contract(3), 3)]
+        );
+        assert_eq!(sizes.get(&contract(2)).leaves, 1);
+        assert_eq!(sizes.get(&contract(1)).max_nodes(), 10);
+    }
+
+    #[test]
+    fn test_largest_contracts_from_tries() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let contract = |n: u64| ContractAddress(PatriciaKey(StarkFelt::from(n)));
+        let slots = |contract_address: ContractAddress, values: &[u64]| -> (_, IndexMap<StorageKey, StarkFelt>) {
+            let updates = values
+                .iter()
+                .enumerate()
+                .map(|(key, value)| (StorageKey(PatriciaKey(StarkFelt::from(key as u64 + 1))), StarkFelt::from(*value)))
+                .collect();
+            (contract_address, updates)
+        };
+        let csd = |storage_updates: Vec<(ContractAddress, IndexMap<StorageKey, StarkFelt>)>| CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: Default::default(),
+            storage_updates: storage_updates.into_iter().collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let config = ChainConfig::default();
+        try_update_state_root(&tries, csd(vec![slots(contract(1), &[1, 2, 3]), slots(contract(2), &[1])]), 1, &config)
+            .unwrap();
+        try_update_state_root(
+            &tries,
+            csd(vec![slots(contract(2), &[1, 2, 3, 4]), slots(contract(1), &[0])]),
+            2,
+            &config,
+        )
+        .unwrap();
+
+        // The blocks committed before the process started are accounted for
+        *contract_sizes(&tries) = ContractSizes::default();
+        let largest = largest_contracts(&tries, 2).unwrap();
+        assert_eq!(
+            largest.iter().map(|size| (size.contract_address, size.leaves)).collect::<Vec<_>>(),
+            vec![(contract(2), 4), (contract(1), 2)]
+        );
+    }
+
+    #[test]
+    fn test_written_stats() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x5a_u64)));
+        let write = |slots: &[(u64, u64)]| CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: Default::default(),
+            storage_updates: [(
+                contract_address,
+                slots
+                    .iter()
+                    .map(|(key, value)| (StorageKey(PatriciaKey(StarkFelt::from(*key))), StarkFelt::from(*value)))
+                    .collect(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let config = ChainConfig::default();
+        let commit = |block_number: u64, csd: CommitmentStateDiff| {
+            // The leaves counted as the tries are written are those read from the tries beforehand
+            let read = commit_stats(&tries, &csd, &config).unwrap();
+            try_update_state_root(&tries, csd, block_number, &config).unwrap();
+            let stats = root_registry(&tries).get(block_number).unwrap().stats;
+            assert_eq!(CommitStats { new_nodes: None, ..stats }, read);
+            stats
+        };
+
+        let stats = commit(1, write(&[(1, 10), (2, 20), (3, 30)]));
+        assert_eq!(stats.storage, LeafCounts { new: 3, updated: 0, deleted: 0 });
+        assert_eq!(stats.contracts, LeafCounts { new: 1, updated: 0, deleted: 0 });
+        assert!(stats.new_nodes.unwrap() > 0);
+
+        // Updating leaves rewrites the nodes on their paths without creating any
+        let stats = commit(2, write(&[(1, 11), (2, 20)]));
+        assert_eq!(stats.storage, LeafCounts { new: 0, updated: 1, deleted: 0 });
+        assert_eq!(stats.contracts, LeafCounts { new: 0, updated: 1, deleted: 0 });
+        assert_eq!(stats.new_nodes, Some(0));
+
+        let stats = commit(3, write(&[(4, 40), (3, 0)]));
+        assert_eq!(stats.storage, LeafCounts { new: 1, updated: 0, deleted: 1 });
+        assert!(stats.new_nodes.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_zero_writes() {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(0x5a_u64)));
+        let write = |key: u64, value: u64| CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: Default::default(),
+            storage_updates: [(
+                contract_address,
+                [(StorageKey(PatriciaKey(StarkFelt::from(key))), StarkFelt::from(value))].into(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let stats = |tries: &StateTries, block_number: u64| root_registry(tries).get(block_number).unwrap().stats;
+
+        // Zeroing a slot removes its leaf, as on Starknet
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+        let config = ChainConfig::default();
+        let state_root = try_update_state_root(&tries, write(1, 10), 1, &config).unwrap();
+        assert_ne!(try_update_state_root(&tries, write(2, 20), 2, &config).unwrap(), state_root);
+        assert_eq!(try_update_state_root(&tries, write(2, 0), 3, &config).unwrap(), state_root);
+        assert_eq!(stats(&tries, 3).storage, LeafCounts { new: 0, updated: 0, deleted: 1 });
+        assert_eq!(stats(&tries, 3).ignored_zero_writes, 0);
+
+        // Ignored zero writes leave the slot in the trie and are counted, unless the slot was empty
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+        let config = ChainConfig { zero_writes: ZeroWriteSemantics::Ignore, ..Default::default() };
+        try_update_state_root(&tries, write(1, 10), 1, &config).unwrap();
+        let state_root = try_update_state_root(&tries, write(2, 20), 2, &config).unwrap();
+        assert_eq!(try_update_state_root(&tries, write(2, 0), 3, &config).unwrap(), state_root);
+        assert_eq!(stats(&tries, 3).storage, LeafCounts::default());
+        assert_eq!(stats(&tries, 3).ignored_zero_writes, 1);
+        assert_eq!(try_update_state_root(&tries, write(3, 0), 4, &config).unwrap(), state_root);
+        assert_eq!(stats(&tries, 4).ignored_zero_writes, 0);
+    }
+}
@@ -0,0 +1,97 @@
+//! Storage addressing of Cairo contracts, to derive the storage keys of high-level variables.
+//!
+//! A storage variable lives at `sn_keccak(name)`. Each key of a mapping is hashed into the address
+//! with Pedersen, in order, and the result is reduced modulo [ADDR_BOUND]. So `balances(user)` is at
+//! `h(sn_keccak("balances"), user)`, which is what a storage proof has to be requested for.
+
+use mp_convert::field_element::FromFieldElement;
+use mp_hashers::pedersen::PedersenHasher;
+use mp_hashers::HasherT;
+use starknet_api::state::StorageKey;
+use starknet_core::utils::starknet_keccak;
+use starknet_ff::FieldElement;
+
+use super::consts::ADDR_BOUND;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StorageKeyError {
+    #[error("short string is {0} characters long, at most 31 fit in a felt")]
+    ShortStringTooLong(usize),
+    #[error("short strings are ASCII only")]
+    NonAsciiShortString,
+}
+
+/// Encodes a Cairo short string, ie: a `felt252` map key written `'name'` in Cairo.
+pub fn short_string(string: &str) -> Result<FieldElement, StorageKeyError> {
+    if !string.is_ascii() {
+        return Err(StorageKeyError::NonAsciiShortString);
+    }
+    if string.len() > 31 {
+        return Err(StorageKeyError::ShortStringTooLong(string.len()));
+    }
+    Ok(FieldElement::from_byte_slice_be(string.as_bytes()).expect("31 bytes fit in a felt"))
+}
+
+/// The felts a `u256` map key is hashed as, its low 128 bits then its high 128 bits.
+pub fn u256_key(low: u128, high: u128) -> [FieldElement; 2] {
+    [FieldElement::from(low), FieldElement::from(high)]
+}
+
+/// The address of a storage variable, `keys` being the keys of a mapping, in order.
+///
+/// Keys spanning several felts (ie: [u256_key]) contribute each of their felts.
+pub fn storage_var_address(name: &str, keys: &[FieldElement]) -> FieldElement {
+    let address =
+        keys.iter().fold(starknet_keccak(name.as_bytes()), |address, key| PedersenHasher::hash_elements(address, *key));
+    if address >= ADDR_BOUND {
+        address - ADDR_BOUND
+    } else {
+        address
+    }
+}
+
+/// The storage key of a storage variable, see [storage_var_address].
+pub fn storage_var_key(name: &str, keys: &[FieldElement]) -> StorageKey {
+    // Addresses are below `ADDR_BOUND`, they always fit in the key space of the tries
+    StorageKey::from_field_element(&storage_var_address(name, keys))
+}
+
+/// The storage keys of a value spanning `len` felts (ie: 2 for a `u256`), which are stored at
+/// consecutive addresses from the variable's.
+pub fn storage_var_keys(name: &str, keys: &[FieldElement], len: u64) -> Vec<StorageKey> {
+    let address = storage_var_address(name, keys);
+    (0..len).map(|offset| StorageKey::from_field_element(&(address + FieldElement::from(offset)))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_core::utils::{cairo_short_string_to_felt, get_storage_var_address};
+
+    use super::*;
+
+    #[test]
+    fn test_storage_var_address() {
+        let user = FieldElement::from(0x1234_u64);
+        assert_eq!(storage_var_address("balances", &[]), starknet_keccak(b"balances"));
+        assert_eq!(storage_var_address("balances", &[user]), get_storage_var_address("balances", &[user]).unwrap());
+
+        let [low, high] = u256_key(7, 1);
+        assert_eq!(
+            storage_var_address("allowances", &[user, low, high]),
+            get_storage_var_address("allowances", &[user, low, high]).unwrap()
+        );
+        let keys = storage_var_keys("total_supply", &[], 2);
+        assert_eq!(keys[0], storage_var_key("total_supply", &[]));
+        assert_eq!(
+            keys[1],
+            StorageKey::from_field_element(&(storage_var_address("total_supply", &[]) + FieldElement::ONE))
+        );
+    }
+
+    #[test]
+    fn test_short_string() {
+        assert_eq!(short_string("ERC20").unwrap(), cairo_short_string_to_felt("ERC20").unwrap());
+        assert_eq!(short_string(&"a".repeat(32)), Err(StorageKeyError::ShortStringTooLong(32)));
+        assert_eq!(short_string("é"), Err(StorageKeyError::NonAsciiShortString));
+    }
+}
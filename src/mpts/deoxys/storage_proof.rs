@@ -0,0 +1,232 @@
+use mc_db::storage_handler::{self, DeoxysStorageError, StorageView};
+use mp_felt::Felt252Wrapper;
+use serde_json::{json, Value};
+use starknet_api::core::ContractAddress;
+use starknet_api::state::StorageKey;
+use starknet_types_core::felt::Felt;
+
+use super::config::ChainConfig;
+use super::contracts::compute_contract_state_hash;
+use super::engine::StateTries;
+use super::error::TrieError;
+use super::proof::{felt_to_path, from_bonsai, ProofError, ProofNode};
+use super::proof_format::ProofNodeJson;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageProofError {
+    #[error("block {block_number} was not committed, or its trie versions were pruned")]
+    NotCommitted { block_number: u64 },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+impl From<DeoxysStorageError> for StorageProofError {
+    fn from(e: DeoxysStorageError) -> Self {
+        StorageProofError::Trie(e.into())
+    }
+}
+
+/// The contracts trie leaf preimage of a contract, along with the proofs of its storage slots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractData {
+    pub class_hash: Felt,
+    pub nonce: Felt,
+    /// Root of the contract storage trie.
+    pub root: Felt,
+    pub contract_state_hash_version: Felt,
+    /// Proof of each requested key, in the contract storage trie, root first.
+    pub storage_proofs: Vec<Vec<ProofNode>>,
+}
+
+/// Merkle proofs of storage slots of a contract, from the storage leaves up to the state root.
+///
+/// This matches the `pathfinder_getProof` response: each proof is a list of nodes from the root of
+/// the trie down to the leaf, binary nodes holding both children (ie: the sibling of the path)
+/// and edge nodes the bits of the path they skip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageProof {
+    pub block_number: u64,
+    pub contract_address: ContractAddress,
+    pub state_commitment: Felt,
+    pub class_commitment: Felt,
+    /// Root of the contracts trie, which `contract_proof` is verified against.
+    pub contracts_trie_root: Felt,
+    /// Proof of the contract leaf in the contracts trie, root first.
+    pub contract_proof: Vec<ProofNode>,
+    /// `None` if the contract is not deployed, `contract_proof` then proves its absence.
+    pub contract_data: Option<ContractData>,
+}
+
+impl ContractData {
+    /// Computes the contracts trie leaf of the contract, `h(h(h(class_hash, root), nonce), version)`.
+    pub fn leaf_hash(&self, config: &ChainConfig) -> Felt {
+        compute_contract_state_hash(
+            self.class_hash,
+            self.root,
+            self.nonce,
+            self.contract_state_hash_version,
+            config.hashers.contract_leaf,
+        )
+    }
+}
+
+fn felt_json(felt: &Felt) -> Value {
+    json!(format!("{felt:#x}"))
+}
+
+fn proof_json(proof: &[ProofNode]) -> Value {
+    Value::Array(proof.iter().map(ProofNode::to_json).collect())
+}
+
+impl StorageProof {
+    /// Verifies the proofs of storage slots, from the storage tries up to `state_root`.
+    ///
+    /// The state commitment held by the proof is not trusted: the proof only holds if the roots it
+    /// carries hash to the root the caller obtained independently, ie: from a block header.
+    ///
+    /// Absent slots are proven by non-membership proofs: the proof stops at the closest node to the
+    /// key, an edge whose path diverges from it (or is empty if the storage trie is). A slot of a
+    /// contract which is not deployed is proven absent by the contract proof alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `state_root` - The trusted state root of the block the proof was generated at.
+    /// * `keys`       - The storage keys the proof was generated for, in the same order.
+    /// * `config`     - Chain-specific commitment rules.
+    ///
+    /// # Returns
+    ///
+    /// The value of each slot, zero for absent slots.
+    pub fn verify(&self, state_root: Felt, keys: &[StorageKey], config: &ChainConfig) -> Result<Vec<Felt>, ProofError> {
+        let state_commitment = state_commitment(self.contracts_trie_root, self.class_commitment, config);
+        if state_commitment != state_root {
+            return Err(ProofError::StateRootMismatch { expected: state_root, computed: state_commitment });
+        }
+
+        let contract_key = felt_to_path(&Felt::from_bytes_be(&self.contract_address.0.key().0));
+        let proven = config.hashers.contracts_node.verify_proof(
+            self.contracts_trie_root,
+            &contract_key,
+            &self.contract_proof,
+        )?;
+        let expected = self.contract_data.as_ref().map(|data| data.leaf_hash(config));
+        if proven != expected {
+            return Err(ProofError::ValueMismatch { expected, proven });
+        }
+
+        let Some(data) = &self.contract_data else {
+            return Ok(vec![Felt::ZERO; keys.len()]);
+        };
+        if data.storage_proofs.len() != keys.len() {
+            return Err(ProofError::ProofCount { expected: keys.len(), actual: data.storage_proofs.len() });
+        }
+        keys.iter()
+            .zip(&data.storage_proofs)
+            .map(|(key, proof)| {
+                let key = felt_to_path(&Felt::from_bytes_be(&key.0.key().0));
+                Ok(config.hashers.storage_node.verify_proof(data.root, &key, proof)?.unwrap_or(Felt::ZERO))
+            })
+            .collect()
+    }
+
+    /// Serializes the proof as a `pathfinder_getProof` response.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "state_commitment": felt_json(&self.state_commitment),
+            "class_commitment": felt_json(&self.class_commitment),
+            "contract_proof": proof_json(&self.contract_proof),
+            "contract_data": self.contract_data.as_ref().map(|data| json!({
+                "class_hash": felt_json(&data.class_hash),
+                "nonce": felt_json(&data.nonce),
+                "root": felt_json(&data.root),
+                "contract_state_hash_version": felt_json(&data.contract_state_hash_version),
+                "storage_proofs": data.storage_proofs.iter().map(|proof| proof_json(proof)).collect::<Vec<_>>(),
+            })),
+        })
+    }
+}
+
+pub(crate) fn state_commitment(contracts_trie_root: Felt, class_commitment: Felt, config: &ChainConfig) -> Felt {
+    let contracts_root = Felt252Wrapper::from(contracts_trie_root);
+    let classes_root = Felt252Wrapper::from(class_commitment);
+    config.state_root(contracts_root, classes_root).into()
+}
+
+pub(crate) fn proof(nodes: Vec<bonsai_trie::ProofNode>) -> Vec<ProofNode> {
+    nodes.into_iter().map(from_bonsai).collect()
+}
+
+/// Generates the Merkle proofs of storage slots of a contract, for light clients.
+///
+/// Proofs are read from the trie versions of `block_number`, which committed blocks never modify:
+/// blocks keep being committed while the proofs are generated. The tries of an
+/// [engine](super::engine::CommitmentEngine) generate them from their snapshot of the block. Keys
+/// which are absent from the storage trie get a non-membership proof, which [StorageProof::verify]
+/// proves to be zero.
+///
+/// # Arguments
+///
+/// * `tries`            - The committed tries.
+/// * `contract_address` - The contract whose storage is proven.
+/// * `keys`             - The storage keys to prove.
+/// * `block_number`     - The block the proofs are generated at.
+/// * `config`           - Chain-specific commitment rules, for the state commitment.
+///
+/// # Returns
+///
+/// The proofs, see [StorageProof].
+pub fn get_storage_proof(
+    tries: &StateTries,
+    contract_address: &ContractAddress,
+    keys: &[StorageKey],
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<StorageProof, StorageProofError> {
+    // The engine is only locked while the snapshot is opened
+    let view = match tries.engine() {
+        Some(engine) => Some(engine.view_at(block_number)?.ok_or(StorageProofError::NotCommitted { block_number })?),
+        None => None,
+    };
+    if let Some(view) = view {
+        return Ok(view.storage_proof(contract_address, keys, config)?);
+    }
+
+    let not_committed = || StorageProofError::NotCommitted { block_number };
+    let handler_contract = storage_handler::contract_trie();
+    let contracts_trie_root = handler_contract.root_at(block_number)?.ok_or_else(not_committed)?;
+    let class_commitment = storage_handler::class_trie().root_at(block_number)?.ok_or_else(not_committed)?;
+    let contract_proof = proof(handler_contract.get_proof_at(contract_address, block_number)?);
+    drop(handler_contract);
+
+    let contract_data = match storage_handler::contract_class_hash().get_at(contract_address, block_number)? {
+        Some(class_hash) => {
+            let handler_storage_trie = storage_handler::contract_storage_trie();
+            let nonce = storage_handler::contract_nonces().get_at(contract_address, block_number)?.unwrap_or_default();
+            let storage_proofs = keys
+                .iter()
+                .map(|key| Ok(proof(handler_storage_trie.get_proof_at(contract_address, key, block_number)?)))
+                .collect::<Result<_, StorageProofError>>()?;
+
+            Some(ContractData {
+                class_hash: Felt::from_bytes_be(&class_hash.0.0),
+                nonce: Felt::from_bytes_be(&nonce.0.0),
+                root: handler_storage_trie.root_at(contract_address, block_number)?,
+                contract_state_hash_version: Felt::from(Felt252Wrapper::from(
+                    config.constants.contract_state_hash_version,
+                )),
+                storage_proofs,
+            })
+        }
+        None => None,
+    };
+
+    Ok(StorageProof {
+        block_number,
+        contract_address: *contract_address,
+        state_commitment: state_commitment(contracts_trie_root, class_commitment, config),
+        class_commitment,
+        contracts_trie_root,
+        contract_proof,
+        contract_data,
+    })
+}
@@ -0,0 +1,118 @@
+use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+use starknet_api::hash::StarkFelt;
+
+/// A Starknet protocol version, ie: `0.13.3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion(pub u8, pub u8, pub u8);
+
+impl ProtocolVersion {
+    pub const LATEST: ProtocolVersion = ProtocolVersion(0, 13, 3);
+}
+
+/// How the contracts trie leaf of a system contract is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafConvention {
+    /// The contract has no class: any class hash assigned to it in a state diff is replaced by zero.
+    ZeroClassHash,
+    /// The leaf is built like any other contract's.
+    Standard,
+}
+
+/// A contract living at a reserved address, without being deployed like regular contracts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemContract {
+    pub address: u64,
+    pub name: &'static str,
+    /// First protocol version the contract exists in.
+    pub since: ProtocolVersion,
+    pub leaf: LeafConvention,
+}
+
+impl SystemContract {
+    pub fn contract_address(&self) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(self.address)))
+    }
+}
+
+/// The system contracts of Starknet.
+pub const STARKNET_SYSTEM_CONTRACTS: &[SystemContract] = &[
+    SystemContract {
+        address: 0x0,
+        name: "reserved",
+        since: ProtocolVersion(0, 0, 0),
+        leaf: LeafConvention::ZeroClassHash,
+    },
+    SystemContract {
+        address: 0x1,
+        name: "block hash",
+        since: ProtocolVersion(0, 12, 0),
+        leaf: LeafConvention::ZeroClassHash,
+    },
+    SystemContract {
+        address: 0x2,
+        name: "alias",
+        since: ProtocolVersion(0, 13, 3),
+        leaf: LeafConvention::ZeroClassHash,
+    },
+];
+
+/// Table of the system contracts of a chain, at a given protocol version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemContracts {
+    contracts: Vec<SystemContract>,
+}
+
+impl Default for SystemContracts {
+    fn default() -> Self {
+        Self::starknet(ProtocolVersion::LATEST)
+    }
+}
+
+impl SystemContracts {
+    /// The Starknet system contracts which exist at `version`.
+    pub fn starknet(version: ProtocolVersion) -> Self {
+        Self::new(STARKNET_SYSTEM_CONTRACTS.iter().filter(|contract| contract.since <= version).copied().collect())
+    }
+
+    /// A custom table, for chains which reserve other addresses.
+    pub fn new(contracts: Vec<SystemContract>) -> Self {
+        Self { contracts }
+    }
+
+    pub fn get(&self, contract_address: &ContractAddress) -> Option<&SystemContract> {
+        self.contracts.iter().find(|contract| contract.contract_address() == *contract_address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SystemContract> {
+        self.contracts.iter()
+    }
+
+    /// Applies the leaf convention of `contract_address` to a class hash assigned to it.
+    pub fn class_hash(&self, contract_address: &ContractAddress, class_hash: ClassHash) -> ClassHash {
+        match self.get(contract_address).map(|contract| contract.leaf) {
+            Some(LeafConvention::ZeroClassHash) => ClassHash::default(),
+            Some(LeafConvention::Standard) | None => class_hash,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_contracts_per_version() {
+        let address = |address: u64| ContractAddress(PatriciaKey(StarkFelt::from(address)));
+        let class_hash = ClassHash(StarkFelt::ONE);
+
+        let v0_11 = SystemContracts::starknet(ProtocolVersion(0, 11, 0));
+        assert_eq!(v0_11.iter().count(), 1);
+        assert_eq!(v0_11.class_hash(&address(0), class_hash), ClassHash::default());
+        assert_eq!(v0_11.class_hash(&address(1), class_hash), class_hash);
+
+        let latest = SystemContracts::default();
+        assert_eq!(latest.iter().count(), 3);
+        assert_eq!(latest.class_hash(&address(2), class_hash), ClassHash::default());
+        assert_eq!(latest.class_hash(&address(3), class_hash), class_hash);
+    }
+}
@@ -0,0 +1,238 @@
+//! Storage proofs of the standard token queries, ie: the balance of an account, as light clients
+//! request them.
+//!
+//! The slots of a query are derived from the token's [storage layout](TokenLayout) with the
+//! [storage addressing](super::storage_keys) of Cairo, then proven and verified in one call.
+
+use starknet_api::core::ContractAddress;
+use starknet_api::state::StorageKey;
+use starknet_ff::FieldElement;
+use starknet_types_core::felt::Felt;
+
+use super::config::ChainConfig;
+use super::engine::{CommitmentEngine, StateTries};
+use super::error::TrieError;
+use super::proof::ProofError;
+use super::storage_keys::{storage_var_keys, u256_key};
+use super::storage_proof::{get_storage_proof, StorageProof, StorageProofError};
+
+/// Names of the storage variables of ERC-20 and ERC-721 tokens.
+///
+/// The [Default] is the layout of the OpenZeppelin Cairo contracts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenLayout {
+    /// `ContractAddress -> u256`
+    pub erc20_balances: String,
+    /// `(ContractAddress, ContractAddress) -> u256`
+    pub erc20_allowances: String,
+    /// `u256`
+    pub erc20_total_supply: String,
+    /// `u256 -> ContractAddress`
+    pub erc721_owners: String,
+    /// `ContractAddress -> u256`
+    pub erc721_balances: String,
+}
+
+impl Default for TokenLayout {
+    fn default() -> Self {
+        Self {
+            erc20_balances: "ERC20_balances".to_string(),
+            erc20_allowances: "ERC20_allowances".to_string(),
+            erc20_total_supply: "ERC20_total_supply".to_string(),
+            erc721_owners: "ERC721_owners".to_string(),
+            erc721_balances: "ERC721_balances".to_string(),
+        }
+    }
+}
+
+/// A query of a token's state.
+///
+/// `u256` token ids are given as their low then high 128 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenQuery {
+    Erc20Balance { owner: FieldElement },
+    Erc20Allowance { owner: FieldElement, spender: FieldElement },
+    Erc20TotalSupply,
+    Erc721OwnerOf { token_id: (u128, u128) },
+    Erc721Balance { owner: FieldElement },
+}
+
+/// The value a [TokenQuery] resolves to, the owner of a token which does not exist being zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenValue {
+    U256 { low: Felt, high: Felt },
+    Address(Felt),
+}
+
+/// A verified answer to a [TokenQuery], along with the proof it was verified with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvenValue {
+    pub value: TokenValue,
+    pub proof: StorageProof,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenProofError {
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+    #[error(transparent)]
+    StorageProof(#[from] StorageProofError),
+    #[error("the proof does not verify: {0}")]
+    Proof(#[from] ProofError),
+}
+
+impl TokenQuery {
+    /// The storage keys the query reads, in the order of the felts of its value.
+    pub fn keys(&self, layout: &TokenLayout) -> Vec<StorageKey> {
+        match *self {
+            TokenQuery::Erc20Balance { owner } => storage_var_keys(&layout.erc20_balances, &[owner], 2),
+            TokenQuery::Erc20Allowance { owner, spender } => {
+                storage_var_keys(&layout.erc20_allowances, &[owner, spender], 2)
+            }
+            TokenQuery::Erc20TotalSupply => storage_var_keys(&layout.erc20_total_supply, &[], 2),
+            TokenQuery::Erc721OwnerOf { token_id: (low, high) } => {
+                storage_var_keys(&layout.erc721_owners, &u256_key(low, high), 1)
+            }
+            TokenQuery::Erc721Balance { owner } => storage_var_keys(&layout.erc721_balances, &[owner], 2),
+        }
+    }
+
+    /// Proves the query against the latest state of `engine`, verified against `state_root`, the
+    /// trusted state root of the latest block.
+    pub fn prove(
+        &self,
+        engine: &CommitmentEngine,
+        token: &ContractAddress,
+        state_root: Felt,
+        layout: &TokenLayout,
+        config: &ChainConfig,
+    ) -> Result<ProvenValue, TokenProofError> {
+        let keys = self.keys(layout);
+        let proof = engine.storage_proof(token, &keys, config)?;
+        self.verify(proof, state_root, &keys, config)
+    }
+
+    /// Proves the query against `tries` at `block_number`, verified against `state_root`, the
+    /// trusted state root of the block, see [get_storage_proof].
+    pub fn prove_at(
+        &self,
+        tries: &StateTries,
+        token: &ContractAddress,
+        block_number: u64,
+        state_root: Felt,
+        layout: &TokenLayout,
+        config: &ChainConfig,
+    ) -> Result<ProvenValue, TokenProofError> {
+        let keys = self.keys(layout);
+        let proof = get_storage_proof(tries, token, &keys, block_number, config)?;
+        self.verify(proof, state_root, &keys, config)
+    }
+
+    fn verify(
+        &self,
+        proof: StorageProof,
+        state_root: Felt,
+        keys: &[StorageKey],
+        config: &ChainConfig,
+    ) -> Result<ProvenValue, TokenProofError> {
+        let values = proof.verify(state_root, keys, config)?;
+        let value = match self {
+            TokenQuery::Erc721OwnerOf { .. } => TokenValue::Address(values[0]),
+            _ => TokenValue::U256 { low: values[0], high: values[1] },
+        };
+        Ok(ProvenValue { value, proof })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+
+    fn balance_diff(token: ContractAddress, keys: [StorageKey; 2], low: u64) -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: [(token, ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(
+                token,
+                [(keys[0], StarkFelt::from(low)), (keys[1], StarkFelt::ZERO)].into_iter().collect(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_prove_erc20_balance() {
+        let config = ChainConfig::default();
+        let layout = TokenLayout::default();
+        let token = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let (owner, other) = (FieldElement::from(0xa_u64), FieldElement::from(0xb_u64));
+        let balance = TokenQuery::Erc20Balance { owner };
+        let [low, high] = <[StorageKey; 2]>::try_from(balance.keys(&layout)).unwrap();
+
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: [(token, ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(token, [(low, StarkFelt::from(100_u64)), (high, StarkFelt::ONE)].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        };
+        // The trusted root is the one the block was committed with, not the one the proof carries
+        let state_root = Felt::from(engine.update_state_root(csd, 0, &config).unwrap());
+
+        let proven = balance.prove(&engine, &token, state_root, &layout, &config).unwrap();
+        assert_eq!(proven.value, TokenValue::U256 { low: Felt::from(100_u64), high: Felt::ONE });
+        let proven =
+            TokenQuery::Erc20Balance { owner: other }.prove(&engine, &token, state_root, &layout, &config).unwrap();
+        assert_eq!(proven.value, TokenValue::U256 { low: Felt::ZERO, high: Felt::ZERO });
+        let owner_of = TokenQuery::Erc721OwnerOf { token_id: (1, 0) };
+        assert_eq!(
+            owner_of.prove(&engine, &token, state_root, &layout, &config).unwrap().value,
+            TokenValue::Address(Felt::ZERO)
+        );
+
+        assert!(matches!(
+            balance.prove(&engine, &token, Felt::ONE, &layout, &config),
+            Err(TokenProofError::Proof(ProofError::StateRootMismatch { expected, .. })) if expected == Felt::ONE
+        ));
+    }
+
+    #[test]
+    fn test_prove_at_past_block() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        let config = ChainConfig::default();
+        let layout = TokenLayout::default();
+        let token = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let balance = TokenQuery::Erc20Balance { owner: FieldElement::from(0xa_u64) };
+        let keys = <[StorageKey; 2]>::try_from(balance.keys(&layout)).unwrap();
+        let past_root = Felt::from(try_update_state_root(&tries, balance_diff(token, keys, 100), 1, &config).unwrap());
+        let latest_root = Felt::from(try_update_state_root(&tries, balance_diff(token, keys, 40), 2, &config).unwrap());
+
+        let proven = balance.prove_at(&tries, &token, 1, past_root, &layout, &config).unwrap();
+        assert_eq!(proven.value, TokenValue::U256 { low: Felt::from(100_u64), high: Felt::ZERO });
+        let proven = balance.prove_at(&tries, &token, 2, latest_root, &layout, &config).unwrap();
+        assert_eq!(proven.value, TokenValue::U256 { low: Felt::from(40_u64), high: Felt::ZERO });
+        // The proof of a block does not verify against the root of another
+        assert!(matches!(
+            balance.prove_at(&tries, &token, 1, latest_root, &layout, &config),
+            Err(TokenProofError::Proof(ProofError::StateRootMismatch { .. }))
+        ));
+        assert!(matches!(
+            balance.prove_at(&tries, &token, 3, latest_root, &layout, &config),
+            Err(TokenProofError::StorageProof(StorageProofError::NotCommitted { block_number })) if block_number == 3
+        ));
+    }
+}
@@ -8,10 +8,15 @@ use mp_hashers::pedersen::PedersenHasher;
 use mp_hashers::HasherT;
 use mp_transactions::compute_hash::ComputeTransactionHash;
 use rayon::prelude::*;
-use starknet_api::transaction::Transaction;
+use starknet_api::hash::StarkFelt;
+use starknet_api::transaction::{DeclareTransaction, DeployAccountTransaction, InvokeTransaction, Transaction};
 use starknet_ff::FieldElement;
 use starknet_types_core::felt::Felt;
-use starknet_types_core::hash::Pedersen;
+use starknet_types_core::hash::{Pedersen, Poseidon, StarkHash};
+
+use super::config::CommitmentScheme;
+use super::consts::SIGNATURE_IN_COMMITMENT_BLOCK;
+use super::error::{CommitmentError, CommitmentItem};
 
 /// Compute the combined hash of the transaction hash and the signature.
 ///
@@ -34,7 +39,7 @@ pub fn calculate_transaction_hash_with_signature<H: HasherT>(
 where
     H: HasherT,
 {
-    let include_signature = block_number >= 61394;
+    let include_signature = block_number >= SIGNATURE_IN_COMMITMENT_BLOCK;
 
     let (signature_hash, tx_hash) = rayon::join(
         || match transaction {
@@ -80,6 +85,47 @@ where
     H::hash_elements(tx_hash, signature_hash)
 }
 
+/// Compute the leaf of a transaction in the transaction commitment, since Starknet v0.13.2.
+///
+/// # Returns
+///
+/// `Poseidon(tx_hash, signature...)`, or `Poseidon(tx_hash, 0)` if the signature is empty.
+pub fn calculate_transaction_leaf_poseidon(
+    transaction: &Transaction,
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+) -> Felt {
+    let signature = match transaction {
+        Transaction::Invoke(invoke_tx) => invoke_tx.signature().0,
+        Transaction::Declare(declare_tx) => declare_tx.signature().0,
+        Transaction::DeployAccount(deploy_account_tx) => deploy_account_tx.signature().0,
+        _ => vec![],
+    };
+    let tx_hash = transaction.compute_hash::<PedersenHasher>(chain_id, false, Some(block_number)).0;
+    let signature = signature.iter().map(|value| Felt::from_bytes_be(&value.0)).collect::<Vec<_>>();
+
+    transaction_leaf_poseidon(Felt::from_bytes_be(&tx_hash.0), &signature)
+}
+
+/// `Poseidon(tx_hash, signature...)`, or `Poseidon(tx_hash, 0)` if the signature is empty.
+fn transaction_leaf_poseidon(tx_hash: Felt, signature: &[Felt]) -> Felt {
+    let mut elements = vec![tx_hash];
+    elements.extend_from_slice(signature);
+    if elements.len() == 1 {
+        elements.push(Felt::ZERO);
+    }
+    Poseidon::hash_array(&elements)
+}
+
+/// `Pedersen(tx_hash, h(signature...))`, see [calculate_transaction_hash_with_signature].
+fn transaction_leaf_pedersen(tx_hash: Felt, signature: &[Felt]) -> Felt {
+    let field_element = |felt: &Felt| FieldElement::from_bytes_be(&felt.to_bytes_be()).unwrap();
+    let signature_hash =
+        PedersenHasher::compute_hash_on_elements(&signature.iter().map(field_element).collect::<Vec<_>>());
+    let leaf = PedersenHasher::hash_elements(field_element(&tx_hash), signature_hash);
+    Felt::from_bytes_be(&leaf.to_bytes_be())
+}
+
 /// Calculate the transaction commitment in memory using HashMapDb (which is more efficient for this
 /// usecase).
 ///
@@ -97,31 +143,378 @@ pub fn memory_transaction_commitment(
     chain_id: Felt252Wrapper,
     block_number: u64,
 ) -> Result<Felt252Wrapper, String> {
-    // TODO @cchudant refacto/optimise this function
-    let config = BonsaiStorageConfig::default();
-    let bonsai_db = HashMapDb::<BasicId>::default();
-    let mut bonsai_storage =
-        BonsaiStorage::<_, _, Pedersen>::new(bonsai_db, config).expect("Failed to create bonsai storage");
-    let identifier = bonsai_identifier::TRANSACTION;
+    try_memory_transaction_commitment(transactions, chain_id, block_number).map_err(|e| e.to_string())
+}
 
+/// The felts read when hashing a transaction, field by field.
+fn transaction_felts(transaction: &Transaction) -> Vec<&StarkFelt> {
+    let mut felts = Vec::new();
+    match transaction {
+        Transaction::Declare(DeclareTransaction::V0(tx) | DeclareTransaction::V1(tx)) => {
+            felts.extend([&tx.nonce.0, &tx.class_hash.0, tx.sender_address.0.key()]);
+            felts.extend(&tx.signature.0);
+        }
+        Transaction::Declare(DeclareTransaction::V2(tx)) => {
+            felts.extend([&tx.nonce.0, &tx.class_hash.0, &tx.compiled_class_hash.0, tx.sender_address.0.key()]);
+            felts.extend(&tx.signature.0);
+        }
+        Transaction::Declare(DeclareTransaction::V3(tx)) => {
+            felts.extend([&tx.nonce.0, &tx.class_hash.0, &tx.compiled_class_hash.0, tx.sender_address.0.key()]);
+            felts.extend(tx.signature.0.iter().chain(&tx.paymaster_data.0).chain(&tx.account_deployment_data.0));
+        }
+        Transaction::Deploy(tx) => {
+            felts.extend([&tx.version.0, &tx.class_hash.0, &tx.contract_address_salt.0]);
+            felts.extend(tx.constructor_calldata.0.iter());
+        }
+        Transaction::DeployAccount(DeployAccountTransaction::V1(tx)) => {
+            felts.extend([&tx.nonce.0, &tx.class_hash.0, &tx.contract_address_salt.0]);
+            felts.extend(tx.signature.0.iter().chain(tx.constructor_calldata.0.iter()));
+        }
+        Transaction::DeployAccount(DeployAccountTransaction::V3(tx)) => {
+            felts.extend([&tx.nonce.0, &tx.class_hash.0, &tx.contract_address_salt.0]);
+            felts.extend(tx.signature.0.iter().chain(tx.constructor_calldata.0.iter()).chain(&tx.paymaster_data.0));
+        }
+        Transaction::Invoke(InvokeTransaction::V0(tx)) => {
+            felts.extend([tx.contract_address.0.key(), &tx.entry_point_selector.0]);
+            felts.extend(tx.signature.0.iter().chain(tx.calldata.0.iter()));
+        }
+        Transaction::Invoke(InvokeTransaction::V1(tx)) => {
+            felts.extend([&tx.nonce.0, tx.sender_address.0.key()]);
+            felts.extend(tx.signature.0.iter().chain(tx.calldata.0.iter()));
+        }
+        Transaction::Invoke(InvokeTransaction::V3(tx)) => {
+            felts.extend([&tx.nonce.0, tx.sender_address.0.key()]);
+            felts.extend(tx.signature.0.iter().chain(tx.calldata.0.iter()));
+            felts.extend(tx.paymaster_data.0.iter().chain(&tx.account_deployment_data.0));
+        }
+        Transaction::L1Handler(tx) => {
+            felts.extend([&tx.version.0, &tx.nonce.0, tx.contract_address.0.key(), &tx.entry_point_selector.0]);
+            felts.extend(tx.calldata.0.iter());
+        }
+    }
+    felts
+}
+
+/// Computes the leaf of a transaction in the transaction commitment of `scheme`.
+///
+/// Hashing panics on values which are not valid felts, the felts of the transaction are converted
+/// beforehand so that the first invalid one is reported as [CommitmentError::Conversion] instead.
+fn transaction_leaf(
+    index: usize,
+    transaction: &Transaction,
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+    scheme: CommitmentScheme,
+) -> Result<Felt, CommitmentError> {
+    let invalid = transaction_felts(transaction).into_iter().find(|felt| FieldElement::from_bytes_be(&felt.0).is_err());
+    if let Some(value) = invalid {
+        return Err(CommitmentError::Conversion { item: CommitmentItem::Transaction, index, value: *value });
+    }
+    Ok(match scheme {
+        CommitmentScheme::Pedersen => {
+            let hash = calculate_transaction_hash_with_signature::<PedersenHasher>(transaction, chain_id, block_number);
+            Felt::from(Felt252Wrapper::from(hash))
+        }
+        CommitmentScheme::Poseidon => calculate_transaction_leaf_poseidon(transaction, chain_id, block_number),
+    })
+}
+
+/// Calculate the transaction commitment in memory, see [memory_transaction_commitment].
+///
+/// Transaction hashing panics on malformed transactions (ie: values which are not valid felts), such
+/// values are reported as [CommitmentError::Conversion] before hashing instead.
+///
+/// # Arguments
+///
+/// * `transactions` - The transactions of the block
+/// * `chain_id` - The current chain id
+/// * `block_number` - The current block number
+///
+/// # Returns
+///
+/// The transaction commitment as `Felt252Wrapper`, or the first transaction which could not be
+/// committed to.
+pub fn try_memory_transaction_commitment(
+    transactions: &[Transaction],
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+) -> Result<Felt252Wrapper, CommitmentError> {
+    try_memory_transaction_commitment_with_scheme(transactions, chain_id, block_number, CommitmentScheme::Pedersen)
+}
+
+/// Calculate the transaction commitment in memory following the scheme of the block's protocol
+/// version, see [try_memory_transaction_commitment].
+///
+/// # Arguments
+///
+/// * `transactions` - The transactions of the block
+/// * `chain_id` - The current chain id
+/// * `block_number` - The current block number
+/// * `scheme` - The commitment scheme of the block, see [CommitmentScheme::for_protocol_version]
+///
+/// # Returns
+///
+/// The transaction commitment as `Felt252Wrapper`, or the first transaction which could not be
+/// committed to.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(block_number = block_number, transactions = transactions.len(), scheme = ?scheme)
+    )
+)]
+pub fn try_memory_transaction_commitment_with_scheme(
+    transactions: &[Transaction],
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+    scheme: CommitmentScheme,
+) -> Result<Felt252Wrapper, CommitmentError> {
     // transaction hashes are computed in parallel
     let txs = transactions
         .par_iter()
-        .map(|tx| calculate_transaction_hash_with_signature::<PedersenHasher>(tx, chain_id, block_number))
-        .collect::<Vec<_>>();
+        .enumerate()
+        .map(|(index, tx)| transaction_leaf(index, tx, chain_id, block_number, scheme))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let identifier = bonsai_identifier::TRANSACTION;
+    match scheme {
+        CommitmentScheme::Pedersen => memory_trie_root::<Pedersen>(identifier, txs, CommitmentItem::Transaction),
+        CommitmentScheme::Poseidon => memory_trie_root::<Poseidon>(identifier, txs, CommitmentItem::Transaction),
+    }
+}
 
-    // once transaction hashes have finished computing, they are inserted into the local Bonsai db
-    for (i, tx_hash) in txs.into_iter().enumerate() {
+/// Computes the root of an in-memory trie whose leaves are keyed by their index.
+///
+/// # Arguments
+///
+/// * `identifier` - The identifier of the trie in the Bonsai db.
+/// * `leaves` - The leaves, in order.
+/// * `item` - The items the leaves commit to, for errors.
+///
+/// # Returns
+///
+/// The trie root as `Felt252Wrapper`.
+pub(crate) fn memory_trie_root<H: StarkHash + Send + Sync>(
+    identifier: &[u8],
+    leaves: Vec<Felt>,
+    item: CommitmentItem,
+) -> Result<Felt252Wrapper, CommitmentError> {
+    // TODO @cchudant refacto/optimise this function
+    let backend = |e| CommitmentError::Backend { item, reason: format!("{e:?}") };
+
+    let config = BonsaiStorageConfig::default();
+    let bonsai_db = HashMapDb::<BasicId>::default();
+    let mut bonsai_storage = BonsaiStorage::<_, _, H>::new(bonsai_db, config).map_err(backend)?;
+
+    // once hashes have finished computing, they are inserted into the local Bonsai db
+    for (i, leaf) in leaves.into_iter().enumerate() {
         let key = BitVec::from_vec(i.to_be_bytes().to_vec());
-        let value = Felt::from(Felt252Wrapper::from(tx_hash));
-        bonsai_storage.insert(identifier, key.as_bitslice(), &value).expect("Failed to insert into bonsai storage");
+        bonsai_storage.insert(identifier, key.as_bitslice(), &leaf).map_err(backend)?;
     }
 
+    // Note that committing changes still has the greatest performance hit
+    // as this is where the root hash is calculated. Due to the Merkle structure
+    // of Bonsai Tries, this results in a trie size that grows very rapidly with
+    // each new insertion.
     let mut id_builder = BasicIdBuilder::new();
     let id = id_builder.new_id();
 
-    bonsai_storage.commit(id).expect("Failed to commit to bonsai storage");
-    let root_hash = bonsai_storage.root_hash(identifier).expect("Failed to get root hash");
+    bonsai_storage.commit(id).map_err(backend)?;
+    let root_hash = bonsai_storage.root_hash(identifier).map_err(backend)?;
 
     Ok(Felt252Wrapper::from(root_hash))
 }
+
+enum MemoryStorage {
+    Pedersen(BonsaiStorage<BasicId, HashMapDb<BasicId>, Pedersen>),
+    Poseidon(BonsaiStorage<BasicId, HashMapDb<BasicId>, Poseidon>),
+}
+
+/// An in-memory trie whose leaves are keyed by their index and inserted one at a time, for the
+/// commitment builders. See [memory_trie_root].
+pub(crate) struct IndexedTrie {
+    storage: MemoryStorage,
+    identifier: &'static [u8],
+    item: CommitmentItem,
+    len: usize,
+}
+
+impl IndexedTrie {
+    pub(crate) fn new(
+        identifier: &'static [u8],
+        item: CommitmentItem,
+        scheme: CommitmentScheme,
+    ) -> Result<Self, CommitmentError> {
+        let backend = |e| CommitmentError::Backend { item, reason: format!("{e:?}") };
+        let config = BonsaiStorageConfig::default;
+        let storage = match scheme {
+            CommitmentScheme::Pedersen => {
+                MemoryStorage::Pedersen(BonsaiStorage::new(HashMapDb::default(), config()).map_err(backend)?)
+            }
+            CommitmentScheme::Poseidon => {
+                MemoryStorage::Poseidon(BonsaiStorage::new(HashMapDb::default(), config()).map_err(backend)?)
+            }
+        };
+        Ok(Self { storage, identifier, item, len: 0 })
+    }
+
+    /// Number of leaves inserted.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Inserts the next leaf.
+    pub(crate) fn push(&mut self, leaf: Felt) -> Result<(), CommitmentError> {
+        let key = BitVec::<u8, Msb0>::from_vec(self.len.to_be_bytes().to_vec());
+        match &mut self.storage {
+            MemoryStorage::Pedersen(storage) => storage.insert(self.identifier, key.as_bitslice(), &leaf),
+            MemoryStorage::Poseidon(storage) => storage.insert(self.identifier, key.as_bitslice(), &leaf),
+        }
+        .map_err(|e| CommitmentError::Backend { item: self.item, reason: format!("{e:?}") })?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Computes the root of the trie.
+    pub(crate) fn root(self) -> Result<Felt252Wrapper, CommitmentError> {
+        let (identifier, item) = (self.identifier, self.item);
+        let id = BasicIdBuilder::new().new_id();
+        let root_hash = match self.storage {
+            MemoryStorage::Pedersen(mut storage) => storage.commit(id).and_then(|_| storage.root_hash(identifier)),
+            MemoryStorage::Poseidon(mut storage) => storage.commit(id).and_then(|_| storage.root_hash(identifier)),
+        }
+        .map_err(|e| CommitmentError::Backend { item, reason: format!("{e:?}") })?;
+        Ok(Felt252Wrapper::from(root_hash))
+    }
+}
+
+/// Builds the transaction commitment of a block from transactions pushed one at a time, ie: as they
+/// are accepted into the block by a block builder.
+///
+/// Each transaction leaf is inserted in the commitment trie as it is pushed, instead of recomputing
+/// the commitment over the whole block after each addition. The commitment is the same as
+/// [try_memory_transaction_commitment_with_scheme] over the pushed transactions, in the same order.
+pub struct TransactionCommitmentBuilder {
+    scheme: CommitmentScheme,
+    chain_id: Felt252Wrapper,
+    block_number: u64,
+    trie: IndexedTrie,
+}
+
+impl TransactionCommitmentBuilder {
+    pub fn new(scheme: CommitmentScheme, chain_id: Felt252Wrapper, block_number: u64) -> Result<Self, CommitmentError> {
+        let trie = IndexedTrie::new(bonsai_identifier::TRANSACTION, CommitmentItem::Transaction, scheme)?;
+        Ok(Self { scheme, chain_id, block_number, trie })
+    }
+
+    /// Number of transactions pushed so far.
+    pub fn len(&self) -> usize {
+        self.trie.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Folds the next transaction of the block into the commitment, from its hash and signature.
+    ///
+    /// # Arguments
+    ///
+    /// * `tx_hash` - The hash of the transaction.
+    /// * `signature` - The signature committed to, which is empty for L1 handlers, and for declare
+    ///   and deploy account transactions before [SIGNATURE_IN_COMMITMENT_BLOCK] with
+    ///   [CommitmentScheme::Pedersen]
+    pub fn push(&mut self, tx_hash: Felt, signature: &[Felt]) -> Result<(), CommitmentError> {
+        let leaf = match self.scheme {
+            CommitmentScheme::Pedersen => transaction_leaf_pedersen(tx_hash, signature),
+            CommitmentScheme::Poseidon => transaction_leaf_poseidon(tx_hash, signature),
+        };
+        self.trie.push(leaf)
+    }
+
+    /// Folds the next transaction of the block into the commitment, hashing it.
+    ///
+    /// Malformed transactions are reported as [CommitmentError::Conversion], see
+    /// [try_memory_transaction_commitment].
+    pub fn push_transaction(&mut self, transaction: &Transaction) -> Result<(), CommitmentError> {
+        let leaf = transaction_leaf(self.len(), transaction, self.chain_id, self.block_number, self.scheme)?;
+        self.trie.push(leaf)
+    }
+
+    /// Computes the transaction commitment of the transactions pushed.
+    pub fn finalize(self) -> Result<Felt252Wrapper, CommitmentError> {
+        self.trie.root()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transaction_commitment_builder() {
+        let transactions = [(Felt::ONE, vec![]), (Felt::TWO, vec![Felt::THREE, Felt::from(4_u64)])];
+
+        for scheme in [CommitmentScheme::Pedersen, CommitmentScheme::Poseidon] {
+            let mut builder = TransactionCommitmentBuilder::new(scheme, Felt252Wrapper::ZERO, 0).unwrap();
+            let mut leaves = Vec::new();
+            for (tx_hash, signature) in transactions.iter() {
+                builder.push(*tx_hash, signature).unwrap();
+                leaves.push(match scheme {
+                    CommitmentScheme::Pedersen => transaction_leaf_pedersen(*tx_hash, signature),
+                    CommitmentScheme::Poseidon => transaction_leaf_poseidon(*tx_hash, signature),
+                });
+            }
+            assert_eq!(builder.len(), 2);
+
+            let identifier = bonsai_identifier::TRANSACTION;
+            let expected = match scheme {
+                CommitmentScheme::Pedersen => {
+                    memory_trie_root::<Pedersen>(identifier, leaves, CommitmentItem::Transaction)
+                }
+                CommitmentScheme::Poseidon => {
+                    memory_trie_root::<Poseidon>(identifier, leaves, CommitmentItem::Transaction)
+                }
+            };
+            assert_eq!(builder.finalize().unwrap(), expected.unwrap());
+        }
+
+        // Poseidon leaves of unsigned transactions commit to a zero signature
+        assert_eq!(transaction_leaf_poseidon(Felt::ONE, &[]), transaction_leaf_poseidon(Felt::ONE, &[Felt::ZERO]));
+    }
+
+    #[test]
+    fn test_invalid_transaction() {
+        use std::sync::Arc;
+
+        use starknet_api::transaction::{Calldata, L1HandlerTransaction};
+
+        // The field prime, which fits in 32 bytes but is not a felt
+        let mut prime = [0; 32];
+        (prime[0], prime[7], prime[31]) = (0x08, 0x11, 0x01);
+        let invalid = StarkFelt(prime);
+        let transaction = |calldata: Vec<StarkFelt>| {
+            Transaction::L1Handler(L1HandlerTransaction {
+                calldata: Calldata(Arc::new(calldata)),
+                ..Default::default()
+            })
+        };
+        let transactions = [transaction(vec![StarkFelt::ONE]), transaction(vec![StarkFelt::ONE, invalid])];
+
+        for scheme in [CommitmentScheme::Pedersen, CommitmentScheme::Poseidon] {
+            let committed =
+                try_memory_transaction_commitment_with_scheme(&transactions, Felt252Wrapper::ZERO, 0, scheme);
+            assert!(matches!(
+                committed,
+                Err(CommitmentError::Conversion { item: CommitmentItem::Transaction, index: 1, value }) if value == invalid
+            ));
+
+            let mut builder = TransactionCommitmentBuilder::new(scheme, Felt252Wrapper::ZERO, 0).unwrap();
+            builder.push_transaction(&transactions[0]).unwrap();
+            assert!(matches!(
+                builder.push_transaction(&transactions[1]),
+                Err(CommitmentError::Conversion { index: 1, .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Binary snapshots of the tries of an [engine](CommitmentEngine), to bootstrap a node from a
+//! trusted snapshot instead of replaying every state update.
+//!
+//! Unlike the [interchange format](super::interchange), which holds the leaves of the state and has
+//! them hashed again on import, a trie snapshot holds the entries of the backend as they are: the
+//! nodes of the tries along with the class hashes and nonces. Importing one only writes them back.
+//! The trie logs are left out, the imported tries cannot be reverted before the snapshot's block.
+//!
+//! The file is [framed](super::framing) with the magic `SRTS`, the kind of each chunk being the
+//! column of its entries:
+//!
+//! ```text
+//! kind  := index of the column in Column::ALL
+//! entry := key_len:u16 key value_len:u32 value
+//! ```
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use bonsai_trie::DatabaseKey;
+use mp_felt::Felt252Wrapper;
+
+use super::backend::{Backend, BackendError, BonsaiBackend, Column, StarkrootBackend};
+use super::config::ChainConfig;
+use super::engine::CommitmentEngine;
+use super::error::TrieError;
+use super::framing::{self, ChunkReader, Format, FramingError, CHUNK_ENTRIES};
+
+pub const MAGIC: &[u8; 4] = b"SRTS";
+pub const VERSION: u16 = 1;
+
+const FORMAT: Format = Format { name: "trie snapshot", magic: MAGIC, version: VERSION };
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrieSnapshotError {
+    #[error(transparent)]
+    Framing(#[from] FramingError),
+    #[error("unknown column {0:#x}")]
+    UnknownColumn(u8),
+    #[error(
+        "imported tries of block {block_number} have state root {computed:#x}, the snapshot declares {expected:#x}"
+    )]
+    RootMismatch { block_number: u64, expected: Felt252Wrapper, computed: Felt252Wrapper },
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+impl From<io::Error> for TrieSnapshotError {
+    fn from(error: io::Error) -> Self {
+        Self::Framing(error.into())
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, column: u8, entries: &[(Vec<u8>, Vec<u8>)]) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    for (key, value) in entries {
+        bytes.extend((key.len() as u16).to_be_bytes());
+        bytes.extend(key);
+        bytes.extend((value.len() as u32).to_be_bytes());
+        bytes.extend(value);
+    }
+    framing::write_chunk(writer, column, entries.len(), &bytes)
+}
+
+/// Writes the tries of `engine` right after `block_number`, which must still have its snapshot in
+/// the engine's backend.
+pub fn export_snapshot<W: Write>(
+    engine: &CommitmentEngine,
+    block_number: u64,
+    config: &ChainConfig,
+    writer: &mut W,
+) -> Result<(), TrieSnapshotError> {
+    let snapshot = engine.backend().snapshot(block_number)?;
+    let state_root = engine.reopen(Arc::clone(&snapshot))?.state_root(config)?;
+
+    framing::write_header(writer, &FORMAT, block_number, state_root)?;
+
+    let trie_logs = BonsaiBackend::key(&DatabaseKey::TrieLog(&[]));
+    for (index, column) in Column::ALL.into_iter().enumerate() {
+        // The importing engine keeps its own metadata
+        if column == Column::Metadata {
+            continue;
+        }
+        let mut entries = snapshot.scan_prefix(column, &[])?;
+        if let Column::Trie(_) = column {
+            entries.retain(|(key, _)| !key.starts_with(&trie_logs));
+        }
+        for chunk in entries.chunks(CHUNK_ENTRIES) {
+            write_chunk(writer, index as u8, chunk)?;
+        }
+    }
+
+    framing::write_end(writer)?;
+    Ok(())
+}
+
+/// Reads a buffer prefixed by its length, which is `N` bytes wide.
+fn read_sized<R: Read, const N: usize>(chunks: &mut ChunkReader<'_, R>) -> Result<Vec<u8>, FramingError> {
+    let mut len = [0u8; 8];
+    len[8 - N..].copy_from_slice(chunks.read(N)?);
+    Ok(chunks.read(u64::from_be_bytes(len) as usize)?.to_vec())
+}
+
+/// Reads a trie snapshot into `backend`, which must be empty, and opens the tries.
+///
+/// The imported tries are checked to hash to the state root declared by the snapshot. This does not
+/// make the snapshot trusted: the caller still has to check that root against a trusted source,
+/// ie: the state root of the block on L1. On error, the backend must be discarded.
+pub fn import_snapshot<R: Read>(
+    backend: Backend,
+    reader: &mut R,
+    config: &ChainConfig,
+) -> Result<CommitmentEngine, TrieSnapshotError> {
+    let (block_number, state_root) = framing::read_header(reader, &FORMAT)?;
+
+    let mut chunks = ChunkReader::new(reader);
+    while let Some((column, entries)) = chunks.next_chunk()? {
+        let Some(&column_id) = Column::ALL.get(column as usize) else {
+            return Err(TrieSnapshotError::UnknownColumn(column));
+        };
+
+        // Entries are only written once the whole chunk was checked
+        let mut writes = Vec::with_capacity(entries);
+        for _ in 0..entries {
+            let key = read_sized::<_, 2>(&mut chunks)?;
+            let value = read_sized::<_, 4>(&mut chunks)?;
+            writes.push((key, value));
+        }
+        chunks.verify()?;
+        for (key, value) in writes {
+            backend.put(column_id, &key, Some(&value))?;
+        }
+    }
+    // The entries are committed along with the metadata of the engine
+    let engine = CommitmentEngine::open_at(backend, block_number)?;
+    let computed = engine.state_root(config)?;
+    if computed != state_root {
+        return Err(TrieSnapshotError::RootMismatch { block_number, expected: state_root, computed });
+    }
+    Ok(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+
+    fn csd(block_number: u64) -> CommitmentStateDiff {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::from(block_number % 2 + 1)));
+        let key = StorageKey(PatriciaKey(StarkFelt::from(block_number)));
+        CommitmentStateDiff {
+            address_to_class_hash: [(contract_address, ClassHash(StarkFelt::THREE))].into_iter().collect(),
+            address_to_nonce: [(contract_address, starknet_api::core::Nonce(StarkFelt::from(block_number)))]
+                .into_iter()
+                .collect(),
+            storage_updates: [(contract_address, [(key, StarkFelt::from(block_number + 1))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let config = ChainConfig::default();
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        for block_number in 0..4 {
+            engine.update_state_root(csd(block_number), block_number, &config).unwrap();
+        }
+
+        let mut bytes = Vec::new();
+        export_snapshot(&engine, 3, &config, &mut bytes).unwrap();
+        let mut imported = import_snapshot(Arc::new(MemoryBackend::new()), &mut bytes.as_slice(), &config).unwrap();
+        assert_eq!(imported.state_root(&config).unwrap(), engine.state_root(&config).unwrap());
+        assert_eq!(imported.latest(), Some(3));
+
+        // The imported tries follow the chain from there
+        assert_eq!(
+            imported.update_state_root(csd(4), 4, &config).unwrap(),
+            engine.update_state_root(csd(4), 4, &config).unwrap()
+        );
+
+        let last = bytes.len() - 2;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            import_snapshot(Arc::new(MemoryBackend::new()), &mut bytes.as_slice(), &config),
+            Err(TrieSnapshotError::Framing(FramingError::Checksum(_)))
+        ));
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mp_felt::Felt252Wrapper;
+
+use super::atomic::revert_tries;
+#[cfg(feature = "class-verification")]
+use super::class_verification::{spawn_recompilation_audit, stored_compiled_class_hash, AuditHandle};
+use super::config::ChainConfig;
+use super::engine::StateTries;
+use super::error::{CommitError, TrieError};
+use super::handles::WriteHandle;
+use super::historical::state_root_at;
+use super::roots::root_registry;
+#[cfg(feature = "class-verification")]
+use super::runtime::class_store;
+
+/// Version of this crate, as recorded in the upgrade marker.
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpgradeError {
+    #[error("failed to access the upgrade marker: {0}")]
+    Marker(#[from] io::Error),
+    #[error("blocks before genesis cannot be reverified")]
+    Genesis,
+    #[error("block {0} was not committed, it has no stored root to compare with")]
+    NotCommitted(u64),
+    #[error("failed to read the stored roots: {0}")]
+    Trie(#[from] TrieError),
+    #[error("failed to load the state diff of block {block_number}: {error}")]
+    Diff { block_number: u64, error: String },
+    #[error("failed to revert the tries to block {block_number}: {error}")]
+    Revert { block_number: u64, error: TrieError },
+    #[error(transparent)]
+    Commit(#[from] CommitError),
+    #[error(
+        "behavior regression at block {block_number}: stored root {stored:?}, recomputed root {recomputed:?}, the \
+         tries were reverted to the previous block"
+    )]
+    Regression { block_number: u64, stored: Felt252Wrapper, recomputed: Felt252Wrapper },
+}
+
+/// Outcome of a successful reverification.
+#[derive(Debug)]
+pub struct ReverifyReport {
+    /// Version which committed the blocks, `None` if unknown.
+    pub previous_version: Option<String>,
+    pub blocks: RangeInclusive<u64>,
+    /// The [recompilation audit](spawn_recompilation_audit) of the [class store](class_store), if one
+    /// is configured: the bundled Sierra -> CASM compiler may have changed along with this crate.
+    #[cfg(feature = "class-verification")]
+    pub class_audit: Option<AuditHandle>,
+}
+
+fn revert_to(tries: &StateTries, block_number: u64) -> Result<(), UpgradeError> {
+    revert_tries(tries, block_number).map_err(|error| UpgradeError::Revert { block_number, error })
+}
+
+/// Recomputes the state roots of already committed blocks with the running version of this crate,
+/// and compares them with the roots stored when they were first committed.
+///
+/// The tries are reverted to the block before `range` and every block of the range is applied
+/// again, along with the blocks committed after `range` if any, since the tries cannot be moved
+/// forward without them. The stored roots are read from the versioned tries, so that this works
+/// right after a restart, and every state diff is loaded before anything is reverted. On a mismatch
+/// the tries and the [root registry](root_registry) are left at the last block both versions agree
+/// on, and new blocks must not be accepted: the regression must be fixed (or the release rolled
+/// back) and the node resynced from there.
+///
+/// # Arguments
+///
+/// * `writer` - The write handle of the tries the blocks were committed to.
+/// * `range`  - The blocks to reverify, which must all have been committed.
+/// * `diffs`  - Loads the state diff of a block.
+/// * `config` - Chain-specific commitment rules.
+pub fn shadow_reverify_on_upgrade<E: Display>(
+    writer: &mut WriteHandle,
+    range: RangeInclusive<u64>,
+    mut diffs: impl FnMut(u64) -> Result<CommitmentStateDiff, E>,
+    config: &ChainConfig,
+) -> Result<(), UpgradeError> {
+    let previous_block = range.start().checked_sub(1).ok_or(UpgradeError::Genesis)?;
+
+    let mut stored = Vec::new();
+    for block_number in *range.start().. {
+        match state_root_at(block_number)? {
+            Some(state_root) => stored.push((block_number, state_root)),
+            None if range.contains(&block_number) => return Err(UpgradeError::NotCommitted(block_number)),
+            None => break,
+        }
+    }
+    let stored = stored
+        .into_iter()
+        .map(|(block_number, state_root)| match diffs(block_number) {
+            Ok(csd) => Ok((block_number, state_root, csd)),
+            Err(e) => Err(UpgradeError::Diff { block_number, error: e.to_string() }),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    root_registry(writer.tries()).truncate(previous_block);
+    revert_to(writer.tries(), previous_block)?;
+
+    for (block_number, state_root, csd) in stored {
+        let recomputed = writer.commit(csd, block_number, config)?;
+        if recomputed != state_root {
+            root_registry(writer.tries()).truncate(block_number - 1);
+            revert_to(writer.tries(), block_number - 1)?;
+            return Err(UpgradeError::Regression { block_number, stored: state_root, recomputed });
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs [shadow_reverify_on_upgrade] over the `depth` latest committed blocks if the version of this
+/// crate changed since the last run, as recorded in the `marker` file.
+///
+/// This must run at startup, before new blocks are accepted. The marker is only updated once the
+/// reverification succeeded, so a failed upgrade is reverified again on the next start. With the
+/// `class-verification` feature, the stored classes are recompiled in the background as well, see
+/// [ReverifyReport::class_audit].
+///
+/// # Arguments
+///
+/// * `writer` - The write handle of the tries the blocks were committed to.
+/// * `marker` - The file recording the version which last ran.
+/// * `latest` - The latest block committed to the tries, as recorded by the node along with its
+///   blocks, `None` if nothing was committed yet.
+/// * `depth`  - Number of blocks to reverify.
+/// * `diffs`  - Loads the state diff of a block.
+/// * `config` - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The reverified blocks, or `None` if the version did not change (or nothing was committed yet).
+pub fn reverify_on_upgrade<E: Display>(
+    writer: &mut WriteHandle,
+    marker: impl AsRef<Path>,
+    latest: Option<u64>,
+    depth: u64,
+    diffs: impl FnMut(u64) -> Result<CommitmentStateDiff, E>,
+    config: &ChainConfig,
+) -> Result<Option<ReverifyReport>, UpgradeError> {
+    let marker = marker.as_ref();
+    let previous_version = match fs::read_to_string(marker) {
+        Ok(version) => Some(version.trim().to_string()),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if previous_version.as_deref() == Some(CRATE_VERSION) {
+        return Ok(None);
+    }
+
+    let report = match latest {
+        Some(latest) if depth > 0 => {
+            // Genesis has no previous state to revert to
+            let blocks = latest.saturating_sub(depth - 1).max(1)..=latest;
+            if !blocks.is_empty() {
+                shadow_reverify_on_upgrade(writer, blocks.clone(), diffs, config)?;
+            }
+            Some(ReverifyReport {
+                previous_version,
+                blocks,
+                #[cfg(feature = "class-verification")]
+                class_audit: class_store().map(|store| spawn_recompilation_audit(store, stored_compiled_class_hash)),
+            })
+        }
+        _ => None,
+    };
+
+    fs::write(marker, CRATE_VERSION)?;
+    Ok(report)
+}
@@ -0,0 +1,264 @@
+//! Trust-minimized reads of storage slots, for dapps which only trust a state root.
+//!
+//! [verified_read] fetches the proofs of a batch of slots from a [ProofSource], the local tries or
+//! a remote node, verifies them against the trusted root and returns each value along with where
+//! and at which block it was proven. Slots of the same contract share a single proof request.
+
+use indexmap::IndexMap;
+use serde_json::Value;
+use starknet_api::core::ContractAddress;
+use starknet_api::state::StorageKey;
+use starknet_types_core::felt::Felt;
+
+use super::config::ChainConfig;
+use super::engine::{CommitmentEngine, StateTries};
+use super::error::TrieError;
+use super::proof::ProofError;
+use super::proof_format::ProofFormatError;
+use super::storage_proof::{get_storage_proof, StorageProof, StorageProofError};
+
+/// Where the proof of a value was fetched from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provenance {
+    Local,
+    Remote { endpoint: String },
+}
+
+/// Provider of the storage proofs of a contract.
+pub trait ProofSource {
+    type Error: std::error::Error;
+
+    fn provenance(&self) -> Provenance;
+
+    fn storage_proof(
+        &self,
+        contract_address: &ContractAddress,
+        keys: &[StorageKey],
+        config: &ChainConfig,
+    ) -> Result<StorageProof, Self::Error>;
+}
+
+impl ProofSource for CommitmentEngine {
+    type Error = TrieError;
+
+    fn provenance(&self) -> Provenance {
+        Provenance::Local
+    }
+
+    fn storage_proof(
+        &self,
+        contract_address: &ContractAddress,
+        keys: &[StorageKey],
+        config: &ChainConfig,
+    ) -> Result<StorageProof, TrieError> {
+        CommitmentEngine::storage_proof(self, contract_address, keys, config)
+    }
+}
+
+/// Committed tries, read at `block_number`, see [get_storage_proof].
+#[derive(Debug, Clone)]
+pub struct NodeTries {
+    pub tries: StateTries,
+    pub block_number: u64,
+}
+
+impl ProofSource for NodeTries {
+    type Error = StorageProofError;
+
+    fn provenance(&self) -> Provenance {
+        Provenance::Local
+    }
+
+    fn storage_proof(
+        &self,
+        contract_address: &ContractAddress,
+        keys: &[StorageKey],
+        config: &ChainConfig,
+    ) -> Result<StorageProof, StorageProofError> {
+        get_storage_proof(&self.tries, contract_address, keys, self.block_number, config)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RemoteProofError {
+    #[error("request failed: {0}")]
+    Fetch(String),
+    #[error(transparent)]
+    Format(#[from] ProofFormatError),
+}
+
+/// A remote node serving `starknet_getStorageProof` (RPC 0.8).
+///
+/// The transport is left to the caller: `fetch` sends the request for a contract and its keys at
+/// `block_number` and returns the JSON result.
+pub struct RemoteProofs<F> {
+    pub endpoint: String,
+    pub block_number: u64,
+    pub fetch: F,
+}
+
+impl<F> ProofSource for RemoteProofs<F>
+where
+    F: Fn(&ContractAddress, &[StorageKey]) -> Result<Value, String>,
+{
+    type Error = RemoteProofError;
+
+    fn provenance(&self) -> Provenance {
+        Provenance::Remote { endpoint: self.endpoint.clone() }
+    }
+
+    fn storage_proof(
+        &self,
+        contract_address: &ContractAddress,
+        keys: &[StorageKey],
+        config: &ChainConfig,
+    ) -> Result<StorageProof, RemoteProofError> {
+        let response = (self.fetch)(contract_address, keys).map_err(RemoteProofError::Fetch)?;
+        Ok(StorageProof::from_rpc_json(&response, *contract_address, keys, self.block_number, config)?)
+    }
+}
+
+/// The value of a storage slot, proven against a trusted state root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedValue {
+    pub contract_address: ContractAddress,
+    pub key: StorageKey,
+    /// Zero if the slot was never written.
+    pub value: Felt,
+    /// The block the source generated the proof at.
+    pub block_number: u64,
+    pub state_root: Felt,
+    pub provenance: Provenance,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifiedReadError {
+    #[error("failed to fetch the proof of contract {contract_address:?}: {error}")]
+    Source { contract_address: ContractAddress, error: String },
+    #[error("proof of contract {contract_address:?} is against state root {proven:#x}, expected {expected:#x}")]
+    RootMismatch { contract_address: ContractAddress, expected: Felt, proven: Felt },
+    #[error("proof of contract {contract_address:?} does not verify: {source}")]
+    Proof { contract_address: ContractAddress, source: ProofError },
+}
+
+/// Reads storage slots from `source`, verifying every value against `state_root`.
+///
+/// # Arguments
+///
+/// * `source`     - Where to fetch the proofs from, which does not need to be trusted.
+/// * `state_root` - The trusted state root, ie: the one of a block settled on L1.
+/// * `reads`      - The slots to read, as `(contract, key)` pairs.
+/// * `config`     - Chain-specific commitment rules, for the state commitment.
+///
+/// # Returns
+///
+/// The value of each slot, in the order of `reads`. A single value which cannot be verified fails
+/// the whole read.
+pub fn verified_read<S: ProofSource>(
+    source: &S,
+    state_root: Felt,
+    reads: Vec<(ContractAddress, StorageKey)>,
+    config: &ChainConfig,
+) -> Result<Vec<VerifiedValue>, VerifiedReadError> {
+    let mut by_contract = IndexMap::<ContractAddress, Vec<usize>>::new();
+    for (index, (contract_address, _)) in reads.iter().enumerate() {
+        by_contract.entry(*contract_address).or_default().push(index);
+    }
+
+    let provenance = source.provenance();
+    let mut values = vec![None; reads.len()];
+    for (contract_address, indices) in by_contract {
+        let keys = indices.iter().map(|index| reads[*index].1).collect::<Vec<_>>();
+        let proof = source
+            .storage_proof(&contract_address, &keys, config)
+            .map_err(|e| VerifiedReadError::Source { contract_address, error: e.to_string() })?;
+        if proof.state_commitment != state_root {
+            return Err(VerifiedReadError::RootMismatch {
+                contract_address,
+                expected: state_root,
+                proven: proof.state_commitment,
+            });
+        }
+        let proven = proof
+            .verify(state_root, &keys, config)
+            .map_err(|source| VerifiedReadError::Proof { contract_address, source })?;
+
+        for (index, value) in indices.into_iter().zip(proven) {
+            values[index] = Some(VerifiedValue {
+                contract_address,
+                key: reads[index].1,
+                value,
+                block_number: proof.block_number,
+                state_root,
+                provenance: provenance.clone(),
+            });
+        }
+    }
+    Ok(values.into_iter().map(|value| value.expect("every read was proven with its contract")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use starknet_api::core::{ClassHash, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+
+    fn address(n: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn key(n: u64) -> StorageKey {
+        StorageKey(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    #[test]
+    fn test_verified_read() {
+        let config = ChainConfig::default();
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: [(address(1), ClassHash(StarkFelt::TWO)), (address(2), ClassHash(StarkFelt::TWO))]
+                .into_iter()
+                .collect(),
+            address_to_nonce: Default::default(),
+            storage_updates: [
+                (address(1), [(key(1), StarkFelt::from(10_u64))].into_iter().collect()),
+                (address(2), [(key(1), StarkFelt::from(20_u64))].into_iter().collect()),
+            ]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        let state_root = Felt::from(engine.update_state_root(csd, 0, &config).unwrap());
+
+        let reads = vec![(address(2), key(1)), (address(1), key(1)), (address(3), key(1)), (address(1), key(2))];
+        let values = verified_read(&engine, state_root, reads.clone(), &config).unwrap();
+        let proven = values.iter().map(|value| value.value).collect::<Vec<_>>();
+        assert_eq!(proven, vec![Felt::from(20_u64), Felt::from(10_u64), Felt::ZERO, Felt::ZERO]);
+        assert_eq!(values[1].key, key(1));
+        assert_eq!(values[1].provenance, Provenance::Local);
+
+        let remote = RemoteProofs {
+            endpoint: "http://localhost:9944".to_string(),
+            block_number: 0,
+            fetch: |contract_address: &ContractAddress, keys: &[StorageKey]| {
+                engine
+                    .storage_proof(contract_address, keys, &config)
+                    .map(|proof| proof.to_rpc_json())
+                    .map_err(|e| e.to_string())
+            },
+        };
+        let remote_values = verified_read(&remote, state_root, reads.clone(), &config).unwrap();
+        assert_eq!(remote_values.iter().map(|value| value.value).collect::<Vec<_>>(), proven);
+        assert_eq!(remote_values[0].provenance, Provenance::Remote { endpoint: "http://localhost:9944".to_string() });
+
+        assert!(matches!(
+            verified_read(&engine, Felt::ONE, reads, &config),
+            Err(VerifiedReadError::RootMismatch { expected: Felt::ONE, .. })
+        ));
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mc_db::storage_handler;
+use starknet_api::core::ContractAddress;
+use starknet_types_core::felt::Felt;
+pub use starkroot_types::roots::RootDiff;
+
+use super::atomic::Trie;
+use super::error::{CommitError, ErrorContext, ResultExt, TrieError};
+use super::handles::WriteHandle;
+use super::reorg::RevertError;
+use super::retry::Operation;
+use super::roots::root_registry;
+use super::runtime::current_chain_config;
+
+/// The roots a block is expected to lead to, ie: as fetched from the feeder gateway or a peer.
+///
+/// Only the state root is mandatory. The trie roots and contract storage roots, when known (ie: from
+/// a `pathfinder_getProof` response), narrow down where a mismatch comes from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpectedRoots {
+    pub state_root: Felt,
+    pub contracts_trie_root: Option<Felt>,
+    pub classes_trie_root: Option<Felt>,
+    pub contract_storage_roots: BTreeMap<ContractAddress, Felt>,
+}
+
+impl ExpectedRoots {
+    pub fn new(state_root: Felt) -> Self {
+        Self { state_root, ..Default::default() }
+    }
+}
+
+/// Where a locally computed state root disagrees with the expected one.
+///
+/// Trie and contract storage roots are only compared when they were [expected](ExpectedRoots).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootMismatch {
+    pub block_number: u64,
+    pub state_root: RootDiff,
+    pub contracts_trie: Option<RootDiff>,
+    pub classes_trie: Option<RootDiff>,
+    /// The contracts of the diff whose storage root disagrees.
+    pub contract_storage: BTreeMap<ContractAddress, RootDiff>,
+}
+
+impl fmt::Display for RootMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let RootDiff { expected, computed } = self.state_root;
+        write!(f, "block {}: expected state root {expected:#x}, computed {computed:#x}", self.block_number)?;
+        for (trie, diff) in [("contracts", self.contracts_trie), ("classes", self.classes_trie)] {
+            if let Some(RootDiff { expected, computed }) = diff {
+                write!(f, ", {trie} trie root {computed:#x} instead of {expected:#x}")?;
+            }
+        }
+        for (contract_address, RootDiff { expected, computed }) in self.contract_storage.iter() {
+            write!(f, ", storage root of {contract_address:?} {computed:#x} instead of {expected:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Commit(#[from] CommitError),
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+    #[error("state root mismatch, {0}")]
+    Mismatch(Box<RootMismatch>),
+    #[error("state root mismatch, {mismatch}, and the block could not be reverted: {error}")]
+    Unreverted { mismatch: Box<RootMismatch>, error: RevertError },
+}
+
+/// Commits a block and checks the resulting state root against the expected one.
+///
+/// On a mismatch, the contracts and classes trie roots and the storage roots of the contracts of
+/// the diff are compared with those which were expected, so that the caller knows where to look.
+/// The block is then [reverted](WriteHandle::revert_to) if it is the latest committed block, so that a wrong
+/// state never stays committed and the block can be retried once the diff is fixed. The genesis
+/// block cannot be reverted.
+///
+/// # Arguments
+///
+/// * `writer`       - The write handle of the tries to commit the block to.
+/// * `expected`     - The roots the block is expected to lead to.
+/// * `csd`          - The commitment state diff of the block.
+/// * `block_number` - The block number.
+pub fn verify_state_root(
+    writer: &mut WriteHandle,
+    expected: &ExpectedRoots,
+    csd: CommitmentStateDiff,
+    block_number: u64,
+) -> Result<(), VerifyError> {
+    let config = current_chain_config();
+    let contract_addresses = csd.storage_updates.keys().copied().collect::<Vec<_>>();
+
+    let state_root = Felt::from(writer.commit(csd, block_number, &config)?);
+    let Some(state_root) = RootDiff::new(expected.state_root, state_root) else {
+        return Ok(());
+    };
+
+    // The roots are read at the block, which may not be the latest one if it was already committed
+    let context = |trie| move || ErrorContext::block(block_number).trie(trie);
+    let handler_contract = storage_handler::contract_trie();
+    let handler_class = storage_handler::class_trie();
+    let contracts_trie = match expected.contracts_trie_root {
+        Some(expected) => {
+            let root = config
+                .retry
+                .run(Operation::Read, || handler_contract.root_at(block_number))
+                .context(context(Trie::Contracts))?;
+            RootDiff::new(expected, root.unwrap_or_default())
+        }
+        None => None,
+    };
+    let classes_trie = match expected.classes_trie_root {
+        Some(expected) => {
+            let root = config
+                .retry
+                .run(Operation::Read, || handler_class.root_at(block_number))
+                .context(context(Trie::Classes))?;
+            RootDiff::new(expected, root.unwrap_or_default())
+        }
+        None => None,
+    };
+    drop((handler_contract, handler_class));
+
+    let mut contract_storage = BTreeMap::new();
+    let handler_storage_trie = storage_handler::contract_storage_trie();
+    for contract_address in contract_addresses {
+        let Some(expected) = expected.contract_storage_roots.get(&contract_address) else {
+            continue;
+        };
+        let computed = config
+            .retry
+            .run(Operation::Read, || handler_storage_trie.root_at(&contract_address, block_number))
+            .context(|| context(Trie::ContractStorage)().contract(contract_address))?;
+        if let Some(diff) = RootDiff::new(*expected, computed) {
+            contract_storage.insert(contract_address, diff);
+        }
+    }
+
+    let mismatch = Box::new(RootMismatch { block_number, state_root, contracts_trie, classes_trie, contract_storage });
+    let latest = root_registry(writer.tries()).latest().map(|(latest, _)| latest);
+    match block_number.checked_sub(1) {
+        Some(previous_block) if latest == Some(block_number) => match writer.revert_to(previous_block) {
+            Ok(_) => Err(VerifyError::Mismatch(mismatch)),
+            Err(error) => Err(VerifyError::Unreverted { mismatch, error }),
+        },
+        _ => Err(VerifyError::Mismatch(mismatch)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_api::core::PatriciaKey;
+    use starknet_api::hash::StarkFelt;
+
+    use super::*;
+
+    #[test]
+    fn test_root_mismatch_display() {
+        let contract_address = ContractAddress(PatriciaKey(StarkFelt::ONE));
+        let mismatch = RootMismatch {
+            block_number: 7,
+            state_root: RootDiff { expected: Felt::ONE, computed: Felt::TWO },
+            contracts_trie: None,
+            classes_trie: RootDiff::new(Felt::THREE, Felt::ZERO),
+            contract_storage: [(contract_address, RootDiff { expected: Felt::ONE, computed: Felt::ZERO })]
+                .into_iter()
+                .collect(),
+        };
+
+        let message = mismatch.to_string();
+        assert!(
+            message.starts_with("block 7: expected state root 0x1, computed 0x2, classes trie root 0x0 instead of 0x3")
+        );
+        assert!(!message.contains("contracts trie"));
+        assert!(message.contains("storage root of"));
+        assert_eq!(RootDiff::new(Felt::ONE, Felt::ONE), None);
+    }
+}
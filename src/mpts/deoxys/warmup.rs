@@ -0,0 +1,92 @@
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+use blockifier::state::cached_state::CommitmentStateDiff;
+use mc_db::storage_handler;
+use starknet_api::core::ContractAddress;
+
+use super::error::TrieError;
+use super::history::ContractActivity;
+use super::runtime::current_config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WarmupError {
+    #[error("failed to load the state diff of block {block_number}: {error}")]
+    Diff { block_number: u64, error: String },
+    #[error(transparent)]
+    Trie(#[from] TrieError),
+}
+
+/// Outcome of a [warmup].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WarmupReport {
+    /// Number of contracts whose trie paths were loaded.
+    pub contracts: usize,
+    /// Number of trie nodes read.
+    pub nodes: usize,
+    pub elapsed: Duration,
+}
+
+/// Preloads the upper trie nodes of the hottest contracts after a restart.
+///
+/// The first blocks after a reboot otherwise pay for reading every node on the path of each updated
+/// contract from disk. This reads the contracts trie path and the storage root of the `top_n`
+/// contracts updated in the most blocks of `blocks`, so that they are in the backend caches by the
+/// time the next block is committed.
+///
+/// The in-memory [contract activity index](super::history::contract_activity) is empty at startup,
+/// so the contracts are ranked from the state diffs of the blocks, as stored by the node.
+///
+/// # Arguments
+///
+/// * `blocks`          - The latest committed blocks, ie: the last thousand.
+/// * `top_n_contracts` - The number of contracts to warm up.
+/// * `diffs`           - Loads the state diff of a block.
+///
+/// # Returns
+///
+/// What was loaded.
+pub fn warmup<E: Display>(
+    blocks: RangeInclusive<u64>,
+    top_n_contracts: usize,
+    mut diffs: impl FnMut(u64) -> Result<CommitmentStateDiff, E>,
+) -> Result<WarmupReport, WarmupError> {
+    let start = Instant::now();
+    let mut activity = ContractActivity::default();
+    for block_number in blocks {
+        let csd = diffs(block_number).map_err(|e| WarmupError::Diff { block_number, error: e.to_string() })?;
+        activity.record(block_number, &csd);
+    }
+
+    let report = warmup_contracts(&activity.most_active(top_n_contracts))?;
+    Ok(WarmupReport { elapsed: start.elapsed(), ..report })
+}
+
+/// Runs [warmup] over the `caches.warmup_contracts` most active contracts of the current
+/// configuration, none by default.
+pub fn warmup_configured<E: Display>(
+    blocks: RangeInclusive<u64>,
+    diffs: impl FnMut(u64) -> Result<CommitmentStateDiff, E>,
+) -> Result<WarmupReport, WarmupError> {
+    match current_config().caches.warmup_contracts {
+        0 => Ok(WarmupReport::default()),
+        top_n_contracts => warmup(blocks, top_n_contracts, diffs),
+    }
+}
+
+/// Preloads the upper trie nodes of `contracts`, see [warmup].
+pub fn warmup_contracts(contracts: &[ContractAddress]) -> Result<WarmupReport, TrieError> {
+    let start = Instant::now();
+    let handler_contract = storage_handler::contract_trie();
+    let handler_storage_trie = storage_handler::contract_storage_trie();
+    let mut nodes = 0;
+    for contract_address in contracts.iter() {
+        // a proof walks every node from the root to the contract's leaf
+        nodes += handler_contract.get_proof(contract_address)?.len();
+        handler_storage_trie.root(contract_address)?;
+        nodes += 1;
+    }
+
+    Ok(WarmupReport { contracts: contracts.len(), nodes, elapsed: start.elapsed() })
+}
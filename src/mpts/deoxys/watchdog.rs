@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+
+use super::engine::StateTries;
+
+/// Number of SLA breaches kept in memory.
+const BREACH_LOG_CAPACITY: usize = 256;
+
+/// Phases of a block commit, see [WriteHandle::commit](super::handles::WriteHandle::commit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommitPhase {
+    /// Reading the tries to compute the write statistics.
+    Stats,
+    /// Updating and committing the contract storage tries and the contracts trie.
+    Contracts,
+    /// Updating and committing the classes trie, concurrently with [CommitPhase::Contracts].
+    Classes,
+    /// Recording the block in the optional indexes.
+    Indexes,
+    /// Canary verification of the new roots.
+    Canary,
+}
+
+/// Time spent in each phase of a block commit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PhaseTimings {
+    pub phases: Vec<(CommitPhase, Duration)>,
+    /// Wall-clock time of the whole commit. Phases may overlap, so this is not their sum.
+    pub total: Duration,
+}
+
+impl PhaseTimings {
+    pub fn record(&mut self, phase: CommitPhase, elapsed: Duration) {
+        self.phases.push((phase, elapsed));
+    }
+
+    /// The phase which took the longest.
+    pub fn slowest(&self) -> Option<(CommitPhase, Duration)> {
+        self.phases.iter().copied().max_by_key(|(_, elapsed)| *elapsed)
+    }
+}
+
+/// A block whose commit exceeded the SLA.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlaBreach {
+    pub block_number: u64,
+    pub sla: Duration,
+    pub timings: PhaseTimings,
+}
+
+type BreachHook = Arc<dyn Fn(&SlaBreach) + Send + Sync>;
+
+/// Measures block commit latency against the SLA configured in
+/// [ChainConfig::commit_sla](super::config::ChainConfig::commit_sla).
+///
+/// Breaches are logged as warnings along with their phase breakdown, kept in a bounded log, and passed
+/// to the registered hooks. Hooks are where the embedding node reacts, ie: by growing its caches or
+/// changing the size of its thread pool.
+///
+/// Hooks are called once the commit released its locks, so that they can call back into the API.
+#[derive(Default)]
+pub struct SlaWatchdog {
+    observed: u64,
+    breaches: VecDeque<SlaBreach>,
+    breach_count: u64,
+    hooks: Vec<BreachHook>,
+}
+
+impl SlaWatchdog {
+    /// Registers a hook called on every breach.
+    pub fn on_breach(&mut self, hook: impl Fn(&SlaBreach) + Send + Sync + 'static) {
+        self.hooks.push(Arc::new(hook));
+    }
+
+    /// Records the timings of a block commit, logging a warning if it exceeded `sla`.
+    ///
+    /// The hooks are not called, see [notify_breach].
+    ///
+    /// # Returns
+    ///
+    /// The breach, if the commit exceeded `sla`.
+    pub fn observe(&mut self, block_number: u64, sla: Duration, timings: PhaseTimings) -> Option<SlaBreach> {
+        self.observed += 1;
+        if timings.total <= sla {
+            return None;
+        }
+
+        let breach = SlaBreach { block_number, sla, timings };
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            block_number,
+            ?sla,
+            total = ?breach.timings.total,
+            phases = ?breach.timings.phases,
+            "Block commit exceeded the SLA"
+        );
+        self.breach_count += 1;
+        if self.breaches.len() == BREACH_LOG_CAPACITY {
+            self.breaches.pop_front();
+        }
+        self.breaches.push_back(breach.clone());
+        Some(breach)
+    }
+
+    /// Number of commits observed and how many of them exceeded the SLA.
+    pub fn counts(&self) -> (u64, u64) {
+        (self.observed, self.breach_count)
+    }
+
+    /// The latest breaches, oldest first.
+    pub fn breaches(&self) -> impl Iterator<Item = &SlaBreach> {
+        self.breaches.iter()
+    }
+}
+
+/// Returns the [SlaWatchdog] of the commits to `tries`.
+pub fn sla_watchdog(tries: &StateTries) -> MutexGuard<'_, SlaWatchdog> {
+    tries.state().sla_watchdog.lock().expect("Poisoned lock on SLA watchdog")
+}
+
+/// Passes a breach [observed](SlaWatchdog::observe) by the watchdog of `tries` to its hooks.
+///
+/// Must be called without holding any of the commit locks, which the hooks may take.
+pub(crate) fn notify_breach(tries: &StateTries, breach: &SlaBreach) {
+    let hooks = sla_watchdog(tries).hooks.clone();
+    for hook in hooks {
+        hook(breach);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use blockifier::state::cached_state::CommitmentStateDiff;
+    use starknet_api::core::{ContractAddress, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::config::ChainConfig;
+    use crate::mpts::deoxys::engine::StateTries;
+    use crate::mpts::deoxys::lib::try_update_state_root;
+    use crate::mpts::deoxys::roots::root_registry;
+
+    fn timings(total: u64) -> PhaseTimings {
+        let mut timings = PhaseTimings { total: Duration::from_millis(total), ..Default::default() };
+        timings.record(CommitPhase::Stats, Duration::from_millis(total / 4));
+        timings.record(CommitPhase::Contracts, Duration::from_millis(total / 2));
+        timings
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let mut watchdog = SlaWatchdog::default();
+
+        let sla = Duration::from_millis(100);
+        assert_eq!(watchdog.observe(1, sla, timings(50)), None);
+        let breach = watchdog.observe(2, sla, timings(200)).unwrap();
+        assert_eq!(breach.block_number, 2);
+
+        assert_eq!(watchdog.counts(), (2, 1));
+        let breach = watchdog.breaches().next().unwrap();
+        assert_eq!(breach.timings.slowest(), Some((CommitPhase::Contracts, Duration::from_millis(100))));
+    }
+
+    #[test]
+    fn test_hooks_call_back_into_the_api() {
+        let tries = StateTries::open(Arc::new(MemoryBackend::new())).unwrap();
+
+        // The hook reads the registry and the watchdog, which would deadlock if it was called while
+        // the commit holds them
+        let breached = Arc::new(AtomicU64::new(0));
+        let hook_breached = Arc::clone(&breached);
+        let hook_tries = tries.clone();
+        sla_watchdog(&tries).on_breach(move |breach| {
+            if breach.block_number == 1 && root_registry(&hook_tries).get(1).is_some() {
+                hook_breached.store(sla_watchdog(&hook_tries).counts().1, Ordering::Relaxed);
+            }
+        });
+
+        let config = ChainConfig { commit_sla: Some(Duration::ZERO), ..Default::default() };
+        let csd = CommitmentStateDiff {
+            address_to_class_hash: Default::default(),
+            address_to_nonce: Default::default(),
+            storage_updates: [(
+                ContractAddress(PatriciaKey(StarkFelt::from(0x11_u64))),
+                [(StorageKey(PatriciaKey(StarkFelt::ONE)), StarkFelt::ONE)].into(),
+            )]
+            .into_iter()
+            .collect(),
+            class_hash_to_compiled_class_hash: Default::default(),
+        };
+        try_update_state_root(&tries, csd, 1, &config).unwrap();
+        assert_ne!(breached.load(Ordering::Relaxed), 0);
+        assert_eq!(sla_watchdog(&tries).breaches().last().unwrap().block_number, 1);
+    }
+}
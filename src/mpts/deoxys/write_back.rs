@@ -0,0 +1,150 @@
+use blockifier::state::cached_state::{CachedState, CommitmentStateDiff};
+use blockifier::state::state_api::StateReader;
+use mp_felt::Felt252Wrapper;
+
+use super::alias::AliasMapping;
+use super::canonical::Canonicalize;
+use super::config::ChainConfig;
+use super::conversions::{validate_trie_keys, ConversionError};
+use super::handles::WriteHandle;
+
+/// Extracts the writes of an executed block as a [CommitmentStateDiff] ready to be committed.
+///
+/// This closes the execute -> commit loop: execute the block with a [CachedState] over a
+/// [BlockifierStateAdapter](super::blockifier_reader::BlockifierStateAdapter), then commit the
+/// resulting diff.
+///
+/// # Arguments
+///
+/// * `state`   - The cached state the block was executed against.
+/// * `aliases` - The stateful compression alias mapping, if enabled on this chain. Aliases for the
+///               new addresses and keys are allocated and written to the alias contract.
+///
+/// # Returns
+///
+/// The canonicalized commitment state diff of the block.
+pub fn execution_state_diff<S: StateReader>(
+    state: &mut CachedState<S>,
+    aliases: Option<&mut AliasMapping>,
+) -> Result<CommitmentStateDiff, ConversionError> {
+    let mut csd = state.to_state_diff();
+    validate_trie_keys(&csd)?;
+
+    if let Some(aliases) = aliases {
+        aliases.allocate(&mut csd);
+    }
+
+    csd.canonicalize();
+    Ok(csd)
+}
+
+/// Commits the writes of an executed block and returns the new state root.
+///
+/// # Arguments
+///
+/// * `writer`       - The write handle of the tries to commit the block to.
+/// * `state`        - The cached state the block was executed against.
+/// * `aliases`      - The stateful compression alias mapping, if enabled on this chain.
+/// * `block_number` - The number of the executed block.
+/// * `config`       - Chain-specific commitment rules.
+///
+/// # Returns
+///
+/// The updated state root as a `Felt252Wrapper`.
+pub fn commit_execution<S: StateReader>(
+    writer: &mut WriteHandle,
+    state: &mut CachedState<S>,
+    aliases: Option<&mut AliasMapping>,
+    block_number: u64,
+    config: &ChainConfig,
+) -> Result<Felt252Wrapper, ConversionError> {
+    let csd = execution_state_diff(state, aliases)?;
+    Ok(writer.commit(csd, block_number, config).expect("Failed to update state root"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use blockifier::state::state_api::State;
+    use indexmap::IndexMap;
+    use starknet_api::core::{ClassHash, ContractAddress, Nonce, PatriciaKey};
+    use starknet_api::hash::StarkFelt;
+    use starknet_api::state::StorageKey;
+
+    use super::*;
+    use crate::mpts::deoxys::alias::ALIAS_CONTRACT_ADDRESS;
+    use crate::mpts::deoxys::backend::MemoryBackend;
+    use crate::mpts::deoxys::blockifier_reader::BlockifierStateAdapter;
+    use crate::mpts::deoxys::engine::{CommitmentEngine, StateTries};
+    use crate::mpts::deoxys::state_reader::TrieStateReader;
+
+    fn address(n: u64) -> ContractAddress {
+        ContractAddress(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn key(n: u64) -> StorageKey {
+        StorageKey(PatriciaKey(StarkFelt::from(n)))
+    }
+
+    fn deploy() -> CommitmentStateDiff {
+        CommitmentStateDiff {
+            address_to_class_hash: [(address(0x1000), ClassHash(StarkFelt::TWO))].into_iter().collect(),
+            address_to_nonce: IndexMap::new(),
+            storage_updates: [(address(0x1000), [(key(1), StarkFelt::from(10_u64))].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            class_hash_to_compiled_class_hash: IndexMap::new(),
+        }
+    }
+
+    /// Executes a block over the state right after [deploy]: a slot is written, the nonce of the
+    /// deployed contract is incremented and a second contract is deployed.
+    fn execute(engine: &CommitmentEngine) -> CachedState<BlockifierStateAdapter<TrieStateReader>> {
+        let reader = TrieStateReader::at(engine, 0).unwrap().unwrap();
+        let mut state = CachedState::from(BlockifierStateAdapter::new(reader));
+        state.set_storage_at(address(0x1000), key(2), StarkFelt::from(20_u64)).unwrap();
+        state.increment_nonce(address(0x1000)).unwrap();
+        state.set_class_hash_at(address(0x2000), ClassHash(StarkFelt::THREE)).unwrap();
+        state
+    }
+
+    fn engine(config: &ChainConfig) -> CommitmentEngine {
+        let mut engine = CommitmentEngine::new(Arc::new(MemoryBackend::new())).unwrap();
+        engine.update_state_root(deploy(), 0, config).unwrap();
+        engine
+    }
+
+    #[test]
+    fn test_execution_state_diff() {
+        let engine = engine(&ChainConfig::default());
+
+        let csd = execution_state_diff(&mut execute(&engine), None).unwrap();
+        assert_eq!(csd.storage_updates[&address(0x1000)], [(key(2), StarkFelt::from(20_u64))].into_iter().collect());
+        assert_eq!(csd.address_to_nonce[&address(0x1000)], Nonce(StarkFelt::ONE));
+        assert_eq!(csd.address_to_class_hash[&address(0x2000)], ClassHash(StarkFelt::THREE));
+        assert!(csd.class_hash_to_compiled_class_hash.is_empty());
+
+        // The aliases of the modified contracts are written to the alias contract
+        let mut aliases = AliasMapping::default();
+        let csd = execution_state_diff(&mut execute(&engine), Some(&mut aliases)).unwrap();
+        assert_eq!(aliases.alias_of(address(0x1000).0.key()), Some(StarkFelt::from(128_u64)));
+        assert_eq!(aliases.alias_of(address(0x2000).0.key()), Some(StarkFelt::from(129_u64)));
+        assert_eq!(aliases.alias_of(key(2).0.key()), None);
+        assert!(csd.storage_updates.contains_key(&address(ALIAS_CONTRACT_ADDRESS)));
+    }
+
+    #[test]
+    fn test_commit_execution() {
+        let config = ChainConfig::default();
+        let mut engine = engine(&config);
+
+        let mut writer = WriteHandle::acquire(StateTries::open(Arc::new(MemoryBackend::new())).unwrap()).unwrap();
+        writer.commit(deploy(), 0, &config).unwrap();
+        let state_root = commit_execution(&mut writer, &mut execute(&engine), None, 1, &config).unwrap();
+
+        // The committed root is the one of the executed writes
+        let csd = execution_state_diff(&mut execute(&engine), None).unwrap();
+        assert_eq!(state_root, engine.update_state_root(csd, 1, &config).unwrap());
+    }
+}
@@ -1,2 +1,3 @@
-mod deoxys;
-mod pathfinder;
\ No newline at end of file
+pub mod deoxys;
+#[cfg(feature = "pedersen")]
+pub mod pathfinder;
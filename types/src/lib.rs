@@ -0,0 +1,14 @@
+//! The types starkroot exchanges with the rest of a Starknet stack: felts, state roots and Merkle
+//! proofs.
+//!
+//! RPC servers, indexers and provers which only pass these types around can depend on this crate
+//! instead of the commitment engine, which pulls in the Madara and Pathfinder storage stacks.
+//! starkroot re-exports them under their previous paths.
+//!
+//! The public types follow semver: changing them in a breaking way bumps the minor version while
+//! the crate is in 0.x. Error enums are `#[non_exhaustive]` so that new variants are not breaking.
+
+pub mod proof;
+pub mod roots;
+
+pub use starknet_types_core::felt::Felt;
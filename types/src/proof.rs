@@ -0,0 +1,175 @@
+//! Merkle proofs of the state tries.
+
+use bitvec::prelude::{BitSlice, BitVec, Msb0};
+use bitvec::view::BitView;
+use starknet_types_core::hash::StarkHash;
+
+use crate::Felt;
+
+/// A node of a Merkle proof, as stored in the state tries.
+///
+/// Proofs are ordered from the root down to the leaf (or to the node proving the key is absent).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofNode {
+    Binary { left: Felt, right: Felt },
+    Edge { child: Felt, path: BitVec<u8, Msb0> },
+}
+
+/// Errors raised when a proof does not match the root it is verified against.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ProofError {
+    #[error("proof node {index} hashes to {computed:#x}, expected {expected:#x}")]
+    HashMismatch { index: usize, expected: Felt, computed: Felt },
+    #[error("proof ends at height {height} before reaching a leaf")]
+    Incomplete { height: usize },
+    #[error("proof continues after reaching a leaf or diverging edge")]
+    TrailingNodes,
+    #[error("proof for an empty trie must be empty")]
+    EmptyTrie,
+    #[error("trie roots hash to state root {computed:#x}, expected {expected:#x}")]
+    StateRootMismatch { expected: Felt, computed: Felt },
+    #[error("proof proves {proven:?} but expected {expected:?}")]
+    ValueMismatch { expected: Option<Felt>, proven: Option<Felt> },
+    #[error("expected {expected} proofs, got {actual}")]
+    ProofCount { expected: usize, actual: usize },
+    #[error("proof node {index} has an edge of {length} bits at height {height}, past the {key_length} bits key")]
+    EdgeOverflow { index: usize, height: usize, length: usize, key_length: usize },
+}
+
+/// Converts a path of at most 251 bits to the felt it encodes.
+///
+/// # Panics
+///
+/// If the path is longer than 256 bits.
+pub fn path_to_felt(path: &BitSlice<u8, Msb0>) -> Felt {
+    let mut bytes = [0u8; 32];
+    bytes.view_bits_mut::<Msb0>()[256 - path.len()..].copy_from_bitslice(path);
+    Felt::from_bytes_be(&bytes)
+}
+
+/// Converts a felt to the 251 bits path it is stored at in the state tries.
+pub fn felt_to_path(felt: &Felt) -> BitVec<u8, Msb0> {
+    felt.to_bytes_be().view_bits::<Msb0>()[5..].to_bitvec()
+}
+
+impl ProofNode {
+    /// Computes the hash of the node, as committed to by its parent.
+    pub fn hash<H: StarkHash>(&self) -> Felt {
+        match self {
+            ProofNode::Binary { left, right } => H::hash(left, right),
+            ProofNode::Edge { child, path } => H::hash(child, &path_to_felt(path)) + Felt::from(path.len() as u64),
+        }
+    }
+}
+
+/// Verifies a Merkle proof against a trie root.
+///
+/// # Arguments
+///
+/// * `root`  - The root of the trie the proof was generated from.
+/// * `key`   - The 251 bits path of the proven key.
+/// * `proof` - The proof nodes, root first.
+///
+/// # Returns
+///
+/// The value of the leaf at `key`, or `None` if the proof shows that `key` is absent from the trie.
+pub fn verify_proof<H: StarkHash>(
+    root: Felt,
+    key: &BitSlice<u8, Msb0>,
+    proof: &[ProofNode],
+) -> Result<Option<Felt>, ProofError> {
+    if root == Felt::ZERO {
+        return if proof.is_empty() { Ok(None) } else { Err(ProofError::EmptyTrie) };
+    }
+
+    let mut expected = root;
+    let mut height = 0;
+    for (index, node) in proof.iter().enumerate() {
+        if height == key.len() {
+            return Err(ProofError::TrailingNodes);
+        }
+        // An edge cannot run past the key, which also keeps its path hashable
+        let overflow = |length| ProofError::EdgeOverflow { index, height, length, key_length: key.len() };
+        if let ProofNode::Edge { path, .. } = node {
+            if path.len() > key.len() - height {
+                return Err(overflow(path.len()));
+            }
+        }
+
+        let computed = node.hash::<H>();
+        if computed != expected {
+            return Err(ProofError::HashMismatch { index, expected, computed });
+        }
+
+        match node {
+            ProofNode::Binary { left, right } => {
+                expected = if key[height] { *right } else { *left };
+                height += 1;
+            }
+            ProofNode::Edge { child, path } => {
+                let Some(segment) = key.get(height..height + path.len()) else {
+                    return Err(overflow(path.len()));
+                };
+                if segment != path.as_bitslice() {
+                    // The key diverges from the edge: it is not in the trie
+                    return if index == proof.len() - 1 { Ok(None) } else { Err(ProofError::TrailingNodes) };
+                }
+                expected = *child;
+                height += path.len();
+            }
+        }
+    }
+
+    if height != key.len() {
+        return Err(ProofError::Incomplete { height });
+    }
+
+    Ok(Some(expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use starknet_types_core::hash::Pedersen;
+
+    use super::*;
+
+    #[test]
+    fn test_path_round_trip() {
+        let felt = Felt::from(0x1234_5678_u64);
+        let path = felt_to_path(&felt);
+
+        assert_eq!(path.len(), 251);
+        assert_eq!(path_to_felt(&path), felt);
+    }
+
+    #[test]
+    fn test_non_membership() {
+        let key = felt_to_path(&Felt::from(3_u64));
+        assert_eq!(verify_proof::<Pedersen>(Felt::ZERO, &key, &[]), Ok(None));
+
+        // A single leaf at key 2: the root is an edge whose path diverges from key 3
+        let path = felt_to_path(&Felt::from(2_u64));
+        let edge = ProofNode::Edge { child: Felt::from(20_u64), path };
+        let root = edge.hash::<Pedersen>();
+        assert_eq!(verify_proof::<Pedersen>(root, &key, std::slice::from_ref(&edge)), Ok(None));
+        assert_eq!(verify_proof::<Pedersen>(root, &key, &[edge.clone(), edge]), Err(ProofError::TrailingNodes));
+    }
+
+    #[test]
+    fn test_edge_overflow() {
+        let key = felt_to_path(&Felt::from(3_u64));
+        let binary = |left| ProofNode::Binary { left, right: Felt::ONE };
+
+        // An edge longer than the key is rejected before its path is hashed
+        let edge = ProofNode::Edge { child: Felt::ONE, path: BitVec::repeat(false, 300) };
+        let expected = ProofError::EdgeOverflow { index: 0, height: 0, length: 300, key_length: 251 };
+        assert_eq!(verify_proof::<Pedersen>(Felt::ONE, &key, &[edge]), Err(expected));
+
+        // An edge running past the key below a binary node is rejected rather than proving the key absent
+        let edge = ProofNode::Edge { child: Felt::ONE, path: key.clone() };
+        let root = binary(edge.hash::<Pedersen>()).hash::<Pedersen>();
+        let expected = ProofError::EdgeOverflow { index: 1, height: 1, length: 251, key_length: 251 };
+        assert_eq!(verify_proof::<Pedersen>(root, &key, &[binary(edge.hash::<Pedersen>()), edge]), Err(expected));
+    }
+}
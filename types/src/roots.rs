@@ -0,0 +1,46 @@
+//! State roots and how final they are.
+
+use crate::Felt;
+
+/// How final the state root of a block is, from the least to the most final.
+///
+/// Statuses apply to a block and all the blocks before it: once block `n` is accepted on L1, so are
+/// all the blocks up to `n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Finality {
+    /// Committed locally, the block may still be reorged.
+    #[default]
+    Pending,
+    /// The block was accepted by the Starknet sequencer.
+    AcceptedOnL2,
+    /// A validity proof of the block was generated.
+    Proven,
+    /// The state update of the block was settled on L1.
+    AcceptedOnL1,
+}
+
+/// A root which differs from the expected one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootDiff {
+    pub expected: Felt,
+    pub computed: Felt,
+}
+
+impl RootDiff {
+    /// The difference between two roots, `None` if they are equal.
+    pub fn new(expected: Felt, computed: Felt) -> Option<Self> {
+        (expected != computed).then_some(Self { expected, computed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_diff() {
+        assert_eq!(RootDiff::new(Felt::ONE, Felt::ONE), None);
+        assert_eq!(RootDiff::new(Felt::ONE, Felt::TWO), Some(RootDiff { expected: Felt::ONE, computed: Felt::TWO }));
+        assert!(Finality::AcceptedOnL1 > Finality::Proven);
+    }
+}